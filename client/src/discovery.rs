@@ -0,0 +1,315 @@
+//! Discovery of the supervisor domains and of their TSMs, as a host does it at boot: probe SUPD
+//! and COVH, read the bitmap of the active domains and the `TsmInfo` of the TSM domains.
+//!
+//! The bitmap is read one word at a time (a0 of GET_ACTIVE_DOMAINS), so every domain id below
+//! `MAX_SUPERVISOR_DOMAINS` fits on RV32 too. A TSM still initializing is reported by the
+//! TSM-driver with `TSM_STATUS_LOADED` or `SBI_ERR_NOT_READY`: GET_TSM_INFO is issued again, a
+//! bounded number of times with a wait in between. The `TsmInfo` of a ready TSM does not change
+//! and is cached per domain.
+
+use core::fmt;
+
+use common::sbi::{
+    cove_pack_fid, TsmInfo, MAX_SUPERVISOR_DOMAINS, SBI_COVH_EXT_ID, SBI_COVH_GET_TSM_INFO,
+    SBI_PROBE_AVAILABLE, SBI_SUPD_EXT_ID, TSM_STATUS_FAULTED, TSM_STATUS_LOADED,
+    TSM_STATUS_NOT_LOADED, TSM_STATUS_READY,
+};
+
+use crate::{Client, Error, Transport, SBI_ERR_FAILED, SBI_ERR_NOT_READY};
+
+const WORD_BITS: usize = usize::BITS as usize;
+
+/// Words of a bitmap of all the supervisor domains
+pub const DOMAIN_MASK_WORDS: usize = MAX_SUPERVISOR_DOMAINS.div_ceil(WORD_BITS);
+
+/// Bitmap of supervisor domains, bit i of word w is the domain `w * XLEN + i`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DomainMask {
+    words: [usize; DOMAIN_MASK_WORDS],
+}
+
+impl DomainMask {
+    pub const fn from_words(words: [usize; DOMAIN_MASK_WORDS]) -> Self {
+        Self { words }
+    }
+
+    pub fn words(&self) -> &[usize; DOMAIN_MASK_WORDS] {
+        &self.words
+    }
+
+    pub fn contains(&self, sdid: usize) -> bool {
+        self.words
+            .get(sdid / WORD_BITS)
+            .is_some_and(|word| word & (1usize << (sdid % WORD_BITS)) != 0)
+    }
+
+    /// Ids of the domains in the mask, in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..DOMAIN_MASK_WORDS * WORD_BITS).filter(|&sdid| self.contains(sdid))
+    }
+}
+
+/// Why the TSM of a domain cannot be used
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiscoveryError {
+    /// The firmware does not implement SUPD
+    NoSupd,
+    /// The firmware does not implement COVH
+    NoCovh,
+    /// The domain is not active
+    NoDomain(usize),
+    /// No TSM is loaded in the domain
+    NoTsm(usize),
+    /// The TSM of the domain was not ready after all the attempts
+    TsmNotReady(usize),
+    /// The TSM of the domain was stopped by the TSM-driver watchdog
+    TsmFaulted(usize),
+    /// Unexpected SBI error
+    Sbi(Error),
+}
+
+impl From<Error> for DiscoveryError {
+    fn from(error: Error) -> Self {
+        DiscoveryError::Sbi(error)
+    }
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoveryError::NoSupd => write!(f, "SUPD not available"),
+            DiscoveryError::NoCovh => write!(f, "COVH not available"),
+            DiscoveryError::NoDomain(sdid) => write!(f, "domain {} not active", sdid),
+            DiscoveryError::NoTsm(sdid) => write!(f, "no TSM in domain {}", sdid),
+            DiscoveryError::TsmNotReady(sdid) => write!(f, "TSM of domain {} not ready", sdid),
+            DiscoveryError::TsmFaulted(sdid) => write!(f, "TSM of domain {} faulted", sdid),
+            DiscoveryError::Sbi(error) => error.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DiscoveryError {}
+
+/// Discovered domains and cached `TsmInfo`.
+pub struct Discovery {
+    /// GET_TSM_INFO calls issued before giving up on a TSM not ready
+    attempts: usize,
+    /// Called between two attempts
+    wait: fn(),
+    domains: Option<DomainMask>,
+    tsm_info: [Option<TsmInfo>; MAX_SUPERVISOR_DOMAINS],
+}
+
+impl Discovery {
+    /// Discovery trying GET_TSM_INFO up to `attempts` times (at least once), calling `wait` in
+    /// between.
+    pub const fn new(attempts: usize, wait: fn()) -> Self {
+        Self {
+            attempts,
+            wait,
+            domains: None,
+            tsm_info: [const { None }; MAX_SUPERVISOR_DOMAINS],
+        }
+    }
+
+    /// Active supervisor domains, read once.
+    pub fn domains<T: Transport>(
+        &mut self,
+        client: &mut Client<T>,
+    ) -> Result<DomainMask, DiscoveryError> {
+        if let Some(domains) = self.domains {
+            return Ok(domains);
+        }
+        if !available(client, SBI_SUPD_EXT_ID) {
+            return Err(DiscoveryError::NoSupd);
+        }
+        let domains = client.get_active_domains()?;
+        self.domains = Some(domains);
+        Ok(domains)
+    }
+
+    /// `TsmInfo` of the TSM of the domain `sdid`, written by the TSM in `buffer`. The buffer is
+    /// passed by address: it must be identity mapped, as for a host running in S-mode without
+    /// translation.
+    pub fn tsm_info<T: Transport>(
+        &mut self,
+        client: &mut Client<T>,
+        sdid: usize,
+        buffer: &mut TsmInfo,
+    ) -> Result<&TsmInfo, DiscoveryError> {
+        if sdid >= MAX_SUPERVISOR_DOMAINS || !self.domains(client)?.contains(sdid) {
+            return Err(DiscoveryError::NoDomain(sdid));
+        }
+        if self.tsm_info[sdid].is_none() {
+            if !available(client, SBI_COVH_EXT_ID) {
+                return Err(DiscoveryError::NoCovh);
+            }
+            let info = self.query(client, sdid, buffer)?;
+            self.tsm_info[sdid] = Some(info);
+        }
+        Ok(self.tsm_info[sdid].as_ref().unwrap())
+    }
+
+    /// Forget what was read about `sdid`, e.g. after the TSM-driver restarted its TSM.
+    pub fn invalidate(&mut self, sdid: usize) {
+        self.domains = None;
+        if let Some(info) = self.tsm_info.get_mut(sdid) {
+            *info = None;
+        }
+    }
+
+    fn query<T: Transport>(
+        &self,
+        client: &mut Client<T>,
+        sdid: usize,
+        buffer: &mut TsmInfo,
+    ) -> Result<TsmInfo, DiscoveryError> {
+        let addr = buffer as *mut TsmInfo as usize;
+        let len = core::mem::size_of::<TsmInfo>();
+        for attempt in 0..self.attempts.max(1) {
+            if attempt != 0 {
+                (self.wait)();
+            }
+            let fid = cove_pack_fid(sdid, SBI_COVH_GET_TSM_INFO);
+            match client.call(SBI_COVH_EXT_ID, fid, [addr, len, 0, 0, 0, 0]) {
+                Ok(_) => {}
+                Err(Error(SBI_ERR_NOT_READY)) => continue,
+                Err(error) => return Err(error.into()),
+            }
+            // Written behind the back of the compiler
+            let info = unsafe { core::ptr::read_volatile(buffer) };
+            match info.tsm_status {
+                TSM_STATUS_READY => return Ok(info),
+                TSM_STATUS_LOADED => {}
+                TSM_STATUS_NOT_LOADED => return Err(DiscoveryError::NoTsm(sdid)),
+                TSM_STATUS_FAULTED => return Err(DiscoveryError::TsmFaulted(sdid)),
+                _ => return Err(Error(SBI_ERR_FAILED).into()),
+            }
+        }
+        Err(DiscoveryError::TsmNotReady(sdid))
+    }
+}
+
+fn available<T: Transport>(client: &mut Client<T>, extid: usize) -> bool {
+    client
+        .probe_extension(extid)
+        .is_ok_and(|probe| probe & SBI_PROBE_AVAILABLE != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use common::sbi::{cove_unpack_fid, SbiRet, SBI_EXT_BASE};
+
+    use super::*;
+
+    /// Answers every probe, GET_ACTIVE_DOMAINS with `words` and GET_TSM_INFO with the next of
+    /// `statuses` (an SBI error, or a status written in the buffer).
+    struct Firmware {
+        words: [usize; DOMAIN_MASK_WORDS],
+        statuses: VecDeque<Result<u32, isize>>,
+        tsm_info_calls: usize,
+    }
+
+    impl Transport for Firmware {
+        fn call(&mut self, extid: usize, fid: usize, args: &[usize; 6]) -> SbiRet {
+            let ok = |a1: usize| SbiRet {
+                a0: 0,
+                a1: a1 as isize,
+            };
+            match extid {
+                SBI_EXT_BASE => ok(SBI_PROBE_AVAILABLE),
+                SBI_SUPD_EXT_ID => ok(self.words.get(args[0]).copied().unwrap_or(0)),
+                SBI_COVH_EXT_ID => {
+                    assert_eq!(cove_unpack_fid(fid).1, SBI_COVH_GET_TSM_INFO);
+                    self.tsm_info_calls += 1;
+                    match self.statuses.pop_front().unwrap() {
+                        Ok(status) => {
+                            let info = args[0] as *mut TsmInfo;
+                            unsafe {
+                                core::ptr::write_bytes(info, 0, 1);
+                                (*info).tsm_status = status;
+                                (*info).tsm_capabilities = 0x20;
+                            }
+                            ok(args[1])
+                        }
+                        Err(error) => SbiRet { a0: error, a1: 0 },
+                    }
+                }
+                _ => SbiRet { a0: -2, a1: 0 },
+            }
+        }
+    }
+
+    fn client(statuses: &[Result<u32, isize>]) -> Client<Firmware> {
+        let mut words = [0; DOMAIN_MASK_WORDS];
+        words[0] = 0b111;
+        Client::new(
+            Firmware {
+                words,
+                statuses: statuses.iter().copied().collect(),
+                tsm_info_calls: 0,
+            },
+            1,
+        )
+    }
+
+    fn buffer() -> TsmInfo {
+        unsafe { core::mem::zeroed() }
+    }
+
+    #[test]
+    fn domain_mask_spans_words() {
+        let mut words = [0; DOMAIN_MASK_WORDS];
+        words[0] = 0b11;
+        words[DOMAIN_MASK_WORDS - 1] |= 1 << (WORD_BITS - 1);
+        let mask = DomainMask::from_words(words);
+        let last = DOMAIN_MASK_WORDS * WORD_BITS - 1;
+        assert!(mask.contains(last));
+        assert!(!mask.contains(2));
+        assert!(!mask.contains(last + 1));
+        assert_eq!(mask.iter().collect::<Vec<_>>(), [0, 1, last]);
+    }
+
+    #[test]
+    fn retries_until_ready_and_caches() {
+        let mut client = client(&[
+            Err(SBI_ERR_NOT_READY),
+            Ok(TSM_STATUS_LOADED),
+            Ok(TSM_STATUS_READY),
+        ]);
+        let mut discovery = Discovery::new(3, || {});
+        let mut buffer = buffer();
+        let info = discovery.tsm_info(&mut client, 1, &mut buffer).unwrap();
+        assert_eq!(info.tsm_capabilities, 0x20);
+        discovery.tsm_info(&mut client, 1, &mut buffer).unwrap();
+        assert_eq!(client.transport.tsm_info_calls, 3);
+    }
+
+    #[test]
+    fn gives_up_on_a_tsm_not_ready() {
+        let mut client = client(&[Ok(TSM_STATUS_LOADED), Ok(TSM_STATUS_LOADED)]);
+        let mut discovery = Discovery::new(2, || {});
+        assert_eq!(
+            discovery.tsm_info(&mut client, 1, &mut buffer()).err(),
+            Some(DiscoveryError::TsmNotReady(1))
+        );
+    }
+
+    #[test]
+    fn reports_faulted_and_inactive_domains() {
+        let mut client = client(&[Ok(TSM_STATUS_FAULTED)]);
+        let mut discovery = Discovery::new(3, || {});
+        assert_eq!(
+            discovery.tsm_info(&mut client, 1, &mut buffer()).err(),
+            Some(DiscoveryError::TsmFaulted(1))
+        );
+        assert_eq!(
+            discovery.tsm_info(&mut client, 5, &mut buffer()).err(),
+            Some(DiscoveryError::NoDomain(5))
+        );
+        assert_eq!(client.transport.tsm_info_calls, 1);
+    }
+}
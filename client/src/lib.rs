@@ -0,0 +1,444 @@
+//! Typed client of the shadowfax SBI extensions (COVH and SUPD) for a host VMM.
+//!
+//! The calls mirror what `payload/cove-vmm` issues by hand: each method packs its arguments in
+//! a0-a5 as decoded by `tsm_core::covh`, targets the TSM of the supervisor domain `sdid` and turns
+//! the SBI error in a0 into `Error`. How the calls reach the firmware is up to the `Transport`:
+//! `Ecall` from S-mode, `SbiDevice` (feature `std`) from Linux userspace through a kernel shim.
+//!
+//! Addresses are host physical addresses: the output buffers (`get_tsm_info`, `get_tsm_stats`,
+//! ...) must be memory of the host the TSM can be granted for the duration of the call, the
+//! pages given to `convert_pages` must be page aligned.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+use core::fmt;
+
+use common::sbi::{
+    cove_pack_fid, SbiRet, TsmMemoryStats, TsmStats, TvmGpaTranslation, TvmVcpuTime, PAGE_SIZE,
+    SBI_COVH_ADD_TVM_MEASURED_PAGES, SBI_COVH_ADD_TVM_MEMORY_REGION, SBI_COVH_ADD_TVM_SEALED_IMAGE,
+    SBI_COVH_ADD_TVM_SHARED_PAGES, SBI_COVH_ADD_ZERO_PAGES, SBI_COVH_CONVERT_PAGES,
+    SBI_COVH_CREATE_TVM, SBI_COVH_CREATE_TVM_VCPU, SBI_COVH_DESTROY_TVM, SBI_COVH_EXT_ID,
+    SBI_COVH_FINALIZE_TVM, SBI_COVH_GET_TSM_INFO, SBI_COVH_GET_TSM_MEMORY_STATS,
+    SBI_COVH_GET_TSM_STATS, SBI_COVH_GET_TVM_VCPU_TIME, SBI_COVH_PROCESS_QUEUE,
+    SBI_COVH_RECLAIM_PAGES, SBI_COVH_RUN_TVM_VCPU, SBI_COVH_SET_TVM_VCPU_AFFINITY,
+    SBI_COVH_TSM_LOCAL_FENCE, SBI_COVH_TVM_TRANSLATE_GPA, SBI_EXT_BASE, SBI_EXT_BASE_PROBE_EXT,
+    SBI_EXT_SUPD_GET_ACTIVE_DOMAINS, SBI_EXT_SUPD_READ_TRACE, SBI_SUPD_EXT_ID,
+};
+
+pub mod discovery;
+pub mod transport;
+
+pub use discovery::{Discovery, DiscoveryError, DomainMask, DOMAIN_MASK_WORDS};
+pub use transport::Transport;
+
+pub const SBI_ERR_FAILED: isize = -1;
+pub const SBI_ERR_NOT_SUPPORTED: isize = -2;
+pub const SBI_ERR_INVALID_PARAM: isize = -3;
+pub const SBI_ERR_DENIED: isize = -4;
+pub const SBI_ERR_INVALID_ADDRESS: isize = -5;
+pub const SBI_ERR_NOT_READY: isize = -10;
+
+/// Page type of the COVH page calls, the TSM only accepts 4K pages
+const PAGE_TYPE_4K: usize = 0;
+
+/// SBI error returned in a0 by a failed call
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Error(pub isize);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self.0 {
+            SBI_ERR_FAILED => "failed",
+            SBI_ERR_NOT_SUPPORTED => "not supported",
+            SBI_ERR_INVALID_PARAM => "invalid parameter",
+            SBI_ERR_DENIED => "denied",
+            SBI_ERR_INVALID_ADDRESS => "invalid address",
+            SBI_ERR_NOT_READY => "not ready",
+            _ => "error",
+        };
+        write!(f, "SBI {} ({})", name, self.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+fn check(ret: SbiRet) -> Result<usize> {
+    match ret.a0 {
+        0 => Ok(ret.a1 as usize),
+        error => Err(Error(error)),
+    }
+}
+
+/// Client of the TSM of the supervisor domain `sdid`.
+pub struct Client<T: Transport> {
+    transport: T,
+    sdid: usize,
+}
+
+impl<T: Transport> Client<T> {
+    pub fn new(transport: T, sdid: usize) -> Self {
+        Self { transport, sdid }
+    }
+
+    pub fn sdid(&self) -> usize {
+        self.sdid
+    }
+
+    /// Issue any SBI call, for the extensions without a typed method.
+    pub fn call(&mut self, extid: usize, fid: usize, args: [usize; 6]) -> Result<usize> {
+        check(self.transport.call(extid, fid, &args))
+    }
+
+    fn covh(&mut self, fid: usize, args: [usize; 6]) -> Result<usize> {
+        self.call(SBI_COVH_EXT_ID, cove_pack_fid(self.sdid, fid), args)
+    }
+
+    /// `sbi_probe_extension`: zero if the extension is not available.
+    pub fn probe_extension(&mut self, extid: usize) -> Result<usize> {
+        self.call(SBI_EXT_BASE, SBI_EXT_BASE_PROBE_EXT, [extid, 0, 0, 0, 0, 0])
+    }
+
+    /// Active supervisor domains, read a word of the bitmap at a time.
+    pub fn get_active_domains(&mut self) -> Result<DomainMask> {
+        let mut words = [0; DOMAIN_MASK_WORDS];
+        for (index, word) in words.iter_mut().enumerate() {
+            *word = self.call(
+                SBI_SUPD_EXT_ID,
+                SBI_EXT_SUPD_GET_ACTIVE_DOMAINS,
+                [index, 0, 0, 0, 0, 0],
+            )?;
+        }
+        Ok(DomainMask::from_words(words))
+    }
+
+    /// Whether SUPD and COVH are available and the TSM domain is active. `Discovery` tells why
+    /// not, and whether the TSM is ready.
+    pub fn has_tsm(&mut self) -> bool {
+        let available = |probe: Result<usize>| probe.is_ok_and(|probe| probe != 0);
+        available(self.probe_extension(SBI_SUPD_EXT_ID))
+            && available(self.probe_extension(SBI_COVH_EXT_ID))
+            && self
+                .get_active_domains()
+                .is_ok_and(|domains| domains.contains(self.sdid))
+    }
+
+    /// Write the `TsmInfo` of the TSM in the `len` bytes at `addr`, returns the bytes written.
+    pub fn get_tsm_info(&mut self, addr: usize, len: usize) -> Result<usize> {
+        self.covh(SBI_COVH_GET_TSM_INFO, [addr, len, 0, 0, 0, 0])
+    }
+
+    pub fn convert_pages(&mut self, base_addr: usize, num_pages: usize) -> Result<()> {
+        self.covh(SBI_COVH_CONVERT_PAGES, [base_addr, num_pages, 0, 0, 0, 0])
+            .map(drop)
+    }
+
+    pub fn reclaim_pages(&mut self, base_addr: usize, num_pages: usize) -> Result<()> {
+        self.covh(SBI_COVH_RECLAIM_PAGES, [base_addr, num_pages, 0, 0, 0, 0])
+            .map(drop)
+    }
+
+    /// Fence the G-stage TLB of the calling hart.
+    pub fn tsm_local_fence(&mut self) -> Result<()> {
+        self.covh(SBI_COVH_TSM_LOCAL_FENCE, [0; 6]).map(drop)
+    }
+
+    /// Create a TVM from the parameters in the `len` bytes at `params_addr` (see
+    /// `tsm_core::CreateTvmParams`), returns its id.
+    pub fn create_tvm(&mut self, params_addr: usize, len: usize) -> Result<usize> {
+        self.covh(SBI_COVH_CREATE_TVM, [params_addr, len, 0, 0, 0, 0])
+    }
+
+    pub fn add_tvm_memory_region(&mut self, tvm_id: usize, gpa: usize, len: usize) -> Result<()> {
+        self.covh(SBI_COVH_ADD_TVM_MEMORY_REGION, [tvm_id, gpa, len, 0, 0, 0])
+            .map(drop)
+    }
+
+    /// Copy and measure `num_pages` pages from `src` to the confidential pages at `dest`, mapped at
+    /// `gpa`. The TSM adds a chunk of the pages per call and returns how many: the call is issued
+    /// again for the pages left, so the host gets the hart back between the chunks.
+    pub fn add_tvm_measured_pages(
+        &mut self,
+        tvm_id: usize,
+        src: usize,
+        dest: usize,
+        num_pages: usize,
+        gpa: usize,
+    ) -> Result<()> {
+        let mut added = 0;
+        while added < num_pages {
+            let offset = added * PAGE_SIZE;
+            let chunk = self.covh(
+                SBI_COVH_ADD_TVM_MEASURED_PAGES,
+                [
+                    tvm_id,
+                    src + offset,
+                    dest + offset,
+                    PAGE_TYPE_4K,
+                    num_pages - added,
+                    gpa + offset,
+                ],
+            )?;
+            if chunk == 0 || chunk > num_pages - added {
+                return Err(Error(SBI_ERR_FAILED));
+            }
+            added += chunk;
+        }
+        Ok(())
+    }
+
+    /// Open the sealed image `[image, image + len)` into the `num_pages` confidential pages at
+    /// `dest`, mapped at the GPA of the image. The host never sees the plaintext.
+    pub fn add_tvm_sealed_image(
+        &mut self,
+        tvm_id: usize,
+        image: usize,
+        len: usize,
+        dest: usize,
+        num_pages: usize,
+    ) -> Result<()> {
+        self.covh(
+            SBI_COVH_ADD_TVM_SEALED_IMAGE,
+            [tvm_id, image, len, dest, num_pages, 0],
+        )
+        .map(drop)
+    }
+
+    pub fn add_tvm_zero_pages(
+        &mut self,
+        tvm_id: usize,
+        base_addr: usize,
+        num_pages: usize,
+        gpa: usize,
+    ) -> Result<()> {
+        self.covh(
+            SBI_COVH_ADD_ZERO_PAGES,
+            [tvm_id, base_addr, PAGE_TYPE_4K, num_pages, gpa, 0],
+        )
+        .map(drop)
+    }
+
+    /// Map the host pages at `base_addr` in the TVM at `gpa`, they are not converted.
+    pub fn add_tvm_shared_pages(
+        &mut self,
+        tvm_id: usize,
+        base_addr: usize,
+        num_pages: usize,
+        gpa: usize,
+    ) -> Result<()> {
+        self.covh(
+            SBI_COVH_ADD_TVM_SHARED_PAGES,
+            [tvm_id, base_addr, PAGE_TYPE_4K, num_pages, gpa, 0],
+        )
+        .map(drop)
+    }
+
+    pub fn create_tvm_vcpu(
+        &mut self,
+        tvm_id: usize,
+        vcpu_id: usize,
+        state_addr: usize,
+    ) -> Result<()> {
+        self.covh(
+            SBI_COVH_CREATE_TVM_VCPU,
+            [tvm_id, vcpu_id, state_addr, 0, 0, 0],
+        )
+        .map(drop)
+    }
+
+    /// Seal the measurement of the TVM, which starts at `entry_sepc` with `entry_arg` in a1.
+    pub fn finalize_tvm(
+        &mut self,
+        tvm_id: usize,
+        entry_sepc: usize,
+        entry_arg: usize,
+    ) -> Result<()> {
+        self.covh(
+            SBI_COVH_FINALIZE_TVM,
+            [tvm_id, entry_sepc, entry_arg, 0, 0, 0],
+        )
+        .map(drop)
+    }
+
+    pub fn destroy_tvm(&mut self, tvm_id: usize) -> Result<()> {
+        self.covh(SBI_COVH_DESTROY_TVM, [tvm_id, 0, 0, 0, 0, 0])
+            .map(drop)
+    }
+
+    /// Run the vCPU until its next exit, returns the exit (`TVM_EXIT_*` and its payload).
+    /// `flags` are `TVM_RUN_*`.
+    pub fn run_tvm_vcpu(&mut self, tvm_id: usize, vcpu_id: usize, flags: usize) -> Result<usize> {
+        self.covh(SBI_COVH_RUN_TVM_VCPU, [tvm_id, vcpu_id, flags, 0, 0, 0])
+    }
+
+    /// Pin the vCPU to the physical hart `hart`, or let it run on any hart with
+    /// `TVM_VCPU_ANY_HART`.
+    pub fn set_tvm_vcpu_affinity(
+        &mut self,
+        tvm_id: usize,
+        vcpu_id: usize,
+        hart: usize,
+    ) -> Result<()> {
+        self.covh(
+            SBI_COVH_SET_TVM_VCPU_AFFINITY,
+            [tvm_id, vcpu_id, hart, 0, 0, 0],
+        )
+        .map(drop)
+    }
+
+    /// Write the `TvmVcpuTime` of the vCPU at `addr`.
+    pub fn get_tvm_vcpu_time(&mut self, tvm_id: usize, vcpu_id: usize, addr: usize) -> Result<()> {
+        let len = core::mem::size_of::<TvmVcpuTime>();
+        self.covh(
+            SBI_COVH_GET_TVM_VCPU_TIME,
+            [tvm_id, vcpu_id, addr, len, 0, 0],
+        )
+        .map(drop)
+    }
+
+    /// Write the `TvmGpaTranslation` of `gpa` at `addr`.
+    pub fn tvm_translate_gpa(&mut self, tvm_id: usize, gpa: usize, addr: usize) -> Result<()> {
+        let len = core::mem::size_of::<TvmGpaTranslation>();
+        self.covh(SBI_COVH_TVM_TRANSLATE_GPA, [tvm_id, gpa, addr, len, 0, 0])
+            .map(drop)
+    }
+
+    /// Write the `TsmStats` of the TSM at `addr`.
+    pub fn get_tsm_stats(&mut self, addr: usize) -> Result<()> {
+        let len = core::mem::size_of::<TsmStats>();
+        self.covh(SBI_COVH_GET_TSM_STATS, [addr, len, 0, 0, 0, 0])
+            .map(drop)
+    }
+
+    /// Write the `TsmMemoryStats` of the TSM at `addr`, debug builds of the TSM only.
+    pub fn get_tsm_memory_stats(&mut self, addr: usize) -> Result<()> {
+        let len = core::mem::size_of::<TsmMemoryStats>();
+        self.covh(SBI_COVH_GET_TSM_MEMORY_STATS, [addr, len, 0, 0, 0, 0])
+            .map(drop)
+    }
+
+    /// Copy the `SupdTraceEntry`s of the firmware trace of `hartid` from `first_seq` on to the `len`
+    /// bytes at `addr`, firmware builds with the `trace` feature only. Returns the entries copied.
+    pub fn read_trace(
+        &mut self,
+        hartid: usize,
+        first_seq: usize,
+        addr: usize,
+        len: usize,
+    ) -> Result<usize> {
+        self.call(
+            SBI_SUPD_EXT_ID,
+            SBI_EXT_SUPD_READ_TRACE,
+            [hartid, addr, len, first_seq, 0, 0],
+        )
+    }
+
+    /// Execute the `count` `CovhQueueEntry` at `addr`, each entry gets its own result.
+    pub fn process_queue(&mut self, addr: usize, count: usize) -> Result<()> {
+        self.covh(SBI_COVH_PROCESS_QUEUE, [addr, count, 0, 0, 0, 0])
+            .map(drop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::sbi::cove_unpack_fid;
+
+    use super::*;
+
+    /// Records the last call, answers with `ret`
+    struct Mock {
+        last: Option<(usize, usize, [usize; 6])>,
+        ret: (isize, isize),
+    }
+
+    impl Transport for Mock {
+        fn call(&mut self, extid: usize, fid: usize, args: &[usize; 6]) -> SbiRet {
+            self.last = Some((extid, fid, *args));
+            SbiRet {
+                a0: self.ret.0,
+                a1: self.ret.1,
+            }
+        }
+    }
+
+    fn client(ret: (isize, isize)) -> Client<Mock> {
+        Client::new(Mock { last: None, ret }, 1)
+    }
+
+    #[test]
+    fn covh_targets_the_tsm_domain() {
+        let mut client = client((0, 3));
+        assert_eq!(client.create_tvm(0x8a20_1000, 16), Ok(3));
+
+        let (extid, fid, args) = client.transport.last.unwrap();
+        assert_eq!(extid, SBI_COVH_EXT_ID);
+        assert_eq!(cove_unpack_fid(fid), (1, SBI_COVH_CREATE_TVM));
+        assert_eq!(args, [0x8a20_1000, 16, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn page_calls_pass_the_page_type() {
+        let mut client = client((0, 0));
+        client
+            .add_tvm_zero_pages(2, 0x8a80_0000, 4, 0x1000)
+            .unwrap();
+        let (_, _, args) = client.transport.last.unwrap();
+        assert_eq!(args, [2, 0x8a80_0000, PAGE_TYPE_4K, 4, 0x1000, 0]);
+    }
+
+    /// Adds at most `chunk` measured pages per call, like the TSM
+    struct Chunked {
+        chunk: usize,
+        calls: usize,
+        last: [usize; 6],
+    }
+
+    impl Transport for Chunked {
+        fn call(&mut self, _extid: usize, _fid: usize, args: &[usize; 6]) -> SbiRet {
+            self.calls += 1;
+            self.last = *args;
+            SbiRet {
+                a0: 0,
+                a1: args[4].min(self.chunk) as isize,
+            }
+        }
+    }
+
+    #[test]
+    fn measured_pages_are_added_in_chunks() {
+        let transport = Chunked {
+            chunk: 64,
+            calls: 0,
+            last: [0; 6],
+        };
+        let mut client = Client::new(transport, 1);
+        client
+            .add_tvm_measured_pages(2, 0x8a40_0000, 0x8a80_0000, 150, 0x1000)
+            .unwrap();
+
+        // 64 + 64 + 22 pages, the last call starts 128 pages in
+        assert_eq!(client.transport.calls, 3);
+        assert_eq!(
+            client.transport.last,
+            [2, 0x8a48_0000, 0x8a88_0000, PAGE_TYPE_4K, 22, 0x81000]
+        );
+
+        // A TSM adding no page fails the call instead of looping
+        client.transport.chunk = 0;
+        assert_eq!(
+            client.add_tvm_measured_pages(2, 0x8a40_0000, 0x8a80_0000, 1, 0x1000),
+            Err(Error(SBI_ERR_FAILED))
+        );
+    }
+
+    #[test]
+    fn sbi_errors_are_returned() {
+        let mut client = client((SBI_ERR_INVALID_PARAM, 0));
+        assert_eq!(
+            client.convert_pages(0x8a80_0000, 3),
+            Err(Error(SBI_ERR_INVALID_PARAM))
+        );
+    }
+}
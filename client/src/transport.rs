@@ -0,0 +1,111 @@
+//! How the SBI calls reach the firmware. In S-mode (a kernel, or a bare-metal host like
+//! `payload/cove-vmm`) the client issues the `ecall` itself; from Linux userspace a kernel shim
+//! exposing `/dev/sbi` issues it on behalf of the VMM.
+
+use common::sbi::SbiRet;
+
+/// Issues a single SBI call: `extid` in a7, `fid` in a6, `args` in a0-a5.
+pub trait Transport {
+    fn call(&mut self, extid: usize, fid: usize, args: &[usize; 6]) -> SbiRet;
+}
+
+/// The `ecall` instruction, for a client running in S-mode.
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Ecall;
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+impl Transport for Ecall {
+    fn call(&mut self, extid: usize, fid: usize, args: &[usize; 6]) -> SbiRet {
+        common::sbi::sbi_call(extid, fid, args)
+    }
+}
+
+#[cfg(feature = "std")]
+pub use sbi_device::{SbiDevice, SbiIoctlCall, SBI_DEVICE_PATH, SBI_IOCTL_CALL};
+
+#[cfg(feature = "std")]
+mod sbi_device {
+    use std::{fs::File, io, os::fd::AsRawFd};
+
+    use common::sbi::SbiRet;
+
+    use super::Transport;
+
+    /// Character device of the kernel shim
+    pub const SBI_DEVICE_PATH: &str = "/dev/sbi";
+
+    /// Argument of `SBI_IOCTL_CALL`: the shim issues the call and fills `ret` with a0/a1.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct SbiIoctlCall {
+        pub extid: u64,
+        pub fid: u64,
+        pub args: [u64; 6],
+        pub ret: [i64; 2],
+    }
+
+    const fn iowr(ty: u8, nr: u8, size: usize) -> u64 {
+        (3 << 30) | ((size as u64) << 16) | ((ty as u64) << 8) | nr as u64
+    }
+
+    /// `_IOWR('S', 0, struct sbi_ioctl_call)`
+    pub const SBI_IOCTL_CALL: u64 = iowr(b'S', 0, core::mem::size_of::<SbiIoctlCall>());
+
+    /// `/dev/sbi`, for a VMM in Linux userspace. The shim decides which calls a process may issue;
+    /// the addresses passed to the COVH calls are physical addresses, so the VMM gets its buffers
+    /// and its guest memory from the shim as well.
+    pub struct SbiDevice {
+        file: File,
+    }
+
+    impl SbiDevice {
+        pub fn open() -> io::Result<Self> {
+            Self::open_path(SBI_DEVICE_PATH)
+        }
+
+        pub fn open_path(path: &str) -> io::Result<Self> {
+            let file = File::options().read(true).write(true).open(path)?;
+            Ok(Self { file })
+        }
+
+        /// Issue the call, failing only if the shim refuses it.
+        pub fn try_call(
+            &mut self,
+            extid: usize,
+            fid: usize,
+            args: &[usize; 6],
+        ) -> io::Result<SbiRet> {
+            let mut call = SbiIoctlCall {
+                extid: extid as u64,
+                fid: fid as u64,
+                args: args.map(|arg| arg as u64),
+                ret: [0; 2],
+            };
+            let ret = unsafe {
+                libc::ioctl(
+                    self.file.as_raw_fd(),
+                    SBI_IOCTL_CALL as _,
+                    &mut call as *mut SbiIoctlCall,
+                )
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(SbiRet {
+                a0: call.ret[0] as isize,
+                a1: call.ret[1] as isize,
+            })
+        }
+    }
+
+    impl Transport for SbiDevice {
+        /// A call the shim refuses is reported as SBI_ERR_DENIED.
+        fn call(&mut self, extid: usize, fid: usize, args: &[usize; 6]) -> SbiRet {
+            self.try_call(extid, fid, args).unwrap_or(SbiRet {
+                a0: crate::SBI_ERR_DENIED,
+                a1: 0,
+            })
+        }
+    }
+}
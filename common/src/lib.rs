@@ -1,55 +1,2677 @@
 #![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_main)]
 
 pub mod sbi {
     pub const COVH_DEFAULT_PAGE_SIZE: usize = 4096;
+    // Base extension
+    pub const SBI_EXT_BASE: usize = 0x10;
+    pub const SBI_EXT_BASE_PROBE_EXT: usize = 3;
+
+    // Value returned by sbi_probe_extension for the CoVE extensions: bit 0 tells the extension is
+    // available, the other bits are extension specific features.
+    pub const SBI_PROBE_AVAILABLE: usize = 1 << 0;
+    // SUPD: DMA regions can be granted to devices (IOPMP)
+    pub const SBI_SUPD_PROBE_DMA: usize = 1 << 1;
+    // SUPD: the TSM-driver has an entropy source
+    pub const SBI_SUPD_PROBE_RANDOM: usize = 1 << 2;
+    // SUPD: the caller can read the audit log
+    pub const SBI_SUPD_PROBE_AUDIT_LOG: usize = 1 << 3;
+    // SUPD: the TSM-driver has persistent storage for sealed blobs
+    pub const SBI_SUPD_PROBE_STORAGE: usize = 1 << 4;
+
+    // CoVE fid (a6): bits [31:26] hold the target supervisor domain id (SDID), bits [15:0] the
+    // function id
+    pub const SDID_SHIFT: usize = 26;
+    pub const SDID_BITS: usize = 6;
+    pub const SDID_MASK: usize = (1 << SDID_BITS) - 1;
+    pub const COVE_FID_MASK: usize = 0xFFFF;
+    /// Supervisor domains an SDID can address
+    pub const MAX_SUPERVISOR_DOMAINS: usize = 1 << SDID_BITS;
+
+    /// CoVE fid calling `fid` of the supervisor domain `sdid`.
+    pub const fn cove_pack_fid(sdid: usize, fid: usize) -> usize {
+        ((sdid & SDID_MASK) << SDID_SHIFT) | (fid & COVE_FID_MASK)
+    }
+
+    /// SDID and function id of the CoVE fid `a6`.
+    pub const fn cove_unpack_fid(a6: usize) -> (usize, usize) {
+        ((a6 >> SDID_SHIFT) & SDID_MASK, a6 & COVE_FID_MASK)
+    }
+
     // CoVH constants
     pub const SBI_COVH_EXT_ID: usize = 0x434F5648;
 
-    pub const SBI_COVH_GET_TSM_INFO: usize = 0;
-    pub const SBI_COVH_CONVERT_PAGES: usize = 1;
-    pub const SBI_COVH_RECLAIM_PAGES: usize = 2;
-    pub const SBI_COVH_CREATE_TVM: usize = 5;
-    pub const SBI_COVH_FINALIZE_TVM: usize = 6;
-    pub const SBI_COVH_DESTROY_TVM: usize = 8;
-    pub const SBI_COVH_ADD_TVM_MEMORY_REGION: usize = 9;
-    pub const SBI_COVH_ADD_TVM_MEASURED_PAGES: usize = 11;
-    pub const SBI_COVH_ADD_ZERO_PAGES: usize = 12;
-    pub const SBI_COVH_CREATE_TVM_VCPU: usize = 14;
-    pub const SBI_COVH_RUN_TVM_VCPU: usize = 15;
+    pub const SBI_COVH_GET_TSM_INFO: usize = 0;
+    pub const SBI_COVH_CONVERT_PAGES: usize = 1;
+    pub const SBI_COVH_RECLAIM_PAGES: usize = 2;
+    // Fence the G-stage TLB of the calling hart
+    pub const SBI_COVH_TSM_LOCAL_FENCE: usize = 4;
+    pub const SBI_COVH_CREATE_TVM: usize = 5;
+    pub const SBI_COVH_FINALIZE_TVM: usize = 6;
+    pub const SBI_COVH_DESTROY_TVM: usize = 8;
+    pub const SBI_COVH_ADD_TVM_MEMORY_REGION: usize = 9;
+    // Adds a chunk of the pages at a time: a1 returns how many, the host issues the call again
+    // for the rest (also for the BATCH variant)
+    pub const SBI_COVH_ADD_TVM_MEASURED_PAGES: usize = 11;
+    pub const SBI_COVH_ADD_ZERO_PAGES: usize = 12;
+    pub const SBI_COVH_ADD_TVM_SHARED_PAGES: usize = 13;
+    pub const SBI_COVH_CREATE_TVM_VCPU: usize = 14;
+    pub const SBI_COVH_RUN_TVM_VCPU: usize = 15;
+    // Shadowfax specific FIDs, outside of the CoVE numbering
+    pub const SBI_COVH_ADD_TVM_MEASURED_PAGES_BATCH: usize = 32;
+    // a0: tvm_id, a1: blob buffer, a2: its size, a3: import offer of the destination TSM (see
+    // `migration`), a4: its size
+    pub const SBI_COVH_EXPORT_TVM: usize = 33;
+    // Consumes the offer of the last SBI_COVH_PREPARE_TVM_IMPORT
+    pub const SBI_COVH_IMPORT_TVM: usize = 34;
+    pub const SBI_COVH_TVM_VCPU_SET_TIMER_FREQUENCY: usize = 35;
+    pub const SBI_COVH_SET_TVM_BOOT_INFO: usize = 36;
+    pub const SBI_COVH_PROCESS_QUEUE: usize = 37;
+    // a0: tvm_id, a1: vcpu_id, a2: address of a `TvmVcpuTime`, a3: its size
+    pub const SBI_COVH_GET_TVM_VCPU_TIME: usize = 38;
+    // Debug: a0: tvm_id, a1: GPA, a2: address of a `TvmGpaTranslation`, a3: its size. Only with
+    // SHADOWFAX_TSM_CAP_TRANSLATE_GPA
+    pub const SBI_COVH_TVM_TRANSLATE_GPA: usize = 39;
+    // a0: address of a `TsmStats`, a1: its size
+    pub const SBI_COVH_GET_TSM_STATS: usize = 40;
+    // Debug: a0: address of a `TsmMemoryStats`, a1: its size. Only with
+    // SHADOWFAX_TSM_CAP_TRANSLATE_GPA
+    pub const SBI_COVH_GET_TSM_MEMORY_STATS: usize = 41;
+    // a0: tvm_id, a1: address of a sealed image (see `sealed_image`), a2: its size, a3: destination
+    // confidential pages, a4: number of pages
+    pub const SBI_COVH_ADD_TVM_SEALED_IMAGE: usize = 42;
+    // a0: tvm_id, a1: vcpu_id, a2: hart id. The vCPU only runs on that physical hart from now on:
+    // a RUN_TVM_VCPU from another hart fails with SBI_ERR_DENIED. TVM_VCPU_ANY_HART unpins it
+    pub const SBI_COVH_SET_TVM_VCPU_AFFINITY: usize = 43;
+    // a0: address of the import offer, a1: its size (`MIGRATION_OFFER_SIZE`). The TSM makes a new
+    // exchange key for the next SBI_COVH_IMPORT_TVM and writes its offer
+    pub const SBI_COVH_PREPARE_TVM_IMPORT: usize = 44;
+    /// Highest COVH fid: the calls above it are unknown
+    pub const SBI_COVH_MAX_FID: usize = SBI_COVH_PREPARE_TVM_IMPORT;
+    /// Hart of `SBI_COVH_SET_TVM_VCPU_AFFINITY` which lets the vCPU run on any hart
+    pub const TVM_VCPU_ANY_HART: usize = usize::MAX;
+
+    /// Maximum number of entries of a `SBI_COVH_PROCESS_QUEUE` queue
+    pub const COVH_QUEUE_MAX_ENTRIES: usize = 256;
+
+    /// Entry of the command queue passed to `SBI_COVH_PROCESS_QUEUE`: a COVH call (`fid` without
+    /// SDID, a0-a5) filled by the host, and its result (`error` and `value`, as a0 and a1) written
+    /// back by the TSM once the call is executed.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct CovhQueueEntry {
+        pub fid: usize,
+        pub args: [usize; 6],
+        pub error: isize,
+        pub value: isize,
+    }
+
+    /// Whether the COVH function `fid` can be queued. The calls the TSM-driver acts on (GET_TSM_INFO,
+    /// page conversion and shared pages) and RUN_TVM_VCPU, which does not return, are issued alone.
+    pub const fn covh_queueable(fid: usize) -> bool {
+        matches!(
+            fid,
+            SBI_COVH_CREATE_TVM
+                | SBI_COVH_FINALIZE_TVM
+                | SBI_COVH_DESTROY_TVM
+                | SBI_COVH_ADD_TVM_MEMORY_REGION
+                | SBI_COVH_ADD_TVM_MEASURED_PAGES
+                | SBI_COVH_ADD_ZERO_PAGES
+                | SBI_COVH_CREATE_TVM_VCPU
+                | SBI_COVH_ADD_TVM_MEASURED_PAGES_BATCH
+                | SBI_COVH_EXPORT_TVM
+                | SBI_COVH_IMPORT_TVM
+                | SBI_COVH_TVM_VCPU_SET_TIMER_FREQUENCY
+                | SBI_COVH_SET_TVM_BOOT_INFO
+                | SBI_COVH_SET_TVM_VCPU_AFFINITY
+                | SBI_COVH_PREPARE_TVM_IMPORT
+        )
+    }
+
+    // SUPD constants
+    pub const SBI_SUPD_EXT_ID: usize = 0x53555044;
+    // a0 selects the word of the bitmap: bits of the domains [a0 * XLEN, (a0 + 1) * XLEN)
+    pub const SBI_EXT_SUPD_GET_ACTIVE_DOMAINS: usize = 0;
+    // Shadowfax specific FIDs, handled by the TSM-driver
+    pub const SBI_EXT_SUPD_GRANT_DMA_REGION: usize = 32;
+    pub const SBI_EXT_SUPD_REVOKE_DMA_REGION: usize = 33;
+    pub const SBI_EXT_SUPD_GET_RANDOM: usize = 34;
+    // a0: address of the SUPD_COUNTER_ID_SIZE-byte id of the counter, in the memory of the
+    // caller. Only for the domains running a TSM, each domain has its own counters
+    pub const SBI_EXT_SUPD_READ_COUNTER: usize = 35;
+    pub const SBI_EXT_SUPD_INCREMENT_COUNTER: usize = 36;
+    pub const SBI_EXT_SUPD_GET_TIME: usize = 37;
+    pub const SBI_EXT_SUPD_READ_AUDIT_LOG: usize = 38;
+    pub const SBI_EXT_SUPD_GET_AUDIT_MEASUREMENT: usize = 39;
+    pub const SBI_EXT_SUPD_GET_ACCESS_FAULTS: usize = 40;
+    // Debug: value of the firmware heap statistic a0 (see `heap::HEAP_STAT_*`)
+    pub const SBI_EXT_SUPD_GET_HEAP_STAT: usize = 41;
+    // a0: address of the blob id, a1: address of the data, a2: size (0 removes the blob)
+    pub const SBI_EXT_SUPD_STORE_BLOB: usize = 42;
+    // a0: address of the blob id, a1: address of the buffer, a2: size. Returns the blob size
+    pub const SBI_EXT_SUPD_LOAD_BLOB: usize = 43;
+    // a0: address of the label, a1: label size, a2: address of the 32-byte key. Key ladder of the
+    // platform CDI, separated per caller domain
+    pub const SBI_EXT_SUPD_DERIVE_KEY: usize = 44;
+    // Debug: run the isolation self-test of the PMP sets of the domains. Returns the bitmap of the
+    // failed targets (see `selftest.rs` in the firmware)
+    pub const SBI_EXT_SUPD_SELF_TEST: usize = 45;
+    // a0: domain id. The caller offers its trust to a0, which accepts it with ACCEPT_TRUST: then
+    // both trust each other (they can TEECALL each other). REVOKE_TRUST undoes it, from either side
+    pub const SBI_EXT_SUPD_OFFER_TRUST: usize = 46;
+    pub const SBI_EXT_SUPD_ACCEPT_TRUST: usize = 47;
+    pub const SBI_EXT_SUPD_REVOKE_TRUST: usize = 48;
+    // a0: address of an array of `SnapshotRange`, a1: number of ranges. A domain without TSM saves
+    // its context and these ranges of its memory in the firmware (see `snapshot.rs` there)
+    pub const SBI_EXT_SUPD_SNAPSHOT_DOMAIN: usize = 49;
+    // Restore the snapshot of the caller, which resumes after its SNAPSHOT_DOMAIN call with 1 in a1
+    pub const SBI_EXT_SUPD_RESTORE_DOMAIN: usize = 50;
+    // a0: domain id, a1: address of the buffer, a2: size. Writes the `SupdDomainInfo` of a0, so
+    // that the caller picks a confidential domain before asking its TSM. Returns the size written
+    pub const SBI_EXT_SUPD_GET_DOMAIN_INFO: usize = 51;
+    // Test builds: a0: fault of the TEE switch path to inject (`SUPD_FAULT_*`), 0 disarms. Returns
+    // the error the firmware answered the last fault with (see `inject.rs` in the firmware)
+    pub const SBI_EXT_SUPD_INJECT_FAULT: usize = 52;
+    // a0: domain id, a1: address of the buffer, a2: size. Writes the `SupdDomainStats` of a0.
+    // Returns the size written
+    pub const SBI_EXT_SUPD_GET_DOMAIN_STATS: usize = 53;
+    // Called by a ready TSM when its answer to GET_TSM_INFO changes (its status dropped): the
+    // TSM-driver, which answers GET_TSM_INFO from the last answer of the TSM, asks it again
+    pub const SBI_EXT_SUPD_TSM_INFO_CHANGED: usize = 54;
+    // Debug builds: a0: hart id, a1: address of the buffer, a2: size, a3: first sequence number.
+    // Copies the `SupdTraceEntry`s of the trace ring of a0 from a3 on, as many as fit. Returns the
+    // number of entries copied (see `trace.rs` in the firmware)
+    pub const SBI_EXT_SUPD_READ_TRACE: usize = 55;
+    // Returns the id of the hart of the caller in a1: a TSM does not learn it otherwise
+    pub const SBI_EXT_SUPD_GET_HART_ID: usize = 56;
+    // a0: address of the id of a counter of the caller. The counter reads zero again and its entry
+    // is free for other counters. Only for the domains running a TSM
+    pub const SBI_EXT_SUPD_FREE_COUNTER: usize = 57;
+    pub const SUPD_FAULT_DROP_TEERET: usize = 1;
+    pub const SUPD_FAULT_CORRUPT_TEERET: usize = 2;
+    pub const SUPD_FAULT_SPURIOUS_TEECALL: usize = 3;
+    pub const SUPD_FAULT_PMP_FAILURE: usize = 4;
+    pub const SUPD_SNAPSHOT_MAX_RANGES: usize = 8;
+    // Blobs kept by the TSM-driver storage, per caller domain
+    pub const SUPD_BLOB_ID_SIZE: usize = 32;
+    pub const SUPD_BLOB_MAX_SIZE: usize = 4032;
+    // Monotonic counters held by the TSM-driver, for all the domains, and by a single domain
+    pub const SUPD_NUM_COUNTERS: usize = 64;
+    pub const SUPD_COUNTERS_PER_DOMAIN: usize = 32;
+    pub const SUPD_COUNTER_ID_SIZE: usize = 32;
+
+    // CoVI constants
+    pub const SBI_COVI_EXT_ID: usize = 0x434F5649;
+
+    pub const SBI_COVI_INIT_TVM_AIA: usize = 0;
+    pub const SBI_COVI_SET_TVM_AIA_CPU_IMSIC_ADDR: usize = 1;
+    pub const SBI_COVI_CONVERT_AIA_IMSIC: usize = 2;
+    pub const SBI_COVI_RECLAIM_AIA_IMSIC: usize = 3;
+    pub const SBI_COVI_BIND_AIA_IMSIC: usize = 4;
+    pub const SBI_COVI_UNBIND_AIA_IMSIC_BEGIN: usize = 5;
+    pub const SBI_COVI_UNBIND_AIA_IMSIC_END: usize = 6;
+    pub const SBI_COVI_INJECT_TVM_CPU_INTERRUPT: usize = 7;
+    // Shadowfax specific: host interrupt sources routed to a TVM vCPU by the TSM
+    pub const SBI_COVI_BIND_TVM_INTERRUPT: usize = 32;
+    pub const SBI_COVI_UNBIND_TVM_INTERRUPT: usize = 33;
+    pub const SBI_COVI_SIGNAL_TVM_INTERRUPT: usize = 34;
+
+    // TsmInfo tsm_status values
+    pub const TSM_STATUS_NOT_LOADED: u32 = 0;
+    pub const TSM_STATUS_LOADED: u32 = 1;
+    pub const TSM_STATUS_READY: u32 = 2;
+    // Shadowfax specific: the TSM-driver watchdog stopped a TEECALL, the TSM takes no more calls
+    pub const TSM_STATUS_FAULTED: u32 = 3;
+
+    // TsmInfo capability bits
+    pub const COVE_TSM_CAP_PROMOTE_TVM: usize = 0;
+    pub const COVE_TSM_CAP_ATTESTATION_LOCAL: usize = 1;
+    pub const COVE_TSM_CAP_ATTESTATION_REMOTE: usize = 2;
+    pub const COVE_TSM_CAP_AIA: usize = 3;
+    pub const COVE_TSM_CAP_MRIF: usize = 4;
+    pub const COVE_TSM_CAP_MEMORY_ALLOCATION: usize = 5;
+    // Shadowfax specific: TVM pages can be kept encrypted in host memory
+    pub const SHADOWFAX_TSM_CAP_SW_PAGE_ENCRYPTION: usize = 16;
+    // Shadowfax specific: no hypervisor extension, the TSM runs a single confidential payload in
+    // S-mode instead of TVMs
+    pub const SHADOWFAX_TSM_CAP_DOMAIN_MODE: usize = 17;
+    // Shadowfax specific: debug builds of the TSM, SBI_COVH_TVM_TRANSLATE_GPA and
+    // SBI_COVH_GET_TSM_MEMORY_STATS are available
+    pub const SHADOWFAX_TSM_CAP_TRANSLATE_GPA: usize = 18;
+
+    /// Written by `SBI_COVH_GET_TSM_INFO`. While the TSM is not ready the TSM-driver answers
+    /// itself with `tsm_status` alone, the rest zeroed.
+    #[repr(C)]
+    #[derive(Clone, Debug)]
+    pub struct TsmInfo {
+        /// `TSM_STATUS_*`
+        pub tsm_status: u32,
+        pub tsm_impl_id: u32,
+        pub tsm_version: u32,
+        pub _padding: u32,
+        /// `COVE_TSM_CAP_*` and `SHADOWFAX_TSM_CAP_*` bits
+        pub tsm_capabilities: usize,
+        /// Pages of the state the TSM keeps for a TVM, and for each of its vCPUs
+        pub tvm_state_pages: usize,
+        pub tvm_max_vcpus: usize,
+        pub tvm_vcpu_state_pages: usize,
+    }
+
+    /// `tsm_impl_id` of the shadowfax TSM, the only one the TSM-driver boots
+    pub const SHADOWFAX_TSM_IMPL_ID: u32 = 0x45;
+
+    /// Size of the digests of `TsmIdentity` (SHA-512)
+    pub const TSM_DIGEST_SIZE: usize = 64;
+    /// Size of the build id of the TSM, a git commit hash in hex
+    pub const TSM_BUILD_ID_SIZE: usize = 40;
+
+    /// Identity of the TSM binary, established by the TSM-driver when it verifies the signature.
+    /// Handed to the TSM at boot, which reports it with GET_TSM_INFO.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug)]
+    pub struct TsmIdentity {
+        /// SHA-512 of the TSM binary, the TSM measurement of the attestation chain
+        pub measurement: [u8; TSM_DIGEST_SIZE],
+        /// SHA-512 of the Ed25519 public key which verified the TSM signature
+        pub signer_key_id: [u8; TSM_DIGEST_SIZE],
+    }
+
+    impl TsmIdentity {
+        /// Identity of a TSM not booted by the TSM-driver (tests)
+        pub const UNKNOWN: Self = Self {
+            measurement: [0; TSM_DIGEST_SIZE],
+            signer_key_id: [0; TSM_DIGEST_SIZE],
+        };
+    }
+
+    // `SupdDomainInfo` flags
+    pub const SUPD_DOMAIN_HAS_TSM: u32 = 1 << 0;
+    // The TSM completed its initialization and takes TEECALLs
+    pub const SUPD_DOMAIN_TSM_READY: u32 = 1 << 1;
+    // The TSM has an attestation context derived from the platform one
+    pub const SUPD_DOMAIN_ATTESTATION: u32 = 1 << 2;
+    // The TSM was stopped by the watchdog
+    pub const SUPD_DOMAIN_TSM_FAULTED: u32 = 1 << 3;
+
+    /// Written by `SBI_EXT_SUPD_GET_DOMAIN_INFO`. The digests are zero when the flags do not
+    /// vouch for them.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug)]
+    pub struct SupdDomainInfo {
+        /// `SUPD_DOMAIN_*`
+        pub flags: u32,
+        /// `tsm_impl_id` of the TSM, as reported by GET_TSM_INFO
+        pub tsm_impl_id: u32,
+        pub tsm: TsmIdentity,
+        /// SHA-512 of the platform public key the DICE chain of the TSM roots to
+        pub attestation_root: [u8; TSM_DIGEST_SIZE],
+    }
+
+    /// Event counters of a domain since boot, written by `SBI_EXT_SUPD_GET_DOMAIN_STATS`.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct SupdDomainStats {
+        /// TEECALLs made by the domain
+        pub teecalls: u64,
+        /// TEERETs made by the domain
+        pub teerets: u64,
+        /// Pages the domain converted to confidential memory, and reclaimed
+        pub pages_converted: u64,
+        pub pages_reclaimed: u64,
+        /// Times the PMP was programmed with the regions of the domain
+        pub pmp_reprograms: u64,
+        /// Access faults (PMP violations), as reported by GET_ACCESS_FAULTS
+        pub access_faults: u64,
+    }
+
+    // `SupdTraceEntry` events
+    pub const SUPD_TRACE_TEECALL: u32 = 1;
+    pub const SUPD_TRACE_TEERET: u32 = 2;
+    pub const SUPD_TRACE_SUPD_CALL: u32 = 3;
+
+    /// TEECALL, TEERET or SUPD call seen by the TSM-driver, written by `SBI_EXT_SUPD_READ_TRACE`.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct SupdTraceEntry {
+        /// Position of the entry in the trace of its hart, from 0
+        pub seq: u64,
+        /// mtime when the call was handled
+        pub timestamp: u64,
+        /// `SUPD_TRACE_*`
+        pub event: u32,
+        pub hartid: u32,
+        /// Caller, and the domain it called (TEECALL) or returned to (TEERET). Both are the
+        /// caller for a SUPD call.
+        pub src_domain: u32,
+        pub dst_domain: u32,
+        pub eid: u64,
+        pub fid: u64,
+        /// SBI error of the call, 0 if it succeeded. For a TEERET, the a0 the callee returned.
+        pub error: i64,
+    }
+
+    // TVM creation policy flags, folded into the TVM measurement
+    pub const TVM_POLICY_SW_PAGE_ENCRYPTION: usize = 1 << 0;
+    // The host can export the TVM (SBI_COVH_EXPORT_TVM)
+    pub const TVM_POLICY_MIGRATABLE: usize = 1 << 1;
+    // The host can add shared pages to the TVM
+    pub const TVM_POLICY_SHARED_PAGES: usize = 1 << 2;
+    // Debug: the TVM console reaches the host (COVG_CONSOLE_NOTIFY)
+    pub const TVM_POLICY_DEBUG: usize = 1 << 3;
+    // The TVM can get its evidence as a certificate chain (COVG_GET_CERT_CHAIN) besides
+    // COVG_GET_EVIDENCE
+    pub const TVM_POLICY_CERT_CHAIN: usize = 1 << 4;
+    pub const TVM_POLICY_MASK: usize = (1 << 5) - 1;
+    // Policy of the TVMs created without a policy in their params
+    pub const TVM_POLICY_DEFAULT: usize =
+        TVM_POLICY_MIGRATABLE | TVM_POLICY_SHARED_PAGES | TVM_POLICY_DEBUG | TVM_POLICY_CERT_CHAIN;
+
+    // CoVG constants
+    pub const COVG_EXTENSION: usize = 0x434F5647;
+    pub const COVG_GET_EVIDENCE: usize = 8;
+    // Shadowfax specific
+    pub const COVG_GET_RANDOM: usize = 32;
+    pub const COVG_READ_COUNTER: usize = 33;
+    pub const COVG_INCREMENT_COUNTER: usize = 34;
+    pub const COVG_GET_TIME: usize = 35;
+    // a0: GPA of the ConsoleRing, in shared pages. Exits to the host with TVM_EXIT_CONSOLE
+    pub const COVG_CONSOLE_NOTIFY: usize = 36;
+    // a0: slot, a1: GPA of the data, a2: size. The blob is sealed to the TVM measurement and
+    // persisted by the TSM-driver, size 0 removes it
+    pub const COVG_STORE_BLOB: usize = 37;
+    // a0: slot, a1: GPA of the buffer, a2: size. Returns the size of the blob
+    pub const COVG_LOAD_BLOB: usize = 38;
+    // Sealed blobs available to each TVM, of COVG_BLOB_MAX_SIZE bytes at most
+    pub const COVG_NUM_BLOB_SLOTS: usize = 4;
+    pub const COVG_BLOB_MAX_SIZE: usize = 2048;
+    // a0: GPA of the label, a1: label size, a2: GPA of the 32-byte key. Key ladder of the TVM CDI
+    pub const COVG_DERIVE_KEY: usize = 39;
+    // a0: GPA of the buffer, a1: size. Writes the CBOR array [platform token, TSM token, TVM token]
+    // certifying the TVM key, returns its size
+    pub const COVG_GET_CERT_CHAIN: usize = 40;
+    // a0: GPA of a `TvmInfo`, in a single page, a1: its size
+    pub const COVG_GET_TVM_INFO: usize = 41;
+    // a0: GPA, a1: size of a range of shared pages, within one ADD_TVM_SHARED_PAGES range. The TSM
+    // zeroes the pages, unmaps them from the TVM and exits to the host with TVM_EXIT_UNSHARE: the
+    // GPAs are no longer shared, the host cannot reach the TVM through them
+    pub const COVG_UNSHARE_MEMORY: usize = 42;
+    // a0: counter index. Gives up a counter of the TVM, which reads zero again: the TVM drops the
+    // state the counter protects first
+    pub const COVG_FREE_COUNTER: usize = 43;
+    // Monotonic counters available to each TVM
+    pub const COVG_NUM_COUNTERS: usize = 8;
+    // Shared ranges listed in a `TvmInfo`
+    pub const COVG_TVM_INFO_MAX_SHARED: usize = 16;
+
+    pub const PAGE_SIZE: usize = 4096;
+
+    // Exit reasons returned in a1 by sbi_covh_run_tvm_vcpu (bits [11:0])
+    pub const TVM_EXIT_REASON_MASK: usize = 0xfff;
+    // The console ring has data, the GPA of the ring is in bits [63:12]
+    pub const TVM_EXIT_CONSOLE: usize = 1;
+    // The vCPU executed WFI and is idle until an interrupt is pending for it. It resumes after the
+    // WFI, whenever the host runs it again
+    pub const TVM_EXIT_WFI: usize = 2;
+    // HSM of the guest, the id of the vCPU concerned is in bits [63:12] (TVM_EXIT_VCPU_SHIFT).
+    // HSM_START: the guest started the vCPU, the host can run it. HSM_STOP: the calling vCPU
+    // stopped, RUN_TVM_VCPU fails on it until it is started again. HSM_SUSPEND: the calling vCPU
+    // suspended, it resumes whenever the host runs it again
+    pub const TVM_EXIT_HSM_START: usize = 3;
+    pub const TVM_EXIT_HSM_STOP: usize = 4;
+    pub const TVM_EXIT_HSM_SUSPEND: usize = 5;
+    pub const TVM_EXIT_VCPU_SHIFT: usize = 12;
+    // The TVM unshared a range of its shared pages (COVG_UNSHARE_MEMORY): the host drops its
+    // mappings of the range. The GPA of the range is in bits [40:12], its number of pages in bits
+    // [63:41] (TVM_EXIT_UNSHARE_PAGES_SHIFT), see `tvm_exit_unshare`
+    pub const TVM_EXIT_UNSHARE: usize = 6;
+    pub const TVM_EXIT_UNSHARE_PAGES_SHIFT: u32 = 41;
+    // The TSM stopped the TVM: a page sealed in host memory (TVM_POLICY_SW_PAGE_ENCRYPTION) failed
+    // authentication. RUN_TVM_VCPU fails from now on, the host can only destroy the TVM
+    pub const TVM_EXIT_TAMPERED: usize = 7;
+
+    /// TVM_EXIT_UNSHARE of the `num_pages` pages at `gpa`, which must be below 2 TiB (SV39x4).
+    pub const fn tvm_exit_unshare(gpa: usize, num_pages: usize) -> usize {
+        TVM_EXIT_UNSHARE | gpa | (num_pages << TVM_EXIT_UNSHARE_PAGES_SHIFT)
+    }
+
+    /// GPA and number of pages of the range of the TVM_EXIT_UNSHARE `exit`.
+    pub const fn unshared_range(exit: usize) -> (usize, usize) {
+        let gpa_mask = (1 << TVM_EXIT_UNSHARE_PAGES_SHIFT) - 1;
+        (
+            exit & gpa_mask & !TVM_EXIT_REASON_MASK,
+            exit >> TVM_EXIT_UNSHARE_PAGES_SHIFT,
+        )
+    }
+
+    // Shadowfax specific: sbi_covh_run_tvm_vcpu flags (a2)
+    // The TSM keeps the hart on the exits it can resolve without the host, a WFI with an interrupt
+    // already pending for the vCPU is not reported
+    pub const TVM_RUN_FAST_PATH: usize = 1 << 0;
+    pub const TVM_RUN_FLAGS_MASK: usize = (1 << 1) - 1;
+
+    pub const CONSOLE_RING_SIZE: usize = PAGE_SIZE;
+    pub const CONSOLE_RING_DATA_SIZE: usize = CONSOLE_RING_SIZE - 8;
+
+    /// Console of a TVM, in a page shared with the host. The guest writes the bytes at `head` and
+    /// notifies the host with `COVG_CONSOLE_NOTIFY`; the host prints the bytes up to `head` and
+    /// moves `tail`. Both indexes wrap around and are taken modulo `CONSOLE_RING_DATA_SIZE`.
+    #[repr(C)]
+    pub struct ConsoleRing {
+        pub head: u32,
+        pub tail: u32,
+        pub data: [u8; CONSOLE_RING_DATA_SIZE],
+    }
+
+    /// Entry of the list passed to `SBI_COVH_ADD_TVM_MEASURED_PAGES_BATCH`. Each entry describes
+    /// a single 4K page.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct MeasuredPageDesc {
+        pub source_addr: usize,
+        pub dest_addr: usize,
+        pub tvm_guest_gpa: usize,
+    }
+
+    /// Memory range saved by `SBI_EXT_SUPD_SNAPSHOT_DOMAIN`.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct SnapshotRange {
+        pub base_addr: usize,
+        pub size: usize,
+    }
+
+    pub const TVM_BOOT_INFO_MAGIC: u64 = u64::from_le_bytes(*b"SFXBOOT\0");
+    pub const TVM_BOOT_INFO_VERSION: u32 = 2;
+    pub const TVM_BOOT_INFO_MAX_REGIONS: usize = 16;
+
+    /// Boot information generated by the TSM at finalize and mapped read-only in the TVM. Unlike
+    /// the device tree passed by the host, this page is part of the TVM measurement.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug)]
+    pub struct TvmBootInfo {
+        pub magic: u64,
+        pub version: u32,
+        pub num_vcpus: u32,
+        /// Zero if the host did not set it
+        pub timebase_frequency: u64,
+        pub measurement_alg: u32,
+        pub num_memory_regions: u32,
+        /// Measurement of the TVM pages, before this page was measured
+        pub initial_measurement: [u8; 64],
+        pub memory_regions: [TvmBootMemoryRegion; TVM_BOOT_INFO_MAX_REGIONS],
+        /// Device tree of the TVM (vCPUs, timebase, memory regions), at this offset of the page
+        pub fdt_offset: u32,
+        pub fdt_size: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct TvmBootMemoryRegion {
+        pub gpa_base: u64,
+        pub size: u64,
+    }
+
+    /// Configuration of the calling TVM, written by `COVG_GET_TVM_INFO`. The guest checks what it
+    /// was launched with without asking the host.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug)]
+    pub struct TvmInfo {
+        /// `MEASUREMENT_ALG_*` of `measurement`
+        pub measurement_alg: u32,
+        pub num_vcpus: u32,
+        /// `TVM_POLICY_*`
+        pub policy: u64,
+        /// Launch measurement, the digest is zero-padded
+        pub measurement: [u8; 64],
+        /// Number of shared ranges, only the first `COVG_TVM_INFO_MAX_SHARED` are listed
+        pub num_shared_regions: u32,
+        pub reserved: u32,
+        pub shared_regions: [TvmBootMemoryRegion; COVG_TVM_INFO_MAX_SHARED],
+    }
+
+    /// Exit reasons counted by `TsmStats`, `TVM_EXIT_*` is the index
+    pub const TSM_STATS_EXITS: usize = 8;
+
+    /// Event counters of the TSM since boot, written by `SBI_COVH_GET_TSM_STATS`.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct TsmStats {
+        /// TEECALLs served, a queue counts once
+        pub teecalls: u64,
+        /// Pages converted to confidential memory and reclaimed by the host
+        pub pages_converted: u64,
+        pub pages_reclaimed: u64,
+        /// Pages added to the TVMs, by kind
+        pub measured_pages: u64,
+        pub zero_pages: u64,
+        pub shared_pages: u64,
+        /// Guest page faults resolved by the TSM
+        pub guest_page_faults: u64,
+        /// SBI calls of the guests, handled or forwarded by the TSM
+        pub guest_calls: u64,
+        /// Exits of the vCPUs to the host, by `TVM_EXIT_*` reason
+        pub exits: [u64; TSM_STATS_EXITS],
+    }
+
+    /// Stack and heap usage of the TSM, written by `SBI_COVH_GET_TSM_MEMORY_STATS`.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct TsmMemoryStats {
+        /// Bytes of the stack of the TSM, and the deepest use since `_secure_init`
+        pub stack_size: u64,
+        pub stack_peak: u64,
+        /// Nonzero once the canary at the bottom of the stack was overwritten
+        pub stack_overflow: u64,
+        /// Bytes of the heap, in use (allocator metadata included) and at the peak
+        pub heap_size: u64,
+        pub heap_used: u64,
+        pub heap_peak: u64,
+        /// Allocations which failed
+        pub heap_failures: u64,
+    }
+
+    /// Time spent running a vCPU, written by `SBI_COVH_GET_TVM_VCPU_TIME`. The host cannot see
+    /// inside the TVM: this is what it can bill, and how it can spot a vCPU which keeps the hart.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct TvmVcpuTime {
+        /// `time` ticks between the entries in the vCPU and its exits to the host, the calls the
+        /// TSM handles for the guest included
+        pub guest_time: u64,
+        /// `cycle` over the same intervals
+        pub guest_cycles: u64,
+        /// Number of RUN_TVM_VCPU which entered the vCPU
+        pub runs: u64,
+        /// Longest run, in `time` ticks
+        pub longest_run: u64,
+    }
+
+    /// G-stage mapping of a GPA, written by `SBI_COVH_TVM_TRANSLATE_GPA`.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct TvmGpaTranslation {
+        /// `TVM_GPA_*` flags, zero if the GPA is not mapped
+        pub flags: u64,
+        /// Backing physical address, only reported for the TVMs with `TVM_POLICY_DEBUG`
+        pub pa: u64,
+    }
+
+    // TvmGpaTranslation flags: permissions, and how the page was added (bits [9:8])
+    pub const TVM_GPA_MAPPED: u64 = 1 << 0;
+    pub const TVM_GPA_READ: u64 = 1 << 1;
+    pub const TVM_GPA_WRITE: u64 = 1 << 2;
+    pub const TVM_GPA_EXEC: u64 = 1 << 3;
+    pub const TVM_GPA_KIND_MASK: u64 = 3 << 8;
+    // Loaded by the TSM on a fault (lazy ELF loading, sealed pages) or an IMSIC guest file
+    pub const TVM_GPA_KIND_OTHER: u64 = 0;
+    pub const TVM_GPA_KIND_MEASURED: u64 = 1 << 8;
+    pub const TVM_GPA_KIND_ZERO: u64 = 2 << 8;
+    pub const TVM_GPA_KIND_SHARED: u64 = 3 << 8;
+
+    /// Parameters of `sbi_covi_init_tvm_aia` describing the IMSIC layout seen by the TVM.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug)]
+    pub struct TvmAiaParams {
+        pub imsic_base_addr: u64,
+        pub group_index_bits: u32,
+        pub group_index_shift: u32,
+        pub hart_index_bits: u32,
+        pub guest_index_bits: u32,
+        pub guests_per_hart: u32,
+    }
+
+    /// Supervisor-level IMSIC discovered by the firmware and handed to the TSM at boot.
+    /// The interrupt files of a hart are `1 << guest_index_bits` pages: page 0 is the S-level
+    /// file, the following ones are the guest interrupt files.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug)]
+    pub struct ImsicInfo {
+        pub base_addr: usize,
+        pub size: usize,
+        pub guest_index_bits: usize,
+        pub num_ids: usize,
+    }
+
+    impl ImsicInfo {
+        /// Returns the guest index (the value to program in `hstatus.VGEIN`) of the guest
+        /// interrupt file at `addr`, or `None` if `addr` is not a guest interrupt file.
+        pub fn guest_file_index(&self, addr: usize) -> Option<usize> {
+            if !addr.is_multiple_of(PAGE_SIZE)
+                || addr < self.base_addr
+                || addr >= self.base_addr + self.size
+            {
+                return None;
+            }
+
+            let index = ((addr - self.base_addr) / PAGE_SIZE) & ((1 << self.guest_index_bits) - 1);
+            if index == 0 { None } else { Some(index) }
+        }
+    }
+
+    #[repr(C)]
+    pub struct SbiRet {
+        pub a0: isize,
+        pub a1: isize,
+    }
+
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    pub fn sbi_call(extid: usize, fid: usize, args: &[usize; 6]) -> SbiRet {
+        let (a0, a1);
+        unsafe {
+            core::arch::asm!(
+                "ecall",
+                in("a7") extid,
+                in("a6") fid,
+                inlateout("a0") args[0] => a0,
+                inlateout("a1") args[1] => a1,
+                in("a2") args[2],
+                in("a3") args[3],
+                in("a4") args[4],
+                in("a5") args[5],
+            );
+        }
+        SbiRet { a0, a1 }
+    }
+
+    /// `sbi_probe_extension`: zero if the extension is not available, otherwise an extension
+    /// specific value.
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    pub fn sbi_probe_extension(extid: usize) -> usize {
+        let ret = sbi_call(
+            SBI_EXT_BASE,
+            SBI_EXT_BASE_PROBE_EXT,
+            &[extid, 0, 0, 0, 0, 0],
+        );
+        if ret.a0 != 0 { 0 } else { ret.a1 as usize }
+    }
+}
+
+pub mod config {
+    //! Build-time limits of the TSM-driver and the TSM. The defaults fit the QEMU demos, each one
+    //! can be overridden with an environment variable of the build (e.g.
+    //! `SHADOWFAX_MAX_DOMAINS=8 make`) to shrink the reservations on small platforms or to raise
+    //! them on large ones.
+    use crate::sbi::MAX_SUPERVISOR_DOMAINS;
+
+    /// Supervisor domains the TSM-driver keeps a context and per-domain state for
+    pub const MAX_DOMAINS: usize = parse(option_env!("SHADOWFAX_MAX_DOMAINS"), 64);
+    /// TVMs a TSM runs at the same time
+    pub const MAX_TVMS: usize = parse(option_env!("SHADOWFAX_MAX_TVMS"), 1);
+    /// vCPUs of a TVM, reported in `TsmInfo::tvm_max_vcpus`
+    pub const MAX_VCPUS_PER_TVM: usize = parse(option_env!("SHADOWFAX_MAX_VCPUS_PER_TVM"), 1);
+
+    // Root, TSM and untrusted domains always exist, and a domain id must fit in the SDID
+    const _: () = assert!(MAX_DOMAINS >= 3 && MAX_DOMAINS <= MAX_SUPERVISOR_DOMAINS);
+    const _: () = assert!(MAX_TVMS >= 1 && MAX_VCPUS_PER_TVM >= 1);
+
+    /// Decimal value of an environment variable, `default` if it is not set.
+    const fn parse(value: Option<&str>, default: usize) -> usize {
+        let Some(value) = value else {
+            return default;
+        };
+        let bytes = value.as_bytes();
+        assert!(!bytes.is_empty(), "empty shadowfax limit");
+        let mut n = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            assert!(
+                bytes[i].is_ascii_digit(),
+                "shadowfax limits must be decimal"
+            );
+            n = n * 10 + (bytes[i] - b'0') as usize;
+            i += 1;
+        }
+        n
+    }
+}
+
+pub mod pmp {
+    //! PMP address encoding (Privileged ISA, 3.7). A pmpaddr register holds bits [XLEN+1:2] of an
+    //! address: a NAPOT entry encodes a naturally aligned power-of-two region of at least 8 bytes,
+    //! its size in the trailing ones of the pmpaddr; a TOR entry encodes the top of its region,
+    //! whose base is the pmpaddr of the entry before. Every pmpaddr the firmware writes, to the
+    //! PMP or to the IOPMP, comes from a `Region`.
+
+    /// Address matching of a pmpcfg byte (its A field): top of range
+    pub const PMP_A_TOR: usize = 1 << 3;
+    /// Address matching of a pmpcfg byte (its A field): naturally aligned power-of-two region
+    pub const PMP_A_NAPOT: usize = 3 << 3;
+    /// Smallest NAPOT region: 8 bytes
+    pub const NAPOT_MIN_ORDER: u32 = 3;
+
+    /// Bytes `[base, base + size)`, never empty and never past the end of the address space.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct Region {
+        base: usize,
+        size: usize,
+    }
+
+    impl Region {
+        /// `[base, base + size)`, if `size` is not 0 and the region does not wrap around.
+        pub const fn new(base: usize, size: usize) -> Option<Self> {
+            if size == 0 || base.checked_add(size - 1).is_none() {
+                return None;
+            }
+            Some(Self { base, size })
+        }
+
+        /// NAPOT region of `2^order` bytes at `base`, if `order` is at least 3 and `base` is
+        /// aligned to the size.
+        pub const fn napot(base: usize, order: u32) -> Option<Self> {
+            if order < NAPOT_MIN_ORDER || order >= usize::BITS || base & ((1 << order) - 1) != 0 {
+                return None;
+            }
+            Self::new(base, 1 << order)
+        }
+
+        /// Smallest NAPOT region of at least `2^min_order` bytes covering `[addr, addr + size)`.
+        pub fn covering(addr: usize, size: usize, min_order: u32) -> Option<Self> {
+            let range = Self::new(addr, size)?;
+            let mut order = size
+                .checked_next_power_of_two()?
+                .trailing_zeros()
+                .max(min_order)
+                .max(NAPOT_MIN_ORDER);
+            while order < usize::BITS {
+                let region = Self::napot(addr & !((1 << order) - 1), order)?;
+                if region.contains_region(&range) {
+                    return Some(region);
+                }
+                order += 1;
+            }
+            None
+        }
+
+        /// Smallest set of NAPOT regions covering exactly `[base, base + size)`: each region is as
+        /// large as the alignment of its base and the bytes left allow. Nothing if the range
+        /// wraps around, or if `base` or `size` is not a multiple of 8 (no NAPOT region fits).
+        pub fn split(base: usize, size: usize) -> impl Iterator<Item = Self> {
+            let valid = base.is_multiple_of(1 << NAPOT_MIN_ORDER)
+                && size.is_multiple_of(1 << NAPOT_MIN_ORDER)
+                && Self::new(base, size).is_some();
+            let (mut base, mut left) = (base, if valid { size } else { 0 });
+            core::iter::from_fn(move || {
+                if left == 0 {
+                    return None;
+                }
+                let order = base
+                    .trailing_zeros()
+                    .min(usize::BITS - 1 - left.leading_zeros());
+                let region = Self {
+                    base,
+                    size: 1 << order,
+                };
+                base = base.wrapping_add(1 << order);
+                left -= 1 << order;
+                Some(region)
+            })
+        }
+
+        pub const fn base(&self) -> usize {
+            self.base
+        }
+
+        pub const fn size(&self) -> usize {
+            self.size
+        }
+
+        /// Last byte of the region
+        pub const fn last(&self) -> usize {
+            self.base + (self.size - 1)
+        }
+
+        /// log2 of the size, if the region can be a NAPOT entry.
+        pub const fn order(&self) -> Option<u32> {
+            let napot = self.size.is_power_of_two()
+                && self.size >= 1 << NAPOT_MIN_ORDER
+                && self.base & (self.size - 1) == 0;
+            if napot {
+                Some(self.size.trailing_zeros())
+            } else {
+                None
+            }
+        }
+
+        /// Whether `addr` is in the region.
+        pub const fn contains(&self, addr: usize) -> bool {
+            addr.wrapping_sub(self.base) < self.size
+        }
+
+        /// Whether every byte of `other` is in the region.
+        pub const fn contains_region(&self, other: &Self) -> bool {
+            other.base >= self.base && other.last() <= self.last()
+        }
+
+        /// pmpaddr of the region as a NAPOT entry, if it can be one.
+        pub const fn encode_napot(&self) -> Option<usize> {
+            match self.order() {
+                Some(_) => Some((self.base >> 2) | ((self.size >> NAPOT_MIN_ORDER) - 1)),
+                None => None,
+            }
+        }
+
+        /// Region matched by the NAPOT entry `pmpaddr`, if it fits in the address space.
+        pub const fn decode_napot(pmpaddr: usize) -> Option<Self> {
+            let ones = pmpaddr.trailing_ones();
+            let order = ones + NAPOT_MIN_ORDER;
+            if order >= usize::BITS {
+                return None;
+            }
+            let base = pmpaddr & !((1 << ones) - 1);
+            if base > usize::MAX >> 2 {
+                return None;
+            }
+            Self::napot(base << 2, order)
+        }
+
+        /// pmpaddr of the entry before and of the TOR entry matching the region, if its base and
+        /// size are multiples of 4. The entry before is not used if it is entry 0 and the base is 0.
+        pub const fn encode_tor(&self) -> Option<(usize, usize)> {
+            if !self.base.is_multiple_of(4) || !self.size.is_multiple_of(4) {
+                return None;
+            }
+            Some((self.base >> 2, (self.last() >> 2) + 1))
+        }
+
+        /// Region matched by a TOR entry `pmpaddr` after an entry `prev`, if it matches anything.
+        pub const fn decode_tor(prev: usize, pmpaddr: usize) -> Option<Self> {
+            if prev >= pmpaddr || prev > usize::MAX >> 2 {
+                return None;
+            }
+            match (pmpaddr - prev).checked_mul(4) {
+                Some(size) => Self::new(prev << 2, size),
+                None => None,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // Sizes and bases around the edges of the address space and of the NAPOT encoding
+        fn edge_values() -> impl Iterator<Item = usize> {
+            (0..usize::BITS).flat_map(|bit| {
+                let v = 1usize << bit;
+                [v - 1, v, v + 1, usize::MAX - v, usize::MAX - v + 1]
+            })
+        }
+
+        #[test]
+        fn napot_round_trip() {
+            for order in 0..=usize::BITS + 1 {
+                for base in edge_values() {
+                    let Some(region) = Region::napot(base, order) else {
+                        assert!(
+                            !(NAPOT_MIN_ORDER..usize::BITS).contains(&order)
+                                || !base.is_multiple_of(1 << order)
+                        );
+                        continue;
+                    };
+                    assert_eq!(region.order(), Some(order));
+                    let pmpaddr = region.encode_napot().unwrap();
+                    assert_eq!(pmpaddr.trailing_ones(), order - NAPOT_MIN_ORDER);
+                    assert_eq!(Region::decode_napot(pmpaddr), Some(region));
+                }
+            }
+
+            // Every pmpaddr decodes to a region which encodes back to it, unless the region would
+            // not fit in the address space
+            for pmpaddr in (0..1 << 16).chain((usize::MAX >> 2) - 0xFFFF..=usize::MAX >> 2) {
+                match Region::decode_napot(pmpaddr) {
+                    Some(region) => assert_eq!(region.encode_napot(), Some(pmpaddr)),
+                    None => assert!(pmpaddr.trailing_ones() + NAPOT_MIN_ORDER >= usize::BITS),
+                }
+            }
+            assert_eq!(Region::decode_napot(usize::MAX >> 1), None);
+            assert_eq!(Region::decode_napot(1 << (usize::BITS - 1)), None);
+        }
+
+        #[test]
+        fn napot_rejects_other_regions() {
+            // Size 0, not a power of two, smaller than 8 bytes, misaligned
+            assert_eq!(Region::new(0x1000, 0), None);
+            assert_eq!(Region::new(0x1000, 0x3000).unwrap().encode_napot(), None);
+            assert_eq!(Region::new(0x1000, 4).unwrap().encode_napot(), None);
+            assert_eq!(Region::new(0x1000, 0x2000).unwrap().encode_napot(), None);
+            assert_eq!(Region::napot(0x1000, 2), None);
+            assert_eq!(Region::napot(0x1000, 13), None);
+            // Past the end of the address space
+            assert_eq!(Region::new(usize::MAX, 2), None);
+            assert!(Region::new(usize::MAX, 1).is_some());
+
+            // 64 MiB of TSM memory
+            let region = Region::napot(0x8800_0000, 26).unwrap();
+            assert_eq!(region.encode_napot(), Some(0x2200_0000 | ((1 << 23) - 1)));
+            // Smallest region: 8 bytes, no trailing ones
+            let region = Region::napot(0x1000_0000, 3).unwrap();
+            assert_eq!(region.encode_napot(), Some(0x1000_0000 >> 2));
+        }
+
+        #[test]
+        fn tor_round_trip() {
+            for base in edge_values() {
+                for size in edge_values() {
+                    let Some(region) = Region::new(base, size) else {
+                        continue;
+                    };
+                    let Some((prev, pmpaddr)) = region.encode_tor() else {
+                        assert!(!base.is_multiple_of(4) || !size.is_multiple_of(4));
+                        continue;
+                    };
+                    assert_eq!(Region::decode_tor(prev, pmpaddr), Some(region));
+                }
+            }
+
+            // An empty or inverted range matches nothing
+            assert_eq!(Region::decode_tor(0x400, 0x400), None);
+            assert_eq!(Region::decode_tor(0x800, 0x400), None);
+            // The whole address space is not a region
+            assert_eq!(Region::decode_tor(0, 1 << (usize::BITS - 2)), None);
+        }
+
+        #[test]
+        fn containment() {
+            for order in NAPOT_MIN_ORDER..usize::BITS {
+                for base in edge_values() {
+                    let Some(region) = Region::napot(base, order) else {
+                        continue;
+                    };
+                    assert!(region.contains(base));
+                    assert!(region.contains(region.last()));
+                    assert!(!region.contains(base.wrapping_sub(1)));
+                    assert!(!region.contains(region.last().wrapping_add(1)));
+                    assert!(region.contains_region(&region));
+                }
+            }
+        }
+
+        #[test]
+        fn split_covers_exactly() {
+            for base in (0..0x400).step_by(8) {
+                for size in (0..0x400).step_by(8) {
+                    let mut next = base;
+                    for region in Region::split(base, size) {
+                        assert_eq!(region.base(), next);
+                        assert!(region.encode_napot().is_some());
+                        next += region.size();
+                    }
+                    assert_eq!(next, base + size);
+                }
+            }
+
+            // No region smaller than 8 bytes, nor wrapping around
+            assert_eq!(Region::split(0x1004, 0x1000).count(), 0);
+            assert_eq!(Region::split(0x1000, 0x1004).count(), 0);
+            assert_eq!(Region::split(usize::MAX - 7, 16).count(), 0);
+            let top = Region::napot(usize::MAX - 0xFFF, 12).unwrap();
+            assert!(Region::split(usize::MAX - 0xFFF, 0x1000).eq([top]));
+        }
+
+        #[test]
+        fn covering_is_the_smallest() {
+            for addr in 0..0x200 {
+                for size in 1..0x100 {
+                    let range = Region::new(addr, size).unwrap();
+                    for min_order in [0, 3, 6] {
+                        let region = Region::covering(addr, size, min_order).unwrap();
+                        let order = region.order().unwrap();
+                        assert!(order >= min_order.max(NAPOT_MIN_ORDER));
+                        assert!(region.contains_region(&range));
+                        // Half the region does not cover the range
+                        if order > min_order.max(NAPOT_MIN_ORDER) {
+                            let half = Region::napot(addr & !((1 << (order - 1)) - 1), order - 1);
+                            assert!(!half.unwrap().contains_region(&range));
+                        }
+                    }
+                }
+            }
+
+            assert_eq!(Region::covering(0x1000, 0, 3), None);
+            assert_eq!(Region::covering(usize::MAX, 2, 3), None);
+        }
+    }
+}
+
+pub mod asm {
+    //! XLEN-independent building blocks for the context save/restore assembly. Templates are
+    //! assembled with `concat!`, e.g. `reg_store!(ra, 1(sp))` stores `ra` in the second XLEN-sized
+    //! slot at `sp` and `reg_load!(t0, "{offset}(a0)")` loads `t0` from an explicit address.
+
+    /// Store mnemonic for a XLEN-sized register.
+    #[cfg(target_pointer_width = "64")]
+    #[macro_export]
+    macro_rules! reg_s {
+        () => {
+            "sd"
+        };
+    }
+
+    /// Store mnemonic for a XLEN-sized register.
+    #[cfg(target_pointer_width = "32")]
+    #[macro_export]
+    macro_rules! reg_s {
+        () => {
+            "sw"
+        };
+    }
+
+    /// Load mnemonic for a XLEN-sized register.
+    #[cfg(target_pointer_width = "64")]
+    #[macro_export]
+    macro_rules! reg_l {
+        () => {
+            "ld"
+        };
+    }
+
+    /// Load mnemonic for a XLEN-sized register.
+    #[cfg(target_pointer_width = "32")]
+    #[macro_export]
+    macro_rules! reg_l {
+        () => {
+            "lw"
+        };
+    }
+
+    /// Size in bytes of a register, as a string to be used in assembly templates.
+    #[cfg(target_pointer_width = "64")]
+    #[macro_export]
+    macro_rules! regbytes {
+        () => {
+            "8"
+        };
+    }
+
+    /// Size in bytes of a register, as a string to be used in assembly templates.
+    #[cfg(target_pointer_width = "32")]
+    #[macro_export]
+    macro_rules! regbytes {
+        () => {
+            "4"
+        };
+    }
+
+    /// `.attribute arch` directive matching the base ISA of the target.
+    #[cfg(target_pointer_width = "64")]
+    #[macro_export]
+    macro_rules! arch_attribute {
+        () => {
+            ".attribute arch, \"rv64imac\"\n"
+        };
+    }
+
+    /// `.attribute arch` directive matching the base ISA of the target.
+    #[cfg(target_pointer_width = "32")]
+    #[macro_export]
+    macro_rules! arch_attribute {
+        () => {
+            ".attribute arch, \"rv32imac\"\n"
+        };
+    }
+
+    /// Store `$reg` at `$addr`, or in the `$slot`-th register-sized slot of `$base`.
+    #[macro_export]
+    macro_rules! reg_store {
+        ($reg:ident, $addr:literal) => {
+            concat!($crate::reg_s!(), " ", stringify!($reg), ", ", $addr, "\n")
+        };
+        ($reg:ident, $slot:literal($base:ident)) => {
+            concat!(
+                $crate::reg_s!(),
+                " ",
+                stringify!($reg),
+                ", ",
+                $slot,
+                "*",
+                $crate::regbytes!(),
+                "(",
+                stringify!($base),
+                ")\n"
+            )
+        };
+    }
+
+    /// Load `$reg` from `$addr`, or from the `$slot`-th register-sized slot of `$base`.
+    #[macro_export]
+    macro_rules! reg_load {
+        ($reg:ident, $addr:literal) => {
+            concat!($crate::reg_l!(), " ", stringify!($reg), ", ", $addr, "\n")
+        };
+        ($reg:ident, $slot:literal($base:ident)) => {
+            concat!(
+                $crate::reg_l!(),
+                " ",
+                stringify!($reg),
+                ", ",
+                $slot,
+                "*",
+                $crate::regbytes!(),
+                "(",
+                stringify!($base),
+                ")\n"
+            )
+        };
+    }
+}
+
+pub mod crypto {
+    //! Crypto backends of SHA-384 and SHA-512, used by the measurements, the digest of the signed
+    //! TSM image and HKDF. The software backend runs everywhere; on RV64 cores with the scalar
+    //! crypto extension Zknh (also part of Zkn and Zk) the SHA-512 compression uses its
+    //! instructions. The TSM-driver selects the backend at boot from the ISA in the device tree
+    //! (`select`) and hands the features over to the TSM.
+    //!
+    //! Ed25519 keeps the SHA-512 of `ed25519_compact`, and Zksed (SM4) has no user: none of the
+    //! algorithms of shadowfax is built on it.
+    use core::{
+        fmt,
+        marker::PhantomData,
+        slice::from_ref,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use sha2::digest::{
+        HashMarker, Output,
+        block_buffer::Eager,
+        core_api::{
+            AlgorithmName, Block, BlockSizeUser, Buffer, BufferKindUser, CoreWrapper,
+            FixedOutputCore, OutputSizeUser, Reset, UpdateCore,
+        },
+        generic_array::{ArrayLength, GenericArray},
+        typenum::{U48, U64, U128, Unsigned},
+    };
+
+    /// SHA-2 instructions of the scalar crypto extension (Zknh)
+    pub const CRYPTO_FEATURE_ZKNH: usize = 1 << 0;
+
+    static FEATURES: AtomicUsize = AtomicUsize::new(0);
+
+    pub trait CryptoBackend: Sync {
+        fn name(&self) -> &'static str;
+        /// SHA-512 compression function: process `blocks` into `state`.
+        fn sha512_compress(&self, state: &mut [u64; 8], blocks: &[GenericArray<u8, U128>]);
+    }
+
+    /// Features of an ISA extension, as named in the device tree.
+    pub fn extension_features(extension: &str) -> usize {
+        match extension {
+            "zknh" | "zkn" | "zk" => CRYPTO_FEATURE_ZKNH,
+            _ => 0,
+        }
+    }
+
+    /// Use the backend of `features`, the ones this hart cannot use are ignored.
+    pub fn select(features: usize) {
+        let supported = if cfg!(target_arch = "riscv64") {
+            CRYPTO_FEATURE_ZKNH
+        } else {
+            0
+        };
+        FEATURES.store(features & supported, Ordering::Relaxed);
+    }
+
+    /// Features of the selected backend.
+    pub fn features() -> usize {
+        FEATURES.load(Ordering::Relaxed)
+    }
+
+    pub fn backend() -> &'static dyn CryptoBackend {
+        #[cfg(target_arch = "riscv64")]
+        if features() & CRYPTO_FEATURE_ZKNH != 0 {
+            return &zknh::Zknh;
+        }
+        &Software
+    }
+
+    struct Software;
+
+    impl CryptoBackend for Software {
+        fn name(&self) -> &'static str {
+            "software"
+        }
+
+        fn sha512_compress(&self, state: &mut [u64; 8], blocks: &[GenericArray<u8, U128>]) {
+            sha2::compress512(state, blocks);
+        }
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    mod zknh {
+        use super::{CryptoBackend, GenericArray, U128};
+
+        macro_rules! zknh {
+            ($insn:literal, $x:expr) => {{
+                let r: u64;
+                unsafe {
+                    core::arch::asm!(
+                        ".option push",
+                        ".option arch, +zknh",
+                        concat!($insn, " {0}, {1}"),
+                        ".option pop",
+                        out(reg) r,
+                        in(reg) $x,
+                        options(pure, nomem, nostack),
+                    )
+                };
+                r
+            }};
+        }
+
+        pub struct Zknh;
+
+        impl CryptoBackend for Zknh {
+            fn name(&self) -> &'static str {
+                "zknh"
+            }
+
+            fn sha512_compress(&self, state: &mut [u64; 8], blocks: &[GenericArray<u8, U128>]) {
+                for block in blocks {
+                    compress_block(state, block);
+                }
+            }
+        }
+
+        fn compress_block(state: &mut [u64; 8], block: &[u8]) {
+            let mut w = [0u64; 80];
+            for (w, word) in w.iter_mut().zip(block.chunks_exact(8)) {
+                *w = u64::from_be_bytes(word.try_into().unwrap());
+            }
+            for t in 16..80 {
+                w[t] = zknh!("sha512sig1", w[t - 2])
+                    .wrapping_add(w[t - 7])
+                    .wrapping_add(zknh!("sha512sig0", w[t - 15]))
+                    .wrapping_add(w[t - 16]);
+            }
+
+            let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+            for t in 0..80 {
+                let t1 = h
+                    .wrapping_add(zknh!("sha512sum1", e))
+                    .wrapping_add((e & f) ^ (!e & g))
+                    .wrapping_add(K[t])
+                    .wrapping_add(w[t]);
+                let t2 = zknh!("sha512sum0", a).wrapping_add((a & b) ^ (a & c) ^ (b & c));
+                (h, g, f, e) = (g, f, e, d.wrapping_add(t1));
+                (d, c, b, a) = (c, b, a, t1.wrapping_add(t2));
+            }
+            for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+                *s = s.wrapping_add(v);
+            }
+        }
+
+        /// Round constants of SHA-512
+        #[rustfmt::skip]
+        const K: [u64; 80] = [
+            0x428a2f98d728ae22, 0x7137449123ef65cd, 0xb5c0fbcfec4d3b2f, 0xe9b5dba58189dbbc,
+            0x3956c25bf348b538, 0x59f111f1b605d019, 0x923f82a4af194f9b, 0xab1c5ed5da6d8118,
+            0xd807aa98a3030242, 0x12835b0145706fbe, 0x243185be4ee4b28c, 0x550c7dc3d5ffb4e2,
+            0x72be5d74f27b896f, 0x80deb1fe3b1696b1, 0x9bdc06a725c71235, 0xc19bf174cf692694,
+            0xe49b69c19ef14ad2, 0xefbe4786384f25e3, 0x0fc19dc68b8cd5b5, 0x240ca1cc77ac9c65,
+            0x2de92c6f592b0275, 0x4a7484aa6ea6e483, 0x5cb0a9dcbd41fbd4, 0x76f988da831153b5,
+            0x983e5152ee66dfab, 0xa831c66d2db43210, 0xb00327c898fb213f, 0xbf597fc7beef0ee4,
+            0xc6e00bf33da88fc2, 0xd5a79147930aa725, 0x06ca6351e003826f, 0x142929670a0e6e70,
+            0x27b70a8546d22ffc, 0x2e1b21385c26c926, 0x4d2c6dfc5ac42aed, 0x53380d139d95b3df,
+            0x650a73548baf63de, 0x766a0abb3c77b2a8, 0x81c2c92e47edaee6, 0x92722c851482353b,
+            0xa2bfe8a14cf10364, 0xa81a664bbc423001, 0xc24b8b70d0f89791, 0xc76c51a30654be30,
+            0xd192e819d6ef5218, 0xd69906245565a910, 0xf40e35855771202a, 0x106aa07032bbd1b8,
+            0x19a4c116b8d2d0c8, 0x1e376c085141ab53, 0x2748774cdf8eeb99, 0x34b0bcb5e19b48a8,
+            0x391c0cb3c5c95a63, 0x4ed8aa4ae3418acb, 0x5b9cca4f7763e373, 0x682e6ff3d6b2b8a3,
+            0x748f82ee5defb2fc, 0x78a5636f43172f60, 0x84c87814a1f0ab72, 0x8cc702081a6439ec,
+            0x90befffa23631e28, 0xa4506cebde82bde9, 0xbef9a3f7b2c67915, 0xc67178f2e372532b,
+            0xca273eceea26619c, 0xd186b8c721c0c207, 0xeada7dd6cde0eb1e, 0xf57d4f7fee6ed178,
+            0x06f067aa72176fba, 0x0a637dc5a2c898a6, 0x113f9804bef90dae, 0x1b710b35131c471b,
+            0x28db77f523047d84, 0x32caab7b40c72493, 0x3c9ebe0a15c9bebc, 0x431d67c49c100d4c,
+            0x4cc5d4becb3e42b6, 0x597f299cfc657e2a, 0x5fcb6fab3ad6faec, 0x6c44198c4a475817,
+        ];
+    }
+
+    /// Initial state, digest size and name of a hash of the SHA-512 family.
+    pub trait Sha512Variant: Clone {
+        const IV: [u64; 8];
+        const NAME: &'static str;
+        type OutputSize: ArrayLength<u8> + 'static;
+    }
+
+    #[derive(Clone)]
+    pub struct Variant384;
+
+    impl Sha512Variant for Variant384 {
+        #[rustfmt::skip]
+        const IV: [u64; 8] = [
+            0xcbbb9d5dc1059ed8, 0x629a292a367cd507, 0x9159015a3070dd17, 0x152fecd8f70e5939,
+            0x67332667ffc00b31, 0x8eb44a8768581511, 0xdb0c2e0d64f98fa7, 0x47b5481dbefa4fa4,
+        ];
+        const NAME: &'static str = "Sha384";
+        type OutputSize = U48;
+    }
+
+    #[derive(Clone)]
+    pub struct Variant512;
+
+    impl Sha512Variant for Variant512 {
+        #[rustfmt::skip]
+        const IV: [u64; 8] = [
+            0x6a09e667f3bcc908, 0xbb67ae8584caa73b, 0x3c6ef372fe94f82b, 0xa54ff53a5f1d36f1,
+            0x510e527fade682d1, 0x9b05688c2b3e6c1f, 0x1f83d9abfb41bd6b, 0x5be0cd19137e2179,
+        ];
+        const NAME: &'static str = "Sha512";
+        type OutputSize = U64;
+    }
+
+    /// Block level SHA-384/SHA-512 running on the selected backend.
+    #[derive(Clone)]
+    pub struct Sha512Core<V> {
+        state: [u64; 8],
+        block_len: u128,
+        variant: PhantomData<V>,
+    }
+
+    pub type Sha384 = CoreWrapper<Sha512Core<Variant384>>;
+    pub type Sha512 = CoreWrapper<Sha512Core<Variant512>>;
+
+    impl<V: Sha512Variant> Default for Sha512Core<V> {
+        fn default() -> Self {
+            Self {
+                state: V::IV,
+                block_len: 0,
+                variant: PhantomData,
+            }
+        }
+    }
+
+    impl<V> HashMarker for Sha512Core<V> {}
+
+    impl<V> BlockSizeUser for Sha512Core<V> {
+        type BlockSize = U128;
+    }
+
+    impl<V> BufferKindUser for Sha512Core<V> {
+        type BufferKind = Eager;
+    }
+
+    impl<V: Sha512Variant> OutputSizeUser for Sha512Core<V> {
+        type OutputSize = V::OutputSize;
+    }
+
+    impl<V> UpdateCore for Sha512Core<V> {
+        fn update_blocks(&mut self, blocks: &[Block<Self>]) {
+            self.block_len += blocks.len() as u128;
+            backend().sha512_compress(&mut self.state, blocks);
+        }
+    }
+
+    impl<V: Sha512Variant> FixedOutputCore for Sha512Core<V> {
+        fn finalize_fixed_core(&mut self, buffer: &mut Buffer<Self>, out: &mut Output<Self>) {
+            let backend = backend();
+            let block_size = U128::U64 as u128;
+            let bit_len = 8 * (buffer.get_pos() as u128 + block_size * self.block_len);
+            buffer.len128_padding_be(bit_len, |b| {
+                backend.sha512_compress(&mut self.state, from_ref(b))
+            });
+
+            for (chunk, v) in out.chunks_exact_mut(8).zip(self.state.iter()) {
+                chunk.copy_from_slice(&v.to_be_bytes());
+            }
+        }
+    }
+
+    impl<V: Sha512Variant> Reset for Sha512Core<V> {
+        fn reset(&mut self) {
+            *self = Self::default();
+        }
+    }
+
+    impl<V: Sha512Variant> AlgorithmName for Sha512Core<V> {
+        fn write_alg_name(f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(V::NAME)
+        }
+    }
+
+    impl<V: Sha512Variant> fmt::Debug for Sha512Core<V> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}Core {{ ... }}", V::NAME)
+        }
+    }
+}
+
+pub mod measurement {
+    //! Hash algorithms used to measure TVMs. The TSM only talks to a `MeasurementHasher` so the
+    //! extend/finalize paths do not depend on the digest selected at TVM creation.
+    extern crate alloc;
+    use crate::crypto::{Sha384, Sha512};
+    use alloc::{boxed::Box, vec::Vec};
+    use sha2::{Digest, digest::FixedOutputReset};
+
+    /// Algorithm identifiers accepted by `sbi_covh_create_tvm`.
+    pub const MEASUREMENT_ALG_SHA384: usize = 0;
+    pub const MEASUREMENT_ALG_SHA512: usize = 1;
+    pub const MEASUREMENT_ALG_SM3: usize = 2;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum HashAlgorithm {
+        Sha384 = MEASUREMENT_ALG_SHA384 as isize,
+        Sha512 = MEASUREMENT_ALG_SHA512 as isize,
+        Sm3 = MEASUREMENT_ALG_SM3 as isize,
+    }
+
+    impl Default for HashAlgorithm {
+        fn default() -> Self {
+            Self::Sha384
+        }
+    }
+
+    impl HashAlgorithm {
+        pub fn from_id(id: usize) -> Option<Self> {
+            match id {
+                MEASUREMENT_ALG_SHA384 => Some(Self::Sha384),
+                MEASUREMENT_ALG_SHA512 => Some(Self::Sha512),
+                MEASUREMENT_ALG_SM3 => Some(Self::Sm3),
+                _ => None,
+            }
+        }
+
+        /// Name reported as `hash-alg-id` in the attestation evidence.
+        pub fn name(&self) -> &'static str {
+            match self {
+                Self::Sha384 => "SHA384",
+                Self::Sha512 => "SHA512",
+                Self::Sm3 => "SM3",
+            }
+        }
+
+        pub fn digest_size(&self) -> usize {
+            match self {
+                Self::Sha384 => 48,
+                Self::Sha512 => 64,
+                Self::Sm3 => 32,
+            }
+        }
+
+        /// Returns a fresh hasher for this algorithm or `None` if it is not built into this
+        /// image (SM3 has no backend yet).
+        pub fn hasher(&self) -> Option<Box<dyn MeasurementHasher>> {
+            match self {
+                Self::Sha384 => Some(Box::new(DigestHasher::<Sha384>::new(*self))),
+                Self::Sha512 => Some(Box::new(DigestHasher::<Sha512>::new(*self))),
+                Self::Sm3 => None,
+            }
+        }
+    }
+
+    /// A running measurement.
+    pub trait MeasurementHasher: Send {
+        fn algorithm(&self) -> HashAlgorithm;
+        /// Extend the measurement with `data`.
+        fn extend(&mut self, data: &[u8]);
+        /// Return the current digest and reset the hasher.
+        fn finalize_reset(&mut self) -> Vec<u8>;
+        /// Write the current digest to `out`, of the digest size, and reset the hasher. Does not
+        /// allocate.
+        fn finalize_reset_into(&mut self, out: &mut [u8]);
+    }
+
+    struct DigestHasher<D> {
+        algorithm: HashAlgorithm,
+        hasher: D,
+    }
+
+    impl<D: Digest> DigestHasher<D> {
+        fn new(algorithm: HashAlgorithm) -> Self {
+            Self {
+                algorithm,
+                hasher: D::new(),
+            }
+        }
+    }
+
+    impl<D: Digest + FixedOutputReset + Send> MeasurementHasher for DigestHasher<D> {
+        fn algorithm(&self) -> HashAlgorithm {
+            self.algorithm
+        }
+
+        fn extend(&mut self, data: &[u8]) {
+            Digest::update(&mut self.hasher, data);
+        }
+
+        fn finalize_reset(&mut self) -> Vec<u8> {
+            Digest::finalize_reset(&mut self.hasher).to_vec()
+        }
+
+        fn finalize_reset_into(&mut self, out: &mut [u8]) {
+            out.copy_from_slice(&Digest::finalize_reset(&mut self.hasher));
+        }
+    }
+}
+
+pub mod boot_manifest {
+    //! Boot manifest: a table at `BOOT_MANIFEST_ADDR` listing the images loaded with the firmware
+    //! (QEMU `-device loader`), so that the TSM and the guests change without rebuilding the crates
+    //! which use them. `scripts/boot_manifest.py` writes it. An entry gives the name, address, size
+    //! and SHA-512 of an image, and an optional Ed25519 signature: the TSM-driver checks the one of
+    //! the TSM against its key ring. The hash is checked before an image is handed out.
+    //!
+    //! The manifest lives in the untrusted memory, which the TSM-driver, the TSM and the host can
+    //! read. Without a manifest, or without the entry, the users fall back on the image embedded
+    //! at build time (`embed-elf` feature).
+    use crate::crypto::Sha512;
+    use sha2::Digest;
+
+    /// Right after the page pool of the reference host (see `payload/cove-vmm`)
+    pub const BOOT_MANIFEST_ADDR: usize = 0x8AC0_0000;
+    pub const BOOT_MANIFEST_MAGIC: u64 = u64::from_le_bytes(*b"SFXMANIF");
+    pub const BOOT_MANIFEST_VERSION: u32 = 1;
+    pub const BOOT_MANIFEST_MAX_IMAGES: usize = 8;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug)]
+    pub struct BootManifestHeader {
+        pub magic: u64,
+        pub version: u32,
+        pub num_images: u32,
+    }
+
+    /// Entry of an image, the entries follow the header.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug)]
+    pub struct BootImageEntry {
+        /// Padded with NULs
+        pub name: [u8; 16],
+        pub addr: u64,
+        pub size: u64,
+        pub sha512: [u8; 64],
+        /// All zeros if the image is not signed
+        pub signature: [u8; 64],
+    }
+
+    pub struct BootImage {
+        pub data: &'static [u8],
+        pub signature: Option<&'static [u8; 64]>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum ManifestError {
+        NoManifest,
+        UnsupportedVersion(u32),
+        NotFound,
+        /// The image does not fit the address space
+        InvalidEntry,
+        HashMismatch,
+    }
+
+    /// Find the image `name` in the manifest at `addr`.
+    ///
+    /// # Safety
+    /// The header at `addr` must be readable. If it holds a manifest, its entries and images must
+    /// be readable and left untouched while the image is used.
+    pub unsafe fn find(addr: usize, name: &str) -> Result<BootImage, ManifestError> {
+        let header = unsafe { core::ptr::read_volatile(addr as *const BootManifestHeader) };
+        if header.magic != BOOT_MANIFEST_MAGIC {
+            return Err(ManifestError::NoManifest);
+        }
+        if header.version != BOOT_MANIFEST_VERSION {
+            return Err(ManifestError::UnsupportedVersion(header.version));
+        }
+
+        let num_images = (header.num_images as usize).min(BOOT_MANIFEST_MAX_IMAGES);
+        let entries_addr = addr + size_of::<BootManifestHeader>();
+        let entries = unsafe {
+            core::slice::from_raw_parts(entries_addr as *const BootImageEntry, num_images)
+        };
+        let entry = entries
+            .iter()
+            .find(|entry| entry_name(entry) == name.as_bytes())
+            .ok_or(ManifestError::NotFound)?;
+
+        let (Ok(image_addr), Ok(size)) = (usize::try_from(entry.addr), usize::try_from(entry.size))
+        else {
+            return Err(ManifestError::InvalidEntry);
+        };
+        if image_addr.checked_add(size).is_none() {
+            return Err(ManifestError::InvalidEntry);
+        }
+        let data = unsafe { core::slice::from_raw_parts(image_addr as *const u8, size) };
+        if Sha512::digest(data)[..] != entry.sha512 {
+            return Err(ManifestError::HashMismatch);
+        }
+
+        let signed = entry.signature.iter().any(|&b| b != 0);
+        Ok(BootImage {
+            data,
+            signature: signed.then_some(&entry.signature),
+        })
+    }
+
+    fn entry_name(entry: &BootImageEntry) -> &[u8] {
+        let len = entry.name.iter().position(|&b| b == 0);
+        &entry.name[..len.unwrap_or(entry.name.len())]
+    }
+}
+
+pub mod sealed_image {
+    //! Sealed guest image: the code of a confidential workload encrypted for a TSM, so that the
+    //! host loads it (`SBI_COVH_ADD_TVM_SEALED_IMAGE`) without ever seeing the plaintext.
+    //! `scripts/seal_image.py` writes it. The image is a header followed by one record per page:
+    //!
+    //! |--------|---------|-----------|-----|------|
+    //! | magic  | version | num_pages | gpa | salt |
+    //! |--------|---------|-----------|-----|------|
+    //! |   8    |    4    |     4     |  8  |  16  |
+    //! |--------|---------|-----------|-----|------|
+    //!
+    //! A record is the 4K ciphertext of the page at `gpa + index * 4K` and its 16-byte tag, sealed
+    //! with AES-256-GCM using the page index as nonce and the header as associated data. The key is
+    //! the key ladder key of `KEY_LADDER_OWNER_TSM` for `SEALED_IMAGE_KEY_LABEL` followed by the
+    //! salt, derived from the TSM CDI: only the TSM the image was sealed for (same binary, on a
+    //! platform with the same DICE identity) can open it.
+    use crate::sbi::PAGE_SIZE;
+
+    pub const SEALED_IMAGE_MAGIC: [u8; 8] = *b"SFXSEAL1";
+    pub const SEALED_IMAGE_VERSION: u32 = 1;
+    pub const SEALED_IMAGE_KEY_LABEL: &[u8] = b"tvm-sealed-image";
+    pub const SEALED_IMAGE_SALT_SIZE: usize = 16;
+    pub const SEALED_IMAGE_TAG_SIZE: usize = 16;
+    pub const SEALED_IMAGE_RECORD_SIZE: usize = PAGE_SIZE + SEALED_IMAGE_TAG_SIZE;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct SealedImageHeader {
+        pub magic: [u8; 8],
+        pub version: u32,
+        pub num_pages: u32,
+        /// Guest physical address of the first page, page aligned
+        pub gpa: u64,
+        pub salt: [u8; SEALED_IMAGE_SALT_SIZE],
+    }
+
+    pub const SEALED_IMAGE_HEADER_SIZE: usize = size_of::<SealedImageHeader>();
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum SealedImageError {
+        Truncated,
+        BadMagic,
+        UnsupportedVersion(u32),
+        /// No pages, or a GPA which is not page aligned
+        InvalidHeader,
+    }
+
+    impl SealedImageHeader {
+        /// Parse the header at the start of `bytes`.
+        pub fn parse(bytes: &[u8]) -> Result<Self, SealedImageError> {
+            let bytes: &[u8; SEALED_IMAGE_HEADER_SIZE] = bytes
+                .get(..SEALED_IMAGE_HEADER_SIZE)
+                .and_then(|b| b.try_into().ok())
+                .ok_or(SealedImageError::Truncated)?;
+            let header = Self::from_bytes(bytes);
+            if header.magic != SEALED_IMAGE_MAGIC {
+                return Err(SealedImageError::BadMagic);
+            }
+            if header.version != SEALED_IMAGE_VERSION {
+                return Err(SealedImageError::UnsupportedVersion(header.version));
+            }
+            if header.num_pages == 0 || !header.gpa.is_multiple_of(PAGE_SIZE as u64) {
+                return Err(SealedImageError::InvalidHeader);
+            }
+            Ok(header)
+        }
+
+        fn from_bytes(bytes: &[u8; SEALED_IMAGE_HEADER_SIZE]) -> Self {
+            let mut magic = [0; 8];
+            magic.copy_from_slice(&bytes[0..8]);
+            let mut salt = [0; SEALED_IMAGE_SALT_SIZE];
+            salt.copy_from_slice(&bytes[24..40]);
+            Self {
+                magic,
+                version: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+                num_pages: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+                gpa: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+                salt,
+            }
+        }
+
+        /// The header as it is authenticated with each record
+        pub fn to_bytes(&self) -> [u8; SEALED_IMAGE_HEADER_SIZE] {
+            let mut bytes = [0; SEALED_IMAGE_HEADER_SIZE];
+            bytes[0..8].copy_from_slice(&self.magic);
+            bytes[8..12].copy_from_slice(&self.version.to_le_bytes());
+            bytes[12..16].copy_from_slice(&self.num_pages.to_le_bytes());
+            bytes[16..24].copy_from_slice(&self.gpa.to_le_bytes());
+            bytes[24..40].copy_from_slice(&self.salt);
+            bytes
+        }
+
+        /// Size of the whole image, header included
+        pub fn image_size(&self) -> usize {
+            SEALED_IMAGE_HEADER_SIZE + self.num_pages as usize * SEALED_IMAGE_RECORD_SIZE
+        }
+
+        /// Offset of the record of page `index` in the image
+        pub fn record_offset(index: usize) -> usize {
+            SEALED_IMAGE_HEADER_SIZE + index * SEALED_IMAGE_RECORD_SIZE
+        }
+
+        /// Key ladder label of the image key
+        pub fn key_label(&self) -> [u8; SEALED_IMAGE_KEY_LABEL.len() + SEALED_IMAGE_SALT_SIZE] {
+            let mut label = [0; SEALED_IMAGE_KEY_LABEL.len() + SEALED_IMAGE_SALT_SIZE];
+            label[..SEALED_IMAGE_KEY_LABEL.len()].copy_from_slice(SEALED_IMAGE_KEY_LABEL);
+            label[SEALED_IMAGE_KEY_LABEL.len()..].copy_from_slice(&self.salt);
+            label
+        }
+    }
+
+    /// AES-GCM nonce of the record of page `index`
+    pub fn record_nonce(index: usize) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&(index as u64).to_le_bytes());
+        nonce
+    }
+}
+
+pub mod migration {
+    //! Transport key of a TVM migration, negotiated between the source and the destination TSM.
+    //! Before the host exports a TVM, the destination TSM makes an import offer
+    //! (`SBI_COVH_PREPARE_TVM_IMPORT`): an ephemeral X25519 public key signed with its TSM key,
+    //! and the TSM token certifying that key. The host passes the offer to `SBI_COVH_EXPORT_TVM`
+    //! on the source, which checks the token against its trust anchor, the key of its own platform
+    //! layer, then the signature. It makes its own ephemeral key and seals the blob with the key
+    //! derived from the shared secret. The offer is laid out as:
+    //!
+    //! |--------------|---------|-----------|-----------|-----------|
+    //! | exchange key | TSM key | signature | token len | TSM token |
+    //! |--------------|---------|-----------|-----------|-----------|
+    //! |      32      |   32    |    64     |     4     | token len |
+    //! |--------------|---------|-----------|-----------|-----------|
+    //!
+    //! The signature covers `MIGRATION_OFFER_CONTEXT` followed by the exchange key. The ephemeral
+    //! secrets never leave TSM memory and the destination drops its secret at the first import, so
+    //! a blob can be imported once. Only a TSM certified by the same platform key, i.e. loaded by
+    //! the firmware of the same platform, can import the TVMs of a source.
+    extern crate alloc;
+    use alloc::vec::Vec;
+    use coset::CborSerializable;
+    use ed25519_compact::{PublicKey, Signature, x25519};
+
+    use crate::{
+        attestation::{
+            AttestationError, DiceLayer, SecretBytes, TsmAttestationContext, certified_tsm_key,
+        },
+        crypto::Sha512,
+    };
+
+    /// Size of the offer buffers, enough for the offer and the TSM token
+    pub const MIGRATION_OFFER_SIZE: usize = 4096;
+    /// Size of the X25519 public keys of the exchange
+    pub const EXCHANGE_KEY_SIZE: usize = x25519::PublicKey::BYTES;
+    /// Size of the offer without the TSM token
+    const OFFER_HEADER_SIZE: usize = 132;
+    const MIGRATION_OFFER_CONTEXT: &[u8] = b"Shadowfax TVM import offer";
+    const TRANSPORT_KEY_LABEL: &[u8] = b"tvm-migration-transport";
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct MigrationOffer {
+        /// X25519 public key of the destination for this import
+        pub exchange_key: [u8; EXCHANGE_KEY_SIZE],
+        /// Ed25519 public key of the destination TSM
+        pub tsm_key: [u8; PublicKey::BYTES],
+        signature: [u8; Signature::BYTES],
+        /// Serialized TSM token of the destination, certifying `tsm_key`
+        tsm_token: Vec<u8>,
+    }
+
+    impl MigrationOffer {
+        /// Offer of `secret`, signed with the key of the TSM layer `tsm` and certified by its
+        /// token.
+        pub fn new(
+            tsm: &TsmAttestationContext,
+            secret: &ExchangeSecret,
+        ) -> Result<Self, AttestationError> {
+            let tsm_token = tsm
+                .token()
+                .clone()
+                .to_vec()
+                .map_err(|_| AttestationError::InvalidToken)?;
+            if tsm_token.len() > MIGRATION_OFFER_SIZE - OFFER_HEADER_SIZE {
+                return Err(AttestationError::InvalidLength);
+            }
+            let exchange_key = secret.public_key();
+            Ok(Self {
+                exchange_key,
+                tsm_key: tsm.cdi().public_key(),
+                signature: tsm
+                    .cdi()
+                    .sign(&[MIGRATION_OFFER_CONTEXT, &exchange_key].concat()),
+                tsm_token,
+            })
+        }
+
+        /// Parse the offer at the start of `bytes`. The TSM token must be signed with
+        /// `platform_key`, the trust anchor of the caller, and certify the key which signed the
+        /// exchange key.
+        pub fn parse(bytes: &[u8], platform_key: &[u8]) -> Result<Self, AttestationError> {
+            let header = bytes
+                .get(..OFFER_HEADER_SIZE)
+                .ok_or(AttestationError::Truncated)?;
+            let token_len = u32::from_le_bytes(header[128..132].try_into().unwrap()) as usize;
+            if token_len > MIGRATION_OFFER_SIZE - OFFER_HEADER_SIZE {
+                return Err(AttestationError::InvalidLength);
+            }
+            let tsm_token = bytes
+                .get(OFFER_HEADER_SIZE..OFFER_HEADER_SIZE + token_len)
+                .ok_or(AttestationError::Truncated)?;
+            let offer = Self {
+                exchange_key: header[0..32].try_into().unwrap(),
+                tsm_key: header[32..64].try_into().unwrap(),
+                signature: header[64..128].try_into().unwrap(),
+                tsm_token: tsm_token.to_vec(),
+            };
+
+            if certified_tsm_key(&offer.tsm_token, platform_key)? != offer.tsm_key {
+                return Err(AttestationError::InvalidPublicKey);
+            }
+            let pk = PublicKey::from_slice(&offer.tsm_key)
+                .map_err(|_| AttestationError::InvalidPublicKey)?;
+            pk.verify(
+                [MIGRATION_OFFER_CONTEXT, &offer.exchange_key].concat(),
+                &Signature::new(offer.signature),
+            )
+            .map_err(|_| AttestationError::SignatureVerificationFailed)?;
+            Ok(offer)
+        }
+
+        /// The offer as laid out in the offer buffers, at most `MIGRATION_OFFER_SIZE` bytes
+        pub fn to_bytes(&self) -> Vec<u8> {
+            let mut bytes = Vec::with_capacity(OFFER_HEADER_SIZE + self.tsm_token.len());
+            bytes.extend_from_slice(&self.exchange_key);
+            bytes.extend_from_slice(&self.tsm_key);
+            bytes.extend_from_slice(&self.signature);
+            bytes.extend_from_slice(&(self.tsm_token.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&self.tsm_token);
+            bytes
+        }
+    }
+
+    /// Ephemeral X25519 secret of one side of a migration, wiped when dropped.
+    pub struct ExchangeSecret(x25519::SecretKey);
+
+    impl ExchangeSecret {
+        /// Secret made of `random`, bytes of the firmware entropy source.
+        pub fn new(random: [u8; x25519::SecretKey::BYTES]) -> Self {
+            Self(x25519::SecretKey::new(random))
+        }
+
+        pub fn public_key(&self) -> [u8; EXCHANGE_KEY_SIZE] {
+            *self
+                .0
+                .recover_public_key()
+                .expect("clamped X25519 secret has a public key")
+        }
+
+        /// AES-256 transport key shared with the owner of `peer_key`. The key is bound to
+        /// `transcript`, which both sides must build the same way (e.g. the blob header and the
+        /// offer). Fails on a weak peer key.
+        pub fn transport_key(
+            &self,
+            peer_key: &[u8; EXCHANGE_KEY_SIZE],
+            transcript: &[&[u8]],
+        ) -> Result<SecretBytes, AttestationError> {
+            let shared = x25519::PublicKey::new(*peer_key)
+                .dh(&self.0)
+                .map_err(|_| AttestationError::InvalidPublicKey)?;
+            let mut key = SecretBytes::zeroed(32);
+            hkdf::Hkdf::<Sha512>::new(None, &*shared)
+                .expand_multi_info(&[&[TRANSPORT_KEY_LABEL], transcript].concat(), &mut key)
+                .expect("HKDF for transport key");
+            Ok(key)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::attestation::PlatformAttestationContext;
+
+        fn tsm(platform_cdi: u8) -> TsmAttestationContext {
+            PlatformAttestationContext::for_tests([platform_cdi; 32]).compute_next(&[0x75; 64])
+        }
+
+        #[test]
+        fn both_sides_derive_the_same_key() {
+            let tsm = tsm(1);
+            let destination = ExchangeSecret::new([1; 32]);
+            let bytes = MigrationOffer::new(&tsm, &destination).unwrap().to_bytes();
+            assert!(bytes.len() <= MIGRATION_OFFER_SIZE);
+            let offer = MigrationOffer::parse(&bytes, tsm.platform_public_key()).unwrap();
+
+            let source = ExchangeSecret::new([2; 32]);
+            let header: &[u8] = b"header";
+            let sent = source
+                .transport_key(&offer.exchange_key, &[header, &offer.to_bytes()])
+                .unwrap();
+            let received = destination
+                .transport_key(&source.public_key(), &[header, &offer.to_bytes()])
+                .unwrap();
+            assert_eq!(&*sent, &*received);
+
+            let other = ExchangeSecret::new([3; 32]);
+            let stolen = other
+                .transport_key(&source.public_key(), &[header, &offer.to_bytes()])
+                .unwrap();
+            assert_ne!(&*sent, &*stolen);
+        }
+
+        #[test]
+        fn tampered_offer_is_rejected() {
+            let tsm = tsm(1);
+            let offer = MigrationOffer::new(&tsm, &ExchangeSecret::new([1; 32])).unwrap();
+            let mut bytes = offer.to_bytes();
+            bytes[0] ^= 1;
+            assert!(MigrationOffer::parse(&bytes, tsm.platform_public_key()).is_err());
+            assert!(MigrationOffer::parse(&bytes[..64], tsm.platform_public_key()).is_err());
+        }
+
+        #[test]
+        fn offer_needs_a_token_of_the_platform() {
+            let (tsm, other_platform) = (tsm(1), tsm(2));
+            let bytes = MigrationOffer::new(&other_platform, &ExchangeSecret::new([1; 32]))
+                .unwrap()
+                .to_bytes();
+            assert!(MigrationOffer::parse(&bytes, tsm.platform_public_key()).is_err());
+            assert!(MigrationOffer::parse(&bytes, other_platform.platform_public_key()).is_ok());
+
+            // A key of its own, signing the exchange key, with the token of a genuine TSM
+            let mut forged =
+                MigrationOffer::new(&other_platform, &ExchangeSecret::new([1; 32])).unwrap();
+            forged.tsm_token = MigrationOffer::new(&tsm, &ExchangeSecret::new([1; 32]))
+                .unwrap()
+                .tsm_token;
+            assert!(MigrationOffer::parse(&forged.to_bytes(), tsm.platform_public_key()).is_err());
+
+            let no_anchor = TsmAttestationContext::default();
+            let bytes = MigrationOffer::new(&tsm, &ExchangeSecret::new([1; 32]))
+                .unwrap()
+                .to_bytes();
+            assert!(MigrationOffer::parse(&bytes, no_anchor.platform_public_key()).is_err());
+        }
+    }
+}
+
+pub mod fdt {
+    //! Flattened device tree blobs, at the token level: `Reader` walks the structure block of a
+    //! blob and `Writer` builds a new one. Enough for the TSM-driver to filter the device tree of
+    //! the platform for each domain, and for the TSM to describe a TVM in its boot-info page.
+    //! Property values are the raw big-endian cells.
+    extern crate alloc;
+    use alloc::{string::String, vec::Vec};
+
+    pub const FDT_MAGIC: u32 = 0xd00d_feed;
+    const FDT_BEGIN_NODE: u32 = 1;
+    const FDT_END_NODE: u32 = 2;
+    const FDT_PROP: u32 = 3;
+    const FDT_NOP: u32 = 4;
+    const FDT_END: u32 = 9;
+    const FDT_VERSION: u32 = 17;
+    const FDT_LAST_COMP_VERSION: u32 = 16;
+    const HEADER_SIZE: usize = 40;
+    /// The empty memory reservation map, right after the header
+    const RSVMAP_SIZE: usize = 16;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Token<'a> {
+        BeginNode(&'a str),
+        EndNode,
+        /// Name and value of a property
+        Prop(&'a str, &'a [u8]),
+    }
+
+    fn be32(bytes: &[u8], offset: usize) -> Option<u32> {
+        let word = bytes.get(offset..offset.checked_add(4)?)?;
+        Some(u32::from_be_bytes(word.try_into().ok()?))
+    }
+
+    fn align4(offset: usize) -> usize {
+        (offset + 3) & !3
+    }
+
+    fn c_str(bytes: &[u8]) -> Option<&str> {
+        let len = bytes.iter().position(|&b| b == 0)?;
+        core::str::from_utf8(&bytes[..len]).ok()
+    }
+
+    /// Value of `cells` 32-bit cells at the start of `value` (1 or 2 cells).
+    pub fn read_cells(value: &[u8], cells: usize) -> Option<u64> {
+        match cells {
+            1 => be32(value, 0).map(u64::from),
+            2 => Some((u64::from(be32(value, 0)?) << 32) | u64::from(be32(value, 4)?)),
+            _ => None,
+        }
+    }
+
+    /// Whether the `compatible` list `value` has `compatible`.
+    pub fn is_compatible(value: &[u8], compatible: &str) -> bool {
+        value
+            .split(|&b| b == 0)
+            .any(|entry| entry == compatible.as_bytes())
+    }
+
+    /// Tokens of the structure block with their offset in it, up to `FDT_END`. A malformed blob
+    /// ends the walk early.
+    pub struct Reader<'a> {
+        structs: &'a [u8],
+        strings: &'a [u8],
+        offset: usize,
+    }
+
+    impl<'a> Reader<'a> {
+        pub fn new(blob: &'a [u8]) -> Option<Self> {
+            if be32(blob, 0)? != FDT_MAGIC {
+                return None;
+            }
+            let field = |i: usize| be32(blob, 4 * i).map(|v| v as usize);
+            let (off_structs, off_strings) = (field(2)?, field(3)?);
+            let (size_strings, size_structs) = (field(8)?, field(9)?);
+            Some(Self {
+                structs: blob.get(off_structs..off_structs.checked_add(size_structs)?)?,
+                strings: blob.get(off_strings..off_strings.checked_add(size_strings)?)?,
+                offset: 0,
+            })
+        }
+
+        /// # Safety
+        ///
+        /// `addr` must point to a device tree blob, readable up to its `totalsize`.
+        pub unsafe fn from_addr(addr: usize) -> Option<Self> {
+            let header = unsafe { core::slice::from_raw_parts(addr as *const u8, HEADER_SIZE) };
+            if be32(header, 0)? != FDT_MAGIC {
+                return None;
+            }
+            let size = be32(header, 4)? as usize;
+            Self::new(unsafe { core::slice::from_raw_parts(addr as *const u8, size) })
+        }
+    }
 
-    // SUPD constants
-    pub const SBI_SUPD_EXT_ID: usize = 0x53555044;
-    pub const SBI_EXT_SUPD_GET_ACTIVE_DOMAINS: usize = 0;
+    impl<'a> Iterator for Reader<'a> {
+        type Item = (usize, Token<'a>);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            loop {
+                let offset = self.offset;
+                let body = offset + 4;
+                match be32(self.structs, offset)? {
+                    FDT_BEGIN_NODE => {
+                        let name = c_str(self.structs.get(body..)?)?;
+                        self.offset = align4(body + name.len() + 1);
+                        return Some((offset, Token::BeginNode(name)));
+                    }
+                    FDT_END_NODE => {
+                        self.offset = body;
+                        return Some((offset, Token::EndNode));
+                    }
+                    FDT_PROP => {
+                        let len = be32(self.structs, body)? as usize;
+                        let name_offset = be32(self.structs, body + 4)? as usize;
+                        let value = self.structs.get(body + 8..(body + 8).checked_add(len)?)?;
+                        let name = c_str(self.strings.get(name_offset..)?)?;
+                        self.offset = align4(body + 8 + len);
+                        return Some((offset, Token::Prop(name, value)));
+                    }
+                    FDT_NOP => self.offset = body,
+                    _ => return None,
+                }
+            }
+        }
+    }
 
-    // CoVG constants
-    pub const COVG_EXTENSION: usize = 0x434F5647;
-    pub const COVG_GET_EVIDENCE: usize = 8;
+    /// Builds a blob node by node. The names of the properties are stored once.
+    #[derive(Default)]
+    pub struct Writer {
+        structs: Vec<u8>,
+        strings: Vec<u8>,
+        names: Vec<(String, u32)>,
+    }
 
-    pub const PAGE_SIZE: usize = 4096;
+    impl Writer {
+        pub fn new() -> Self {
+            Self::default()
+        }
 
-    #[repr(C)]
-    pub struct SbiRet {
-        pub a0: isize,
-        pub a1: isize,
+        fn token(&mut self, token: u32) {
+            self.structs.extend_from_slice(&token.to_be_bytes());
+        }
+
+        fn pad(&mut self) {
+            self.structs.resize(align4(self.structs.len()), 0);
+        }
+
+        fn name_offset(&mut self, name: &str) -> u32 {
+            if let Some((_, offset)) = self.names.iter().find(|(n, _)| n == name) {
+                return *offset;
+            }
+            let offset = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.names.push((String::from(name), offset));
+            offset
+        }
+
+        /// Open a node, the root node has an empty name.
+        pub fn begin_node(&mut self, name: &str) {
+            self.token(FDT_BEGIN_NODE);
+            self.structs.extend_from_slice(name.as_bytes());
+            self.structs.push(0);
+            self.pad();
+        }
+
+        pub fn end_node(&mut self) {
+            self.token(FDT_END_NODE);
+        }
+
+        /// Add a property to the open node, before its subnodes.
+        pub fn property(&mut self, name: &str, value: &[u8]) {
+            let name_offset = self.name_offset(name);
+            self.token(FDT_PROP);
+            self.structs
+                .extend_from_slice(&(value.len() as u32).to_be_bytes());
+            self.structs.extend_from_slice(&name_offset.to_be_bytes());
+            self.structs.extend_from_slice(value);
+            self.pad();
+        }
+
+        pub fn property_u32(&mut self, name: &str, value: u32) {
+            self.property(name, &value.to_be_bytes());
+        }
+
+        /// A property of 64-bit values, two cells each (e.g. `reg` with two address and size cells)
+        pub fn property_u64s(&mut self, name: &str, values: &[u64]) {
+            let value: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+            self.property(name, &value);
+        }
+
+        pub fn property_str(&mut self, name: &str, value: &str) {
+            let mut bytes = Vec::with_capacity(value.len() + 1);
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.push(0);
+            self.property(name, &bytes);
+        }
+
+        /// The blob, the nodes must all be closed.
+        pub fn finish(mut self) -> Vec<u8> {
+            self.token(FDT_END);
+            let off_structs = HEADER_SIZE + RSVMAP_SIZE;
+            let off_strings = off_structs + self.structs.len();
+            let total = off_strings + self.strings.len();
+
+            let mut blob = Vec::with_capacity(total);
+            for field in [
+                FDT_MAGIC,
+                total as u32,
+                off_structs as u32,
+                off_strings as u32,
+                HEADER_SIZE as u32,
+                FDT_VERSION,
+                FDT_LAST_COMP_VERSION,
+                // boot_cpuid_phys
+                0,
+                self.strings.len() as u32,
+                self.structs.len() as u32,
+            ] {
+                blob.extend_from_slice(&field.to_be_bytes());
+            }
+            blob.resize(off_structs, 0);
+            blob.extend_from_slice(&self.structs);
+            blob.extend_from_slice(&self.strings);
+            blob
+        }
     }
+}
 
-    pub fn sbi_call(extid: usize, fid: usize, args: &[usize; 6]) -> SbiRet {
-        let (a0, a1);
-        unsafe {
-            core::arch::asm!(
-                "ecall",
-                in("a7") extid,
-                in("a6") fid,
-                inlateout("a0") args[0] => a0,
-                inlateout("a1") args[1] => a1,
-                in("a2") args[2],
-                in("a3") args[3],
-                in("a4") args[4],
-                in("a5") args[5],
+pub mod heap {
+    //! Global allocator with usage statistics, used by the TSM-driver and the TSM. It wraps
+    //! `linked_list_allocator` heaps and keeps the current and peak usage, the failed allocations
+    //! and the bytes held by each tag. A tag is the subsystem which allocates (see
+    //! `TrackedHeap::tag`): it is stored in a header in front of each block, so that the block is
+    //! accounted to the same tag when freed. Tags are meant for a single hart.
+    //!
+    //! A tag can have its own arena (see `TrackedHeap::init_arena`), so that an allocation storm in
+    //! another subsystem cannot starve it. The tags without an arena share arena 0, the one given
+    //! to `TrackedHeap::init`.
+    use core::{
+        alloc::{GlobalAlloc, Layout},
+        fmt,
+        ptr::{self, NonNull},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use linked_list_allocator::LockedHeap;
+
+    pub const HEAP_MAX_TAGS: usize = 8;
+
+    /// Indexes of the values returned by `HeapStats::get`. The bytes held by tag `i` are at
+    /// `HEAP_STAT_TAG + i`, the bytes used in the arena of tag `i` at `HEAP_STAT_ARENA + i`.
+    pub const HEAP_STAT_SIZE: usize = 0;
+    pub const HEAP_STAT_USED: usize = 1;
+    pub const HEAP_STAT_PEAK: usize = 2;
+    pub const HEAP_STAT_FAILURES: usize = 3;
+    pub const HEAP_STAT_TAG: usize = 4;
+    pub const HEAP_STAT_ARENA: usize = HEAP_STAT_TAG + HEAP_MAX_TAGS;
+
+    #[derive(Clone, Copy, Debug)]
+    pub struct HeapStats {
+        /// Bytes of all the arenas
+        pub size: usize,
+        /// Bytes in use, including the allocator metadata
+        pub used: usize,
+        pub peak: usize,
+        pub failures: usize,
+        /// Bytes requested by each tag and not freed yet
+        pub tags: [usize; HEAP_MAX_TAGS],
+        /// Size and bytes in use of the arena of each tag, 0 if the tag has none
+        pub arenas: [(usize, usize); HEAP_MAX_TAGS],
+        pub tag_names: &'static [&'static str],
+    }
+
+    impl HeapStats {
+        pub fn get(&self, index: usize) -> Option<usize> {
+            match index {
+                HEAP_STAT_SIZE => Some(self.size),
+                HEAP_STAT_USED => Some(self.used),
+                HEAP_STAT_PEAK => Some(self.peak),
+                HEAP_STAT_FAILURES => Some(self.failures),
+                HEAP_STAT_ARENA.. => self.arenas.get(index - HEAP_STAT_ARENA).map(|a| a.1),
+                _ => self.tags.get(index - HEAP_STAT_TAG).copied(),
+            }
+        }
+    }
+
+    impl fmt::Display for HeapStats {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "{}/{} bytes used, peak {}, {} failed allocations",
+                self.used, self.size, self.peak, self.failures
+            )?;
+            for (name, bytes) in self.tag_names.iter().zip(self.tags) {
+                write!(f, ", {name}: {bytes}")?;
+            }
+            for (name, (size, used)) in self.tag_names.iter().zip(self.arenas).skip(1) {
+                if size != 0 {
+                    write!(f, ", {name} arena: {used}/{size}")?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum HeapError {
+        /// The heap cannot hold the allocator metadata
+        TooSmall(usize),
+        AlreadyInitialized,
+    }
+
+    pub struct TrackedHeap {
+        /// Arena of each tag, empty if the tag allocates from arena 0
+        arenas: [LockedHeap; HEAP_MAX_TAGS],
+        tag_names: &'static [&'static str],
+        on_oom: fn(Layout, &HeapStats),
+        tag: AtomicUsize,
+        // updated after each allocation
+        peak: AtomicUsize,
+        failures: AtomicUsize,
+        tags: [AtomicUsize; HEAP_MAX_TAGS],
+    }
+
+    /// Restores the previous tag when dropped.
+    pub struct TagGuard<'a> {
+        heap: &'a TrackedHeap,
+        previous: usize,
+    }
+
+    impl Drop for TagGuard<'_> {
+        fn drop(&mut self) {
+            self.heap.tag.store(self.previous, Ordering::Relaxed);
+        }
+    }
+
+    impl TrackedHeap {
+        /// `tag_names` names the tags used by the image (tag 0 is the default one). `on_oom` is
+        /// called when an allocation fails, before the allocation error is reported.
+        pub const fn new(
+            tag_names: &'static [&'static str],
+            on_oom: fn(Layout, &HeapStats),
+        ) -> Self {
+            assert!(!tag_names.is_empty() && tag_names.len() <= HEAP_MAX_TAGS);
+            Self {
+                arenas: [const { LockedHeap::empty() }; HEAP_MAX_TAGS],
+                tag_names,
+                on_oom,
+                tag: AtomicUsize::new(0),
+                peak: AtomicUsize::new(0),
+                failures: AtomicUsize::new(0),
+                tags: [const { AtomicUsize::new(0) }; HEAP_MAX_TAGS],
+            }
+        }
+
+        /// Give `[start, start + size)` to the heap, as arena 0.
+        ///
+        /// # Safety
+        ///
+        /// The memory must be valid for the `'static` lifetime and not used for anything else.
+        pub unsafe fn init(&self, start: *mut u8, size: usize) -> Result<(), HeapError> {
+            unsafe { self.init_arena(0, start, size) }
+        }
+
+        /// Give `[start, start + size)` to the arena of `tag`: its allocations no longer come from
+        /// arena 0.
+        ///
+        /// # Safety
+        ///
+        /// The memory must be valid for the `'static` lifetime and not used for anything else.
+        pub unsafe fn init_arena(
+            &self,
+            tag: usize,
+            start: *mut u8,
+            size: usize,
+        ) -> Result<(), HeapError> {
+            assert!(tag < self.tag_names.len());
+            // room for the first hole, whatever the alignment of start
+            if size < 3 * size_of::<usize>() {
+                return Err(HeapError::TooSmall(size));
+            }
+            let mut heap = self.arenas[tag].lock();
+            if heap.size() != 0 {
+                return Err(HeapError::AlreadyInitialized);
+            }
+            unsafe { heap.init(start, size) };
+            Ok(())
+        }
+
+        /// Account the allocations to `tag` until the guard is dropped.
+        pub fn tag(&self, tag: usize) -> TagGuard<'_> {
+            assert!(tag < self.tag_names.len());
+            TagGuard {
+                heap: self,
+                previous: self.tag.swap(tag, Ordering::Relaxed),
+            }
+        }
+
+        pub fn stats(&self) -> HeapStats {
+            let arenas: [(usize, usize); HEAP_MAX_TAGS] = core::array::from_fn(|i| {
+                let heap = self.arenas[i].lock();
+                (heap.size(), heap.used())
+            });
+            HeapStats {
+                size: arenas.iter().map(|a| a.0).sum(),
+                used: arenas.iter().map(|a| a.1).sum(),
+                peak: self.peak.load(Ordering::Relaxed),
+                failures: self.failures.load(Ordering::Relaxed),
+                tags: core::array::from_fn(|i| self.tags[i].load(Ordering::Relaxed)),
+                arenas,
+                tag_names: self.tag_names,
+            }
+        }
+
+        // Arena serving the allocations of `tag`
+        fn arena(&self, tag: usize) -> usize {
+            match tag != 0 && self.arenas[tag].lock().size() != 0 {
+                true => tag,
+                false => 0,
+            }
+        }
+
+        // Layout of the block holding the header and the allocation, and offset of the
+        // allocation in it. The offset keeps the allocation aligned.
+        fn block_layout(layout: Layout) -> Option<(Layout, usize)> {
+            let offset = layout.align().max(size_of::<usize>());
+            let block = Layout::from_size_align(layout.size().checked_add(offset)?, offset).ok()?;
+            Some((block, offset))
+        }
+    }
+
+    unsafe impl GlobalAlloc for TrackedHeap {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let Some((block_layout, offset)) = Self::block_layout(layout) else {
+                return ptr::null_mut();
+            };
+
+            let tag = self.tag.load(Ordering::Relaxed);
+            let arena = self.arena(tag);
+            let block = self.arenas[arena].lock().allocate_first_fit(block_layout);
+            let Ok(block) = block else {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                (self.on_oom)(layout, &self.stats());
+                return ptr::null_mut();
+            };
+            let used = self.arenas.iter().map(|a| a.lock().used()).sum();
+            self.peak.fetch_max(used, Ordering::Relaxed);
+
+            self.tags[tag].fetch_add(layout.size(), Ordering::Relaxed);
+            unsafe {
+                let ptr = block.as_ptr().add(offset);
+                // the header keeps the arena too, the tag may get one while the block lives
+                (ptr as *mut usize)
+                    .sub(1)
+                    .write(arena * HEAP_MAX_TAGS + tag);
+                ptr
+            }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            // the layout was valid for alloc
+            let (block_layout, offset) = Self::block_layout(layout).unwrap();
+            unsafe {
+                let header = (ptr as *const usize).sub(1).read();
+                let (arena, tag) = (header / HEAP_MAX_TAGS, header % HEAP_MAX_TAGS);
+                self.tags[tag].fetch_sub(layout.size(), Ordering::Relaxed);
+                let block = NonNull::new_unchecked(ptr.sub(offset));
+                self.arenas[arena].lock().deallocate(block, block_layout);
+            }
+        }
+    }
+}
+
+pub mod drbg {
+    //! HMAC_DRBG (NIST SP 800-90A) with SHA-256. The TSM-driver seeds it from the platform entropy
+    //! sources and serves random bytes to the TSM and to TVMs.
+    use hkdf::hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    const OUTLEN: usize = 32;
+    /// Maximum number of requests between reseeds (SP 800-90A allows up to 2^48).
+    pub const RESEED_INTERVAL: u64 = 1 << 16;
+    /// Maximum number of bytes per request.
+    pub const MAX_REQUEST_SIZE: usize = 1 << 16;
+
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum DrbgError {
+        ReseedRequired,
+        RequestTooLarge,
+    }
+
+    pub struct HmacDrbg {
+        key: [u8; OUTLEN],
+        value: [u8; OUTLEN],
+        reseed_counter: u64,
+    }
+
+    impl HmacDrbg {
+        pub fn new(entropy: &[u8], nonce: &[u8], personalization: &[u8]) -> Self {
+            let mut drbg = Self {
+                key: [0; OUTLEN],
+                value: [1; OUTLEN],
+                reseed_counter: 1,
+            };
+            drbg.update(&[entropy, nonce, personalization]);
+            drbg
+        }
+
+        pub fn reseed(&mut self, entropy: &[u8], additional_input: &[u8]) {
+            self.update(&[entropy, additional_input]);
+            self.reseed_counter = 1;
+        }
+
+        pub fn needs_reseed(&self) -> bool {
+            self.reseed_counter > RESEED_INTERVAL
+        }
+
+        /// Fill `out` with pseudo-random bytes.
+        pub fn generate(
+            &mut self,
+            out: &mut [u8],
+            additional_input: &[u8],
+        ) -> Result<(), DrbgError> {
+            if out.len() > MAX_REQUEST_SIZE {
+                return Err(DrbgError::RequestTooLarge);
+            }
+            if self.needs_reseed() {
+                return Err(DrbgError::ReseedRequired);
+            }
+
+            if !additional_input.is_empty() {
+                self.update(&[additional_input]);
+            }
+            for chunk in out.chunks_mut(OUTLEN) {
+                self.value = self.hmac(&[&self.value]);
+                chunk.copy_from_slice(&self.value[..chunk.len()]);
+            }
+            self.update(&[additional_input]);
+            self.reseed_counter += 1;
+            Ok(())
+        }
+
+        /// HMAC_DRBG_Update: `provided_data` is the concatenation of `data`.
+        fn update(&mut self, data: &[&[u8]]) {
+            let provided = data.iter().any(|d| !d.is_empty());
+            for round in [0u8, 1] {
+                if round == 1 && !provided {
+                    break;
+                }
+                let mut mac = self.mac();
+                mac.update(&self.value);
+                mac.update(&[round]);
+                for d in data {
+                    mac.update(d);
+                }
+                self.key = mac.finalize().into_bytes().into();
+                self.value = self.hmac(&[&self.value]);
+            }
+        }
+
+        fn hmac(&self, data: &[&[u8]]) -> [u8; OUTLEN] {
+            let mut mac = self.mac();
+            for d in data {
+                mac.update(d);
+            }
+            mac.finalize().into_bytes().into()
+        }
+
+        fn mac(&self) -> Hmac<Sha256> {
+            <Hmac<Sha256> as Mac>::new_from_slice(&self.key).unwrap()
+        }
+    }
+
+    impl Drop for HmacDrbg {
+        fn drop(&mut self) {
+            self.key.fill(0);
+            self.value.fill(0);
+        }
+    }
+
+    // Known answers of the NIST CAVP HMAC_DRBG vectors (SP 800-90A), SHA-256 without prediction
+    // resistance: instantiate, optionally reseed, generate twice and compare the second output
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn hex<const N: usize>(s: &str) -> [u8; N] {
+            core::array::from_fn(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap())
+        }
+
+        fn second_output(drbg: &mut HmacDrbg, additional_input: [&[u8]; 2]) -> [u8; 128] {
+            let mut out = [0; 128];
+            drbg.generate(&mut out, additional_input[0]).unwrap();
+            drbg.generate(&mut out, additional_input[1]).unwrap();
+            out
+        }
+
+        #[test]
+        fn instantiate_and_generate() {
+            // HMAC_DRBG.rsp, no reseed, COUNT = 0
+            let mut drbg = HmacDrbg::new(
+                &hex::<32>("ca851911349384bffe89de1cbdc46e6831e44d34a4fb935ee285dd14b71a7488"),
+                &hex::<16>("659ba96c601dc69fc902940805ec0ca8"),
+                &[],
+            );
+            assert_eq!(
+                second_output(&mut drbg, [&[], &[]]),
+                hex::<128>(
+                    "e528e9abf2dece54d47c7e75e5fe302149f817ea9fb4bee6f4199697d04d5b89\
+                     d54fbb978a15b5c443c9ec21036d2460b6f73ebad0dc2aba6e624abf07745bc1\
+                     07694bb7547bb0995f70de25d6b29e2d3011bb19d27676c07162c8b5ccde0668\
+                     961df86803482cb37ed6d5c0bb8d50cf1f50d476aa0458bdaba806f48be9dcb8"
+                )
+            );
+        }
+
+        #[test]
+        fn generate_with_additional_input() {
+            // HMAC_DRBG.rsp, no reseed, AdditionalInputLen = 256, COUNT = 0
+            let mut drbg = HmacDrbg::new(
+                &hex::<32>("d3cc4d1acf3dde0c4bd2290d262337042dc632948223d3a2eaab87da44295fbd"),
+                &hex::<16>("0109b0e729f457328aa18569a9224921"),
+                &[],
+            );
+            let additional_input = [
+                hex::<32>("3c311848183c9a212a26f27f8c6647e40375e466a0857cc39c4e47575d53f1f6"),
+                hex::<32>("fcb9abd19ccfbccef88c9c39bfb3dd7b1c12266c9808992e305bc3cff566e4e4"),
+            ];
+            assert_eq!(
+                second_output(&mut drbg, [&additional_input[0], &additional_input[1]]),
+                hex::<128>(
+                    "9c7b758b212cd0fcecd5daa489821712e3cdea4467b560ef5ddc24ab47749a1f\
+                     1ffdbbb118f4e62fcfca3371b8fbfc5b0646b83e06bfbbab5fac30ea09ea2bc7\
+                     6f1ea568c9be0444b2cc90517b20ca825f2d0eccd88e7175538b85d90ab39018\
+                     3ca6395535d34473af6b5a5b88f5a59ee7561573337ea819da0dcc3573a22974"
+                )
+            );
+        }
+
+        #[test]
+        fn reseed_and_generate() {
+            // HMAC_DRBG.rsp, with reseed, COUNT = 0
+            let mut drbg = HmacDrbg::new(
+                &hex::<32>("06032cd5eed33f39265f49ecb142c511da9aff2af71203bffaf34a9ca5bd9c0d"),
+                &hex::<16>("0e66f71edc43e42a45ad3c6fc6cdc4df"),
+                &[],
+            );
+            drbg.reseed(
+                &hex::<32>("01920a4e669ed3a85ae8a33b35a74ad7fb2a6bb4cf395ce00334a9c9a5a5d552"),
+                &[],
+            );
+            assert_eq!(
+                second_output(&mut drbg, [&[], &[]]),
+                hex::<128>(
+                    "76fc79fe9b50beccc991a11b5635783a83536add03c157fb30645e611c2898bb\
+                     2b1bc215000209208cd506cb28da2a51bdb03826aaf2bd2335d576d519160842\
+                     e7158ad0949d1a9ec3e66ea1b1a064b005de914eac2e9d4f2d72a8616a802254\
+                     22918250ff66a41bd2f864a6a38cc5b6499dc43f7f2bd09e1e0f8f5885935124"
+                )
+            );
+        }
+
+        #[test]
+        fn reseed_interval() {
+            let mut drbg = HmacDrbg::new(&[0; 32], &[0; 16], &[]);
+            drbg.reseed_counter = RESEED_INTERVAL + 1;
+            assert_eq!(
+                drbg.generate(&mut [0; 16], &[]),
+                Err(DrbgError::ReseedRequired)
+            );
+            drbg.reseed(&[1; 32], &[]);
+            assert!(drbg.generate(&mut [0; 16], &[]).is_ok());
+            let mut large = [0; MAX_REQUEST_SIZE + 1];
+            assert_eq!(
+                drbg.generate(&mut large, &[]),
+                Err(DrbgError::RequestTooLarge)
             );
         }
-        SbiRet { a0, a1 }
     }
 }
 
@@ -57,15 +2679,29 @@ pub mod attestation {
     extern crate alloc;
     use alloc::vec::Vec;
     use coset::{
-        AsCborValue, CborSerializable, CoseKeyBuilder, CoseSign1, CoseSign1Builder, HeaderBuilder,
+        AsCborValue, CborSerializable, CoseKey, CoseKeyBuilder, CoseSign1, CoseSign1Builder,
+        HeaderBuilder, Label,
         cbor::{Value, Value::Integer},
         cwt::{self, ClaimsSet},
         iana::{self, Algorithm},
     };
     use ed25519_compact::{KeyPair, PublicKey, Seed, Signature};
-    use sha2::Sha512;
+    use subtle::ConstantTimeEq;
+    use zeroize::Zeroize;
+
+    use crate::{crypto::Sha512, measurement::HashAlgorithm};
 
     const CDI_LENGTH: usize = 32;
+    /// Largest EAT accepted in the DICE input
+    const DICE_TOKEN_MAX_LENGTH: usize = 4096;
+
+    /// Longest label accepted by the key ladder
+    pub const KEY_LADDER_MAX_LABEL: usize = 64;
+    const KEY_LADDER_SALT: &[u8] = b"Shadowfax Key Ladder";
+    /// Key ladder owners of a TVM CDI: the TVM itself (COVG_DERIVE_KEY) and the TSM, for the keys
+    /// it keeps on behalf of the TVM
+    pub const KEY_LADDER_OWNER_GUEST: u32 = 0;
+    pub const KEY_LADDER_OWNER_TSM: u32 = 1;
 
     const ASYM_SALT: [u8; 64] = [
         0x63, 0xB6, 0xA0, 0x4D, 0x2C, 0x07, 0x7F, 0xC1, 0x0F, 0x63, 0x9F, 0x21, 0xDA, 0x79, 0x38,
@@ -81,6 +2717,9 @@ pub mod attestation {
     const PLATFORM_STATE_LABEL: i64 = -70_002;
     const PLATFORM_SW_COMPONENTS_LABEL: i64 = -70_003;
     const TSM_PUBLIC_KEY_LABEL: i64 = -70_004;
+    const TVM_SW_COMPONENT_LABEL: i64 = -70_005;
+    const TVM_CHALLENGE_LABEL: i64 = -70_006;
+    const TVM_PUBLIC_KEY_LABEL: i64 = -70_007;
 
     #[derive(Debug)]
     pub enum AttestationError {
@@ -88,10 +2727,58 @@ pub mod attestation {
         MissingSignature,
         InvalidSignatureFormat,
         SignatureVerificationFailed,
+        /// The DICE input ends before one of its fields
+        Truncated,
+        /// A length of the DICE input is out of range
+        InvalidLength,
+        /// The DICE input does not hold a COSE_Sign1 token
+        InvalidToken,
+    }
+    /// Secret bytes (CDIs, derived keys): zeroed when dropped, so no copy is left behind in the
+    /// heap.
+    #[derive(Clone, Default)]
+    pub struct SecretBytes(Vec<u8>);
+
+    impl SecretBytes {
+        /// `len` zero bytes, to be filled in place
+        pub fn zeroed(len: usize) -> Self {
+            Self(alloc::vec![0; len])
+        }
+    }
+
+    impl From<&[u8]> for SecretBytes {
+        fn from(bytes: &[u8]) -> Self {
+            Self(Vec::from(bytes))
+        }
+    }
+
+    impl core::ops::Deref for SecretBytes {
+        type Target = [u8];
+        fn deref(&self) -> &[u8] {
+            &self.0
+        }
     }
+
+    impl core::ops::DerefMut for SecretBytes {
+        fn deref_mut(&mut self) -> &mut [u8] {
+            &mut self.0
+        }
+    }
+
+    impl Drop for SecretBytes {
+        fn drop(&mut self) {
+            self.0.zeroize();
+        }
+    }
+
+    /// Compare `a` and `b` in constant time, for secrets and values derived from them.
+    pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+        a.ct_eq(b).into()
+    }
+
     /// A Compound Device Identifier (CDI) wrapper.
     #[derive(Clone)]
-    pub struct Cdi(Vec<u8>);
+    pub struct Cdi(SecretBytes);
 
     impl Default for Cdi {
         fn default() -> Self {
@@ -102,7 +2789,7 @@ pub mod attestation {
     impl Cdi {
         /// Derive the next CDI given measurements (using HKDF).
         fn derive_next(&self, next_measurement: &[u8]) -> Self {
-            let mut okm = [0u8; CDI_LENGTH];
+            let mut okm = SecretBytes::zeroed(CDI_LENGTH);
             let next_measurement = if next_measurement.len() > 0 {
                 Some(next_measurement)
             } else {
@@ -112,17 +2799,51 @@ pub mod attestation {
             hkdf::Hkdf::<Sha512>::new(next_measurement, &self.0)
                 .expand(b"CDI_Attest", &mut okm)
                 .expect("HKDF output length");
-            Cdi(okm.to_vec())
+            Cdi(okm)
+        }
+        /// Derive a 32-byte symmetric key bound to this CDI (HKDF(salt, CDI) expanded with `info`).
+        pub fn derive_key(&self, salt: &[u8], info: &[u8]) -> SecretBytes {
+            let mut key = SecretBytes::zeroed(32);
+            hkdf::Hkdf::<Sha512>::new(Some(salt), &self.0)
+                .expand(info, &mut key)
+                .expect("HKDF for symmetric key");
+            key
+        }
+        /// Key ladder: derive the key of `label` for `owner`, one of the users of this CDI (e.g. a
+        /// supervisor domain for the platform CDI). The owner and the label size come before the
+        /// label in the HKDF info, so different (owner, label) pairs never share a key. Returns
+        /// `None` if the label is longer than `KEY_LADDER_MAX_LABEL`.
+        pub fn ladder_key(&self, owner: u32, label: &[u8]) -> Option<SecretBytes> {
+            if label.len() > KEY_LADDER_MAX_LABEL {
+                return None;
+            }
+
+            let mut info = [0u8; 8 + KEY_LADDER_MAX_LABEL];
+            info[..4].copy_from_slice(&owner.to_le_bytes());
+            info[4..8].copy_from_slice(&(label.len() as u32).to_le_bytes());
+            info[8..8 + label.len()].copy_from_slice(label);
+            Some(self.derive_key(KEY_LADDER_SALT, &info[..8 + label.len()]))
         }
         /// Derive an Ed25519 keypair from this CDI.
         fn derive_keys(&self) -> KeyPair {
-            let mut seed = [0u8; CDI_LENGTH];
+            let mut seed = Seed::new([0; Seed::BYTES]);
             // HKDF(salt=ASYM_SALT, input_key_material=this CDI)
             hkdf::Hkdf::<Sha512>::new(Some(&ASYM_SALT), &self.0)
-                .expand(b"Key Pair", &mut seed)
+                .expand(b"Key Pair", &mut *seed)
                 .expect("HKDF for key seed");
-            let seed = Seed::from_slice(&seed).unwrap();
-            KeyPair::from_seed(seed)
+            // The secret key of the pair wipes itself, the seed is a plain array
+            let keys = KeyPair::from_seed(seed);
+            seed.zeroize();
+            keys
+        }
+        /// Public key of the Ed25519 keypair derived from this CDI, the key certified by the token
+        /// of the layer.
+        pub fn public_key(&self) -> [u8; PublicKey::BYTES] {
+            *self.derive_keys().pk
+        }
+        /// Sign `msg` with the Ed25519 keypair derived from this CDI.
+        pub fn sign(&self, msg: &[u8]) -> [u8; Signature::BYTES] {
+            *self.derive_keys().sk.sign(msg, None)
         }
     }
 
@@ -202,6 +2923,7 @@ pub mod attestation {
 
             TsmAttestationContext {
                 platform_token: self.token.clone(),
+                platform_public_key: self.cdi.public_key(),
                 cdi: next_cdi,
                 token: tsm_token,
             }
@@ -215,9 +2937,10 @@ pub mod attestation {
     }
 
     impl PlatformAttestationContext {
-        pub fn init_from_addr(addr: usize) -> Self {
-            let ptr = addr as *const u8;
-            Self::from_raw_bytes(ptr)
+        /// Parse the DICE input region of `size` bytes at `addr`.
+        pub fn init_from_addr(addr: usize, size: usize) -> Result<Self, AttestationError> {
+            let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, size) };
+            Self::from_bytes(bytes)
         }
 
         /// Parses the Payload input formatted as follows:
@@ -226,36 +2949,65 @@ pub mod attestation {
         /// |--------|-----------------|--------|-----------------|
         /// | CDILEN |       CDI       | EATLEN |       EAT       |
         /// |--------|-----------------|--------|-----------------|
-        fn from_raw_bytes(ptr: *const u8) -> Self {
-            let mut offset = 0;
-
-            let read_u32 = |offset: &mut usize| -> u32 {
-                let mut buf = [0; 4];
-                for i in 0..4 {
-                    buf[i] = unsafe { core::ptr::read(ptr.add(*offset + i)) };
+        /// The lengths are untrusted: both fields must fit in `bytes`, the CDI must be
+        /// `CDI_LENGTH` bytes long and the EAT at most `DICE_TOKEN_MAX_LENGTH`.
+        pub fn from_bytes(bytes: &[u8]) -> Result<Self, AttestationError> {
+            let mut rest = bytes;
+            let mut take = |len: usize| -> Result<&[u8], AttestationError> {
+                if len > rest.len() {
+                    return Err(AttestationError::Truncated);
                 }
-                *offset += 4;
-                u32::from_le_bytes(buf)
+                let (field, tail) = rest.split_at(len);
+                rest = tail;
+                Ok(field)
             };
 
-            // Read CDI len and CDI
-            let len = read_u32(&mut offset) as usize;
-
-            // Read CDI
-            let cdi = {
-                let slice = unsafe { core::slice::from_raw_parts(ptr.add(offset), len) };
-                offset += len;
-                Cdi(Vec::from(slice))
-            };
+            let len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+            if len != CDI_LENGTH {
+                return Err(AttestationError::InvalidLength);
+            }
+            let cdi = Cdi(SecretBytes::from(take(len)?));
 
-            let len = read_u32(&mut offset) as usize;
+            let len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+            if len == 0 || len > DICE_TOKEN_MAX_LENGTH {
+                return Err(AttestationError::InvalidLength);
+            }
+            let token =
+                CoseSign1::from_slice(take(len)?).map_err(|_| AttestationError::InvalidToken)?;
 
-            // Read CoseSign1
-            let token = {
-                let slice = unsafe { core::slice::from_raw_parts(ptr.add(offset), len) };
-                CoseSign1::from_slice(slice).unwrap()
-            };
+            Ok(Self { cdi, token })
+        }
+    }
 
+    #[cfg(test)]
+    impl PlatformAttestationContext {
+        /// Platform layer of `cdi`, with a minimal self-signed token
+        pub(crate) fn for_tests(cdi: [u8; CDI_LENGTH]) -> Self {
+            let cdi = Cdi(SecretBytes::from(&cdi[..]));
+            let claims = Value::Map(alloc::vec![
+                (
+                    Value::Integer(PLATFORM_PUBLIC_KEY_LABEL.into()),
+                    Value::Bytes(cdi.public_key().to_vec()),
+                ),
+                (
+                    Value::Integer(MANUFACTURER_ID_LABEL.into()),
+                    Value::Bytes(alloc::vec![0; 64]),
+                ),
+                (
+                    Value::Integer(PLATFORM_STATE_LABEL.into()),
+                    Value::Integer(2.into()),
+                ),
+                (
+                    Value::Integer(PLATFORM_SW_COMPONENTS_LABEL.into()),
+                    Value::Array(Vec::new()),
+                ),
+            ]);
+            let mut payload = Vec::new();
+            coset::cbor::ser::into_writer(&claims, &mut payload).unwrap();
+            let token = CoseSign1Builder::new()
+                .payload(payload)
+                .create_signature(b"", |data| cdi.sign(data).to_vec())
+                .build();
             Self { cdi, token }
         }
     }
@@ -265,6 +3017,8 @@ pub mod attestation {
     pub struct TsmAttestationContext {
         cdi: Cdi,
         platform_token: CoseSign1,
+        /// Key of the platform layer, which signed `token`
+        platform_public_key: [u8; PublicKey::BYTES],
         token: CoseSign1,
     }
 
@@ -273,12 +3027,19 @@ pub mod attestation {
             Self {
                 cdi: Default::default(),
                 platform_token: Default::default(),
+                platform_public_key: [0; PublicKey::BYTES],
                 token: Default::default(),
             }
         }
     }
 
     impl TsmAttestationContext {
+        /// Public key of the platform layer: the TSM tokens it signed certify the TSMs of this
+        /// platform. All zeros without a DICE input, which no token verifies against.
+        pub fn platform_public_key(&self) -> &[u8; PublicKey::BYTES] {
+            &self.platform_public_key
+        }
+
         pub fn init_from_addr(addr: usize) -> Self {
             let ptr = addr as *const u8;
             Self::from_raw_bytes(ptr)
@@ -336,11 +3097,44 @@ pub mod attestation {
         }
 
         /// Returns (platform_token, tsm_token, tvm_token) packaged into a `Evidence` representation.
-        pub fn get_evidence(&self, _tvm_measurement: &[u8], challenge: &[u8]) -> Evidence {
-            // Build TVM token payload: include placeholder claims + the challenge
-            let mut tvm_payload = Vec::new();
-            tvm_payload.extend_from_slice(b"tvm-claims:");
-            tvm_payload.extend_from_slice(challenge);
+        /// The TVM token records the measurement together with the algorithm used to compute it.
+        pub fn get_evidence(
+            &self,
+            tvm_measurement: &[u8],
+            hash_alg: HashAlgorithm,
+            challenge: &[u8],
+        ) -> Evidence {
+            // Build TVM token payload: the TVM measurement + the challenge
+            self.evidence(TvmClaims {
+                tvm_component: RiscvCoveSwComponent::tvm(tvm_measurement, hash_alg),
+                challenge: challenge.to_vec(),
+                tvm_public_key: None,
+            })
+        }
+
+        /// Returns the certificate chain of the TVM layer: the platform and TSM tokens this layer
+        /// descends from, and a TVM token certifying the TVM measurement and the public key derived
+        /// from the TVM CDI. Unlike `get_evidence`, it takes no challenge: the chain of a TVM is
+        /// the same for every request.
+        pub fn get_cert_chain(&self, tvm_measurement: &[u8], hash_alg: HashAlgorithm) -> Evidence {
+            let tvm_public_key = CoseKeyBuilder::new_okp_key()
+                .algorithm(Algorithm::EdDSA)
+                .param(-2, Value::Bytes(self.cdi.derive_keys().pk.to_vec()))
+                .build();
+            self.evidence(TvmClaims {
+                tvm_component: RiscvCoveSwComponent::tvm(tvm_measurement, hash_alg),
+                challenge: Vec::new(),
+                tvm_public_key: tvm_public_key.to_vec().ok(),
+            })
+        }
+
+        fn evidence(&self, tvm_claims: TvmClaims) -> Evidence {
+            let tvm_payload = tvm_claims
+                .to_claims_set()
+                .to_cbor_value()
+                .unwrap()
+                .to_vec()
+                .unwrap();
 
             // Sign TVM token with TSM's key (i.e., key derived from TSM CDI)
             let tsm_key = self.cdi.derive_keys();
@@ -392,6 +3186,37 @@ pub mod attestation {
         }
     }
 
+    /// TSM key certified by the serialized TSM token `token`, once its signature is checked with
+    /// the platform key `platform_key`.
+    pub fn certified_tsm_key(
+        token: &[u8],
+        platform_key: &[u8],
+    ) -> Result<[u8; PublicKey::BYTES], AttestationError> {
+        let token = CoseSign1::from_slice(token).map_err(|_| AttestationError::InvalidToken)?;
+        verify_cose_signature(&token, platform_key)?;
+        let payload = token
+            .payload
+            .as_deref()
+            .ok_or(AttestationError::InvalidToken)?;
+        let claims = ClaimsSet::from_slice(payload).map_err(|_| AttestationError::InvalidToken)?;
+        let key = claims
+            .rest
+            .iter()
+            .find_map(|(name, value)| match (name, value) {
+                (cwt::ClaimName::PrivateUse(TSM_PUBLIC_KEY_LABEL), Value::Bytes(key)) => Some(key),
+                _ => None,
+            })
+            .ok_or(AttestationError::InvalidToken)?;
+        let key = CoseKey::from_slice(key).map_err(|_| AttestationError::InvalidPublicKey)?;
+        key.params
+            .iter()
+            .find_map(|(label, value)| match (label, value) {
+                (Label::Int(-2), Value::Bytes(x)) => x.as_slice().try_into().ok(),
+                _ => None,
+            })
+            .ok_or(AttestationError::InvalidPublicKey)
+    }
+
     /// Verify a COSE_Sign1 token using an external Ed25519 public key bytes.
     ///
     /// Uses `CoseSign1::verify_signature` to obtain (sig, data) and performs Ed25519 verification.
@@ -421,6 +3246,9 @@ pub mod attestation {
                 Self::MissingSignature => write!(f, "missing signature"),
                 Self::InvalidSignatureFormat => write!(f, "invalid signature format"),
                 Self::SignatureVerificationFailed => write!(f, "signature verification failed"),
+                Self::Truncated => write!(f, "truncated DICE input"),
+                Self::InvalidLength => write!(f, "invalid length in DICE input"),
+                Self::InvalidToken => write!(f, "invalid token in DICE input"),
             }
         }
     }
@@ -437,6 +3265,17 @@ pub mod attestation {
     }
 
     impl RiscvCoveSwComponent {
+        fn tvm(measurement: &[u8], hash_alg: HashAlgorithm) -> Self {
+            Self {
+                component_type: alloc::string::ToString::to_string(&"tvm"),
+                measurement: measurement.to_vec(),
+                svn: alloc::string::ToString::to_string(&"0"),
+                manifest_hash: None,
+                signer_pubkey_hash: Vec::new(),
+                hash_alg_id: alloc::string::ToString::to_string(hash_alg.name()),
+            }
+        }
+
         fn to_cbor(&self) -> Value {
             let mut map = Vec::from([
                 (
@@ -492,6 +3331,26 @@ pub mod attestation {
         }
     }
 
+    #[derive(Debug, Clone)]
+    struct TvmClaims {
+        tvm_component: RiscvCoveSwComponent,
+        challenge: Vec<u8>,
+        /// COSE key of the TVM layer, in the certificate chain
+        tvm_public_key: Option<Vec<u8>>,
+    }
+
+    impl TvmClaims {
+        fn to_claims_set(&self) -> ClaimsSet {
+            let mut claims = cwt::ClaimsSetBuilder::new()
+                .private_claim(TVM_SW_COMPONENT_LABEL, self.tvm_component.to_cbor())
+                .private_claim(TVM_CHALLENGE_LABEL, Value::Bytes(self.challenge.clone()));
+            if let Some(key) = &self.tvm_public_key {
+                claims = claims.private_claim(TVM_PUBLIC_KEY_LABEL, Value::Bytes(key.clone()));
+            }
+            claims.build()
+        }
+    }
+
     #[derive(Debug, Clone)]
     enum PlatformState {
         NotConfigured,
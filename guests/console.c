@@ -0,0 +1,64 @@
+/*
+ * Console of a TVM through a page shared with the host.
+ *
+ * The host maps the ring with sbi_covh_add_tvm_shared_pages at CONSOLE_GPA. The guest appends the
+ * bytes at head and calls COVG_CONSOLE_NOTIFY: the vCPU exits to the host, which prints the bytes
+ * up to head and moves tail. head and tail wrap around, the data is indexed modulo
+ * CONSOLE_RING_DATA_SIZE (see ConsoleRing in common).
+ */
+
+struct sbiret {
+    long error;
+    long value;
+};
+
+#define SBI_EXT_COVG                0x434F5647UL  /* 'C' 'O' 'V' 'G' */
+#define SBI_EXT_COVG_CONSOLE_NOTIFY 36UL
+
+#define CONSOLE_GPA                 0x100000UL
+#define CONSOLE_RING_DATA_SIZE      (4096 - 8)
+
+struct console_ring {
+    volatile unsigned int head;
+    volatile unsigned int tail;
+    volatile char data[CONSOLE_RING_DATA_SIZE];
+};
+
+static inline struct sbiret sbi_covg_console_notify(unsigned long ring_gpa) {
+    register unsigned long a0 asm("a0") = ring_gpa;
+    register unsigned long a1 asm("a1") = 0;
+    register unsigned long a6 asm("a6") = SBI_EXT_COVG_CONSOLE_NOTIFY;
+    register unsigned long a7 asm("a7") = SBI_EXT_COVG;
+
+    asm volatile (
+        "ecall"
+        : "+r"(a0), "+r"(a1)
+        : "r"(a6), "r"(a7)
+        : "memory"
+    );
+
+    struct sbiret ret = {
+        .error = (long)a0,
+        .value = (long)a1,
+    };
+    return ret;
+}
+
+static void console_write(const char *msg) {
+    struct console_ring *ring = (struct console_ring *)CONSOLE_GPA;
+
+    while (*msg) {
+        /* Ring full: let the host drain it */
+        if (ring->head - ring->tail == CONSOLE_RING_DATA_SIZE)
+            sbi_covg_console_notify(CONSOLE_GPA);
+        ring->data[ring->head % CONSOLE_RING_DATA_SIZE] = *msg++;
+        ring->head++;
+    }
+    sbi_covg_console_notify(CONSOLE_GPA);
+}
+
+int main (void) {
+    console_write("Hello from the TVM console ring\n");
+
+    return 0;
+}
@@ -0,0 +1,34 @@
+/* The VMM runs in the untrusted domain (see shadowfax/src/constants.rs). Only the first 2M are
+ * used for code and stack, the rest of the domain memory is managed by the VMM itself. */
+MEMORY
+{
+    RAM (rwx) : ORIGIN = 0x8A000000, LENGTH = 2M
+}
+
+_heap_size = 64K;
+
+_stack_top = ORIGIN(RAM) + LENGTH(RAM);
+
+SECTIONS {
+  . = ORIGIN(RAM);
+
+  .text : ALIGN(4K) {
+    KEEP(*(._start));
+    *(.text .text.*);
+    *(.rodata .rodata.*);
+  } > RAM
+
+  .data : ALIGN(8) {
+    *(.data .data.*);
+    *(.sdata .sdata.*);
+    . = ALIGN(8);
+    _heap_start = .;
+    . += _heap_size;
+    _heap_end = .;
+  } > RAM
+
+  .bss : ALIGN(8) {
+    *(.bss .bss.*);
+    *(.sbss .sbss.*);
+  } > RAM
+}
@@ -0,0 +1,116 @@
+/*
+ * SBI implementation offered to the ordinary VM (see vm.rs), enough for an unmodified S-mode
+ * kernel. The guest ecalls are decoded from a7/a6 and served as follows:
+ *  - Base: emulated, the probe only reports the extensions below. The implementation and machine
+ *    ids are the ones of the firmware;
+ *  - DBCN: forwarded to the firmware, buffer addresses are translated from guest physical
+ *    addresses;
+ *  - TIME: emulated, the deadline is kept in the guest state and the VMM injects the timer
+ *    interrupt when it expires (see vm.rs);
+ *  - IPI, RFENCE: the VM has a single vCPU, handled locally;
+ *  - SRST: forwarded to the firmware.
+ * Anything else is SBI_ERR_NOT_SUPPORTED. The result is returned in the guest a0/a1.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use common::sbi::{sbi_call, SbiRet, SBI_EXT_BASE, SBI_EXT_BASE_PROBE_EXT};
+
+use crate::{GUEST_RAM_BASE, GUEST_RAM_SIZE};
+
+const SBI_SUCCESS: isize = 0;
+const SBI_ERR_NOT_SUPPORTED: isize = -2;
+const SBI_ERR_INVALID_PARAM: isize = -3;
+
+// SBI v2.0
+const SBI_SPEC_VERSION: isize = 2 << 24;
+
+const SBI_EXT_BASE_GET_SPEC_VERSION: usize = 0;
+const SBI_EXT_BASE_GET_MIMPID: usize = 6;
+
+const SBI_EXT_TIME: usize = 0x54494D45;
+const SBI_EXT_TIME_SET_TIMER: usize = 0;
+const SBI_EXT_IPI: usize = 0x735049;
+const SBI_EXT_IPI_SEND_IPI: usize = 0;
+const SBI_EXT_RFENCE: usize = 0x52464E43;
+const SBI_EXT_RFENCE_REMOTE_FENCE_I: usize = 0;
+const SBI_EXT_RFENCE_REMOTE_SFENCE_VMA_ASID: usize = 2;
+const SBI_EXT_SRST: usize = 0x53525354;
+const SBI_EXT_DBCN: usize = 0x4442434E;
+const SBI_EXT_DBCN_CONSOLE_WRITE: usize = 0;
+const SBI_EXT_DBCN_CONSOLE_READ: usize = 1;
+const SBI_EXT_DBCN_CONSOLE_WRITE_BYTE: usize = 2;
+
+const GUEST_EXTENSIONS: [usize; 6] = [
+    SBI_EXT_BASE,
+    SBI_EXT_TIME,
+    SBI_EXT_IPI,
+    SBI_EXT_RFENCE,
+    SBI_EXT_SRST,
+    SBI_EXT_DBCN,
+];
+
+const CSR_HVIP: usize = 0x645;
+const HVIP_VSSIP: usize = 1 << 2;
+const HVIP_VSTIP: usize = 1 << 6;
+
+/// Serve the SBI call in the guest registers `regs`. `guest_ram` is the host address of the guest
+/// RAM, `timer` the deadline of the guest timer.
+pub fn handle_ecall(regs: &[usize; 32], guest_ram: usize, timer: &mut u64) -> SbiRet {
+    let (eid, fid) = (regs[17], regs[16]);
+    let args: [usize; 6] = regs[10..16].try_into().unwrap();
+
+    let (a0, a1) = match (eid, fid) {
+        (SBI_EXT_BASE, SBI_EXT_BASE_GET_SPEC_VERSION) => (SBI_SUCCESS, SBI_SPEC_VERSION),
+        (SBI_EXT_BASE, SBI_EXT_BASE_PROBE_EXT) => {
+            (SBI_SUCCESS, GUEST_EXTENSIONS.contains(&args[0]) as isize)
+        }
+        // implementation and machine ids
+        (SBI_EXT_BASE, fid) if fid <= SBI_EXT_BASE_GET_MIMPID => forward(eid, fid, args),
+        (SBI_EXT_TIME, SBI_EXT_TIME_SET_TIMER) => {
+            // the pending timer interrupt is acknowledged by programming the next one
+            unsafe { core::arch::asm!("csrc {}, {}", const CSR_HVIP, in(reg) HVIP_VSTIP) };
+            *timer = args[0] as u64;
+            (SBI_SUCCESS, 0)
+        }
+        (SBI_EXT_IPI, SBI_EXT_IPI_SEND_IPI) => {
+            // vCPU 0 is the only hart: hart_mask_base -1 selects all harts
+            let (hart_mask, hart_mask_base) = (args[0], args[1]);
+            if hart_mask_base == usize::MAX || (hart_mask_base == 0 && hart_mask & 1 != 0) {
+                unsafe { core::arch::asm!("csrs {}, {}", const CSR_HVIP, in(reg) HVIP_VSSIP) };
+            }
+            (SBI_SUCCESS, 0)
+        }
+        (SBI_EXT_RFENCE, SBI_EXT_RFENCE_REMOTE_FENCE_I) => {
+            unsafe { core::arch::asm!("fence.i") };
+            (SBI_SUCCESS, 0)
+        }
+        (SBI_EXT_RFENCE, fid) if fid <= SBI_EXT_RFENCE_REMOTE_SFENCE_VMA_ASID => {
+            // hfence.vvma zero, zero
+            unsafe { core::arch::asm!(".word 0x22000073") };
+            (SBI_SUCCESS, 0)
+        }
+        (SBI_EXT_SRST, _) => forward(eid, fid, args),
+        (SBI_EXT_DBCN, SBI_EXT_DBCN_CONSOLE_WRITE | SBI_EXT_DBCN_CONSOLE_READ) => {
+            let (num_bytes, gpa) = (args[0], args[1] | args[2] << 32);
+            match translate(gpa, num_bytes, guest_ram) {
+                Some(addr) => forward(eid, fid, [num_bytes, addr, 0, 0, 0, 0]),
+                None => (SBI_ERR_INVALID_PARAM, 0),
+            }
+        }
+        (SBI_EXT_DBCN, SBI_EXT_DBCN_CONSOLE_WRITE_BYTE) => forward(eid, fid, args),
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    };
+    SbiRet { a0, a1 }
+}
+
+fn forward(eid: usize, fid: usize, args: [usize; 6]) -> (isize, isize) {
+    let ret = sbi_call(eid, fid, &args);
+    (ret.a0, ret.a1)
+}
+
+/// Host address of the `size` bytes at `gpa`, if they are in the guest RAM.
+fn translate(gpa: usize, size: usize, guest_ram: usize) -> Option<usize> {
+    let offset = gpa.checked_sub(GUEST_RAM_BASE)?;
+    (offset.checked_add(size)? <= GUEST_RAM_SIZE).then_some(guest_ram + offset)
+}
@@ -0,0 +1,50 @@
+//! Print macros for logging
+
+use core::fmt::{self, Write};
+
+use common::sbi::sbi_call;
+
+const EDBCN: usize = 0x4442434E;
+const CONSOLE_WRITE_FID: usize = 0x0;
+
+/// Writer for print macro, on the SBI debug console of the firmware.
+struct Writer;
+impl core::fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            // On RV64 the whole address goes in base_addr_lo, base_addr_hi is 0
+            let ret = sbi_call(
+                EDBCN,
+                CONSOLE_WRITE_FID,
+                &[bytes.len(), bytes.as_ptr() as usize, 0, 0, 0, 0],
+            );
+            // The console may write less than asked
+            match ret.a0 {
+                0 if ret.a1 > 0 => bytes = &bytes[(ret.a1 as usize).min(bytes.len())..],
+                _ => return Err(fmt::Error),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Print function calling from print macro
+pub fn print_for_macro(args: fmt::Arguments) {
+    let mut writer = Writer;
+    // nowhere to report a console error
+    let _ = writer.write_fmt(args);
+}
+
+/// Print to standard output.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::log::print_for_macro(format_args!($($arg)*)));
+}
+
+/// Print with linebreak to standard output.
+#[macro_export]
+macro_rules! println {
+    ($fmt:expr) => ($crate::print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::print!(concat!($fmt, "\n"), $($arg)*));
+}
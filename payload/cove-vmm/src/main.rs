@@ -0,0 +1,429 @@
+/*
+ * Reference CoVE host. The VMM runs in the untrusted domain and drives the full TVM lifecycle
+ * through the TSM, in the order required by the CoVE specification:
+ *
+ *  1. sbi_probe_extension and sbi_supd_get_active_domains: discover the TSM domain. Without SUPD,
+ *     CoVE-H or a TSM domain, VM_GUESTS run as ordinary VMs instead (see vm.rs);
+ *  2. sbi_covh_get_tsm_info: read the TSM capabilities, again while the TSM is not ready. Both
+ *     steps go through `shadowfax_client::Discovery`;
+ *  3. sbi_covh_convert_pages: donate a pool of pages to the TSM (confidential memory). A TSM
+ *     without COVE_TSM_CAP_MEMORY_ALLOCATION gets the whole pool before the TVM is created (static
+ *     flow), otherwise the pages are converted as the TVM needs them;
+ *  4. sbi_covh_create_tvm: the page directory and the TVM state live in the pool;
+ *  5. sbi_covh_add_tvm_memory_region: declare the guest RAM;
+ *  6. sbi_covh_add_tvm_measured_pages: copy and measure every loadable segment of the guest, a
+ *     chunk of pages per call;
+ *  7. sbi_covh_add_tvm_zero_pages: back the rest of the guest RAM (stack, bss), one page per
+ *     call, all queued with a single sbi_covh_process_queue;
+ *  8. sbi_covh_create_tvm_vcpu;
+ *  9. sbi_covh_finalize_tvm: the measurement is sealed, no more pages can be added;
+ * 10. sbi_covh_add_tvm_shared_pages: map the console ring (CONSOLE_ADDR) at CONSOLE_GPA;
+ * 11. sbi_covh_run_tvm_vcpu: run the vCPU and service its exits. TVM_EXIT_CONSOLE drains the
+ *     console ring, TVM_EXIT_WFI runs the idle vCPU again, TVM_EXIT_UNSHARE reports the shared
+ *     pages the TVM gave back.
+ *
+ * Each successful call is reported as `[SHADOWFAX-TEST] step <name> PASS`, any error as
+ * `[SHADOWFAX-TEST] FAIL: <reason>`. The functional tests (test/functional) rely on these markers.
+ *
+ * Memory layout of the untrusted domain (16M at 0x8A000000):
+ *  - 0x8A000000: VMM code and stack (see memory.x);
+ *  - SCRATCH_ADDR: buffers shared with the TSM (TsmInfo, TVM params) and with the TVM (console);
+ *  - STAGING_ADDR: guest image, page aligned by GPA, before it is measured;
+ *  - POOL_ADDR: pages converted to confidential memory, or the memory of the ordinary VMs;
+ *  - BOOT_MANIFEST_ADDR: boot manifest and images, if any (see `common::boot_manifest`).
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+#![no_std]
+#![no_main]
+#![feature(fn_align)]
+
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+
+use common::boot_manifest::{self, ManifestError, BOOT_MANIFEST_ADDR};
+use common::sbi::{
+    cove_pack_fid, sbi_call, unshared_range, ConsoleRing, CovhQueueEntry, SbiRet, TsmInfo,
+    CONSOLE_RING_DATA_SIZE, CONSOLE_RING_SIZE, COVE_TSM_CAP_MEMORY_ALLOCATION,
+    COVH_QUEUE_MAX_ENTRIES, PAGE_SIZE, SBI_COVH_ADD_TVM_MEASURED_PAGES,
+    SBI_COVH_ADD_TVM_MEMORY_REGION, SBI_COVH_ADD_TVM_SHARED_PAGES, SBI_COVH_ADD_ZERO_PAGES,
+    SBI_COVH_CONVERT_PAGES, SBI_COVH_CREATE_TVM, SBI_COVH_CREATE_TVM_VCPU, SBI_COVH_EXT_ID,
+    SBI_COVH_FINALIZE_TVM, SBI_COVH_PROCESS_QUEUE, SBI_COVH_RUN_TVM_VCPU, TVM_EXIT_CONSOLE,
+    TVM_EXIT_REASON_MASK, TVM_EXIT_UNSHARE, TVM_EXIT_WFI, TVM_RUN_FAST_PATH,
+};
+use elf::{abi::PT_LOAD, endian::AnyEndian, ElfBytes};
+use linked_list_allocator::LockedHeap;
+use shadowfax_client::{transport::Ecall, Client, Discovery, DiscoveryError};
+
+mod guest_sbi;
+mod log;
+mod vm;
+
+extern crate alloc;
+#[global_allocator]
+/// Global allocator, needed by `common` and the COVH queue.
+static ALLOCATOR: LockedHeap = LockedHeap::empty();
+
+/// Guest image, see `guests/`.
+#[cfg(feature = "embed-elf")]
+static GUEST_ELF: &[u8] = include_bytes!("../../../guests/hellotvm.out");
+/// Guests run side by side when there is no TSM
+const VM_GUESTS: usize = 2;
+
+// Supervisor domain id of the TSM
+const TSM_SDID: usize = 1;
+
+const SCRATCH_ADDR: usize = 0x8A20_0000;
+// Console ring shared with the TVM, outside of the guest RAM
+const CONSOLE_ADDR: usize = SCRATCH_ADDR + 2 * PAGE_SIZE;
+const CONSOLE_GPA: usize = 0x10_0000;
+const STAGING_ADDR: usize = 0x8A40_0000;
+const POOL_ADDR: usize = 0x8A80_0000;
+const POOL_PAGES: usize = 1024;
+const _: () = assert!(VM_GUESTS * vm::VM_MEMORY_SIZE <= POOL_PAGES * PAGE_SIZE);
+
+// GET_TSM_INFO calls while the TSM is not ready, TSM_WAIT ticks apart (1ms at 10MHz)
+const TSM_INFO_ATTEMPTS: usize = 100;
+const TSM_WAIT: u64 = 10_000;
+const PAGE_DIRECTORY_SIZE: usize = 4 * PAGE_SIZE;
+
+// Guest RAM as declared in guests/linker.ld: [0x0, 0x21000)
+pub const GUEST_RAM_BASE: usize = 0x0;
+pub const GUEST_RAM_SIZE: usize = 0x21000;
+pub const GUEST_RAM_PAGES: usize = GUEST_RAM_SIZE / PAGE_SIZE;
+// The zero pages are added with a single queue
+const _: () = assert!(GUEST_RAM_PAGES <= COVH_QUEUE_MAX_ENTRIES);
+
+const VCPU_ID: usize = 0;
+
+/// Prefix of the markers parsed by the functional tests.
+pub const TEST_MARKER: &str = "[SHADOWFAX-TEST]";
+
+#[inline(never)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("{} FAIL: {}", TEST_MARKER, info);
+    loop {}
+}
+
+#[no_mangle]
+#[unsafe(naked)]
+#[link_section = "._start"]
+extern "C" fn _start() -> ! {
+    core::arch::naked_asm!(
+        r#"
+        .attribute arch, "rv64imac"
+        la sp, {stack_top}
+        call {main}
+        "#,
+        stack_top = sym _stack_top,
+        main = sym main,
+    )
+}
+
+unsafe extern "C" {
+    static _stack_top: u8;
+    static mut _heap_start: u8;
+    static _heap_end: u8;
+}
+
+fn covh(fid: usize, args: [usize; 6]) -> SbiRet {
+    sbi_call(SBI_COVH_EXT_ID, cove_pack_fid(TSM_SDID, fid), &args)
+}
+
+/// Issue a COVH call and stop the VMM if it fails.
+fn covh_ok(name: &str, fid: usize, args: [usize; 6]) -> usize {
+    let ret = covh(fid, args);
+    if ret.a0 != 0 {
+        panic!("{} failed ({})", name, ret.a0);
+    }
+    println!("{} step {} PASS", TEST_MARKER, name);
+    ret.a1 as usize
+}
+
+extern "C" fn main() -> ! {
+    unsafe {
+        let heap_start = &raw mut _heap_start;
+        let heap_size = &raw const _heap_end as usize - heap_start as usize;
+        ALLOCATOR.lock().init(heap_start, heap_size);
+    }
+
+    // 1-2. Without a TSM, the guests run as ordinary VMs. TsmInfo lands in SCRATCH_ADDR.
+    let mut client = Client::new(Ecall, TSM_SDID);
+    let mut discovery = Discovery::new(TSM_INFO_ATTEMPTS, wait_tsm);
+    let buffer = unsafe { &mut *(SCRATCH_ADDR as *mut TsmInfo) };
+    let capabilities = match discovery.tsm_info(&mut client, TSM_SDID, buffer) {
+        Ok(info) => info.tsm_capabilities,
+        Err(
+            error @ (DiscoveryError::NoSupd
+            | DiscoveryError::NoCovh
+            | DiscoveryError::NoDomain(_)
+            | DiscoveryError::NoTsm(_)),
+        ) => {
+            println!(
+                "[VMM] no TSM ({}), running {} ordinary VMs",
+                error, VM_GUESTS
+            );
+            vm::run(&[guest_elf(); VM_GUESTS], POOL_ADDR);
+        }
+        Err(error) => panic!("get_tsm_info failed ({})", error),
+    };
+    println!("{} step get_tsm_info PASS", TEST_MARKER);
+    println!("[VMM] tsm capabilities {:#x}", capabilities);
+
+    // 3. Donate the pool
+    let mut pool = PagePool::new(capabilities);
+
+    // 4. Page directory at the start of the pool, followed by the TVM state page
+    let page_table_addr = pool.take(PAGE_DIRECTORY_SIZE / PAGE_SIZE);
+    let state_addr = pool.take(1);
+    let params_addr = SCRATCH_ADDR + PAGE_SIZE;
+    unsafe {
+        core::ptr::write_volatile(params_addr as *mut usize, page_table_addr);
+        core::ptr::write_volatile((params_addr + 8) as *mut usize, state_addr);
+    }
+    let tvm_id = covh_ok(
+        "create_tvm",
+        SBI_COVH_CREATE_TVM,
+        [params_addr, 16, 0, 0, 0, 0],
+    );
+
+    // 5. Guest RAM
+    covh_ok(
+        "add_tvm_memory_region",
+        SBI_COVH_ADD_TVM_MEMORY_REGION,
+        [tvm_id, GUEST_RAM_BASE, GUEST_RAM_SIZE, 0, 0, 0],
+    );
+
+    // 6-7. Guest pages are taken from the pool after the TVM state
+    let entry = load_guest(tvm_id, &mut pool);
+
+    // 8. A single vCPU
+    covh_ok(
+        "create_tvm_vcpu",
+        SBI_COVH_CREATE_TVM_VCPU,
+        [tvm_id, VCPU_ID, 0, 0, 0, 0],
+    );
+
+    // 9. Seal the measurement
+    covh_ok(
+        "finalize_tvm",
+        SBI_COVH_FINALIZE_TVM,
+        [tvm_id, entry, 0, 0, 0, 0],
+    );
+
+    // 10. Console ring, never converted: both the VMM and the TVM access it
+    unsafe { core::ptr::write_bytes(CONSOLE_ADDR as *mut u8, 0, CONSOLE_RING_SIZE) };
+    covh_ok(
+        "add_tvm_shared_pages",
+        SBI_COVH_ADD_TVM_SHARED_PAGES,
+        [
+            tvm_id,
+            CONSOLE_ADDR,
+            0,
+            CONSOLE_RING_SIZE / PAGE_SIZE,
+            CONSOLE_GPA,
+            0,
+        ],
+    );
+
+    // 11. Run the vCPU. Guest SBI calls are forwarded to the firmware by the TSM, only the console
+    // and WFI exits come back here. On the fast path, the TSM resumes a WFI with an interrupt
+    // pending itself.
+    loop {
+        let ret = covh(
+            SBI_COVH_RUN_TVM_VCPU,
+            [tvm_id, VCPU_ID, TVM_RUN_FAST_PATH, 0, 0, 0],
+        );
+        if ret.a0 != 0 {
+            panic!("run_tvm_vcpu failed ({})", ret.a0);
+        }
+        let exit = ret.a1 as usize;
+        match exit & TVM_EXIT_REASON_MASK {
+            TVM_EXIT_CONSOLE => drain_console(exit & !TVM_EXIT_REASON_MASK),
+            // Nothing else runs on this hart: resume the vCPU right away
+            TVM_EXIT_WFI => {}
+            // The TSM zeroed the pages: nothing to read there, the VMM just stops using them
+            TVM_EXIT_UNSHARE => {
+                let (gpa, num_pages) = unshared_range(exit);
+                println!("[VMM] TVM unshared {} pages at GPA {:#x}", num_pages, gpa);
+            }
+            reason => panic!("unknown TVM exit {}", reason),
+        }
+    }
+}
+
+/// Print the bytes the TVM wrote in the console ring at `ring_gpa`.
+fn drain_console(ring_gpa: usize) {
+    if ring_gpa != CONSOLE_GPA {
+        panic!("console exit for unknown ring {:#x}", ring_gpa);
+    }
+    let ring = CONSOLE_ADDR as *mut ConsoleRing;
+
+    // The ring is written by the guest: never read more than the ring holds
+    let head = unsafe { core::ptr::read_volatile(&raw const (*ring).head) };
+    let mut tail = unsafe { core::ptr::read_volatile(&raw const (*ring).tail) };
+    let pending = (head.wrapping_sub(tail) as usize).min(CONSOLE_RING_DATA_SIZE);
+
+    for _ in 0..pending {
+        let index = tail as usize % CONSOLE_RING_DATA_SIZE;
+        let byte = unsafe { core::ptr::read_volatile(&raw const (*ring).data[index]) };
+        print!("{}", byte as char);
+        tail = tail.wrapping_add(1);
+    }
+    unsafe { core::ptr::write_volatile(&raw mut (*ring).tail, tail) };
+}
+
+/// Between two GET_TSM_INFO of a TSM not ready yet.
+fn wait_tsm() {
+    let start = vm::read_time();
+    while vm::read_time() - start < TSM_WAIT {
+        core::hint::spin_loop();
+    }
+}
+
+/// Pages of the pool given to the TSM. A TSM without dynamic memory allocation gets the whole pool
+/// upfront, otherwise pages are converted when taken.
+struct PagePool {
+    next: usize,
+    converted: usize,
+    on_demand: bool,
+}
+
+impl PagePool {
+    fn new(capabilities: usize) -> Self {
+        let on_demand = capabilities & (1 << COVE_TSM_CAP_MEMORY_ALLOCATION) != 0;
+        println!(
+            "[VMM] {} memory allocation",
+            if on_demand { "dynamic" } else { "static" }
+        );
+        let mut pool = Self {
+            next: POOL_ADDR,
+            converted: POOL_ADDR,
+            on_demand,
+        };
+        if !on_demand {
+            pool.convert(POOL_PAGES);
+        }
+        pool
+    }
+
+    fn convert(&mut self, num_pages: usize) {
+        if self.converted + num_pages * PAGE_SIZE > POOL_ADDR + POOL_PAGES * PAGE_SIZE {
+            panic!("page pool exhausted");
+        }
+        covh_ok(
+            "convert_pages",
+            SBI_COVH_CONVERT_PAGES,
+            [self.converted, num_pages, 0, 0, 0, 0],
+        );
+        self.converted += num_pages * PAGE_SIZE;
+    }
+
+    /// Take `num_pages` confidential pages.
+    fn take(&mut self, num_pages: usize) -> usize {
+        let addr = self.next;
+        self.next += num_pages * PAGE_SIZE;
+        if self.next > self.converted {
+            if !self.on_demand {
+                panic!("page pool exhausted");
+            }
+            self.convert((self.next - self.converted) / PAGE_SIZE);
+        }
+        addr
+    }
+}
+
+/// The `guest` entry of the boot manifest, else `GUEST_ELF`.
+fn guest_elf() -> &'static [u8] {
+    match unsafe { boot_manifest::find(BOOT_MANIFEST_ADDR, "guest") } {
+        Ok(image) => return image.data,
+        Err(ManifestError::NoManifest) => {}
+        Err(e) => println!("[VMM] no guest in the boot manifest: {:?}", e),
+    }
+
+    #[cfg(feature = "embed-elf")]
+    return GUEST_ELF;
+    #[cfg(not(feature = "embed-elf"))]
+    panic!("no guest image, build with the embed-elf feature")
+}
+
+/// Stage every loadable segment of the guest and add it as measured pages. The remaining guest
+/// RAM is backed with zero pages. Returns the guest entry point.
+fn load_guest(tvm_id: usize, pool: &mut PagePool) -> usize {
+    let elf = ElfBytes::<AnyEndian>::minimal_parse(guest_elf()).expect("invalid guest ELF");
+    let segments = elf.segments().expect("guest ELF without segments");
+
+    // Guest RAM pages already backed
+    let mut mapped = [false; GUEST_RAM_PAGES];
+
+    for phdr in segments.iter().filter(|phdr| phdr.p_type == PT_LOAD) {
+        let gpa = phdr.p_paddr as usize;
+        let gpa_page = gpa & !(PAGE_SIZE - 1);
+        let num_pages = (gpa - gpa_page + phdr.p_memsz as usize).div_ceil(PAGE_SIZE);
+        let data = elf.segment_data(&phdr).expect("invalid guest segment");
+
+        // Stage the segment at the same page offset it has in the guest
+        let staging = STAGING_ADDR + gpa_page;
+        unsafe {
+            core::ptr::write_bytes(staging as *mut u8, 0, num_pages * PAGE_SIZE);
+            core::ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                (STAGING_ADDR + gpa) as *mut u8,
+                data.len(),
+            );
+        }
+
+        // The TSM adds a chunk of the pages per call and returns how many
+        let dest = pool.take(num_pages);
+        let mut added = 0;
+        while added < num_pages {
+            let offset = added * PAGE_SIZE;
+            let chunk = covh_ok(
+                "add_tvm_measured_pages",
+                SBI_COVH_ADD_TVM_MEASURED_PAGES,
+                [
+                    tvm_id,
+                    staging + offset,
+                    dest + offset,
+                    0,
+                    num_pages - added,
+                    gpa_page + offset,
+                ],
+            );
+            assert!(chunk > 0, "add_tvm_measured_pages added no page");
+            added += chunk;
+        }
+
+        let first = (gpa_page - GUEST_RAM_BASE) / PAGE_SIZE;
+        mapped[first..first + num_pages].fill(true);
+    }
+
+    let queue: Vec<CovhQueueEntry> = mapped
+        .iter()
+        .enumerate()
+        .filter(|(_, mapped)| !**mapped)
+        .map(|(i, _)| CovhQueueEntry {
+            fid: SBI_COVH_ADD_ZERO_PAGES,
+            args: [
+                tvm_id,
+                pool.take(1),
+                0,
+                1,
+                GUEST_RAM_BASE + i * PAGE_SIZE,
+                0,
+            ],
+            error: 0,
+            value: 0,
+        })
+        .collect();
+    if !queue.is_empty() {
+        covh_ok(
+            "add_tvm_zero_pages",
+            SBI_COVH_PROCESS_QUEUE,
+            [queue.as_ptr() as usize, queue.len(), 0, 0, 0, 0],
+        );
+    }
+
+    elf.ehdr.e_entry as usize
+}
@@ -0,0 +1,399 @@
+/*
+ * Ordinary (non-confidential) VMs, used when there is no TSM to run the guests as TVMs. The VMM
+ * sets up the H extension itself:
+ *  - the RAM of each guest is backed by pages of the untrusted domain and mapped by its own Sv39x4
+ *    G-stage page table (a 16K root, one level 1 and one level 0 table are enough for
+ *    GUEST_RAM_SIZE);
+ *  - hgatp points to the root of the running guest, sepc to its pc and hstatus.SPV is set, so that
+ *    sret enters VS-mode;
+ *  - traps from VS-mode land in `vm_trap`, which saves the guest registers in the VmContext held by
+ *    sscratch, services the trap on the VMM stack and resumes the guest returned by the handler.
+ *    Guest SBI calls are served by guest_sbi.rs.
+ *
+ * The guests are scheduled round-robin, with a time slice of TIME_SLICE ticks. The firmware timer
+ * is shared between the time slices and the guest timers: it is programmed with the earliest
+ * deadline, and an expired guest timer is injected as a VS timer interrupt (hvip.VSTIP) when the
+ * guest runs. A guest stops on an unexpected trap, the others keep running.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use alloc::vec::Vec;
+
+use common::sbi::{sbi_call, PAGE_SIZE};
+use elf::{abi::PT_LOAD, endian::AnyEndian, ElfBytes};
+use spin::Mutex;
+
+use crate::{guest_sbi, println, GUEST_RAM_BASE, GUEST_RAM_PAGES, GUEST_RAM_SIZE, TEST_MARKER};
+
+const CSR_HSTATUS: usize = 0x600;
+const HSTATUS_SPV: usize = 1 << 7;
+const SSTATUS_SPP: usize = 1 << 8;
+const HGATP_MODE_SV39X4: usize = 8 << 60;
+const HVIP_VSTIP: usize = 1 << 6;
+
+// G-stage leaf: valid, readable, writable, executable and user (required by the G-stage)
+const PTE_V: usize = 1 << 0;
+const PTE_LEAF: usize = PTE_V | 0b1111 << 1;
+
+const ROOT_TABLE_SIZE: usize = 4 * PAGE_SIZE;
+/// Page tables and RAM of a guest, the next root stays 16K aligned
+pub const VM_MEMORY_SIZE: usize =
+    (ROOT_TABLE_SIZE + 2 * PAGE_SIZE + GUEST_RAM_SIZE).next_multiple_of(ROOT_TABLE_SIZE);
+
+/// 10ms with the 10MHz timebase of QEMU virt
+const TIME_SLICE: u64 = 100_000;
+
+const SCAUSE_INTERRUPT: usize = 1 << 63;
+const IRQ_S_TIMER: usize = 5;
+const EXC_VS_ECALL: usize = 10;
+
+const SBI_EXT_TIME: usize = 0x54494D45;
+const SBI_EXT_TIME_SET_TIMER: usize = 0;
+
+macro_rules! csr_read {
+    ($csr:literal) => {{
+        let value: usize;
+        unsafe { core::arch::asm!(concat!("csrr {}, ", $csr), out(reg) value) };
+        value
+    }};
+}
+
+macro_rules! csr_write {
+    ($csr:literal, $value:expr) => {
+        unsafe { core::arch::asm!(concat!("csrw ", $csr, ", {}"), in(reg) $value) }
+    };
+}
+
+/// Guest registers saved by `vm_trap`, followed by the VMM stack pointer. The other fields hold
+/// the state of a guest which is not running.
+#[repr(C)]
+struct VmContext {
+    regs: [usize; 32],
+    hs_sp: usize,
+    id: usize,
+    /// Host address of the guest RAM
+    guest_ram: usize,
+    hgatp: usize,
+    sepc: usize,
+    hvip: usize,
+    /// vsstatus, vsie, vstvec, vsscratch, vsepc, vscause, vstval, vsatp
+    vs_csrs: [usize; 8],
+    /// Deadline programmed by the guest with set_timer
+    timer: u64,
+    stopped: bool,
+}
+
+impl VmContext {
+    fn save_csrs(&mut self) {
+        self.sepc = csr_read!("sepc");
+        self.hvip = csr_read!("0x645");
+        self.vs_csrs = [
+            csr_read!("0x200"),
+            csr_read!("0x204"),
+            csr_read!("0x205"),
+            csr_read!("0x240"),
+            csr_read!("0x241"),
+            csr_read!("0x242"),
+            csr_read!("0x243"),
+            csr_read!("0x280"),
+        ];
+    }
+
+    fn restore_csrs(&self) {
+        csr_write!("sepc", self.sepc);
+        csr_write!("0x645", self.hvip);
+        csr_write!("0x680", self.hgatp);
+        // hfence.gvma zero, zero: all the guests share VMID 0
+        unsafe { core::arch::asm!(".word 0x62000073") };
+        let [vsstatus, vsie, vstvec, vsscratch, vsepc, vscause, vstval, vsatp] = self.vs_csrs;
+        csr_write!("0x200", vsstatus);
+        csr_write!("0x204", vsie);
+        csr_write!("0x205", vstvec);
+        csr_write!("0x240", vsscratch);
+        csr_write!("0x241", vsepc);
+        csr_write!("0x242", vscause);
+        csr_write!("0x243", vstval);
+        csr_write!("0x280", vsatp);
+    }
+}
+
+struct Scheduler {
+    vms: Vec<VmContext>,
+    current: usize,
+    slice_end: u64,
+}
+
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+
+unsafe extern "C" {
+    static _stack_top: u8;
+}
+
+pub fn read_time() -> u64 {
+    csr_read!("time") as u64
+}
+
+/// Load each guest in its VM_MEMORY_SIZE bytes from `base_addr` and run them in VS-mode.
+pub fn run(guests: &[&[u8]], base_addr: usize) -> ! {
+    let vms = guests
+        .iter()
+        .enumerate()
+        .map(|(id, guest_elf)| create_vm(id, guest_elf, base_addr + id * VM_MEMORY_SIZE))
+        .collect();
+    let mut scheduler = Scheduler {
+        vms,
+        current: 0,
+        slice_end: read_time() + TIME_SLICE,
+    };
+    let first = &mut scheduler.vms[0] as *mut VmContext;
+    scheduler.vms[0].restore_csrs();
+    scheduler.program_timer();
+    *SCHEDULER.lock() = Some(scheduler);
+
+    unsafe {
+        core::arch::asm!(
+            "csrw stvec, {trap}",
+            "csrs sstatus, {spp}",
+            "csrs sie, {stie}",
+            "csrs {hstatus}, {spv}",
+            trap = in(reg) vm_trap as usize,
+            spp = in(reg) SSTATUS_SPP,
+            stie = in(reg) 1 << IRQ_S_TIMER,
+            hstatus = const CSR_HSTATUS,
+            spv = in(reg) HSTATUS_SPV,
+        );
+        vm_resume(first)
+    }
+}
+
+/// Map the guest RAM and load the guest in the memory at `base_addr`.
+fn create_vm(id: usize, guest_elf: &[u8], base_addr: usize) -> VmContext {
+    // Page tables first: the root must be 16K aligned
+    let root = base_addr;
+    let level1 = root + ROOT_TABLE_SIZE;
+    let level0 = level1 + PAGE_SIZE;
+    let ram = level0 + PAGE_SIZE;
+    unsafe {
+        core::ptr::write_bytes(root as *mut u8, 0, ROOT_TABLE_SIZE + 2 * PAGE_SIZE);
+        core::ptr::write_bytes(ram as *mut u8, 0, GUEST_RAM_SIZE);
+    }
+
+    // The guest RAM fits in a single level 0 table
+    let table = |addr: usize| (addr >> 12) << 10 | PTE_V;
+    unsafe {
+        *(root as *mut usize).add(GUEST_RAM_BASE >> 30) = table(level1);
+        *(level1 as *mut usize).add((GUEST_RAM_BASE >> 21) & 0x1ff) = table(level0);
+        for i in 0..GUEST_RAM_PAGES {
+            let pte = (level0 as *mut usize).add(((GUEST_RAM_BASE >> 12) + i) & 0x1ff);
+            *pte = ((ram + i * PAGE_SIZE) >> 12) << 10 | PTE_LEAF;
+        }
+    }
+
+    let entry = load_guest(guest_elf, ram);
+    println!("{} step create_vm PASS", TEST_MARKER);
+
+    VmContext {
+        regs: [0; 32],
+        hs_sp: &raw const _stack_top as usize,
+        id,
+        guest_ram: ram,
+        hgatp: HGATP_MODE_SV39X4 | root >> 12,
+        sepc: entry,
+        hvip: 0,
+        vs_csrs: [0; 8],
+        timer: u64::MAX,
+        stopped: false,
+    }
+}
+
+/// Copy every loadable segment of the guest to the guest RAM at `ram`. Returns the entry point.
+fn load_guest(guest_elf: &[u8], ram: usize) -> usize {
+    let elf = ElfBytes::<AnyEndian>::minimal_parse(guest_elf).expect("invalid guest ELF");
+    let segments = elf.segments().expect("guest ELF without segments");
+
+    for phdr in segments.iter().filter(|phdr| phdr.p_type == PT_LOAD) {
+        let gpa = phdr.p_paddr as usize;
+        let guest_ram = GUEST_RAM_BASE..=GUEST_RAM_BASE + GUEST_RAM_SIZE;
+        if !guest_ram.contains(&gpa) || !guest_ram.contains(&(gpa + phdr.p_memsz as usize)) {
+            panic!("guest segment at {:#x} outside of the guest RAM", gpa);
+        }
+        let data = elf.segment_data(&phdr).expect("invalid guest segment");
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                (ram + gpa - GUEST_RAM_BASE) as *mut u8,
+                data.len(),
+            );
+        }
+    }
+
+    elf.ehdr.e_entry as usize
+}
+
+impl Scheduler {
+    /// Inject the expired guest timers, switch to the next runnable guest at the end of the time
+    /// slice and program the firmware timer. Returns the guest to resume.
+    fn schedule(&mut self) -> *mut VmContext {
+        let now = read_time();
+        for (i, vm) in self.vms.iter_mut().enumerate() {
+            if vm.timer <= now {
+                // pending until the guest programs its next timer
+                vm.timer = u64::MAX;
+                if i == self.current {
+                    unsafe { core::arch::asm!("csrs 0x645, {}", in(reg) HVIP_VSTIP) };
+                } else {
+                    vm.hvip |= HVIP_VSTIP;
+                }
+            }
+        }
+
+        if now >= self.slice_end || self.vms[self.current].stopped {
+            let n = self.vms.len();
+            let next = (1..=n)
+                .map(|i| (self.current + i) % n)
+                .find(|&i| !self.vms[i].stopped)
+                .expect("all the guests stopped");
+            if next != self.current {
+                self.vms[self.current].save_csrs();
+                self.vms[next].restore_csrs();
+                self.current = next;
+            }
+            self.slice_end = now + TIME_SLICE;
+        }
+
+        self.program_timer();
+        &mut self.vms[self.current]
+    }
+
+    fn program_timer(&self) {
+        let deadline = self
+            .vms
+            .iter()
+            .map(|vm| vm.timer)
+            .fold(self.slice_end, u64::min);
+        sbi_call(
+            SBI_EXT_TIME,
+            SBI_EXT_TIME_SET_TIMER,
+            &[deadline as usize, 0, 0, 0, 0, 0],
+        );
+    }
+}
+
+/// Service a trap from the running guest. Returns the context of the guest to resume.
+extern "C" fn vm_trap_handler(_ctx: *mut VmContext) -> *mut VmContext {
+    let (scause, stval, sepc) = (csr_read!("scause"), csr_read!("stval"), csr_read!("sepc"));
+    let mut scheduler = SCHEDULER.lock();
+    let scheduler = scheduler.as_mut().unwrap();
+    let vm = &mut scheduler.vms[scheduler.current];
+
+    match scause {
+        EXC_VS_ECALL => {
+            let ret = guest_sbi::handle_ecall(&vm.regs, vm.guest_ram, &mut vm.timer);
+            vm.regs[10] = ret.a0 as usize;
+            vm.regs[11] = ret.a1 as usize;
+            csr_write!("sepc", sepc + 4);
+        }
+        // expired timers are handled by the scheduler
+        cause if cause == SCAUSE_INTERRUPT | IRQ_S_TIMER => {}
+        _ => {
+            println!(
+                "[VMM] vm {} stopped: scause {:#x}, stval {:#x}, sepc {:#x}",
+                vm.id, scause, stval, sepc
+            );
+            vm.stopped = true;
+        }
+    }
+    scheduler.schedule()
+}
+
+/// Trap vector while a guest runs: save the guest registers in the VmContext held by sscratch,
+/// call `vm_trap_handler` on the VMM stack and resume the guest it returns.
+#[unsafe(naked)]
+#[rustc_align(4)]
+unsafe extern "C" fn vm_trap() -> ! {
+    core::arch::naked_asm!(
+        "csrrw t6, sscratch, t6",
+        "sd x1, 8(t6)",
+        "sd x2, 16(t6)",
+        "sd x3, 24(t6)",
+        "sd x4, 32(t6)",
+        "sd x5, 40(t6)",
+        "sd x6, 48(t6)",
+        "sd x7, 56(t6)",
+        "sd x8, 64(t6)",
+        "sd x9, 72(t6)",
+        "sd x10, 80(t6)",
+        "sd x11, 88(t6)",
+        "sd x12, 96(t6)",
+        "sd x13, 104(t6)",
+        "sd x14, 112(t6)",
+        "sd x15, 120(t6)",
+        "sd x16, 128(t6)",
+        "sd x17, 136(t6)",
+        "sd x18, 144(t6)",
+        "sd x19, 152(t6)",
+        "sd x20, 160(t6)",
+        "sd x21, 168(t6)",
+        "sd x22, 176(t6)",
+        "sd x23, 184(t6)",
+        "sd x24, 192(t6)",
+        "sd x25, 200(t6)",
+        "sd x26, 208(t6)",
+        "sd x27, 216(t6)",
+        "sd x28, 224(t6)",
+        "sd x29, 232(t6)",
+        "sd x30, 240(t6)",
+        // guest t6 was swapped in sscratch
+        "csrr t0, sscratch",
+        "sd t0, 248(t6)",
+        "ld sp, 256(t6)",
+        "mv a0, t6",
+        "call {handler}",
+        "j {resume}",
+        handler = sym vm_trap_handler,
+        resume = sym vm_resume,
+    )
+}
+
+/// Restore the guest registers from `ctx` and enter the guest. sscratch keeps `ctx` for the next
+/// trap.
+#[unsafe(naked)]
+unsafe extern "C" fn vm_resume(ctx: *mut VmContext) -> ! {
+    core::arch::naked_asm!(
+        "mv t6, a0",
+        "csrw sscratch, t6",
+        "ld x1, 8(t6)",
+        "ld x2, 16(t6)",
+        "ld x3, 24(t6)",
+        "ld x4, 32(t6)",
+        "ld x5, 40(t6)",
+        "ld x6, 48(t6)",
+        "ld x7, 56(t6)",
+        "ld x8, 64(t6)",
+        "ld x9, 72(t6)",
+        "ld x10, 80(t6)",
+        "ld x11, 88(t6)",
+        "ld x12, 96(t6)",
+        "ld x13, 104(t6)",
+        "ld x14, 112(t6)",
+        "ld x15, 120(t6)",
+        "ld x16, 128(t6)",
+        "ld x17, 136(t6)",
+        "ld x18, 144(t6)",
+        "ld x19, 152(t6)",
+        "ld x20, 160(t6)",
+        "ld x21, 168(t6)",
+        "ld x22, 176(t6)",
+        "ld x23, 184(t6)",
+        "ld x24, 192(t6)",
+        "ld x25, 200(t6)",
+        "ld x26, 208(t6)",
+        "ld x27, 216(t6)",
+        "ld x28, 224(t6)",
+        "ld x29, 232(t6)",
+        "ld x30, 240(t6)",
+        "ld t6, 248(t6)",
+        "sret",
+    )
+}
@@ -0,0 +1,82 @@
+####################################################################################################
+# Write a boot manifest (see `common::boot_manifest`): the header, the image entries and the images,
+# in a single blob loaded at the manifest address with QEMU `-device loader,force-raw=on`. The
+# images follow the entries in the order of the command line, page aligned.
+#
+# Usage:
+#     boot_manifest.py --address 0x8AC00000 -o bin/boot-manifest.bin \
+#         guest=guests/hellotvm.out tsm=target/.../tsm:bin/tsm.bin.signature
+#
+# An image is `name=path`, or `name=path:signature` for a signed one (raw 64-byte Ed25519
+# signature, as written by `openssl pkeyutl -sign`). The images the host reads must lie in the
+# untrusted memory: put them first.
+#
+# Author: Giuseppe Capasso <capassog97@gmail.com>
+####################################################################################################
+
+import argparse
+import hashlib
+import struct
+
+MAGIC = b"SFXMANIF"
+VERSION = 1
+MAX_IMAGES = 8
+PAGE_SIZE = 4096
+
+# struct BootManifestHeader and struct BootImageEntry
+HEADER = struct.Struct("<8sII")
+ENTRY = struct.Struct("<16sQQ64s64s")
+
+
+def align_up(value, alignment):
+    return (value + alignment - 1) & ~(alignment - 1)
+
+
+def parse_image(arg):
+    name, _, paths = arg.partition("=")
+    path, _, signature_path = paths.partition(":")
+    if not name or not path:
+        raise argparse.ArgumentTypeError(f"expected name=path[:signature], got {arg}")
+    if len(name.encode()) > 16:
+        raise argparse.ArgumentTypeError(f"image name longer than 16 bytes: {name}")
+
+    with open(path, "rb") as f:
+        data = f.read()
+    signature = bytes(64)
+    if signature_path:
+        with open(signature_path, "rb") as f:
+            signature = f.read()
+        if len(signature) != 64:
+            raise argparse.ArgumentTypeError(f"{signature_path} is not an Ed25519 signature")
+    return name, data, signature
+
+
+def main():
+    parser = argparse.ArgumentParser(description="Write a shadowfax boot manifest")
+    parser.add_argument("--address", type=lambda x: int(x, 0), required=True,
+                        help="address the manifest is loaded at")
+    parser.add_argument("-o", "--output", required=True)
+    parser.add_argument("images", nargs="+", type=parse_image, help="name=path[:signature]")
+    args = parser.parse_args()
+
+    if len(args.images) > MAX_IMAGES:
+        parser.error(f"at most {MAX_IMAGES} images")
+
+    offset = align_up(HEADER.size + ENTRY.size * len(args.images), PAGE_SIZE)
+    entries = b""
+    blob = b""
+    for name, data, signature in args.images:
+        entries += ENTRY.pack(name.encode(), args.address + offset, len(data),
+                              hashlib.sha512(data).digest(), signature)
+        padded = data + bytes(align_up(len(data), PAGE_SIZE) - len(data))
+        blob += padded
+        offset += len(padded)
+
+    header = HEADER.pack(MAGIC, VERSION, len(args.images)) + entries
+    header += bytes(align_up(len(header), PAGE_SIZE) - len(header))
+    with open(args.output, "wb") as f:
+        f.write(header + blob)
+
+
+if __name__ == "__main__":
+    main()
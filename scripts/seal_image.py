@@ -0,0 +1,98 @@
+# /// script
+# dependencies = [
+#   "cryptography",
+# ]
+# ///
+
+####################################################################################################
+# Seal a guest image for a TSM (see `common::sealed_image`): the host loads it with
+# COVH ADD_TVM_SEALED_IMAGE and only the TSM opens it, in confidential memory.
+#
+# The key comes from the DICE chain: the platform CDI of the DICE package written by `dice_tool.py`,
+# the TSM CDI derived from it with the SHA-512 of the TSM binary (the signed ELF), then the key
+# ladder key of the TSM for the label "tvm-sealed-image" followed by a random salt.
+#
+# Usage:
+#     seal_image.py --dice bin/dice_input.bin --tsm target/.../tsm --gpa 0x1000 \
+#         -o bin/guest.sealed guest.bin
+#
+# The input is a flat binary loaded at --gpa, padded to whole pages.
+#
+# Author: Giuseppe Capasso <capassog97@gmail.com>
+####################################################################################################
+
+import argparse
+import hashlib
+import os
+import struct
+
+from cryptography.hazmat.primitives import hashes
+from cryptography.hazmat.primitives.ciphers.aead import AESGCM
+from cryptography.hazmat.primitives.kdf.hkdf import HKDF
+
+MAGIC = b"SFXSEAL1"
+VERSION = 1
+PAGE_SIZE = 4096
+KEY_LABEL = b"tvm-sealed-image"
+KEY_LADDER_SALT = b"Shadowfax Key Ladder"
+KEY_LADDER_OWNER_TSM = 1
+
+# struct SealedImageHeader
+HEADER = struct.Struct("<8sIIQ16s")
+
+
+def hkdf(ikm, salt, info):
+    return HKDF(algorithm=hashes.SHA512(), length=32, salt=salt, info=info).derive(ikm)
+
+
+def platform_cdi(path):
+    # | 4 bytes | CDILEN | 4 bytes | EATLEN |, as written by dice_tool.py
+    with open(path, "rb") as f:
+        data = f.read()
+    (length,) = struct.unpack_from("<I", data)
+    return data[4:4 + length]
+
+
+def sealing_key(cdi, tsm, salt):
+    with open(tsm, "rb") as f:
+        tsm_cdi = hkdf(cdi, hashlib.sha512(f.read()).digest(), b"CDI_Attest")
+    label = KEY_LABEL + salt
+    info = struct.pack("<II", KEY_LADDER_OWNER_TSM, len(label)) + label
+    return hkdf(tsm_cdi, KEY_LADDER_SALT, info)
+
+
+def main():
+    parser = argparse.ArgumentParser(description="Seal a guest image for the shadowfax TSM")
+    parser.add_argument("--dice", required=True, help="DICE package of the platform")
+    parser.add_argument("--tsm", required=True, help="TSM binary the image is sealed for")
+    parser.add_argument("--gpa", type=lambda x: int(x, 0), required=True,
+                        help="guest physical address of the image")
+    parser.add_argument("-o", "--output", required=True)
+    parser.add_argument("input", help="flat guest binary")
+    args = parser.parse_args()
+
+    if args.gpa % PAGE_SIZE != 0:
+        parser.error("the GPA must be page aligned")
+    with open(args.input, "rb") as f:
+        data = f.read()
+    data += bytes(-len(data) % PAGE_SIZE)
+    num_pages = len(data) // PAGE_SIZE
+    if num_pages == 0:
+        parser.error("empty image")
+
+    salt = os.urandom(16)
+    header = HEADER.pack(MAGIC, VERSION, num_pages, args.gpa, salt)
+    aead = AESGCM(sealing_key(platform_cdi(args.dice), args.tsm, salt))
+
+    records = b""
+    for i in range(num_pages):
+        nonce = bytes(4) + struct.pack("<Q", i)
+        # ciphertext followed by the 16-byte tag
+        records += aead.encrypt(nonce, data[i * PAGE_SIZE:(i + 1) * PAGE_SIZE], header)
+
+    with open(args.output, "wb") as f:
+        f.write(header + records)
+
+
+if __name__ == "__main__":
+    main()
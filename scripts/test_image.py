@@ -0,0 +1,42 @@
+####################################################################################################
+# Copy the test executable `cargo test --no-run` built for a package to a fixed path. Cargo names
+# it after a hash (`target/<triplet>/debug/deps/<package>-<hash>`) and reports it in its JSON
+# messages, read from stdin.
+#
+# Usage:
+#     cargo test -p shadowfax --no-run --message-format=json \
+#         | test_image.py --package shadowfax -o bin/shadowfax-tests
+#
+# Author: Giuseppe Capasso <capassog97@gmail.com>
+####################################################################################################
+
+import argparse
+import json
+import shutil
+import sys
+
+
+def main():
+    parser = argparse.ArgumentParser(description="Copy the test executable built by cargo")
+    parser.add_argument("--package", required=True, help="package the tests belong to")
+    parser.add_argument("-o", "--output", required=True)
+    args = parser.parse_args()
+
+    executable = None
+    for line in sys.stdin:
+        try:
+            message = json.loads(line)
+        except json.JSONDecodeError:
+            continue
+        if message.get("reason") != "compiler-artifact" or not message.get("executable"):
+            continue
+        if message["target"]["name"] == args.package and message["profile"]["test"]:
+            executable = message["executable"]
+
+    if executable is None:
+        sys.exit(f"cargo built no test executable for {args.package}")
+    shutil.copyfile(executable, args.output)
+
+
+if __name__ == "__main__":
+    main()
@@ -2,8 +2,11 @@
  *  which are:
  *      - link opensbi static library;
  *      - generate rust bindings from opensbi include;
+ *        (both skipped with the `rust-sbi` feature, which does not use OpenSBI)
  *      - specify correct linkerscript;
- *      - compile the device tree;
+ *      - generate the memory layout of the platform (`memory.x` and `layout.rs`);
+ *      - compile the device tree, or without the `fdt` feature generate the static configuration
+ *        which replaces it (`static_config.rs`);
  *
  *  The idea of a build script is well documented here
  *  "https://doc.rust-lang.org/cargo/reference/build-scripts.html".
@@ -17,7 +20,8 @@ use std::process::Command;
 use std::{env, fs};
 
 const PLATFORM_BASE_DIR: &str = "platform";
-const LINKERSCRIPT_PATH: &str = "memory.x";
+const LINKERSCRIPT_PATH: &str = "link.x";
+const DEFAULT_TRUSTED_KEY: &str = "keys/publickey.pem";
 
 fn main() {
     // Ensure the bin/ folder exists.
@@ -30,12 +34,50 @@ fn main() {
     // Retrieve platform details if exists otherwise throw an error
     let platform = env::var("PLATFORM").unwrap_or_else(|_| "generic".to_string());
 
-    let platform_dir = PathBuf::from(PLATFORM_BASE_DIR).join(&platform);
+    let platform_dir = PathBuf::from(PLATFORM_BASE_DIR)
+        .join(&platform)
+        .canonicalize()
+        .unwrap_or_else(|_| panic!("unknown platform {platform}"));
+    let mut platform_config = PlatformConfig::load(&platform, &platform_dir);
+
+    // `src/platform.rs` includes the constants of the selected platform
+    println!(
+        "cargo:rustc-env=SHADOWFAX_PLATFORM_DIR={}",
+        platform_dir.display()
+    );
+    println!("cargo::rerun-if-env-changed=PLATFORM");
+    println!("cargo::rerun-if-changed={}", platform_dir.display());
+
+    // The pure-Rust SBI core replaces the OpenSBI runtime
+    let rust_sbi = env::var_os("CARGO_FEATURE_RUST_SBI").is_some();
+    // Without a device tree the platform and the domains come from `static-config.toml`
+    let fdt = env::var_os("CARGO_FEATURE_FDT").is_some();
+    assert!(
+        fdt || rust_sbi,
+        "the static configuration needs the `rust-sbi` runtime: OpenSBI reads the device tree"
+    );
+
+    // The linker script and `src/platform.rs` share the layout of `platform.conf`
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    if !fdt {
+        write_static_config(&platform_dir, &mut platform_config, &out_dir);
+    }
+    platform_config.check(&platform);
+    platform_config.write_layout(&out_dir);
+    write_keyring(&out_dir);
+
+    // Base ISA and ABI follow the target triple (riscv64imac or riscv32imac)
+    let target = Target::from_arch(&env::var("CARGO_CFG_TARGET_ARCH").unwrap());
+    // The TSM image embedded in the firmware is built for the same target triple
+    println!("cargo:rustc-env=TARGET={}", env::var("TARGET").unwrap());
 
     // Setup linker:
     // - build and link opensbi
     // - link linkerscript
-    {
+    let linkerscript_path = PathBuf::from(LINKERSCRIPT_PATH).canonicalize().unwrap();
+    if rust_sbi {
+        configure_linker(&linkerscript_path, None, &out_dir);
+    } else {
         let cross_compile =
             env::var("RV_PREFIX").unwrap_or("riscv64-unknown-linux-gnu-".to_string());
 
@@ -43,8 +85,9 @@ fn main() {
             .args([
                 "-C",
                 &opensbi_path.to_string_lossy(),
-                &format!("PLATFORM={}", &platform),
+                &format!("PLATFORM={}", &platform_config.opensbi_platform),
                 &format!("CROSS_COMPILE={}", cross_compile),
+                &format!("PLATFORM_RISCV_XLEN={}", target.xlen),
             ])
             .status()
             .expect("failed to build opensbi");
@@ -52,21 +95,23 @@ fn main() {
             panic!("OpenSBI build failed with status: {}", status);
         }
 
-        let linkerscript_path = PathBuf::from(LINKERSCRIPT_PATH).canonicalize().unwrap();
         let libopensbi_path = opensbi_path
-            .join(format!("build/platform/{}/lib", &platform))
+            .join(format!(
+                "build/platform/{}/lib",
+                &platform_config.opensbi_platform
+            ))
             .canonicalize()
             .unwrap();
 
-        configure_linker(&linkerscript_path, &libopensbi_path);
+        configure_linker(&linkerscript_path, Some(&libopensbi_path), &out_dir);
 
         // recompile if linkerscript changes
         println!("cargo::rerun-if-changed={}", &libopensbi_path.display());
     }
 
     // Compile the device tree
-    {
-        let dts_file = &platform_dir.join("device-tree.dts").canonicalize().unwrap();
+    if fdt {
+        let dts_file = &platform_dir.join("device-tree.dts");
         let dtb_file = &bin_dir.join("device-tree.dtb");
         let status = Command::new("dtc")
             .args([
@@ -87,7 +132,7 @@ fn main() {
     }
 
     // Generate rust bindgen
-    {
+    if !rust_sbi {
         let include_path = opensbi_path.join("include").canonicalize().unwrap();
 
         // Use bindgen API to create a valid `bindings.rs` which will be used
@@ -101,11 +146,10 @@ fn main() {
             .header("wrapper.h")
             .clang_arg("-I")
             .clang_arg(include_path.to_string_lossy())
-            //TODO: make the target architecture variable
             .clang_args([
-                "-mabi=lp64",
-                "-march=rv64imac",
-                "--target=riscv64-unknown-elf",
+                format!("-mabi={}", target.abi),
+                format!("-march={}", target.isa),
+                format!("--target=riscv{}-unknown-elf", target.xlen),
             ])
             .derive_debug(true)
             .derive_default(true)
@@ -114,9 +158,8 @@ fn main() {
             .expect("Unable to generate bindings");
 
         // save the bindings in the build directory
-        let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
         bindings
-            .write_to_file(out_path.join("bindings.rs"))
+            .write_to_file(out_dir.join("bindings.rs"))
             .expect("Couldn't write bindings!");
 
         // recompile if wrapper.h changes
@@ -126,12 +169,537 @@ fn main() {
     println!("cargo::rerun-if-changed=build.rs");
 }
 
-fn configure_linker(linkerscript_path: &PathBuf, libopensbi_path: &PathBuf) {
-    // Tell the linker to use our linkerscript "linker.ld" and pass `-static` and `-nostdlib` flags
+struct Target {
+    xlen: usize,
+    isa: &'static str,
+    abi: &'static str,
+}
+
+impl Target {
+    fn from_arch(arch: &str) -> Self {
+        match arch {
+            "riscv64" => Self {
+                xlen: 64,
+                isa: "rv64imac",
+                abi: "lp64",
+            },
+            "riscv32" => Self {
+                xlen: 32,
+                isa: "rv32imac",
+                abi: "ilp32",
+            },
+            _ => panic!("unsupported target architecture {arch}"),
+        }
+    }
+}
+
+/// Settings read from `platform/<PLATFORM>/platform.conf`, a list of `KEY=VALUE` lines.
+/// The file is optional, every key has a default: the layout defaults are the ones of QEMU virt.
+/// Addresses and sizes are decimal or hex numbers, sizes take a `K` or `M` suffix.
+struct PlatformConfig {
+    /// OpenSBI platform used to build `libplatsbi.a` (defaults to the shadowfax platform name)
+    opensbi_platform: String,
+    /// Code and read-only data of the firmware, TSM image included
+    flash: (u64, u64),
+    /// Data, heaps and TEE RAM of the firmware
+    ram: (u64, u64),
+    stack_size: u64,
+    /// Arenas of the firmware heap, see `HEAP_TAGS` in `main.rs`
+    heap_size: u64,
+    heap_state_size: u64,
+    heap_crypto_size: u64,
+    /// Handler stacks and domain contexts, see `tee.rs`
+    tee_ram_size: u64,
+    /// Snapshot area of the untrusted domain, see `snapshot.rs`
+    snapshot_size: u64,
+    /// Memory of the TSM domain, where the TSM is linked (`tsm/memory.x`). The static
+    /// configuration, if any, sets it.
+    tsm: (u64, u64),
+    /// Memory of the untrusted domain, where the host payload starts. The static configuration,
+    /// if any, sets it.
+    untrusted: (u64, u64),
+}
+
+impl PlatformConfig {
+    fn load(platform: &str, platform_dir: &PathBuf) -> Self {
+        let mut config = Self {
+            opensbi_platform: platform.to_string(),
+            flash: (0x8000_0000, 64 << 20),
+            ram: (0x8400_0000, 64 << 20),
+            stack_size: 16 << 10,
+            heap_size: 32 << 10,
+            heap_state_size: 16 << 10,
+            heap_crypto_size: 32 << 10,
+            tee_ram_size: 64 << 10,
+            snapshot_size: 1 << 20,
+            tsm: (0x8800_0000, 64 << 20),
+            untrusted: (0x8A00_0000, 16 << 20),
+        };
+
+        let Ok(content) = fs::read_to_string(platform_dir.join("platform.conf")) else {
+            return config;
+        };
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=').map(|(k, v)| (k.trim(), v.trim())) else {
+                panic!("invalid line in {platform}/platform.conf: {line}");
+            };
+            if key == "OPENSBI_PLATFORM" {
+                config.opensbi_platform = value.to_string();
+                continue;
+            }
+
+            let value = parse_size(value)
+                .unwrap_or_else(|| panic!("invalid value in {platform}/platform.conf: {line}"));
+            match key {
+                "FLASH_BASE" => config.flash.0 = value,
+                "FLASH_SIZE" => config.flash.1 = value,
+                "RAM_BASE" => config.ram.0 = value,
+                "RAM_SIZE" => config.ram.1 = value,
+                "STACK_SIZE" => config.stack_size = value,
+                "HEAP_SIZE" => config.heap_size = value,
+                "HEAP_STATE_SIZE" => config.heap_state_size = value,
+                "HEAP_CRYPTO_SIZE" => config.heap_crypto_size = value,
+                "TEE_RAM_SIZE" => config.tee_ram_size = value,
+                "SNAPSHOT_SIZE" => config.snapshot_size = value,
+                "TSM_BASE" => config.tsm.0 = value,
+                "TSM_SIZE" => config.tsm.1 = value,
+                "UNTRUSTED_BASE" => config.untrusted.0 = value,
+                "UNTRUSTED_SIZE" => config.untrusted.1 = value,
+                _ => panic!("unknown key in {platform}/platform.conf: {line}"),
+            }
+        }
+        config
+    }
+
+    /// Check the layout, once the static configuration (if any) has set the domain memory.
+    fn check(&self, platform: &str) {
+        let config = self;
+        // The domain memory is protected by a single NAPOT PMP entry
+        for (name, (base, size)) in [("TSM", config.tsm), ("UNTRUSTED", config.untrusted)] {
+            assert!(
+                size.is_power_of_two() && base % size == 0,
+                "{platform}/platform.conf: {name} memory is not a NAPOT region"
+            );
+        }
+        let overlaps =
+            |(a, a_size): (u64, u64), (b, b_size): (u64, u64)| a < b + b_size && b < a + a_size;
+        assert!(
+            !overlaps(config.flash, config.ram),
+            "{platform}/platform.conf: FLASH and RAM overlap"
+        );
+        for region in [config.tsm, config.untrusted] {
+            assert!(
+                !overlaps(config.flash, region) && !overlaps(config.ram, region),
+                "{platform}/platform.conf: the firmware overlaps the memory of a domain"
+            );
+        }
+    }
+
+    /// Write `memory.x`, included by `link.x`, and `layout.rs`, included by `src/platform.rs`.
+    fn write_layout(&self, out_dir: &PathBuf) {
+        let memory_x = format!(
+            "/* Generated by build.rs from platform.conf */\n\
+             MEMORY\n\
+             {{\n  \
+               FLASH (rwx) : ORIGIN = {:#x}, LENGTH = {:#x}\n  \
+               RAM   (rxw) : ORIGIN = {:#x}, LENGTH = {:#x}\n\
+             }}\n\n\
+             _stack_size       = {:#x};\n\
+             _heap_size        = {:#x};\n\
+             _heap_state_size  = {:#x};\n\
+             _heap_crypto_size = {:#x};\n\
+             _tee_stack_size   = {:#x};\n\
+             _snapshot_size    = {:#x};\n",
+            self.flash.0,
+            self.flash.1,
+            self.ram.0,
+            self.ram.1,
+            self.stack_size,
+            self.heap_size,
+            self.heap_state_size,
+            self.heap_crypto_size,
+            self.tee_ram_size,
+            self.snapshot_size,
+        );
+        fs::write(out_dir.join("memory.x"), memory_x).unwrap();
+
+        let layout_rs = format!(
+            "// Generated by build.rs from platform.conf\n\
+             \n\
+             /// Bytes of TEE RAM (`_tee_stack_size` in `link.x`)\n\
+             pub const TEE_RAM_SIZE: usize = {:#x};\n\
+             /// Memory of the TSM domain\n\
+             pub const TSM_BASE: usize = {:#x};\n\
+             pub const TSM_ORDER: u32 = {};\n\
+             /// Memory of the untrusted domain\n\
+             pub const UNTRUSTED_BASE: usize = {:#x};\n\
+             pub const UNTRUSTED_ORDER: u32 = {};\n\
+             /// Memory of the firmware, `(base, size)` of FLASH and RAM (see `link.x`)\n\
+             pub const FIRMWARE_MEMORY: [(usize, usize); 2] = [({:#x}, {:#x}), ({:#x}, {:#x})];\n",
+            self.tee_ram_size,
+            self.tsm.0,
+            self.tsm.1.trailing_zeros(),
+            self.untrusted.0,
+            self.untrusted.1.trailing_zeros(),
+            self.flash.0,
+            self.flash.1,
+            self.ram.0,
+            self.ram.1,
+        );
+        fs::write(out_dir.join("layout.rs"), layout_rs).unwrap();
+    }
+}
+
+/// Write `tsm_keyring.rs`, included by `src/domain.rs`: the PEM public keys trusted to sign the
+/// TSM, `:`-separated in `TSM_TRUSTED_KEYS` (defaults to `keys/publickey.pem`). The id of a key is
+/// its position in the list.
+fn write_keyring(out_dir: &PathBuf) {
+    println!("cargo::rerun-if-env-changed=TSM_TRUSTED_KEYS");
+    let keys = env::var("TSM_TRUSTED_KEYS").unwrap_or_else(|_| DEFAULT_TRUSTED_KEY.to_string());
+
+    let mut entries = String::new();
+    for key in keys.split(':').filter(|key| !key.is_empty()) {
+        let path = PathBuf::from(key).canonicalize().unwrap_or_else(|_| {
+            panic!("TSM_TRUSTED_KEYS: cannot find {key} (see `make generate-keys`)")
+        });
+        println!("cargo::rerun-if-changed={}", path.display());
+        entries += &format!("    include_bytes!({:?}),\n", path.display().to_string());
+    }
+    assert!(
+        !entries.is_empty(),
+        "TSM_TRUSTED_KEYS: no key to verify the TSM"
+    );
+
+    let keyring_rs = format!(
+        "// Generated by build.rs from TSM_TRUSTED_KEYS\n\
+         \n\
+         #[link_section = \".rodata\"]\n\
+         pub static TSM_KEYRING: &[&[u8]] = &[\n{entries}];\n"
+    );
+    fs::write(out_dir.join("tsm_keyring.rs"), keyring_rs).unwrap();
+}
+
+/// Write `static_config.rs`, included by `src/static_config.rs` in the builds without the `fdt`
+/// feature: the platform and the domains read from `static-config.toml` next to `platform.conf`
+/// (`SHADOWFAX_STATIC_CONFIG` overrides the path). The file has:
+///  - `[platform]`: `harts`, `timebase-frequency`, `isa-extensions` and the optional devices
+///    `imsic`, `iopmp`, `trng`, `flash`, `test-device` and `htif`, as found in a device tree;
+///  - two `[[domain]]`, the TSM and the untrusted domain: their `memory` (`[base, size]`, which
+///    replaces the one of `platform.conf`) and the fields of an `opensbi,domain,instance`;
+///  - `[[sbi-policy]]` and `[[device]]`, as the `shadowfax,sbi-policy` and
+///    `shadowfax,device-assignment` nodes.
+fn write_static_config(platform_dir: &PathBuf, config: &mut PlatformConfig, out_dir: &PathBuf) {
+    println!("cargo::rerun-if-env-changed=SHADOWFAX_STATIC_CONFIG");
+    let path = env::var("SHADOWFAX_STATIC_CONFIG")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| platform_dir.join("static-config.toml"));
+    println!("cargo::rerun-if-changed={}", path.display());
+    let name = path.display().to_string();
+    let toml: toml::Table = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("cannot read {name}: {e}"))
+        .parse()
+        .unwrap_or_else(|e| panic!("{name}: {e}"));
+
+    let empty = toml::Table::new();
+    let platform = toml_table(&toml, "platform", &name).unwrap_or(&empty);
+    let ctx = format!("{name}: [platform]");
+    let hex = |value: Option<u64>| match value {
+        Some(value) => format!("Some({value:#x})"),
+        None => "None".to_string(),
+    };
+    let extensions: Vec<String> = match platform.get("isa-extensions") {
+        Some(toml::Value::Array(values)) => values
+            .iter()
+            .map(|value| match value.as_str() {
+                Some(extension) => format!("{extension:?}"),
+                None => panic!("{ctx}: `isa-extensions` is not a list of strings"),
+            })
+            .collect(),
+        Some(_) => panic!("{ctx}: `isa-extensions` is not a list of strings"),
+        None => Vec::new(),
+    };
+    let imsic = match toml_table(platform, "imsic", &ctx) {
+        Some(imsic) => {
+            let ctx = format!("{ctx}: imsic");
+            format!(
+                "Some(ImsicInfo {{ base_addr: {:#x}, size: {:#x}, guest_index_bits: {}, num_ids: {} }})",
+                toml_required(imsic, "base", &ctx),
+                toml_required(imsic, "size", &ctx),
+                toml_required(imsic, "guest-index-bits", &ctx),
+                toml_int(imsic, "num-ids", &ctx).unwrap_or(0),
+            )
+        }
+        None => "None".to_string(),
+    };
+    let flash = match toml_table(platform, "flash", &ctx) {
+        Some(flash) => {
+            let ctx = format!("{ctx}: flash");
+            format!(
+                "Some(({:#x}, {:#x}, {}))",
+                toml_required(flash, "base", &ctx),
+                toml_required(flash, "size", &ctx),
+                toml_required(flash, "bank-width", &ctx),
+            )
+        }
+        None => "None".to_string(),
+    };
+    let timebase = toml_int(platform, "timebase-frequency", &ctx);
+
+    // Domain n + 1 is the n-th `[[domain]]`: the firmware runs the TSM and the untrusted domain
+    let domains = toml_tables(&toml, "domain", &name);
+    assert!(
+        domains.len() == 2,
+        "{name}: {} domains, the TSM and the untrusted domain expected",
+        domains.len()
+    );
+    let mut domain_entries = String::new();
+    for (id, domain) in (1..).zip(&domains) {
+        let ctx = format!("{name}: domain {id}");
+        let (base, size) = match toml_ints(domain, "memory", &ctx)[..] {
+            [base, size] => (base, size),
+            _ => panic!("{ctx}: `memory` is not [base, size]"),
+        };
+        match id {
+            1 => config.tsm = (base, size),
+            _ => config.untrusted = (base, size),
+        }
+
+        // The whole memory of the domain if the instance has no regions
+        let mut regions: Vec<(u64, u64, bool, u64)> = toml_tables(domain, "regions", &ctx)
+            .iter()
+            .map(|region| {
+                (
+                    toml_required(region, "base", &ctx),
+                    toml_required(region, "order", &ctx),
+                    toml_bool(region, "mmio", &ctx),
+                    toml_int(region, "flags", &ctx).unwrap_or(MEMREGION_RWX),
+                )
+            })
+            .collect();
+        if regions.is_empty() {
+            regions.push((base, size.trailing_zeros() as u64, false, MEMREGION_RWX));
+        }
+        let regions: String = regions
+            .iter()
+            .map(|(base, order, mmio, flags)| {
+                format!(
+                    "MemoryRegion {{ base_addr: {base:#x}, order: {order}, mmio: {mmio}, permissions: {:#x} }}, ",
+                    flags & MEMREGION_RWX
+                )
+            })
+            .collect();
+        let harts: String = toml_ints(domain, "possible-harts", &ctx)
+            .iter()
+            .map(|hartid| format!("{hartid}, "))
+            .collect();
+        let bootargs = match toml_str(domain, "bootargs", &ctx) {
+            Some(bootargs) => format!("Some({bootargs:?})"),
+            None => "None".to_string(),
+        };
+        domain_entries += &format!(
+            "    StaticDomain {{ name: {:?}, possible_harts: &[{harts}], regions: &[{regions}], \
+             next_addr: {:#x}, next_arg1: {:#x}, next_mode: {}, bootargs: {bootargs}, \
+             trust_map: {}, watchdog_ms: {:?} }},\n",
+            toml_str(domain, "name", &ctx).unwrap_or(&format!("domain{id}")),
+            toml_int(domain, "next-addr", &ctx).unwrap_or(base),
+            toml_int(domain, "next-arg1", &ctx).unwrap_or(0),
+            toml_int(domain, "next-mode", &ctx).unwrap_or(1),
+            hex(toml_int(domain, "trust-map", &ctx)),
+            toml_int(domain, "watchdog-ms", &ctx),
+        );
+    }
+
+    let mut policy_entries = String::new();
+    for policy in toml_tables(&toml, "sbi-policy", &name) {
+        let ctx = format!("{name}: sbi-policy");
+        let mut rules = String::new();
+        for (key, allow) in [("allow", true), ("deny", false)] {
+            for (eid, fid) in toml_pairs(policy, key, &ctx) {
+                rules += &format!("SbiRule {{ eid: {eid:#x}, fid: {fid:#x}, allow: {allow} }}, ");
+            }
+        }
+        policy_entries += &format!(
+            "    StaticPolicy {{ domain: {}, default_allow: {}, rules: &[{rules}], \
+             audit_log: {}, platform_reset: {} }},\n",
+            toml_required(policy, "domain", &ctx),
+            !toml_bool(policy, "default-deny", &ctx),
+            toml_bool(policy, "audit-log", &ctx),
+            !toml_bool(policy, "no-platform-reset", &ctx),
+        );
+    }
+
+    let mut device_entries = String::new();
+    for device in toml_tables(&toml, "device", &name) {
+        let ctx = format!("{name}: device");
+        device_entries += &format!(
+            "    DeviceAssignment {{ domain: {}, base_addr: {:#x}, size: {:#x}, exclusive: {}, console_mux: {} }},
+",
+            toml_required(device, "domain", &ctx),
+            toml_required(device, "base", &ctx),
+            toml_required(device, "size", &ctx),
+            !toml_bool(device, "shared", &ctx),
+            toml_bool(device, "console-mux", &ctx),
+        );
+    }
+
+    let static_config_rs = format!(
+        "// Generated by build.rs from {name}\n\
+         \n\
+         pub const HARTS: usize = {};\n\
+         pub const TIMEBASE_FREQUENCY: Option<u32> = {:?};\n\
+         pub const ISA_EXTENSIONS: &[&str] = &[{}];\n\
+         pub const IMSIC: Option<ImsicInfo> = {imsic};\n\
+         pub const IOPMP: Option<usize> = {};\n\
+         pub const TRNG: Option<usize> = {};\n\
+         pub const FLASH: Option<(usize, usize, u32)> = {flash};\n\
+         pub const TEST_DEVICE: Option<usize> = {};\n\
+         pub const HTIF: bool = {};\n\
+         \n\
+         #[link_section = \".rodata\"]\n\
+         pub static DOMAINS: &[StaticDomain] = &[\n{domain_entries}];\n\
+         \n\
+         #[link_section = \".rodata\"]\n\
+         pub static SBI_POLICIES: &[StaticPolicy] = &[\n{policy_entries}];\n\
+         \n\
+         #[link_section = \".rodata\"]\n\
+         pub static DEVICES: &[DeviceAssignment] = &[\n{device_entries}];\n",
+        toml_int(platform, "harts", &ctx).unwrap_or(1),
+        timebase,
+        extensions.join(", "),
+        hex(toml_int(platform, "iopmp", &ctx)),
+        hex(toml_int(platform, "trng", &ctx)),
+        hex(toml_int(platform, "test-device", &ctx)),
+        toml_bool(platform, "htif", &ctx),
+    );
+    fs::write(out_dir.join("static_config.rs"), static_config_rs).unwrap();
+}
+
+/// Permissions of a memory region which is not given any (`MEMREGION_RWX` in `src/domain.rs`)
+const MEMREGION_RWX: u64 = 0x3f;
+
+/// Non-negative integer `key` of `table`, if any.
+fn toml_int(table: &toml::Table, key: &str, ctx: &str) -> Option<u64> {
+    table.get(key).map(|value| match value.as_integer() {
+        Some(n) if n >= 0 => n as u64,
+        _ => panic!("{ctx}: `{key}` is not a non-negative integer"),
+    })
+}
+
+fn toml_required(table: &toml::Table, key: &str, ctx: &str) -> u64 {
+    toml_int(table, key, ctx).unwrap_or_else(|| panic!("{ctx}: `{key}` is missing"))
+}
+
+/// Flag `key` of `table`, false if it is missing.
+fn toml_bool(table: &toml::Table, key: &str, ctx: &str) -> bool {
+    table.get(key).is_some_and(|value| {
+        value
+            .as_bool()
+            .unwrap_or_else(|| panic!("{ctx}: `{key}` is not a boolean"))
+    })
+}
+
+fn toml_str<'a>(table: &'a toml::Table, key: &str, ctx: &str) -> Option<&'a str> {
+    table.get(key).map(|value| {
+        value
+            .as_str()
+            .unwrap_or_else(|| panic!("{ctx}: `{key}` is not a string"))
+    })
+}
+
+fn toml_table<'a>(table: &'a toml::Table, key: &str, ctx: &str) -> Option<&'a toml::Table> {
+    table.get(key).map(|value| {
+        value
+            .as_table()
+            .unwrap_or_else(|| panic!("{ctx}: `{key}` is not a table"))
+    })
+}
+
+/// List of integers `key` of `table`, empty if it is missing.
+fn toml_ints(table: &toml::Table, key: &str, ctx: &str) -> Vec<u64> {
+    table.get(key).map_or(Vec::new(), |value| {
+        int_list(value).unwrap_or_else(|| panic!("{ctx}: `{key}` is not a list of integers"))
+    })
+}
+
+/// List of `[a, b]` integer pairs `key` of `table`, empty if it is missing.
+fn toml_pairs(table: &toml::Table, key: &str, ctx: &str) -> Vec<(u64, u64)> {
+    let Some(value) = table.get(key) else {
+        return Vec::new();
+    };
+    let pairs = value.as_array().map(|values| {
+        values
+            .iter()
+            .map(|pair| match int_list(pair)?[..] {
+                [a, b] => Some((a, b)),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+    });
+    pairs
+        .flatten()
+        .unwrap_or_else(|| panic!("{ctx}: `{key}` is not a list of [a, b] pairs"))
+}
+
+fn int_list(value: &toml::Value) -> Option<Vec<u64>> {
+    value
+        .as_array()?
+        .iter()
+        .map(|value| value.as_integer().and_then(|n| u64::try_from(n).ok()))
+        .collect()
+}
+
+/// Array of tables `key` of `table` (`[[key]]`), empty if it is missing.
+fn toml_tables<'a>(table: &'a toml::Table, key: &str, ctx: &str) -> Vec<&'a toml::Table> {
+    let Some(value) = table.get(key) else {
+        return Vec::new();
+    };
+    let values = value
+        .as_array()
+        .unwrap_or_else(|| panic!("{ctx}: `{key}` is not an array of tables"));
+    values
+        .iter()
+        .map(|value| {
+            value
+                .as_table()
+                .unwrap_or_else(|| panic!("{ctx}: `{key}` is not an array of tables"))
+        })
+        .collect()
+}
+
+/// `0x8000`, `32768`, `32K` or `64M`.
+fn parse_size(value: &str) -> Option<u64> {
+    let (digits, shift) = match value.as_bytes().last()? {
+        b'K' => (&value[..value.len() - 1], 10),
+        b'M' => (&value[..value.len() - 1], 20),
+        _ => (value, 0),
+    };
+    let n = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    n.checked_mul(1 << shift)
+}
+
+fn configure_linker(
+    linkerscript_path: &PathBuf,
+    libopensbi_path: Option<&PathBuf>,
+    layout_dir: &PathBuf,
+) {
+    // Tell the linker to use our linkerscript "link.x" and pass `-static` and `-nostdlib` flags.
+    // The linkerscript includes the `memory.x` generated for the platform, found through the
+    // search path.
     #[rustfmt::skip]
     println!("cargo:rustc-link-arg=-T{}", linkerscript_path.display());
+    println!("cargo:rustc-link-search={}", layout_dir.display());
     println!("cargo:rustc-link-arg=-static");
     println!("cargo:rustc-link-arg=-nostdlib");
+
+    let Some(libopensbi_path) = libopensbi_path else {
+        return;
+    };
     println!("cargo:rustc-link-search={}", libopensbi_path.display());
 
     // Opensbi installs the static library in `./lib64/lp64/opensbi/generic/lib/`
@@ -0,0 +1,383 @@
+//! Activation of the supervisor domains on the boot hart. A TEECALL suspends the caller and runs
+//! the callee until its TEERET: the firmware keeps the outstanding calls in a stack of
+//! (caller, callee) pairs, and the run state of each domain, and checks every CoVE call against
+//! them:
+//!  - TEERET: the running domain is the callee of the last call and returns to its caller (the id
+//!    in a6 bits [31:26], which the callee must preserve);
+//!  - TEECALL: any other call. The callee must accept TEECALLs (it has a TSM), be idle and trust
+//!    the caller.
+//!
+//! Anything else (a domain calling itself, a call to a busy or stopped domain, a TEERET to another
+//! domain) is rejected before any state changes. After each transition the invariants are checked:
+//! a single domain runs, and the waiting domains are exactly the callers in the stack.
+//!
+//! A callee can make a further TEECALL before its TEERET (e.g. the TSM calling a secure-storage
+//! domain), up to `MAX_CALL_DEPTH` outstanding calls. The saved context of a domain is also its
+//! entry point for the next TEECALL: when a callee calls further, its entry context is kept in its
+//! frame (`save_entry`) and put back on its TEERET, once the domain has resumed from the saved one.
+//! The contexts are read and written through the `ContextStorage` of the firmware.
+//! `unwind` gives the hart back to the first caller, when a call of the chain never returns.
+
+use alloc::vec::Vec;
+
+use crate::{domain::Domain, error::ActivationError, platform::ContextStorage};
+
+/// Outstanding TEECALLs at most
+pub const MAX_CALL_DEPTH: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DomainRunState {
+    /// Not running and not waiting: it can take a TEECALL
+    Idle,
+    Running,
+    /// Made a TEECALL, waits for the TEERET of the callee
+    Calling,
+    /// Shut down (see `reset.rs`): it never runs again
+    Stopped,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Call {
+    pub caller: usize,
+    pub callee: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transition {
+    Call(Call),
+    Return(Call),
+}
+
+struct Frame<C> {
+    call: Call,
+    /// Entry context of the callee, if it made a further TEECALL
+    callee_entry: Option<C>,
+}
+
+pub struct CallStack<S: ContextStorage> {
+    frames: Vec<Frame<S::Context>>,
+    storage: S,
+}
+
+impl<S: ContextStorage> CallStack<S> {
+    /// The frames are allocated once: TEECALLs and TEERETs do not allocate.
+    pub fn new(storage: S) -> Self {
+        Self {
+            frames: Vec::with_capacity(MAX_CALL_DEPTH),
+            storage,
+        }
+    }
+
+    /// Last outstanding call
+    pub fn top(&self) -> Option<Call> {
+        self.frames.last().map(|frame| frame.call)
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Outstanding calls, the first one at the bottom
+    pub fn calls(&self) -> impl Iterator<Item = Call> + '_ {
+        self.frames.iter().map(|frame| frame.call)
+    }
+
+    /// Keep the entry context at `ctx_addr` of `domain`, before its context is overwritten by a
+    /// further TEECALL.
+    pub fn save_entry(&mut self, domain: usize, ctx_addr: usize) {
+        if let Some(frame) = self.frames.last_mut() {
+            if frame.call.callee == domain && frame.callee_entry.is_none() {
+                frame.callee_entry = Some(self.storage.load(ctx_addr));
+            }
+        }
+    }
+
+    /// Transition of the CoVE call of `src`, the running domain, to `dst`.
+    pub fn transition(
+        &self,
+        domains: &[Domain],
+        src: usize,
+        dst: usize,
+    ) -> Result<Transition, ActivationError> {
+        let state = |id: usize| domains.get(id).map(|d| d.run_state);
+        if state(src) != Some(DomainRunState::Running) {
+            return Err(ActivationError::NotRunning(src));
+        }
+
+        let Some(callee) = domains.get(dst) else {
+            return Err(ActivationError::UnknownDomain(dst));
+        };
+        if src == dst {
+            return Err(ActivationError::SelfCall(src));
+        }
+
+        if let Some(call) = self.top().filter(|call| call.callee == src) {
+            if call.caller == dst {
+                return Ok(Transition::Return(call));
+            }
+        }
+
+        if !callee.has_tsm {
+            return Err(ActivationError::NotCallable(dst));
+        }
+        if callee.run_state == DomainRunState::Stopped {
+            return Err(ActivationError::Stopped(dst));
+        }
+        if callee.run_state != DomainRunState::Idle {
+            return Err(ActivationError::Busy(dst));
+        }
+        if !callee.is_trusted(src) {
+            return Err(ActivationError::Untrusted { src, dst });
+        }
+        if self.depth() == MAX_CALL_DEPTH {
+            return Err(ActivationError::TooDeep(MAX_CALL_DEPTH));
+        }
+        Ok(Transition::Call(Call {
+            caller: src,
+            callee: dst,
+        }))
+    }
+
+    /// Apply `transition`, checked by `transition`, and return the domain to run.
+    pub fn apply(&mut self, domains: &mut [Domain], transition: Transition) -> usize {
+        let next = match transition {
+            Transition::Call(call) => {
+                self.frames.push(Frame {
+                    call,
+                    callee_entry: None,
+                });
+                domains[call.caller].run_state = DomainRunState::Calling;
+                domains[call.callee].run_state = DomainRunState::Running;
+                call.callee
+            }
+            Transition::Return(call) => {
+                assert_eq!(self.top(), Some(call), "TEERET out of order");
+                self.pop(domains);
+                domains[call.caller].run_state = DomainRunState::Running;
+                call.caller
+            }
+        };
+        self.check(domains, next);
+        next
+    }
+
+    /// Drop every outstanding call and return the first one: its caller runs next.
+    pub fn unwind(&mut self, domains: &mut [Domain]) -> Option<Call> {
+        let first = self.frames.first()?.call;
+        while !self.frames.is_empty() {
+            self.pop(domains);
+        }
+        domains[first.caller].run_state = DomainRunState::Running;
+        self.check(domains, first.caller);
+        Some(first)
+    }
+
+    /// Pop the last call: the callee is idle again, at its entry context
+    fn pop(&mut self, domains: &mut [Domain]) {
+        let Some(frame) = self.frames.pop() else {
+            return;
+        };
+        let callee = &mut domains[frame.call.callee];
+        callee.run_state = DomainRunState::Idle;
+        if let Some(entry) = frame.callee_entry {
+            self.storage.store(callee.context_addr, entry);
+        }
+    }
+
+    fn check(&self, domains: &[Domain], running: usize) {
+        for (id, domain) in domains.iter().enumerate() {
+            let calling = self.calls().any(|call| call.caller == id);
+            let expected = match (id == running, calling) {
+                (true, false) => DomainRunState::Running,
+                (false, true) => DomainRunState::Calling,
+                (false, false) if domain.run_state == DomainRunState::Stopped => {
+                    DomainRunState::Stopped
+                }
+                (false, false) => DomainRunState::Idle,
+                (true, true) => panic!("domain {id} runs and waits for a TEERET"),
+            };
+            assert!(
+                domain.run_state == expected,
+                "domain {id} is {:?}, expected {expected:?} ({} calls)",
+                domain.run_state,
+                self.depth()
+            );
+        }
+        assert!(self.depth() <= MAX_CALL_DEPTH);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::BTreeMap;
+
+    use super::*;
+
+    /// Contexts by address, a context is a single value
+    #[derive(Default)]
+    struct MockContexts(BTreeMap<usize, u64>);
+
+    impl ContextStorage for MockContexts {
+        type Context = u64;
+
+        fn load(&self, addr: usize) -> u64 {
+            self.0.get(&addr).copied().unwrap_or_default()
+        }
+
+        fn store(&mut self, addr: usize, context: u64) {
+            self.0.insert(addr, context);
+        }
+    }
+
+    /// Root domain 0 running, TSM 1 trusting it, secure storage 2 trusting the TSM only
+    fn domains() -> Vec<Domain> {
+        let mut domains: Vec<_> = (0..3).map(|_| Domain::empty()).collect();
+        domains[0].run_state = DomainRunState::Running;
+        for (id, trust_map) in [(1, 0b001), (2, 0b010)] {
+            domains[id].has_tsm = true;
+            domains[id].trust_map = trust_map;
+            domains[id].context_addr = 0x1000 * id;
+        }
+        domains
+    }
+
+    fn call(caller: usize, callee: usize) -> Call {
+        Call { caller, callee }
+    }
+
+    #[test]
+    fn call_and_return() {
+        let mut domains = domains();
+        let mut calls = CallStack::new(MockContexts::default());
+
+        let t = calls.transition(&domains, 0, 1).unwrap();
+        assert_eq!(t, Transition::Call(call(0, 1)));
+        assert_eq!(calls.apply(&mut domains, t), 1);
+        assert_eq!(domains[0].run_state, DomainRunState::Calling);
+
+        let t = calls.transition(&domains, 1, 0).unwrap();
+        assert_eq!(t, Transition::Return(call(0, 1)));
+        assert_eq!(calls.apply(&mut domains, t), 0);
+        assert_eq!(domains[1].run_state, DomainRunState::Idle);
+        assert_eq!(calls.depth(), 0);
+    }
+
+    #[test]
+    fn rejected_calls() {
+        let mut domains = domains();
+        let mut calls = CallStack::new(MockContexts::default());
+
+        assert!(matches!(
+            calls.transition(&domains, 1, 0),
+            Err(ActivationError::NotRunning(1))
+        ));
+        assert!(matches!(
+            calls.transition(&domains, 0, 3),
+            Err(ActivationError::UnknownDomain(3))
+        ));
+        assert!(matches!(
+            calls.transition(&domains, 0, 0),
+            Err(ActivationError::SelfCall(0))
+        ));
+        assert!(matches!(
+            calls.transition(&domains, 0, 2),
+            Err(ActivationError::Untrusted { src: 0, dst: 2 })
+        ));
+
+        for (src, dst) in [(0, 1), (1, 2)] {
+            let t = calls.transition(&domains, src, dst).unwrap();
+            calls.apply(&mut domains, t);
+        }
+        // A TEERET to another domain than the caller is a TEECALL, the root domain waits for the TSM
+        assert!(matches!(
+            calls.transition(&domains, 2, 0),
+            Err(ActivationError::NotCallable(0))
+        ));
+        domains[0].has_tsm = true;
+        assert!(matches!(
+            calls.transition(&domains, 2, 0),
+            Err(ActivationError::Busy(0))
+        ));
+    }
+
+    #[test]
+    fn stopped_and_not_callable() {
+        let mut domains = domains();
+        let calls = CallStack::new(MockContexts::default());
+
+        domains[1].run_state = DomainRunState::Stopped;
+        assert!(matches!(
+            calls.transition(&domains, 0, 1),
+            Err(ActivationError::Stopped(1))
+        ));
+        domains[1].has_tsm = false;
+        assert!(matches!(
+            calls.transition(&domains, 0, 1),
+            Err(ActivationError::NotCallable(1))
+        ));
+    }
+
+    #[test]
+    fn too_deep() {
+        let mut domains: Vec<_> = (0..=MAX_CALL_DEPTH + 1).map(|_| Domain::empty()).collect();
+        domains[0].run_state = DomainRunState::Running;
+        for domain in &mut domains[1..] {
+            domain.has_tsm = true;
+            domain.trust_map = usize::MAX;
+        }
+        let mut calls = CallStack::new(MockContexts::default());
+
+        for id in 1..=MAX_CALL_DEPTH {
+            let t = calls.transition(&domains, id - 1, id).unwrap();
+            calls.apply(&mut domains, t);
+        }
+        assert!(matches!(
+            calls.transition(&domains, MAX_CALL_DEPTH, MAX_CALL_DEPTH + 1),
+            Err(ActivationError::TooDeep(MAX_CALL_DEPTH))
+        ));
+    }
+
+    #[test]
+    fn nested_call_restores_entry() {
+        let mut domains = domains();
+        let mut contexts = MockContexts::default();
+        // Entry point of the TSM
+        contexts.store(0x1000, 7);
+        let mut calls = CallStack::new(contexts);
+
+        let t = calls.transition(&domains, 0, 1).unwrap();
+        calls.apply(&mut domains, t);
+
+        // The TSM calls further: its context is overwritten with where it resumes
+        calls.save_entry(1, 0x1000);
+        calls.storage.store(0x1000, 42);
+        let t = calls.transition(&domains, 1, 2).unwrap();
+        assert_eq!(calls.apply(&mut domains, t), 2);
+        let t = calls.transition(&domains, 2, 1).unwrap();
+        assert_eq!(calls.apply(&mut domains, t), 1);
+        assert_eq!(calls.storage.load(0x1000), 42);
+
+        // At its TEERET, the entry point is back for the next TEECALL
+        let t = calls.transition(&domains, 1, 0).unwrap();
+        calls.apply(&mut domains, t);
+        assert_eq!(calls.storage.load(0x1000), 7);
+    }
+
+    #[test]
+    fn unwind_to_first_caller() {
+        let mut domains = domains();
+        let mut calls = CallStack::new(MockContexts::default());
+
+        for (src, dst) in [(0, 1), (1, 2)] {
+            let t = calls.transition(&domains, src, dst).unwrap();
+            calls.apply(&mut domains, t);
+        }
+        assert_eq!(calls.unwind(&mut domains), Some(call(0, 1)));
+        assert_eq!(calls.depth(), 0);
+        assert_eq!(domains[0].run_state, DomainRunState::Running);
+        assert!(domains[1..]
+            .iter()
+            .all(|d| d.run_state == DomainRunState::Idle));
+        assert_eq!(calls.unwind(&mut domains), None);
+    }
+}
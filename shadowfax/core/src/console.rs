@@ -0,0 +1,103 @@
+//! Output of the domains on the shared console. The output of each domain is buffered and written a
+//! whole line at a time, prefixed by the domain id, so that the lines of several domains do not
+//! mix:
+//!
+//!   [domain 2] [VMM] tsm capabilities 0x3
+//!   [domain 1] Hello from TVM (VS-mode)
+//!
+//! A line longer than `CONSOLE_LINE_SIZE` is written in pieces.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use crate::platform::Console;
+
+/// Longest line written at once
+pub const CONSOLE_LINE_SIZE: usize = 256;
+
+/// Pending line of each domain
+pub struct DomainLines {
+    lines: BTreeMap<usize, Vec<u8>>,
+}
+
+impl DomainLines {
+    pub const fn new() -> Self {
+        Self {
+            lines: BTreeMap::new(),
+        }
+    }
+
+    /// Append `buf` to the pending line of `domain` and write the complete lines on `console`.
+    pub fn write(&mut self, console: &mut impl Console, domain: usize, buf: &[u8]) {
+        let line = self.lines.entry(domain).or_default();
+
+        for &c in buf {
+            line.push(c);
+            if c == b'\n' || line.len() == CONSOLE_LINE_SIZE {
+                write_line(console, domain, line);
+                line.clear();
+            }
+        }
+    }
+}
+
+impl Default for DomainLines {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn write_line(console: &mut impl Console, domain: usize, line: &[u8]) {
+    let mut prefix = *b"[domain 0] ";
+    prefix[8] = b'0' + (domain % 10) as u8;
+    prefix.iter().chain(line).for_each(|&c| console.putc(c));
+    if line.last() != Some(&b'\n') {
+        console.putc(b'\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct MockConsole {
+        output: Vec<u8>,
+    }
+
+    impl Console for MockConsole {
+        fn putc(&mut self, c: u8) {
+            self.output.push(c);
+        }
+
+        fn getc(&mut self) -> Option<u8> {
+            None
+        }
+    }
+
+    #[test]
+    fn lines_do_not_mix() {
+        let mut console = MockConsole::default();
+        let mut lines = DomainLines::new();
+        lines.write(&mut console, 2, b"[VMM] tsm ");
+        lines.write(&mut console, 1, b"Hello from TVM\n");
+        lines.write(&mut console, 2, b"capabilities 0x3\n");
+        assert_eq!(
+            console.output,
+            b"[domain 1] Hello from TVM\n[domain 2] [VMM] tsm capabilities 0x3\n"
+        );
+    }
+
+    #[test]
+    fn long_lines_are_split() {
+        let mut console = MockConsole::default();
+        let mut lines = DomainLines::new();
+        lines.write(&mut console, 1, &[b'x'; CONSOLE_LINE_SIZE + 1]);
+        // The first piece is written and terminated, the last byte waits for the end of its line
+        let prefix = b"[domain 1] ".len();
+        assert_eq!(console.output.len(), prefix + CONSOLE_LINE_SIZE + 1);
+        assert_eq!(console.output.last(), Some(&b'\n'));
+
+        lines.write(&mut console, 1, b"\n");
+        assert!(console.output.ends_with(b"[domain 1] x\n"));
+    }
+}
@@ -0,0 +1,474 @@
+//! Supervisor domains: their memory regions and PMP permissions, the regions granted after their
+//! creation, their trust maps and SBI policies.
+
+use alloc::{string::String, vec::Vec};
+use common::{
+    pmp::Region,
+    sbi::{SupdDomainStats, TsmIdentity},
+};
+
+use crate::{activation::DomainRunState, error::GrantError, tsm_info::TsmInfoCache};
+
+// Permissions of a memory region, with the flags of the `regions` of an OpenSBI domain instance
+// (`regions = <&tmem 0x3f>`). PMP entries are not locked, so the M-mode flags are not enforced.
+pub const MEMREGION_M_READABLE: u8 = 1 << 0;
+pub const MEMREGION_M_WRITABLE: u8 = 1 << 1;
+pub const MEMREGION_M_EXECUTABLE: u8 = 1 << 2;
+pub const MEMREGION_SU_READABLE: u8 = 1 << 3;
+pub const MEMREGION_SU_WRITABLE: u8 = 1 << 4;
+pub const MEMREGION_SU_EXECUTABLE: u8 = 1 << 5;
+pub const MEMREGION_RW: u8 =
+    MEMREGION_M_READABLE | MEMREGION_M_WRITABLE | MEMREGION_SU_READABLE | MEMREGION_SU_WRITABLE;
+pub const MEMREGION_R: u8 = MEMREGION_M_READABLE | MEMREGION_SU_READABLE;
+pub const MEMREGION_RWX: u8 = MEMREGION_RW | MEMREGION_M_EXECUTABLE | MEMREGION_SU_EXECUTABLE;
+
+// PMP cfg permission bits
+pub const PMP_R: usize = 1 << 0;
+pub const PMP_W: usize = 1 << 1;
+pub const PMP_X: usize = 1 << 2;
+// Locked entry: enforced in M-mode too, and not writable until the hart resets
+pub const PMP_L: usize = 1 << 7;
+
+/// PMP entries of a domain
+pub const MAX_MEMORY_REGIONS: usize = 8;
+
+/// Smallest set of NAPOT regions covering exactly `[base, base + size)`, as `(base, order)`, see
+/// `Region::split`. Nothing if no set of NAPOT regions covers the range.
+pub fn napot_split(base: usize, size: usize) -> impl Iterator<Item = (usize, u32)> {
+    Region::split(base, size).map(|r| (r.base(), r.size().trailing_zeros()))
+}
+
+/// Why a region was granted to a domain after its creation. A grant is revoked with its tag, so
+/// that revoking it never touches the regions the domain got otherwise.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionTag {
+    /// Device of a `shadowfax,device-assignment` node
+    Device,
+    /// Pages the host converted to confidential memory
+    Confidential,
+    /// Host pages shared with a TVM
+    Shared,
+    /// Guest interrupt file bound to a TVM vCPU
+    InterruptFile,
+    /// Host buffer of the outstanding call, revoked at its TEERET (see `call_buffers.rs`)
+    CallBuffer,
+}
+
+/// Region granted with `Domain::grant`
+#[derive(Clone, Copy, Debug)]
+pub struct Grant {
+    tag: RegionTag,
+    base_addr: usize,
+    order: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct MemoryRegion {
+    pub base_addr: usize,
+    pub order: u32,
+    pub mmio: bool,
+    pub permissions: u8,
+}
+
+impl MemoryRegion {
+    /// R/W/X bits of the PMP cfg of the region, from its S/U-mode permissions. MMIO is never
+    /// executable, and write-only (a reserved PMP encoding) is no access.
+    pub fn pmp_permissions(&self) -> usize {
+        let mut bits = 0;
+        if self.permissions & MEMREGION_SU_READABLE != 0 {
+            bits |= PMP_R;
+        }
+        if self.permissions & MEMREGION_SU_WRITABLE != 0 && bits & PMP_R != 0 {
+            bits |= PMP_W;
+        }
+        if self.permissions & MEMREGION_SU_EXECUTABLE != 0 && !self.mmio {
+            bits |= PMP_X;
+        }
+        bits
+    }
+
+    /// Bytes covered by the region, if it can be a NAPOT entry.
+    pub fn pmp_region(&self) -> Option<Region> {
+        Region::napot(self.base_addr, self.order)
+    }
+}
+
+#[derive(Clone)]
+pub struct Domain {
+    pub trust_map: usize,
+    // Domains this domain offered its trust to, until they accept it (see `State::offer_trust`)
+    pub trust_offers: usize,
+    pub memory_regions: Vec<MemoryRegion>,
+
+    pub context_addr: usize,
+    pub has_tsm: bool,
+    // The TSM of the domain completed `_secure_init`, TEECALLs reach it only then
+    pub tsm_ready: bool,
+    // Measurement and signer of the TSM, once verified
+    pub tsm_identity: Option<TsmIdentity>,
+    // Last GET_TSM_INFO answer of the TSM, replayed by the TSM-driver (see `tsm_info`)
+    pub tsm_info: Option<TsmInfoCache>,
+    pub sbi_policy: SbiPolicy,
+    // Access faults (PMP violations) of the domain
+    pub access_faults: usize,
+    // Event counters of the domain, reported by GET_DOMAIN_STATS
+    pub stats: SupdDomainStats,
+    // Regions granted after the creation of the domain, see `grant`
+    pub grants: heapless::Vec<Grant, MAX_MEMORY_REGIONS>,
+    // The TSM of the domain was stopped by the watchdog and takes no more TEECALLs
+    pub faulted: bool,
+    pub run_state: DomainRunState,
+    // The `opensbi,domain,instance` node of the domain, mirrored in the OpenSBI domain
+    pub instance: Option<DomainInstance>,
+    // Device tree the domain boots with, see `fdt::domain_view`
+    pub fdt_view: Option<usize>,
+}
+
+/// Supervisor domain as declared in the device tree, see `fdt::find_domain_instances`. The memory
+/// regions of the instance must be memory of the domain, the firmware adds the devices and the
+/// confidential memory.
+#[derive(Clone, Debug)]
+pub struct DomainInstance {
+    pub name: String,
+    /// Hart ids the domain can run on
+    pub possible_harts: Vec<usize>,
+    pub regions: Vec<MemoryRegion>,
+    pub next_addr: usize,
+    pub next_arg1: usize,
+    pub next_mode: usize,
+    pub bootargs: Option<String>,
+    /// Domains this one trusts at boot, instead of the default of the firmware
+    pub trust_map: Option<usize>,
+}
+
+/// Function id of a policy rule which matches every function of the extension
+pub const SBI_POLICY_ANY_FID: usize = u32::MAX as usize;
+
+/// Calls to `eid`/`fid` are allowed or denied.
+#[derive(Clone, Copy, Debug)]
+pub struct SbiRule {
+    pub eid: usize,
+    pub fid: usize,
+    pub allow: bool,
+}
+
+impl SbiRule {
+    fn matches(&self, eid: usize, fid: usize) -> bool {
+        self.eid == eid && (self.fid == SBI_POLICY_ANY_FID || self.fid == fid)
+    }
+}
+
+/// SBI calls a supervisor domain can make to the firmware (and through it, to the TSM). Deny rules
+/// win over allow rules, the calls no rule matches follow the default. The policies are read from
+/// the device tree, see `fdt::find_sbi_policies`.
+#[derive(Clone, Debug)]
+pub struct SbiPolicy {
+    pub default_allow: bool,
+    pub rules: Vec<SbiRule>,
+    /// The domain can read the audit log (see `audit.rs`)
+    pub audit_log: bool,
+    /// A domain without a TSM can reset the platform with SRST (see `reset.rs`)
+    pub platform_reset: bool,
+}
+
+impl SbiPolicy {
+    pub const fn allow_all() -> Self {
+        Self {
+            default_allow: true,
+            rules: Vec::new(),
+            audit_log: false,
+            platform_reset: true,
+        }
+    }
+
+    pub fn allows(&self, eid: usize, fid: usize) -> bool {
+        let mut matching = self.rules.iter().filter(|rule| rule.matches(eid, fid));
+        match matching.clone().any(|rule| !rule.allow) {
+            true => false,
+            false => matching.any(|rule| rule.allow) || self.default_allow,
+        }
+    }
+}
+
+impl Domain {
+    pub fn empty() -> Self {
+        Self {
+            trust_map: 0,
+            trust_offers: 0,
+            memory_regions: Vec::new(),
+            context_addr: 0,
+            has_tsm: false,
+            tsm_ready: false,
+            tsm_identity: None,
+            tsm_info: None,
+            sbi_policy: SbiPolicy::allow_all(),
+            access_faults: 0,
+            stats: SupdDomainStats::default(),
+            grants: heapless::Vec::new(),
+            faulted: false,
+            run_state: DomainRunState::Idle,
+            instance: None,
+            fdt_view: None,
+        }
+    }
+
+    pub fn is_trusted(&self, dst: usize) -> bool {
+        self.trust_map & (1 << dst) != 0
+    }
+
+    /// Whether `[base, base + size)` is memory (not MMIO) of the domain.
+    pub fn owns(&self, base: usize, size: usize) -> bool {
+        let Some(end) = base.checked_add(size) else {
+            return false;
+        };
+
+        self.memory_regions.iter().any(|r| {
+            let region_end = 1usize
+                .checked_shl(r.order)
+                .map_or(usize::MAX, |size| r.base_addr.saturating_add(size));
+            !r.mmio && base >= r.base_addr && end <= region_end
+        })
+    }
+
+    /// Whether a region of the domain, memory or MMIO, overlaps `[base, base + size)`. The host
+    /// pages the domain was lent (shared pages, call buffers) do not count: they stay the host's.
+    pub fn overlaps(&self, base: usize, size: usize) -> bool {
+        let end = base.saturating_add(size);
+        let lent = |r: &MemoryRegion| {
+            self.grants.iter().any(|g| {
+                matches!(g.tag, RegionTag::Shared | RegionTag::CallBuffer)
+                    && g.base_addr == r.base_addr
+                    && g.order == r.order
+            })
+        };
+        self.memory_regions.iter().any(|r| {
+            let r_end = 1usize
+                .checked_shl(r.order)
+                .map_or(usize::MAX, |size| r.base_addr.saturating_add(size));
+            r.base_addr < end && base < r_end && !lent(r)
+        })
+    }
+
+    /// Grant `region` to the domain in a free PMP entry, tagged with `tag`.
+    pub fn grant(&mut self, region: MemoryRegion, tag: RegionTag) -> Result<(), GrantError> {
+        if self.memory_regions.len() >= MAX_MEMORY_REGIONS {
+            return Err(GrantError::NoPmpEntry {
+                base_addr: region.base_addr,
+                tag,
+            });
+        }
+        if region.pmp_region().is_none() {
+            return Err(GrantError::NotNapot {
+                base_addr: region.base_addr,
+                order: region.order,
+            });
+        }
+        let grant = Grant {
+            tag,
+            base_addr: region.base_addr,
+            order: region.order,
+        };
+        // As many grants as regions
+        self.grants.push(grant).unwrap();
+        self.memory_regions.push(region);
+        Ok(())
+    }
+
+    /// Revoke the regions granted with `tag` which lie within `[base, base + size)`. Returns how
+    /// many were revoked.
+    pub fn revoke(&mut self, tag: RegionTag, base: usize, size: usize) -> usize {
+        let end = base.saturating_add(size);
+        let mut revoked = 0;
+        let mut i = 0;
+        while i < self.grants.len() {
+            let grant = self.grants[i];
+            let grant_end = 1usize
+                .checked_shl(grant.order)
+                .map_or(usize::MAX, |size| grant.base_addr.saturating_add(size));
+            if grant.tag != tag || grant.base_addr < base || grant_end > end {
+                i += 1;
+                continue;
+            }
+            self.grants.swap_remove(i);
+            if let Some(r) = self
+                .memory_regions
+                .iter()
+                .rposition(|r| r.base_addr == grant.base_addr && r.order == grant.order)
+            {
+                self.memory_regions.remove(r);
+            }
+            revoked += 1;
+        }
+        revoked
+    }
+
+    /// Revoke all the regions granted with `tag`.
+    pub fn revoke_all(&mut self, tag: RegionTag) -> usize {
+        self.revoke(tag, 0, usize::MAX)
+    }
+
+    /// Remove the MMIO regions which overlap `region`.
+    pub fn release_mmio(&mut self, region: &MemoryRegion) {
+        let end = region.base_addr + (1 << region.order);
+        self.memory_regions.retain(|r| {
+            let r_end = r
+                .base_addr
+                .saturating_add(1usize.checked_shl(r.order).unwrap_or(0));
+            !r.mmio || r_end <= region.base_addr || r.base_addr >= end
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(base_addr: usize, order: u32, mmio: bool) -> MemoryRegion {
+        MemoryRegion {
+            base_addr,
+            order,
+            mmio,
+            permissions: MEMREGION_RW,
+        }
+    }
+
+    #[test]
+    fn napot_split_covers_exactly() {
+        let regions: Vec<_> = napot_split(0x8000_1000, 0x3000).collect();
+        assert_eq!(regions, [(0x8000_1000, 12), (0x8000_2000, 13)]);
+
+        let regions: Vec<_> = napot_split(0x8800_0000, 0x400_0000).collect();
+        assert_eq!(regions, [(0x8800_0000, 26)]);
+
+        assert_eq!(napot_split(0x1000, 0).count(), 0);
+        assert_eq!(napot_split(0x1004, 0x1000).count(), 0);
+    }
+
+    #[test]
+    fn owns_memory_not_mmio() {
+        let mut domain = Domain::empty();
+        domain.memory_regions.push(region(0x8A00_0000, 24, false));
+        domain.memory_regions.push(region(0x1000_0000, 12, true));
+
+        assert!(domain.owns(0x8A00_0000, 0x100_0000));
+        assert!(domain.owns(0x8A80_0000, 0x1000));
+        assert!(!domain.owns(0x8AFF_F000, 0x2000));
+        assert!(!domain.owns(0x1000_0000, 0x100));
+        assert!(!domain.owns(usize::MAX, 2));
+    }
+
+    #[test]
+    fn revoke_by_tag() {
+        let mut domain = Domain::empty();
+        domain.memory_regions.push(region(0x8A00_0000, 24, false));
+        domain
+            .grant(region(0x8B00_0000, 12, false), RegionTag::Shared)
+            .unwrap();
+        domain
+            .grant(region(0x8B00_1000, 12, false), RegionTag::Shared)
+            .unwrap();
+        domain
+            .grant(region(0x8B00_2000, 12, false), RegionTag::Confidential)
+            .unwrap();
+        assert!(domain.owns(0x8B00_0000, 0x1000));
+
+        // Only the grants with the tag, within the range
+        assert_eq!(domain.revoke(RegionTag::Shared, 0x8B00_1000, 0x1000), 1);
+        assert!(domain.owns(0x8B00_0000, 0x1000));
+        assert!(!domain.owns(0x8B00_1000, 0x1000));
+        assert_eq!(domain.revoke_all(RegionTag::Shared), 1);
+        assert_eq!(domain.memory_regions.len(), 2);
+        assert!(domain.owns(0x8A00_0000, 0x1000));
+        assert!(domain.owns(0x8B00_2000, 0x1000));
+    }
+
+    #[test]
+    fn grants_limited_by_pmp_entries() {
+        let mut domain = Domain::empty();
+        for i in 0..MAX_MEMORY_REGIONS {
+            domain
+                .grant(
+                    region(0x8B00_0000 + (i << 12), 12, false),
+                    RegionTag::Shared,
+                )
+                .unwrap();
+        }
+        assert!(matches!(
+            domain.grant(region(0x8C00_0000, 12, false), RegionTag::Shared),
+            Err(GrantError::NoPmpEntry { .. })
+        ));
+    }
+
+    #[test]
+    fn overlaps_all_but_lent_pages() {
+        let mut domain = Domain::empty();
+        domain.memory_regions.push(region(0x8A00_0000, 24, false));
+        domain.memory_regions.push(region(0x1000_0000, 12, true));
+        domain
+            .grant(region(0x8B00_0000, 12, false), RegionTag::Shared)
+            .unwrap();
+
+        assert!(domain.overlaps(0x89FF_F000, 0x2000));
+        assert!(domain.overlaps(0x1000_0000, 0x10));
+        assert!(!domain.overlaps(0x8B00_0000, 0x1000));
+        assert!(!domain.overlaps(0x8C00_0000, 0x1000));
+    }
+
+    #[test]
+    fn grants_only_napot_regions() {
+        let mut domain = Domain::empty();
+        assert!(matches!(
+            domain.grant(region(0x8B00_1000, 13, false), RegionTag::Shared),
+            Err(GrantError::NotNapot { .. })
+        ));
+        assert!(matches!(
+            domain.grant(region(0x8B00_0000, 2, false), RegionTag::Shared),
+            Err(GrantError::NotNapot { .. })
+        ));
+        assert!(domain.memory_regions.is_empty());
+    }
+
+    #[test]
+    fn release_mmio_overlapping() {
+        let mut domain = Domain::empty();
+        domain.memory_regions.push(region(0x1000_0000, 12, true));
+        domain.memory_regions.push(region(0x1000_1000, 12, true));
+        domain.memory_regions.push(region(0x8A00_0000, 24, false));
+
+        domain.release_mmio(&region(0x1000_0000, 12, true));
+        assert_eq!(domain.memory_regions.len(), 2);
+        assert_eq!(domain.memory_regions[0].base_addr, 0x1000_1000);
+    }
+
+    #[test]
+    fn sbi_policy_deny_wins() {
+        const SUPD: usize = 0x53555044;
+        let policy = SbiPolicy {
+            default_allow: true,
+            rules: Vec::from([
+                SbiRule {
+                    eid: SUPD,
+                    fid: SBI_POLICY_ANY_FID,
+                    allow: true,
+                },
+                SbiRule {
+                    eid: SUPD,
+                    fid: 35,
+                    allow: false,
+                },
+            ]),
+            audit_log: false,
+            platform_reset: false,
+        };
+        assert!(policy.allows(SUPD, 0));
+        assert!(!policy.allows(SUPD, 35));
+        assert!(policy.allows(0x10, 0));
+
+        let policy = SbiPolicy {
+            default_allow: false,
+            ..policy
+        };
+        assert!(policy.allows(SUPD, 0));
+        assert!(!policy.allows(0x10, 0));
+    }
+}
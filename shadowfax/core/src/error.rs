@@ -0,0 +1,67 @@
+//! Errors of the platform-independent core of the firmware.
+
+use core::{error::Error, fmt::Display};
+
+use crate::domain::RegionTag;
+
+/// CoVE call rejected by the activation state machine, see `activation.rs`
+#[derive(Debug)]
+pub enum ActivationError {
+    UnknownDomain(usize),
+    /// The caller is not the running domain
+    NotRunning(usize),
+    SelfCall(usize),
+    /// The callee has no TSM
+    NotCallable(usize),
+    /// The callee is running or waits for a TEERET
+    Busy(usize),
+    Untrusted {
+        src: usize,
+        dst: usize,
+    },
+    TooDeep(usize),
+    Stopped(usize),
+}
+
+impl Display for ActivationError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::UnknownDomain(id) => write!(f, "no domain {}", id),
+            Self::NotRunning(id) => write!(f, "domain {} is not running", id),
+            Self::SelfCall(id) => write!(f, "domain {} calls itself", id),
+            Self::NotCallable(id) => write!(f, "domain {} takes no TEECALL", id),
+            Self::Busy(id) => write!(f, "domain {} is busy", id),
+            Self::Untrusted { src, dst } => write!(f, "domain {} does not trust {}", dst, src),
+            Self::TooDeep(depth) => write!(f, "more than {} outstanding TEECALLs", depth),
+            Self::Stopped(id) => write!(f, "domain {} is shut down", id),
+        }
+    }
+}
+
+impl Error for ActivationError {}
+
+/// Region not granted to a domain, see `Domain::grant`
+#[derive(Debug)]
+pub enum GrantError {
+    NoPmpEntry { base_addr: usize, tag: RegionTag },
+    NotNapot { base_addr: usize, order: u32 },
+}
+
+impl Display for GrantError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::NoPmpEntry { base_addr, tag } => {
+                write!(f, "no PMP entry left for {:#x} ({:?})", base_addr, tag)
+            }
+            Self::NotNapot { base_addr, order } => {
+                write!(
+                    f,
+                    "{:#x} (order {}) is not a NAPOT region",
+                    base_addr, order
+                )
+            }
+        }
+    }
+}
+
+impl Error for GrantError {}
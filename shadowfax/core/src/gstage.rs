@@ -0,0 +1,150 @@
+//! G-stage address translation (Privileged ISA, 9.5), as the hart does it for a guest. The SBI
+//! calls of a VS-mode guest pass guest physical addresses: the firmware walks the G-stage page
+//! table the hypervisor of the guest programmed in `hgatp` to reach the memory behind them.
+
+/// Translation modes of hgatp.MODE
+const HGATP_MODE_BARE: u64 = 0;
+const HGATP_MODE_SV39X4: u64 = 8;
+const HGATP_MODE_SV48X4: u64 = 9;
+const HGATP_MODE_SHIFT: u32 = 60;
+const HGATP_PPN_MASK: u64 = (1 << 44) - 1;
+
+pub const PTE_V: u64 = 1 << 0;
+pub const PTE_R: u64 = 1 << 1;
+pub const PTE_W: u64 = 1 << 2;
+pub const PTE_X: u64 = 1 << 3;
+/// G-stage leaves must have U set
+pub const PTE_U: u64 = 1 << 4;
+const PTE_PPN_SHIFT: u32 = 10;
+const PTE_PPN_MASK: u64 = (1 << 44) - 1;
+
+const PAGE_SHIFT: u32 = 12;
+const PTE_SIZE: u64 = 8;
+/// Bits of the VPN of each level, the root level has two more
+const VPN_BITS: u32 = 9;
+
+/// Physical address of the guest physical address `gpa`, translated through the G-stage page table
+/// of `hgatp`. The leaf must grant `perms` (`PTE_*` bits). `read_pte` reads the PTE at a physical
+/// address, or refuses to. `None` if `gpa` is not mapped with `perms`.
+pub fn translate(
+    hgatp: u64,
+    gpa: u64,
+    perms: u64,
+    mut read_pte: impl FnMut(u64) -> Option<u64>,
+) -> Option<u64> {
+    let levels = match hgatp >> HGATP_MODE_SHIFT {
+        HGATP_MODE_BARE => return Some(gpa),
+        HGATP_MODE_SV39X4 => 3,
+        HGATP_MODE_SV48X4 => 4,
+        _ => return None,
+    };
+    // The root VPN is 11 bits wide: 2 more than the other levels
+    let gpa_bits = PAGE_SHIFT + levels * VPN_BITS + 2;
+    if gpa >> gpa_bits != 0 {
+        return None;
+    }
+
+    let mut table = (hgatp & HGATP_PPN_MASK) << PAGE_SHIFT;
+    for level in (0..levels).rev() {
+        let shift = PAGE_SHIFT + level * VPN_BITS;
+        let vpn_bits = if level == levels - 1 {
+            VPN_BITS + 2
+        } else {
+            VPN_BITS
+        };
+        let vpn = (gpa >> shift) & ((1 << vpn_bits) - 1);
+        let pte = read_pte(table + vpn * PTE_SIZE)?;
+        if pte & PTE_V == 0 || (pte & PTE_W != 0 && pte & PTE_R == 0) {
+            return None;
+        }
+
+        let ppn = (pte >> PTE_PPN_SHIFT) & PTE_PPN_MASK;
+        if pte & (PTE_R | PTE_W | PTE_X) == 0 {
+            table = ppn << PAGE_SHIFT;
+            continue;
+        }
+        // A superpage must be aligned to its size
+        let offset_mask = (1 << shift) - 1;
+        let base = ppn << PAGE_SHIFT;
+        if pte & (perms | PTE_U) != perms | PTE_U || base & offset_mask != 0 {
+            return None;
+        }
+        return Some(base | (gpa & offset_mask));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::BTreeMap;
+
+    const ROOT: u64 = 0x8000_0000;
+    const L1: u64 = 0x8000_4000;
+    const L0: u64 = 0x8000_5000;
+    const L1_SUPERPAGES: u64 = 0x8000_6000;
+
+    fn table_pte(table: u64) -> u64 {
+        ((table >> PAGE_SHIFT) << PTE_PPN_SHIFT) | PTE_V
+    }
+
+    fn leaf_pte(pa: u64, perms: u64) -> u64 {
+        ((pa >> PAGE_SHIFT) << PTE_PPN_SHIFT) | PTE_V | PTE_U | perms
+    }
+
+    /// Sv39x4 table mapping GPA 0x4000_1000 on 0x9000_0000 (4K, RW) and GPA 0x20_0000 on
+    /// 0xa000_0000 (2M, read-only)
+    fn memory() -> BTreeMap<u64, u64> {
+        let mut memory = BTreeMap::new();
+        memory.insert(ROOT + 8, table_pte(L1));
+        memory.insert(L1, table_pte(L0));
+        memory.insert(L0 + 8, leaf_pte(0x9000_0000, PTE_R | PTE_W));
+        memory.insert(ROOT, table_pte(L1_SUPERPAGES));
+        memory.insert(L1_SUPERPAGES + 8, leaf_pte(0xa000_0000, PTE_R));
+        memory
+    }
+
+    fn walk(memory: &BTreeMap<u64, u64>, gpa: u64, perms: u64) -> Option<u64> {
+        let hgatp = (HGATP_MODE_SV39X4 << HGATP_MODE_SHIFT) | (ROOT >> PAGE_SHIFT);
+        translate(hgatp, gpa, perms, |addr| {
+            Some(*memory.get(&addr).unwrap_or(&0))
+        })
+    }
+
+    #[test]
+    fn pages_and_superpages() {
+        let memory = memory();
+        assert_eq!(walk(&memory, 0x4000_1234, PTE_W), Some(0x9000_0234));
+        assert_eq!(walk(&memory, 0x2f_0010, PTE_R), Some(0xa00f_0010));
+        // Read-only superpage, unmapped page, GPA beyond Sv39x4
+        assert_eq!(walk(&memory, 0x20_0000, PTE_W), None);
+        assert_eq!(walk(&memory, 0x4000_2000, PTE_R), None);
+        assert_eq!(walk(&memory, 1 << 41, PTE_R), None);
+    }
+
+    #[test]
+    fn bad_leaves_are_rejected() {
+        let mut memory = memory();
+        // Not accessible from U-mode, then a misaligned superpage
+        memory.insert(
+            L0 + 8,
+            ((0x9000_0000 >> PAGE_SHIFT) << PTE_PPN_SHIFT) | PTE_V | PTE_R,
+        );
+        assert_eq!(walk(&memory, 0x4000_1000, PTE_R), None);
+        memory.insert(L1_SUPERPAGES + 8, leaf_pte(0xa000_1000, PTE_R));
+        assert_eq!(walk(&memory, 0x20_0000, PTE_R), None);
+    }
+
+    #[test]
+    fn refused_tables_and_bare_mode() {
+        let memory = memory();
+        let hgatp = (HGATP_MODE_SV39X4 << HGATP_MODE_SHIFT) | (ROOT >> PAGE_SHIFT);
+        assert_eq!(translate(hgatp, 0x4000_1000, PTE_R, |_| None), None);
+        assert_eq!(walk(&memory, 0x4000_1000, PTE_R), Some(0x9000_0000));
+        assert_eq!(translate(0, 0x1234, PTE_R, |_| None), Some(0x1234));
+        assert_eq!(
+            translate(1 << HGATP_MODE_SHIFT, 0x1234, PTE_R, |_| None),
+            None
+        );
+    }
+}
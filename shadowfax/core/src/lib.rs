@@ -0,0 +1,27 @@
+//! Platform-independent core of the TSM-driver.
+//!
+//! The firmware binary cannot run on the host (M-mode CSRs, the PMP, the SBI runtime), so the
+//! logic deciding what a supervisor domain may do lives here: the domains with their memory
+//! regions, grants, trust maps and SBI policies, the activation state machine of the TEECALLs and
+//! TEERETs, the PMP encoding of the regions, the console lines of the domains, the GET_TSM_INFO
+//! answers the firmware keeps and the G-stage walk of the addresses passed by VS-mode guests.
+//! The hardware is only reached through the traits of `platform` (console, PMP programming,
+//! context storage), which the firmware implements on the hart and `cargo test` in memory.
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+pub mod activation;
+pub mod console;
+pub mod domain;
+pub mod error;
+pub mod gstage;
+pub mod platform;
+pub mod pmp;
+pub mod tsm_info;
+
+pub use activation::{CallStack, DomainRunState, Transition};
+pub use domain::{Domain, MemoryRegion};
+pub use error::{ActivationError, GrantError};
+pub use platform::{Console, ContextStorage, Pmp};
+pub use tsm_info::TsmInfoCache;
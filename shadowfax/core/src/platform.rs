@@ -0,0 +1,28 @@
+//! Platform services the core needs from the firmware. The firmware implements them on the hart
+//! (CSRs, the SBI runtime console, the TEE RAM); the tests implement them in memory.
+
+/// Console of the platform, written one byte at a time.
+pub trait Console {
+    fn putc(&mut self, c: u8);
+    /// Next input byte, if any: never blocks.
+    fn getc(&mut self) -> Option<u8>;
+}
+
+/// PMP of the hart running the firmware.
+pub trait Pmp {
+    /// Write the entry `index`: its address register and its configuration byte.
+    fn write(&mut self, index: usize, pmpaddr: usize, pmpcfg: usize);
+    /// Configuration byte of the entry `index`, as the hart reads it back.
+    fn read_cfg(&self, index: usize) -> usize;
+}
+
+/// Where the contexts of the domains are saved, see `Domain::context_addr`.
+pub trait ContextStorage {
+    /// Registers and CSRs of a domain, as the context switch saves them.
+    type Context: Clone;
+
+    /// Context saved at `addr`.
+    fn load(&self, addr: usize) -> Self::Context;
+    /// Overwrite the context saved at `addr`.
+    fn store(&mut self, addr: usize, context: Self::Context);
+}
@@ -0,0 +1,119 @@
+//! PMP entries of the domain regions (Privileged ISA, 3.7): every region is a NAPOT entry, the
+//! regions of the active domain take consecutive entries. The pmpaddr comes from
+//! `common::pmp::Region`.
+
+use common::pmp::PMP_A_NAPOT;
+
+use crate::{domain::MemoryRegion, platform::Pmp};
+
+/// pmpaddr and pmpcfg byte of the NAPOT entry of `r`. A region which cannot be a NAPOT entry gets
+/// an entry off: the domain loses the region rather than getting a wrong one.
+pub fn pmp_entry(r: &MemoryRegion) -> (usize, usize) {
+    match r.pmp_region().and_then(|region| region.encode_napot()) {
+        Some(pmpaddr) => (pmpaddr, (PMP_A_NAPOT | r.pmp_permissions()) & 0xFF),
+        None => (0, 0),
+    }
+}
+
+/// Program `regions` in the entries of `pmp` from `first`.
+pub fn program(pmp: &mut impl Pmp, first: usize, regions: &[MemoryRegion]) {
+    for (i, r) in regions.iter().enumerate() {
+        let (pmpaddr, pmpcfg) = pmp_entry(r);
+        pmp.write(first + i, pmpaddr, pmpcfg);
+    }
+}
+
+/// Whether the configuration of `regions` reads back from `pmp` as `program` wrote it.
+pub fn programmed(pmp: &impl Pmp, first: usize, regions: &[MemoryRegion]) -> bool {
+    regions
+        .iter()
+        .enumerate()
+        .all(|(i, r)| pmp.read_cfg(first + i) == pmp_entry(r).1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{MEMREGION_R, MEMREGION_RW, MEMREGION_RWX, PMP_R, PMP_W, PMP_X};
+
+    /// 16 entries, the configuration reads back as written unless the entry is stuck
+    #[derive(Default)]
+    struct MockPmp {
+        entries: [(usize, usize); 16],
+        stuck: Option<usize>,
+    }
+
+    impl Pmp for MockPmp {
+        fn write(&mut self, index: usize, pmpaddr: usize, pmpcfg: usize) {
+            if self.stuck != Some(index) {
+                self.entries[index] = (pmpaddr, pmpcfg);
+            }
+        }
+
+        fn read_cfg(&self, index: usize) -> usize {
+            self.entries[index].1
+        }
+    }
+
+    fn region(base_addr: usize, order: u32, mmio: bool, permissions: u8) -> MemoryRegion {
+        MemoryRegion {
+            base_addr,
+            order,
+            mmio,
+            permissions,
+        }
+    }
+
+    #[test]
+    fn napot_encoding() {
+        // 64 MiB of TSM memory
+        let (pmpaddr, pmpcfg) = pmp_entry(&region(0x8800_0000, 26, false, MEMREGION_RWX));
+        assert_eq!(pmpaddr, 0x2200_0000 | ((1 << 23) - 1));
+        assert_eq!(pmpcfg, PMP_A_NAPOT | PMP_R | PMP_W | PMP_X);
+
+        // Smallest region: 8 bytes, no trailing ones
+        let (pmpaddr, _) = pmp_entry(&region(0x1000_0000, 3, true, MEMREGION_RW));
+        assert_eq!(pmpaddr, 0x1000_0000 >> 2);
+
+        // Smaller than 8 bytes or misaligned: no access
+        assert_eq!(
+            pmp_entry(&region(0x1000_0000, 2, true, MEMREGION_RW)),
+            (0, 0)
+        );
+        assert_eq!(
+            pmp_entry(&region(0x1000_1000, 13, false, MEMREGION_RW)),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn permissions() {
+        // MMIO is never executable
+        let (_, pmpcfg) = pmp_entry(&region(0x1000_0000, 12, true, MEMREGION_RWX));
+        assert_eq!(pmpcfg, PMP_A_NAPOT | PMP_R | PMP_W);
+        let (_, pmpcfg) = pmp_entry(&region(0x8A00_0000, 12, false, MEMREGION_R));
+        assert_eq!(pmpcfg, PMP_A_NAPOT | PMP_R);
+    }
+
+    #[test]
+    fn program_and_read_back() {
+        let regions = [
+            region(0x8A00_0000, 24, false, MEMREGION_RWX),
+            region(0x1000_0000, 12, true, MEMREGION_RW),
+        ];
+        let mut pmp = MockPmp::default();
+        program(&mut pmp, 8, &regions);
+        assert_eq!(pmp.entries[8], pmp_entry(&regions[0]));
+        assert_eq!(pmp.entries[9], pmp_entry(&regions[1]));
+        assert_eq!(pmp.entries[0], (0, 0));
+        assert!(programmed(&pmp, 8, &regions));
+        assert!(!programmed(&pmp, 0, &regions));
+
+        let mut pmp = MockPmp {
+            stuck: Some(9),
+            ..Default::default()
+        };
+        program(&mut pmp, 8, &regions);
+        assert!(!programmed(&pmp, 8, &regions));
+    }
+}
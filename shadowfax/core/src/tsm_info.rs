@@ -0,0 +1,99 @@
+//! Answers of the TSMs to GET_TSM_INFO, kept by the TSM-driver. The `TsmInfo` of a ready TSM does
+//! not change until the TSM reports so with `SBI_EXT_SUPD_TSM_INFO_CHANGED`: until then the
+//! TSM-driver answers GET_TSM_INFO itself, without switching to the TSM and back. A call the kept
+//! answer does not cover (a smaller or larger buffer than the TSM saw) still goes to the TSM.
+
+use alloc::vec::Vec;
+use core::mem::{align_of, size_of};
+
+use common::sbi::{TsmInfo, TSM_STATUS_READY};
+
+/// Answer of a TSM to a GET_TSM_INFO
+#[derive(Clone, Debug)]
+pub struct TsmInfoCache {
+    /// Bytes the TSM wrote: `TsmInfo`, then its build info if the buffer had room
+    answer: Vec<u8>,
+    /// Size of the buffer the TSM wrote `answer` in
+    buffer_len: usize,
+}
+
+impl TsmInfoCache {
+    /// Keep the `answer` of a TSM to a GET_TSM_INFO with a buffer of `buffer_len` bytes. Only the
+    /// answers of a ready TSM are kept: the status of a TSM which is not ready may change anytime.
+    pub fn new(answer: &[u8], buffer_len: usize) -> Option<Self> {
+        let status = answer.get(..size_of::<u32>())?;
+        if answer.len() < size_of::<TsmInfo>()
+            || answer.len() > buffer_len
+            || u32::from_ne_bytes(status.try_into().unwrap()) != TSM_STATUS_READY
+        {
+            return None;
+        }
+        Some(Self {
+            answer: answer.to_vec(),
+            buffer_len,
+        })
+    }
+
+    /// Bytes the TSM would write in the buffer of `len` bytes at `addr`, if they are known.
+    pub fn answer(&self, addr: usize, len: usize) -> Option<&[u8]> {
+        let info_size = size_of::<TsmInfo>();
+        if len < info_size || !addr.is_multiple_of(align_of::<TsmInfo>()) {
+            return None;
+        }
+        match self.answer.len() > info_size {
+            // The build info follows when it fits
+            true if len >= self.answer.len() => Some(&self.answer),
+            true => Some(&self.answer[..info_size]),
+            // A larger buffer than the TSM saw may take the build info too
+            false => (len <= self.buffer_len).then_some(&self.answer[..]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::sbi::TSM_STATUS_LOADED;
+
+    const INFO: usize = size_of::<TsmInfo>();
+    const BUILD_INFO: usize = 104;
+
+    fn answer(status: u32, len: usize) -> Vec<u8> {
+        let mut answer = alloc::vec![0xab; len];
+        answer[..size_of::<u32>()].copy_from_slice(&status.to_ne_bytes());
+        answer
+    }
+
+    #[test]
+    fn only_ready_answers_are_kept() {
+        assert!(TsmInfoCache::new(&answer(TSM_STATUS_READY, INFO), INFO).is_some());
+        assert!(TsmInfoCache::new(&answer(TSM_STATUS_LOADED, INFO), INFO).is_none());
+        // Truncated, or larger than the buffer
+        assert!(TsmInfoCache::new(&answer(TSM_STATUS_READY, INFO - 1), INFO).is_none());
+        assert!(TsmInfoCache::new(&answer(TSM_STATUS_READY, INFO), INFO - 8).is_none());
+        assert!(TsmInfoCache::new(&[], INFO).is_none());
+    }
+
+    #[test]
+    fn answers_with_build_info() {
+        let full = answer(TSM_STATUS_READY, INFO + BUILD_INFO);
+        let cache = TsmInfoCache::new(&full, 4096).unwrap();
+        assert_eq!(cache.answer(0x1000, 4096), Some(&full[..]));
+        assert_eq!(cache.answer(0x1000, INFO + BUILD_INFO), Some(&full[..]));
+        // No room for the build info
+        assert_eq!(cache.answer(0x1000, INFO + 8), Some(&full[..INFO]));
+        // The TSM rejects these
+        assert_eq!(cache.answer(0x1000, INFO - 1), None);
+        assert_eq!(cache.answer(0x1004, 4096), None);
+    }
+
+    #[test]
+    fn answers_without_build_info() {
+        let info = answer(TSM_STATUS_READY, INFO);
+        let cache = TsmInfoCache::new(&info, INFO + 8).unwrap();
+        assert_eq!(cache.answer(0x1000, INFO), Some(&info[..]));
+        assert_eq!(cache.answer(0x1000, INFO + 8), Some(&info[..]));
+        // The TSM decides whether the build info fits
+        assert_eq!(cache.answer(0x1000, INFO + 16), None);
+    }
+}
@@ -3,18 +3,13 @@
  *  - FLASH: where all code and read-only data (including the TSM, signatures and key) are stored
  *  - RAM: where data, TSM context and state are stored;
  *
+ * The two regions and the sizes of the stack, heap arenas, snapshot area and TEE RAM are declared
+ * in the `memory.x` generated by `build.rs` from `platform/<PLATFORM>/platform.conf`.
+ *
  * Author: Giuseppe Capasso <capassog97@gmail.com>
  */
 
-/*
- * FLASH    0x80000000 - 0x81FFFFFF
- * RAM      0x82000000 - 0x82FFFFFF
- */
-MEMORY
-{
-  FLASH (rwx) : ORIGIN = 0x80000000, LENGTH = 64M
-  RAM   (rxw) : ORIGIN = 0x84000000, LENGTH = 64M
-}
+INCLUDE memory.x
 
 /*
  * Memory regions alias to give semantic meaning to what we are storing.
@@ -24,11 +19,6 @@ REGION_ALIAS("REGION_RODATA", FLASH);
 REGION_ALIAS("REGION_DATA", RAM);
 REGION_ALIAS("REGION_TEE_MEM", RAM);
 
-/* variables */
-_stack_size          = 0x4000;   /* 16k */
-_heap_size           = 0x10000;  /* 64k */
-_tee_stack_size      = 0x10000;  /* 64k */
-
 _fw_start  = ORIGIN(FLASH);
 _stack_top = ORIGIN(RAM) + LENGTH(RAM);
 
@@ -44,6 +34,8 @@ SECTIONS {
   /* read only data.*/
   .rodata : ALIGN(4K) {
     *(.rodata .rodata.*);
+    /* small data sections, RV32 toolchains emit them for globals <= 8 bytes */
+    *(.srodata .srodata.*);
     . = ALIGN(4K);
   } > REGION_RODATA
 
@@ -54,12 +46,26 @@ SECTIONS {
   /* here we can store heap data */
   .data : ALIGN(4K) {
     *(.data .data.*);
+    *(.sdata .sdata.*);
+    /* HTIF mailboxes (`tohost`/`fromhost`) of the OpenSBI driver, spike looks them up by symbol */
+    *(.htif);
     . = ALIGN(4K);
 
+    /* one arena per subsystem, see HEAP_TAGS in main.rs */
     _heap_start = .;
     . += _heap_size;
     . = ALIGN(4K);
     _heap_end = .;
+
+    _heap_state_start = .;
+    . += _heap_state_size;
+    . = ALIGN(4K);
+    _heap_state_end = .;
+
+    _heap_crypto_start = .;
+    . += _heap_crypto_size;
+    . = ALIGN(4K);
+    _heap_crypto_end = .;
   } > REGION_DATA
 
   /* store bss_data */
@@ -72,10 +78,19 @@ SECTIONS {
     _fw_end = .;
   } > REGION_DATA
 
+  /* Snapshot of the untrusted domain, see snapshot.rs */
+  .snapshot (NOLOAD): ALIGN(4K) {
+    _snapshot_start = .;
+    . += _snapshot_size;
+    . = ALIGN(4K);
+    _snapshot_end = .;
+  } > REGION_DATA
+
   /* Place where to store TEE state and context */
   .tee_ram (NOLOAD): ALIGN(4K) {
-    /* Scratch memory for CoVE interrupt handling and interrupt handling*/
+    /* Handler stacks and domain contexts of each hart, the layout is in tee.rs */
     . = ALIGN(4K);
+    _tee_ram_start = .;
     . += _tee_stack_size;
     _tee_stack_top = .;
   } > REGION_TEE_MEM
@@ -0,0 +1,14 @@
+// QEMU virt machine (`-M virt`)
+
+/// Hart which runs the firmware, the other harts wait.
+pub const BOOT_HARTID: usize = 0;
+
+/// Console UART
+pub const UART: Uart = Uart::Ns16550 { base: 0x1000_0000 };
+
+/// CLINT (machine timer and software interrupts)
+pub const CLINT_BASE: usize = 0x200_0000;
+
+/// Smallest region (log2 of the size in bytes) the PMP can protect. QEMU implements the 4-byte
+/// granularity of the spec, the NAPOT minimum is 8 bytes.
+pub const PMP_GRANULARITY_ORDER: u32 = 3;
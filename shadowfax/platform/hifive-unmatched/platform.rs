@@ -0,0 +1,15 @@
+// SiFive HiFive Unmatched (FU740-C000)
+
+/// Hart which runs the firmware, the other harts wait. Hart 0 is the S7 monitor core, which has
+/// no S-mode: the first U74 runs the firmware.
+pub const BOOT_HARTID: usize = 1;
+
+/// Console UART (UART0, the one wired to the on-board USB bridge)
+pub const UART: Uart = Uart::Sifive { base: 0x1001_0000 };
+
+/// CLINT (machine timer and software interrupts)
+pub const CLINT_BASE: usize = 0x200_0000;
+
+/// Smallest region (log2 of the size in bytes) the PMP can protect. The U74 PMP has a 4KiB
+/// granularity, smaller regions are silently extended by the hardware.
+pub const PMP_GRANULARITY_ORDER: u32 = 12;
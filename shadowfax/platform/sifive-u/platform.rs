@@ -0,0 +1,15 @@
+// QEMU sifive_u machine (`-M sifive_u`, FU540-C000)
+
+/// Hart which runs the firmware, the other harts wait. Hart 0 is the E51 monitor core, which has
+/// no S-mode: the first U54 runs the firmware.
+pub const BOOT_HARTID: usize = 1;
+
+/// Console UART (UART0)
+pub const UART: Uart = Uart::Sifive { base: 0x1001_0000 };
+
+/// CLINT (machine timer and software interrupts)
+pub const CLINT_BASE: usize = 0x200_0000;
+
+/// Smallest region (log2 of the size in bytes) the PMP can protect. QEMU implements the 4-byte
+/// granularity of the spec, the NAPOT minimum is 8 bytes.
+pub const PMP_GRANULARITY_ORDER: u32 = 3;
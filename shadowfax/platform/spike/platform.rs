@@ -0,0 +1,14 @@
+// Spike ISA simulator
+
+/// Hart which runs the firmware, the other harts wait.
+pub const BOOT_HARTID: usize = 0;
+
+/// Console (HTIF, spike has no UART)
+pub const UART: Uart = Uart::Htif;
+
+/// CLINT (machine timer and software interrupts)
+pub const CLINT_BASE: usize = 0x200_0000;
+
+/// Smallest region (log2 of the size in bytes) the PMP can protect. Spike implements the 4-byte
+/// granularity of the spec, the NAPOT minimum is 8 bytes.
+pub const PMP_GRANULARITY_ORDER: u32 = 3;
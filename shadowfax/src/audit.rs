@@ -0,0 +1,141 @@
+/*
+ * Audit log of the security-relevant events seen by the TSM-driver: TSM verification, domain
+ * creation, confidential page conversions, PMP faults and calls denied by the SBI policies.
+ *
+ * The log is append-only: each record gets the next sequence number and records are never
+ * modified. Only the last `AUDIT_LOG_CAPACITY` records are kept in memory, older records are
+ * dropped (a gap in the sequence numbers tells the reader). Every record, including the dropped
+ * ones, is extended into a runtime measurement register:
+ *
+ *   mr = SHA384(mr || record)
+ *
+ * so a verifier can replay the records and compare the result with the register. Domains granted
+ * the `audit-log` capability by their SBI policy read both through SUPD.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use alloc::{boxed::Box, collections::VecDeque};
+use common::measurement::{HashAlgorithm, MeasurementHasher};
+
+use crate::scheduler::read_mtime;
+
+/// Records kept in memory
+pub const AUDIT_LOG_CAPACITY: usize = 256;
+/// Size of the audit measurement register (SHA384)
+pub const AUDIT_MEASUREMENT_SIZE: usize = 48;
+
+#[repr(u32)]
+#[derive(Clone, Copy, Debug)]
+pub enum AuditEvent {
+    /// The TSM signature was verified and the TSM loaded. arg0: size of the TSM image, arg1: id of
+    /// the signing key in the key ring
+    TsmVerified = 1,
+    /// The TSM signature check failed, the TSM is not started. arg0: size of the TSM image
+    TsmRejected = 2,
+    /// A supervisor domain was created. arg0: trust map
+    DomainCreated = 3,
+    /// Pages were converted to confidential memory. arg0: base address, arg1: number of pages
+    PagesConverted = 4,
+    /// Confidential pages were given back. arg0: base address, arg1: number of pages
+    PagesReclaimed = 5,
+    /// Access fault of a supervisor domain. arg0: mcause, arg1: mtval
+    PmpFault = 6,
+    /// A call was denied by the SBI policy of the domain. arg0: eid, arg1: fid
+    PolicyDenied = 7,
+    /// A TEECALL did not return within the watchdog budget, the TSM is faulted. arg0: eid, arg1:
+    /// fid
+    TsmWatchdog = 8,
+    /// A confidential domain shut itself down and was wiped. arg0: reset type, arg1: reason
+    DomainStopped = 9,
+    /// The platform is reset, the confidential domains were wiped. arg0: reset type, arg1: reason
+    PlatformReset = 10,
+    /// The DICE input is missing or invalid, the TSM is not started
+    AttestationUnavailable = 11,
+    /// The TSM completed its initialization and takes TEECALLs
+    TsmReady = 12,
+    /// Two domains trust each other (arg1: 1) or not anymore (arg1: 0). arg0: the other domain
+    TrustChanged = 13,
+    /// The domain restored its snapshot. arg0: bytes of memory copied back
+    DomainRestored = 14,
+    /// Non-retentive suspend of the hart (arg0: HSM) or of the system (arg0: SUSP). arg1: the type
+    Suspended = 15,
+    /// The PMP did not read back as programmed for the domain about to run, the calls were
+    /// unwound
+    PmpFailure = 16,
+    /// The PMP denied an access the OpenSBI domain of the faulting domain allows: both views of
+    /// its isolation disagree. arg0: mcause, arg1: mtval
+    DomainMismatch = 17,
+}
+
+/// Audit record as copied to the reader.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct AuditRecord {
+    pub seq: u64,
+    /// mtime when the event was recorded
+    pub time: u64,
+    pub event: u32,
+    /// Domain which caused the event
+    pub domain: u32,
+    pub arg0: u64,
+    pub arg1: u64,
+}
+
+impl AuditRecord {
+    fn as_bytes(&self) -> &[u8] {
+        // SAFETY: AuditRecord is repr(C) without padding
+        unsafe {
+            core::slice::from_raw_parts(self as *const Self as *const u8, size_of::<AuditRecord>())
+        }
+    }
+}
+
+pub struct AuditLog {
+    records: VecDeque<AuditRecord>,
+    next_seq: u64,
+    hasher: Box<dyn MeasurementHasher>,
+    measurement: [u8; AUDIT_MEASUREMENT_SIZE],
+}
+
+impl AuditLog {
+    pub fn new() -> Self {
+        Self {
+            records: VecDeque::with_capacity(AUDIT_LOG_CAPACITY),
+            next_seq: 0,
+            hasher: HashAlgorithm::Sha384.hasher().unwrap(),
+            measurement: [0; AUDIT_MEASUREMENT_SIZE],
+        }
+    }
+
+    /// Append an event and extend the measurement register with it.
+    pub fn record(&mut self, event: AuditEvent, domain: usize, arg0: usize, arg1: usize) {
+        let record = AuditRecord {
+            seq: self.next_seq,
+            time: read_mtime(),
+            event: event as u32,
+            domain: domain as u32,
+            arg0: arg0 as u64,
+            arg1: arg1 as u64,
+        };
+        self.next_seq += 1;
+
+        self.hasher.extend(&self.measurement);
+        self.hasher.extend(record.as_bytes());
+        self.hasher.finalize_reset_into(&mut self.measurement);
+
+        if self.records.len() == AUDIT_LOG_CAPACITY {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Records still in memory with a sequence number of at least `first_seq`.
+    pub fn records_from(&self, first_seq: u64) -> impl Iterator<Item = &AuditRecord> {
+        self.records.iter().filter(move |r| r.seq >= first_seq)
+    }
+
+    pub fn measurement(&self) -> &[u8; AUDIT_MEASUREMENT_SIZE] {
+        &self.measurement
+    }
+}
@@ -0,0 +1,306 @@
+/*
+ * Memory handed to the TSM by the COVH calls, as a table: each function id lists the buffers it
+ * passes in a0-a5, where their size comes from and who owns them. On TEECALL the firmware checks
+ * each buffer and prepares the PMP of the TSM:
+ *  - Host: memory of the caller (parameter blocks, output structs, source pages). The caller must
+ *    own the NAPOT region covering the buffer, which the TSM gets for the duration of the call and
+ *    loses at its TEERET (or when the call is aborted): the grants are tagged `CallBuffer`;
+ *  - Confidential: pages the caller converted earlier (destination pages). The TSM must own them
+ *    already, so that a call naming other pages fails in the firmware instead of faulting in the
+ *    TSM.
+ *
+ * The calls changing the ownership of memory (CONVERT_PAGES, RECLAIM_PAGES, ADD_TVM_SHARED_PAGES)
+ * and the AIA interrupt files keep their own handling in `cove.rs`. The calls with a list in host
+ * memory also hand the buffers the list names: each page of an ADD_TVM_MEASURED_PAGES_BATCH is
+ * checked as an ADD_TVM_MEASURED_PAGES of one page, each entry of a PROCESS_QUEUE as the call it
+ * queues. The list is read once the caller is known to own it.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use common::{
+    migration::MIGRATION_OFFER_SIZE,
+    pmp::Region,
+    sbi::{
+        CovhQueueEntry, MeasuredPageDesc, TsmMemoryStats, TsmStats, TvmGpaTranslation, TvmVcpuTime,
+        COVH_DEFAULT_PAGE_SIZE, SBI_COVH_ADD_TVM_MEASURED_PAGES,
+        SBI_COVH_ADD_TVM_MEASURED_PAGES_BATCH, SBI_COVH_ADD_TVM_SEALED_IMAGE,
+        SBI_COVH_ADD_ZERO_PAGES, SBI_COVH_CREATE_TVM, SBI_COVH_EXPORT_TVM, SBI_COVH_GET_TSM_INFO,
+        SBI_COVH_GET_TSM_MEMORY_STATS, SBI_COVH_GET_TSM_STATS, SBI_COVH_GET_TVM_VCPU_TIME,
+        SBI_COVH_IMPORT_TVM, SBI_COVH_PREPARE_TVM_IMPORT, SBI_COVH_PROCESS_QUEUE,
+        SBI_COVH_SET_TVM_BOOT_INFO, SBI_COVH_TVM_TRANSLATE_GPA,
+    },
+};
+use core::mem::size_of;
+
+use crate::{
+    domain::{Domain, MemoryRegion, RegionTag, MAX_MEMORY_REGIONS, MEMREGION_R, MEMREGION_RW},
+    error::CallError,
+    platform,
+};
+
+/// Regions granted for a single call. The buffers named by a list usually share the region of the
+/// host memory, or a few of them.
+const MAX_CALL_BUFFERS: usize = 4;
+
+#[derive(Clone, Copy)]
+enum Size {
+    /// Bytes in the argument
+    Bytes(usize),
+    /// Pages in the argument
+    Pages(usize),
+    /// Entries of the given size in the argument
+    Entries(usize, usize),
+    Fixed(usize),
+}
+
+#[derive(Clone, Copy)]
+enum Owner {
+    /// Memory of the caller, granted to the TSM during the call with these permissions
+    Host(u8),
+    /// Confidential memory, already owned by the TSM
+    Confidential,
+}
+
+#[derive(Clone, Copy)]
+struct Buffer {
+    /// Argument holding the address (0 for a0)
+    addr: usize,
+    size: Size,
+    owner: Owner,
+}
+
+const fn host(addr: usize, size: Size, permissions: u8) -> Buffer {
+    Buffer {
+        addr,
+        size,
+        owner: Owner::Host(permissions),
+    }
+}
+
+const fn confidential(addr: usize, size: Size) -> Buffer {
+    Buffer {
+        addr,
+        size,
+        owner: Owner::Confidential,
+    }
+}
+
+/// Buffers of the COVH calls, see the argument layout of each call in `tsm_core::covh`
+const COVH_BUFFERS: &[(usize, &[Buffer])] = &[
+    (
+        SBI_COVH_GET_TSM_INFO,
+        &[host(0, Size::Bytes(1), MEMREGION_RW)],
+    ),
+    (SBI_COVH_CREATE_TVM, &[host(0, Size::Bytes(1), MEMREGION_R)]),
+    (
+        SBI_COVH_ADD_TVM_MEASURED_PAGES,
+        &[
+            host(1, Size::Pages(4), MEMREGION_R),
+            confidential(2, Size::Pages(4)),
+        ],
+    ),
+    (
+        SBI_COVH_ADD_TVM_MEASURED_PAGES_BATCH,
+        &[host(
+            1,
+            Size::Entries(2, size_of::<MeasuredPageDesc>()),
+            MEMREGION_R,
+        )],
+    ),
+    (
+        SBI_COVH_ADD_TVM_SEALED_IMAGE,
+        &[
+            host(1, Size::Bytes(2), MEMREGION_R),
+            confidential(3, Size::Pages(4)),
+        ],
+    ),
+    (SBI_COVH_ADD_ZERO_PAGES, &[confidential(1, Size::Pages(3))]),
+    (
+        SBI_COVH_SET_TVM_BOOT_INFO,
+        &[confidential(1, Size::Fixed(COVH_DEFAULT_PAGE_SIZE))],
+    ),
+    (
+        SBI_COVH_EXPORT_TVM,
+        &[
+            host(1, Size::Bytes(2), MEMREGION_RW),
+            host(3, Size::Fixed(MIGRATION_OFFER_SIZE), MEMREGION_R),
+        ],
+    ),
+    (
+        SBI_COVH_IMPORT_TVM,
+        &[
+            host(0, Size::Bytes(1), MEMREGION_R),
+            confidential(4, Size::Pages(5)),
+        ],
+    ),
+    (
+        SBI_COVH_GET_TVM_VCPU_TIME,
+        &[host(2, Size::Fixed(size_of::<TvmVcpuTime>()), MEMREGION_RW)],
+    ),
+    (
+        SBI_COVH_TVM_TRANSLATE_GPA,
+        &[host(
+            2,
+            Size::Fixed(size_of::<TvmGpaTranslation>()),
+            MEMREGION_RW,
+        )],
+    ),
+    (
+        SBI_COVH_GET_TSM_STATS,
+        &[host(0, Size::Fixed(size_of::<TsmStats>()), MEMREGION_RW)],
+    ),
+    (
+        SBI_COVH_GET_TSM_MEMORY_STATS,
+        &[host(
+            0,
+            Size::Fixed(size_of::<TsmMemoryStats>()),
+            MEMREGION_RW,
+        )],
+    ),
+    (
+        SBI_COVH_PROCESS_QUEUE,
+        &[host(
+            0,
+            Size::Entries(1, size_of::<CovhQueueEntry>()),
+            MEMREGION_RW,
+        )],
+    ),
+    (
+        SBI_COVH_PREPARE_TVM_IMPORT,
+        &[host(0, Size::Fixed(MIGRATION_OFFER_SIZE), MEMREGION_RW)],
+    ),
+];
+
+/// Smallest NAPOT region covering `[addr, addr + size)`, at least as large as the PMP granularity.
+fn covering_region(addr: usize, size: usize) -> Option<(usize, u32)> {
+    let region = Region::covering(addr, size, platform::PMP_GRANULARITY_ORDER)?;
+    Some((region.base(), region.size().trailing_zeros()))
+}
+
+/// Check the buffers of the COVH call `fid` made by the domain `caller` to the domain `tsm`, with
+/// arguments `args`, and give the host buffers to `tsm` until `release`. Fails if a buffer is not
+/// where it should be, leaving the domains untouched.
+pub fn grant(
+    domains: &mut [Domain],
+    tsm: usize,
+    caller: usize,
+    fid: usize,
+    args: &[usize; 6],
+) -> Result<(), CallError> {
+    let mut grants = heapless::Vec::<MemoryRegion, MAX_CALL_BUFFERS>::new();
+    check_call(&domains[tsm], &domains[caller], fid, args, &mut grants)?;
+
+    let tsm = &mut domains[tsm];
+    if tsm.memory_regions.len() + grants.len() > MAX_MEMORY_REGIONS {
+        return Err(CallError::NoBufferEntries);
+    }
+    for region in grants {
+        tsm.grant(region, RegionTag::CallBuffer)?;
+    }
+    Ok(())
+}
+
+/// Check the buffers of the call `fid` and of the lists it names, and collect the host regions to
+/// grant in `grants`.
+fn check_call(
+    callee: &Domain,
+    caller: &Domain,
+    fid: usize,
+    args: &[usize; 6],
+    grants: &mut heapless::Vec<MemoryRegion, MAX_CALL_BUFFERS>,
+) -> Result<(), CallError> {
+    let Some((_, buffers)) = COVH_BUFFERS.iter().find(|(id, _)| *id == fid) else {
+        return Ok(());
+    };
+
+    for buffer in buffers.iter() {
+        let addr = args[buffer.addr];
+        let size = match buffer.size {
+            Size::Bytes(arg) => Some(args[arg]),
+            Size::Pages(arg) => args[arg].checked_mul(COVH_DEFAULT_PAGE_SIZE),
+            Size::Entries(arg, entry) => args[arg].checked_mul(entry),
+            Size::Fixed(size) => Some(size),
+        };
+        let Some(size) = size.filter(|&size| size != 0) else {
+            return Err(CallError::BufferSize(buffer.addr));
+        };
+
+        match buffer.owner {
+            Owner::Confidential => {
+                if !callee.owns(addr, size) {
+                    return Err(CallError::BufferNotConfidential(buffer.addr));
+                }
+            }
+            // Already reachable by the TSM, e.g. pages it shares with the host
+            Owner::Host(_) if callee.owns(addr, size) => {}
+            Owner::Host(permissions) => {
+                let Some((base_addr, order)) = covering_region(addr, size)
+                    .filter(|&(base, order)| caller.owns(base, 1 << order))
+                else {
+                    return Err(CallError::BufferNotCallerMemory(buffer.addr));
+                };
+                // The buffers of a call fit in the same region
+                if grants
+                    .iter()
+                    .any(|g| g.base_addr == base_addr && g.order == order)
+                {
+                    continue;
+                }
+                grants
+                    .push(MemoryRegion {
+                        base_addr,
+                        order,
+                        mmio: false,
+                        permissions,
+                    })
+                    .map_err(|_| CallError::NoBufferEntries)?;
+            }
+        }
+    }
+
+    // The lists were checked above: they are memory of the caller or of the TSM
+    match fid {
+        SBI_COVH_ADD_TVM_MEASURED_PAGES_BATCH => {
+            for i in 0..args[2] {
+                let desc: MeasuredPageDesc = unsafe { read_entry(args[1], i) };
+                let page = [
+                    args[0],
+                    desc.source_addr,
+                    desc.dest_addr,
+                    0,
+                    1,
+                    desc.tvm_guest_gpa,
+                ];
+                check_call(
+                    callee,
+                    caller,
+                    SBI_COVH_ADD_TVM_MEASURED_PAGES,
+                    &page,
+                    grants,
+                )?;
+            }
+        }
+        SBI_COVH_PROCESS_QUEUE => {
+            for i in 0..args[1] {
+                let entry: CovhQueueEntry = unsafe { read_entry(args[0], i) };
+                // A queue cannot hold another queue, see `covh_queueable`
+                if entry.fid != SBI_COVH_PROCESS_QUEUE {
+                    check_call(callee, caller, entry.fid, &entry.args, grants)?;
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Copy of the entry `index` of the list at `addr`, which the caller can change at any time
+unsafe fn read_entry<T: Copy>(addr: usize, index: usize) -> T {
+    core::ptr::read_unaligned((addr as *const T).add(index))
+}
+
+/// Take back from `tsm` the buffers granted to its last call.
+pub fn release(tsm: &mut Domain) {
+    tsm.revoke_all(RegionTag::CallBuffer);
+}
@@ -0,0 +1,109 @@
+/*
+ * Firmware console service: the Debug Console extension (DBCN) for every SBI runtime. `trap.rs`
+ * sends the DBCN ecalls of the supervisor domains here before the runtime sees them.
+ *
+ * Several domains print on the same console (the TSM and the TVM guests it forwards, the host and
+ * its VMs), so their output is written a line at a time, see `cove_core::console`. When the
+ * device tree assigns the console UART to a domain without `console-mux`, the writes of the other
+ * domains are denied (see `State::console_allowed`). The input goes to the owner of the UART
+ * only, or to any domain if the UART is shared; reads never block.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use common::sbi::SbiRet;
+use cove_core::{console::DomainLines, platform::Console};
+use spin::mutex::Mutex;
+
+use crate::{
+    dispatch::{SBI_ERR_DENIED, SBI_ERR_INVALID_PARAM, SBI_ERR_NOT_SUPPORTED},
+    runtime,
+    state::State,
+};
+
+pub const EXT_DBCN: usize = 0x4442434E;
+const DBCN_CONSOLE_WRITE: usize = 0;
+const DBCN_CONSOLE_READ: usize = 1;
+const DBCN_CONSOLE_WRITE_BYTE: usize = 2;
+
+/// Pending line of each domain
+static LINES: Mutex<DomainLines> = Mutex::new(DomainLines::new());
+
+/// Console of the SBI runtime
+struct RuntimeConsole;
+
+impl Console for RuntimeConsole {
+    fn putc(&mut self, c: u8) {
+        runtime::putc(c);
+    }
+
+    fn getc(&mut self) -> Option<u8> {
+        runtime::getc()
+    }
+}
+
+/// Handle the DBCN call `fid` of the active domain.
+pub fn handle(state: &State, fid: usize, args: &[usize; 6]) -> SbiRet {
+    let domain = state.active_domain;
+
+    match fid {
+        DBCN_CONSOLE_WRITE | DBCN_CONSOLE_WRITE_BYTE if !state.console_allowed(domain) => {
+            err(SBI_ERR_DENIED)
+        }
+        DBCN_CONSOLE_READ if !state.console_input_allowed(domain) => err(SBI_ERR_DENIED),
+        DBCN_CONSOLE_WRITE | DBCN_CONSOLE_READ => {
+            let Some(buf) = caller_buffer(state, args[0], args[1], args[2]) else {
+                return err(SBI_ERR_INVALID_PARAM);
+            };
+
+            if fid == DBCN_CONSOLE_WRITE {
+                write(domain, buf);
+                return ok(buf.len());
+            }
+            let read = buf
+                .iter_mut()
+                .map_while(|b| RuntimeConsole.getc().map(|c| *b = c))
+                .count();
+            ok(read)
+        }
+        DBCN_CONSOLE_WRITE_BYTE => {
+            write(domain, &[args[0] as u8]);
+            ok(0)
+        }
+        _ => err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// Buffer of `num_bytes` at `base_lo` | `base_hi`, if it is memory of the active domain. On RV64
+/// the address fits in `base_lo`.
+fn caller_buffer(
+    state: &State,
+    num_bytes: usize,
+    base_lo: usize,
+    base_hi: usize,
+) -> Option<&'static mut [u8]> {
+    let owned = state
+        .domains
+        .get(state.active_domain)
+        .is_some_and(|d| d.owns(base_lo, num_bytes));
+    if base_hi != 0 || !owned {
+        return None;
+    }
+    Some(unsafe { core::slice::from_raw_parts_mut(base_lo as *mut u8, num_bytes) })
+}
+
+/// Append `buf` to the pending line of `domain` and write the complete lines.
+fn write(domain: usize, buf: &[u8]) {
+    LINES.lock().write(&mut RuntimeConsole, domain, buf);
+}
+
+const fn ok(value: usize) -> SbiRet {
+    SbiRet {
+        a0: 0,
+        a1: value as isize,
+    }
+}
+
+const fn err(code: isize) -> SbiRet {
+    SbiRet { a0: code, a1: 0 }
+}
@@ -1,34 +1,50 @@
 pub const DICE_INPUT_ADDR: usize = 0x8800_0000;
+/// Size of the DICE input region: its length fields are only trusted within it. The region is
+/// wiped once parsed.
+pub const DICE_INPUT_SIZE: usize = 0x2000;
+/// Space of the device tree view of a domain, at the end of its first memory region
+#[cfg(feature = "fdt")]
+pub const FDT_VIEW_SIZE: usize = 0x10000;
 
 pub mod memory_layout {
-    use crate::domain::MemoryRegion;
+    use crate::{
+        domain::{MemoryRegion, MEMREGION_RWX},
+        platform::{TSM_BASE, TSM_ORDER, UNTRUSTED_BASE, UNTRUSTED_ORDER},
+    };
 
     pub const ROOT_DOMAIN_REGIONS: [MemoryRegion; 1] = [MemoryRegion {
         base_addr: 0,
         order: 64,
         mmio: false,
-        permissions: 0x3F,
+        permissions: MEMREGION_RWX,
     }];
 
     pub const UNTRUSTED_DOMAIN_REGIONS: [MemoryRegion; 1] = [MemoryRegion {
-        base_addr: 0x8A00_0000,
-        order: 24,
+        base_addr: UNTRUSTED_BASE,
+        order: UNTRUSTED_ORDER,
         mmio: false,
-        permissions: 0x3F,
+        permissions: MEMREGION_RWX,
     }];
 
-    pub const TRUSTED_DOMAIN_REGIONS: [MemoryRegion; 2] = [
-        MemoryRegion {
-            base_addr: 0x8800_0000,
-            order: 26,
-            permissions: 0x3f,
-            mmio: false,
-        },
-        MemoryRegion {
-            base_addr: 0x1000_0000,
-            order: 12,
-            permissions: 0x3f,
-            mmio: true,
-        },
-    ];
+    // The untrusted domain never sees the DICE input, even before it is wiped. The TSM memory
+    // holds it, but the TSM is only loaded once it was wiped.
+    const _: () = {
+        let mut i = 0;
+        while i < UNTRUSTED_DOMAIN_REGIONS.len() {
+            let r = &UNTRUSTED_DOMAIN_REGIONS[i];
+            assert!(
+                r.base_addr >= super::DICE_INPUT_ADDR + super::DICE_INPUT_SIZE
+                    || r.base_addr + (1 << r.order) <= super::DICE_INPUT_ADDR
+            );
+            i += 1;
+        }
+    };
+
+    // The MMIO devices come from the device table (`devices.rs`)
+    pub const TRUSTED_DOMAIN_REGIONS: [MemoryRegion; 1] = [MemoryRegion {
+        base_addr: TSM_BASE,
+        order: TSM_ORDER,
+        permissions: MEMREGION_RWX,
+        mmio: false,
+    }];
 }
@@ -1,23 +1,80 @@
 /*
 * The Context struct represent the set of gprs, csrs and pmp registers needed for a context switch
-* towards and from a TSM.
+* towards and from a TSM. It is saved and restored by the CoVE entries and exit, see
+* `context_switch.rs`.
 * Author: Giuseppe Capasso <capassog97@gmail.com>
 */
+use cove_core::platform::ContextStorage;
+
 #[derive(Clone, Debug)]
 #[repr(C, align(4))]
 pub struct Context {
     pub regs: [usize; 32],
 
-    sstatus: usize,
+    pub sstatus: usize,
     pub stvec: usize,
-    sip: usize,
-    scounteren: usize,
-    sscratch: usize,
-    satp: usize,
+    pub sip: usize,
+    pub scounteren: usize,
+    pub sscratch: usize,
+    pub satp: usize,
     senvcfg: usize,
     scontext: usize,
     pub mepc: usize,
 
+    // Hypervisor extension, switched only if the harts have it
+    pub hstatus: usize,
+    pub hedeleg: usize,
+    pub hideleg: usize,
+    pub hgatp: usize,
+
     interrupted: usize,
     pub caller_ctx: usize,
+    /// Interrupts of the domain held while it waits for a TEERET, see `interrupts.rs`
+    pub pending_irqs: usize,
+}
+
+impl Context {
+    /// Take the registers and CSRs of `saved`, keeping the links of this context to its callers.
+    pub fn resume_from(&mut self, saved: &Context) {
+        *self = Context {
+            interrupted: self.interrupted,
+            caller_ctx: self.caller_ctx,
+            pending_irqs: self.pending_irqs,
+            ..saved.clone()
+        };
+    }
+
+    /// Saved CSRs, by name
+    pub fn csrs(&self) -> [(&'static str, usize); 13] {
+        [
+            ("sstatus", self.sstatus),
+            ("stvec", self.stvec),
+            ("sip", self.sip),
+            ("scounteren", self.scounteren),
+            ("sscratch", self.sscratch),
+            ("satp", self.satp),
+            ("senvcfg", self.senvcfg),
+            ("scontext", self.scontext),
+            ("mepc", self.mepc),
+            ("hstatus", self.hstatus),
+            ("hedeleg", self.hedeleg),
+            ("hideleg", self.hideleg),
+            ("hgatp", self.hgatp),
+        ]
+    }
+}
+
+/// Contexts saved in place by the context switch, at the `context_addr` of each domain
+pub struct RawContexts;
+
+impl ContextStorage for RawContexts {
+    type Context = Context;
+
+    fn load(&self, addr: usize) -> Context {
+        unsafe { (*(addr as *const Context)).clone() }
+    }
+
+    fn store(&mut self, addr: usize, context: Context) {
+        unsafe { core::ptr::write(addr as *mut Context, context) };
+    }
 }
@@ -0,0 +1,325 @@
+/*
+ * Context switch between the supervisor domains. A CoVE entry (`cove_entry!`) saves the trapped
+ * domain in the scratch context of the TEE RAM (see `tee.rs`) and calls its handler on the TEE
+ * stack, `tee_handler_exit` restores the Context returned by the handler and goes back with mret.
+ *
+ * The Context (see `context.rs`) is addressed through the offsets below, taken from the struct,
+ * and the save and restore blocks are generated once for all the entries and the exit: a CSR
+ * added to the Context is switched everywhere by adding it to `save_csrs!` and `restore_csrs!`.
+ * senvcfg and scontext are not switched, since not every hart implements them.
+ *
+ * The hypervisor CSRs (hstatus, hedeleg, hideleg and hgatp) are switched too when the harts have
+ * the hypervisor extension (`SWITCH_H_CSRS`), so that the untrusted domain and a confidential
+ * domain can both run guests without seeing each other's G-stage. The G-stage TLB is flushed
+ * whenever hgatp is restored, as the domains do not share VMIDs.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use core::{
+    mem::offset_of,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::context::Context;
+
+pub const CTX_REGS: usize = offset_of!(Context, regs);
+pub const CTX_SSTATUS: usize = offset_of!(Context, sstatus);
+pub const CTX_STVEC: usize = offset_of!(Context, stvec);
+pub const CTX_SIP: usize = offset_of!(Context, sip);
+pub const CTX_SCOUNTEREN: usize = offset_of!(Context, scounteren);
+pub const CTX_SSCRATCH: usize = offset_of!(Context, sscratch);
+pub const CTX_SATP: usize = offset_of!(Context, satp);
+pub const CTX_MEPC: usize = offset_of!(Context, mepc);
+pub const CTX_HSTATUS: usize = offset_of!(Context, hstatus);
+pub const CTX_HEDELEG: usize = offset_of!(Context, hedeleg);
+pub const CTX_HIDELEG: usize = offset_of!(Context, hideleg);
+pub const CTX_HGATP: usize = offset_of!(Context, hgatp);
+
+/// Whether the hypervisor CSRs are switched (non-zero), set at init if the harts have the
+/// hypervisor extension. Read by the entries and the exit before they have a stack.
+pub static SWITCH_H_CSRS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the CSR at `offset` is an XLEN-sized slot after the GPRs
+const fn is_csr_slot(offset: usize) -> bool {
+    offset >= CTX_REGS + 32 * size_of::<usize>() && offset % size_of::<usize>() == 0
+}
+
+// The GPRs are addressed as XLEN-sized slots of the base of the Context (x0 is slot 0)
+const _: () = assert!(CTX_REGS == 0);
+const _: () = assert!(
+    is_csr_slot(CTX_SSTATUS)
+        && is_csr_slot(CTX_STVEC)
+        && is_csr_slot(CTX_SIP)
+        && is_csr_slot(CTX_SCOUNTEREN)
+        && is_csr_slot(CTX_SSCRATCH)
+        && is_csr_slot(CTX_SATP)
+        && is_csr_slot(CTX_MEPC)
+        && is_csr_slot(CTX_HSTATUS)
+        && is_csr_slot(CTX_HEDELEG)
+        && is_csr_slot(CTX_HIDELEG)
+        && is_csr_slot(CTX_HGATP)
+);
+
+/// Save x0, x1 and x3-x31 in the Context at sp. sp is saved by the entry.
+macro_rules! save_gprs {
+    () => {
+        concat!(
+            common::reg_store!(x0, 0(sp)),
+            common::reg_store!(x1, 1(sp)),
+            common::reg_store!(x3, 3(sp)),
+            common::reg_store!(x4, 4(sp)),
+            common::reg_store!(x5, 5(sp)),
+            common::reg_store!(x6, 6(sp)),
+            common::reg_store!(x7, 7(sp)),
+            common::reg_store!(x8, 8(sp)),
+            common::reg_store!(x9, 9(sp)),
+            common::reg_store!(x10, 10(sp)),
+            common::reg_store!(x11, 11(sp)),
+            common::reg_store!(x12, 12(sp)),
+            common::reg_store!(x13, 13(sp)),
+            common::reg_store!(x14, 14(sp)),
+            common::reg_store!(x15, 15(sp)),
+            common::reg_store!(x16, 16(sp)),
+            common::reg_store!(x17, 17(sp)),
+            common::reg_store!(x18, 18(sp)),
+            common::reg_store!(x19, 19(sp)),
+            common::reg_store!(x20, 20(sp)),
+            common::reg_store!(x21, 21(sp)),
+            common::reg_store!(x22, 22(sp)),
+            common::reg_store!(x23, 23(sp)),
+            common::reg_store!(x24, 24(sp)),
+            common::reg_store!(x25, 25(sp)),
+            common::reg_store!(x26, 26(sp)),
+            common::reg_store!(x27, 27(sp)),
+            common::reg_store!(x28, 28(sp)),
+            common::reg_store!(x29, 29(sp)),
+            common::reg_store!(x30, 30(sp)),
+            common::reg_store!(x31, 31(sp)),
+        )
+    };
+}
+
+/// Restore the GPRs from the Context at sp, but t0, a0 and sp: t0 is the scratch of
+/// `restore_csrs!`.
+macro_rules! restore_gprs {
+    () => {
+        concat!(
+            common::reg_load!(ra, 1(sp)),
+            common::reg_load!(gp, 3(sp)),
+            common::reg_load!(tp, 4(sp)),
+            common::reg_load!(t1, 6(sp)),
+            common::reg_load!(t2, 7(sp)),
+            common::reg_load!(s0, 8(sp)),
+            common::reg_load!(s1, 9(sp)),
+            common::reg_load!(a1, 11(sp)),
+            common::reg_load!(a2, 12(sp)),
+            common::reg_load!(a3, 13(sp)),
+            common::reg_load!(a4, 14(sp)),
+            common::reg_load!(a5, 15(sp)),
+            common::reg_load!(a6, 16(sp)),
+            common::reg_load!(a7, 17(sp)),
+            common::reg_load!(s2, 18(sp)),
+            common::reg_load!(s3, 19(sp)),
+            common::reg_load!(s4, 20(sp)),
+            common::reg_load!(s5, 21(sp)),
+            common::reg_load!(s6, 22(sp)),
+            common::reg_load!(s7, 23(sp)),
+            common::reg_load!(s8, 24(sp)),
+            common::reg_load!(s9, 25(sp)),
+            common::reg_load!(s10, 26(sp)),
+            common::reg_load!(s11, 27(sp)),
+            common::reg_load!(t3, 28(sp)),
+            common::reg_load!(t4, 29(sp)),
+            common::reg_load!(t5, 30(sp)),
+            common::reg_load!(t6, 31(sp)),
+        )
+    };
+}
+
+/// Save the CSRs in the Context at sp, through t0. Needs the `ctx_*` offsets and `switch_h_csrs`
+/// as operands.
+macro_rules! save_csrs {
+    () => {
+        concat!(
+            "csrr t0, sstatus\n",
+            common::reg_store!(t0, "{ctx_sstatus}(sp)"),
+            "csrr t0, stvec\n",
+            common::reg_store!(t0, "{ctx_stvec}(sp)"),
+            "csrr t0, sip\n",
+            common::reg_store!(t0, "{ctx_sip}(sp)"),
+            "csrr t0, scounteren\n",
+            common::reg_store!(t0, "{ctx_scounteren}(sp)"),
+            "csrr t0, sscratch\n",
+            common::reg_store!(t0, "{ctx_sscratch}(sp)"),
+            "csrr t0, satp\n",
+            common::reg_store!(t0, "{ctx_satp}(sp)"),
+            "csrr t0, mepc\n",
+            common::reg_store!(t0, "{ctx_mepc}(sp)"),
+            "la t0, {switch_h_csrs}\n",
+            common::reg_load!(t0, "0(t0)"),
+            "beqz t0, 1f\n",
+            "csrr t0, hstatus\n",
+            common::reg_store!(t0, "{ctx_hstatus}(sp)"),
+            "csrr t0, hedeleg\n",
+            common::reg_store!(t0, "{ctx_hedeleg}(sp)"),
+            "csrr t0, hideleg\n",
+            common::reg_store!(t0, "{ctx_hideleg}(sp)"),
+            "csrr t0, hgatp\n",
+            common::reg_store!(t0, "{ctx_hgatp}(sp)"),
+            "1:\n",
+        )
+    };
+}
+
+/// Restore the CSRs from the Context at sp, through t0. Needs the `ctx_*` offsets and
+/// `switch_h_csrs` as operands.
+macro_rules! restore_csrs {
+    () => {
+        concat!(
+            common::reg_load!(t0, "{ctx_sstatus}(sp)"),
+            "csrw sstatus, t0\n",
+            common::reg_load!(t0, "{ctx_stvec}(sp)"),
+            "csrw stvec, t0\n",
+            common::reg_load!(t0, "{ctx_sip}(sp)"),
+            "csrw sip, t0\n",
+            common::reg_load!(t0, "{ctx_scounteren}(sp)"),
+            "csrw scounteren, t0\n",
+            common::reg_load!(t0, "{ctx_sscratch}(sp)"),
+            "csrw sscratch, t0\n",
+            common::reg_load!(t0, "{ctx_satp}(sp)"),
+            "csrw satp, t0\n",
+            common::reg_load!(t0, "{ctx_mepc}(sp)"),
+            "csrw mepc, t0\n",
+            "la t0, {switch_h_csrs}\n",
+            common::reg_load!(t0, "0(t0)"),
+            "beqz t0, 1f\n",
+            common::reg_load!(t0, "{ctx_hstatus}(sp)"),
+            "csrw hstatus, t0\n",
+            common::reg_load!(t0, "{ctx_hedeleg}(sp)"),
+            "csrw hedeleg, t0\n",
+            common::reg_load!(t0, "{ctx_hideleg}(sp)"),
+            "csrw hideleg, t0\n",
+            common::reg_load!(t0, "{ctx_hgatp}(sp)"),
+            "csrw hgatp, t0\n",
+            "hfence.gvma zero, zero\n",
+            "1:\n",
+        )
+    };
+}
+
+/// Entry `$name` of the CoVE extension `$ext_id`, jumped to by the trap handler with the SBI
+/// runtime scratch in mscratch: save the trapped domain in the scratch context, call
+/// `$handler(a6)` on the TEE stack and restore the Context at the address it returns. The saved a7
+/// holds `$ext_id`.
+macro_rules! cove_entry {
+    ($name:ident, $ext_id:expr, $handler:path) => {
+        #[unsafe(naked)]
+        pub fn $name() -> ! {
+            core::arch::naked_asm!(
+                // a7 is the base pointer, as it holds the extension id anyway
+                "la a7, {scratch_context}",
+                common::reg_load!(a7, 0(a7)),
+                common::reg_store!(sp, 2(a7)),
+                "add sp, a7, zero",
+                // restore a7 and t0 and swap back the mscratch
+                "la a7, {ext_id}",
+                common::reg_load!(t0, "{sbi_scratch_tmp0_offset}(tp)"),
+                "csrrw tp, mscratch, tp",
+                $crate::context_switch::save_gprs!(),
+                $crate::context_switch::save_csrs!(),
+                "la sp, {tee_stack}",
+                "add a0, a6, zero",
+                "call {handler}",
+                "add sp, a0, zero",
+                "j {tee_handler_exit}",
+                ext_id = const $ext_id,
+                handler = sym $handler,
+                tee_stack = sym $crate::_tee_stack_top,
+                scratch_context = sym $crate::tee::TEE_SCRATCH_CONTEXT,
+                sbi_scratch_tmp0_offset = const $crate::runtime::SCRATCH_TMP0_OFFSET,
+                tee_handler_exit = sym $crate::context_switch::tee_handler_exit,
+                ctx_sstatus = const $crate::context_switch::CTX_SSTATUS,
+                ctx_stvec = const $crate::context_switch::CTX_STVEC,
+                ctx_sip = const $crate::context_switch::CTX_SIP,
+                ctx_scounteren = const $crate::context_switch::CTX_SCOUNTEREN,
+                ctx_sscratch = const $crate::context_switch::CTX_SSCRATCH,
+                ctx_satp = const $crate::context_switch::CTX_SATP,
+                ctx_mepc = const $crate::context_switch::CTX_MEPC,
+                ctx_hstatus = const $crate::context_switch::CTX_HSTATUS,
+                ctx_hedeleg = const $crate::context_switch::CTX_HEDELEG,
+                ctx_hideleg = const $crate::context_switch::CTX_HIDELEG,
+                ctx_hgatp = const $crate::context_switch::CTX_HGATP,
+                switch_h_csrs = sym $crate::context_switch::SWITCH_H_CSRS,
+            )
+        }
+    };
+}
+
+pub(crate) use {cove_entry, restore_csrs, restore_gprs, save_csrs, save_gprs};
+
+/// Restore the Context at sp, returned by a CoVE handler, and go back to its domain.
+#[unsafe(naked)]
+pub fn tee_handler_exit() -> ! {
+    core::arch::naked_asm!(
+        restore_gprs!(),
+        restore_csrs!(),
+        // restore t0, a0, sp
+        common::reg_load!(t0, 5(sp)),
+        common::reg_load!(a0, 10(sp)),
+        common::reg_load!(sp, 2(sp)),
+        "mret",
+        ctx_sstatus = const CTX_SSTATUS,
+        ctx_stvec = const CTX_STVEC,
+        ctx_sip = const CTX_SIP,
+        ctx_scounteren = const CTX_SCOUNTEREN,
+        ctx_sscratch = const CTX_SSCRATCH,
+        ctx_satp = const CTX_SATP,
+        ctx_mepc = const CTX_MEPC,
+        ctx_hstatus = const CTX_HSTATUS,
+        ctx_hedeleg = const CTX_HEDELEG,
+        ctx_hideleg = const CTX_HIDELEG,
+        ctx_hgatp = const CTX_HGATP,
+        switch_h_csrs = sym SWITCH_H_CSRS,
+    )
+}
+
+/// Save the hypervisor CSRs of the hart in `ctx`, if they are switched.
+pub fn save_h_csrs(ctx: &mut Context) {
+    if SWITCH_H_CSRS.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    unsafe {
+        core::arch::asm!(
+            "csrr {hstatus}, hstatus",
+            "csrr {hedeleg}, hedeleg",
+            "csrr {hideleg}, hideleg",
+            "csrr {hgatp}, hgatp",
+            hstatus = out(reg) ctx.hstatus,
+            hedeleg = out(reg) ctx.hedeleg,
+            hideleg = out(reg) ctx.hideleg,
+            hgatp = out(reg) ctx.hgatp,
+        )
+    };
+}
+
+/// Load the hypervisor CSRs of `ctx` in the hart, if they are switched, as `tee_handler_exit`
+/// does.
+pub fn restore_h_csrs(ctx: &Context) {
+    if SWITCH_H_CSRS.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    unsafe {
+        core::arch::asm!(
+            "csrw hstatus, {hstatus}",
+            "csrw hedeleg, {hedeleg}",
+            "csrw hideleg, {hideleg}",
+            "csrw hgatp, {hgatp}",
+            "hfence.gvma zero, zero",
+            hstatus = in(reg) ctx.hstatus,
+            hedeleg = in(reg) ctx.hedeleg,
+            hideleg = in(reg) ctx.hideleg,
+            hgatp = in(reg) ctx.hgatp,
+        )
+    };
+}
@@ -0,0 +1,178 @@
+/*
+ * Monotonic counters served to the TSM (and through it to TVMs) for replay protection. A counter
+ * is keyed by the domain using it and a `SUPD_COUNTER_ID_SIZE`-byte id chosen by that domain: the
+ * TSM derives the ids from the identity of the TVM, so a TVM finds its counters again whatever
+ * the TVMs created before it. Counters start from zero and can only grow: `increment` is the only
+ * way to change a counter. A domain holds at most `SUPD_COUNTERS_PER_DOMAIN` counters, so that it
+ * cannot take the counters of the others.
+ *
+ * The only way back to zero is `free`, made by the domain of the counter: its entry goes back to
+ * the table. The TSM frees the counters of a TVM when the TVM asks for it (COVG_FREE_COUNTER),
+ * never on its own: a TVM handing its state over to a new version of itself, which has another
+ * measurement and other counters, frees its own first, so the TVMs of the past do not keep the
+ * table full.
+ *
+ * With a blob storage (`storage`) the table is persisted as a blob of the root domain, which never
+ * runs. An increment is stored before it is returned, so the counters keep growing across resets.
+ * Without storage the counters only live in firmware memory (protected by the PMP): they are
+ * monotonic within a boot session and restart from zero after a reset.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use alloc::vec::Vec;
+use common::{
+    attestation::ct_eq,
+    sbi::{
+        SUPD_BLOB_ID_SIZE, SUPD_BLOB_MAX_SIZE, SUPD_COUNTERS_PER_DOMAIN, SUPD_COUNTER_ID_SIZE,
+        SUPD_NUM_COUNTERS,
+    },
+};
+
+use crate::{error::CallError, storage::BlobStorage};
+
+/// Blob of the counter table, stored for the root domain
+const COUNTERS_BLOB_DOMAIN: usize = 0;
+const COUNTERS_BLOB_ID: [u8; SUPD_BLOB_ID_SIZE] = *b"shadowfax monotonic counters\0\0\0\0";
+/// domain (u32) | reserved (u32) | value (u64) | id
+const COUNTER_ENTRY_SIZE: usize = 16 + SUPD_COUNTER_ID_SIZE;
+// The whole table is stored in a single blob
+const _: () = assert!(SUPD_NUM_COUNTERS * COUNTER_ENTRY_SIZE <= SUPD_BLOB_MAX_SIZE);
+
+#[derive(Clone)]
+struct Counter {
+    domain: usize,
+    id: [u8; SUPD_COUNTER_ID_SIZE],
+    value: u64,
+}
+
+#[derive(Clone, Default)]
+pub struct MonotonicCounters {
+    counters: Vec<Counter>,
+}
+
+impl MonotonicCounters {
+    /// Counters persisted in `storage`, none without storage.
+    pub fn load(storage: Option<&BlobStorage>) -> Self {
+        let blob = storage
+            .and_then(|storage| storage.load(COUNTERS_BLOB_DOMAIN, &COUNTERS_BLOB_ID))
+            .unwrap_or_default();
+        let counters = blob
+            .chunks_exact(COUNTER_ENTRY_SIZE)
+            .take(SUPD_NUM_COUNTERS)
+            .map(|entry| Counter {
+                domain: u32::from_le_bytes(entry[0..4].try_into().unwrap()) as usize,
+                value: u64::from_le_bytes(entry[8..16].try_into().unwrap()),
+                id: entry[16..].try_into().unwrap(),
+            })
+            .collect();
+        Self { counters }
+    }
+
+    fn find(&self, domain: usize, id: &[u8]) -> Option<usize> {
+        self.counters
+            .iter()
+            .position(|c| c.domain == domain && ct_eq(&c.id, id))
+    }
+
+    /// Value of the counter `id` of `domain`, zero if it was never incremented.
+    pub fn read(&self, domain: usize, id: &[u8]) -> u64 {
+        self.find(domain, id)
+            .map_or(0, |index| self.counters[index].value)
+    }
+
+    /// Increment the counter `id` of `domain` and return the new value. The new value is stored in
+    /// `storage` first: on failure the counter keeps its value.
+    pub fn increment(
+        &mut self,
+        domain: usize,
+        id: &[u8],
+        storage: Option<&mut BlobStorage>,
+    ) -> Result<u64, CallError> {
+        let mut next = self.clone();
+        let index = match next.find(domain, id) {
+            Some(index) => index,
+            None if next.counters.len() < SUPD_NUM_COUNTERS
+                && next.counters.iter().filter(|c| c.domain == domain).count()
+                    < SUPD_COUNTERS_PER_DOMAIN =>
+            {
+                next.counters.push(Counter {
+                    domain,
+                    id: id.try_into().map_err(|_| CallError::InvalidCounterId)?,
+                    value: 0,
+                });
+                next.counters.len() - 1
+            }
+            None => return Err(CallError::NoFreeCounter),
+        };
+        let counter = &mut next.counters[index];
+        counter.value = counter
+            .value
+            .checked_add(1)
+            .ok_or(CallError::CounterExhausted)?;
+        let value = counter.value;
+
+        if let Some(storage) = storage {
+            storage.store(COUNTERS_BLOB_DOMAIN, &COUNTERS_BLOB_ID, &next.to_bytes())?;
+        }
+        *self = next;
+        Ok(value)
+    }
+
+    /// Free the counter `id` of `domain`, which reads zero again. The table is stored in `storage`
+    /// first: on failure the counter is kept.
+    pub fn free(
+        &mut self,
+        domain: usize,
+        id: &[u8],
+        storage: Option<&mut BlobStorage>,
+    ) -> Result<(), CallError> {
+        let Some(index) = self.find(domain, id) else {
+            return Ok(());
+        };
+        let mut next = self.clone();
+        next.counters.remove(index);
+
+        if let Some(storage) = storage {
+            storage.store(COUNTERS_BLOB_DOMAIN, &COUNTERS_BLOB_ID, &next.to_bytes())?;
+        }
+        *self = next;
+        Ok(())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.counters.len() * COUNTER_ENTRY_SIZE);
+        for c in &self.counters {
+            bytes.extend_from_slice(&(c.domain as u32).to_le_bytes());
+            bytes.extend_from_slice(&[0; 4]);
+            bytes.extend_from_slice(&c.value.to_le_bytes());
+            bytes.extend_from_slice(&c.id);
+        }
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn quota_and_free() {
+        let mut counters = MonotonicCounters::default();
+        let id = |n: usize| [n as u8; SUPD_COUNTER_ID_SIZE];
+        for n in 0..SUPD_COUNTERS_PER_DOMAIN {
+            assert_eq!(counters.increment(1, &id(n), None).unwrap(), 1);
+        }
+        // The quota of the domain is full, not the table
+        assert!(counters.increment(1, &id(255), None).is_err());
+        assert_eq!(counters.increment(2, &id(255), None).unwrap(), 1);
+        assert_eq!(counters.increment(1, &id(0), None).unwrap(), 2);
+
+        // Only the domain of a counter frees it
+        counters.free(2, &id(0), None).unwrap();
+        assert_eq!(counters.read(1, &id(0)), 2);
+        counters.free(1, &id(0), None).unwrap();
+        assert_eq!(counters.read(1, &id(0)), 0);
+        assert_eq!(counters.increment(1, &id(255), None).unwrap(), 1);
+    }
+}
@@ -1,174 +1,246 @@
 /*
- * CoVE handler module. In this module, we provide CoVH and SUPD extension trap handling.
+ * CoVE handler module. In this module, we provide CoVH, CoVI and SUPD extension trap handling.
  * The handling is structured as follows:
- * - entry: the context is saved to the TEE_SCRATCH and calls the handler
+ * - entry: the context is saved to the scratch context (see `tee.rs`) and calls the handler, see
+ * `context_switch.rs`
  * - handler: the function which handles the interrupt and prepare the context switch. Returns the
  * address of the Context to be restored
- * - exit: restores the Context prepared by the handler
+ * - exit: restores the Context prepared by the handler (`tee_handler_exit` in `context_switch.rs`)
  *
- * While the entry is separed, the exit is shared across the SUPD and COVH since the operations are
- * the same. The entry tells the exit if it needs to restore the PMP using a0 register (0 don't
+ * The SUPD functions are declared as a table (see `dispatch.rs`).
+ *
+ * The entries of SUPD, COVH and COVI only differ in the extension id and the handler, and the
+ * exit is shared. COVH and COVI share the handler too: the entry leaves the extension id in the
+ * saved a7. The entry tells the exit if it needs to restore the PMP using a0 register (0 don't
  * restore)
  *
  * Author: Giuseppe Capasso <capassog97@gmail.com>
  */
 
-use core::mem::offset_of;
+use alloc::vec::Vec;
+use common::{
+    attestation::{DiceLayer, KEY_LADDER_MAX_LABEL},
+    pmp::Region,
+    sbi::{
+        cove_pack_fid, cove_unpack_fid, covh_queueable, CovhQueueEntry, ImsicInfo, SbiRet,
+        SupdDomainInfo, SupdDomainStats, SupdTraceEntry, COVG_EXTENSION, COVH_DEFAULT_PAGE_SIZE,
+        COVH_QUEUE_MAX_ENTRIES, SBI_COVH_ADD_TVM_SHARED_PAGES, SBI_COVH_CONVERT_PAGES,
+        SBI_COVH_EXT_ID, SBI_COVH_GET_TSM_INFO, SBI_COVH_PROCESS_QUEUE, SBI_COVH_RECLAIM_PAGES,
+        SBI_COVH_RUN_TVM_VCPU, SBI_COVI_CONVERT_AIA_IMSIC, SBI_COVI_EXT_ID,
+        SBI_COVI_RECLAIM_AIA_IMSIC, SBI_EXT_SUPD_ACCEPT_TRUST, SBI_EXT_SUPD_DERIVE_KEY,
+        SBI_EXT_SUPD_FREE_COUNTER, SBI_EXT_SUPD_GET_ACCESS_FAULTS, SBI_EXT_SUPD_GET_ACTIVE_DOMAINS,
+        SBI_EXT_SUPD_GET_AUDIT_MEASUREMENT, SBI_EXT_SUPD_GET_DOMAIN_INFO,
+        SBI_EXT_SUPD_GET_DOMAIN_STATS, SBI_EXT_SUPD_GET_HART_ID, SBI_EXT_SUPD_GET_HEAP_STAT,
+        SBI_EXT_SUPD_GET_RANDOM, SBI_EXT_SUPD_GET_TIME, SBI_EXT_SUPD_GRANT_DMA_REGION,
+        SBI_EXT_SUPD_INCREMENT_COUNTER, SBI_EXT_SUPD_INJECT_FAULT, SBI_EXT_SUPD_LOAD_BLOB,
+        SBI_EXT_SUPD_OFFER_TRUST, SBI_EXT_SUPD_READ_AUDIT_LOG, SBI_EXT_SUPD_READ_COUNTER,
+        SBI_EXT_SUPD_READ_TRACE, SBI_EXT_SUPD_RESTORE_DOMAIN, SBI_EXT_SUPD_REVOKE_DMA_REGION,
+        SBI_EXT_SUPD_REVOKE_TRUST, SBI_EXT_SUPD_SELF_TEST, SBI_EXT_SUPD_SNAPSHOT_DOMAIN,
+        SBI_EXT_SUPD_STORE_BLOB, SBI_EXT_SUPD_TSM_INFO_CHANGED, SBI_PROBE_AVAILABLE,
+        SBI_SUPD_EXT_ID, SBI_SUPD_PROBE_AUDIT_LOG, SBI_SUPD_PROBE_DMA, SBI_SUPD_PROBE_RANDOM,
+        SBI_SUPD_PROBE_STORAGE, SUPD_BLOB_ID_SIZE, SUPD_BLOB_MAX_SIZE, SUPD_COUNTER_ID_SIZE,
+        SUPD_FAULT_CORRUPT_TEERET, SUPD_FAULT_DROP_TEERET, SUPD_FAULT_PMP_FAILURE,
+        SUPD_FAULT_SPURIOUS_TEECALL, SUPD_TRACE_SUPD_CALL, SUPD_TRACE_TEECALL, SUPD_TRACE_TEERET,
+        TSM_STATUS_FAULTED, TSM_STATUS_LOADED,
+    },
+};
 
-use common::sbi::{
-    COVH_DEFAULT_PAGE_SIZE, SBI_COVH_CONVERT_PAGES, SBI_COVH_EXT_ID, SBI_COVH_GET_TSM_INFO,
-    SBI_COVH_RECLAIM_PAGES, SBI_EXT_SUPD_GET_ACTIVE_DOMAINS, SBI_SUPD_EXT_ID,
+use cove_core::{
+    activation::Transition, error::ActivationError, gstage, platform::Pmp, pmp,
+    tsm_info::TsmInfoCache,
 };
+use riscv::register::{mhartid, misa};
 
-use crate::{_tee_stack_top, context::Context, domain::MemoryRegion, opensbi, state::STATE};
+use crate::{
+    audit::{AuditEvent, AuditRecord, AUDIT_MEASUREMENT_SIZE},
+    call_buffers,
+    context::Context,
+    context_switch::{cove_entry, restore_h_csrs},
+    crash,
+    dispatch::{
+        sbi_extension, Capability, SBI_ERR_DENIED, SBI_ERR_FAILED, SBI_ERR_INVALID_PARAM,
+        SBI_ERR_NOT_READY, SBI_ERR_NOT_SUPPORTED, SBI_ERR_TIMEOUT,
+    },
+    domain::{
+        napot_split, MemoryRegion, RegionTag, MAX_MEMORY_REGIONS, MEMREGION_RW, MEMREGION_RWX,
+    },
+    error::CallError,
+    inject, interrupts,
+    iopmp::DmaGrant,
+    platform::FIRMWARE_MEMORY,
+    runtime::{self, TrapContext, TrapRegs},
+    scheduler::read_mtime,
+    state::{State, STATE},
+    stats,
+    tee::TeeStackCheck,
+    trace,
+};
 
-macro_rules! cove_unpack_fid {
-    ($fid:expr) => {
-        (($fid >> 26) & 0x3F, $fid & 0xFFFF)
-    };
-}
+cove_entry!(tee_handler_entry, SBI_COVH_EXT_ID, covh_handler);
+cove_entry!(covi_handler_entry, SBI_COVI_EXT_ID, covh_handler);
 
-// 8K scratch memory
-pub const TEE_SCRATCH_SIZE: usize = 0x2000;
-
-#[unsafe(naked)]
-pub fn tee_handler_entry() -> ! {
-    core::arch::naked_asm!(
-    // calculate new stack pointer for tee handling. To do so, we use the mscratch and adapt to
-    // the opensbi scartch memory layout.
-    // This block needs:
-    // - a7 as base pointer as we assume it as CoVE ID
-    // - t0 as arithemtic register to calculate the offset
-    "
-        la a7, {tee_stack}
-        li t0, {scratch_size}
-        add t0, t0, {context_size}
-        sub a7, a7, t0
-        sd sp, 8*2(a7)
-        add sp, a7, zero
-        // restore a7 and t0 and swap back the mscratch
-        la a7, {covh_ext_id}
-        ld t0, {sbi_scratch_tmp0_offset}(tp)
-        csrrw tp, mscratch, tp
-    ",
-    // save gprs
-    "
-        sd x0, 8 * 0 (sp)
-        sd x1, 8 * 1 (sp)
-        sd x3, 8 * 3 (sp)
-        sd x4, 8 * 4 (sp)
-        sd x5, 8 * 5 (sp)
-        sd x6, 8 * 6 (sp)
-        sd x7, 8 * 7 (sp)
-        sd x8, 8 * 8 (sp)
-        sd x9, 8 * 9 (sp)
-        sd x10, 8 * 10 (sp)
-        sd x11, 8 * 11 (sp)
-        sd x12, 8 * 12 (sp)
-        sd x13, 8 * 13 (sp)
-        sd x14, 8 * 14 (sp)
-        sd x15, 8 * 15 (sp)
-        sd x16, 8 * 16 (sp)
-        sd x17, 8 * 17 (sp)
-        sd x18, 8 * 18 (sp)
-        sd x19, 8 * 19 (sp)
-        sd x20, 8 * 20 (sp)
-        sd x21, 8 * 21 (sp)
-        sd x22, 8 * 22 (sp)
-        sd x23, 8 * 23 (sp)
-        sd x24, 8 * 24 (sp)
-        sd x25, 8 * 25 (sp)
-        sd x26, 8 * 26 (sp)
-        sd x27, 8 * 27 (sp)
-        sd x28, 8 * 28 (sp)
-        sd x29, 8 * 29 (sp)
-        sd x30, 8 * 30 (sp)
-        sd x31, 8 * 31 (sp)
-    ",
-    // save csrs
-    "
-        csrr t0, sstatus
-        sd t0, 32*8(sp)
-        csrr t0, stvec
-        sd t0, 33*8(sp)
-        csrr t0, sip
-        sd t0, 34*8(sp)
-        csrr  t0,scounteren
-        sd t0, 35*8(sp)
-        csrr  t0, sscratch
-        sd t0, 36*8(sp)
-        csrr t0, satp
-        sd t0, 37*8(sp)
-        //csrr t0,senvcfg
-        //sd t0, 38*8(sp)
-        // sd t0, 39*8(sp)
-        // csrr scontext, t0
-        csrr t0, mepc
-        sd t0, 40*8(sp)
-    ",
-    "
-        // call tee handler
-        la sp, {tee_stack}
-        add a0, a6, zero
-        call {tee_handler}
-
-        // restore the target supervisor domain
-        add sp, a0, zero
-        j {tee_handler_exit}
-        ",
-        tee_stack = sym _tee_stack_top,
-        covh_ext_id = const SBI_COVH_EXT_ID,
-        context_size= const size_of::<Context>(),
-        scratch_size = const TEE_SCRATCH_SIZE,
-        sbi_scratch_tmp0_offset = const offset_of!(opensbi::sbi_scratch, tmp0),
-        tee_handler = sym covh_handler,
-        tee_handler_exit = sym tee_handler_exit
-    )
-}
-
-/// Handle the CoVH call:
+/// Handle the CoVH and CoVI calls:
 /// - Unlock the state;
-/// - Find out if it is a TEECALL or a TEERET
+/// - Find out if it is a TEECALL or a TEERET (see `activation.rs`)
 /// - Find the destination context address
 /// - Return the destination address
 ///
-/// The `domain.active` field of a TSM encodes the src supervisor domain which must be preserved by
-/// the TSM in a TEERET.
+/// a6 bits [31:26] of a callee hold its caller, which must be preserved by the callee in a TEERET.
 #[no_mangle]
 #[inline(never)]
 extern "C" fn covh_handler(fid: usize) -> usize {
+    // dropped last, after the state is unlocked
+    let _stack_check = TeeStackCheck::enter();
     // unlock the state
     let mut guard = STATE.lock();
     let state = guard.get_mut().unwrap();
 
-    let (dst_id, fid) = cove_unpack_fid!(fid);
-    let domain = state.domains.get_mut(dst_id);
+    // A TEERET goes back to the caller of the innermost call
+    let src_id = state.active_domain;
+    let (dst_id, call_fid) = cove_unpack_fid(fid);
+    let returning = state
+        .calls
+        .calls()
+        .last()
+        .is_some_and(|call| (call.callee, call.caller) == (src_id, dst_id));
+    let base_ctx = state.tee.scratch_context();
+    let eid = unsafe { (*(base_ctx as *const Context)).regs[17] };
+    let next_ctx = cove_call(state, fid);
+
+    // A TEECALL which switched succeeded, the other calls resume with their result in a0
+    let (event, error) = match (returning, next_ctx != base_ctx) {
+        (false, true) => (SUPD_TRACE_TEECALL, 0),
+        (false, false) => (SUPD_TRACE_TEECALL, unsafe { context_a0(next_ctx) }),
+        (true, _) => (SUPD_TRACE_TEERET, unsafe { context_a0(next_ctx) }),
+    };
+    trace::record(event, src_id, dst_id, eid, call_fid, error);
+    next_ctx
+}
+
+/// a0 of the context at `ctx_addr`, as an SBI error
+unsafe fn context_a0(ctx_addr: usize) -> isize {
+    (*(ctx_addr as *const Context)).regs[10] as isize
+}
+
+/// TEECALL or TEERET `fid` of the active domain, returns the context to resume.
+fn cove_call(state: &mut State, fid: usize) -> usize {
+    let (dst_id, fid) = cove_unpack_fid(fid);
 
     // Scratch space
-    let scratch_start = &raw const _tee_stack_top as *const u8 as usize;
-    let base_ctx = scratch_start - (TEE_SCRATCH_SIZE + size_of::<Context>());
+    let tee = state.tee;
+    let base_ctx = tee.scratch_context();
     let scratch_ctx = base_ctx as *mut Context;
+    // The entry restores a7 with the extension id before saving the context
+    let eid = unsafe { (*scratch_ctx).regs[17] };
+    let src_id = state.active_domain;
+    crash::record_call(src_id, eid, fid);
+    let imsic = state.imsic;
+    // SBI policy of the caller, enforced on TEECALL. A queue is allowed if each of its calls is.
+    let allowed = state.allows_call(eid, fid)
+        && ((eid, fid) != (SBI_COVH_EXT_ID, SBI_COVH_PROCESS_QUEUE) || {
+            let addr = unsafe { (*scratch_ctx).regs[10] };
+            let count = unsafe { (*scratch_ctx).regs[11] };
+            queue_allowed(state, src_id, addr, count)
+        });
+    // Pages shared with a TVM (a1, a3 pages) must belong to the caller
+    let shares_own_pages = (eid, fid) != (SBI_COVH_EXT_ID, SBI_COVH_ADD_TVM_SHARED_PAGES) || {
+        let base_addr = unsafe { (*scratch_ctx).regs[11] };
+        let num_pages = unsafe { (*scratch_ctx).regs[13] };
+        num_pages
+            .checked_mul(COVH_DEFAULT_PAGE_SIZE)
+            .zip(state.domains.get(src_id))
+            .is_some_and(|(size, caller)| caller.owns(base_addr, size))
+    };
+    // Buffer of GET_TSM_INFO (a0, a1), filled by the TSM-driver if the TSM is faulted or not ready
+    let tsm_info_buffer = {
+        let base_addr = unsafe { (*scratch_ctx).regs[10] };
+        let size = unsafe { (*scratch_ctx).regs[11] };
+        let owned = state
+            .domains
+            .get(src_id)
+            .is_some_and(|caller| caller.owns(base_addr, size));
+        ((eid, fid) == (SBI_COVH_EXT_ID, SBI_COVH_GET_TSM_INFO) && owned)
+            .then_some((base_addr, size))
+    };
 
-    // Invalid domain id, go back with an error
-    if domain.is_none() {
-        return unsafe { return_error(base_ctx, -1) };
+    let mut transition = state.calls.transition(&state.domains, src_id, dst_id);
+    if let Ok(Transition::Return(_)) = transition {
+        // Injected faults: the callee is resumed at its TEERET, or its TEERET names another caller
+        if inject::active(SUPD_FAULT_DROP_TEERET) {
+            return base_ctx;
+        }
+        if inject::take(SUPD_FAULT_CORRUPT_TEERET) {
+            let wrong = (0..state.domains.len()).find(|&id| id != src_id && id != dst_id);
+            let wrong = wrong.unwrap_or(state.domains.len());
+            transition = state.calls.transition(&state.domains, src_id, wrong);
+            inject::record(
+                transition
+                    .as_ref()
+                    .map_or_else(activation_error_code, |_| 0),
+            );
+        }
     }
 
+    // Invalid transition (unknown or busy domain, untrusted caller...), go back with an error
+    let transition = match transition {
+        Ok(transition) => transition,
+        Err(e) => {
+            debug!(
+                "domain {} CoVE call {:#x}/{} rejected: {}",
+                src_id, eid, fid, e
+            );
+            return unsafe { return_error(base_ctx, activation_error_code(&e)) };
+        }
+    };
+
     // Get destination domain
-    let domain = domain.unwrap();
+    let domain = &mut state.domains[dst_id];
 
     // TEECALL
-    if domain.has_tsm {
+    if let Transition::Call(_) = transition {
         let domain_ctx = domain.context_addr as *mut Context;
-        // TODO: get current domain
-        let src_id = 2;
-        // check if the domain is trusted. If not just return an error to the caller
-        if !domain.is_trusted(src_id) {
-            return unsafe { return_error(base_ctx, -1) };
+        if !allowed {
+            state
+                .audit
+                .record(AuditEvent::PolicyDenied, src_id, eid, fid);
+            return unsafe { return_error(base_ctx, SBI_ERR_DENIED) };
         }
-        // We need to store the calling context into the right structure
-        let caller_ctx_addr = base_ctx - (src_id) * size_of::<Context>();
+        if domain.faulted {
+            return unsafe {
+                unavailable_tsm_call(
+                    base_ctx,
+                    tsm_info_buffer,
+                    TSM_STATUS_FAULTED,
+                    SBI_ERR_FAILED,
+                )
+            };
+        }
+        if !domain.tsm_ready {
+            return unsafe {
+                unavailable_tsm_call(
+                    base_ctx,
+                    tsm_info_buffer,
+                    TSM_STATUS_LOADED,
+                    SBI_ERR_NOT_READY,
+                )
+            };
+        }
+        // A ready TSM answers GET_TSM_INFO as it did last time, until it reports a change: the
+        // TSM-driver answers itself, in memory of the caller the TSM could write
+        let cached = tsm_info_buffer
+            .filter(|&(base_addr, size)| !state.is_confidential(base_addr, size))
+            .and_then(|(base_addr, size)| {
+                let cache = state.domains[dst_id].tsm_info.as_ref()?;
+                Some((base_addr, cache.answer(base_addr, size)?))
+            });
+        if let Some((base_addr, answer)) = cached {
+            return unsafe { cached_tsm_info(base_ctx, base_addr, answer) };
+        }
+        // We need to store the calling context into the right structure. A callee calling further
+        // resumes from it, its entry context is kept aside until its TEERET.
+        let caller_ctx_addr = tee.context(src_id);
         let caller_ctx = caller_ctx_addr as *mut Context;
+        state.calls.save_entry(src_id, caller_ctx_addr);
         unsafe {
             core::ptr::copy_nonoverlapping(scratch_ctx, caller_ctx, 1);
         }
@@ -184,292 +256,933 @@ extern "C" fn covh_handler(fid: usize) -> usize {
             // Save the caller id into a6 register, but we must preserve the EID. This is used for
             // the TEERET
             // The caller id must be saved in bits [31:26]
-            let eid = (*domain_ctx).regs[16] & 0xFFFF;
-            (*domain_ctx).regs[16] = ((src_id) << 26) | eid;
+            let (_, eid) = cove_unpack_fid((*domain_ctx).regs[16]);
+            (*domain_ctx).regs[16] = cove_pack_fid(src_id, eid);
 
             // save the caller context address into domain context
             (*domain_ctx).caller_ctx = caller_ctx_addr;
         }
 
+        // The host buffers of the call reach the TSM until its TEERET, see `call_buffers.rs`
+        if eid == SBI_COVH_EXT_ID {
+            let args = unsafe { core::array::from_fn(|i| (*domain_ctx).regs[10 + i]) };
+            if let Err(e) = call_buffers::grant(&mut state.domains, dst_id, src_id, fid, &args) {
+                debug!("domain {} CoVH call {} rejected: {}", src_id, fid, e);
+                return unsafe { return_error(base_ctx, SBI_ERR_INVALID_PARAM) };
+            }
+            state.tlb.call_entered(fid, &args, mhartid::read());
+        }
+
         // Perform operations to allow the specific functionality
-        match fid {
-            // For sbi_covh_get_domain_info we need to give the TSM access to the memory space
-            // where he will write the domain_info struct (a0) for the necessary size (a1).
-            SBI_COVH_GET_TSM_INFO => {
+        match (eid, fid) {
+            (SBI_COVH_EXT_ID, SBI_COVH_CONVERT_PAGES) => {
                 let base_addr = unsafe { (*domain_ctx).regs[10] };
-                let size = unsafe { (*domain_ctx).regs[11] };
+                let num_pages = unsafe { (*domain_ctx).regs[11] };
 
-                // Base address must be page aligned, we cannot exceed number of available pmp
-                // registers
-                assert!(base_addr % COVH_DEFAULT_PAGE_SIZE == 0);
-                assert!(domain.memory_regions.len() < 8);
+                // Page aligned, within the memory of the caller: a range which is not a NAPOT
+                // region takes one PMP entry per NAPOT block (a 2 MiB aligned bulk takes one)
+                let Some(size) = num_pages
+                    .checked_mul(COVH_DEFAULT_PAGE_SIZE)
+                    .filter(|&size| size != 0 && base_addr.checked_add(size).is_some())
+                    .filter(|_| base_addr % COVH_DEFAULT_PAGE_SIZE == 0)
+                else {
+                    return unsafe { return_error(base_ctx, SBI_ERR_INVALID_PARAM) };
+                };
+                if !state.domains[src_id].owns(base_addr, size) {
+                    debug!(
+                        "domain {} converts {:#x}..{:#x}: not its memory",
+                        src_id,
+                        base_addr,
+                        base_addr + size
+                    );
+                    return unsafe { return_error(base_ctx, SBI_ERR_INVALID_PARAM) };
+                }
+                let domain = &mut state.domains[dst_id];
+                let needed = napot_split(base_addr, size).count();
+                let free = MAX_MEMORY_REGIONS.saturating_sub(domain.memory_regions.len());
+                if needed > free {
+                    debug!(
+                        "domain {} converts {:#x}..{:#x}: {} PMP entries needed, {} free",
+                        src_id,
+                        base_addr,
+                        base_addr + size,
+                        needed,
+                        free
+                    );
+                    return unsafe { return_error(base_ctx, SBI_ERR_INVALID_PARAM) };
+                }
 
-                let order = if (size & (size - 1)) == 0 {
-                    size.trailing_zeros()
-                } else {
-                    size.next_power_of_two().trailing_zeros()
+                // TVM code runs from confidential pages
+                for (base_addr, order) in napot_split(base_addr, size) {
+                    let region = MemoryRegion {
+                        base_addr,
+                        order,
+                        mmio: false,
+                        permissions: MEMREGION_RWX,
+                    };
+                    // Checked above
+                    domain.grant(region, RegionTag::Confidential).unwrap();
                 }
-                .max(3);
 
-                domain.memory_regions.push(MemoryRegion {
-                    base_addr,
-                    order,
-                    mmio: false,
-                    permissions: 0x3f,
-                });
+                // Confidential pages must not be reachable by DMA
+                if let Some(iopmp) = state.iopmp.as_mut() {
+                    let protected = napot_split(base_addr, size)
+                        .take_while(|&(base, order)| iopmp.protect(base, order).is_ok())
+                        .count();
+                    if protected < needed {
+                        // The entries just added, they are there
+                        let protected_size: usize = napot_split(base_addr, size)
+                            .take(protected)
+                            .map(|(_, order)| 1usize << order)
+                            .sum();
+                        if iopmp.unprotect_range(base_addr, protected_size).is_err() {
+                            debug!("cannot roll back the IOPMP entries of {:#x}", base_addr);
+                        }
+                        domain.revoke(RegionTag::Confidential, base_addr, size);
+                        return unsafe { return_error(base_ctx, SBI_ERR_FAILED) };
+                    }
+                }
+
+                state.track_borrow(src_id, base_addr, num_pages);
+                state.domains[src_id].stats.pages_converted += num_pages as u64;
+                state
+                    .audit
+                    .record(AuditEvent::PagesConverted, src_id, base_addr, num_pages);
             }
-            SBI_COVH_CONVERT_PAGES => {
-                let base_addr = unsafe { (*domain_ctx).regs[10] };
-                let num_pages = unsafe { (*domain_ctx).regs[11] };
 
-                // Base address must be page aligned, we cannot exceed number of available pmp
-                // registers
-                assert!(base_addr % COVH_DEFAULT_PAGE_SIZE == 0);
-                assert!(domain.memory_regions.len() < 8);
+            // The TSM maps the shared pages (a1) in the TVM: they stay accessible to the caller
+            (SBI_COVH_EXT_ID, SBI_COVH_ADD_TVM_SHARED_PAGES) => {
+                let base_addr = unsafe { (*domain_ctx).regs[11] };
+                let num_pages = unsafe { (*domain_ctx).regs[13] };
 
-                let order = (num_pages * COVH_DEFAULT_PAGE_SIZE).trailing_zeros();
+                // A single NAPOT region
+                let order = num_pages
+                    .checked_mul(COVH_DEFAULT_PAGE_SIZE)
+                    .and_then(|size| Region::new(base_addr, size))
+                    .and_then(|r| r.order());
+                let Some(order) = order else {
+                    return unsafe { return_error(base_ctx, SBI_ERR_INVALID_PARAM) };
+                };
+                if !shares_own_pages {
+                    return unsafe { return_error(base_ctx, -1) };
+                }
+                let size = 1usize << order;
 
-                domain.memory_regions.push(MemoryRegion {
+                let region = MemoryRegion {
                     base_addr,
                     order,
                     mmio: false,
-                    permissions: 0x3f,
-                });
-
-                state.track_borrow(src_id, base_addr, num_pages);
-
-                remove_region(domain, base_addr, num_pages);
+                    permissions: MEMREGION_RW,
+                };
+                let domain = &mut state.domains[dst_id];
+                if !domain.owns(base_addr, size) && domain.grant(region, RegionTag::Shared).is_err()
+                {
+                    return unsafe { return_error(base_ctx, -1) };
+                }
             }
 
-            SBI_COVH_RECLAIM_PAGES => {
+            (SBI_COVH_EXT_ID, SBI_COVH_RECLAIM_PAGES) => {
                 let base_addr = unsafe { (*domain_ctx).regs[10] };
                 let num_pages = unsafe { (*domain_ctx).regs[11] };
-                match state.reclaim(src_id, base_addr, num_pages) {
-                    Ok(_) => {}
-                    Err(e) => panic!("memory stealing detected"),
+                // Only pages the caller converted, which makes the size below valid
+                if let Err(e) = state.reclaim(src_id, base_addr, num_pages) {
+                    debug!("domain {} reclaims {:#x}: {}", src_id, base_addr, e);
+                    return unsafe { return_error(base_ctx, SBI_ERR_INVALID_PARAM) };
                 }
-                // Remove the pages from the trusted domain
-                let domain = state.domains.get_mut(1).unwrap();
-                remove_region(domain, base_addr, num_pages);
+                if let Some(iopmp) = state.iopmp.as_mut() {
+                    let size = num_pages * COVH_DEFAULT_PAGE_SIZE;
+                    if let Err(e) = iopmp.unprotect_range(base_addr, size) {
+                        debug!("domain {} reclaims {:#x}: {}", src_id, base_addr, e);
+                        state.track_borrow(src_id, base_addr, num_pages);
+                        return unsafe { return_error(base_ctx, SBI_ERR_FAILED) };
+                    }
+                }
+                state
+                    .audit
+                    .record(AuditEvent::PagesReclaimed, src_id, base_addr, num_pages);
+                state.domains[src_id].stats.pages_reclaimed += num_pages as u64;
+                // Revoke the pages from the trusted domain
+                state.domains[dst_id].revoke(
+                    RegionTag::Confidential,
+                    base_addr,
+                    num_pages * COVH_DEFAULT_PAGE_SIZE,
+                );
+            }
+
+            // The TSM needs to write the guest interrupt file (a0) to bind it to a TVM vCPU
+            (SBI_COVI_EXT_ID, SBI_COVI_CONVERT_AIA_IMSIC) => {
+                let imsic_addr = unsafe { (*domain_ctx).regs[10] };
+                if !is_guest_interrupt_file(imsic, imsic_addr) {
+                    return unsafe { return_error(base_ctx, -1) };
+                }
+                let region = MemoryRegion {
+                    base_addr: imsic_addr,
+                    order: COVH_DEFAULT_PAGE_SIZE.trailing_zeros(),
+                    mmio: true,
+                    permissions: MEMREGION_RW,
+                };
+                if state.domains[dst_id]
+                    .grant(region, RegionTag::InterruptFile)
+                    .is_err()
+                {
+                    return unsafe { return_error(base_ctx, -1) };
+                }
+            }
+
+            (SBI_COVI_EXT_ID, SBI_COVI_RECLAIM_AIA_IMSIC) => {
+                let imsic_addr = unsafe { (*domain_ctx).regs[10] };
+                if !is_guest_interrupt_file(imsic, imsic_addr) {
+                    return unsafe { return_error(base_ctx, -1) };
+                }
+                state.domains[dst_id].revoke(
+                    RegionTag::InterruptFile,
+                    imsic_addr,
+                    COVH_DEFAULT_PAGE_SIZE,
+                );
             }
             _ => {}
         }
-        unsafe {
-            let ret = opensbi::sbi_domain_change_active(dst_id as u32);
-            assert!(ret == 0);
+        // The TVM runs inside RUN_TVM_VCPU, which has no time limit. The nested calls count in the
+        // budget of the first one.
+        if state.calls.depth() == 0 && (eid, fid) != (SBI_COVH_EXT_ID, SBI_COVH_RUN_TVM_VCPU) {
+            state.watchdog.arm(src_id, dst_id, eid, fid);
+        }
+        // The interrupts of the caller wait for its TEERET
+        unsafe { interrupts::hold(&mut *caller_ctx) };
+        let context_addr = state.domains[dst_id].context_addr;
+        if !switch_domain(state, transition) {
+            return pmp_failure(state);
+        }
+        // Injected fault: the same TEECALL again, while it is outstanding
+        if inject::take(SUPD_FAULT_SPURIOUS_TEECALL) {
+            let replay = state.calls.transition(&state.domains, src_id, dst_id);
+            inject::record(replay.as_ref().map_or_else(activation_error_code, |_| 0));
         }
-        program_pmp_from_regions(&domain.memory_regions);
-        return domain.context_addr;
+        return context_addr;
     }
 
     // TEERET
     // We don't need to store the calling context since we are implementing the
     // non reentrant TSM. We need a0 and a1 registers to deliver the result
+    if state.calls.depth() == 1 {
+        state.watchdog.disarm();
+    }
 
-    // Restore the original TSM id
-    // TODO make this dynamic
-    let tsmid = 1;
-
+    // The caller context is left untouched if the caller cannot run
+    if !switch_domain(state, transition) {
+        return pmp_failure(state);
+    }
+    let caller_ctx_addr = tee.context(dst_id);
     unsafe {
-        let domain_ctx = domain.context_addr as *mut Context;
-        let eid = (*scratch_ctx).regs[16] & 0xFFFF;
-        (*domain_ctx).regs[10] = (*scratch_ctx).regs[10];
-        (*domain_ctx).regs[11] = (*scratch_ctx).regs[11];
-        (*domain_ctx).regs[16] = (tsmid << 26) | eid;
+        let caller_ctx = caller_ctx_addr as *mut Context;
+        // The caller context still holds the arguments of the call, see `tlb.rs`
+        if (*caller_ctx).regs[17] == SBI_COVH_EXT_ID {
+            let (_, call_fid) = cove_unpack_fid((*caller_ctx).regs[16]);
+            let args = core::array::from_fn(|i| (*caller_ctx).regs[10 + i]);
+            let ret = SbiRet {
+                a0: (*scratch_ctx).regs[10] as isize,
+                a1: (*scratch_ctx).regs[11] as isize,
+            };
+            state
+                .tlb
+                .call_returned(call_fid, &args, &ret, mhartid::read());
+            // Kept for the next GET_TSM_INFO, the caller buffer holds the answer of the TSM
+            let [base_addr, size, ..] = args;
+            if (call_fid, ret.a0) == (SBI_COVH_GET_TSM_INFO, 0)
+                && ret.a1 as usize <= size
+                && state.domains[dst_id].owns(base_addr, size)
+            {
+                let answer = core::slice::from_raw_parts(base_addr as *const u8, ret.a1 as usize);
+                state.domains[src_id].tsm_info = TsmInfoCache::new(answer, size);
+            }
+        }
+        let (_, eid) = cove_unpack_fid((*scratch_ctx).regs[16]);
+        (*caller_ctx).regs[10] = (*scratch_ctx).regs[10];
+        (*caller_ctx).regs[11] = (*scratch_ctx).regs[11];
+        (*caller_ctx).regs[16] = cove_pack_fid(src_id, eid);
         // increment mepc to avoid loop
-        (*domain_ctx).mepc += 4;
+        (*caller_ctx).mepc += 4;
+        interrupts::replay(&mut *caller_ctx);
     }
+    return caller_ctx_addr;
+}
 
-    // Perform operations to cleanup specific to the functionality
-    match fid {
-        // Remove the last memory region
-        SBI_COVH_GET_TSM_INFO => {}
-        SBI_COVH_CONVERT_PAGES => {}
-        _ => {}
-    }
-    unsafe {
-        let ret = opensbi::sbi_domain_change_active(dst_id as u32);
-        assert!(ret == 0);
+/// Apply the TEECALL or TEERET `transition` and give the PMP to the domain which runs next.
+/// Returns whether the PMP reads back as programmed.
+fn switch_domain(state: &mut State, transition: Transition) -> bool {
+    stats::count_switch(&mut state.domains, state.active_domain, &transition);
+    if let Transition::Return(_) = transition {
+        call_buffers::release(&mut state.domains[state.active_domain]);
     }
+    let next = state.calls.apply(&mut state.domains, transition);
+    activate_domain(state, next);
+    pmp_programmed(&state.domains[next].memory_regions)
+}
+
+/// Make `id` the domain running on the hart: the runtime, the PMP and the delegation of the guest
+/// ecalls follow it.
+fn activate_domain(state: &mut State, id: usize) {
+    runtime::change_active_domain(id, &state.domains[id].memory_regions);
+    state.active_domain = id;
+    delegate_guest_calls(state.domains[id].has_tsm);
+    program_domain_pmp(state, id);
+}
+
+/// Program the PMP with the regions of the domain `id`, which runs next.
+fn program_domain_pmp(state: &mut State, id: usize) {
+    let domain = &mut state.domains[id];
+    domain.stats.pmp_reprograms += 1;
     program_pmp_from_regions(&domain.memory_regions);
-    return domain.context_addr;
-}
-
-#[unsafe(naked)]
-pub fn supd_handler_entry() -> ! {
-    core::arch::naked_asm!(
-    "
-        la a7, {tee_stack}
-        li t0, {scratch_size}
-        add t0, t0, {context_size}
-        sub a7, a7, t0
-        sd sp, 8*2(a7)
-        add sp, a7, zero
-        // restore a7 and t0 and swap back the mscratch
-        la a7, {supd_ext_id}
-        ld t0, {sbi_scratch_tmp0_offset}(tp)
-        csrrw tp, mscratch, tp
-    ",
-    // save gprs
-    "
-        sd x0, 8 * 0 (sp)
-        sd x1, 8 * 1 (sp)
-        sd x3, 8 * 3 (sp)
-        sd x4, 8 * 4 (sp)
-        sd x5, 8 * 5 (sp)
-        sd x6, 8 * 6 (sp)
-        sd x7, 8 * 7 (sp)
-        sd x8, 8 * 8 (sp)
-        sd x9, 8 * 9 (sp)
-        sd x10, 8 * 10 (sp)
-        sd x11, 8 * 11 (sp)
-        sd x12, 8 * 12 (sp)
-        sd x13, 8 * 13 (sp)
-        sd x14, 8 * 14 (sp)
-        sd x15, 8 * 15 (sp)
-        sd x16, 8 * 16 (sp)
-        sd x17, 8 * 17 (sp)
-        sd x18, 8 * 18 (sp)
-        sd x19, 8 * 19 (sp)
-        sd x20, 8 * 20 (sp)
-        sd x21, 8 * 21 (sp)
-        sd x22, 8 * 22 (sp)
-        sd x23, 8 * 23 (sp)
-        sd x24, 8 * 24 (sp)
-        sd x25, 8 * 25 (sp)
-        sd x26, 8 * 26 (sp)
-        sd x27, 8 * 27 (sp)
-        sd x28, 8 * 28 (sp)
-        sd x29, 8 * 29 (sp)
-        sd x30, 8 * 30 (sp)
-        sd x31, 8 * 31 (sp)
-    ",
-    // save csrs
-    "
-        csrr t0, sstatus
-        sd t0, 32*8(sp)
-        csrr t0, stvec
-        sd t0, 33*8(sp)
-        csrr t0, sip
-        sd t0, 34*8(sp)
-        csrr  t0,scounteren
-        sd t0, 35*8(sp)
-        csrr  t0, sscratch
-        sd t0, 36*8(sp)
-        csrr t0, satp
-        sd t0, 37*8(sp)
-        //csrr t0,senvcfg
-        //sd t0, 38*8(sp)
-        // sd t0, 39*8(sp)
-        // csrr scontext, t0
-        csrr t0, mepc
-        sd t0, 40*8(sp)
-    ",
-    "
-        la sp, {tee_stack}
-        add a0, a6, zero
-        call {handler}
-
-        add sp, a0, zero
-        j {tee_handler_exit}
-    ",
-        tee_stack = sym _tee_stack_top,
-        supd_ext_id = const SBI_SUPD_EXT_ID,
-        context_size= const size_of::<Context>(),
-        scratch_size = const TEE_SCRATCH_SIZE,
-        sbi_scratch_tmp0_offset = const offset_of!(opensbi::sbi_scratch, tmp0),
-        handler = sym supd_handler,
-        tee_handler_exit = sym tee_handler_exit
-    )
 }
 
+/// The PMP of the domain switched to did not read back as programmed, it must not run. The calls
+/// are unwound and the first caller gets SBI_ERR_FAILED. Returns the context to restore.
+fn pmp_failure(state: &mut State) -> usize {
+    let failed = state.active_domain;
+    debug!("cannot program the PMP of domain {}", failed);
+    state.audit.record(AuditEvent::PmpFailure, failed, 0, 0);
+    state.watchdog.disarm();
+    state.domains.iter_mut().for_each(call_buffers::release);
+    let caller = state
+        .calls
+        .unwind(&mut state.domains)
+        .map_or(failed, |call| call.caller);
+    activate_domain(state, caller);
+    if !pmp_programmed(&state.domains[caller].memory_regions) {
+        panic!("cannot program the PMP of domain {caller}");
+    }
+    inject::record(SBI_ERR_FAILED);
+    let caller_ctx = state.tee.context(caller);
+    unsafe { interrupts::replay(&mut *(caller_ctx as *mut Context)) };
+    unsafe { return_error(caller_ctx, SBI_ERR_FAILED) }
+}
+
+/// SBI error of a CoVE call rejected by the activation state machine
+fn activation_error_code(e: &ActivationError) -> isize {
+    match e {
+        ActivationError::UnknownDomain(_) => SBI_ERR_INVALID_PARAM,
+        _ => SBI_ERR_FAILED,
+    }
+}
+
+cove_entry!(supd_handler_entry, SBI_SUPD_EXT_ID, supd_handler);
+
 fn supd_handler(fid: usize) -> usize {
+    let _stack_check = TeeStackCheck::enter();
     let mut guard = STATE.lock();
     let state = guard.get_mut().unwrap();
-    let scratch_addr = &raw const _tee_stack_top as *const u8 as usize;
-    let dst_addr = scratch_addr - (TEE_SCRATCH_SIZE + size_of::<Context>());
+    let dst_addr = state.tee.scratch_context();
     let dst_ctx = dst_addr as *mut Context;
+    let src_id = state.active_domain;
+    crash::record_call(src_id, SBI_SUPD_EXT_ID, fid);
 
-    if fid == SBI_EXT_SUPD_GET_ACTIVE_DOMAINS {
-        // root supervisor domain is mandatory
-        let mut ret: usize = 1;
-        for i in 0..state.domains.len() {
-            ret |= 1 << i;
-        }
-
-        unsafe {
+    let args = unsafe { core::array::from_fn(|i| (*dst_ctx).regs[10 + i]) };
+    let result = supd(fid, state, &args);
+    let error = result.err().unwrap_or(0);
+    trace::record(
+        SUPD_TRACE_SUPD_CALL,
+        src_id,
+        src_id,
+        SBI_SUPD_EXT_ID,
+        fid,
+        error,
+    );
+    match result {
+        Ok(value) => unsafe {
             (*dst_ctx).regs[10] = 0;
-            (*dst_ctx).regs[11] = ret;
+            (*dst_ctx).regs[11] = value;
             (*dst_ctx).mepc += 4;
-            return dst_addr;
+            dst_addr
+        },
+        Err(code) => unsafe { return_error(dst_addr, code) },
+    }
+}
+
+sbi_extension! {
+    /// SUPD functions handled by the firmware. The result is returned in a1.
+    fn supd(state: &mut State) for SBI_SUPD_EXT_ID {
+        SBI_EXT_SUPD_GET_ACTIVE_DOMAINS => get_active_domains(word),
+        SBI_EXT_SUPD_GRANT_DMA_REGION => grant_dma_region(base_addr, size) requires Iopmp,
+        SBI_EXT_SUPD_REVOKE_DMA_REGION => revoke_dma_region(base_addr, size) requires Iopmp,
+        SBI_EXT_SUPD_GET_RANDOM => get_random() requires Rng,
+        SBI_EXT_SUPD_READ_COUNTER => read_counter(id) requires Tsm,
+        SBI_EXT_SUPD_INCREMENT_COUNTER => increment_counter(id) requires Tsm,
+        SBI_EXT_SUPD_FREE_COUNTER => free_counter(id) requires Tsm,
+        SBI_EXT_SUPD_GET_TIME => get_time(),
+        SBI_EXT_SUPD_GET_HART_ID => get_hart_id(),
+        SBI_EXT_SUPD_READ_AUDIT_LOG => read_audit_log(buf, size, first_seq) requires AuditLog,
+        SBI_EXT_SUPD_GET_AUDIT_MEASUREMENT => get_audit_measurement(buf, size) requires AuditLog,
+        SBI_EXT_SUPD_GET_ACCESS_FAULTS => get_access_faults(domain_id),
+        SBI_EXT_SUPD_GET_HEAP_STAT => get_heap_stat(index),
+        SBI_EXT_SUPD_STORE_BLOB => store_blob(id, buf, size) requires Storage,
+        SBI_EXT_SUPD_LOAD_BLOB => load_blob(id, buf, size) requires Storage,
+        SBI_EXT_SUPD_DERIVE_KEY => derive_key(label, label_size, key),
+        SBI_EXT_SUPD_SELF_TEST => self_test(),
+        SBI_EXT_SUPD_OFFER_TRUST => offer_trust(domain_id),
+        SBI_EXT_SUPD_ACCEPT_TRUST => accept_trust(domain_id),
+        SBI_EXT_SUPD_REVOKE_TRUST => revoke_trust(domain_id),
+        SBI_EXT_SUPD_SNAPSHOT_DOMAIN => snapshot_domain(ranges, count),
+        SBI_EXT_SUPD_RESTORE_DOMAIN => restore_domain(),
+        SBI_EXT_SUPD_GET_DOMAIN_INFO => get_domain_info(domain_id, buf, size),
+        SBI_EXT_SUPD_INJECT_FAULT => inject_fault(fault) requires FaultInjection,
+        SBI_EXT_SUPD_GET_DOMAIN_STATS => get_domain_stats(domain_id, buf, size),
+        SBI_EXT_SUPD_TSM_INFO_CHANGED => tsm_info_changed(),
+        SBI_EXT_SUPD_READ_TRACE => read_trace(hartid, buf, size, first_seq) requires Trace,
+    }
+}
+
+// Word `word` of the bitmap of the active domains: bit i is domain `word * usize::BITS + i`. The
+// domain ids are contiguous and the root domain is always active.
+fn get_active_domains(state: &mut State, word: usize) -> Result<usize, CallError> {
+    let first = word.saturating_mul(usize::BITS as usize);
+    let count = state.domains.len().max(1).saturating_sub(first);
+    Ok(match count {
+        0 => 0,
+        count if count >= usize::BITS as usize => usize::MAX,
+        count => (1 << count) - 1,
+    })
+}
+
+// Grant/revoke DMA access to the shared region [base_addr, base_addr + size). The size must be a
+// power of two and the base naturally aligned to it, and the region memory of the caller: neither
+// confidential, nor of the firmware, nor of another domain (the root domain never runs).
+fn dma_grant(state: &State, base_addr: usize, size: usize) -> Result<DmaGrant, CallError> {
+    let order = Region::new(base_addr, size)
+        .and_then(|r| r.order())
+        .filter(|_| size >= COVH_DEFAULT_PAGE_SIZE);
+    let Some(order) = order else {
+        return Err(CallError::InvalidDmaRegion { base_addr, size });
+    };
+
+    let caller = state.active_domain;
+    let end = base_addr.saturating_add(size);
+    let firmware = FIRMWARE_MEMORY
+        .iter()
+        .any(|&(base, fw_size)| base < end && base_addr < base.saturating_add(fw_size));
+    let foreign = state
+        .domains
+        .iter()
+        .enumerate()
+        .any(|(id, d)| id != 0 && id != caller && d.overlaps(base_addr, size));
+    if !state.domains[caller].owns(base_addr, size)
+        || firmware
+        || foreign
+        || state.is_confidential(base_addr, size)
+    {
+        return Err(CallError::ForeignDmaRegion {
+            base_addr,
+            size,
+            domain: caller,
+        });
+    }
+    Ok(DmaGrant { base_addr, order })
+}
+
+fn grant_dma_region(state: &mut State, base_addr: usize, size: usize) -> Result<usize, CallError> {
+    let grant = dma_grant(state, base_addr, size)?;
+    let iopmp = state.iopmp.as_mut().ok_or(CallError::NoIopmp)?;
+    iopmp.grant(grant)?;
+    Ok(0)
+}
+
+fn revoke_dma_region(state: &mut State, base_addr: usize, size: usize) -> Result<usize, CallError> {
+    let grant = dma_grant(state, base_addr, size)?;
+    let iopmp = state.iopmp.as_mut().ok_or(CallError::NoIopmp)?;
+    iopmp.revoke(grant)?;
+    Ok(0)
+}
+
+// 64 random bits
+fn get_random(state: &mut State) -> Result<usize, CallError> {
+    Ok(state.rng.as_mut().unwrap().next_u64()? as usize)
+}
+
+// Monotonic counters of the caller, keyed by the id at `id`, and trusted time
+fn read_counter(state: &mut State, id: usize) -> Result<usize, CallError> {
+    caller_buffer(state, id, SUPD_COUNTER_ID_SIZE)?;
+    let id = unsafe { core::slice::from_raw_parts(id as *const u8, SUPD_COUNTER_ID_SIZE) };
+    Ok(state.counters.read(state.active_domain, id) as usize)
+}
+
+fn increment_counter(state: &mut State, id: usize) -> Result<usize, CallError> {
+    caller_buffer(state, id, SUPD_COUNTER_ID_SIZE)?;
+    let id = unsafe { core::slice::from_raw_parts(id as *const u8, SUPD_COUNTER_ID_SIZE) };
+    let domain = state.active_domain;
+    Ok(state
+        .counters
+        .increment(domain, id, state.storage.as_mut())? as usize)
+}
+
+fn free_counter(state: &mut State, id: usize) -> Result<usize, CallError> {
+    caller_buffer(state, id, SUPD_COUNTER_ID_SIZE)?;
+    let id = unsafe { core::slice::from_raw_parts(id as *const u8, SUPD_COUNTER_ID_SIZE) };
+    let domain = state.active_domain;
+    state.counters.free(domain, id, state.storage.as_mut())?;
+    Ok(0)
+}
+
+fn get_time(_state: &mut State) -> Result<usize, CallError> {
+    Ok(read_mtime() as usize)
+}
+
+// Hart of the caller, e.g. for a TSM enforcing the affinity of the vCPUs
+fn get_hart_id(_state: &mut State) -> Result<usize, CallError> {
+    Ok(mhartid::read())
+}
+
+// Access faults (PMP violations) of a domain since boot
+fn get_access_faults(state: &mut State, domain_id: usize) -> Result<usize, CallError> {
+    let domain = state
+        .domains
+        .get(domain_id)
+        .ok_or(CallError::UnknownDomain(domain_id))?;
+    Ok(domain.access_faults)
+}
+
+// Copy the SupdDomainInfo of a domain to [buf, buf + size). Returns its size.
+fn get_domain_info(
+    state: &mut State,
+    domain_id: usize,
+    buf: usize,
+    size: usize,
+) -> Result<usize, CallError> {
+    let info_size = size_of::<SupdDomainInfo>();
+    if size < info_size {
+        return Err(CallError::BufferTooSmall(info_size));
+    }
+    caller_buffer(state, buf, info_size)?;
+    let info = state.domain_info(domain_id)?;
+    unsafe { (buf as *mut SupdDomainInfo).write_unaligned(info) };
+    Ok(info_size)
+}
+
+// Copy the SupdDomainStats of a domain to [buf, buf + size). Returns its size.
+fn get_domain_stats(
+    state: &mut State,
+    domain_id: usize,
+    buf: usize,
+    size: usize,
+) -> Result<usize, CallError> {
+    let stats_size = size_of::<SupdDomainStats>();
+    if size < stats_size {
+        return Err(CallError::BufferTooSmall(stats_size));
+    }
+    caller_buffer(state, buf, stats_size)?;
+    let domain = state
+        .domains
+        .get(domain_id)
+        .ok_or(CallError::UnknownDomain(domain_id))?;
+    let domain_stats = stats::report(domain);
+    unsafe { (buf as *mut SupdDomainStats).write_unaligned(domain_stats) };
+    Ok(stats_size)
+}
+
+// The TSM of the caller answers GET_TSM_INFO differently from now on: the next one reaches it
+fn tsm_info_changed(state: &mut State) -> Result<usize, CallError> {
+    state.domains[state.active_domain].tsm_info = None;
+    Ok(0)
+}
+
+// Trust between the caller and another domain, with the consent of both
+fn offer_trust(state: &mut State, domain_id: usize) -> Result<usize, CallError> {
+    state.offer_trust(domain_id)?;
+    Ok(0)
+}
+
+fn accept_trust(state: &mut State, domain_id: usize) -> Result<usize, CallError> {
+    state.accept_trust(domain_id)?;
+    Ok(0)
+}
+
+fn revoke_trust(state: &mut State, domain_id: usize) -> Result<usize, CallError> {
+    state.revoke_trust(domain_id)?;
+    Ok(0)
+}
+
+// Snapshot of the caller, restored to reboot it. The context saved is the one of the SNAPSHOT_DOMAIN
+// call: the restored caller returns from it, with 1 in a1
+fn snapshot_domain(state: &mut State, ranges: usize, count: usize) -> Result<usize, CallError> {
+    let ctx = unsafe { &*(state.tee.scratch_context() as *const Context) };
+    crate::snapshot::take(state, ctx, ranges, count)?;
+    Ok(0)
+}
+
+fn restore_domain(state: &mut State) -> Result<usize, CallError> {
+    let ctx = unsafe { &mut *(state.tee.scratch_context() as *mut Context) };
+    crate::snapshot::restore(state, ctx)?;
+    Ok(1)
+}
+
+// Arm a fault of the TEE switch path, returns the outcome of the last one (see `inject.rs`)
+fn inject_fault(_state: &mut State, fault: usize) -> Result<usize, CallError> {
+    Ok(inject::arm(fault)? as usize)
+}
+
+// Bitmap of the targets which failed the isolation self-test
+fn self_test(state: &mut State) -> Result<usize, CallError> {
+    Ok(crate::selftest::run(state).failed)
+}
+
+// Usage of the firmware heap, to size _heap_size
+fn get_heap_stat(_state: &mut State, index: usize) -> Result<usize, CallError> {
+    crate::ALLOCATOR
+        .stats()
+        .get(index)
+        .ok_or(CallError::UnknownHeapStat(index))
+}
+
+// Copy the records with a sequence number of at least first_seq to [buf, buf + size), which must
+// be memory of the caller. Returns the number of records copied.
+fn read_audit_log(
+    state: &mut State,
+    buf: usize,
+    size: usize,
+    first_seq: usize,
+) -> Result<usize, CallError> {
+    caller_buffer(state, buf, size)?;
+    let out = buf as *mut AuditRecord;
+    let records = state.audit.records_from(first_seq as u64);
+    let mut count = 0;
+    for record in records.take(size / size_of::<AuditRecord>()) {
+        unsafe { out.add(count).write_unaligned(*record) };
+        count += 1;
+    }
+    Ok(count)
+}
+
+// Copy the trace entries of hartid with a sequence number of at least first_seq to
+// [buf, buf + size), which must be memory of the caller. Returns the number of entries copied.
+fn read_trace(
+    state: &mut State,
+    hartid: usize,
+    buf: usize,
+    size: usize,
+    first_seq: usize,
+) -> Result<usize, CallError> {
+    caller_buffer(state, buf, size)?;
+    let out = buf as *mut SupdTraceEntry;
+    let mut count = 0;
+    for entry in trace::entries(hartid, first_seq).take(size / size_of::<SupdTraceEntry>()) {
+        unsafe { out.add(count).write_unaligned(entry) };
+        count += 1;
+    }
+    Ok(count)
+}
+
+// Copy the audit measurement register to [buf, buf + size). Returns its size.
+fn get_audit_measurement(state: &mut State, buf: usize, size: usize) -> Result<usize, CallError> {
+    if size < AUDIT_MEASUREMENT_SIZE {
+        return Err(CallError::BufferTooSmall(AUDIT_MEASUREMENT_SIZE));
+    }
+    caller_buffer(state, buf, AUDIT_MEASUREMENT_SIZE)?;
+    let measurement = state.audit.measurement();
+    unsafe {
+        core::ptr::copy_nonoverlapping(measurement.as_ptr(), buf as *mut u8, measurement.len());
+    }
+    Ok(AUDIT_MEASUREMENT_SIZE)
+}
+
+// Store the blob [buf, buf + size) of the caller under the id at `id`, remove it if size is 0
+fn store_blob(state: &mut State, id: usize, buf: usize, size: usize) -> Result<usize, CallError> {
+    if size > SUPD_BLOB_MAX_SIZE {
+        return Err(CallError::BlobTooLarge(size));
+    }
+    caller_buffer(state, id, SUPD_BLOB_ID_SIZE)?;
+    let id = unsafe { core::slice::from_raw_parts(id as *const u8, SUPD_BLOB_ID_SIZE) };
+    let data = match size {
+        0 => &[][..],
+        _ => {
+            caller_buffer(state, buf, size)?;
+            unsafe { core::slice::from_raw_parts(buf as *const u8, size) }
         }
+    };
+    let domain = state.active_domain;
+    state.storage.as_mut().unwrap().store(domain, id, data)?;
+    Ok(0)
+}
+
+// Copy the blob of the caller with the id at `id` to [buf, buf + size). Returns its size.
+fn load_blob(state: &mut State, id: usize, buf: usize, size: usize) -> Result<usize, CallError> {
+    caller_buffer(state, id, SUPD_BLOB_ID_SIZE)?;
+    let id = unsafe { core::slice::from_raw_parts(id as *const u8, SUPD_BLOB_ID_SIZE) };
+    let blob = state
+        .storage
+        .as_ref()
+        .unwrap()
+        .load(state.active_domain, id)
+        .ok_or(CallError::NoBlob)?;
+    if blob.len() > size {
+        return Err(CallError::BufferTooSmall(blob.len()));
+    }
+    caller_buffer(state, buf, blob.len())?;
+    unsafe { core::ptr::copy_nonoverlapping(blob.as_ptr(), buf as *mut u8, blob.len()) };
+    Ok(blob.len())
+}
+
+// Derive the key of the label [label, label + label_size) for the caller from the platform CDI and
+// copy it to [key, key + 32)
+fn derive_key(
+    state: &mut State,
+    label: usize,
+    label_size: usize,
+    key: usize,
+) -> Result<usize, CallError> {
+    if label_size > KEY_LADDER_MAX_LABEL {
+        return Err(CallError::KeyLabelTooLong(label_size));
+    }
+    caller_buffer(state, label, label_size)?;
+    caller_buffer(state, key, 32)?;
+    let _heap_tag = crate::ALLOCATOR.tag(crate::HEAP_TAG_CRYPTO);
+    let label = unsafe { core::slice::from_raw_parts(label as *const u8, label_size) };
+    let derived = state
+        .attestation_context
+        .as_ref()
+        .ok_or(CallError::AttestationUnavailable)?
+        .cdi()
+        .ladder_key(state.active_domain as u32, label)
+        .ok_or(CallError::InvalidKeyLabel)?;
+    unsafe { core::ptr::copy_nonoverlapping(derived.as_ptr(), key as *mut u8, derived.len()) };
+    Ok(0)
+}
+
+fn caller_buffer(state: &State, buf: usize, size: usize) -> Result<(), CallError> {
+    let caller = &state.domains[state.active_domain];
+    if !caller.owns(buf, size) {
+        return Err(CallError::NotCallerMemory { addr: buf, size });
     }
-    return unsafe { return_error(dst_addr, -1) };
-}
-
-#[unsafe(naked)]
-fn tee_handler_exit() -> ! {
-    core::arch::naked_asm!(
-        "
-            ld zero, 0(sp)
-            ld ra, 1*8(sp)
-            ld gp, 3*8(sp)
-            ld tp, 4*8(sp)
-            ld t1, 6*8(sp)
-            ld t2, 7*8(sp)
-            ld s0, 8*8(sp)
-            ld s1, 9*8(sp)
-            ld a1, 11*8(sp)
-            ld a2, 12*8(sp)
-            ld a3, 13*8(sp)
-            ld a4, 14*8(sp)
-            ld a5, 15*8(sp)
-            ld a6, 16*8(sp)
-            ld a7, 17*8(sp)
-            ld s2, 18*8(sp)
-            ld s3, 19*8(sp)
-            ld s4, 20*8(sp)
-            ld s5, 21*8(sp)
-            ld s6, 22*8(sp)
-            ld s7, 23*8(sp)
-            ld s8, 24*8(sp)
-            ld s9, 25*8(sp)
-            ld s10, 26*8(sp)
-            ld s11, 27*8(sp)
-            ld t3, 28*8(sp)
-            ld t4, 29*8(sp)
-            ld t5, 30*8(sp)
-            ld t6, 31*8(sp)
-        ",
-        // restore CSRs
-        "
-            ld t0, 32*8(sp)
-            csrw sstatus, t0
-            ld t0, 33*8(sp)
-            csrw stvec, t0
-            ld t0, 34*8(sp)
-            csrw sip, t0
-            ld t0, 35*8(sp)
-            csrw scounteren, t0
-            ld t0, 36*8(sp)
-            csrw sscratch, t0
-            ld t0, 37*8(sp)
-            csrw satp, t0
-            //ld t0, 38*8(sp)
-            //csrw senvcfg, t0
-            // ld t0, 39*8(sp)
-            // csrw scontext, t0
-            ld t0, 40*8(sp)
-            csrw mepc, t0
-        ",
-        // restore t0, a0, sp
-        "
-            ld t0, 5*8(sp)
-            ld a0, 10*8(sp)
-            ld sp, 2*8(sp)
-            mret
-        ",
-    )
+    Ok(())
+}
+
+/// Value returned by `sbi_probe_extension` for the extensions handled by the firmware: zero if
+/// the extension is not available, otherwise `SBI_PROBE_AVAILABLE` with the feature bits.
+pub fn probe_extension(eid: usize) -> usize {
+    let guard = STATE.lock();
+    let Some(state) = guard.get() else {
+        return 0;
+    };
+
+    match eid {
+        SBI_COVH_EXT_ID if state.domains.iter().any(|d| d.has_tsm) => SBI_PROBE_AVAILABLE,
+        // TVM interrupts need guest interrupt files
+        SBI_COVI_EXT_ID if state.imsic.is_some() && state.h_extension => SBI_PROBE_AVAILABLE,
+        SBI_SUPD_EXT_ID => {
+            let mut value = SBI_PROBE_AVAILABLE;
+            if Capability::Iopmp.available(state) {
+                value |= SBI_SUPD_PROBE_DMA;
+            }
+            if Capability::Rng.available(state) {
+                value |= SBI_SUPD_PROBE_RANDOM;
+            }
+            if Capability::AuditLog.available(state) {
+                value |= SBI_SUPD_PROBE_AUDIT_LOG;
+            }
+            if Capability::Storage.available(state) {
+                value |= SBI_SUPD_PROBE_STORAGE;
+            }
+            value
+        }
+        _ => 0,
+    }
+}
+
+/// ecall from VS-mode (mcause 10)
+const CAUSE_VIRTUAL_SUPERVISOR_ECALL: usize = 10;
+/// hgatp CSR number
+const CSR_HGATP: usize = 0x680;
+
+/// SUPD functions a VS-mode guest may call: they neither take a buffer nor act on the domain
+const GUEST_SUPD_CALLS: [usize; 3] = [
+    SBI_EXT_SUPD_GET_ACTIVE_DOMAINS,
+    SBI_EXT_SUPD_GET_RANDOM,
+    SBI_EXT_SUPD_GET_TIME,
+];
+
+/// Delegate the ecalls of the VS-mode guests on this hart to the hypervisor of the domain about to
+/// run if it is a TSM (`to_tsm`): its TVMs reach it without a detour through the firmware (COVG,
+/// the WFI exits of RUN_TVM_VCPU). The firmware takes the ecalls of the guests of the other
+/// domains and serves their CoVE calls (see `guest_ecall`).
+pub fn delegate_guest_calls(to_tsm: bool) {
+    if !misa::read().has_extension('H') {
+        return;
+    }
+    let bit = 1usize << CAUSE_VIRTUAL_SUPERVISOR_ECALL;
+    if to_tsm {
+        unsafe { core::arch::asm!("csrs medeleg, {0}", in(reg) bit) };
+    } else {
+        unsafe { core::arch::asm!("csrc medeleg, {0}", in(reg) bit) };
+    }
+}
+
+/// Serve an ecall of a VS-mode guest of the active domain, a domain without TSM (see
+/// `delegate_guest_calls`):
+/// - COVG is not supported, the guest has no TSM to call;
+/// - GET_TSM_INFO is answered with the last answer of the TSM to its host (see `tsm_info.rs` in
+///   cove-core), written at the guest physical address of the call;
+/// - the SUPD functions of `GUEST_SUPD_CALLS` are served as for the domain itself;
+/// - the other COVH, COVI and SUPD functions belong to the host: SBI_ERR_DENIED.
+///
+/// The ecalls of the other extensions go to the hypervisor of the guest.
+// The registers are `c_ulong` with OpenSBI
+#[allow(clippy::unnecessary_cast)]
+pub fn guest_ecall(ctx: &mut TrapContext) -> &mut TrapRegs {
+    let regs = &mut ctx.regs;
+    let eid = regs.a7 as usize;
+    let fid = regs.a6 as usize;
+    let args = [regs.a0, regs.a1, regs.a2, regs.a3, regs.a4, regs.a5].map(|r| r as usize);
+
+    let mut guard = STATE.lock();
+    let Some(state) = guard.get_mut() else {
+        drop(guard);
+        return forward_guest_ecall(ctx);
+    };
+    let confidential = state.domains[state.active_domain].has_tsm;
+    let result = match eid {
+        SBI_COVH_EXT_ID | SBI_COVI_EXT_ID | SBI_SUPD_EXT_ID => {
+            guest_cove_call(state, eid, fid, &args)
+        }
+        COVG_EXTENSION if !confidential => Err(SBI_ERR_NOT_SUPPORTED),
+        _ => {
+            drop(guard);
+            return forward_guest_ecall(ctx);
+        }
+    };
+    if eid == SBI_SUPD_EXT_ID {
+        let src_id = state.active_domain;
+        let error = result.err().unwrap_or(0);
+        trace::record(SUPD_TRACE_SUPD_CALL, src_id, src_id, eid, fid, error);
+    }
+    drop(guard);
+
+    let (a0, a1) = match result {
+        Ok(value) => (0, value),
+        Err(code) => (code as usize, 0),
+    };
+    let regs = &mut ctx.regs;
+    regs.a0 = a0 as _;
+    regs.a1 = a1 as _;
+    regs.mepc += 4;
+    regs
+}
+
+/// Send the ecall of a VS-mode guest to the hypervisor of its domain, as if it had been delegated.
+/// If the runtime cannot redirect it, the guest gets the error.
+// The registers are `c_ulong` with OpenSBI
+#[allow(clippy::unnecessary_cast)]
+fn forward_guest_ecall(ctx: &mut TrapContext) -> &mut TrapRegs {
+    if let Some(code) = runtime::redirect_trap(ctx).err() {
+        debug!("cannot forward the guest ecall {:#x}", ctx.regs.a7 as usize);
+        ctx.regs.a0 = code as _;
+        ctx.regs.mepc += 4;
+    }
+    &mut ctx.regs
+}
+
+/// CoVE call `eid`/`fid` of a VS-mode guest, see `guest_ecall`.
+fn guest_cove_call(
+    state: &mut State,
+    eid: usize,
+    fid: usize,
+    args: &[usize; 6],
+) -> Result<usize, isize> {
+    let (dst_id, call_fid) = cove_unpack_fid(fid);
+    match (eid, call_fid) {
+        (SBI_SUPD_EXT_ID, _) if GUEST_SUPD_CALLS.contains(&fid) => supd(fid, state, args),
+        (SBI_COVH_EXT_ID, SBI_COVH_GET_TSM_INFO) if state.allows_call(eid, call_fid) => {
+            guest_tsm_info(state, dst_id, args[0], args[1])
+        }
+        _ => {
+            state.record(AuditEvent::PolicyDenied, eid, fid);
+            Err(SBI_ERR_DENIED)
+        }
+    }
+}
+
+/// GET_TSM_INFO of a VS-mode guest to the TSM of `dst_id`, with the buffer of `size` bytes at the
+/// guest physical address `gpa`. The guest cannot TEECALL: only a ready TSM which answered its
+/// host already has an answer for it.
+fn guest_tsm_info(state: &State, dst_id: usize, gpa: usize, size: usize) -> Result<usize, isize> {
+    let tsm = state
+        .domains
+        .get(dst_id)
+        .filter(|domain| domain.has_tsm)
+        .ok_or(SBI_ERR_INVALID_PARAM)?;
+    let cache = tsm
+        .tsm_info
+        .as_ref()
+        .filter(|_| tsm.tsm_ready && !tsm.faulted)
+        .ok_or(SBI_ERR_NOT_READY)?;
+    let answer = cache.answer(gpa, size).ok_or(SBI_ERR_INVALID_PARAM)?;
+    if !write_guest(state, gpa, answer) {
+        return Err(SBI_ERR_INVALID_PARAM);
+    }
+    Ok(answer.len())
+}
+
+/// Write `bytes` at the guest physical address `gpa` of the running guest. The page tables and
+/// the pages must be memory of the active domain, confidential memory only if the domain is the
+/// one of a TSM: nothing is written otherwise.
+fn write_guest(state: &State, gpa: usize, bytes: &[u8]) -> bool {
+    let caller = &state.domains[state.active_domain];
+    let writable = |pa: usize, len: usize| {
+        caller.owns(pa, len) && (caller.has_tsm || !state.is_confidential(pa, len))
+    };
+    let hgatp: usize;
+    unsafe { core::arch::asm!("csrr {0}, {csr}", out(reg) hgatp, csr = const CSR_HGATP) };
+    let read_pte = |addr: u64| {
+        let addr = addr as usize;
+        writable(addr, size_of::<u64>()).then(|| unsafe { (addr as *const u64).read_volatile() })
+    };
+
+    // Translate every page before the first byte is written
+    let mut chunks: Vec<(usize, &[u8])> = Vec::new();
+    let mut rest = bytes;
+    let mut addr = gpa;
+    while !rest.is_empty() {
+        let len = (COVH_DEFAULT_PAGE_SIZE - addr % COVH_DEFAULT_PAGE_SIZE).min(rest.len());
+        let Some(pa) = gstage::translate(hgatp as u64, addr as u64, gstage::PTE_W, &read_pte)
+        else {
+            return false;
+        };
+        if !writable(pa as usize, len) {
+            return false;
+        }
+        let (chunk, tail) = rest.split_at(len);
+        chunks.push((pa as usize, chunk));
+        rest = tail;
+        addr += len;
+    }
+
+    for (pa, chunk) in chunks {
+        unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), pa as *mut u8, chunk.len()) };
+    }
+    true
+}
+
+/// Whether `caller` owns the COVH queue of `count` entries at `addr` and its policy allows every
+/// queued call. The TSM executes the queueable calls only.
+fn queue_allowed(state: &State, caller: usize, addr: usize, count: usize) -> bool {
+    let Some(size) = count.checked_mul(size_of::<CovhQueueEntry>()) else {
+        return false;
+    };
+    if count > COVH_QUEUE_MAX_ENTRIES
+        || addr % align_of::<CovhQueueEntry>() != 0
+        || !state
+            .domains
+            .get(caller)
+            .is_some_and(|domain| domain.owns(addr, size))
+    {
+        return false;
+    }
+
+    let entries = unsafe { core::slice::from_raw_parts(addr as *const CovhQueueEntry, count) };
+    entries
+        .iter()
+        .all(|entry| covh_queueable(entry.fid) && state.allows_call(SBI_COVH_EXT_ID, entry.fid))
+}
+
+// Whether `addr` is a guest interrupt file of the IMSIC
+fn is_guest_interrupt_file(imsic: Option<ImsicInfo>, addr: usize) -> bool {
+    imsic.is_some_and(|imsic| imsic.guest_file_index(addr).is_some())
 }
 
 // Encode an error code to the a0 register of the calling context and increment mepc
@@ -483,23 +1196,163 @@ unsafe fn return_error(ctx_addr: usize, code: isize) -> usize {
     return ctx_addr;
 }
 
-// Program the PMP as stated in 3.7 in Privileged ISA
-pub fn program_pmp_from_regions(regions: &[MemoryRegion]) {
-    for (i, r) in regions.iter().enumerate() {
-        let ones = (1 << (r.order - 3)) - 1;
-        let range = riscv::register::Range::NAPOT as usize;
-        let permission = riscv::register::Permission::RWX as usize;
+/// Answer a TEECALL to a TSM which cannot take it (stopped by the watchdog, or not initialized):
+/// GET_TSM_INFO reports `status` in `tsm_status` (the first word of TsmInfo, the rest is zeroed),
+/// the other calls fail with `error`.
+unsafe fn unavailable_tsm_call(
+    ctx_addr: usize,
+    tsm_info: Option<(usize, usize)>,
+    status: u32,
+    error: isize,
+) -> usize {
+    let Some((base_addr, size)) =
+        tsm_info.filter(|(addr, size)| *size >= size_of::<u32>() && addr % align_of::<u32>() == 0)
+    else {
+        return return_error(ctx_addr, error);
+    };
+
+    core::ptr::write_bytes(base_addr as *mut u8, 0, size);
+    (base_addr as *mut u32).write_volatile(status);
+
+    let ctx = ctx_addr as *mut Context;
+    (*ctx).regs[10] = 0;
+    (*ctx).regs[11] = size;
+    (*ctx).mepc += 4;
+    ctx_addr
+}
 
-        // This should be a byte and be shifted by index
-        let pmpcfg = ((0) << 7 | (range) << 3 | (permission)) & 0xFF;
-        let pmpaddr = ((r.base_addr >> 2) as usize) | ones as usize;
+/// Answer GET_TSM_INFO with the `answer` the TSM gave last time (see `tsm_info.rs` in cove-core),
+/// written to the buffer of the caller at `base_addr`.
+unsafe fn cached_tsm_info(ctx_addr: usize, base_addr: usize, answer: &[u8]) -> usize {
+    core::ptr::copy_nonoverlapping(answer.as_ptr(), base_addr as *mut u8, answer.len());
+
+    let ctx = ctx_addr as *mut Context;
+    (*ctx).regs[10] = 0;
+    (*ctx).regs[11] = answer.len();
+    (*ctx).mepc += 4;
+    ctx_addr
+}
+
+/// The TEECALL watched by the watchdog did not TEERET within its budget: go back to the caller
+/// with SBI_ERR_TIMEOUT, through the trap frame `regs` of the timer interrupt. The nested calls are
+/// unwound too. Any callee of the chain may hang, so they are all marked as faulted and never run
+/// again.
+pub fn tsm_timeout(state: &mut State, regs: &mut TrapRegs) {
+    let Some(armed) = state.watchdog.disarm() else {
+        return;
+    };
+    debug!(
+        "TEECALL {:#x}/{} timed out, TSM faulted",
+        armed.eid, armed.fid
+    );
+
+    for call in state.calls.calls() {
+        state.domains[call.callee].faulted = true;
+        state.domains[call.callee].tsm_info = None;
+    }
+    state
+        .audit
+        .record(AuditEvent::TsmWatchdog, armed.caller, armed.eid, armed.fid);
+    abort_calls(state, regs, SBI_ERR_TIMEOUT);
+    trace::record(
+        SUPD_TRACE_TEERET,
+        armed.tsm,
+        armed.caller,
+        armed.eid,
+        armed.fid,
+        SBI_ERR_TIMEOUT,
+    );
+}
 
-        write_pmpaddr(i, pmpaddr);
-        write_pmpcfg(i, pmpcfg);
+/// Give the hart back to the first caller of the call chain, which gets the error `code`: the
+/// calls are unwound as the TEERETs would and the caller is loaded into the trap frame `regs`.
+pub fn abort_calls(state: &mut State, regs: &mut TrapRegs, code: isize) {
+    state.watchdog.disarm();
+    if inject::take(SUPD_FAULT_DROP_TEERET) {
+        inject::record(code);
     }
+    let Some(call) = state.calls.unwind(&mut state.domains) else {
+        return;
+    };
+    state.domains.iter_mut().for_each(call_buffers::release);
+
+    let caller = call.caller;
+    let caller_ctx = state.tee.context(caller);
+    unsafe { interrupts::replay(&mut *(caller_ctx as *mut Context)) };
+    unsafe { return_error(caller_ctx, code) };
+    activate_domain(state, caller);
+    unsafe { restore_into_trap_regs(&*(caller_ctx as *const Context), regs) };
+}
+
+// sstatus fields of mstatus: SIE, SPIE, UBE, SPP, VS, FS, XS, SUM, MXR, SD
+const SSTATUS_BITS: usize = 1 << 1
+    | 1 << 5
+    | 1 << 6
+    | 1 << 8
+    | 0b11 << 9
+    | 0b11 << 13
+    | 0b11 << 15
+    | 1 << 18
+    | 1 << 19
+    | 1 << (usize::BITS - 1);
+
+/// Load the context `ctx` as `tee_handler_exit` does, but into the trap frame `regs` restored by
+/// the trap handler. x0-x31 come first in both (see `crash.rs`).
+// The registers are `c_ulong` with OpenSBI
+#[allow(clippy::unnecessary_cast)]
+unsafe fn restore_into_trap_regs(ctx: &Context, regs: &mut TrapRegs) {
+    let gprs = core::slice::from_raw_parts_mut(regs as *mut TrapRegs as *mut usize, 32);
+    gprs[1..].copy_from_slice(&ctx.regs[1..]);
+    regs.mepc = ctx.mepc as _;
+    // mret restores mstatus from the frame, and sstatus with it
+    regs.mstatus = ((regs.mstatus as usize & !SSTATUS_BITS) | (ctx.sstatus & SSTATUS_BITS)) as _;
+
+    core::arch::asm!(
+        "csrw stvec, {stvec}",
+        "csrw sip, {sip}",
+        "csrw scounteren, {scounteren}",
+        "csrw sscratch, {sscratch}",
+        "csrw satp, {satp}",
+        stvec = in(reg) ctx.stvec,
+        sip = in(reg) ctx.sip,
+        scounteren = in(reg) ctx.scounteren,
+        sscratch = in(reg) ctx.sscratch,
+        satp = in(reg) ctx.satp,
+    );
+    restore_h_csrs(ctx);
+}
+
+/// First PMP entry of the domain regions, the ones before it are locked by `wx.rs`
+#[cfg(feature = "firmware-wx")]
+const FIRST_DOMAIN_ENTRY: usize = crate::wx::LOCKED_ENTRIES;
+#[cfg(not(feature = "firmware-wx"))]
+const FIRST_DOMAIN_ENTRY: usize = 0;
+
+/// PMP of the hart, through the CSRs
+struct HartPmp;
+
+impl Pmp for HartPmp {
+    fn write(&mut self, index: usize, pmpaddr: usize, pmpcfg: usize) {
+        write_pmpaddr(index, pmpaddr);
+        write_pmpcfg(index, pmpcfg);
+    }
+
+    fn read_cfg(&self, index: usize) -> usize {
+        read_pmpcfg(index)
+    }
+}
+
+// Program the PMP as stated in 3.7 in Privileged ISA
+pub fn program_pmp_from_regions(regions: &[MemoryRegion]) {
+    pmp::program(&mut HartPmp, FIRST_DOMAIN_ENTRY, regions);
 }
 
-fn write_pmpaddr(index: usize, val: usize) {
+/// Whether the PMP configuration of `regions` reads back as `program_pmp_from_regions` wrote it.
+fn pmp_programmed(regions: &[MemoryRegion]) -> bool {
+    pmp::programmed(&HartPmp, FIRST_DOMAIN_ENTRY, regions) && !inject::take(SUPD_FAULT_PMP_FAILURE)
+}
+
+pub fn write_pmpaddr(index: usize, val: usize) {
     unsafe {
         match index {
             0 => core::arch::asm!("csrw pmpaddr0, {0}", in(reg) val),
@@ -523,34 +1376,25 @@ fn write_pmpaddr(index: usize, val: usize) {
     }
 }
 
-// TODO: adapt this for 32bit
-// According to the spec, RV64 has only even numbers for pmpcfgX. pmpcfg0, pmpcfg2,
-// pmpcfg4...pmpcfg14
-fn write_pmpcfg(index: usize, val: usize) {
-    let n = index / 8;
-    let shift = (index % 8) * 8;
-    let old: usize;
-
-    unsafe {
-        match n {
-            0 => core::arch::asm!("csrr {0}, pmpcfg0", out(reg) old),
-            2 => core::arch::asm!("csrr {0}, pmpcfg2", out(reg) old),
-            4 => core::arch::asm!("csrr {0}, pmpcfg4", out(reg) old),
-            8 => core::arch::asm!("csrr {0}, pmpcfg8", out(reg) old),
-            10 => core::arch::asm!("csrr {0}, pmpcfg10", out(reg) old),
-            12 => core::arch::asm!("csrr {0}, pmpcfg12", out(reg) old),
-            14 => core::arch::asm!("csrr {0}, pmpcfg14", out(reg) old),
-            _ => unreachable!(),
-        };
-    }
+// Each pmpcfgX holds one byte per PMP entry, so there are XLEN/8 entries per register. RV64 only
+// has even numbered pmpcfgX (pmpcfg0, pmpcfg2...pmpcfg14), RV32 has all of them.
+const ENTRIES_PER_CFG: usize = size_of::<usize>();
 
+pub fn write_pmpcfg(index: usize, val: usize) {
+    let n = index / ENTRIES_PER_CFG * (ENTRIES_PER_CFG / 4);
+    let shift = (index % ENTRIES_PER_CFG) * 8;
+    let old = read_pmpcfg_reg(n);
     let mask = !(0xFF << shift);
     let new = (old & mask) | (val << shift);
 
     unsafe {
         match n {
             0 => core::arch::asm!("csrw pmpcfg0, {0}", in(reg) new),
+            #[cfg(target_arch = "riscv32")]
+            1 => core::arch::asm!("csrw pmpcfg1, {0}", in(reg) new),
             2 => core::arch::asm!("csrw pmpcfg2, {0}", in(reg) new),
+            #[cfg(target_arch = "riscv32")]
+            3 => core::arch::asm!("csrw pmpcfg3, {0}", in(reg) new),
             4 => core::arch::asm!("csrw pmpcfg4, {0}", in(reg) new),
             8 => core::arch::asm!("csrw pmpcfg8, {0}", in(reg) new),
             10 => core::arch::asm!("csrw pmpcfg10, {0}", in(reg)new),
@@ -561,38 +1405,60 @@ fn write_pmpcfg(index: usize, val: usize) {
     }
 }
 
-fn remove_region(domain: &mut Domain, target_start: usize, target_end: usize) {
-    let mut new_regions = Vec::new();
+// Configuration byte of the PMP entry `index`
+pub fn read_pmpcfg(index: usize) -> usize {
+    let n = index / ENTRIES_PER_CFG * (ENTRIES_PER_CFG / 4);
+    let shift = (index % ENTRIES_PER_CFG) * 8;
+    (read_pmpcfg_reg(n) >> shift) & 0xFF
+}
 
-    for region in domain.memory_regions {
-        let region_start = region.base_addr;
-        let region_end = region_start + (1 << region.order);
+fn read_pmpcfg_reg(n: usize) -> usize {
+    let old: usize;
+    unsafe {
+        match n {
+            0 => core::arch::asm!("csrr {0}, pmpcfg0", out(reg) old),
+            #[cfg(target_arch = "riscv32")]
+            1 => core::arch::asm!("csrr {0}, pmpcfg1", out(reg) old),
+            2 => core::arch::asm!("csrr {0}, pmpcfg2", out(reg) old),
+            #[cfg(target_arch = "riscv32")]
+            3 => core::arch::asm!("csrr {0}, pmpcfg3", out(reg) old),
+            4 => core::arch::asm!("csrr {0}, pmpcfg4", out(reg) old),
+            8 => core::arch::asm!("csrr {0}, pmpcfg8", out(reg) old),
+            10 => core::arch::asm!("csrr {0}, pmpcfg10", out(reg) old),
+            12 => core::arch::asm!("csrr {0}, pmpcfg12", out(reg) old),
+            14 => core::arch::asm!("csrr {0}, pmpcfg14", out(reg) old),
+            _ => unreachable!(),
+        };
+    }
+    old
+}
 
-        // Case 1: No overlap -
-        // keep the region as is
-        if region_end <= target_start || region_start >= target_end {
-            new_regions.push(region);
-        } else {
-            // Case 2: Partial overlap
-            // - fragment exists BEFORE target
-            if region_start < target_start {
-                new_regions.push(MemoryRegion {
-                    base_addr: region_start,
-                    order: calculate_order(target_start - region_start),
-                    ..region // Copy mmio, permissions, etc.
-                });
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cove_core::domain::{MEMREGION_R, MEMREGION_SU_WRITABLE};
 
-            // Case 3: Partial overlap
-            // - fragment exists AFTER target
-            if region_end > target_end {
-                new_regions.push(MemoryRegion {
-                    base_addr: target_end,
-                    order: calculate_order(region_end - target_end),
-                    ..region // Copy mmio, permissions, etc.
-                });
-            }
+    #[test_case]
+    fn pmp_reads_back_as_programmed() {
+        let region = |base_addr, order, mmio, permissions| MemoryRegion {
+            base_addr,
+            order,
+            mmio,
+            permissions,
+        };
+        let regions = [
+            region(0x8000_0000, 21, false, MEMREGION_RWX),
+            region(0x8a00_0000, 16, false, MEMREGION_RW),
+            region(0x8a01_0000, 12, false, MEMREGION_R),
+            // Write-only is reserved, executable MMIO is not executable
+            region(0x8a02_0000, 12, false, MEMREGION_SU_WRITABLE),
+            region(0x1000_0000, 12, true, MEMREGION_RWX),
+        ];
+        program_pmp_from_regions(&regions);
+        let programmed = pmp_programmed(&regions);
+        for i in 0..regions.len() {
+            write_pmpcfg(FIRST_DOMAIN_ENTRY + i, 0);
         }
+        assert!(programmed);
     }
-    domain.memory_regions = new_regions;
 }
@@ -0,0 +1,283 @@
+/*
+ * Crash dump, printed by the panic handler after the panic message. Besides the message, it tells
+ * which domain was running and what it was doing:
+ *  - the last SBI call of each domain (eid, fid);
+ *  - the activation state of the domains (active, run state, TSM, trust map, regions, access
+ *    faults) and the outstanding TEECALLs;
+ *  - the saved Context of the active domain (GPRs and CSRs), from the scratch context of the last
+ *    CoVE call, and the trap frame of the trap being handled, if any;
+ *  - the PMP configuration;
+ *  - the trace of the domain switches of each hart, oldest first (`trace` feature, see `trace.rs`);
+ *  - a short firmware stack trace: the words of the stack which point into the firmware code,
+ *    innermost first. The firmware is built without frame pointers, so some may be stale.
+ *
+ * Every line is `[SHADOWFAX-CRASH] <record> key=value...`, between a `begin` and an `end` record,
+ * so the functional tests can parse it (see `test/support`):
+ *
+ *   [SHADOWFAX-CRASH] begin
+ *   [SHADOWFAX-CRASH] panic location=src/cove.rs:394
+ *   [SHADOWFAX-CRASH] call domain=2 eid=0x434f5648 fid=0x1000004
+ *   [SHADOWFAX-CRASH] domain id=2 active=1 run=Calling tsm=0 trust_map=0x2 regions=1 ...
+ *   [SHADOWFAX-CRASH] teecall depth=0 caller=2 callee=1
+ *   [SHADOWFAX-CRASH] context domain=2 x1=0x8a000420 ... mepc=0x8a0012a4
+ *   [SHADOWFAX-CRASH] trap x1=0x... mepc=0x... mstatus=0x... mcause=0x5 mtval=0x0
+ *   [SHADOWFAX-CRASH] pmp index=0 cfg=0x1f addr=0x22bfffff
+ *   [SHADOWFAX-CRASH] trace hart=0 seq=41 time=0x2a4f01 event=teecall src=2 dst=1 eid=0x434f5648 ...
+ *   [SHADOWFAX-CRASH] frame index=0 pc=0x80012a4c
+ *   [SHADOWFAX-CRASH] end
+ *
+ * The domains are only read if the state is not locked: a panic with the lock held (e.g. in a
+ * CoVE handler) prints `state busy=1`, the context and the calls are printed anyway.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use common::config::MAX_DOMAINS;
+use riscv::register::{mcause, mtval};
+
+use crate::{
+    _fw_end, _fw_rw_start, _fw_start, _stack_top, _tee_stack_top,
+    context::Context,
+    print_raw,
+    runtime::TrapRegs,
+    state::{State, STATE},
+    tee::TEE_SCRATCH_CONTEXT,
+    trace,
+};
+
+/// Prefix of the crash dump lines
+pub const CRASH_MARKER: &str = "[SHADOWFAX-CRASH]";
+
+/// Stack words looked at for the stack trace
+const STACK_SCAN_WORDS: usize = 512;
+/// Frames printed at most
+const MAX_FRAMES: usize = 16;
+
+/// Print a crash dump record
+macro_rules! crash_line {
+    ($($arg:tt)*) => {
+        print_raw!("{} {}\r\n", CRASH_MARKER, format_args!($($arg)*))
+    };
+}
+
+const GPR_NAMES: [&str; 32] = [
+    "x0", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5",
+    "t6",
+];
+
+// The trap frame is dumped as XLEN words: x0-x31 first, then mepc and mstatus
+const _: () = assert!(core::mem::offset_of!(TrapRegs, mepc) == 32 * size_of::<usize>());
+
+/// Last SBI call (eid, fid) of each domain
+static LAST_CALLS: [(AtomicUsize, AtomicUsize); MAX_DOMAINS] =
+    [const { (AtomicUsize::new(usize::MAX), AtomicUsize::new(0)) }; MAX_DOMAINS];
+
+/// Domain of the last SBI call
+static LAST_DOMAIN: AtomicUsize = AtomicUsize::new(0);
+
+/// Trap frame of the trap being handled, 0 if none
+static TRAP_FRAME: AtomicUsize = AtomicUsize::new(0);
+
+/// Remember the SBI call `eid`/`fid` of `domain`.
+pub fn record_call(domain: usize, eid: usize, fid: usize) {
+    LAST_DOMAIN.store(domain, Ordering::Relaxed);
+    if let Some((last_eid, last_fid)) = LAST_CALLS.get(domain) {
+        last_eid.store(eid, Ordering::Relaxed);
+        last_fid.store(fid, Ordering::Relaxed);
+    }
+}
+
+/// Keeps the address of the trap frame being handled until dropped.
+pub struct TrapFrameGuard;
+
+impl TrapFrameGuard {
+    pub fn enter(regs: &TrapRegs) -> Self {
+        TRAP_FRAME.store(regs as *const TrapRegs as usize, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Drop for TrapFrameGuard {
+    fn drop(&mut self) {
+        TRAP_FRAME.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Print the crash dump of the panic `info`.
+pub fn dump(info: &PanicInfo) {
+    crash_line!("begin");
+    match info.location() {
+        Some(location) => crash_line!("panic location={}:{}", location.file(), location.line()),
+        None => crash_line!("panic location=unknown"),
+    }
+
+    for (domain, (eid, fid)) in LAST_CALLS.iter().enumerate() {
+        let eid = eid.load(Ordering::Relaxed);
+        if eid != usize::MAX {
+            let fid = fid.load(Ordering::Relaxed);
+            crash_line!("call domain={domain} eid={eid:#x} fid={fid:#x}");
+        }
+    }
+
+    match STATE.try_lock() {
+        Some(guard) => match guard.get() {
+            Some(state) => dump_state(state),
+            None => crash_line!("state initialized=0"),
+        },
+        None => crash_line!("state busy=1"),
+    }
+
+    dump_context();
+    dump_trap_frame();
+    dump_pmp();
+    dump_trace();
+    dump_stack();
+    crash_line!("end");
+}
+
+fn dump_state(state: &State) {
+    for (id, domain) in state.domains.iter().enumerate() {
+        crash_line!(
+            "domain id={} active={} run={:?} tsm={} trust_map={:#x} regions={} access_faults={}",
+            id,
+            (id == state.active_domain) as u8,
+            domain.run_state,
+            domain.has_tsm as u8,
+            domain.trust_map,
+            domain.memory_regions.len(),
+            domain.access_faults
+        );
+    }
+    for (depth, call) in state.calls.calls().enumerate() {
+        crash_line!(
+            "teecall depth={} caller={} callee={}",
+            depth,
+            call.caller,
+            call.callee
+        );
+    }
+}
+
+/// The CoVE entries save the caller in the scratch context: it holds the last domain which made a
+/// CoVE call
+fn dump_context() {
+    let scratch = TEE_SCRATCH_CONTEXT.load(Ordering::Relaxed);
+    if scratch == 0 {
+        return;
+    }
+
+    let scratch = unsafe { &*(scratch as *const Context) };
+    let domain = LAST_DOMAIN.load(Ordering::Relaxed);
+    print_raw!("{} context domain={}", CRASH_MARKER, domain);
+    print_words(GPR_NAMES.iter().copied().zip(scratch.regs).skip(1));
+    print_words(scratch.csrs().into_iter());
+    print_raw!("\r\n");
+}
+
+fn dump_trap_frame() {
+    let frame = TRAP_FRAME.load(Ordering::Relaxed);
+    if frame == 0 {
+        return;
+    }
+
+    let words = unsafe { core::slice::from_raw_parts(frame as *const usize, 34) };
+    print_raw!("{} trap", CRASH_MARKER);
+    print_words(
+        GPR_NAMES
+            .iter()
+            .copied()
+            .zip(words[..32].iter().copied())
+            .skip(1),
+    );
+    print_words([("mepc", words[32]), ("mstatus", words[33])].into_iter());
+    print_words([("mcause", mcause::read().bits()), ("mtval", mtval::read())].into_iter());
+    print_raw!("\r\n");
+}
+
+fn dump_pmp() {
+    use riscv::register::*;
+
+    let cfg = [pmpcfg0::read().bits, pmpcfg2::read().bits];
+    let addr = [
+        pmpaddr0::read(),
+        pmpaddr1::read(),
+        pmpaddr2::read(),
+        pmpaddr3::read(),
+        pmpaddr4::read(),
+        pmpaddr5::read(),
+        pmpaddr6::read(),
+        pmpaddr7::read(),
+        pmpaddr8::read(),
+        pmpaddr9::read(),
+        pmpaddr10::read(),
+        pmpaddr11::read(),
+        pmpaddr12::read(),
+        pmpaddr13::read(),
+        pmpaddr14::read(),
+        pmpaddr15::read(),
+    ];
+
+    for (index, addr) in addr.into_iter().enumerate() {
+        // 8 entries per pmpcfg register on RV64
+        let cfg = (cfg[index / 8] >> (8 * (index % 8))) & 0xff;
+        if cfg != 0 {
+            crash_line!("pmp index={index} cfg={cfg:#x} addr={addr:#x}");
+        }
+    }
+}
+
+fn dump_trace() {
+    for entry in trace::harts().flat_map(|hartid| trace::entries(hartid, 0)) {
+        crash_line!(
+            "trace hart={} seq={} time={:#x} event={} src={} dst={} eid={:#x} fid={:#x} error={}",
+            entry.hartid,
+            entry.seq,
+            entry.timestamp,
+            trace::event_name(entry.event),
+            entry.src_domain,
+            entry.dst_domain,
+            entry.eid,
+            entry.fid,
+            entry.error
+        );
+    }
+}
+
+fn dump_stack() {
+    let sp: usize;
+    unsafe { core::arch::asm!("mv {}, sp", out(reg) sp) };
+
+    let text = (&raw const _fw_start as usize)..(&raw const _fw_rw_start as usize);
+    // Stop at the top of the stack the firmware runs on: the boot stacks, the TEE stack or the
+    // end of the firmware (the exception stacks of the runtime are in its data)
+    let top = [
+        &raw const _stack_top as usize,
+        &raw const _tee_stack_top as usize,
+        &raw const _fw_end as usize,
+    ]
+    .into_iter()
+    .filter(|&top| top > sp)
+    .min()
+    .unwrap_or(sp)
+    .min(sp + STACK_SCAN_WORDS * size_of::<usize>());
+
+    let words = (sp..top).step_by(size_of::<usize>());
+    words
+        .map(|addr| unsafe { *(addr as *const usize) })
+        .filter(|pc| text.contains(pc) && pc % 2 == 0)
+        .take(MAX_FRAMES)
+        .enumerate()
+        .for_each(|(index, pc)| crash_line!("frame index={index} pc={pc:#x}"));
+}
+
+fn print_words<'a>(words: impl Iterator<Item = (&'a str, usize)>) {
+    for (name, value) in words {
+        print_raw!(" {}={:#x}", name, value);
+    }
+}
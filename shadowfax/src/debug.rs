@@ -2,7 +2,7 @@
 // SPDX-FileContributor: Wojciech Ozga <woz@zurich.ibm.com>, IBM Research - Zurich
 // SPDX-License-Identifier: Apache-2.0
 #![allow(unused)]
-use crate::opensbi;
+use crate::runtime;
 use core::convert::TryInto;
 use core::fmt::{Error, Write};
 
@@ -60,9 +60,7 @@ pub struct Console {}
 
 impl Console {
     pub fn put(c: u8) {
-        unsafe {
-            opensbi::sbi_putc(c);
-        }
+        runtime::putc(c);
     }
 }
 
@@ -86,35 +84,78 @@ pub mod raw {
     use core::fmt::{self, Write};
     use core::ptr::{read_volatile, write_volatile};
 
-    /// Default QEMU virt ns16550 UART base. Change if your platform uses a different UART.
-    const UART0_BASE: usize = 0x1000_0000;
+    use crate::platform::{Uart, UART};
 
     /// ns16550 register offsets (accessed as bytes)
     const REG_THR: usize = 0x00; // transmit holding register (write)
+    const REG_RBR: usize = 0x00; // receive buffer register (read)
     const REG_LSR: usize = 0x05; // line status register (read)
+    const LSR_DR: u8 = 0x01; // Data Ready
     const LSR_THRE: u8 = 0x20; // Transmitter Holding Register Empty
 
+    /// SiFive UART register offsets (accessed as words)
+    const SIFIVE_REG_TXDATA: usize = 0x00; // transmit data register
+    const SIFIVE_TXDATA_FULL: u32 = 1 << 31; // transmit FIFO full
+    const SIFIVE_REG_RXDATA: usize = 0x04; // receive data register
+    const SIFIVE_RXDATA_EMPTY: u32 = 1 << 31; // receive FIFO empty
+
     /// Low-level UART writer that uses MMIO (volatile accesses).
     pub struct RawConsole {
-        base: usize,
+        uart: Uart,
     }
 
     impl RawConsole {
-        /// Create with default UART base (adjust if needed).
+        /// Create with the UART of the platform (see `platform/<PLATFORM>/platform.rs`).
         pub const fn new() -> Self {
-            RawConsole { base: UART0_BASE }
+            RawConsole { uart: UART }
         }
 
         /// write a single byte to UART (busy-wait until THR empty)
         pub fn putc(&self, c: u8) {
             unsafe {
-                let lsr = (self.base + REG_LSR) as *const u8;
-                let thr = (self.base + REG_THR) as *mut u8;
-
-                // wait for THR empty
-                while (read_volatile(lsr) & LSR_THRE) == 0 {}
+                match self.uart {
+                    Uart::Ns16550 { base } => {
+                        let lsr = (base + REG_LSR) as *const u8;
+                        let thr = (base + REG_THR) as *mut u8;
+
+                        // wait for THR empty
+                        while (read_volatile(lsr) & LSR_THRE) == 0 {}
+
+                        write_volatile(thr, c);
+                    }
+                    Uart::Sifive { base } => {
+                        let txdata = (base + SIFIVE_REG_TXDATA) as *mut u32;
+
+                        // wait for a free slot in the TX FIFO
+                        while (read_volatile(txdata) & SIFIVE_TXDATA_FULL) != 0 {}
+
+                        write_volatile(txdata, c as u32);
+                    }
+                    // a second writer of `tohost` would steal the acks of the runtime HTIF driver
+                    Uart::Htif => crate::runtime::putc(c),
+                }
+            }
+        }
 
-                write_volatile(thr, c);
+        /// read a byte from the UART, `None` if nothing was received (never blocks)
+        pub fn getc(&self) -> Option<u8> {
+            unsafe {
+                match self.uart {
+                    Uart::Ns16550 { base } => {
+                        let lsr = (base + REG_LSR) as *const u8;
+                        let rbr = (base + REG_RBR) as *const u8;
+
+                        ((read_volatile(lsr) & LSR_DR) != 0).then(|| read_volatile(rbr))
+                    }
+                    Uart::Sifive { base } => {
+                        // reading pops the FIFO: the empty flag and the data come together
+                        let rxdata = read_volatile((base + SIFIVE_REG_RXDATA) as *const u32);
+
+                        ((rxdata & SIFIVE_RXDATA_EMPTY) == 0).then_some(rxdata as u8)
+                    }
+                    // the HTIF console input is not polled
+                    Uart::Htif => None,
+                }
             }
         }
     }
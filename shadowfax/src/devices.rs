@@ -0,0 +1,179 @@
+/*
+ * Device assignment table. Every MMIO device a domain gets in its PMP comes from here, none is
+ * hardcoded in the memory layout: the table is read from the `shadowfax,device-assignment` nodes of
+ * the device tree (see `fdt::find_device_assignments`), or from the static configuration, and
+ * validated as a whole at init, before any PMP region is granted.
+ *
+ * A device is claimed exclusively unless its node is `shared`. An exclusive device is in the PMP of
+ * its owner only, the MMIO regions of the other domains covering it are dropped; a shared device can
+ * be claimed by several domains. Two claims conflict when their PMP regions overlap and either is
+ * exclusive: the regions are compared once rounded to NAPOT, so two devices in the same PMP granule
+ * cannot go to different domains.
+ *
+ * If no node claims the console UART, the TSM gets it shared, as before the table existed: every
+ * domain keeps writing on the console through DBCN.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use alloc::vec::Vec;
+use common::{pmp::Region, sbi::PAGE_SIZE};
+
+use crate::{
+    domain::{MemoryRegion, MEMREGION_RW},
+    error::ConfigError,
+    fdt,
+    platform::UART,
+};
+
+/// MMIO device claimed by a domain, see `fdt::find_device_assignments`.
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceAssignment {
+    pub domain: usize,
+    pub base_addr: usize,
+    pub size: usize,
+    /// The other domains lose their access to `[base_addr, base_addr + size)` and cannot claim it
+    pub exclusive: bool,
+    /// If the device is the console UART, the other domains keep writing on the console through
+    /// the firmware (DBCN). Their calls are denied otherwise.
+    pub console_mux: bool,
+}
+
+impl DeviceAssignment {
+    /// PMP region covering the device
+    pub fn region(&self) -> Result<MemoryRegion, ConfigError> {
+        // Rounded up to a power of two, the base must be aligned to it
+        let order = self
+            .size
+            .checked_next_power_of_two()
+            .filter(|_| self.size != 0)
+            .map(|size| {
+                size.trailing_zeros()
+                    .max(crate::platform::PMP_GRANULARITY_ORDER)
+            });
+        let Some(order) = order.filter(|&order| Region::napot(self.base_addr, order).is_some())
+        else {
+            return Err(ConfigError::DeviceNotNapot {
+                base_addr: self.base_addr,
+                size: self.size,
+            });
+        };
+
+        Ok(MemoryRegion {
+            base_addr: self.base_addr,
+            order,
+            mmio: true,
+            permissions: MEMREGION_RW,
+        })
+    }
+
+    /// Whether the device is the console UART.
+    pub fn is_console(&self) -> bool {
+        UART.base()
+            .is_some_and(|uart| uart >= self.base_addr && uart - self.base_addr < self.size)
+    }
+}
+
+/// Devices claimed by the supervisor domains, with their PMP regions.
+pub struct DeviceTable {
+    entries: Vec<(DeviceAssignment, MemoryRegion)>,
+}
+
+impl DeviceTable {
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Read the table from the device tree at `fdt_addr`, for `domains` domains the root included.
+    /// The console UART goes shared to `tsm` if it is not claimed.
+    pub fn from_fdt(fdt_addr: usize, domains: usize, tsm: usize) -> Result<Self, ConfigError> {
+        let mut table = Self::new();
+        for assignment in fdt::find_device_assignments(fdt_addr)? {
+            table.insert(assignment, domains)?;
+        }
+
+        let console_claimed = table.iter().any(|a| a.is_console());
+        if let (Some(base_addr), false) = (UART.base(), console_claimed) {
+            let uart = DeviceAssignment {
+                domain: tsm,
+                base_addr,
+                size: PAGE_SIZE,
+                exclusive: false,
+                console_mux: false,
+            };
+            table.insert(uart, domains)?;
+        }
+        Ok(table)
+    }
+
+    /// Add `assignment`, unless it conflicts with the claims already in the table.
+    fn insert(&mut self, assignment: DeviceAssignment, domains: usize) -> Result<(), ConfigError> {
+        // The root domain never runs
+        if assignment.domain == 0 || assignment.domain >= domains {
+            return Err(ConfigError::UnknownDeviceDomain {
+                base_addr: assignment.base_addr,
+                domain: assignment.domain,
+            });
+        }
+        let region = assignment.region()?;
+
+        let conflict = self.entries.iter().find(|(other, other_region)| {
+            overlaps(&region, other_region)
+                && (other.domain == assignment.domain || other.exclusive || assignment.exclusive)
+        });
+        match conflict {
+            Some((other, _)) if other.domain == assignment.domain => {
+                return Err(ConfigError::DeviceAssignedTwice {
+                    base_addr: assignment.base_addr,
+                    domain: assignment.domain,
+                })
+            }
+            Some((other, _)) => {
+                return Err(ConfigError::DeviceConflict {
+                    base_addr: assignment.base_addr,
+                    first: other.domain,
+                    second: assignment.domain,
+                })
+            }
+            None => {}
+        }
+
+        self.entries.push((assignment, region));
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DeviceAssignment> {
+        self.entries.iter().map(|(assignment, _)| assignment)
+    }
+
+    /// The claims with their PMP regions
+    pub fn regions(&self) -> impl Iterator<Item = &(DeviceAssignment, MemoryRegion)> {
+        self.entries.iter()
+    }
+
+    /// Exclusive claim of the console UART, if any.
+    pub fn console(&self) -> Option<&DeviceAssignment> {
+        self.iter().find(|a| a.exclusive && a.is_console())
+    }
+
+    /// Ranges to hide from the device tree view of `domain`: the devices of the other domains
+    /// claimed exclusively, but the console UART muxed by the firmware.
+    #[cfg(feature = "fdt")]
+    pub fn hidden_from(&self, domain: usize) -> Vec<(usize, usize)> {
+        self.iter()
+            .filter(|a| a.domain != domain && a.exclusive && !a.console_mux)
+            .map(|a| (a.base_addr, a.size))
+            .collect()
+    }
+}
+
+fn overlaps(a: &MemoryRegion, b: &MemoryRegion) -> bool {
+    let end = |r: &MemoryRegion| {
+        1usize
+            .checked_shl(r.order)
+            .map_or(usize::MAX, |size| r.base_addr.saturating_add(size))
+    };
+    a.base_addr < end(b) && b.base_addr < end(a)
+}
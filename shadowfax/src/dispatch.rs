@@ -0,0 +1,105 @@
+/*
+ * Declarative dispatch of the SBI extensions implemented by the firmware. An extension is a table
+ * of function ids: each entry names the handler, the arguments it takes from a0-a5 and, optionally,
+ * the capability the platform must provide. `sbi_extension!` generates the function id match, the
+ * argument extraction and the error mapping, so a new function id is a single line in the table.
+ * The SBI policy of the caller domain (see `domain::SbiPolicy`) is checked first, denied calls are
+ * recorded in the audit log.
+ *
+ * Handlers return the value for a1 or an error:
+ *  - calls denied by the policy are reported as SBI_ERR_DENIED;
+ *  - handler errors are reported as SBI_ERR_FAILED;
+ *  - unknown function ids and missing capabilities are reported as SBI_ERR_NOT_SUPPORTED.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use crate::state::State;
+
+pub const SBI_ERR_FAILED: isize = -1;
+pub const SBI_ERR_NOT_SUPPORTED: isize = -2;
+pub const SBI_ERR_INVALID_PARAM: isize = -3;
+pub const SBI_ERR_DENIED: isize = -4;
+/// SBI_ERR_INVALID_STATE: the TSM did not complete its boot
+pub const SBI_ERR_NOT_READY: isize = -10;
+pub const SBI_ERR_TIMEOUT: isize = -12;
+
+/// Optional platform devices or caller grants an extension function may depend on.
+#[derive(Clone, Copy, Debug)]
+pub enum Capability {
+    /// IOPMP in front of the DMA masters (`riscv,iopmp`)
+    Iopmp,
+    /// Entropy source of the firmware DRBG
+    Rng,
+    /// The SBI policy of the caller grants the audit log (`audit-log`)
+    AuditLog,
+    /// Persistent blob storage (`cfi-flash`)
+    Storage,
+    /// The caller domain runs a TSM
+    Tsm,
+    /// Test build with the `fault-injection` feature
+    FaultInjection,
+    /// Debug build with the `trace` feature
+    Trace,
+}
+
+impl Capability {
+    pub fn available(self, state: &State) -> bool {
+        match self {
+            Self::Iopmp => state.iopmp.is_some(),
+            Self::Rng => state.rng.is_some(),
+            Self::AuditLog => state
+                .domains
+                .get(state.active_domain)
+                .is_some_and(|domain| domain.sbi_policy.audit_log),
+            Self::Storage => state.storage.is_some(),
+            Self::Tsm => state
+                .domains
+                .get(state.active_domain)
+                .is_some_and(|domain| domain.has_tsm),
+            Self::FaultInjection => cfg!(feature = "fault-injection"),
+            Self::Trace => cfg!(feature = "trace"),
+        }
+    }
+}
+
+/// Generate `fn $name(fid, state, args) -> Result<usize, isize>` for the extension `EID` from a
+/// table of `FID => handler(arg0, arg1, ...) [requires Capability],` entries. The arguments are
+/// bound to a0, a1, ... in order and passed to `handler(state, arg0, arg1, ...)`, which returns a
+/// `Result<usize, CallError>`: every error is reported as `SBI_ERR_FAILED`.
+macro_rules! sbi_extension {
+    (
+        $(#[$meta:meta])*
+        $vis:vis fn $name:ident($state:ident: &mut $state_ty:ty) for $eid:path {
+            $($fid:path => $handler:ident($($arg:ident),*) $(requires $cap:ident)?,)*
+        }
+    ) => {
+        $(#[$meta])*
+        $vis fn $name(
+            fid: usize,
+            $state: &mut $state_ty,
+            args: &[usize; 6],
+        ) -> Result<usize, isize> {
+            if !$state.allows_call($eid, fid) {
+                $state.record($crate::audit::AuditEvent::PolicyDenied, $eid, fid);
+                return Err($crate::dispatch::SBI_ERR_DENIED);
+            }
+
+            match fid {
+                $($fid => {
+                    $(
+                        if !$crate::dispatch::Capability::$cap.available($state) {
+                            return Err($crate::dispatch::SBI_ERR_NOT_SUPPORTED);
+                        }
+                    )?
+                    // more than 6 arguments is a compile error
+                    let [$($arg,)* ..] = *args;
+                    $handler($state, $($arg),*).map_err(|_| $crate::dispatch::SBI_ERR_FAILED)
+                })*
+                _ => Err($crate::dispatch::SBI_ERR_NOT_SUPPORTED),
+            }
+        }
+    };
+}
+
+pub(crate) use sbi_extension;
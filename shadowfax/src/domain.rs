@@ -1,136 +1,141 @@
 use alloc::{boxed::Box, vec::Vec};
-use common::attestation::TsmAttestationContext;
+use common::{
+    attestation::{DiceLayer, PlatformAttestationContext, TsmAttestationContext},
+    boot_manifest::{self, BootImage, ManifestError, BOOT_MANIFEST_ADDR},
+    crypto::{self, Sha512},
+    sbi::{ImsicInfo, TsmIdentity, TSM_STATUS_READY},
+};
 use ed25519_compact::Signature;
 use elf::{abi::PT_LOAD, endian::AnyEndian, ElfBytes};
-
-use crate::{constants::memory_layout::TRUSTED_DOMAIN_REGIONS, context::Context, error::TsmError};
+use sha2::Digest;
+
+// The domains themselves are platform independent, see `cove_core::domain`
+pub use cove_core::domain::{
+    napot_split, Domain, DomainInstance, MemoryRegion, RegionTag, SbiPolicy, SbiRule,
+    MAX_MEMORY_REGIONS, MEMREGION_R, MEMREGION_RW, MEMREGION_RWX, PMP_R, PMP_W, PMP_X,
+};
+
+use crate::{
+    audit::{AuditEvent, AuditLog},
+    constants::memory_layout::TRUSTED_DOMAIN_REGIONS,
+    context::Context,
+    context_switch::save_h_csrs,
+    error::TsmError,
+};
 
 mod tsm {
+    #[cfg(feature = "embed-elf")]
     #[link_section = ".rodata"]
     pub static DEFAULT_TSM: &[u8] =
-        include_bytes!("../../target/riscv64imac-unknown-none-elf/debug/tsm");
+        include_bytes!(concat!("../../target/", env!("TARGET"), "/debug/tsm"));
 
+    #[cfg(feature = "embed-elf")]
     #[link_section = ".rodata"]
     pub static DEFAULT_TSM_SIGN: &[u8] = include_bytes!("../../bin/tsm.bin.signature");
 
-    #[link_section = ".rodata"]
-    pub static DEFAULT_TSM_PUBKEY: &[u8] = include_bytes!("../keys/publickey.pem");
-}
-
-#[derive(Clone)]
-pub struct MemoryRegion {
-    pub base_addr: usize,
-    pub order: u32,
-    pub mmio: bool,
-    pub permissions: u8,
+    // Public keys trusted to sign the TSM, see `TSM_TRUSTED_KEYS` in build.rs
+    include!(concat!(env!("OUT_DIR"), "/tsm_keyring.rs"));
 }
 
-#[derive(Clone)]
-pub struct Domain {
-    pub trust_map: usize,
-    pub memory_regions: Vec<MemoryRegion>,
-
-    pub context_addr: usize,
-    pub has_tsm: bool,
-}
-
-impl Domain {
-    pub fn empty() -> Self {
-        Self {
-            trust_map: 0,
-            memory_regions: Vec::new(),
-            context_addr: 0,
-            has_tsm: false,
+/// Loads the TSM elf, verify it's signature with the keys of `keyring` (PEM). Returns the
+/// digests of the binary and of the key which verified it, and the id of that key: its index in
+/// `keyring`.
+pub fn verify_and_load_tsm(
+    bin: &[u8],
+    signature: &[u8],
+    keyring: &[&[u8]],
+) -> Result<(TsmIdentity, usize), TsmError> {
+    let _heap_tag = crate::ALLOCATOR.tag(crate::HEAP_TAG_CRYPTO);
+    let signature = Signature::from_slice(signature).map_err(TsmError::SignatureDecode)?;
+
+    let mut signer = None;
+    for (key_id, public_key) in keyring.iter().enumerate() {
+        let public_key = str::from_utf8(public_key)?;
+        let key = from_public_pem(public_key).map_err(TsmError::PublicKeyDecode)?;
+        if key.verify(bin, &signature).is_ok() {
+            signer = Some((key_id, key));
+            break;
         }
     }
+    let (key_id, verifiying_key) = signer.ok_or(TsmError::UnknownSigner)?;
 
-    /// Loads the TSM elf, verify it's signature
-    pub fn verify_and_load_tsm(
-        bin: &[u8],
-        signature: &[u8],
-        public_key: &[u8],
-    ) -> Result<(), anyhow::Error> {
-        // Verify the tsm signature with the provided payload using the the public key
-        let public_key = str::from_utf8(public_key)?;
+    // load the tsm into the destination address
+    let size = load_elf(bin)?;
 
-        let signature = Signature::from_slice(signature).map_err(TsmError::SignatureDecode)?;
-        let verifiying_key = from_public_pem(public_key).map_err(TsmError::PublicKeyDecode)?;
+    assert!(size > 0);
 
-        verifiying_key
-            .verify(bin, &signature)
-            .map_err(TsmError::SignatureVerification)?;
+    let identity = TsmIdentity {
+        measurement: Sha512::digest(bin).into(),
+        signer_key_id: Sha512::digest(*verifiying_key).into(),
+    };
+    Ok((identity, key_id))
+}
 
-        // load the tsm into the destination address
-        let size = Self::load_elf(bin)?;
+fn load_elf(data: &[u8]) -> Result<usize, TsmError> {
+    let elf = ElfBytes::<AnyEndian>::minimal_parse(data)?;
 
-        assert!(size > 0);
+    let segments = elf.segments().ok_or(TsmError::NoProgramHeaders)?;
 
-        Ok(())
-    }
+    // Collect only loadable segments
+    let load_segments: Vec<_> = segments.iter().filter(|ph| ph.p_type == PT_LOAD).collect();
 
-    pub fn is_trusted(&self, dst: usize) -> bool {
-        self.trust_map & (1 << dst) != 0
+    if load_segments.is_empty() {
+        return Err(TsmError::NoLoadSegments);
     }
 
-    fn load_elf(data: &[u8]) -> anyhow::Result<usize> {
-        let elf = ElfBytes::<AnyEndian>::minimal_parse(data).unwrap();
-
-        let segments = elf
-            .segments()
-            .ok_or_else(|| anyhow::anyhow!("ELF has no program headers"))?;
+    let mut max_loaded_addr = 0usize;
+    let mut min_loaded_addr = usize::MAX;
 
-        // Collect only loadable segments
-        let load_segments: Vec<_> = segments.iter().filter(|ph| ph.p_type == PT_LOAD).collect();
+    // Load each PT_LOAD segment
+    for ph in &load_segments {
+        let p_offset = ph.p_offset as usize;
+        let p_filesz = ph.p_filesz as usize;
+        let p_vaddr = ph.p_vaddr as usize;
+        let p_memsz = ph.p_memsz as usize;
 
-        if load_segments.is_empty() {
-            return Err(anyhow::anyhow!("No loadable segments found"));
+        // Bounds check
+        if p_offset + p_filesz > data.len() {
+            return Err(TsmError::SegmentOutOfBounds);
         }
 
-        let mut max_loaded_addr = 0usize;
-        let mut min_loaded_addr = usize::MAX;
-
-        // Load each PT_LOAD segment
-        for ph in &load_segments {
-            let p_offset = ph.p_offset as usize;
-            let p_filesz = ph.p_filesz as usize;
-            let p_vaddr = ph.p_vaddr as usize;
-            let p_memsz = ph.p_memsz as usize;
-
-            // Bounds check
-            if p_offset + p_filesz > data.len() {
-                return Err(anyhow::anyhow!("Segment data out of bounds"));
-            }
-
-            // Copy data into memory (dangerous — assumes addresses are valid)
-            if p_filesz > 0 {
-                let src = &data[p_offset..p_offset + p_filesz];
-                unsafe {
-                    core::ptr::copy_nonoverlapping(src.as_ptr(), p_vaddr as *mut u8, p_filesz);
-                }
+        // Copy data into memory (dangerous — assumes addresses are valid)
+        if p_filesz > 0 {
+            let src = &data[p_offset..p_offset + p_filesz];
+            unsafe {
+                core::ptr::copy_nonoverlapping(src.as_ptr(), p_vaddr as *mut u8, p_filesz);
             }
+        }
 
-            // Zero-fill .bss section
-            if p_memsz > p_filesz {
-                let bss_start = (p_vaddr + p_filesz) as *mut u8;
-                let bss_len = p_memsz - p_filesz;
-                unsafe {
-                    core::ptr::write_bytes(bss_start, 0, bss_len);
-                }
+        // Zero-fill .bss section
+        if p_memsz > p_filesz {
+            let bss_start = (p_vaddr + p_filesz) as *mut u8;
+            let bss_len = p_memsz - p_filesz;
+            unsafe {
+                core::ptr::write_bytes(bss_start, 0, bss_len);
             }
-
-            // Track memory range
-            min_loaded_addr = min_loaded_addr.min(p_vaddr);
-            max_loaded_addr = max_loaded_addr.max(p_vaddr + p_memsz);
         }
 
-        // Return total size loaded in memory
-        Ok(max_loaded_addr - min_loaded_addr)
+        // Track memory range
+        min_loaded_addr = min_loaded_addr.min(p_vaddr);
+        max_loaded_addr = max_loaded_addr.max(p_vaddr + p_memsz);
     }
+
+    // Return total size loaded in memory
+    Ok(max_loaded_addr - min_loaded_addr)
 }
 
+/// Create the domain of the TSM. The attestation context of the TSM is derived from the platform
+/// one with the digest of the verified binary. If the TSM signature is not valid, or there is no
+/// attestation context, the TSM is not started and the domain does not accept TEECALLs. The TSM
+/// is loaded once verified, and ready once its `_secure_init` reports so: until then TEECALLs
+/// fail with `SBI_ERR_NOT_READY`. All outcomes are recorded in the audit log.
 pub fn create_confidential_domain(
+    id: usize,
     context_addr: usize,
-    attestation_context: TsmAttestationContext,
+    platform_context: Option<&PlatformAttestationContext>,
+    imsic: Option<ImsicInfo>,
+    h_extension: bool,
+    audit: &mut AuditLog,
 ) -> Domain {
     // Assume that the specified domain is a trusted domain -> need to load the TSM in it
     // TODO: parse domain from FDT
@@ -140,7 +145,7 @@ pub fn create_confidential_domain(
     // Trust both root and untrusted domains
     domain.trust_map = (1 << 2) | (1 << 0);
 
-    // Hardcoded memory regions for now
+    // The TSM memory, the devices are granted from the device table
     domain.memory_regions = TRUSTED_DOMAIN_REGIONS.to_vec();
 
     // Save the context address and the state address
@@ -162,23 +167,89 @@ pub fn create_confidential_domain(
         (*tsm_ctx).mepc = tmem_region.base_addr;
     }
 
-    Domain::verify_and_load_tsm(
-        tsm::DEFAULT_TSM,
-        tsm::DEFAULT_TSM_SIGN,
-        tsm::DEFAULT_TSM_PUBKEY,
-    )
-    .unwrap();
+    let Some((tsm_bin, tsm_sign)) = tsm_image() else {
+        debug!("no TSM image");
+        audit.record(AuditEvent::TsmRejected, id, 0, 0);
+        domain.has_tsm = false;
+        return domain;
+    };
+    let verified = verify_and_load_tsm(tsm_bin, tsm_sign, tsm::TSM_KEYRING);
+    let (event, key_id) = match verified {
+        Ok((_, key_id)) => (AuditEvent::TsmVerified, key_id),
+        Err(_) => (AuditEvent::TsmRejected, 0),
+    };
+    audit.record(event, id, tsm_bin.len(), key_id);
+
+    let identity = match verified {
+        Ok((identity, key_id)) => {
+            debug!("TSM signed by key {}", key_id);
+            domain.tsm_identity = Some(identity);
+            identity
+        }
+        Err(e) => {
+            debug!("cannot load the TSM: {}", e);
+            domain.has_tsm = false;
+            return domain;
+        }
+    };
+
+    let Some(platform_context) = platform_context else {
+        audit.record(AuditEvent::AttestationUnavailable, id, 0, 0);
+        domain.has_tsm = false;
+        return domain;
+    };
+    let attestation_context = platform_context.compute_next(&identity.measurement);
+
+    // `_secure_init` runs in M-mode: its code is locked first, see `wx.rs`
+    #[cfg(feature = "firmware-wx")]
+    if let Err(e) = crate::wx::lock_tsm(tsm_bin) {
+        debug!("TSM W^X not enforced: {}", e);
+    }
 
     // Boot and initialize secure_init safely
-    boot_tsm(attestation_context);
+    domain.tsm_ready = boot_tsm(tsm_bin, attestation_context, identity, imsic, h_extension);
+    // The TSM starts from the hypervisor CSRs left by its initialization
+    save_h_csrs(unsafe { &mut *tsm_ctx });
+    if domain.tsm_ready {
+        audit.record(AuditEvent::TsmReady, id, 0, 0);
+    } else {
+        debug!("the TSM did not complete its initialization");
+    }
 
     return domain;
 }
 
-/// This function looks for the _secure_init symbol and invoke it as a function
-fn boot_tsm(attestation_context: TsmAttestationContext) {
+/// The TSM image and its signature: the `tsm` entry of the boot manifest, else the embedded image.
+fn tsm_image() -> Option<(&'static [u8], &'static [u8])> {
+    match unsafe { boot_manifest::find(BOOT_MANIFEST_ADDR, "tsm") } {
+        Ok(BootImage {
+            data,
+            signature: Some(signature),
+        }) => return Some((data, signature)),
+        Ok(_) => debug!("the TSM of the boot manifest is not signed"),
+        Err(ManifestError::NoManifest) => {}
+        Err(e) => debug!("no TSM in the boot manifest: {:?}", e),
+    }
+
+    #[cfg(feature = "embed-elf")]
+    return Some((tsm::DEFAULT_TSM, tsm::DEFAULT_TSM_SIGN));
+    #[cfg(not(feature = "embed-elf"))]
+    None
+}
+
+/// This function looks for the _secure_init symbol of `bin` and invoke it as a function. The IMSIC
+/// description is passed by address (0 if the platform has no AIA), followed by whether the harts
+/// have the hypervisor extension, the address of the TSM identity and the crypto features of the
+/// harts (see `common::crypto`). Returns whether the TSM reported `TSM_STATUS_READY`.
+fn boot_tsm(
+    bin: &[u8],
+    attestation_context: TsmAttestationContext,
+    identity: TsmIdentity,
+    imsic: Option<ImsicInfo>,
+    h_extension: bool,
+) -> bool {
     // parse ELF
-    let elf = ElfBytes::<AnyEndian>::minimal_parse(tsm::DEFAULT_TSM).unwrap();
+    let elf = ElfBytes::<AnyEndian>::minimal_parse(bin).unwrap();
 
     // get static symbol table instead of dynsym
     let (symtab, strtab) = elf
@@ -201,12 +272,35 @@ fn boot_tsm(attestation_context: TsmAttestationContext) {
 
     let sym = found.expect("cannot find _secure_init");
 
+    // The TSM copies the attestation context in its own heap during _secure_init
+    let _heap_tag = crate::ALLOCATOR.tag(crate::HEAP_TAG_TSM);
     let boxed = Box::new(attestation_context);
     let addr = Box::into_raw(boxed) as usize;
+    let imsic_addr = imsic.map_or(0, |imsic| Box::into_raw(Box::new(imsic)) as usize);
+    let identity_addr = Box::into_raw(Box::new(identity)) as usize;
     unsafe {
         // Reinterpret the address as a function
-        let secure_init_fn = core::mem::transmute::<u64, fn(addr: usize)>(sym.st_value);
-        secure_init_fn(addr);
+        let secure_init_fn = core::mem::transmute::<
+            usize,
+            fn(
+                addr: usize,
+                imsic_addr: usize,
+                h_extension: usize,
+                identity_addr: usize,
+                crypto_features: usize,
+            ) -> usize,
+        >(sym.st_value as usize);
+        let status = secure_init_fn(
+            addr,
+            imsic_addr,
+            h_extension as usize,
+            identity_addr,
+            crypto::features(),
+        );
+        // Wipe the CDI of the TSM from the firmware heap
+        drop(Box::from_raw(addr as *mut TsmAttestationContext));
+        drop(Box::from_raw(identity_addr as *mut TsmIdentity));
+        status == TSM_STATUS_READY as usize
     }
 }
 
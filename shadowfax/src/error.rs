@@ -1,10 +1,18 @@
+use alloc::string::String;
+use common::drbg::DrbgError;
 use core::{error::Error, fmt::Display};
+use cove_core::GrantError;
 
 #[derive(Debug)]
 pub enum TsmError {
     PublicKeyDecode(ed25519_compact::Error),
     SignatureDecode(ed25519_compact::Error),
-    SignatureVerification(ed25519_compact::Error),
+    UnknownSigner,
+    PublicKeyEncoding(core::str::Utf8Error),
+    ElfParse(elf::ParseError),
+    NoProgramHeaders,
+    NoLoadSegments,
+    SegmentOutOfBounds,
 }
 
 impl Display for TsmError {
@@ -12,9 +20,436 @@ impl Display for TsmError {
         match self {
             Self::PublicKeyDecode(err) => write!(f, "public key format error: {}", err),
             Self::SignatureDecode(err) => write!(f, "signature format error: {}", err),
-            Self::SignatureVerification(err) => write!(f, "signature verification error: {}", err),
+            Self::UnknownSigner => write!(f, "no trusted key verifies the signature"),
+            Self::PublicKeyEncoding(err) => write!(f, "public key is not UTF-8: {}", err),
+            Self::ElfParse(err) => write!(f, "ELF parse error: {}", err),
+            Self::NoProgramHeaders => write!(f, "ELF has no program headers"),
+            Self::NoLoadSegments => write!(f, "no loadable segments found"),
+            Self::SegmentOutOfBounds => write!(f, "segment data out of bounds"),
         }
     }
 }
 
 impl Error for TsmError {}
+
+impl From<core::str::Utf8Error> for TsmError {
+    fn from(err: core::str::Utf8Error) -> Self {
+        Self::PublicKeyEncoding(err)
+    }
+}
+
+impl From<elf::ParseError> for TsmError {
+    fn from(err: elf::ParseError) -> Self {
+        Self::ElfParse(err)
+    }
+}
+
+/// Call of a domain rejected by the firmware: a SUPD call, or the checks of a CoVE call. The SUPD
+/// calls report all of them as SBI_ERR_FAILED (see `dispatch::sbi_extension`).
+#[derive(Debug)]
+pub enum CallError {
+    UnknownDomain(usize),
+    CannotTrust(usize),
+    Stopped(usize),
+    NoTrustOffer {
+        src: usize,
+        dst: usize,
+    },
+    CannotRevokeTrust(usize),
+    NoMatchingBlock,
+    /// `[addr, addr + size)` is not memory of the caller
+    NotCallerMemory {
+        addr: usize,
+        size: usize,
+    },
+    BufferTooSmall(usize),
+    /// Size of the call buffer in a0-a5
+    BufferSize(usize),
+    BufferNotConfidential(usize),
+    BufferNotCallerMemory(usize),
+    NoBufferEntries,
+    Grant(GrantError),
+    InvalidDmaRegion {
+        base_addr: usize,
+        size: usize,
+    },
+    ForeignDmaRegion {
+        base_addr: usize,
+        size: usize,
+        domain: usize,
+    },
+    NoIopmp,
+    Iopmp(IopmpError),
+    Drbg(DrbgError),
+    InvalidCounterId,
+    NoFreeCounter,
+    CounterExhausted,
+    Storage(StorageError),
+    NoBlob,
+    BlobTooLarge(usize),
+    CannotSnapshot(usize),
+    TooManySnapshotRanges(usize),
+    ConfidentialRange {
+        addr: usize,
+        size: usize,
+    },
+    SnapshotTooLarge,
+    NoSnapshot(usize),
+    UnknownFault(usize),
+    UnknownHeapStat(usize),
+    KeyLabelTooLong(usize),
+    AttestationUnavailable,
+    InvalidKeyLabel,
+}
+
+impl Display for CallError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::UnknownDomain(id) => write!(f, "unknown domain {}", id),
+            Self::CannotTrust(id) => write!(f, "cannot trust domain {}", id),
+            Self::Stopped(id) => write!(f, "domain {} is shut down", id),
+            Self::NoTrustOffer { src, dst } => {
+                write!(f, "domain {} offered no trust to domain {}", src, dst)
+            }
+            Self::CannotRevokeTrust(id) => write!(f, "cannot revoke the trust of domain {}", id),
+            Self::NoMatchingBlock => write!(f, "no matching memory block"),
+            Self::NotCallerMemory { addr, size } => write!(
+                f,
+                "{:#x} ({:#x} bytes) is not memory of the caller",
+                addr, size
+            ),
+            Self::BufferTooSmall(size) => write!(f, "buffer too small ({} bytes needed)", size),
+            Self::BufferSize(reg) => write!(f, "invalid size of the buffer in a{}", reg),
+            Self::BufferNotConfidential(reg) => {
+                write!(f, "buffer in a{} is not confidential memory", reg)
+            }
+            Self::BufferNotCallerMemory(reg) => {
+                write!(f, "buffer in a{} is not memory of the caller", reg)
+            }
+            Self::NoBufferEntries => write!(f, "no PMP entry left for the buffers"),
+            Self::Grant(err) => write!(f, "{}", err),
+            Self::InvalidDmaRegion { base_addr, size } => {
+                write!(f, "invalid DMA region {:#x} ({:#x} bytes)", base_addr, size)
+            }
+            Self::ForeignDmaRegion {
+                base_addr,
+                size,
+                domain,
+            } => write!(
+                f,
+                "DMA region {:#x} ({:#x} bytes) is not memory of domain {}",
+                base_addr, size, domain
+            ),
+            Self::NoIopmp => write!(f, "no IOPMP"),
+            Self::Iopmp(err) => write!(f, "{}", err),
+            Self::Drbg(err) => write!(f, "drbg error: {:?}", err),
+            Self::InvalidCounterId => write!(f, "invalid counter id"),
+            Self::NoFreeCounter => write!(f, "no free counter"),
+            Self::CounterExhausted => write!(f, "counter exhausted"),
+            Self::Storage(err) => write!(f, "{}", err),
+            Self::NoBlob => write!(f, "no such blob"),
+            Self::BlobTooLarge(size) => write!(f, "blob too large ({} bytes)", size),
+            Self::CannotSnapshot(id) => write!(f, "domain {} cannot be snapshotted", id),
+            Self::TooManySnapshotRanges(count) => {
+                write!(f, "too many snapshot ranges ({})", count)
+            }
+            Self::ConfidentialRange { addr, size } => {
+                write!(f, "range {:#x} ({:#x} bytes) is confidential", addr, size)
+            }
+            Self::SnapshotTooLarge => write!(f, "snapshot larger than the snapshot area"),
+            Self::NoSnapshot(id) => write!(f, "no snapshot of domain {}", id),
+            Self::UnknownFault(fault) => write!(f, "unknown fault {}", fault),
+            Self::UnknownHeapStat(index) => write!(f, "invalid heap statistic {}", index),
+            Self::KeyLabelTooLong(size) => write!(f, "key label too long ({} bytes)", size),
+            Self::AttestationUnavailable => write!(f, "attestation unavailable"),
+            Self::InvalidKeyLabel => write!(f, "invalid key label"),
+        }
+    }
+}
+
+impl Error for CallError {}
+
+impl From<GrantError> for CallError {
+    fn from(err: GrantError) -> Self {
+        Self::Grant(err)
+    }
+}
+
+impl From<IopmpError> for CallError {
+    fn from(err: IopmpError) -> Self {
+        Self::Iopmp(err)
+    }
+}
+
+impl From<DrbgError> for CallError {
+    fn from(err: DrbgError) -> Self {
+        Self::Drbg(err)
+    }
+}
+
+impl From<StorageError> for CallError {
+    fn from(err: StorageError) -> Self {
+        Self::Storage(err)
+    }
+}
+
+/// IOPMP entries not changed, see `iopmp.rs`
+#[derive(Debug)]
+pub enum IopmpError {
+    NotProtected,
+    ConfidentialGrant,
+    NoMatchingGrant,
+    NoEntries,
+    NotNapot,
+}
+
+impl Display for IopmpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::NotProtected => write!(f, "region is not protected"),
+            Self::ConfidentialGrant => write!(f, "cannot grant DMA access to confidential memory"),
+            Self::NoMatchingGrant => write!(f, "no matching grant"),
+            Self::NoEntries => write!(f, "not enough IOPMP entries"),
+            Self::NotNapot => write!(f, "IOPMP region is not NAPOT"),
+        }
+    }
+}
+
+impl Error for IopmpError {}
+
+/// Blob not stored, see `storage.rs`
+#[derive(Debug)]
+pub enum StorageError {
+    Flash { status: u32, offset: usize },
+    InvalidBlob(usize),
+    Full,
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Flash { status, offset } => {
+                write!(
+                    f,
+                    "flash error, status {:#x} at offset {:#x}",
+                    status, offset
+                )
+            }
+            Self::InvalidBlob(size) => write!(f, "invalid blob ({} bytes)", size),
+            Self::Full => write!(f, "blob storage full"),
+        }
+    }
+}
+
+impl Error for StorageError {}
+
+/// Platform or domain configuration the firmware cannot boot with, see `state::init`
+#[derive(Debug)]
+pub enum ConfigError {
+    TooManyDomains {
+        declared: usize,
+        max: usize,
+    },
+    TeeRamTooSmall {
+        domains: usize,
+        harts: usize,
+        needed: usize,
+        reserved: usize,
+    },
+    TooManyInstances {
+        instances: usize,
+        domains: usize,
+    },
+    ForeignRegion {
+        base_addr: usize,
+        instance: String,
+        domain: usize,
+    },
+    InvalidTrustMap {
+        trust_map: usize,
+        instance: String,
+    },
+    UnknownPolicyDomain(usize),
+    DeviceNotNapot {
+        base_addr: usize,
+        size: usize,
+    },
+    UnknownDeviceDomain {
+        base_addr: usize,
+        domain: usize,
+    },
+    DeviceAssignedTwice {
+        base_addr: usize,
+        domain: usize,
+    },
+    DeviceConflict {
+        base_addr: usize,
+        first: usize,
+        second: usize,
+    },
+    DeviceGrant {
+        domain: usize,
+        err: GrantError,
+    },
+    Iopmp(IopmpError),
+    #[cfg(feature = "fdt")]
+    Fdt(FdtError),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::TooManyDomains { declared, max } => write!(
+                f,
+                "{} domains declared, the firmware is built for {}",
+                declared, max
+            ),
+            Self::TeeRamTooSmall {
+                domains,
+                harts,
+                needed,
+                reserved,
+            } => write!(
+                f,
+                "TEE RAM of {} domains and {} harts needs {:#x} bytes, {:#x} reserved",
+                domains, harts, needed, reserved
+            ),
+            Self::TooManyInstances { instances, domains } => write!(
+                f,
+                "{} domain instances for {} supervisor domains",
+                instances, domains
+            ),
+            Self::ForeignRegion {
+                base_addr,
+                instance,
+                domain,
+            } => write!(
+                f,
+                "region {:#x} of {} is not memory of domain {}",
+                base_addr, instance, domain
+            ),
+            Self::InvalidTrustMap {
+                trust_map,
+                instance,
+            } => write!(f, "invalid trust map {:#x} of {}", trust_map, instance),
+            Self::UnknownPolicyDomain(id) => write!(f, "SBI policy for unknown domain {}", id),
+            Self::DeviceNotNapot { base_addr, size } => write!(
+                f,
+                "device at {:#x} ({:#x} bytes) is not a NAPOT region",
+                base_addr, size
+            ),
+            Self::UnknownDeviceDomain { base_addr, domain } => write!(
+                f,
+                "device at {:#x} assigned to unknown domain {}",
+                base_addr, domain
+            ),
+            Self::DeviceAssignedTwice { base_addr, domain } => write!(
+                f,
+                "device at {:#x} assigned twice to domain {}",
+                base_addr, domain
+            ),
+            Self::DeviceConflict {
+                base_addr,
+                first,
+                second,
+            } => write!(
+                f,
+                "device at {:#x} claimed by domains {} and {}, exclusively by one of them",
+                base_addr, first, second
+            ),
+            Self::DeviceGrant { domain, err } => write!(f, "domain {}: {}", domain, err),
+            Self::Iopmp(err) => write!(f, "{}", err),
+            #[cfg(feature = "fdt")]
+            Self::Fdt(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl From<IopmpError> for ConfigError {
+    fn from(err: IopmpError) -> Self {
+        Self::Iopmp(err)
+    }
+}
+
+#[cfg(feature = "fdt")]
+impl From<FdtError> for ConfigError {
+    fn from(err: FdtError) -> Self {
+        Self::Fdt(err)
+    }
+}
+
+/// Device tree the firmware cannot read, or cannot derive a domain view from, see `fdt.rs`
+#[cfg(feature = "fdt")]
+#[derive(Debug)]
+pub enum FdtError {
+    Invalid(usize),
+    AssignmentWithoutDomain,
+    InvalidPhandle,
+    NoDevice(u32),
+    InstanceWithoutName,
+    NoCpu { instance: String, phandle: u32 },
+    NoMemregion { instance: String, phandle: u32 },
+    MemregionWithoutRange { instance: String, phandle: u32 },
+    ViewTooLarge { domain: usize, size: usize },
+}
+
+#[cfg(feature = "fdt")]
+impl Display for FdtError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Invalid(addr) => write!(f, "invalid device tree at {:#x}", addr),
+            Self::AssignmentWithoutDomain => write!(f, "device assignment without a domain"),
+            Self::InvalidPhandle => write!(f, "invalid phandle"),
+            Self::NoDevice(phandle) => write!(f, "no MMIO device with phandle {:#x}", phandle),
+            Self::InstanceWithoutName => write!(f, "domain instance without a name"),
+            Self::NoCpu { instance, phandle } => {
+                write!(f, "{}: no cpu with phandle {:#x}", instance, phandle)
+            }
+            Self::NoMemregion { instance, phandle } => {
+                write!(f, "{}: no memregion with phandle {:#x}", instance, phandle)
+            }
+            Self::MemregionWithoutRange { instance, phandle } => write!(
+                f,
+                "{}: memregion {:#x} without a base or an order",
+                instance, phandle
+            ),
+            Self::ViewTooLarge { domain, size } => write!(
+                f,
+                "device tree of domain {} ({} bytes) does not fit in its memory",
+                domain, size
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "fdt")]
+impl Error for FdtError {}
+
+/// Code not locked read/execute, see `wx.rs`
+#[cfg(feature = "firmware-wx")]
+#[derive(Debug)]
+pub enum LockError {
+    Elf(elf::ParseError),
+    NoProgramHeaders,
+    NoEntries { needed: usize, left: usize },
+    Locked(usize),
+}
+
+#[cfg(feature = "firmware-wx")]
+impl Display for LockError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Elf(err) => write!(f, "invalid TSM ELF: {}", err),
+            Self::NoProgramHeaders => write!(f, "no program headers in the TSM ELF"),
+            Self::NoEntries { needed, left } => {
+                write!(f, "{} PMP entries needed, {} left", needed, left)
+            }
+            Self::Locked(index) => write!(f, "PMP entry {} cannot be locked", index),
+        }
+    }
+}
+
+#[cfg(feature = "firmware-wx")]
+impl Error for LockError {}
@@ -0,0 +1,561 @@
+/*
+ * Device tree helpers. The firmware receives the FDT address from the previous boot stage and uses
+ * it to discover platform devices it has to manage (e.g. the IMSIC for AIA, the IOPMP).
+ *
+ * All helpers assume `#address-cells = <2>` and `#size-cells = <2>` as in the platform device
+ * trees shipped in `shadowfax/platform`.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use alloc::{format, string::String, vec::Vec};
+use common::{
+    crypto,
+    fdt::{is_compatible, read_cells, Reader, Token, Writer},
+    sbi::ImsicInfo,
+};
+use fdt_rs::{
+    base::{DevTree, DevTreeNode},
+    prelude::*,
+};
+
+use crate::{
+    devices::DeviceAssignment,
+    domain::{DomainInstance, MemoryRegion, SbiPolicy, SbiRule, MEMREGION_RWX},
+    error::FdtError,
+};
+
+/// Parse the device tree located at `fdt_addr`.
+fn parse<'dt>(fdt_addr: usize) -> Option<DevTree<'dt>> {
+    if fdt_addr == 0 {
+        return None;
+    }
+    unsafe { DevTree::from_raw_pointer(fdt_addr as *const u8).ok() }
+}
+
+/// Look for a property called `name` in `node`.
+fn find_prop<'a, 'dt>(
+    node: &DevTreeNode<'a, 'dt>,
+    name: &str,
+) -> Option<fdt_rs::base::DevTreeProp<'a, 'dt>> {
+    let mut props = node.props();
+    while let Ok(Some(prop)) = props.next() {
+        if prop.name().ok()? == name {
+            return Some(prop);
+        }
+    }
+    None
+}
+
+/// Read the first `reg` entry (base, size) of `node`.
+fn read_reg(node: &DevTreeNode) -> Option<(usize, usize)> {
+    let reg = find_prop(node, "reg")?;
+    let base = reg.u64(0).ok()? as usize;
+    let size = reg.u64(1).ok()? as usize;
+    Some((base, size))
+}
+
+/// Read a `u32` property of `node`.
+fn read_u32(node: &DevTreeNode, name: &str) -> Option<u32> {
+    find_prop(node, name)?.u32(0).ok()
+}
+
+/// Find the IOPMP (`riscv,iopmp`) and return its register base address.
+pub fn find_iopmp(fdt_addr: usize) -> Option<usize> {
+    let fdt = parse(fdt_addr)?;
+    let node = fdt.compatible_nodes("riscv,iopmp").next().ok()??;
+    read_reg(&node).map(|(base_addr, _)| base_addr)
+}
+
+/// Find the platform TRNG (`timeriomem_rng`) and return the address of its data register.
+pub fn find_trng(fdt_addr: usize) -> Option<usize> {
+    let fdt = parse(fdt_addr)?;
+    let node = fdt.compatible_nodes("timeriomem_rng").next().ok()??;
+    read_reg(&node).map(|(base_addr, _)| base_addr)
+}
+
+/// Find the CFI flash (`cfi-flash`) and return the base address and size of its last bank, with
+/// the bank width.
+pub fn find_flash(fdt_addr: usize) -> Option<(usize, usize, u32)> {
+    let fdt = parse(fdt_addr)?;
+    let node = fdt.compatible_nodes("cfi-flash").next().ok()??;
+    let reg = find_prop(&node, "reg")?;
+    let last = (reg.length() / 16).checked_sub(1)?;
+    let base_addr = reg.u64(2 * last).ok()? as usize;
+    let size = reg.u64(2 * last + 1).ok()? as usize;
+    Some((base_addr, size, read_u32(&node, "bank-width")?))
+}
+
+/// Find the supervisor-level IMSIC (`riscv,imsics`). The machine-level IMSIC has no guest
+/// interrupt files and therefore does not declare `riscv,guest-index-bits`.
+pub fn find_imsic(fdt_addr: usize) -> Option<ImsicInfo> {
+    let fdt = parse(fdt_addr)?;
+    let mut nodes = fdt.compatible_nodes("riscv,imsics");
+
+    while let Ok(Some(node)) = nodes.next() {
+        let guest_index_bits = match read_u32(&node, "riscv,guest-index-bits") {
+            Some(bits) => bits as usize,
+            None => continue,
+        };
+        let (base_addr, size) = read_reg(&node)?;
+        let num_ids = read_u32(&node, "riscv,num-ids").unwrap_or(0) as usize;
+
+        return Some(ImsicInfo {
+            base_addr,
+            size,
+            guest_index_bits,
+            num_ids,
+        });
+    }
+    None
+}
+
+/// Find the SBI policies (`shadowfax,sbi-policy`) and return them with the id of the domain they
+/// apply to (its position in the domain list). A policy node has:
+///  - `domain`: the domain id;
+///  - `default-deny` (optional): deny the calls no rule matches, they are allowed otherwise;
+///  - `allow`, `deny` (optional): `<eid fid>` pairs, fid 0xffffffff matches every function;
+///  - `no-platform-reset` (optional): the SRST calls of the domain do not reset the platform.
+pub fn find_sbi_policies(fdt_addr: usize) -> Vec<(usize, SbiPolicy)> {
+    let mut policies = Vec::new();
+    let Some(fdt) = parse(fdt_addr) else {
+        return policies;
+    };
+
+    let mut nodes = fdt.compatible_nodes("shadowfax,sbi-policy");
+    while let Ok(Some(node)) = nodes.next() {
+        let Some(domain) = read_u32(&node, "domain") else {
+            continue;
+        };
+        let mut policy = SbiPolicy {
+            default_allow: find_prop(&node, "default-deny").is_none(),
+            rules: Vec::new(),
+            audit_log: find_prop(&node, "audit-log").is_some(),
+            platform_reset: find_prop(&node, "no-platform-reset").is_none(),
+        };
+
+        for (name, allow) in [("allow", true), ("deny", false)] {
+            let Some(prop) = find_prop(&node, name) else {
+                continue;
+            };
+            for i in 0..prop.length() / 8 {
+                let (Ok(eid), Ok(fid)) = (prop.u32(2 * i), prop.u32(2 * i + 1)) else {
+                    break;
+                };
+                policy.rules.push(SbiRule {
+                    eid: eid as usize,
+                    fid: fid as usize,
+                    allow,
+                });
+            }
+        }
+        policies.push((domain as usize, policy));
+    }
+    policies
+}
+
+/// Find the node with the given `phandle`.
+fn find_phandle<'a, 'dt>(fdt: &'a DevTree<'dt>, phandle: u32) -> Option<DevTreeNode<'a, 'dt>> {
+    let mut nodes = fdt.nodes();
+    while let Ok(Some(node)) = nodes.next() {
+        if read_u32(&node, "phandle") == Some(phandle) {
+            return Some(node);
+        }
+    }
+    None
+}
+
+/// Find the MMIO devices assigned to a domain (`shadowfax,device-assignment`). An assignment node
+/// has:
+///  - `domain`: the id of the owner domain;
+///  - `devices`: phandles of the devices, the first `reg` entry of each is assigned;
+///  - `shared` (optional): other domains can claim the devices too, they are exclusive otherwise;
+///  - `console-mux` (optional): the other domains write on the console UART through the firmware.
+/// The claims are checked against each other by `DeviceTable`.
+pub fn find_device_assignments(fdt_addr: usize) -> Result<Vec<DeviceAssignment>, FdtError> {
+    let mut assignments = Vec::new();
+    let Some(fdt) = parse(fdt_addr) else {
+        return Ok(assignments);
+    };
+
+    let mut nodes = fdt.compatible_nodes("shadowfax,device-assignment");
+    while let Ok(Some(node)) = nodes.next() {
+        let domain = read_u32(&node, "domain").ok_or(FdtError::AssignmentWithoutDomain)?;
+        let exclusive = find_prop(&node, "shared").is_none();
+        let console_mux = find_prop(&node, "console-mux").is_some();
+        let Some(devices) = find_prop(&node, "devices") else {
+            continue;
+        };
+
+        for i in 0..devices.length() / 4 {
+            let phandle = devices.u32(i).map_err(|_| FdtError::InvalidPhandle)?;
+            let (base_addr, size) = find_phandle(&fdt, phandle)
+                .as_ref()
+                .and_then(read_reg)
+                .ok_or(FdtError::NoDevice(phandle))?;
+            assignments.push(DeviceAssignment {
+                domain: domain as usize,
+                base_addr,
+                size,
+                exclusive,
+                console_mux,
+            });
+        }
+    }
+    Ok(assignments)
+}
+
+/// Count the supervisor domains declared in the device tree (`opensbi,domain,instance`), the root
+/// domain excluded.
+pub fn count_domains(fdt_addr: usize) -> usize {
+    let Some(fdt) = parse(fdt_addr) else {
+        return 0;
+    };
+    let mut count = 0;
+    let mut nodes = fdt.compatible_nodes("opensbi,domain,instance");
+    while let Ok(Some(_)) = nodes.next() {
+        count += 1;
+    }
+    count
+}
+
+/// Read the first `u64` (two cells) of the property `name` of `node`.
+fn read_u64(node: &DevTreeNode, name: &str) -> Option<u64> {
+    find_prop(node, name)?.u64(0).ok()
+}
+
+/// Hart id of the `cpu` node with the given `phandle`.
+fn find_hartid(fdt: &DevTree, phandle: u32) -> Option<usize> {
+    find_phandle(fdt, phandle)
+        .as_ref()
+        .and_then(|cpu| read_u32(cpu, "reg"))
+        .map(|hartid| hartid as usize)
+}
+
+/// Find the supervisor domains declared in the device tree (`opensbi,domain,instance`), in order:
+/// the n-th instance describes domain n (the root domain has none). An instance has:
+///  - `possible-harts`: phandles of the `cpu` nodes the domain can run on;
+///  - `regions`: `<memregion flags>` pairs, a memregion (`opensbi,domain,memregion`) has a `base`,
+///    an `order` and is `mmio` or memory;
+///  - `next-addr`, `next-arg1`, `next-mode` (optional): how the domain is booted;
+///  - `shadowfax,bootargs` (optional): the `bootargs` of the device tree view of the domain;
+///  - `shadowfax,trust-map` (optional): the domains it trusts at boot, one bit per domain id.
+pub fn find_domain_instances(fdt_addr: usize) -> Result<Vec<DomainInstance>, FdtError> {
+    let mut instances = Vec::new();
+    let Some(fdt) = parse(fdt_addr) else {
+        return Ok(instances);
+    };
+
+    let mut nodes = fdt.compatible_nodes("opensbi,domain,instance");
+    while let Ok(Some(node)) = nodes.next() {
+        let name = node.name().map_err(|_| FdtError::InstanceWithoutName)?;
+        let mut instance = DomainInstance {
+            name: String::from(name),
+            possible_harts: Vec::new(),
+            regions: Vec::new(),
+            next_addr: read_u64(&node, "next-addr").unwrap_or(0) as usize,
+            next_arg1: read_u64(&node, "next-arg1").unwrap_or(0) as usize,
+            next_mode: read_u32(&node, "next-mode").unwrap_or(1) as usize,
+            bootargs: find_prop(&node, "shadowfax,bootargs")
+                .and_then(|p| p.str().ok())
+                .map(String::from),
+            trust_map: read_u32(&node, "shadowfax,trust-map").map(|map| map as usize),
+        };
+
+        if let Some(harts) = find_prop(&node, "possible-harts") {
+            for i in 0..harts.length() / 4 {
+                let phandle = harts.u32(i).map_err(|_| FdtError::InvalidPhandle)?;
+                let hartid = find_hartid(&fdt, phandle).ok_or_else(|| FdtError::NoCpu {
+                    instance: String::from(name),
+                    phandle,
+                })?;
+                instance.possible_harts.push(hartid);
+            }
+        }
+
+        if let Some(regions) = find_prop(&node, "regions") {
+            for i in 0..regions.length() / 8 {
+                let (Ok(phandle), Ok(flags)) = (regions.u32(2 * i), regions.u32(2 * i + 1)) else {
+                    break;
+                };
+                let memregion =
+                    find_phandle(&fdt, phandle).ok_or_else(|| FdtError::NoMemregion {
+                        instance: String::from(name),
+                        phandle,
+                    })?;
+                let (Some(base), Some(order)) =
+                    (read_u64(&memregion, "base"), read_u32(&memregion, "order"))
+                else {
+                    return Err(FdtError::MemregionWithoutRange {
+                        instance: String::from(name),
+                        phandle,
+                    });
+                };
+                instance.regions.push(MemoryRegion {
+                    base_addr: base as usize,
+                    order,
+                    mmio: find_prop(&memregion, "mmio").is_some(),
+                    permissions: flags as u8 & MEMREGION_RWX,
+                });
+            }
+        }
+        instances.push(instance);
+    }
+    Ok(instances)
+}
+
+/// What a domain sees of the platform, see `domain_view`.
+pub struct FdtView<'a> {
+    /// RAM of the domain, `(base, size)`
+    pub memory: &'a [(usize, usize)],
+    /// MMIO ranges assigned to the other domains
+    pub hidden: &'a [(usize, usize)],
+    /// Replace the `bootargs` of the platform
+    pub bootargs: Option<&'a str>,
+}
+
+/// Nodes only the firmware reads
+const FIRMWARE_NODES: [&str; 3] = [
+    "opensbi,domain,config",
+    "shadowfax,device-assignment",
+    "shadowfax,sbi-policy",
+];
+
+/// Build the device tree a domain boots with from the one at `fdt_addr`: the configuration of the
+/// firmware and the nodes of the devices in `view.hidden` are removed, the first memory node
+/// describes `view.memory` (the other memory nodes are removed) and `/chosen` gets the bootargs of
+/// the domain.
+pub fn domain_view(fdt_addr: usize, view: &FdtView) -> Result<Vec<u8>, FdtError> {
+    let reader = unsafe { Reader::from_addr(fdt_addr) }.ok_or(FdtError::Invalid(fdt_addr))?;
+    let tokens: Vec<Token> = reader.map(|(_, token)| token).collect();
+
+    let mut out = Writer::new();
+    // `#address-cells` of the nodes copied so far, the children read their `reg` with it
+    let mut address_cells: Vec<usize> = Vec::new();
+    // Depth inside a removed node
+    let mut removed = 0;
+    let mut has_memory = false;
+
+    for (i, token) in tokens.iter().enumerate() {
+        let name = match *token {
+            Token::BeginNode(_) if removed > 0 => {
+                removed += 1;
+                continue;
+            }
+            Token::EndNode if removed > 0 => {
+                removed -= 1;
+                continue;
+            }
+            Token::EndNode => {
+                address_cells.pop();
+                out.end_node();
+                continue;
+            }
+            // Copied with their node
+            Token::Prop(..) => continue,
+            Token::BeginNode(name) => name,
+        };
+
+        let props: Vec<(&str, &[u8])> = tokens[i + 1..]
+            .iter()
+            .map_while(|token| match *token {
+                Token::Prop(name, value) => Some((name, value)),
+                _ => None,
+            })
+            .collect();
+        let prop = |wanted: &str| {
+            props
+                .iter()
+                .find(|(name, _)| *name == wanted)
+                .map(|&(_, value)| value)
+        };
+
+        let is_memory = prop("device_type") == Some(b"memory\0".as_slice());
+        let is_firmware = prop("compatible")
+            .is_some_and(|value| FIRMWARE_NODES.iter().any(|c| is_compatible(value, c)));
+        let parent_cells = address_cells.last().copied().unwrap_or(2);
+        let is_hidden = prop("reg")
+            .and_then(|reg| read_cells(reg, parent_cells))
+            .is_some_and(|base| {
+                let base = base as usize;
+                view.hidden
+                    .iter()
+                    .any(|&(start, size)| base >= start && base - start < size)
+            });
+        if is_firmware || is_hidden || (is_memory && has_memory) {
+            removed = 1;
+            continue;
+        }
+        has_memory |= is_memory;
+
+        let cells = prop("#address-cells").and_then(|value| read_cells(value, 1));
+        address_cells.push(cells.unwrap_or(2) as usize);
+        match view.memory.first() {
+            Some((base, _)) if is_memory => out.begin_node(&format!("memory@{base:x}")),
+            _ => out.begin_node(name),
+        }
+
+        let is_chosen = name == "chosen" && address_cells.len() == 2;
+        if is_chosen {
+            if let Some(bootargs) = view.bootargs {
+                out.property_str("bootargs", bootargs);
+            }
+        }
+        for &(name, value) in &props {
+            if is_memory && name == "reg" {
+                let reg: Vec<u64> = view
+                    .memory
+                    .iter()
+                    .flat_map(|&(base, size)| [base as u64, size as u64])
+                    .collect();
+                out.property_u64s("reg", &reg);
+            } else if !(is_chosen && view.bootargs.is_some() && name == "bootargs") {
+                out.property(name, value);
+            }
+        }
+    }
+    Ok(out.finish())
+}
+
+/// Find the TEECALL budget of the TSM watchdog in milliseconds: the `shadowfax,watchdog-ms`
+/// property of a domain instance (`opensbi,domain,instance`).
+pub fn find_watchdog_ms(fdt_addr: usize) -> Option<u32> {
+    let fdt = parse(fdt_addr)?;
+    let mut nodes = fdt.compatible_nodes("opensbi,domain,instance");
+    while let Ok(Some(node)) = nodes.next() {
+        if let Some(ms) = read_u32(&node, "shadowfax,watchdog-ms") {
+            return Some(ms);
+        }
+    }
+    None
+}
+
+/// Frequency of `mtime` (`timebase-frequency` of the `cpus` node).
+pub fn find_timebase_frequency(fdt_addr: usize) -> Option<u32> {
+    let fdt = parse(fdt_addr)?;
+    let mut nodes = fdt.nodes();
+    while let Ok(Some(node)) = nodes.next() {
+        if node.name() == Ok("cpus") {
+            return read_u32(&node, "timebase-frequency");
+        }
+    }
+    None
+}
+
+/// Whether the harts have the hypervisor extension, according to the first `cpu` node: its
+/// `riscv,isa-extensions` list or the single-letter extensions of its `riscv,isa` string.
+pub fn has_hypervisor_extension(fdt_addr: usize) -> bool {
+    let Some(fdt) = parse(fdt_addr) else {
+        return false;
+    };
+    let mut nodes = fdt.nodes();
+    while let Ok(Some(node)) = nodes.next() {
+        if !find_prop(&node, "device_type").is_some_and(|p| p.str() == Ok("cpu")) {
+            continue;
+        }
+        if let Some(prop) = find_prop(&node, "riscv,isa-extensions") {
+            let mut extensions = prop.iter_str();
+            while let Ok(Some(extension)) = extensions.next() {
+                if extension == "h" {
+                    return true;
+                }
+            }
+            return false;
+        }
+        // e.g. rv64imafdch_zicsr: the single-letter extensions come before the first underscore
+        return find_prop(&node, "riscv,isa")
+            .and_then(|p| p.str().ok())
+            .and_then(|isa| isa.get(4..))
+            .is_some_and(|isa| isa.split('_').next().is_some_and(|base| base.contains('h')));
+    }
+    false
+}
+
+/// `crypto::CRYPTO_FEATURE_*` of the harts, according to the first `cpu` node: its
+/// `riscv,isa-extensions` list or the multi-letter extensions of its `riscv,isa` string.
+pub fn crypto_features(fdt_addr: usize) -> usize {
+    let Some(fdt) = parse(fdt_addr) else {
+        return 0;
+    };
+    let mut nodes = fdt.nodes();
+    while let Ok(Some(node)) = nodes.next() {
+        if !find_prop(&node, "device_type").is_some_and(|p| p.str() == Ok("cpu")) {
+            continue;
+        }
+        let mut features = 0;
+        if let Some(prop) = find_prop(&node, "riscv,isa-extensions") {
+            let mut extensions = prop.iter_str();
+            while let Ok(Some(extension)) = extensions.next() {
+                features |= crypto::extension_features(extension);
+            }
+            return features;
+        }
+        // e.g. rv64imac_zicsr_zknh: the multi-letter extensions follow the first underscore
+        if let Some(isa) = find_prop(&node, "riscv,isa").and_then(|p| p.str().ok()) {
+            for extension in isa.split('_').skip(1) {
+                features |= crypto::extension_features(extension);
+            }
+        }
+        return features;
+    }
+    0
+}
+
+/// Count the harts of the device tree: `cpu` nodes which are not disabled.
+pub fn count_harts(fdt_addr: usize) -> usize {
+    let Some(fdt) = parse(fdt_addr) else {
+        return 0;
+    };
+    let mut count = 0;
+    let mut nodes = fdt.nodes();
+    while let Ok(Some(node)) = nodes.next() {
+        let is_cpu = find_prop(&node, "device_type").is_some_and(|p| p.str() == Ok("cpu"));
+        let disabled = find_prop(&node, "status").is_some_and(|p| p.str() == Ok("disabled"));
+        if is_cpu && !disabled {
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Find the SiFive test device (`sifive,test0`), used to power off and reset QEMU machines.
+#[cfg(any(feature = "rust-sbi", test))]
+pub fn find_test_device(fdt_addr: usize) -> Option<usize> {
+    let fdt = parse(fdt_addr)?;
+    let node = fdt.compatible_nodes("sifive,test0").next().ok()??;
+    read_reg(&node).map(|(base_addr, _)| base_addr)
+}
+
+/// Whether the platform has an HTIF (`ucb,htif0`), as spike does.
+#[cfg(feature = "rust-sbi")]
+pub fn has_htif(fdt_addr: usize) -> bool {
+    parse(fdt_addr)
+        .is_some_and(|fdt| matches!(fdt.compatible_nodes("ucb,htif0").next(), Ok(Some(_))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_runner::fdt_addr;
+
+    #[test_case]
+    fn machine_description() {
+        assert!(count_harts(fdt_addr()) > 0);
+        assert!(find_timebase_frequency(fdt_addr()).is_some_and(|freq| freq > 0));
+    }
+
+    #[test_case]
+    fn domain_instances() {
+        let instances = find_domain_instances(fdt_addr()).unwrap();
+        assert!(!instances.is_empty());
+        assert_eq!(instances.len(), count_domains(fdt_addr()));
+        for instance in &instances {
+            assert!(!instance.possible_harts.is_empty(), "{}", instance.name);
+            // Every region is a NAPOT region
+            for region in &instance.regions {
+                let size = 1usize.checked_shl(region.order).unwrap_or(0);
+                assert!(size == 0 || region.base_addr % size == 0);
+            }
+        }
+    }
+}
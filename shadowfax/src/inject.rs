@@ -0,0 +1,63 @@
+/*
+ * Fault injection on the TEE switch path, for the functional tests (`fault-injection` feature).
+ * The debug SUPD call INJECT_FAULT arms one fault, consumed by the next event it applies to:
+ *  - DROP_TEERET: the TEERETs of the outstanding call are dropped, the callee is resumed at its
+ *    ecall until the watchdog aborts the call (SBI_ERR_TIMEOUT to the caller). Without a watchdog
+ *    the hart never comes back;
+ *  - CORRUPT_TEERET: the next TEERET names another caller, the firmware must reject it. The TSM
+ *    retries its TEERET;
+ *  - SPURIOUS_TEECALL: the next TEECALL is delivered again while it is outstanding, the firmware
+ *    must reject the replay;
+ *  - PMP_FAILURE: the PMP of the next domain switch does not read back as programmed, the domain
+ *    must not run: the calls are unwound and the first caller gets SBI_ERR_FAILED.
+ *
+ * INJECT_FAULT with fault 0 disarms and returns the error the firmware answered the last fault
+ * with (0 if it accepted the faulty sequence), which the tests check. Without the feature the call
+ * is not supported and nothing is ever armed.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use core::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+
+use common::sbi::{
+    SUPD_FAULT_CORRUPT_TEERET, SUPD_FAULT_DROP_TEERET, SUPD_FAULT_PMP_FAILURE,
+    SUPD_FAULT_SPURIOUS_TEECALL,
+};
+
+use crate::error::CallError;
+
+static ARMED: AtomicUsize = AtomicUsize::new(0);
+static OUTCOME: AtomicIsize = AtomicIsize::new(0);
+
+/// Arm `fault`, or disarm with 0. Returns the outcome of the last fault.
+pub fn arm(fault: usize) -> Result<isize, CallError> {
+    match fault {
+        0
+        | SUPD_FAULT_DROP_TEERET
+        | SUPD_FAULT_CORRUPT_TEERET
+        | SUPD_FAULT_SPURIOUS_TEECALL
+        | SUPD_FAULT_PMP_FAILURE => {}
+        _ => return Err(CallError::UnknownFault(fault)),
+    }
+    ARMED.store(fault, Ordering::Relaxed);
+    Ok(OUTCOME.swap(0, Ordering::Relaxed))
+}
+
+/// Whether `fault` is armed.
+pub fn active(fault: usize) -> bool {
+    cfg!(feature = "fault-injection") && ARMED.load(Ordering::Relaxed) == fault
+}
+
+/// Disarm `fault` if it is armed, returns whether it was.
+pub fn take(fault: usize) -> bool {
+    active(fault)
+        && ARMED
+            .compare_exchange(fault, 0, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+}
+
+/// Error the firmware answered the injected fault with.
+pub fn record(code: isize) {
+    OUTCOME.store(code, Ordering::Relaxed);
+}
@@ -0,0 +1,76 @@
+/*
+ * Supervisor interrupts of the domains waiting for a TEERET. The timer and the IPIs of the
+ * untrusted domain keep firing while a confidential domain serves its TEECALL: left in the hart,
+ * they would be taken by the callee or overwritten when the sip of the caller is restored, and the
+ * untrusted OS would miss timer ticks across the confidential calls.
+ *
+ * At a TEECALL the interrupts pending for the caller are moved out of the hart into its Context
+ * (`hold`): mip.STIP and, with the hypervisor extension, the VS-level interrupts it injects in its
+ * guests (hvip). The ones the firmware raises for the supervisor during the call go to the first
+ * caller of the chain (`defer`), which programmed the timer. At the TEERET, or when the calls are
+ * unwound, they are pending again before the caller resumes (`replay`); the hvip of the callee is
+ * dropped, a TSM injects the interrupts of its vCPUs again when it runs them.
+ *
+ * SSIP is switched with sip by the context switch: an IPI which arrives during the call is added
+ * to the saved sip of the caller. SEIP follows the interrupt controller, the firmware leaves it
+ * alone.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use core::sync::atomic::Ordering;
+
+use crate::{context::Context, context_switch::SWITCH_H_CSRS, state::State};
+
+/// Supervisor software interrupt
+const MIP_SSIP: usize = 1 << 1;
+/// Supervisor timer interrupt
+const MIP_STIP: usize = 1 << 5;
+/// VSSIP, VSTIP and VSEIP: hvip has the layout of mip
+const HVIP_BITS: usize = 1 << 2 | 1 << 6 | 1 << 10;
+
+/// Supervisor interrupts pending in the hart, which the firmware can raise.
+pub fn pending() -> usize {
+    let mip: usize;
+    unsafe { core::arch::asm!("csrr {0}, mip", out(reg) mip) };
+    mip & (MIP_SSIP | MIP_STIP)
+}
+
+/// Move the interrupts pending for the caller of a TEECALL, whose context is `ctx`, out of the
+/// hart: the callee does not see them.
+pub fn hold(ctx: &mut Context) {
+    let mip: usize;
+    unsafe { core::arch::asm!("csrrc {0}, mip, {1}", out(reg) mip, in(reg) MIP_STIP) };
+    ctx.pending_irqs = mip & MIP_STIP;
+
+    if SWITCH_H_CSRS.load(Ordering::Relaxed) != 0 {
+        let hvip: usize;
+        unsafe { core::arch::asm!("csrrc {0}, hvip, {1}", out(reg) hvip, in(reg) HVIP_BITS) };
+        ctx.pending_irqs |= hvip & HVIP_BITS;
+    }
+}
+
+/// The supervisor interrupts `raised` by the firmware while a TEECALL is outstanding belong to the
+/// first caller: take them out of the hart and keep them in its context.
+pub fn defer(state: &State, raised: usize) {
+    let raised = raised & (MIP_SSIP | MIP_STIP);
+    let Some(call) = state.calls.calls().next().filter(|_| raised != 0) else {
+        return;
+    };
+
+    unsafe { core::arch::asm!("csrc mip, {0}", in(reg) raised) };
+    let ctx = state.tee.context(call.caller) as *mut Context;
+    unsafe { (*ctx).pending_irqs |= raised };
+}
+
+/// Make the interrupts kept in `ctx` pending again, before the caller resumes from it.
+pub fn replay(ctx: &mut Context) {
+    let pending = core::mem::take(&mut ctx.pending_irqs);
+
+    // Restored with the context
+    ctx.sip |= pending & MIP_SSIP;
+    unsafe { core::arch::asm!("csrs mip, {0}", in(reg) pending & MIP_STIP) };
+    if SWITCH_H_CSRS.load(Ordering::Relaxed) != 0 {
+        unsafe { core::arch::asm!("csrw hvip, {0}", in(reg) pending & HVIP_BITS) };
+    }
+}
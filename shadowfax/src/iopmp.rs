@@ -0,0 +1,196 @@
+/*
+ * IOPMP driver. PMP only protects confidential memory from harts: DMA-capable devices go through
+ * the IOPMP (if the platform has one). The TSM-driver programs the IOPMP so that:
+ * - confidential memory (the TSM memory and every converted page) is never reachable by DMA;
+ * - devices can only reach the shared regions the host explicitly granted;
+ * - everything else is denied (IOPMP default on no match).
+ *
+ * Entries are priority ordered, so deny entries for confidential memory are written first and
+ * grants after them. Shadowfax uses a single memory domain shared by all the requestors.
+ *
+ * Register layout from the RISC-V IOPMP specification (v0.9).
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use alloc::vec::Vec;
+use common::pmp::Region;
+
+use crate::{
+    domain::{napot_split, MemoryRegion},
+    error::IopmpError,
+};
+
+const IOPMP_HWCFG1: usize = 0x0C;
+const IOPMP_ENTRYOFFSET: usize = 0x2C;
+
+// Per entry registers, relative to the entry base
+const IOPMP_ENTRY_ADDR: usize = 0x0;
+const IOPMP_ENTRY_ADDRH: usize = 0x4;
+const IOPMP_ENTRY_CFG: usize = 0x8;
+const IOPMP_ENTRY_SIZE: usize = 0x10;
+
+const IOPMP_CFG_R: u32 = 1 << 0;
+const IOPMP_CFG_W: u32 = 1 << 1;
+const IOPMP_CFG_A_NAPOT: u32 = 3 << 3;
+
+/// A shared region the host granted to devices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DmaGrant {
+    pub base_addr: usize,
+    pub order: u32,
+}
+
+pub struct Iopmp {
+    base_addr: usize,
+    num_entries: usize,
+    /// Regions no device can access
+    confidential: Vec<MemoryRegion>,
+    grants: Vec<DmaGrant>,
+}
+
+impl Iopmp {
+    /// Probe the IOPMP at `base_addr`. Returns `None` if it exposes no entries.
+    pub fn new(base_addr: usize) -> Option<Self> {
+        let hwcfg1 = unsafe { read_reg(base_addr, IOPMP_HWCFG1) };
+        let num_entries = (hwcfg1 >> 16) as usize;
+        if num_entries == 0 {
+            return None;
+        }
+
+        Some(Self {
+            base_addr,
+            num_entries,
+            confidential: Vec::new(),
+            grants: Vec::new(),
+        })
+    }
+
+    /// Deny DMA on a confidential region.
+    pub fn protect(&mut self, base_addr: usize, order: u32) -> Result<(), IopmpError> {
+        self.confidential.push(MemoryRegion {
+            base_addr,
+            order,
+            mmio: false,
+            permissions: 0,
+        });
+        if let Err(e) = self.program() {
+            self.confidential.pop();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Remove the deny entries of `[base_addr, base_addr + size)`, split in NAPOT regions as it
+    /// was protected (see `napot_split`). Nothing is removed unless every entry is there.
+    pub fn unprotect_range(&mut self, base_addr: usize, size: usize) -> Result<(), IopmpError> {
+        let protected = |(base, order): (usize, u32)| {
+            self.confidential
+                .iter()
+                .any(|r| r.base_addr == base && r.order == order)
+        };
+        if !napot_split(base_addr, size).all(protected) {
+            return Err(IopmpError::NotProtected);
+        }
+        self.confidential.retain(|r| {
+            !napot_split(base_addr, size)
+                .any(|(base, order)| r.base_addr == base && r.order == order)
+        });
+        self.program()
+    }
+
+    /// Allow DMA on a shared region. The region must not overlap confidential memory.
+    pub fn grant(&mut self, grant: DmaGrant) -> Result<(), IopmpError> {
+        let overlaps = self
+            .confidential
+            .iter()
+            .any(|r| overlap(r.base_addr, 1 << r.order, grant.base_addr, 1 << grant.order));
+        if overlaps {
+            return Err(IopmpError::ConfidentialGrant);
+        }
+        if self.grants.contains(&grant) {
+            return Ok(());
+        }
+
+        self.grants.push(grant);
+        if let Err(e) = self.program() {
+            self.grants.pop();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn revoke(&mut self, grant: DmaGrant) -> Result<(), IopmpError> {
+        let idx = self
+            .grants
+            .iter()
+            .position(|g| *g == grant)
+            .ok_or(IopmpError::NoMatchingGrant)?;
+        self.grants.remove(idx);
+        self.program()
+    }
+
+    /// Write the entries again, after the IOPMP lost them (e.g. in a system suspend).
+    pub fn reprogram(&self) -> Result<(), IopmpError> {
+        self.program()
+    }
+
+    /// Rewrite all the entries: deny entries first, then grants, then clear the leftovers.
+    fn program(&self) -> Result<(), IopmpError> {
+        if self.confidential.len() + self.grants.len() > self.num_entries {
+            return Err(IopmpError::NoEntries);
+        }
+
+        let deny = self.confidential.iter().map(|r| (r.base_addr, r.order, 0));
+        let allow = self
+            .grants
+            .iter()
+            .map(|g| (g.base_addr, g.order, IOPMP_CFG_R | IOPMP_CFG_W));
+
+        // Same NAPOT encoding used for the PMP, checked before any entry is written
+        let entries = deny
+            .chain(allow)
+            .map(|(base_addr, order, permissions)| {
+                let addr = Region::napot(base_addr, order)?.encode_napot()?;
+                Some((addr, IOPMP_CFG_A_NAPOT | permissions))
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or(IopmpError::NotNapot)?;
+
+        for (i, &(addr, cfg)) in entries.iter().enumerate() {
+            unsafe { self.write_entry(i, addr, cfg) };
+        }
+        let i = entries.len();
+        for i in i..self.num_entries {
+            unsafe { self.write_entry(i, 0, 0) };
+        }
+        Ok(())
+    }
+
+    unsafe fn write_entry(&self, index: usize, addr: usize, cfg: u32) {
+        let entry_offset = read_reg(self.base_addr, IOPMP_ENTRYOFFSET) as usize;
+        let entry = entry_offset + index * IOPMP_ENTRY_SIZE;
+
+        // Disable the entry while updating the address
+        write_reg(self.base_addr, entry + IOPMP_ENTRY_CFG, 0);
+        write_reg(self.base_addr, entry + IOPMP_ENTRY_ADDR, addr as u32);
+        write_reg(
+            self.base_addr,
+            entry + IOPMP_ENTRY_ADDRH,
+            (addr >> 32) as u32,
+        );
+        write_reg(self.base_addr, entry + IOPMP_ENTRY_CFG, cfg);
+    }
+}
+
+fn overlap(a_base: usize, a_size: usize, b_base: usize, b_size: usize) -> bool {
+    a_base < b_base + b_size && b_base < a_base + a_size
+}
+
+unsafe fn read_reg(base_addr: usize, offset: usize) -> u32 {
+    ((base_addr + offset) as *const u32).read_volatile()
+}
+
+unsafe fn write_reg(base_addr: usize, offset: usize, value: u32) {
+    ((base_addr + offset) as *mut u32).write_volatile(value);
+}
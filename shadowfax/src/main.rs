@@ -19,6 +19,12 @@
  *  - covg: extension used from the guest to access firmware level services;
  *  - supd: supervisor domain extension;
  *
+ * With the experimental `rust-sbi` feature, OpenSBI is not linked: the SBI runtime is the
+ * pure-Rust implementation in `sbi.rs`.
+ *
+ * `cargo test` builds run the firmware test cases in M-mode instead of booting the domains, see
+ * `test_runner.rs`.
+ *
  * Author: Giuseppe Capasso <capassog97@gmail.com>
  */
 #![doc = include_str!("../../README.md")]
@@ -27,10 +33,17 @@
 #![feature(fn_align)]
 #![feature(once_cell_get_mut)]
 #![feature(naked_functions_rustic_abi)]
+#![cfg_attr(test, feature(custom_test_frameworks))]
+#![cfg_attr(test, test_runner(crate::test_runner::run))]
+#![cfg_attr(test, reexport_test_harness_main = "test_main")]
+
+use common::{
+    arch_attribute,
+    heap::{HeapStats, TrackedHeap},
+    reg_store,
+};
+use core::{alloc::Layout, panic::PanicInfo};
 
-use core::{ffi, panic::PanicInfo};
-
-use linked_list_allocator::LockedHeap;
 use riscv::{
     asm::wfi,
     register::{mhartid, misa},
@@ -43,30 +56,79 @@ mod debug;
 mod cove;
 mod scheduler;
 
-/// This module includes the `bindings.rs` generated
-/// using `build.rs` which translates opensbi C definitions
-/// in Rust. This could be also be included without the module,
-/// but doing in this way mandates that every opensbi symbol
-/// is used with `opensbi::<symbol>`.
-mod opensbi {
-    #![allow(non_upper_case_globals)]
-    #![allow(non_camel_case_types)]
-    #![allow(non_snake_case)]
-    #![allow(unused)]
-    include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
-}
-
+/// SBI runtime: OpenSBI (default) or the experimental pure-Rust SBI core (`rust-sbi` feature).
+/// Both provide the same interface, used through `runtime::<symbol>`.
+#[cfg(not(feature = "rust-sbi"))]
+mod opensbi;
+#[cfg(not(feature = "rust-sbi"))]
+use opensbi as runtime;
+#[cfg(feature = "rust-sbi")]
+mod sbi;
+#[cfg(feature = "rust-sbi")]
+use sbi as runtime;
+
+mod audit;
+mod call_buffers;
+mod console;
 mod constants;
 mod context;
+mod context_switch;
+mod counters;
+mod crash;
+mod devices;
+mod dispatch;
 mod domain;
 mod error;
+#[cfg(feature = "fdt")]
+mod fdt;
+mod inject;
+mod interrupts;
+mod iopmp;
+mod platform;
+mod reset;
+mod rng;
+mod selftest;
+mod snapshot;
 mod state;
+/// Without the `fdt` feature the static configuration, which has the same interface, replaces the
+/// device tree: used through `fdt::<symbol>` as well.
+#[cfg(not(feature = "fdt"))]
+mod static_config;
+#[cfg(not(feature = "fdt"))]
+use static_config as fdt;
+mod stats;
+mod storage;
+mod suspend;
+mod tee;
+#[cfg(test)]
+mod test_runner;
+mod tlb;
+mod trace;
 mod trap;
+mod watchdog;
+#[cfg(feature = "firmware-wx")]
+mod wx;
 
 extern crate alloc;
 #[global_allocator]
-/// Global allocator.
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+/// Global allocator. Its usage is reported by the SUPD GET_HEAP_STAT call, to size `_heap_size`.
+pub static ALLOCATOR: TrackedHeap = TrackedHeap::new(&HEAP_TAGS, heap_oom);
+
+/// Heap tags of the firmware subsystems. The state (domains and contexts) and the crypto have an
+/// arena of their own, the other tags share the transient one (parsing, errors).
+pub const HEAP_TAG_STATE: usize = 1;
+pub const HEAP_TAG_TSM: usize = 2;
+pub const HEAP_TAG_CRYPTO: usize = 3;
+const HEAP_TAGS: [&str; 4] = ["firmware", "state", "tsm", "crypto"];
+
+fn heap_oom(layout: Layout, stats: &HeapStats) {
+    print_raw!(
+        "out of memory allocating {} bytes (align {}): {}\r\n",
+        layout.size(),
+        layout.align(),
+        stats
+    );
+}
 
 /*
  * This "object" is just to hold symbols declared in the linkerscript
@@ -76,21 +138,30 @@ static ALLOCATOR: LockedHeap = LockedHeap::empty();
 unsafe extern "C" {
     // Firmware info
     pub static _fw_start: u8;
-    static _fw_end: u8;
+    pub static _fw_end: u8;
     pub static _fw_rw_start: u8;
 
-    // Heap
+    // Heap arenas
     static mut _heap_start: u8;
     static _heap_end: u8;
+    static mut _heap_state_start: u8;
+    static _heap_state_end: u8;
+    static mut _heap_crypto_start: u8;
+    static _heap_crypto_end: u8;
 
     // Bss info
     static _start_bss: u8;
     static _end_bss: u8;
 
     // Stack
-    static _stack_top: u8;
+    pub static _stack_top: u8;
+
+    // Snapshot area (see snapshot.rs)
+    pub static _snapshot_start: u8;
+    pub static _snapshot_end: u8;
 
-    // Start of the TEE Scratch Stack
+    // TEE RAM, sized at init from the device tree (see tee.rs)
+    pub static _tee_ram_start: u8;
     pub static _tee_stack_top: u8;
 
 }
@@ -101,7 +172,10 @@ unsafe extern "C" {
 #[inline(never)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    print_raw!("{}", info);
+    print_raw!("{}\r\n", info);
+    #[cfg(test)]
+    test_runner::panicked(info);
+    crash::dump(info);
     loop {}
 }
 
@@ -115,7 +189,7 @@ const STACK_SIZE_PER_HART: usize = 1024 * 8;
 /// - loads the custom device tree in `a1` register overwriting the default one
 /// provided by qemu
 /// - zero bss section
-/// - call `fw_platform_init` provided by opensbi
+/// - call the `platform_init` of the SBI runtime (`fw_platform_init` with OpenSBI)
 /// - jump to main
 /// temporary stack at the end of the firmware and jump to
 /// main function.
@@ -127,11 +201,12 @@ extern "C" fn _start() -> ! {
     core::arch::naked_asm!(
         // If there are multiple hart, init only hartid 0
         // The .attribute is needed. LLVM does not produce code seems to be a bug.
+        arch_attribute!(),
         r#"
-        .attribute arch, "rv64imac"
         csrr s6, mhartid
-        // If not zero, go to wait loop
-        bnez s6, {hang}
+        // If not the boot hart, go to wait loop
+        li t0, {boot_hartid}
+        bne s6, t0, {hang}
 
         // setup a temporary stack pointer
         li t0, {stack_size_per_hart}
@@ -143,20 +218,22 @@ extern "C" fn _start() -> ! {
         la s4, {bss_start}
         la s5, {bss_end}
         0:
-        sd zero, 0(s4)
+        "#,
+        reg_store!(zero, 0(s4)),
+        r#"
         addi s4, s4, {pointer_size}
 
         // Loop if s4 is less than s5
         blt s4, s5, 0b
 
-        // call fw_platform_init
+        // call the platform init of the SBI runtime
         // save registers a0-a4
         add s0, a0, zero
         add s1, a1, zero
         add s2, a2, zero
         add s3, a3, zero
         add s4, a4, zero
-        call {fw_platform_init}
+        call {platform_init}
         // the platform init could change the device tree address
         // save the return value in t0
         add t0, a0, zero
@@ -174,7 +251,8 @@ extern "C" fn _start() -> ! {
         stack_size_per_hart = const STACK_SIZE_PER_HART,
         stack_top = sym _stack_top,
         hang = sym hang,
-        fw_platform_init = sym opensbi::fw_platform_init,
+        boot_hartid = const platform::BOOT_HARTID,
+        platform_init = sym runtime::platform_init,
         main = sym main,
         bss_start = sym _start_bss,
         bss_end = sym _end_bss,
@@ -182,13 +260,6 @@ extern "C" fn _start() -> ! {
     )
 }
 
-#[allow(unused)]
-enum PrivMode {
-    PrivM = 3_isize,
-    PrivS = 1,
-    PrivU = 0,
-}
-
 /// The main function serves as the entry point for the firmware execution. It performs
 /// several critical initialization tasks to prepare the system for operation. These tasks
 /// include zeroing out the BSS section, setting up a temporary trap handler, initializing
@@ -244,9 +315,22 @@ extern "C" fn main(boot_hartid: usize, fdt_addr: usize) -> ! {
     // this enables heap allocations
     unsafe {
         // Initialize global alloca
-        let heap_start = (&raw const _heap_start as *const u8) as usize;
-        let heap_size = ((&raw const _heap_end as *const u8) as usize) - heap_start;
-        ALLOCATOR.lock().init(heap_start as *mut u8, heap_size);
+        let arena =
+            |start: *const u8, end: *const u8| (start as *mut u8, end as usize - start as usize);
+        let (heap_start, heap_size) = arena(&raw const _heap_start, &raw const _heap_end);
+        ALLOCATOR
+            .init(heap_start, heap_size)
+            .expect("cannot initialize the heap, check _heap_size");
+        let (heap_start, heap_size) =
+            arena(&raw const _heap_state_start, &raw const _heap_state_end);
+        ALLOCATOR
+            .init_arena(HEAP_TAG_STATE, heap_start, heap_size)
+            .expect("cannot initialize the state heap, check _heap_state_size");
+        let (heap_start, heap_size) =
+            arena(&raw const _heap_crypto_start, &raw const _heap_crypto_end);
+        ALLOCATOR
+            .init_arena(HEAP_TAG_CRYPTO, heap_start, heap_size)
+            .expect("cannot initialize the crypto heap, check _heap_crypto_size");
     }
 
     // setup a temporary trap handler (just a busy loop)
@@ -259,9 +343,26 @@ extern "C" fn main(boot_hartid: usize, fdt_addr: usize) -> ! {
 
     dump_linker_symbols();
 
+    // From now on the firmware code is read/execute only, see `wx.rs`
+    #[cfg(feature = "firmware-wx")]
+    if let Err(e) = wx::lock_firmware() {
+        print_raw!("Firmware W^X not enforced: {}\r\n", e);
+    }
+
+    // Test builds run the firmware test cases and power off, see `test_runner.rs`
+    #[cfg(test)]
+    test_runner::start(fdt_addr);
+
     // initialize shadowfax state which will be used to handle the CoVE SBI
     let next_stage_address = state::init(fdt_addr).unwrap();
     print_raw!("State initialized correctly\r\n");
+
+    let report = selftest::run(state::STATE.lock().get().unwrap());
+    print_raw!(
+        "Isolation self-test: {} targets, failed {:#x}\r\n",
+        report.targets,
+        report.failed
+    );
     // unsafe {
     //     // Clear the Timer Delegation (ensure bit 5 of mideleg is 0)
     //     // STIP (Supervisor Timer Interrupt) is bit 5.
@@ -275,185 +376,24 @@ extern "C" fn main(boot_hartid: usize, fdt_addr: usize) -> ! {
     //     set_timer(10_000_000); // 10ms
     // }
     //
-    /*
-     * This code initializes the scratch space, which is a per-HART data structure
-     * defined in <sbi/sbi_scratch.h>. The scratch space is used to store various firmware-related
-     * parameters and configurations necessary for the operation of the RISC-V system.
-     *
-     * The memory layout of the firmware is as follows:
-     * - Firmware Region: Contains the firmware code and data, including the R/W section.
-     * - HART Stacks: Contains the stack space for all HARTs, with each stack having a scratch area.
-     * - Heap Region: A contiguous block of memory for heap usage.
-     *
-     * This function performs the following steps:
-     * 1. Retrieves platform details such as HART count, stack size, and heap size.
-     * 2. Sets up the scratch space for all HARTs by calculating the appropriate memory addresses.
-     * 3. Initializes the heap base address.
-     * 4. Configures the scratch space for HART 0 by storing various firmware parameters.
-     * 5. Clears the trap context and temporary storage fields.
-     * 6. Stores the firmware options and HART index in the scratch space.
-     *
-     * This structure describes the memory layout of the firmware:
-     * -                 Memory Layout
-     * -                -------------
-     * -+---------------------------------------------------------+
-     * -| Firmware Region                                         |
-     * -|                                                         |
-     * -|  _fw_start                                              |
-     * -|    +-----------------------------------------------+    |
-     * -|    |   Firmware Code & Data                        |    |
-     * -|    |                                               |    |
-     * -|    |   (Includes the read/write (R/W) section,     |    |
-     * -|    |    beginning at _fw_rw_start)                 |    |
-     * -|    +-----------------------------------------------+    |
-     * -|  _fw_end                                                |
-     * -+---------------------------------------------------------+
-     * -| HART Stacks (for all HARTs, total size = s7 * s8)       |
-     * -|                                                         |
-     * -|  Hart 0 Stack:                                          |
-     * -|    +---------------------------+                        |
-     * -|    |  (Stack space)            |                        |
-     * -|    |                           |                        |
-     * -|    |  Scratch Area             | <-- SBI_SCRATCH_SIZE   |
-     * -|    |    (holds various fields: |    (e.g., fw_start,    |
-     * -|    |     fw_start, fw_size,     |     fw_size, RW offset|
-     * -|    |     fw_rw_offset,         |     heap offset/size,  |
-     * -|    |     heap offset/size,     |     boot parameters,   |
-     * -|    |     boot addresses, etc.) |     etc.)              |
-     * -|    +---------------------------+                        |
-     * -+---------------------------------------------------------+
-     * -| Heap Region                                             |
-     * -|  (Contiguous block of size s9)                          |
-     * -|                                                         |
-     * -+---------------------------------------------------------+
-     */
-    // Setup scratch space for all harts
-    let hart_count = unsafe { opensbi::platform.hart_count } as usize;
-    let hart_stack_size = unsafe { opensbi::platform.hart_stack_size } as usize;
-    let heap_size = unsafe { opensbi::platform.heap_size } as usize;
-    // parse linkerscript symbols
-
-    let fw_start = unsafe { &_fw_start as *const u8 as usize };
-    let fw_end = unsafe { &_fw_end as *const u8 as usize };
-    let fw_rw_start = unsafe { &_fw_rw_start as *const u8 as usize };
-    let platform_addr = &raw const opensbi::platform as *const _ as usize;
-
-    /* From the fw_base.S of opensbi:
-     *
-     * /* Setup scratch space for all the HARTs */
-     * lla	tp, _fw_end
-     * mul	a5, s7, s8
-     * add	tp, tp, a5
-     * /* Setup heap base address */
-     * lla	s10, _fw_start
-     * sub	s10, tp, s10
-     * add	tp, tp, s9
-     * /* Keep a copy of tp */
-     * add	t3, tp, zero
-     *
-     */
-    let fw_end_tot = fw_end + (hart_count * hart_stack_size) + heap_size;
-    let heap_start = fw_end + (hart_count * hart_stack_size) - fw_start;
-
-    for i in 0..hart_count {
-        /*
-         * Populate the sbi_scratch struct with the correct values
-         * We want to use ffi:c_ulong to avoid hardcoding pointer size.
-         * This is needed if we use riscv32 architectures.
-         * The rust bindgen library will generate the correct types
-         * based on target architecture.
-         */
-        let sbi_scratch = opensbi::sbi_scratch {
-            // fw_start: start address of the firmware
-            fw_start: fw_start as ffi::c_ulong,
-            // fw_size: total firmware size, includes harts' stack and heap
-            fw_size: (fw_end_tot - fw_start) as ffi::c_ulong,
-            // fw_rw_offset: offset where the data starts
-            fw_rw_offset: (fw_rw_start - fw_start) as ffi::c_ulong,
-            // fw_heap_offset: where the heap starts from fw_start
-            fw_heap_offset: heap_start as ffi::c_ulong,
-            // fw_heap_size: heap size specified by the platform
-            fw_heap_size: heap_size as ffi::c_ulong,
-            // next_arg1: the fdt_address passed to the next stage
-            next_arg1: fdt_addr as ffi::c_ulong,
-            // next_addr: address of the next stage
-            next_addr: next_stage_address as ffi::c_ulong,
-            // next_mode: mode used to launch next_addr
-            next_mode: PrivMode::PrivS as ffi::c_ulong,
-            // warmboot_addr: address of the warmboot function.
-            // This is not supported for now, but is needed for
-            // hotplug harts and multicore
-            warmboot_addr: 0,
-            // platform_addr: address of the opensbi::platform struct populated
-            // with fw_platform_init
-            platform_addr: platform_addr as ffi::c_ulong,
-            // hartid_to_scratch: function used to retrieve the hart scratch given the id
-            hartid_to_scratch: hartid_to_scratch as ffi::c_ulong,
-            // trap_context: reset to 0
-            trap_context: 0,
-            // tmp0: reset to 0
-            tmp0: 0,
-            // options: to customize the sbi_runtime
-            options: 0,
-            // hartindex: current hart index 0-based.
-            hartindex: i as ffi::c_ulong,
-        };
-
-        /*
-         * Calculate the address where to write the scratch
-         * add	tp, t3, zero
-         * sub	tp, tp, s9
-         * mul	a5, s8, t1
-         * sub	tp, tp, a5
-         * li	a5, SBI_SCRATCH_SIZE
-         * sub	tp, tp, a5
-         */
-        let scratch_addr =
-            fw_end_tot - heap_size - (hart_stack_size * i) - opensbi::SBI_SCRATCH_SIZE as usize;
-
-        let p = scratch_addr as *mut opensbi::sbi_scratch;
-
-        unsafe {
-            // write the structure to the calculated address
-            p.write_volatile(sbi_scratch);
-        }
-    }
-
-    // Prepare and jump to sbi_init. We need to:
-    //  - disable interrupts
-    //  - find the scratch for hart 0
-    unsafe {
-        use riscv::register::mtvec::Mtvec;
-        // According to the opensbi documentation, we need to disable the interrupt
-        riscv::interrupt::disable();
 
-        // Set the mscratch to the correct address
-        let scratch_addr = hartid_to_scratch(boot_hartid, boot_hartid);
-        riscv::register::mscratch::write(scratch_addr);
-
-        // set the stack pointer to the scratch.
-        // First thing they will need to do is to setup the stack pointer
-        // to a valid location
-        core::arch::asm!(
-            "csrr a0, mscratch",
-            "add tp, a0, zero",
-            options(nomem, nostack)
-        );
-
-        // set the trap handler
-        let a = Mtvec::from_bits(trap::handler as usize);
-        riscv::register::mtvec::write(a);
-
-        riscv::register::mstatus::clear_tsr();
-        riscv::register::mstatus::clear_tvm();
-
-        // call sbi_init for the current hart
-        let sbi_scratch_addr = scratch_addr as *mut opensbi::sbi_scratch;
-        core::arch::asm!(
-            "add sp, tp, {}", in(reg) opensbi::SBI_SCRATCH_SIZE
-        );
-        opensbi::sbi_init(sbi_scratch_addr)
-    }
+    // The untrusted domain boots with its view of the device tree, see `fdt::domain_view`. Without
+    // a device tree, it gets the `next-arg1` of its static configuration.
+    let fdt_view = state::STATE.lock().get().and_then(|state| {
+        let domain = state.domains.last()?;
+        match cfg!(feature = "fdt") {
+            true => domain.fdt_view,
+            false => domain.instance.as_ref().map(|instance| instance.next_arg1),
+        }
+    });
+
+    // Hand over to the SBI runtime, which boots the untrusted domain
+    runtime::boot(
+        boot_hartid,
+        fdt_addr,
+        fdt_view.unwrap_or(fdt_addr),
+        next_stage_address,
+    )
 }
 
 // a small helper to print an address using the print_raw! macro
@@ -504,52 +444,6 @@ fn dump_linker_symbols() {
     print_raw!("========================\n\n");
 }
 
-/// Calculates the starting address of the scratch space for a given HART (Hardware Thread).
-///
-/// This function uses the HART ID and HART Index to determine the appropriate scratch space
-/// starting address. It retrieves platform details such as the HART stack size and count,
-/// and performs calculations to find the correct address.
-///
-/// # Safety
-///
-/// This function is unsafe because it directly manipulates machine-level registers and
-/// relies on specific memory layout assumptions. It should only be called in a controlled
-/// environment where these assumptions hold true.
-#[link_section = ".text"]
-extern "C" fn hartid_to_scratch(_hartid: usize, hartindex: usize) -> usize {
-    // Number of harts, stack size & heap size from the OpenSBI platform struct:
-    let hart_count = unsafe { opensbi::platform.hart_count as usize };
-    let hart_stack_sz = unsafe { opensbi::platform.hart_stack_size as usize };
-    let heap_sz = unsafe { opensbi::platform.heap_size as usize };
-
-    // End of firmware code/data section:
-    let fw_end = unsafe { &_fw_end as *const u8 as usize };
-
-    // Total top-of-memory after firmware + all stacks + heap:
-    let fw_end_tot = fw_end + hart_count * hart_stack_sz + heap_sz;
-
-    // Compute exactly where you wrote the i-th hart’s scratch:
-    let scratch_addr = fw_end_tot
-        // back off the heap
-        .saturating_sub(heap_sz)
-        // back off earlier hart stacks
-        .saturating_sub(hart_stack_sz * hartindex)
-        // back off the scratch size itself
-        .saturating_sub(opensbi::SBI_SCRATCH_SIZE as usize);
-
-    scratch_addr
-}
-
-// Needed for opensbi
-// For some reason the static lib needs these 2 symbols defined
-// TODO: investigate why these are needed.
-// Maybe we can just use libsbi.a (without libplatsbi.a) and provide the `fw_platform_init`
-// externally.
-#[no_mangle]
-fn _start_warm() {}
-#[no_mangle]
-fn _trap_handler() {}
-
 /// This function causes the processor to enter an infinite loop, effectively halting execution.
 /// It is typically used as a placeholder or to indicate a state where further execution should not proceed.
 #[rustc_align(4)]
@@ -0,0 +1,618 @@
+/*
+ * OpenSBI runtime (default). OpenSBI is linked as a static library
+ * (https://github.com/riscv-software-src/opensbi/blob/master/docs/library_usage.md): this module
+ * includes the `bindings.rs` generated by `build.rs` from the OpenSBI headers, prepares the
+ * per-hart scratch space and jumps to `sbi_init`. Every OpenSBI symbol is used as
+ * `opensbi::<symbol>`.
+ *
+ * The rest of the firmware only uses the runtime interface, implemented by `sbi.rs` too:
+ * `TrapRegs`/`TrapInfo`/`TrapContext`, `SCRATCH_TMP0_OFFSET`, `platform_init`, `trap_handler`,
+ * `redirect_trap`, `boot`, `putc`, `change_active_domain` and `domain_allows`.
+ *
+ * The supervisor domains of the state are mirrored in the OpenSBI domains (`domains_init`), with
+ * the same ids: the OpenSBI checks of the traps which are not TEECALLs see the regions of the PMP.
+ * OpenSBI delegates the VS-mode ecalls to HS-mode, the platform `final_init` takes them back.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use alloc::{boxed::Box, format};
+use common::{
+    config::MAX_DOMAINS,
+    sbi::{SBI_COVH_EXT_ID, SBI_COVI_EXT_ID, SBI_SUPD_EXT_ID},
+};
+use core::{ffi, mem::offset_of};
+
+use crate::{
+    domain::{Domain, MemoryRegion, MAX_MEMORY_REGIONS, PMP_R, PMP_W, PMP_X},
+    state::STATE,
+};
+
+mod bindings {
+    #![allow(non_upper_case_globals)]
+    #![allow(non_camel_case_types)]
+    #![allow(non_snake_case)]
+    #![allow(unused)]
+    include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+}
+
+pub use bindings::*;
+
+/// Registers saved by the trap entry (`struct sbi_trap_regs`)
+pub type TrapRegs = sbi_trap_regs;
+/// Trap details saved after the registers (`struct sbi_trap_info`)
+pub type TrapInfo = sbi_trap_info;
+/// Trap context built by `trap::handler` (`struct sbi_trap_context`)
+pub type TrapContext = sbi_trap_context;
+
+/// Offset of the temporary slot used by the trap entry in the scratch pointed by `mscratch`.
+pub const SCRATCH_TMP0_OFFSET: usize = offset_of!(sbi_scratch, tmp0);
+
+/// Called by `_start`, returns the (possibly relocated) device tree.
+pub use bindings::fw_platform_init as platform_init;
+/// Handles the traps which are not CoVE ecalls. Takes the saved trap context and returns the
+/// registers to restore.
+pub fn trap_handler(ctx: &mut TrapContext) -> &mut TrapRegs {
+    unsafe { &mut *sbi_trap_handler(ctx) }
+}
+
+/// Forward the exception of the trap context to the supervisor trap handler. OpenSBI refuses the
+/// traps taken from M-mode: the error is the SBI error code.
+pub fn redirect_trap(ctx: &mut TrapContext) -> Result<&mut TrapRegs, isize> {
+    let rc = unsafe { sbi_trap_redirect(&mut ctx.regs, &ctx.trap) };
+    if rc != 0 {
+        return Err(rc as isize);
+    }
+    Ok(&mut ctx.regs)
+}
+
+#[allow(unused)]
+enum PrivMode {
+    PrivM = 3_isize,
+    PrivS = 1,
+    PrivU = 0,
+}
+
+/// Prepare the scratch space of every hart and jump to `sbi_init`, which boots the untrusted
+/// domain at `next_stage_address` with the device tree `next_arg1`. OpenSBI reads its devices from
+/// `next_arg1` as well, the device tree of the platform `fdt_addr` is not used past `state::init`.
+pub fn boot(
+    boot_hartid: usize,
+    _fdt_addr: usize,
+    next_arg1: usize,
+    next_stage_address: usize,
+) -> ! {
+    /*
+     * This code initializes the scratch space, which is a per-HART data structure
+     * defined in <sbi/sbi_scratch.h>. The scratch space is used to store various firmware-related
+     * parameters and configurations necessary for the operation of the RISC-V system.
+     *
+     * The memory layout of the firmware is as follows:
+     * - Firmware Region: Contains the firmware code and data, including the R/W section.
+     * - HART Stacks: Contains the stack space for all HARTs, with each stack having a scratch area.
+     * - Heap Region: A contiguous block of memory for heap usage.
+     *
+     * This function performs the following steps:
+     * 1. Retrieves platform details such as HART count, stack size, and heap size.
+     * 2. Sets up the scratch space for all HARTs by calculating the appropriate memory addresses.
+     * 3. Initializes the heap base address.
+     * 4. Configures the scratch space for HART 0 by storing various firmware parameters.
+     * 5. Clears the trap context and temporary storage fields.
+     * 6. Stores the firmware options and HART index in the scratch space.
+     *
+     * This structure describes the memory layout of the firmware:
+     * -                 Memory Layout
+     * -                -------------
+     * -+---------------------------------------------------------+
+     * -| Firmware Region                                         |
+     * -|                                                         |
+     * -|  _fw_start                                              |
+     * -|    +-----------------------------------------------+    |
+     * -|    |   Firmware Code & Data                        |    |
+     * -|    |                                               |    |
+     * -|    |   (Includes the read/write (R/W) section,     |    |
+     * -|    |    beginning at _fw_rw_start)                 |    |
+     * -|    +-----------------------------------------------+    |
+     * -|  _fw_end                                                |
+     * -+---------------------------------------------------------+
+     * -| HART Stacks (for all HARTs, total size = s7 * s8)       |
+     * -|                                                         |
+     * -|  Hart 0 Stack:                                          |
+     * -|    +---------------------------+                        |
+     * -|    |  (Stack space)            |                        |
+     * -|    |                           |                        |
+     * -|    |  Scratch Area             | <-- SBI_SCRATCH_SIZE   |
+     * -|    |    (holds various fields: |    (e.g., fw_start,    |
+     * -|    |     fw_start, fw_size,     |     fw_size, RW offset|
+     * -|    |     fw_rw_offset,         |     heap offset/size,  |
+     * -|    |     heap offset/size,     |     boot parameters,   |
+     * -|    |     boot addresses, etc.) |     etc.)              |
+     * -|    +---------------------------+                        |
+     * -+---------------------------------------------------------+
+     * -| Heap Region                                             |
+     * -|  (Contiguous block of size s9)                          |
+     * -|                                                         |
+     * -+---------------------------------------------------------+
+     */
+    // Setup scratch space for all harts
+    let hart_count = unsafe { platform.hart_count } as usize;
+    let hart_stack_size = unsafe { platform.hart_stack_size } as usize;
+    let heap_size = unsafe { platform.heap_size } as usize;
+    // parse linkerscript symbols
+
+    let fw_start = unsafe { &crate::_fw_start as *const u8 as usize };
+    let fw_end = unsafe { &crate::_fw_end as *const u8 as usize };
+    let fw_rw_start = unsafe { &crate::_fw_rw_start as *const u8 as usize };
+    let platform_addr = &raw const platform as *const _ as usize;
+
+    /* From the fw_base.S of opensbi:
+     *
+     * /* Setup scratch space for all the HARTs */
+     * lla	tp, _fw_end
+     * mul	a5, s7, s8
+     * add	tp, tp, a5
+     * /* Setup heap base address */
+     * lla	s10, _fw_start
+     * sub	s10, tp, s10
+     * add	tp, tp, s9
+     * /* Keep a copy of tp */
+     * add	t3, tp, zero
+     *
+     */
+    let fw_end_tot = fw_end + (hart_count * hart_stack_size) + heap_size;
+    let heap_start = fw_end + (hart_count * hart_stack_size) - fw_start;
+
+    for i in 0..hart_count {
+        /*
+         * Populate the sbi_scratch struct with the correct values
+         * We want to use ffi:c_ulong to avoid hardcoding pointer size.
+         * This is needed if we use riscv32 architectures.
+         * The rust bindgen library will generate the correct types
+         * based on target architecture.
+         */
+        let sbi_scratch = sbi_scratch {
+            // fw_start: start address of the firmware
+            fw_start: fw_start as ffi::c_ulong,
+            // fw_size: total firmware size, includes harts' stack and heap
+            fw_size: (fw_end_tot - fw_start) as ffi::c_ulong,
+            // fw_rw_offset: offset where the data starts
+            fw_rw_offset: (fw_rw_start - fw_start) as ffi::c_ulong,
+            // fw_heap_offset: where the heap starts from fw_start
+            fw_heap_offset: heap_start as ffi::c_ulong,
+            // fw_heap_size: heap size specified by the platform
+            fw_heap_size: heap_size as ffi::c_ulong,
+            // next_arg1: the fdt_address passed to the next stage
+            next_arg1: next_arg1 as ffi::c_ulong,
+            // next_addr: address of the next stage
+            next_addr: next_stage_address as ffi::c_ulong,
+            // next_mode: mode used to launch next_addr
+            next_mode: PrivMode::PrivS as ffi::c_ulong,
+            // warmboot_addr: address of the warmboot function.
+            // This is not supported for now, but is needed for
+            // hotplug harts and multicore
+            warmboot_addr: 0,
+            // platform_addr: address of the platform struct populated
+            // with fw_platform_init
+            platform_addr: platform_addr as ffi::c_ulong,
+            // hartid_to_scratch: function used to retrieve the hart scratch given the id
+            hartid_to_scratch: hartid_to_scratch as ffi::c_ulong,
+            // trap_context: reset to 0
+            trap_context: 0,
+            // tmp0: reset to 0
+            tmp0: 0,
+            // options: to customize the sbi_runtime
+            options: 0,
+            // hartindex: current hart index 0-based.
+            hartindex: i as ffi::c_ulong,
+        };
+
+        /*
+         * Calculate the address where to write the scratch
+         * add	tp, t3, zero
+         * sub	tp, tp, s9
+         * mul	a5, s8, t1
+         * sub	tp, tp, a5
+         * li	a5, SBI_SCRATCH_SIZE
+         * sub	tp, tp, a5
+         */
+        let scratch_addr =
+            fw_end_tot - heap_size - (hart_stack_size * i) - SBI_SCRATCH_SIZE as usize;
+
+        let p = scratch_addr as *mut sbi_scratch;
+
+        unsafe {
+            // write the structure to the calculated address
+            p.write_volatile(sbi_scratch);
+        }
+    }
+
+    register_cove_extensions();
+
+    // OpenSBI mirrors the domains of the state instead of parsing the device tree
+    unsafe {
+        let ops = platform.platform_ops_addr as *mut sbi_platform_operations;
+        (*ops).domains_init = Some(domains_init);
+        PLATFORM_FINAL_INIT = (*ops).final_init;
+        (*ops).final_init = Some(final_init);
+    }
+
+    // Prepare and jump to sbi_init. We need to:
+    //  - disable interrupts
+    //  - find the scratch for hart 0
+    unsafe {
+        use riscv::register::mtvec::Mtvec;
+        // According to the opensbi documentation, we need to disable the interrupt
+        riscv::interrupt::disable();
+
+        // Set the mscratch to the correct address
+        let scratch_addr = hartid_to_scratch(boot_hartid, hartid_to_index(boot_hartid));
+        riscv::register::mscratch::write(scratch_addr);
+
+        // set the stack pointer to the scratch.
+        // First thing they will need to do is to setup the stack pointer
+        // to a valid location
+        core::arch::asm!(
+            "csrr a0, mscratch",
+            "add tp, a0, zero",
+            options(nomem, nostack)
+        );
+
+        // set the trap handler
+        let a = Mtvec::from_bits(crate::trap::handler as usize);
+        riscv::register::mtvec::write(a);
+
+        riscv::register::mstatus::clear_tsr();
+        riscv::register::mstatus::clear_tvm();
+
+        // call sbi_init for the current hart
+        let sbi_scratch_addr = scratch_addr as *mut sbi_scratch;
+        core::arch::asm!(
+            "add sp, tp, {}", in(reg) SBI_SCRATCH_SIZE
+        );
+        sbi_init(sbi_scratch_addr)
+    }
+}
+
+/// Register the CoVE extensions in OpenSBI, so that `sbi_probe_extension` reports them. Their
+/// ecalls never reach OpenSBI: `trap.rs` sends them to `cove.rs`.
+fn register_cove_extensions() {
+    static mut EXTENSIONS: [sbi_ecall_extension; 3] = unsafe { core::mem::zeroed() };
+
+    let extids = [SBI_COVH_EXT_ID, SBI_COVI_EXT_ID, SBI_SUPD_EXT_ID];
+    let extensions = unsafe { &mut *(&raw mut EXTENSIONS) };
+    for (ext, extid) in extensions.iter_mut().zip(extids) {
+        ext.extid_start = extid as ffi::c_ulong;
+        ext.extid_end = extid as ffi::c_ulong;
+        ext.probe = Some(cove_ecall_probe);
+        ext.handle = Some(cove_ecall_handle);
+
+        let ret = unsafe { sbi_ecall_register_extension(ext) };
+        assert!(ret == 0);
+    }
+}
+
+unsafe extern "C" fn cove_ecall_probe(
+    extid: ffi::c_ulong,
+    out_val: *mut ffi::c_ulong,
+) -> ffi::c_int {
+    *out_val = crate::cove::probe_extension(extid as usize) as ffi::c_ulong;
+    0
+}
+
+// Never called, see `register_cove_extensions`
+unsafe extern "C" fn cove_ecall_handle(
+    _extid: ffi::c_ulong,
+    _funcid: ffi::c_ulong,
+    _regs: *mut sbi_trap_regs,
+    _out: *mut sbi_ecall_return,
+) -> ffi::c_int {
+    SBI_ERR_NOT_SUPPORTED
+}
+
+/// Print a character on the OpenSBI console.
+pub fn putc(c: u8) {
+    unsafe { sbi_putc(c) }
+}
+
+/// Read a byte from the OpenSBI console, if one was received.
+pub fn getc() -> Option<u8> {
+    let c = unsafe { sbi_getc() };
+    (c >= 0).then_some(c as u8)
+}
+
+/// Make `id` the active OpenSBI domain on the current hart, with the memory `regions` of the
+/// supervisor domain: the OpenSBI checks see the confidential memory it gained or gave back.
+pub fn change_active_domain(id: usize, regions: &[MemoryRegion]) {
+    if let Some(mirror) = unsafe { (*(&raw mut MIRRORS))[id].as_mut() } {
+        assert!(
+            mirror.set_regions(regions),
+            "too many regions in domain {id}"
+        );
+    }
+    let ret = unsafe { sbi_domain_change_active(id as u32) };
+    assert!(ret == 0);
+}
+
+/// Fence the G-stage TLB of the harts of `hart_mask` (hart ids) with OpenSBI TLB requests, which
+/// return once every hart fenced. OpenSBI only interrupts the started harts of the domain active
+/// on the calling hart.
+pub fn remote_hfence_gvma(hart_mask: usize) {
+    let mut info = sbi_tlb_info {
+        start: 0,
+        // start and size 0: the whole TLB
+        size: 0,
+        type_: sbi_tlb_type_SBI_TLB_HFENCE_GVMA,
+        ..Default::default()
+    };
+    hartmask_set(&mut info.smask, riscv::register::mhartid::read());
+    let ret = unsafe { sbi_tlb_request(hart_mask as ffi::c_ulong, 0, &mut info) };
+    assert!(ret == 0, "G-stage TLB shootdown failed: {ret}");
+}
+
+/// Whether the OpenSBI domain of `id` lets S-mode access `addr` with the PMP permissions `access`.
+/// `None` for the root domain, which is not mirrored.
+pub fn domain_allows(id: usize, addr: usize, access: usize) -> Option<bool> {
+    let mirror = unsafe { (*(&raw const MIRRORS)).get(id)?.as_ref()? };
+    let flags = [
+        (PMP_R, SBI_DOMAIN_READ),
+        (PMP_W, SBI_DOMAIN_WRITE),
+        (PMP_X, SBI_DOMAIN_EXECUTE),
+    ]
+    .into_iter()
+    .filter(|&(pmp, _)| access & pmp != 0)
+    .fold(0, |flags, (_, flag)| flags | flag as ffi::c_ulong);
+
+    Some(unsafe {
+        sbi_domain_check_addr(
+            &mirror.domain,
+            addr as ffi::c_ulong,
+            PrivMode::PrivS as ffi::c_ulong,
+            flags,
+        )
+    })
+}
+
+/// Firmware regions of the root domain copied in a mirror, see `Mirror::copy_firmware_regions`
+const MAX_FIRMWARE_REGIONS: usize = 8;
+/// Regions of a mirror: the firmware ones, those of the supervisor domain and the terminator
+const MAX_MIRROR_REGIONS: usize = MAX_FIRMWARE_REGIONS + MAX_MEMORY_REGIONS + 1;
+
+/// OpenSBI domain mirroring a supervisor domain. It is leaked once registered: OpenSBI keeps
+/// pointers to it and to its regions.
+struct Mirror {
+    domain: sbi_domain,
+    possible_harts: sbi_hartmask,
+    regions: [sbi_domain_memregion; MAX_MIRROR_REGIONS],
+    firmware_regions: usize,
+}
+
+impl Mirror {
+    /// Copy the M-mode only regions of the root domain (the firmware), as OpenSBI does for the
+    /// domains of the device tree. Returns false if they do not fit.
+    fn copy_firmware_regions(&mut self) -> bool {
+        let su_access = (SBI_DOMAIN_MEMREGION_SU_READABLE
+            | SBI_DOMAIN_MEMREGION_SU_WRITABLE
+            | SBI_DOMAIN_MEMREGION_SU_EXECUTABLE) as ffi::c_ulong;
+        let mut region = unsafe { (*(&raw const root)).regions };
+        while unsafe { (*region).order } != 0 {
+            let reg = unsafe { *region };
+            region = unsafe { region.add(1) };
+            if reg.flags & su_access != 0 {
+                continue;
+            }
+            if self.firmware_regions == MAX_FIRMWARE_REGIONS {
+                return false;
+            }
+            self.regions[self.firmware_regions] = reg;
+            self.firmware_regions += 1;
+        }
+        true
+    }
+
+    /// Replace the regions of the supervisor domain, after the firmware ones. Returns false if
+    /// they do not fit.
+    fn set_regions(&mut self, regions: &[MemoryRegion]) -> bool {
+        let slots = &mut self.regions[self.firmware_regions..];
+        if regions.len() >= slots.len() {
+            return false;
+        }
+        for (slot, region) in slots.iter_mut().zip(regions) {
+            let mmio = if region.mmio {
+                SBI_DOMAIN_MEMREGION_MMIO as ffi::c_ulong
+            } else {
+                0
+            };
+            slot.order = region.order as ffi::c_ulong;
+            slot.base = region.base_addr as ffi::c_ulong;
+            slot.flags = region.permissions as ffi::c_ulong | mmio;
+        }
+        slots[regions.len()] = unsafe { core::mem::zeroed() };
+        true
+    }
+}
+
+/// `final_init` of the OpenSBI platform, chained by `final_init`
+static mut PLATFORM_FINAL_INIT: Option<unsafe extern "C" fn(bool) -> ffi::c_int> = None;
+
+/// `final_init` of the OpenSBI platform, called by `sbi_init` on every hart once its traps are
+/// delegated: the untrusted domain boots, the VS-mode ecalls go through the firmware (see
+/// `cove::delegate_guest_calls`).
+unsafe extern "C" fn final_init(cold_boot: bool) -> ffi::c_int {
+    if let Some(platform_final_init) = PLATFORM_FINAL_INIT {
+        let ret = platform_final_init(cold_boot);
+        if ret != 0 {
+            return ret;
+        }
+    }
+    crate::cove::delegate_guest_calls(false);
+    0
+}
+
+/// Mirror of every supervisor domain, by id. The root domain is the OpenSBI one.
+static mut MIRRORS: [*mut Mirror; MAX_DOMAINS] = [core::ptr::null_mut(); MAX_DOMAINS];
+
+/// `domains_init` of the OpenSBI platform, called by `sbi_init` once the root domain is
+/// registered. Every other domain of the state is registered with its memory regions and the
+/// possible harts and next stage of its `opensbi,domain,instance` node. OpenSBI numbers the
+/// domains in registration order, so their index is the id of the supervisor domain. The active
+/// domain gets the boot hart.
+unsafe extern "C" fn domains_init() -> ffi::c_int {
+    let guard = STATE.lock();
+    let Some(state) = guard.get() else {
+        return SBI_ERR_FAILED;
+    };
+    let _heap_tag = crate::ALLOCATOR.tag(crate::HEAP_TAG_STATE);
+    let boot_hartid = riscv::register::mhartid::read();
+
+    for (id, domain) in state.domains.iter().enumerate().skip(1) {
+        let ret = register_domain(id, domain, boot_hartid, id == state.active_domain);
+        if ret != 0 {
+            debug!(
+                "cannot register the OpenSBI domain of domain {}: {}",
+                id, ret
+            );
+            return ret;
+        }
+    }
+    0
+}
+
+/// Register the mirror of the supervisor domain `id`. The domains without a device tree instance
+/// run on the boot hart.
+fn register_domain(id: usize, domain: &Domain, boot_hartid: usize, active: bool) -> ffi::c_int {
+    let mut mirror: Box<Mirror> = Box::new(unsafe { core::mem::zeroed() });
+    let instance = domain.instance.as_ref();
+
+    let name = instance.map_or_else(|| format!("domain{id}"), |i| i.name.clone());
+    let name_len = name.len().min(mirror.domain.name.len() - 1);
+    for (dst, &src) in mirror
+        .domain
+        .name
+        .iter_mut()
+        .zip(&name.as_bytes()[..name_len])
+    {
+        *dst = src as ffi::c_char;
+    }
+
+    match instance {
+        Some(instance) => {
+            for &hartid in &instance.possible_harts {
+                hartmask_set(&mut mirror.possible_harts, hartid);
+            }
+            mirror.domain.next_addr = instance.next_addr as ffi::c_ulong;
+            // the device tree view of the domain, unless the instance passes its own
+            let next_arg1 = match instance.next_arg1 {
+                0 => domain.fdt_view.unwrap_or(0),
+                next_arg1 => next_arg1,
+            };
+            mirror.domain.next_arg1 = next_arg1 as ffi::c_ulong;
+            mirror.domain.next_mode = instance.next_mode as ffi::c_ulong;
+        }
+        None => {
+            hartmask_set(&mut mirror.possible_harts, boot_hartid);
+            mirror.domain.next_arg1 = domain.fdt_view.unwrap_or(0) as ffi::c_ulong;
+            mirror.domain.next_mode = PrivMode::PrivS as ffi::c_ulong;
+        }
+    }
+    // `boot` starts the untrusted domain, OpenSBI starts no hart in a domain
+    mirror.domain.boot_hartid = u32::MAX;
+    mirror.domain.system_reset_allowed = domain.sbi_policy.platform_reset;
+    mirror.domain.system_suspend_allowed = true;
+
+    if !mirror.copy_firmware_regions() || !mirror.set_regions(&domain.memory_regions) {
+        return SBI_ERR_FAILED;
+    }
+
+    let mirror = Box::leak(mirror);
+    mirror.domain.possible_harts = &mirror.possible_harts;
+    mirror.domain.regions = mirror.regions.as_mut_ptr();
+
+    let mut assigned_harts: sbi_hartmask = unsafe { core::mem::zeroed() };
+    if active {
+        hartmask_set(&mut assigned_harts, boot_hartid);
+    }
+    let ret = unsafe { sbi_domain_register(&mut mirror.domain, &assigned_harts) };
+    if ret != 0 {
+        return ret;
+    }
+    assert!(
+        mirror.domain.index as usize == id,
+        "OpenSBI registered domain {id} as {}",
+        mirror.domain.index
+    );
+    unsafe { (*(&raw mut MIRRORS))[id] = mirror };
+    0
+}
+
+/// Add `hartid` to `mask`, which is indexed by hart index. Harts OpenSBI does not manage are
+/// ignored.
+fn hartmask_set(mask: &mut sbi_hartmask, hartid: usize) {
+    let Some(index) = hart_index(hartid) else {
+        return;
+    };
+    let bits = ffi::c_ulong::BITS as usize;
+    mask.bits[index / bits] |= 1 << (index % bits);
+}
+
+/// Calculates the starting address of the scratch space for a given HART (Hardware Thread).
+///
+/// This function uses the HART ID and HART Index to determine the appropriate scratch space
+/// starting address. It retrieves platform details such as the HART stack size and count,
+/// and performs calculations to find the correct address.
+///
+/// # Safety
+///
+/// This function is unsafe because it directly manipulates machine-level registers and
+/// relies on specific memory layout assumptions. It should only be called in a controlled
+/// environment where these assumptions hold true.
+#[link_section = ".text"]
+/// Position of `hartid` in the hart list of the OpenSBI platform. It differs from the hart id when
+/// some harts are not usable (e.g. the S7 monitor core of the FU740 is hart 0).
+fn hartid_to_index(hartid: usize) -> usize {
+    hart_index(hartid).expect("boot hart is not in the platform hart list")
+}
+
+/// Position of `hartid` in the hart list of the OpenSBI platform, if it is there.
+fn hart_index(hartid: usize) -> Option<usize> {
+    let platform = unsafe { &platform };
+    if platform.hart_index2id.is_null() {
+        return (hartid < platform.hart_count as usize).then_some(hartid);
+    }
+
+    (0..platform.hart_count as usize)
+        .find(|&i| unsafe { *platform.hart_index2id.add(i) } as usize == hartid)
+}
+
+extern "C" fn hartid_to_scratch(_hartid: usize, hartindex: usize) -> usize {
+    // Number of harts, stack size & heap size from the OpenSBI platform struct:
+    let hart_count = unsafe { platform.hart_count as usize };
+    let hart_stack_sz = unsafe { platform.hart_stack_size as usize };
+    let heap_sz = unsafe { platform.heap_size as usize };
+
+    // End of firmware code/data section:
+    let fw_end = unsafe { &crate::_fw_end as *const u8 as usize };
+
+    // Total top-of-memory after firmware + all stacks + heap:
+    let fw_end_tot = fw_end + hart_count * hart_stack_sz + heap_sz;
+
+    // Compute exactly where you wrote the i-th hart’s scratch:
+    let scratch_addr = fw_end_tot
+        // back off the heap
+        .saturating_sub(heap_sz)
+        // back off earlier hart stacks
+        .saturating_sub(hart_stack_sz * hartindex)
+        // back off the scratch size itself
+        .saturating_sub(SBI_SCRATCH_SIZE as usize);
+
+    scratch_addr
+}
+
+// Needed for OpenSBI
+// For some reason the static lib needs these 2 symbols defined
+// TODO: investigate why these are needed.
+// Maybe we can just use libsbi.a (without libplatsbi.a) and provide the `fw_platform_init`
+// externally.
+#[no_mangle]
+fn _start_warm() {}
+#[no_mangle]
+fn _trap_handler() {}
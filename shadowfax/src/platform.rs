@@ -0,0 +1,34 @@
+/*
+ * Platform specific constants. Every directory in `shadowfax/platform` provides a `platform.rs`
+ * next to its device tree and `platform.conf`; `build.rs` points `SHADOWFAX_PLATFORM_DIR` to the
+ * one selected with `PLATFORM` and it is included here, with the memory layout generated from
+ * `platform.conf`.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+/// UART used by the raw console, the one OpenSBI is not involved in.
+#[allow(unused)]
+#[derive(Clone, Copy)]
+pub enum Uart {
+    /// ns16550 compatible UART with byte-wide registers
+    Ns16550 { base: usize },
+    /// SiFive UART (`sifive,uart0`) with 32-bit registers
+    Sifive { base: usize },
+    /// Berkeley HTIF console (spike). It has no MMIO registers: `tohost`/`fromhost` belong to
+    /// the OpenSBI driver.
+    Htif,
+}
+
+impl Uart {
+    /// MMIO base address, `None` for consoles which are not memory mapped.
+    pub const fn base(&self) -> Option<usize> {
+        match *self {
+            Uart::Ns16550 { base } | Uart::Sifive { base } => Some(base),
+            Uart::Htif => None,
+        }
+    }
+}
+
+include!(concat!(env!("SHADOWFAX_PLATFORM_DIR"), "/platform.rs"));
+include!(concat!(env!("OUT_DIR"), "/layout.rs"));
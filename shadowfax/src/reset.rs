@@ -0,0 +1,117 @@
+/*
+ * System Reset (SRST) per supervisor domain. `trap.rs` sends the SRST calls of the supervisor
+ * domains here before the runtime sees them:
+ *  - a domain without a TSM (the root and the untrusted domain) owns the platform: its reset resets
+ *    the whole platform, unless its SBI policy has `no-platform-reset`. The memory of the
+ *    confidential domains is wiped first, so no secret survives a warm reboot, then the call goes
+ *    on to the runtime;
+ *  - a confidential domain can only shut itself down: its memory is wiped, it is stopped for good
+ *    (every later TEECALL to it fails) and the hart goes back to the first caller of the call chain
+ *    with SBI_ERR_FAILED. A confidential domain cannot reboot alone.
+ * Memory another domain owns too (e.g. the shared pages of a TVM) is not wiped. The confidential
+ * pages of a stopped domain are not given back to the host until the platform resets.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use common::sbi::{SbiRet, COVH_DEFAULT_PAGE_SIZE};
+use cove_core::activation::DomainRunState;
+
+use crate::{
+    audit::AuditEvent,
+    cove,
+    dispatch::{SBI_ERR_DENIED, SBI_ERR_FAILED, SBI_ERR_INVALID_PARAM, SBI_ERR_NOT_SUPPORTED},
+    runtime::TrapRegs,
+    state::State,
+};
+
+pub const EXT_SRST: usize = 0x53525354;
+const SRST_SYSTEM_RESET: usize = 0;
+
+const RESET_TYPE_SHUTDOWN: usize = 0;
+const RESET_TYPE_WARM_REBOOT: usize = 2;
+
+pub enum Reset {
+    /// Reset the platform: the runtime handles the call
+    Platform,
+    /// The caller was stopped, the trap frame holds the first caller of the call chain
+    Stopped,
+    /// Back to the caller with an error
+    Denied(SbiRet),
+}
+
+/// Handle the SRST call `fid` of the active domain, whose trap frame is `regs`.
+pub fn handle(state: &mut State, regs: &mut TrapRegs, fid: usize, args: &[usize; 6]) -> Reset {
+    let (reset_type, reason) = (args[0], args[1]);
+    if fid != SRST_SYSTEM_RESET {
+        return denied(SBI_ERR_NOT_SUPPORTED);
+    }
+    if reset_type > RESET_TYPE_WARM_REBOOT {
+        return denied(SBI_ERR_INVALID_PARAM);
+    }
+
+    let id = state.active_domain;
+    let domain = &state.domains[id];
+    if !domain.has_tsm {
+        if !domain.sbi_policy.platform_reset {
+            state.record(AuditEvent::PolicyDenied, EXT_SRST, fid);
+            return denied(SBI_ERR_DENIED);
+        }
+
+        debug!("domain {} resets the platform (type {})", id, reset_type);
+        for confidential in 0..state.domains.len() {
+            if state.domains[confidential].has_tsm {
+                wipe(state, confidential);
+            }
+        }
+        state.record(AuditEvent::PlatformReset, reset_type, reason);
+        return Reset::Platform;
+    }
+
+    // A confidential domain runs inside a TEECALL only
+    if reset_type != RESET_TYPE_SHUTDOWN {
+        return denied(SBI_ERR_NOT_SUPPORTED);
+    }
+    if state.calls.top().is_none_or(|call| call.callee != id) {
+        return denied(SBI_ERR_DENIED);
+    }
+
+    debug!("domain {} shuts down", id);
+    state.record(AuditEvent::DomainStopped, reset_type, reason);
+    wipe(state, id);
+    cove::abort_calls(state, regs, SBI_ERR_FAILED);
+    state.domains[id].run_state = DomainRunState::Stopped;
+    Reset::Stopped
+}
+
+/// Zero the memory of `domain`, a page at a time: the pages another domain owns too are skipped
+/// (the root domain owns everything, but never runs).
+fn wipe(state: &State, domain: usize) {
+    let others = || {
+        state
+            .domains
+            .iter()
+            .enumerate()
+            .filter(move |(id, _)| *id != 0 && *id != domain)
+    };
+
+    for region in state.domains[domain]
+        .memory_regions
+        .iter()
+        .filter(|r| !r.mmio)
+    {
+        let Some(size) = 1usize.checked_shl(region.order) else {
+            continue;
+        };
+        for page in (region.base_addr..region.base_addr + size).step_by(COVH_DEFAULT_PAGE_SIZE) {
+            if others().any(|(_, other)| other.owns(page, COVH_DEFAULT_PAGE_SIZE)) {
+                continue;
+            }
+            unsafe { core::ptr::write_bytes(page as *mut u8, 0, COVH_DEFAULT_PAGE_SIZE) };
+        }
+    }
+}
+
+fn denied(code: isize) -> Reset {
+    Reset::Denied(SbiRet { a0: code, a1: 0 })
+}
@@ -0,0 +1,79 @@
+/*
+ * Random number service. The TSM and the TVMs have no entropy source of their own (the CDI-based
+ * seeds are deterministic), so the TSM-driver runs an HMAC_DRBG and serves it through
+ * `SBI_EXT_SUPD_GET_RANDOM`.
+ *
+ * The DRBG is seeded and reseeded from:
+ * - timing jitter of a busy loop measured with `mcycle`;
+ * - the platform TRNG, if the device tree declares one (`timeriomem_rng`).
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use common::drbg::{DrbgError, HmacDrbg};
+
+const PERSONALIZATION: &[u8] = b"Shadowfax TSM-driver DRBG";
+// Number of raw samples collected for each (re)seed
+const ENTROPY_SAMPLES: usize = 64;
+
+pub struct Rng {
+    drbg: HmacDrbg,
+    /// Data register of the platform TRNG
+    trng: Option<usize>,
+}
+
+impl Rng {
+    pub fn new(trng: Option<usize>) -> Self {
+        let entropy = collect_entropy(trng);
+        let nonce = riscv::register::mcycle::read().to_le_bytes();
+        Self {
+            drbg: HmacDrbg::new(&entropy, &nonce, PERSONALIZATION),
+            trng,
+        }
+    }
+
+    pub fn fill(&mut self, out: &mut [u8]) -> Result<(), DrbgError> {
+        loop {
+            match self.drbg.generate(out, &[]) {
+                Ok(()) => return Ok(()),
+                Err(DrbgError::ReseedRequired) => {
+                    let entropy = collect_entropy(self.trng);
+                    self.drbg.reseed(&entropy, &[]);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn next_u64(&mut self) -> Result<u64, DrbgError> {
+        let mut buf = [0u8; 8];
+        self.fill(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+/// Collect raw entropy. The samples are not conditioned here: the DRBG update hashes them.
+fn collect_entropy(trng: Option<usize>) -> [u8; ENTROPY_SAMPLES * 8] {
+    let mut entropy = [0u8; ENTROPY_SAMPLES * 8];
+
+    for (i, sample) in entropy.chunks_exact_mut(8).enumerate() {
+        let mut value = jitter_sample(i) as u64;
+        if let Some(trng) = trng {
+            let word = unsafe { (trng as *const u32).read_volatile() };
+            value ^= (word as u64) << 32;
+        }
+        sample.copy_from_slice(&value.to_le_bytes());
+    }
+    entropy
+}
+
+/// Cycles spent in a short data-dependent loop. Only the low bits carry entropy.
+fn jitter_sample(seed: usize) -> usize {
+    let start = riscv::register::mcycle::read();
+    let mut acc = seed;
+    for i in 0..(16 + (start & 0xF)) {
+        acc = core::hint::black_box(acc.rotate_left(7) ^ i);
+    }
+    let end = riscv::register::mcycle::read();
+    end.wrapping_sub(start) ^ (end << 16) ^ acc
+}
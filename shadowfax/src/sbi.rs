@@ -0,0 +1,447 @@
+/*
+ * Pure-Rust SBI core, an experimental replacement of the OpenSBI runtime enabled with the
+ * `rust-sbi` feature. It provides the runtime interface of `opensbi.rs` and implements the
+ * extensions a host needs on the boot hart:
+ *  - BASE (DBCN is served by `crate::console` for every runtime, `console.rs` only writes);
+ *  - TIME (`timer.rs`), backed by the CLINT;
+ *  - IPI (`ipi.rs`), backed by the CLINT;
+ *  - HSM (`hsm.rs`): only the boot hart is managed, the other harts never leave `_start`;
+ *  - SRST (`srst.rs`): SiFive test device (QEMU) or HTIF (spike).
+ *
+ * The CoVE extensions do not get here: `trap.rs` sends them to `cove.rs` before calling
+ * `trap_handler`. Exceptions which are not ecalls are redirected to the supervisor, there is no
+ * emulation in M-mode (e.g. misaligned accesses are delegated).
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use common::sbi::{SbiRet, SBI_COVH_EXT_ID, SBI_COVI_EXT_ID, SBI_SUPD_EXT_ID};
+use riscv::register::{
+    mhartid, misa, mscratch,
+    mstatus::{self, MPP},
+    mtvec::{self, Mtvec},
+};
+
+use crate::{
+    constants::memory_layout::UNTRUSTED_DOMAIN_REGIONS,
+    cove::{probe_extension, program_pmp_from_regions},
+    domain::MemoryRegion,
+    state::STATE,
+};
+
+mod console;
+mod hsm;
+mod htif;
+mod ipi;
+mod srst;
+pub mod timer;
+
+pub use console::{getc, putc};
+
+macro_rules! read_csr {
+    ($csr:literal) => {{
+        let value: usize;
+        unsafe { core::arch::asm!(concat!("csrr {}, ", $csr), out(reg) value) };
+        value
+    }};
+}
+
+// SBI error codes
+const SBI_ERR_FAILED: isize = -1;
+const SBI_ERR_NOT_SUPPORTED: isize = -2;
+const SBI_ERR_INVALID_PARAM: isize = -3;
+const SBI_ERR_ALREADY_AVAILABLE: isize = -6;
+
+const EXT_BASE: usize = 0x10;
+const BASE_GET_SPEC_VERSION: usize = 0;
+const BASE_GET_IMPL_ID: usize = 1;
+const BASE_GET_IMPL_VERSION: usize = 2;
+const BASE_PROBE_EXTENSION: usize = 3;
+const BASE_GET_MVENDORID: usize = 4;
+const BASE_GET_MARCHID: usize = 5;
+const BASE_GET_MIMPID: usize = 6;
+
+// SBI v2.0
+const SPEC_VERSION: usize = 2 << 24;
+// Not a registered SBI implementation ID ("SHFX")
+const IMPL_ID: usize = 0x5348_4658;
+const IMPL_VERSION: usize = 1;
+
+// mcause
+const CAUSE_INTERRUPT: usize = 1 << (usize::BITS - 1);
+const IRQ_M_SOFT: usize = 3;
+const IRQ_M_TIMER: usize = 7;
+const CAUSE_SUPERVISOR_ECALL: usize = 9;
+
+// Interrupts and exceptions handled by the supervisor, as in OpenSBI
+const MIDELEG: usize = (1 << 1) | (1 << 5) | (1 << 9);
+const MEDELEG: usize = (1 << 0) // misaligned fetch
+    | (1 << 3) // breakpoint
+    | (1 << 4) // misaligned load
+    | (1 << 6) // misaligned store
+    | (1 << 8) // user ecall
+    | (1 << 12) // fetch page fault
+    | (1 << 13) // load page fault
+    | (1 << 15); // store page fault
+                 // With the H extension, HS-mode handles the guest page faults. The VS-mode ecalls
+                 // are delegated while a TSM runs, see `cove::delegate_guest_calls`
+const MEDELEG_H: usize = (1 << 20) | (1 << 21) | (1 << 22) | (1 << 23);
+
+// mstatus fields written at boot and when redirecting a trap to the supervisor
+const MSTATUS_SIE: usize = 1 << 1;
+const MSTATUS_SPIE: usize = 1 << 5;
+const MSTATUS_MPIE: usize = 1 << 7;
+const MSTATUS_SPP: usize = 1 << 8;
+const MSTATUS_MPP_SHIFT: usize = 11;
+const MSTATUS_MPP: usize = 3 << MSTATUS_MPP_SHIFT;
+// mstatus.MPV (mstatush on RV32)
+#[cfg(target_pointer_width = "64")]
+const MSTATUS_MPV: usize = 1 << 39;
+#[cfg(target_pointer_width = "32")]
+const MSTATUS_MPV: usize = 1 << 7;
+
+// hstatus fields written when redirecting a trap from a virtualized mode to HS-mode
+const HSTATUS_GVA: usize = 1 << 6;
+const HSTATUS_SPV: usize = 1 << 7;
+const HSTATUS_SPVP: usize = 1 << 8;
+
+// Position of the untrusted domain in `STATE.domains`, see `state::init`
+const UNTRUSTED_DOMAIN_ID: usize = 2;
+
+// M-mode stack of the boot hart
+const HART_STACK_SIZE: usize = 0x4000;
+
+/// Registers saved by the trap entry, same layout as the OpenSBI `struct sbi_trap_regs`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct TrapRegs {
+    pub zero: usize,
+    pub ra: usize,
+    pub sp: usize,
+    pub gp: usize,
+    pub tp: usize,
+    pub t0: usize,
+    pub t1: usize,
+    pub t2: usize,
+    pub s0: usize,
+    pub s1: usize,
+    pub a0: usize,
+    pub a1: usize,
+    pub a2: usize,
+    pub a3: usize,
+    pub a4: usize,
+    pub a5: usize,
+    pub a6: usize,
+    pub a7: usize,
+    pub s2: usize,
+    pub s3: usize,
+    pub s4: usize,
+    pub s5: usize,
+    pub s6: usize,
+    pub s7: usize,
+    pub s8: usize,
+    pub s9: usize,
+    pub s10: usize,
+    pub s11: usize,
+    pub t3: usize,
+    pub t4: usize,
+    pub t5: usize,
+    pub t6: usize,
+    pub mepc: usize,
+    pub mstatus: usize,
+    #[allow(non_snake_case)]
+    pub mstatusH: usize,
+}
+
+/// Trap details saved after the registers, same layout as the OpenSBI `struct sbi_trap_info`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct TrapInfo {
+    pub cause: usize,
+    pub tval: usize,
+    pub tval2: usize,
+    pub tinst: usize,
+    pub gva: usize,
+}
+
+/// Trap context built by `trap::handler` on the exception stack.
+#[repr(C)]
+pub struct TrapContext {
+    pub regs: TrapRegs,
+    info: TrapInfo,
+}
+
+/// Per-hart scratch pointed by `mscratch`. It is the top of the M-mode stack of the hart: the trap
+/// entry switches to it when the trap comes from a lower privilege mode.
+#[repr(C)]
+pub struct Scratch {
+    tmp0: usize,
+}
+
+#[repr(C, align(16))]
+struct HartStack {
+    stack: [u8; HART_STACK_SIZE],
+    scratch: Scratch,
+}
+
+static mut BOOT_HART_STACK: HartStack = HartStack {
+    stack: [0; HART_STACK_SIZE],
+    scratch: Scratch { tmp0: 0 },
+};
+
+/// Offset of the temporary slot used by the trap entry in the scratch pointed by `mscratch`.
+pub const SCRATCH_TMP0_OFFSET: usize = core::mem::offset_of!(Scratch, tmp0);
+
+/// Supervisor domain running on the boot hart
+static ACTIVE_DOMAIN: AtomicUsize = AtomicUsize::new(0);
+
+/// Called by `_start`. There is nothing to probe: the device tree of the previous stage is kept.
+pub extern "C" fn platform_init(
+    _a0: usize,
+    a1: usize,
+    _a2: usize,
+    _a3: usize,
+    _a4: usize,
+) -> usize {
+    a1
+}
+
+/// Delegate the supervisor traps, protect the firmware and jump to the untrusted domain at
+/// `next_stage_address` in S-mode (`a0` = hart id, `a1` = `next_arg1`, its device tree).
+pub fn boot(boot_hartid: usize, fdt_addr: usize, next_arg1: usize, next_stage_address: usize) -> ! {
+    srst::init(fdt_addr);
+
+    let medeleg = if misa::read().has_extension('H') {
+        MEDELEG | MEDELEG_H
+    } else {
+        MEDELEG
+    };
+
+    unsafe {
+        riscv::interrupt::disable();
+
+        mscratch::write(&raw mut BOOT_HART_STACK.scratch as usize);
+        mtvec::write(Mtvec::from_bits(crate::trap::handler as usize));
+
+        core::arch::asm!(
+            "csrw mideleg, {mideleg}",
+            "csrw medeleg, {medeleg}",
+            // the supervisor reads cycle, time and instret
+            "csrw mcounteren, {mcounteren}",
+            mideleg = in(reg) MIDELEG,
+            medeleg = in(reg) medeleg,
+            mcounteren = in(reg) 0x7,
+        );
+        // IPIs are machine software interrupts, the timer is enabled by SET_TIMER
+        riscv::register::mie::set_msoft();
+    }
+
+    // The regions of the state include the devices assigned to the domain
+    let guard = STATE.lock();
+    let regions = match guard.get() {
+        Some(state) => &state.domains[UNTRUSTED_DOMAIN_ID].memory_regions[..],
+        None => &UNTRUSTED_DOMAIN_REGIONS[..],
+    };
+    change_active_domain(UNTRUSTED_DOMAIN_ID, regions);
+    program_pmp_from_regions(regions);
+    drop(guard);
+
+    unsafe {
+        mstatus::set_mpp(MPP::Supervisor);
+        mstatus::clear_tsr();
+        mstatus::clear_tvm();
+        // interrupts stay disabled in M-mode after mret
+        core::arch::asm!("csrc mstatus, {}", in(reg) MSTATUS_MPIE);
+        riscv::register::mepc::write(next_stage_address);
+
+        core::arch::asm!(
+            "mret",
+            in("a0") boot_hartid,
+            in("a1") next_arg1,
+            options(noreturn)
+        )
+    }
+}
+
+/// Record the supervisor domain running on the hart. The PMP is programmed by the caller from
+/// the regions of the domain.
+pub fn change_active_domain(id: usize, _regions: &[MemoryRegion]) {
+    ACTIVE_DOMAIN.store(id, Ordering::Relaxed);
+}
+
+/// Only the boot hart runs (see `sbi/hsm.rs`): no other hart caches G-stage translations.
+pub fn remote_hfence_gvma(hart_mask: usize) {
+    debug_assert!(hart_mask & !(1 << mhartid::read()) == 0);
+}
+
+/// The pure-Rust core has no domains of its own: the PMP is the only view of the isolation of the
+/// supervisor domains.
+pub fn domain_allows(_id: usize, _addr: usize, _access: usize) -> Option<bool> {
+    None
+}
+
+/// Handles the traps which are not CoVE ecalls. Takes the saved trap context and returns the
+/// registers to restore.
+pub fn trap_handler(ctx: &mut TrapContext) -> &mut TrapRegs {
+    let cause = ctx.info.cause;
+
+    if cause & CAUSE_INTERRUPT != 0 {
+        match cause & !CAUSE_INTERRUPT {
+            IRQ_M_SOFT => ipi::interrupt(),
+            IRQ_M_TIMER => timer::interrupt(),
+            irq => panic!("unexpected interrupt {irq}"),
+        }
+        return &mut ctx.regs;
+    }
+
+    let prev_mode = (ctx.regs.mstatus & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT;
+    if prev_mode == MPP::Machine as usize {
+        panic!(
+            "M-mode trap {cause:#x} at {:#x}: {:?}",
+            ctx.regs.mepc, ctx.info
+        );
+    }
+
+    if cause == CAUSE_SUPERVISOR_ECALL {
+        let ret = ecall(&ctx.regs);
+        ctx.regs.a0 = ret.a0 as usize;
+        ctx.regs.a1 = ret.a1 as usize;
+        ctx.regs.mepc += 4;
+    } else {
+        redirect(ctx);
+    }
+    &mut ctx.regs
+}
+
+fn ecall(regs: &TrapRegs) -> SbiRet {
+    let args = [regs.a0, regs.a1, regs.a2, regs.a3, regs.a4, regs.a5];
+    let fid = regs.a6;
+
+    match regs.a7 {
+        EXT_BASE => base(fid, &args),
+        timer::EXT_TIME => timer::handle(fid, &args),
+        ipi::EXT_IPI => ipi::handle(fid, &args),
+        hsm::EXT_HSM => hsm::handle(fid, &args),
+        srst::EXT_SRST => srst::handle(fid, &args),
+        _ => error(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+fn base(fid: usize, args: &[usize; 6]) -> SbiRet {
+    match fid {
+        BASE_GET_SPEC_VERSION => success(SPEC_VERSION),
+        BASE_GET_IMPL_ID => success(IMPL_ID),
+        BASE_GET_IMPL_VERSION => success(IMPL_VERSION),
+        BASE_PROBE_EXTENSION => success(match args[0] {
+            EXT_BASE
+            | crate::console::EXT_DBCN
+            | timer::EXT_TIME
+            | ipi::EXT_IPI
+            | hsm::EXT_HSM
+            | srst::EXT_SRST => 1,
+            SBI_COVH_EXT_ID | SBI_COVI_EXT_ID | SBI_SUPD_EXT_ID => probe_extension(args[0]),
+            _ => 0,
+        }),
+        BASE_GET_MVENDORID => success(read_csr!("mvendorid")),
+        BASE_GET_MARCHID => success(read_csr!("marchid")),
+        BASE_GET_MIMPID => success(read_csr!("mimpid")),
+        _ => error(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// Forward the exception of the trap context to the supervisor trap handler. Never fails, the
+/// signature is the one of the OpenSBI runtime.
+pub fn redirect_trap(ctx: &mut TrapContext) -> Result<&mut TrapRegs, isize> {
+    redirect(ctx);
+    Ok(&mut ctx.regs)
+}
+
+/// Forward an exception to the supervisor trap handler (`stvec`), as if it had been delegated.
+/// Traps from VS/VU-mode go to HS-mode, which is told about the virtualization mode through
+/// `hstatus`.
+fn redirect(ctx: &mut TrapContext) {
+    let regs = &mut ctx.regs;
+    let info = &ctx.info;
+    let prev_mode = (regs.mstatus & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT;
+
+    #[cfg(target_pointer_width = "64")]
+    let mpv = &mut regs.mstatus;
+    #[cfg(target_pointer_width = "32")]
+    let mpv = &mut regs.mstatusH;
+
+    if *mpv & MSTATUS_MPV != 0 {
+        *mpv &= !MSTATUS_MPV;
+
+        let mut hstatus = read_csr!("0x600");
+        hstatus &= !(HSTATUS_SPVP | HSTATUS_GVA);
+        hstatus |= HSTATUS_SPV;
+        if prev_mode == MPP::Supervisor as usize {
+            hstatus |= HSTATUS_SPVP;
+        }
+        if info.gva != 0 {
+            hstatus |= HSTATUS_GVA;
+        }
+        unsafe {
+            core::arch::asm!(
+                "csrw 0x600, {hstatus}",
+                "csrw 0x643, {htval}",
+                "csrw 0x64a, {htinst}",
+                hstatus = in(reg) hstatus,
+                htval = in(reg) info.tval2,
+                htinst = in(reg) info.tinst,
+            );
+        }
+    }
+
+    unsafe {
+        core::arch::asm!(
+            "csrw sepc, {sepc}",
+            "csrw scause, {scause}",
+            "csrw stval, {stval}",
+            sepc = in(reg) regs.mepc,
+            scause = in(reg) info.cause,
+            stval = in(reg) info.tval,
+        );
+        regs.mepc = riscv::register::stvec::read().bits();
+    }
+
+    // sstatus is a view of mstatus: update the copy restored by the trap exit
+    let sie = regs.mstatus & MSTATUS_SIE != 0;
+    regs.mstatus &= !(MSTATUS_SPP | MSTATUS_SPIE | MSTATUS_SIE | MSTATUS_MPP);
+    regs.mstatus |= (MPP::Supervisor as usize) << MSTATUS_MPP_SHIFT;
+    if prev_mode == MPP::Supervisor as usize {
+        regs.mstatus |= MSTATUS_SPP;
+    }
+    if sie {
+        regs.mstatus |= MSTATUS_SPIE;
+    }
+}
+
+/// Whether the hart set (`hart_mask`, `hart_mask_base`) of an ecall includes the boot hart, the
+/// only one managed. `Err` if it names other harts.
+fn targets_boot_hart(hart_mask: usize, hart_mask_base: usize) -> Result<bool, isize> {
+    // a base of -1 selects all the harts
+    if hart_mask_base == usize::MAX {
+        return Ok(true);
+    }
+
+    let hartid = mhartid::read();
+    match hartid.checked_sub(hart_mask_base) {
+        Some(bit) if bit < usize::BITS as usize && hart_mask == 1 << bit => Ok(true),
+        _ if hart_mask == 0 => Ok(false),
+        _ => Err(SBI_ERR_INVALID_PARAM),
+    }
+}
+
+const fn success(value: usize) -> SbiRet {
+    SbiRet {
+        a0: 0,
+        a1: value as isize,
+    }
+}
+
+const fn error(code: isize) -> SbiRet {
+    SbiRet { a0: code, a1: 0 }
+}
@@ -0,0 +1,23 @@
+/*
+ * Console of the rust-sbi runtime: the platform UART, or the HTIF on spike (output only). The DBCN
+ * calls of the supervisor domains are served by `crate::console`, through `putc` and `getc`.
+ */
+
+use super::htif;
+use crate::{
+    debug::raw::RawConsole,
+    platform::{Uart, UART},
+};
+
+/// Write a byte on the platform console.
+pub fn putc(c: u8) {
+    match UART {
+        Uart::Htif => htif::putc(c),
+        _ => RawConsole::new().putc(c),
+    }
+}
+
+/// Read a byte from the platform console, if one was received.
+pub fn getc() -> Option<u8> {
+    RawConsole::new().getc()
+}
@@ -0,0 +1,40 @@
+/*
+ * Hart State Management extension (HSM). Only the boot hart runs the supervisor: it is always
+ * started and no other hart can be started.
+ */
+
+use common::sbi::SbiRet;
+use riscv::register::mhartid;
+
+use super::{
+    error, success, SBI_ERR_ALREADY_AVAILABLE, SBI_ERR_INVALID_PARAM, SBI_ERR_NOT_SUPPORTED,
+};
+
+pub const EXT_HSM: usize = 0x48534D;
+const HSM_HART_START: usize = 0;
+const HSM_HART_STOP: usize = 1;
+const HSM_HART_GET_STATUS: usize = 2;
+const HSM_HART_SUSPEND: usize = 3;
+
+const HART_STATE_STARTED: usize = 0;
+const SUSPEND_DEFAULT_RETENTIVE: usize = 0;
+
+pub fn handle(fid: usize, args: &[usize; 6]) -> SbiRet {
+    let hartid = mhartid::read();
+
+    match fid {
+        HSM_HART_START if args[0] == hartid => error(SBI_ERR_ALREADY_AVAILABLE),
+        HSM_HART_START => error(SBI_ERR_INVALID_PARAM),
+        // nobody can start the hart again
+        HSM_HART_STOP => loop {
+            riscv::asm::wfi();
+        },
+        HSM_HART_GET_STATUS if args[0] == hartid => success(HART_STATE_STARTED),
+        HSM_HART_GET_STATUS => error(SBI_ERR_INVALID_PARAM),
+        HSM_HART_SUSPEND if args[0] == SUSPEND_DEFAULT_RETENTIVE => {
+            riscv::asm::wfi();
+            success(0)
+        }
+        _ => error(SBI_ERR_NOT_SUPPORTED),
+    }
+}
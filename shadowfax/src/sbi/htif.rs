@@ -0,0 +1,53 @@
+/*
+ * Host-Target Interface (HTIF) of spike. The host polls `tohost` (the symbols are looked up in
+ * the firmware ELF) and acknowledges the commands in `fromhost`.
+ */
+
+use core::ptr::{read_volatile, write_volatile};
+
+const DEV_SYSCALL: u64 = 0;
+const DEV_CONSOLE: u64 = 1;
+const CONSOLE_CMD_PUTC: u64 = 1;
+
+#[no_mangle]
+#[link_section = ".htif"]
+static mut tohost: u64 = 0;
+
+#[no_mangle]
+#[link_section = ".htif"]
+static mut fromhost: u64 = 0;
+
+/// Send a command to the host, after the previous one has been consumed.
+fn send(device: u64, cmd: u64, payload: u64) {
+    let packet = device << 56 | cmd << 48 | payload;
+
+    unsafe {
+        while read_volatile(&raw const tohost) != 0 {
+            // drop the acks, nobody waits for them
+            write_volatile(&raw mut fromhost, 0);
+        }
+
+        #[cfg(target_pointer_width = "64")]
+        write_volatile(&raw mut tohost, packet);
+
+        // the host reacts to the low word, written last
+        #[cfg(target_pointer_width = "32")]
+        {
+            let words = &raw mut tohost as *mut u32;
+            write_volatile(words.add(1), (packet >> 32) as u32);
+            write_volatile(words, packet as u32);
+        }
+    }
+}
+
+pub fn putc(c: u8) {
+    send(DEV_CONSOLE, CONSOLE_CMD_PUTC, c as u64);
+}
+
+/// Terminate the simulation with the exit `code`.
+pub fn exit(code: u64) -> ! {
+    send(DEV_SYSCALL, 0, code << 1 | 1);
+    loop {
+        riscv::asm::wfi();
+    }
+}
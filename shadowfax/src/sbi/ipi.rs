@@ -0,0 +1,38 @@
+/*
+ * IPI extension. Supervisor software interrupts are sent through the machine software interrupt
+ * of the CLINT (`msip`), which `interrupt` turns into `mip.SSIP`.
+ */
+
+use common::sbi::SbiRet;
+use riscv::register::{mhartid, mip};
+
+use super::{error, success, targets_boot_hart, SBI_ERR_NOT_SUPPORTED};
+use crate::platform::CLINT_BASE;
+
+pub const EXT_IPI: usize = 0x735049;
+const IPI_SEND_IPI: usize = 0;
+
+pub fn handle(fid: usize, args: &[usize; 6]) -> SbiRet {
+    match fid {
+        IPI_SEND_IPI => match targets_boot_hart(args[0], args[1]) {
+            Ok(true) => {
+                write_msip(mhartid::read(), 1);
+                success(0)
+            }
+            Ok(false) => success(0),
+            Err(code) => error(code),
+        },
+        _ => error(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// Machine software interrupt: clear it and raise the supervisor one.
+pub fn interrupt() {
+    write_msip(mhartid::read(), 0);
+    unsafe { mip::set_ssoft() };
+}
+
+fn write_msip(hartid: usize, value: u32) {
+    let msip = (CLINT_BASE + 4 * hartid) as *mut u32;
+    unsafe { msip.write_volatile(value) };
+}
@@ -0,0 +1,77 @@
+/*
+ * System Reset extension (SRST). The reset device is found in the device tree: the SiFive test
+ * device of the QEMU machines or the HTIF of spike, which can only power off.
+ */
+
+use common::sbi::SbiRet;
+use spin::Mutex;
+
+use super::{error, htif, SBI_ERR_FAILED, SBI_ERR_INVALID_PARAM, SBI_ERR_NOT_SUPPORTED};
+use crate::fdt;
+
+pub const EXT_SRST: usize = 0x53525354;
+const SRST_SYSTEM_RESET: usize = 0;
+
+const RESET_TYPE_SHUTDOWN: usize = 0;
+const RESET_TYPE_COLD_REBOOT: usize = 1;
+const RESET_TYPE_WARM_REBOOT: usize = 2;
+const RESET_REASON_NONE: usize = 0;
+
+// SiFive test device commands
+const TEST_FAIL: u32 = 0x3333;
+const TEST_PASS: u32 = 0x5555;
+const TEST_RESET: u32 = 0x7777;
+
+#[derive(Clone, Copy)]
+enum ResetDevice {
+    None,
+    SifiveTest(usize),
+    Htif,
+}
+
+static RESET_DEVICE: Mutex<ResetDevice> = Mutex::new(ResetDevice::None);
+
+/// Look for the reset device in the device tree at `fdt_addr`.
+pub fn init(fdt_addr: usize) {
+    let device = if fdt::has_htif(fdt_addr) {
+        ResetDevice::Htif
+    } else if let Some(base) = fdt::find_test_device(fdt_addr) {
+        ResetDevice::SifiveTest(base)
+    } else {
+        ResetDevice::None
+    };
+    *RESET_DEVICE.lock() = device;
+}
+
+pub fn handle(fid: usize, args: &[usize; 6]) -> SbiRet {
+    match fid {
+        SRST_SYSTEM_RESET => system_reset(args[0], args[1]),
+        _ => error(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// Returns only on failure.
+fn system_reset(reset_type: usize, reason: usize) -> SbiRet {
+    if reset_type > RESET_TYPE_WARM_REBOOT {
+        return error(SBI_ERR_INVALID_PARAM);
+    }
+
+    let device = *RESET_DEVICE.lock();
+    match (device, reset_type) {
+        (ResetDevice::SifiveTest(base), RESET_TYPE_SHUTDOWN) => {
+            let cmd = match reason {
+                RESET_REASON_NONE => TEST_PASS,
+                _ => TEST_FAIL | 1 << 16,
+            };
+            unsafe { (base as *mut u32).write_volatile(cmd) };
+        }
+        (ResetDevice::SifiveTest(base), RESET_TYPE_COLD_REBOOT | RESET_TYPE_WARM_REBOOT) => unsafe {
+            (base as *mut u32).write_volatile(TEST_RESET)
+        },
+        (ResetDevice::Htif, RESET_TYPE_SHUTDOWN) => htif::exit(reason as u64),
+        _ => return error(SBI_ERR_NOT_SUPPORTED),
+    }
+
+    // the device did not reset the system
+    error(SBI_ERR_FAILED)
+}
@@ -0,0 +1,47 @@
+/*
+ * Timer extension (TIME). The supervisor timer is emulated with the machine timer of the CLINT:
+ * when it fires, `interrupt` raises the supervisor timer interrupt (`mip.STIP`).
+ */
+
+use common::sbi::SbiRet;
+use riscv::register::{mhartid, mie, mip};
+
+use super::{error, success, SBI_ERR_NOT_SUPPORTED};
+use crate::scheduler::write_mtimecmp;
+
+pub const EXT_TIME: usize = 0x54494D45;
+const TIME_SET_TIMER: usize = 0;
+
+pub fn handle(fid: usize, args: &[usize; 6]) -> SbiRet {
+    match fid {
+        TIME_SET_TIMER => {
+            #[cfg(target_pointer_width = "64")]
+            let deadline = args[0] as u64;
+            #[cfg(target_pointer_width = "32")]
+            let deadline = (args[1] as u64) << 32 | args[0] as u64;
+
+            set_timer(deadline);
+            success(0)
+        }
+        _ => error(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// Program the next supervisor timer event at the absolute time `deadline`.
+fn set_timer(deadline: u64) {
+    write_mtimecmp(mhartid::read(), deadline);
+    unsafe {
+        // the previous event is consumed
+        mip::clear_stimer();
+        mie::set_mtimer();
+    }
+}
+
+/// Machine timer interrupt: forward it to the supervisor, which is expected to program the next
+/// event with SET_TIMER.
+pub fn interrupt() {
+    unsafe {
+        mie::clear_mtimer();
+        mip::set_stimer();
+    }
+}
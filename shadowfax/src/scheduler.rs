@@ -1,20 +1,52 @@
-use crate::state::STATE;
+use crate::{cove, interrupts, platform::CLINT_BASE, runtime::TrapRegs, state::STATE};
 
-const CLINT_BASE: usize = 0x200_0000;
 const MTIMECMP_OFFSET: usize = 0x4000;
 const MTIME_OFFSET: usize = 0xbff8;
 
 // Helper to access CLINT registers
+#[cfg(target_pointer_width = "64")]
 unsafe fn clint_read(offset: usize) -> u64 {
     let addr = (CLINT_BASE + offset) as *const u64;
     addr.read_volatile()
 }
 
+#[cfg(target_pointer_width = "64")]
 unsafe fn clint_write(offset: usize, value: u64) {
     let addr = (CLINT_BASE + offset) as *mut u64;
     addr.write_volatile(value);
 }
 
+// RV32 accesses the 64-bit registers in two halves. A read is retried if the high half changed
+// in the meantime (low half wrapped around).
+#[cfg(target_pointer_width = "32")]
+unsafe fn clint_read(offset: usize) -> u64 {
+    let lo = (CLINT_BASE + offset) as *const u32;
+    let hi = (CLINT_BASE + offset + 4) as *const u32;
+    loop {
+        let high = hi.read_volatile();
+        let low = lo.read_volatile();
+        if hi.read_volatile() == high {
+            return (high as u64) << 32 | low as u64;
+        }
+    }
+}
+
+// Privileged spec sequence for mtimecmp on RV32: park the low half at its maximum so that no
+// spurious interrupt fires while the two halves are inconsistent.
+#[cfg(target_pointer_width = "32")]
+unsafe fn clint_write(offset: usize, value: u64) {
+    let lo = (CLINT_BASE + offset) as *mut u32;
+    let hi = (CLINT_BASE + offset + 4) as *mut u32;
+    lo.write_volatile(u32::MAX);
+    hi.write_volatile((value >> 32) as u32);
+    lo.write_volatile(value as u32);
+}
+
+/// Current value of `mtime`. S-mode software can rewrite its own view of time, this read cannot.
+pub fn read_mtime() -> u64 {
+    unsafe { clint_read(MTIME_OFFSET) }
+}
+
 // Set the next timer interrupt
 pub fn set_timer(interval_ns: u64) {
     unsafe {
@@ -34,9 +66,40 @@ pub fn set_timer(interval_ns: u64) {
         core::arch::asm!("csrw mie, {}", in(reg) mie);
     }
 }
+/// Program `mtimecmp` of `hartid` with an absolute deadline.
+pub fn write_mtimecmp(hartid: usize, value: u64) {
+    unsafe { clint_write(MTIMECMP_OFFSET + 8 * hartid, value) }
+}
+
+/// Current deadline of `hartid`.
+pub fn read_mtimecmp(hartid: usize) -> u64 {
+    unsafe { clint_read(MTIMECMP_OFFSET + 8 * hartid) }
+}
+
 // This function should be called from your main Trap Handler
 // when cause == MachineTimerInterrupt (0x8000000000000007)
+// It returns the context to restore in a0
 #[no_mangle]
-pub unsafe fn scheduler_tick() {
+pub unsafe extern "C" fn scheduler_tick(regs: *mut TrapRegs) -> *mut TrapRegs {
+    // A TEECALL over budget goes back to its caller
+    if let Some(state) = STATE.lock().get_mut() {
+        if state.watchdog.expired(read_mtime()) {
+            cove::tsm_timeout(state, &mut *regs);
+            return regs;
+        }
+    }
+
+    // Without OpenSBI the supervisor timer is forwarded here
+    let pending = interrupts::pending();
+    #[cfg(feature = "rust-sbi")]
+    crate::sbi::timer::interrupt();
+    #[cfg(not(feature = "rust-sbi"))]
     debug!("timer");
+    // The event of the supervisor is consumed, wait for the deadline of the TEECALL. During a
+    // TEECALL the supervisor timer is the one of the first caller.
+    if let Some(state) = STATE.lock().get_mut() {
+        interrupts::defer(state, interrupts::pending() & !pending);
+        state.watchdog.rearm();
+    }
+    regs
 }
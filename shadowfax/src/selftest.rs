@@ -0,0 +1,215 @@
+/*
+ * Isolation self-test. The PMP set of each supervisor domain is programmed in turn and the
+ * firmware probes a list of targets with the S-mode permissions (`mstatus.MPRV`): a read (`lw`)
+ * and a write (`amoor.w` of zero, which leaves the memory untouched). Each probe must succeed
+ * exactly when the PMP set of the domain grants the access, so a domain which can reach the
+ * memory of another domain or of the firmware fails the test. The faulting probes are caught by a
+ * dedicated trap vector, which skips the probe.
+ *
+ * The targets are, bit by bit in the report:
+ *   bit 0       firmware code and read-only data (`_fw_start`)
+ *   bit 1       firmware data and heaps (`_fw_rw_start`)
+ *   bit 2       TEE RAM (`_tee_ram_start`)
+ *   bit 3       snapshot area (`_snapshot_start`)
+ *   bit 4...    memory regions (not MMIO) of the domains, in domain order
+ *
+ * The root domain is left out: it is declared with the whole address space and never runs. The
+ * test runs at boot and on demand with the debug SUPD call `SELF_TEST`.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use alloc::vec::Vec;
+use riscv::register::{mepc, mtvec};
+
+use crate::{
+    _fw_rw_start, _fw_start, _snapshot_start, _tee_ram_start,
+    cove::program_pmp_from_regions,
+    domain::{MemoryRegion, PMP_R, PMP_W},
+    state::State,
+};
+
+// mstatus fields set around a probe
+const MSTATUS_MIE: usize = 1 << 3;
+const MSTATUS_MPP: usize = 3 << 11;
+const MSTATUS_MPP_S: usize = 1 << 11;
+const MSTATUS_MPRV: usize = 1 << 17;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SelfTestReport {
+    /// Number of targets probed
+    pub targets: usize,
+    /// Bit i is set if a domain can access target i without a grant, or cannot access it with one
+    pub failed: usize,
+}
+
+/// Probe the targets from every domain but the root one and restore the PMP set of the active
+/// domain.
+pub fn run(state: &State) -> SelfTestReport {
+    let mut targets: Vec<usize> = [
+        &raw const _fw_start as usize,
+        &raw const _fw_rw_start as usize,
+        &raw const _tee_ram_start as usize,
+        &raw const _snapshot_start as usize,
+    ]
+    .into();
+    for domain in state.domains.iter().skip(1) {
+        let regions = domain.memory_regions.iter().filter(|r| !r.mmio);
+        targets.extend(regions.map(|r| r.base_addr));
+    }
+    targets.truncate(usize::BITS as usize);
+
+    let saved = unsafe { Probing::start() };
+    let mut failed = 0;
+    for domain in state.domains.iter().skip(1) {
+        program_pmp_from_regions(&domain.memory_regions);
+        for (i, &addr) in targets.iter().enumerate() {
+            let granted = granted_permissions(&domain.memory_regions, addr);
+            let read = unsafe { probe_read(addr) };
+            let write = unsafe { probe_write(addr) };
+            if read != (granted & PMP_R != 0) || write != (granted & PMP_W != 0) {
+                failed |= 1 << i;
+            }
+        }
+    }
+    unsafe { saved.stop() };
+    program_pmp_from_regions(&state.domains[state.active_domain].memory_regions);
+
+    SelfTestReport {
+        targets: targets.len(),
+        failed,
+    }
+}
+
+/// PMP permissions of `addr`: the ones of the first region matching it, as the PMP does. The
+/// locked regions come first.
+fn granted_permissions(regions: &[MemoryRegion], addr: usize) -> usize {
+    #[cfg(feature = "firmware-wx")]
+    if let Some(permissions) = crate::wx::locked_permissions(addr) {
+        return permissions;
+    }
+    regions
+        .iter()
+        .find(|r| {
+            let size = 1usize.checked_shl(r.order).unwrap_or(0);
+            addr >= r.base_addr && (size == 0 || addr - r.base_addr < size)
+        })
+        .map_or(0, MemoryRegion::pmp_permissions)
+}
+
+/// Machine state replaced while probing
+struct Probing {
+    mstatus: usize,
+    mepc: usize,
+    mtvec: usize,
+}
+
+impl Probing {
+    /// Install the probe trap vector with the interrupts disabled.
+    unsafe fn start() -> Self {
+        let mstatus: usize;
+        core::arch::asm!("csrrc {0}, mstatus, {1}", out(reg) mstatus, in(reg) MSTATUS_MIE);
+        let saved = Self {
+            mstatus,
+            mepc: mepc::read(),
+            mtvec: mtvec::read().bits(),
+        };
+        core::arch::asm!("csrw mtvec, {0}", in(reg) probe_trap as usize);
+        saved
+    }
+
+    unsafe fn stop(self) {
+        core::arch::asm!("csrw mtvec, {0}", in(reg) self.mtvec);
+        mepc::write(self.mepc);
+        core::arch::asm!("csrw mstatus, {0}", in(reg) self.mstatus);
+    }
+}
+
+/// Load from `addr` as S-mode. Returns whether the PMP allowed it.
+unsafe fn probe_read(addr: usize) -> bool {
+    let faulted: usize;
+    core::arch::asm!(
+        // The probes must be 4 bytes long, `probe_trap` skips them
+        ".option push",
+        ".option norvc",
+        "li t0, 0",
+        "csrc mstatus, {mpp}",
+        "csrs mstatus, {mprv_s}",
+        "lw t1, 0({addr})",
+        "csrc mstatus, {mprv}",
+        ".option pop",
+        addr = in(reg) addr,
+        mpp = in(reg) MSTATUS_MPP,
+        mprv_s = in(reg) MSTATUS_MPRV | MSTATUS_MPP_S,
+        mprv = in(reg) MSTATUS_MPRV,
+        out("t0") faulted,
+        out("t1") _,
+    );
+    faulted == 0
+}
+
+/// Atomically OR zero into `addr` as S-mode. Returns whether the PMP allowed it.
+unsafe fn probe_write(addr: usize) -> bool {
+    let faulted: usize;
+    core::arch::asm!(
+        ".option push",
+        ".option norvc",
+        "li t0, 0",
+        "csrc mstatus, {mpp}",
+        "csrs mstatus, {mprv_s}",
+        "amoor.w t1, zero, ({addr})",
+        "csrc mstatus, {mprv}",
+        ".option pop",
+        addr = in(reg) addr,
+        mpp = in(reg) MSTATUS_MPP,
+        mprv_s = in(reg) MSTATUS_MPRV | MSTATUS_MPP_S,
+        mprv = in(reg) MSTATUS_MPRV,
+        out("t0") faulted,
+        out("t1") _,
+    );
+    faulted == 0
+}
+
+/// Trap vector while probing: the only trap is the access fault of a probe, which is skipped with
+/// t0 set. The trap leaves MPP to M, so the firmware accesses after `mret` are not checked.
+#[rustc_align(4)]
+#[unsafe(naked)]
+fn probe_trap() -> ! {
+    core::arch::naked_asm!(
+        "csrr t1, mepc",
+        "addi t1, t1, 4",
+        "csrw mepc, t1",
+        "li t0, 1",
+        "mret",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{MEMREGION_R, MEMREGION_RW};
+
+    /// S-mode read and write of `addr`, with `region` in the first domain entry
+    fn probe(region: MemoryRegion, addr: usize) -> (bool, bool) {
+        program_pmp_from_regions(&[region]);
+        let saved = unsafe { Probing::start() };
+        let access = unsafe { (probe_read(addr), probe_write(addr)) };
+        unsafe { saved.stop() };
+        access
+    }
+
+    #[test_case]
+    fn probes_follow_the_pmp() {
+        let page = &raw const _snapshot_start as usize;
+        let region = |permissions| MemoryRegion {
+            base_addr: page,
+            order: 12,
+            mmio: false,
+            permissions,
+        };
+        assert_eq!(probe(region(MEMREGION_RW), page), (true, true));
+        assert_eq!(probe(region(MEMREGION_R), page + 8), (true, false));
+        // No entry matches the next page
+        assert_eq!(probe(region(MEMREGION_RW), page + 4096), (false, false));
+    }
+}
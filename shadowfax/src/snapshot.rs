@@ -0,0 +1,152 @@
+/*
+ * Snapshot of a domain without TSM (the untrusted OS), to reboot it without tearing down the
+ * confidential domains. SNAPSHOT_DOMAIN saves the context of the caller and up to
+ * `SUPD_SNAPSHOT_MAX_RANGES` ranges of its memory in the snapshot area (`.snapshot` in `link.x`,
+ * `SNAPSHOT_SIZE` bytes of the firmware RAM). RESTORE_DOMAIN copies the ranges back and resumes
+ * the caller after its SNAPSHOT_DOMAIN call with 1 in a1, where SNAPSHOT_DOMAIN itself returns 0.
+ * A single snapshot is kept, a new one replaces it.
+ *
+ * The ranges must be memory of the caller, and not confidential, both when saved and when
+ * restored: the pages converted since the snapshot make the restore fail before anything is
+ * written. The confidential domains, the TVMs and the pages they own are never touched. The
+ * snapshot area is out of reach of every domain and is zeroed when the snapshot is replaced.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use common::sbi::{SnapshotRange, SUPD_SNAPSHOT_MAX_RANGES};
+
+use crate::{
+    _snapshot_end, _snapshot_start, audit::AuditEvent, context::Context, error::CallError,
+    state::State,
+};
+
+pub struct Snapshot {
+    domain: usize,
+    context: Context,
+    ranges: [SnapshotRange; SUPD_SNAPSHOT_MAX_RANGES],
+    num_ranges: usize,
+    // Bytes used in the snapshot area
+    size: usize,
+}
+
+impl Snapshot {
+    fn ranges(&self) -> &[SnapshotRange] {
+        &self.ranges[..self.num_ranges]
+    }
+}
+
+fn area() -> (usize, usize) {
+    let start = &raw const _snapshot_start as usize;
+    (start, &raw const _snapshot_end as usize - start)
+}
+
+/// Check that every range is memory of the active domain, outside the confidential memory.
+fn check_ranges(state: &State, ranges: &[SnapshotRange]) -> Result<(), CallError> {
+    let domain = &state.domains[state.active_domain];
+    for r in ranges {
+        if r.size == 0 || !domain.owns(r.base_addr, r.size) {
+            return Err(CallError::NotCallerMemory {
+                addr: r.base_addr,
+                size: r.size,
+            });
+        }
+        if state.is_confidential(r.base_addr, r.size) {
+            return Err(CallError::ConfidentialRange {
+                addr: r.base_addr,
+                size: r.size,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Save the context of the active domain, `ctx`, and the `count` ranges listed at `list`.
+/// Returns the bytes saved.
+pub fn take(
+    state: &mut State,
+    ctx: &Context,
+    list: usize,
+    count: usize,
+) -> Result<usize, CallError> {
+    let domain = state.active_domain;
+    if domain == 0 || state.domains[domain].has_tsm {
+        return Err(CallError::CannotSnapshot(domain));
+    }
+    if count > SUPD_SNAPSHOT_MAX_RANGES {
+        return Err(CallError::TooManySnapshotRanges(count));
+    }
+    let list_size = count * size_of::<SnapshotRange>();
+    if count > 0 && !state.domains[domain].owns(list, list_size) {
+        return Err(CallError::NotCallerMemory {
+            addr: list,
+            size: list_size,
+        });
+    }
+
+    let mut ranges = [SnapshotRange::default(); SUPD_SNAPSHOT_MAX_RANGES];
+    unsafe {
+        core::ptr::copy_nonoverlapping(list as *const SnapshotRange, ranges.as_mut_ptr(), count)
+    };
+    let ranges = &ranges[..count];
+    check_ranges(state, ranges)?;
+
+    let (area_addr, area_size) = area();
+    let size = ranges
+        .iter()
+        .try_fold(0usize, |total, r| total.checked_add(r.size))
+        .filter(|size| *size <= area_size)
+        .ok_or(CallError::SnapshotTooLarge)?;
+
+    discard(state);
+    let mut offset = area_addr;
+    for r in ranges {
+        unsafe {
+            core::ptr::copy_nonoverlapping(r.base_addr as *const u8, offset as *mut u8, r.size)
+        };
+        offset += r.size;
+    }
+
+    let mut snapshot = Snapshot {
+        domain,
+        context: ctx.clone(),
+        ranges: [SnapshotRange::default(); SUPD_SNAPSHOT_MAX_RANGES],
+        num_ranges: count,
+        size,
+    };
+    snapshot.ranges[..count].copy_from_slice(ranges);
+    state.snapshot = Some(snapshot);
+    Ok(size)
+}
+
+/// Copy the snapshot of the active domain back and resume its context into `ctx`. The snapshot
+/// is kept, to restore it again.
+pub fn restore(state: &mut State, ctx: &mut Context) -> Result<(), CallError> {
+    let domain = state.active_domain;
+    let snapshot = state
+        .snapshot
+        .as_ref()
+        .filter(|s| s.domain == domain)
+        .ok_or(CallError::NoSnapshot(domain))?;
+    check_ranges(state, snapshot.ranges())?;
+
+    let mut offset = area().0;
+    for r in snapshot.ranges() {
+        unsafe {
+            core::ptr::copy_nonoverlapping(offset as *const u8, r.base_addr as *mut u8, r.size)
+        };
+        offset += r.size;
+    }
+    ctx.resume_from(&snapshot.context);
+
+    let size = snapshot.size;
+    state.record(AuditEvent::DomainRestored, size, 0);
+    Ok(())
+}
+
+/// Drop the snapshot and zero the bytes it used.
+fn discard(state: &mut State) {
+    if let Some(snapshot) = state.snapshot.take() {
+        unsafe { core::ptr::write_bytes(area().0 as *mut u8, 0, snapshot.size) };
+    }
+}
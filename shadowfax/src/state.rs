@@ -47,22 +47,49 @@
 * Author: Giuseppe Capasso <capassog97@gmail.com>
 */
 
-use core::cell::OnceCell;
+use core::{cell::OnceCell, sync::atomic::Ordering};
 
 use alloc::vec::Vec;
-use common::attestation::{DiceLayer, PlatformAttestationContext};
+use common::{
+    attestation::{AttestationError, DiceLayer, PlatformAttestationContext},
+    config::MAX_DOMAINS,
+    crypto::{self, Sha512},
+    sbi::{
+        ImsicInfo, SupdDomainInfo, SupdDomainStats, TsmIdentity, COVH_DEFAULT_PAGE_SIZE,
+        SHADOWFAX_TSM_IMPL_ID, SUPD_DOMAIN_ATTESTATION, SUPD_DOMAIN_HAS_TSM,
+        SUPD_DOMAIN_TSM_FAULTED, SUPD_DOMAIN_TSM_READY, TSM_DIGEST_SIZE,
+    },
+};
+use cove_core::activation::{CallStack, DomainRunState};
+use riscv::register::misa;
+use sha2::Digest;
 use spin::mutex::Mutex;
 
 use crate::{
+    audit::{AuditEvent, AuditLog},
     constants::{
-        memory_layout::{ROOT_DOMAIN_REGIONS, UNTRUSTED_DOMAIN_REGIONS},
-        DICE_INPUT_ADDR,
+        memory_layout::{ROOT_DOMAIN_REGIONS, TRUSTED_DOMAIN_REGIONS, UNTRUSTED_DOMAIN_REGIONS},
+        DICE_INPUT_ADDR, DICE_INPUT_SIZE,
     },
-    context::Context,
-    cove::TEE_SCRATCH_SIZE,
-    domain::{create_confidential_domain, Domain},
+    context::RawContexts,
+    context_switch::SWITCH_H_CSRS,
+    counters::MonotonicCounters,
+    devices::DeviceTable,
+    domain::{create_confidential_domain, Domain, RegionTag, SbiPolicy, MAX_MEMORY_REGIONS},
+    error::{CallError, ConfigError},
+    fdt,
+    iopmp::Iopmp,
+    rng::Rng,
+    snapshot::Snapshot,
+    storage::{BlobStorage, CfiFlash},
+    tee::TeeLayout,
+    tlb::TlbTracker,
+    watchdog::Watchdog,
 };
 
+#[cfg(feature = "fdt")]
+use crate::{constants::FDT_VIEW_SIZE, error::FdtError, fdt::FdtView};
+
 #[link_section = ".rodata"]
 static DICE_PLATFORM_PUBLIC_KEY: &[u8; 32] = include_bytes!("../keys/root_of_trust_pub.bin");
 
@@ -70,93 +97,494 @@ pub static STATE: Mutex<OnceCell<State>> = Mutex::new(OnceCell::new());
 
 pub struct State {
     pub domains: Vec<Domain>,
-    pub attestation_context: PlatformAttestationContext,
+    // Platform DICE layer, None if the DICE input is missing or invalid
+    pub attestation_context: Option<PlatformAttestationContext>,
+    // Hypervisor extension: without it the TSM runs no TVM (see `SHADOWFAX_TSM_CAP_DOMAIN_MODE`)
+    pub h_extension: bool,
+    // Supervisor-level IMSIC, if the platform supports AIA
+    pub imsic: Option<ImsicInfo>,
+    // IOPMP guarding confidential memory from DMA, if the platform has one
+    pub iopmp: Option<Iopmp>,
+    // DRBG serving random numbers to the other domains
+    pub rng: Option<Rng>,
+    // Monotonic counters for replay protection
+    pub counters: MonotonicCounters,
+    // Persistent blobs of the TSM, if the platform has a flash
+    pub storage: Option<BlobStorage>,
+    // Supervisor domain running on the boot hart, the caller of the SBI calls
+    pub active_domain: usize,
+    // Outstanding TEECALLs
+    pub calls: CallStack<RawContexts>,
+    // Security events, readable by the domains with the audit-log capability
+    pub audit: AuditLog,
+    // Handler stacks and saved contexts in the TEE RAM
+    pub tee: TeeLayout,
+    // MMIO devices claimed by the domains
+    pub devices: DeviceTable,
+    // Domain owning the console UART, shared by all domains if None
+    pub console_owner: Option<usize>,
+    // The other domains write on the console through the firmware
+    pub console_mux: bool,
+    // Time limit of the TEECALLs
+    pub watchdog: Watchdog,
+    // Harts which may cache G-stage translations of the TVMs
+    pub tlb: TlbTracker,
+    // Snapshot of the untrusted domain, to reboot it
+    pub snapshot: Option<Snapshot>,
     // Ongoing trusted memory: base_address, num_pages, original owner
     memory_allocations: Vec<(usize, usize, usize)>,
 }
 
 impl State {
-    fn new(attestation_context: PlatformAttestationContext) -> Self {
+    fn new(attestation_context: Option<PlatformAttestationContext>, tee: TeeLayout) -> Self {
         Self {
             domains: Vec::new(),
             attestation_context,
+            h_extension: false,
+            imsic: None,
+            iopmp: None,
+            rng: None,
+            counters: MonotonicCounters::default(),
+            storage: None,
+            active_domain: 0,
+            calls: CallStack::new(RawContexts),
+            audit: AuditLog::new(),
+            tee,
+            devices: DeviceTable::new(),
+            console_owner: None,
+            console_mux: false,
+            watchdog: Watchdog::disabled(),
+            tlb: TlbTracker::new(),
+            snapshot: None,
             memory_allocations: Vec::new(),
         }
     }
 
-    pub fn reclaim(&mut self, d: usize, base_addr: usize, num_pages: usize) -> anyhow::Result<()> {
-        let idx = self
-            .memory_allocations
-            .iter
-            .enumerate()
-            .position(|addr, npages, owner| {
-                *addr == base_addr && *npages == num_pages && *owner == d
-            })
-            .ok_or_else(|| anyhow::anyhow!("No matching memory block"))?;
+    /// Whether the SBI policy of the active domain allows the call `eid`/`fid`.
+    pub fn allows_call(&self, eid: usize, fid: usize) -> bool {
+        self.domains
+            .get(self.active_domain)
+            .is_some_and(|domain| domain.sbi_policy.allows(eid, fid))
+    }
 
-        self.memory_allocations.remove(idx);
+    /// Record an event caused by the active domain in the audit log.
+    pub fn record(&mut self, event: AuditEvent, arg0: usize, arg1: usize) {
+        self.audit.record(event, self.active_domain, arg0, arg1);
+    }
+
+    /// Account an access fault of the active domain at `addr` and record it in the audit log.
+    /// Returns the domain owning `addr`, if any.
+    pub fn access_fault(&mut self, cause: usize, addr: usize) -> Option<usize> {
+        if let Some(domain) = self.domains.get_mut(self.active_domain) {
+            domain.access_faults += 1;
+        }
+        self.record(AuditEvent::PmpFault, cause, addr);
+        self.domains.iter().position(|domain| domain.owns(addr, 1))
+    }
+
+    /// Whether `domain` can write on the console through DBCN: the UART is shared, owned by
+    /// `domain` or multiplexed by the firmware.
+    pub fn console_allowed(&self, domain: usize) -> bool {
+        self.console_owner
+            .is_none_or(|owner| owner == domain || self.console_mux)
+    }
+
+    /// Whether `domain` can read the console input: the UART is shared or owned by `domain`.
+    pub fn console_input_allowed(&self, domain: usize) -> bool {
+        self.console_owner.is_none_or(|owner| owner == domain)
+    }
+
+    /// What `domain` hosts, for `SBI_EXT_SUPD_GET_DOMAIN_INFO`. The DICE chain of every TSM roots
+    /// to the platform key.
+    pub fn domain_info(&self, domain: usize) -> Result<SupdDomainInfo, CallError> {
+        let domain = self
+            .domains
+            .get(domain)
+            .ok_or(CallError::UnknownDomain(domain))?;
+
+        let mut info = SupdDomainInfo {
+            flags: 0,
+            tsm_impl_id: 0,
+            tsm: domain.tsm_identity.unwrap_or(TsmIdentity::UNKNOWN),
+            attestation_root: [0; TSM_DIGEST_SIZE],
+        };
+        if !domain.has_tsm {
+            return Ok(info);
+        }
+        info.flags |= SUPD_DOMAIN_HAS_TSM;
+        info.tsm_impl_id = SHADOWFAX_TSM_IMPL_ID;
+        if domain.tsm_ready {
+            info.flags |= SUPD_DOMAIN_TSM_READY;
+        }
+        if domain.faulted {
+            info.flags |= SUPD_DOMAIN_TSM_FAULTED;
+        }
+        if self.attestation_context.is_some() {
+            info.flags |= SUPD_DOMAIN_ATTESTATION;
+            info.attestation_root = Sha512::digest(DICE_PLATFORM_PUBLIC_KEY).into();
+        }
+        Ok(info)
+    }
+
+    /// Offer the trust of the active domain to `domain`: both trust each other once `domain`
+    /// accepts. The root domain never runs, it cannot accept.
+    pub fn offer_trust(&mut self, domain: usize) -> Result<(), CallError> {
+        let src = self.active_domain;
+        match self.domains.get(domain) {
+            None => return Err(CallError::UnknownDomain(domain)),
+            Some(_) if domain == 0 || domain == src => return Err(CallError::CannotTrust(domain)),
+            Some(d) if d.run_state == DomainRunState::Stopped => {
+                return Err(CallError::Stopped(domain))
+            }
+            Some(_) => {}
+        }
+        self.domains[src].trust_offers |= 1 << domain;
+        Ok(())
+    }
+
+    /// Accept the trust offered by `domain` to the active domain: both trust maps are updated.
+    pub fn accept_trust(&mut self, domain: usize) -> Result<(), CallError> {
+        let dst = self.active_domain;
+        let offered = self
+            .domains
+            .get(domain)
+            .is_some_and(|d| d.trust_offers & (1 << dst) != 0);
+        if !offered {
+            return Err(CallError::NoTrustOffer { src: domain, dst });
+        }
+
+        self.domains[domain].trust_offers &= !(1 << dst);
+        self.domains[domain].trust_map |= 1 << dst;
+        self.domains[dst].trust_map |= 1 << domain;
+        self.record(AuditEvent::TrustChanged, domain, 1);
+        Ok(())
+    }
+
+    /// Revoke the trust between the active domain and `domain`, both ways, and withdraw the
+    /// offers between them. The TEECALLs already made still return: a TEERET needs no trust.
+    pub fn revoke_trust(&mut self, domain: usize) -> Result<(), CallError> {
+        let src = self.active_domain;
+        if domain == src || domain >= self.domains.len() {
+            return Err(CallError::CannotRevokeTrust(domain));
+        }
+
+        for (a, b) in [(src, domain), (domain, src)] {
+            self.domains[a].trust_map &= !(1 << b);
+            self.domains[a].trust_offers &= !(1 << b);
+        }
+        self.record(AuditEvent::TrustChanged, domain, 0);
+        Ok(())
+    }
+
+    /// Grant the devices of the table to the domains which claim them. The exclusive devices are
+    /// removed from the MMIO regions of the other domains; the root domain is left alone, it never
+    /// runs.
+    fn assign_devices(&mut self, devices: DeviceTable) -> Result<(), ConfigError> {
+        for (assignment, region) in devices.regions() {
+            if assignment.exclusive {
+                for domain in self.domains.iter_mut().skip(1) {
+                    domain.release_mmio(region);
+                }
+            }
+            self.domains[assignment.domain]
+                .grant(region.clone(), RegionTag::Device)
+                .map_err(|err| ConfigError::DeviceGrant {
+                    domain: assignment.domain,
+                    err,
+                })?;
+        }
+
+        if let Some(console) = devices.console() {
+            self.console_owner = Some(console.domain);
+            self.console_mux = console.console_mux;
+        }
+        self.devices = devices;
         Ok(())
     }
 
-    pub fn track_borrow(
+    fn push_domain(&mut self, mut domain: Domain) {
+        let id = self.domains.len();
+        // Room for every PMP entry: the CoVE calls edit the regions without allocating
+        let regions = domain.memory_regions.len();
+        domain
+            .memory_regions
+            .reserve_exact(MAX_MEMORY_REGIONS.saturating_sub(regions));
+        // The id must fit in the SDID of the CoVE calls and in the per-domain state
+        assert!(id < MAX_DOMAINS, "too many supervisor domains");
+        self.audit
+            .record(AuditEvent::DomainCreated, id, domain.trust_map, 0);
+        self.domains.push(domain);
+    }
+
+    pub fn reclaim(
         &mut self,
         d: usize,
         base_addr: usize,
         num_pages: usize,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), CallError> {
+        let idx = self
+            .memory_allocations
+            .iter()
+            .position(|&(addr, npages, owner)| {
+                addr == base_addr && npages == num_pages && owner == d
+            })
+            .ok_or(CallError::NoMatchingBlock)?;
+
+        self.memory_allocations.remove(idx);
+        Ok(())
+    }
+
+    /// Whether `[base, base + size)` overlaps confidential memory.
+    pub fn is_confidential(&self, base: usize, size: usize) -> bool {
+        let end = base.saturating_add(size);
+        self.memory_allocations.iter().any(|&(addr, num_pages, _)| {
+            base < addr + num_pages * COVH_DEFAULT_PAGE_SIZE && addr < end
+        })
+    }
+
+    pub fn track_borrow(&mut self, d: usize, base_addr: usize, num_pages: usize) {
         self.memory_allocations.push((base_addr, num_pages, d));
     }
 }
 
+/// Parse the DICE input and verify its token against the platform key.
+fn load_attestation_context() -> Result<PlatformAttestationContext, AttestationError> {
+    let context = PlatformAttestationContext::init_from_addr(DICE_INPUT_ADDR, DICE_INPUT_SIZE)?;
+    context.verify_with_pubkey(DICE_PLATFORM_PUBLIC_KEY)?;
+    Ok(context)
+}
+
+/// Whether the harts have the hypervisor extension: `misa` tells, unless the platform does not
+/// implement it (it reads as zero), then the device tree does.
+fn has_hypervisor_extension(fdt_addr: usize) -> bool {
+    let misa = misa::read();
+    if misa.bits() != 0 {
+        misa.has_extension('H')
+    } else {
+        fdt::has_hypervisor_extension(fdt_addr)
+    }
+}
+
+/// Zero the DICE input region, so that no domain finds the platform CDI there after boot (or after
+/// a resume, see `suspend.rs`).
+pub fn wipe_dice_input() {
+    unsafe { core::ptr::write_bytes(DICE_INPUT_ADDR as *mut u8, 0, DICE_INPUT_SIZE) };
+}
+
 /// This function initializes the TSM-driver:
 /// - read DICE input parameters, compute the new security context and create TSM CDI_ID and
-/// certificate
-/// - initialize the TEE stack
-/// - create all domains: for now 3 hardcoded domains:
+/// certificate, then wipe the input. If the DICE input is invalid, attestation is unavailable and the
+/// TSM is not started
+/// - size the TEE RAM from the domains and harts of the device tree and initialize the stack guard
+/// - look for the hypervisor extension: without it the TSM starts in domain mode
+/// - look for the supervisor-level IMSIC in the device tree to enable AIA
+/// - look for the IOPMP in the device tree and deny DMA to the TSM memory
+/// - seed the DRBG (using the platform TRNG if the device tree has one)
+/// - create all domains, recording them in the audit log, attach their `opensbi,domain,instance`
+/// nodes (mirrored in OpenSBI, see `opensbi::domains_init`) and apply the SBI policies and the
+/// device assignments of the device tree. For now 3 hardcoded domains:
 ///     - Trusted domain: where the TSM code leaves
 ///     - Untrusted domain: normal OS/VMM
 ///     - Root domain: mandatory by the Supervisor Domain specification, but should never be used.
 /// TODO: parse domains dynamically from the device tree
+/// Without the `fdt` feature, the device tree is the static configuration (see `static_config.rs`).
 /// Assumption: the domain id matches with its position in the domain array
-pub fn init(_fdt_addr: usize) -> Result<usize, anyhow::Error> {
-    // First, get the security context
-    let attestation_context = PlatformAttestationContext::init_from_addr(DICE_INPUT_ADDR);
-    // Verify the signature
-    attestation_context.verify_with_pubkey(DICE_PLATFORM_PUBLIC_KEY)?;
+pub fn init(fdt_addr: usize) -> Result<usize, ConfigError> {
+    // The hashes of the attestation and of the TSM image run on the crypto extensions, if any
+    crypto::select(fdt::crypto_features(fdt_addr));
+    debug!("crypto backend: {}", crypto::backend().name());
+
+    // First, get the security context. Without it the TSM is not started.
+    let crypto_tag = crate::ALLOCATOR.tag(crate::HEAP_TAG_CRYPTO);
+    let attestation_context = match load_attestation_context() {
+        Ok(context) => Some(context),
+        Err(e) => {
+            debug!("attestation unavailable: {}", e);
+            None
+        }
+    };
+    // The input is consumed: from now on the platform CDI only lives in the firmware memory
+    wipe_dice_input();
+    drop(crypto_tag);
+
+    let _heap_tag = crate::ALLOCATOR.tag(crate::HEAP_TAG_STATE);
+
+    let tee = TeeLayout::from_fdt(fdt_addr)?;
 
     // Lock the state and init the data structure
     let mut state = STATE.lock();
-    let state = state.get_mut_or_init(|| State::new(attestation_context));
+    let state = state.get_mut_or_init(|| State::new(attestation_context, tee));
 
-    let tee_stack = &raw const crate::_tee_stack_top as *const u8 as usize;
+    state.h_extension = has_hypervisor_extension(fdt_addr);
+    SWITCH_H_CSRS.store(state.h_extension as usize, Ordering::Relaxed);
+    state.imsic = fdt::find_imsic(fdt_addr);
+    state.iopmp = fdt::find_iopmp(fdt_addr).and_then(Iopmp::new);
+    if let Some(iopmp) = state.iopmp.as_mut() {
+        let tmem = &TRUSTED_DOMAIN_REGIONS[0];
+        iopmp.protect(tmem.base_addr, tmem.order)?;
+    }
+    state.rng = Some(Rng::new(fdt::find_trng(fdt_addr)));
+    state.storage = fdt::find_flash(fdt_addr)
+        .and_then(|(base_addr, size, bank_width)| CfiFlash::new(base_addr, size, bank_width))
+        .map(BlobStorage::new);
+    state.counters = MonotonicCounters::load(state.storage.as_ref());
+    state.watchdog = Watchdog::from_fdt(fdt_addr);
 
     // Create the root domain. The root domain id is always zero, so it has to be the first
     let root_domain = Domain {
         memory_regions: Vec::from(ROOT_DOMAIN_REGIONS),
         // The root domain should not be involved in Confidential call
         trust_map: 0,
+        trust_offers: 0,
         context_addr: 0,
         has_tsm: false,
+        tsm_ready: false,
+        tsm_identity: None,
+        tsm_info: None,
+        sbi_policy: SbiPolicy::allow_all(),
+        access_faults: 0,
+        stats: SupdDomainStats::default(),
+        grants: heapless::Vec::new(),
+        faulted: false,
+        run_state: DomainRunState::Idle,
+        instance: None,
+        fdt_view: None,
     };
-    state.domains.push(root_domain);
+    state.push_domain(root_domain);
 
     // Create and add the confidential_domain
     // TODO: make this dynamic
-    let context_addr = tee_stack - (TEE_SCRATCH_SIZE + size_of::<Context>()) - size_of::<Context>();
-    let tsm_context = state.attestation_context.compute_next(&[0; 32]);
-    let confidential_domain = create_confidential_domain(context_addr, tsm_context);
+    let confidential_id = state.domains.len();
+    let context_addr = tee.context(confidential_id);
+    let confidential_domain = create_confidential_domain(
+        confidential_id,
+        context_addr,
+        state.attestation_context.as_ref(),
+        state.imsic,
+        state.h_extension,
+        &mut state.audit,
+    );
 
-    state.domains.push(confidential_domain);
+    state.push_domain(confidential_domain);
 
     // Create the untrusted domain
-    let context_addr = context_addr - size_of::<Context>();
+    let context_addr = tee.context(state.domains.len());
     let untrusted_domain = Domain {
         memory_regions: Vec::from(UNTRUSTED_DOMAIN_REGIONS),
         trust_map: 1 << 1,
+        trust_offers: 0,
         context_addr,
         has_tsm: false,
+        tsm_ready: false,
+        tsm_identity: None,
+        tsm_info: None,
+        sbi_policy: SbiPolicy::allow_all(),
+        access_faults: 0,
+        stats: SupdDomainStats::default(),
+        grants: heapless::Vec::new(),
+        faulted: false,
+        run_state: DomainRunState::Idle,
+        instance: None,
+        fdt_view: None,
     };
-    state.domains.push(untrusted_domain);
+    state.push_domain(untrusted_domain);
+    // The untrusted domain runs first
+    state.active_domain = state.domains.len() - 1;
+    state.domains[state.active_domain].run_state = DomainRunState::Running;
+
+    // The n-th domain instance of the device tree describes domain n
+    let instances = fdt::find_domain_instances(fdt_addr)?;
+    if instances.len() >= state.domains.len() {
+        return Err(ConfigError::TooManyInstances {
+            instances: instances.len(),
+            domains: state.domains.len() - 1,
+        });
+    }
+    let domains = state.domains.len();
+    for (id, instance) in (1..).zip(instances) {
+        let domain = &mut state.domains[id];
+        let foreign = instance.regions.iter().find(|r| {
+            let size = 1usize.checked_shl(r.order).unwrap_or(usize::MAX);
+            !r.mmio && !domain.owns(r.base_addr, size)
+        });
+        if let Some(region) = foreign {
+            return Err(ConfigError::ForeignRegion {
+                base_addr: region.base_addr,
+                instance: instance.name.clone(),
+                domain: id,
+            });
+        }
+        if let Some(trust_map) = instance.trust_map {
+            if trust_map >> domains != 0 || trust_map & (1 << id) != 0 {
+                return Err(ConfigError::InvalidTrustMap {
+                    trust_map,
+                    instance: instance.name,
+                });
+            }
+            domain.trust_map = trust_map;
+        }
+        domain.instance = Some(instance);
+    }
+
+    for (id, policy) in fdt::find_sbi_policies(fdt_addr) {
+        match state.domains.get_mut(id) {
+            Some(domain) => domain.sbi_policy = policy,
+            None => return Err(ConfigError::UnknownPolicyDomain(id)),
+        }
+    }
+
+    // Validated as a whole before any device is granted
+    let devices = DeviceTable::from_fdt(fdt_addr, state.domains.len(), confidential_id)?;
+    state.assign_devices(devices)?;
+
+    // The TSM is entered through TEECALLs and boots without a device tree
+    #[cfg(feature = "fdt")]
+    for id in (1..state.domains.len()).filter(|&id| id != confidential_id) {
+        install_fdt_view(state, id, fdt_addr)?;
+    }
 
     Ok(UNTRUSTED_DOMAIN_REGIONS[0].base_addr)
 }
+
+/// Write the device tree view of domain `id` in the last `FDT_VIEW_SIZE` bytes of its first memory
+/// region. The console UART muxed by the firmware stays in the views of all the domains.
+#[cfg(feature = "fdt")]
+fn install_fdt_view(state: &mut State, id: usize, fdt_addr: usize) -> Result<(), FdtError> {
+    let hidden = state.devices.hidden_from(id);
+    let domain = &mut state.domains[id];
+    let memory: Vec<(usize, usize)> = domain
+        .memory_regions
+        .iter()
+        .filter(|r| !r.mmio)
+        .map(|r| {
+            (
+                r.base_addr,
+                1usize.checked_shl(r.order).unwrap_or(usize::MAX),
+            )
+        })
+        .collect();
+    let Some(&(base_addr, size)) = memory.first() else {
+        return Ok(());
+    };
+    let view = fdt::domain_view(
+        fdt_addr,
+        &FdtView {
+            memory: &memory,
+            hidden: &hidden,
+            bootargs: domain.instance.as_ref().and_then(|i| i.bootargs.as_deref()),
+        },
+    )?;
+    if view.len() > FDT_VIEW_SIZE || size < FDT_VIEW_SIZE {
+        return Err(FdtError::ViewTooLarge {
+            domain: id,
+            size: view.len(),
+        });
+    }
+
+    let view_addr = base_addr + size - FDT_VIEW_SIZE;
+    unsafe {
+        core::ptr::copy_nonoverlapping(view.as_ptr(), view_addr as *mut u8, view.len());
+    }
+    domain.fdt_view = Some(view_addr);
+    Ok(())
+}
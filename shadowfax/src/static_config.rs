@@ -0,0 +1,131 @@
+/*
+ * Static configuration, for targets without a device tree. A firmware built without the `fdt`
+ * feature uses this module as its `fdt` module: the platform and the domains come from the tables
+ * build.rs generates from `static-config.toml` (see `write_static_config`), and there is no FDT
+ * parser in the image. The functions have the interface of `fdt.rs` and ignore the device tree
+ * address.
+ *
+ * The domains boot without a device tree view: the untrusted domain gets the `next-arg1` of its
+ * table entry in `a1`.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use alloc::{string::String, vec::Vec};
+use common::{crypto, sbi::ImsicInfo};
+
+use crate::{
+    devices::DeviceAssignment,
+    domain::{DomainInstance, MemoryRegion, SbiPolicy, SbiRule},
+    error::ConfigError,
+};
+
+/// `[[domain]]` of the static configuration, the n-th describes domain n (see
+/// `fdt::find_domain_instances`).
+pub struct StaticDomain {
+    pub name: &'static str,
+    pub possible_harts: &'static [usize],
+    pub regions: &'static [MemoryRegion],
+    pub next_addr: usize,
+    pub next_arg1: usize,
+    pub next_mode: usize,
+    pub bootargs: Option<&'static str>,
+    pub trust_map: Option<usize>,
+    pub watchdog_ms: Option<u32>,
+}
+
+/// `[[sbi-policy]]` of the static configuration (see `fdt::find_sbi_policies`).
+pub struct StaticPolicy {
+    pub domain: usize,
+    pub default_allow: bool,
+    pub rules: &'static [SbiRule],
+    pub audit_log: bool,
+    pub platform_reset: bool,
+}
+
+include!(concat!(env!("OUT_DIR"), "/static_config.rs"));
+
+pub fn find_iopmp(_fdt_addr: usize) -> Option<usize> {
+    IOPMP
+}
+
+pub fn find_trng(_fdt_addr: usize) -> Option<usize> {
+    TRNG
+}
+
+pub fn find_flash(_fdt_addr: usize) -> Option<(usize, usize, u32)> {
+    FLASH
+}
+
+pub fn find_imsic(_fdt_addr: usize) -> Option<ImsicInfo> {
+    IMSIC
+}
+
+pub fn find_sbi_policies(_fdt_addr: usize) -> Vec<(usize, SbiPolicy)> {
+    SBI_POLICIES
+        .iter()
+        .map(|policy| {
+            let sbi_policy = SbiPolicy {
+                default_allow: policy.default_allow,
+                rules: Vec::from(policy.rules),
+                audit_log: policy.audit_log,
+                platform_reset: policy.platform_reset,
+            };
+            (policy.domain, sbi_policy)
+        })
+        .collect()
+}
+
+pub fn find_device_assignments(_fdt_addr: usize) -> Result<Vec<DeviceAssignment>, ConfigError> {
+    Ok(Vec::from(DEVICES))
+}
+
+pub fn count_domains(_fdt_addr: usize) -> usize {
+    DOMAINS.len()
+}
+
+pub fn find_domain_instances(_fdt_addr: usize) -> Result<Vec<DomainInstance>, ConfigError> {
+    Ok(DOMAINS
+        .iter()
+        .map(|domain| DomainInstance {
+            name: String::from(domain.name),
+            possible_harts: Vec::from(domain.possible_harts),
+            regions: Vec::from(domain.regions),
+            next_addr: domain.next_addr,
+            next_arg1: domain.next_arg1,
+            next_mode: domain.next_mode,
+            bootargs: domain.bootargs.map(String::from),
+            trust_map: domain.trust_map,
+        })
+        .collect())
+}
+
+pub fn find_watchdog_ms(_fdt_addr: usize) -> Option<u32> {
+    DOMAINS.iter().find_map(|domain| domain.watchdog_ms)
+}
+
+pub fn find_timebase_frequency(_fdt_addr: usize) -> Option<u32> {
+    TIMEBASE_FREQUENCY
+}
+
+pub fn has_hypervisor_extension(_fdt_addr: usize) -> bool {
+    ISA_EXTENSIONS.contains(&"h")
+}
+
+pub fn crypto_features(_fdt_addr: usize) -> usize {
+    ISA_EXTENSIONS.iter().fold(0, |features, extension| {
+        features | crypto::extension_features(extension)
+    })
+}
+
+pub fn count_harts(_fdt_addr: usize) -> usize {
+    HARTS
+}
+
+pub fn find_test_device(_fdt_addr: usize) -> Option<usize> {
+    TEST_DEVICE
+}
+
+pub fn has_htif(_fdt_addr: usize) -> bool {
+    HTIF
+}
@@ -0,0 +1,57 @@
+/*
+ * Event counters of the domains (`SupdDomainStats`), reported by the SUPD GET_DOMAIN_STATS call.
+ * The firmware counts what crosses the domain boundary: TEECALLs and TEERETs, page conversions and
+ * PMP reprograms. The TSM counts its own events (COVH GET_TSM_STATS).
+ *
+ * Builds with the `stats-print` feature print the counters of every domain each
+ * `PRINT_INTERVAL` TEECALLs.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use common::sbi::SupdDomainStats;
+use cove_core::activation::Transition;
+
+use crate::domain::Domain;
+
+/// TEECALLs between two prints of the counters, with `stats-print`
+const PRINT_INTERVAL: u64 = 1024;
+
+/// Count the TEECALL or TEERET `transition` made by the domain `src`.
+pub fn count_switch(domains: &mut [Domain], src: usize, transition: &Transition) {
+    let stats = &mut domains[src].stats;
+    match transition {
+        Transition::Call(_) => stats.teecalls += 1,
+        Transition::Return(_) => stats.teerets += 1,
+    }
+    if cfg!(feature = "stats-print")
+        && matches!(transition, Transition::Call(_))
+        && stats.teecalls % PRINT_INTERVAL == 0
+    {
+        print(domains);
+    }
+}
+
+/// Counters of `domain`, as reported to the callers.
+pub fn report(domain: &Domain) -> SupdDomainStats {
+    SupdDomainStats {
+        access_faults: domain.access_faults as u64,
+        ..domain.stats
+    }
+}
+
+fn print(domains: &[Domain]) {
+    for (id, domain) in domains.iter().enumerate() {
+        let stats = report(domain);
+        debug!(
+            "domain {} stats: teecall {} teeret {} converted {} reclaimed {} pmp {} faults {}",
+            id,
+            stats.teecalls,
+            stats.teerets,
+            stats.pages_converted,
+            stats.pages_reclaimed,
+            stats.pmp_reprograms,
+            stats.access_faults
+        );
+    }
+}
@@ -0,0 +1,182 @@
+/*
+ * Persistent storage of small opaque blobs for the TSM, which seals them to the identity of a TVM
+ * before they leave it: the firmware never sees plaintext. A blob is keyed by the domain storing
+ * it and a 32-byte id chosen by that domain, and holds up to `SUPD_BLOB_MAX_SIZE` bytes.
+ *
+ * The backend is the CFI parallel flash of the platform (`cfi-flash`, the pflash of the QEMU virt
+ * machine): the first `STORAGE_SIZE` bytes of its last bank, which no supervisor domain can reach.
+ * The area is an array of `STORAGE_ENTRY_SIZE` entries:
+ *
+ *   magic (u32) | size (u32) | domain (u32) | reserved (u32) | id (32 bytes) | reserved | data
+ *
+ * The whole area is cached in firmware memory. A store updates the cache, erases the flash sector
+ * and programs the area back, so a reset in the middle of a store loses the blobs.
+ *
+ * Command set: Intel/Sharp extended (CFI 0x0001), as emulated by QEMU.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use alloc::{vec, vec::Vec};
+use common::{
+    attestation::ct_eq,
+    sbi::{SUPD_BLOB_ID_SIZE, SUPD_BLOB_MAX_SIZE},
+};
+
+use crate::error::StorageError;
+
+const STORAGE_ENTRY_SIZE: usize = 4096;
+const STORAGE_ENTRIES: usize = 16;
+const STORAGE_SIZE: usize = STORAGE_ENTRY_SIZE * STORAGE_ENTRIES;
+const STORAGE_HEADER_SIZE: usize = STORAGE_ENTRY_SIZE - SUPD_BLOB_MAX_SIZE;
+const STORAGE_MAGIC: u32 = u32::from_le_bytes(*b"SFXB");
+
+/// Erase sector of the QEMU virt flash
+const FLASH_SECTOR_SIZE: usize = 256 * 1024;
+
+// CFI commands
+const CFI_READ_ARRAY: u32 = 0xff;
+const CFI_READ_STATUS: u32 = 0x70;
+const CFI_CLEAR_STATUS: u32 = 0x50;
+const CFI_BLOCK_ERASE: u32 = 0x20;
+const CFI_ERASE_CONFIRM: u32 = 0xd0;
+const CFI_PROGRAM: u32 = 0x40;
+
+// Status register
+const CFI_STATUS_READY: u32 = 1 << 7;
+const CFI_STATUS_ERRORS: u32 = 0x3a;
+
+const _: () = assert!(STORAGE_SIZE <= FLASH_SECTOR_SIZE);
+const _: () = assert!(STORAGE_HEADER_SIZE >= 16 + SUPD_BLOB_ID_SIZE);
+
+/// CFI flash bank, 32 bits wide.
+pub struct CfiFlash {
+    base_addr: usize,
+}
+
+impl CfiFlash {
+    /// Flash bank at `base_addr` of `size` bytes, `bank_width` bytes wide. Only 4-byte banks large
+    /// enough for the storage area are supported.
+    pub fn new(base_addr: usize, size: usize, bank_width: u32) -> Option<Self> {
+        if bank_width != 4 || size < FLASH_SECTOR_SIZE {
+            return None;
+        }
+        Some(Self { base_addr })
+    }
+
+    fn command(&self, offset: usize, command: u32) {
+        unsafe { core::ptr::write_volatile((self.base_addr + offset) as *mut u32, command) };
+    }
+
+    fn read(&self, out: &mut [u8]) {
+        self.command(0, CFI_READ_ARRAY);
+        for (i, word) in out.chunks_exact_mut(4).enumerate() {
+            let value = unsafe { core::ptr::read_volatile((self.base_addr + 4 * i) as *const u32) };
+            word.copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    /// Wait for the end of an erase or a program and go back to read array mode.
+    fn wait(&self, offset: usize) -> Result<(), StorageError> {
+        self.command(offset, CFI_READ_STATUS);
+        let status = loop {
+            let status =
+                unsafe { core::ptr::read_volatile((self.base_addr + offset) as *const u32) };
+            if status & CFI_STATUS_READY != 0 {
+                break status;
+            }
+        };
+        self.command(offset, CFI_CLEAR_STATUS);
+        self.command(offset, CFI_READ_ARRAY);
+        if status & CFI_STATUS_ERRORS != 0 {
+            return Err(StorageError::Flash { status, offset });
+        }
+        Ok(())
+    }
+
+    /// Erase the first sector and program `data` at its start.
+    fn rewrite(&self, data: &[u8]) -> Result<(), StorageError> {
+        self.command(0, CFI_BLOCK_ERASE);
+        self.command(0, CFI_ERASE_CONFIRM);
+        self.wait(0)?;
+
+        for (i, word) in data.chunks_exact(4).enumerate() {
+            let value = u32::from_le_bytes(word.try_into().unwrap());
+            // Erased words read as all ones
+            if value == u32::MAX {
+                continue;
+            }
+            self.command(4 * i, CFI_PROGRAM);
+            self.command(4 * i, value);
+            self.wait(4 * i)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct BlobStorage {
+    flash: CfiFlash,
+    /// Copy of the storage area
+    cache: Vec<u8>,
+}
+
+impl BlobStorage {
+    pub fn new(flash: CfiFlash) -> Self {
+        let mut cache = vec![0; STORAGE_SIZE];
+        flash.read(&mut cache);
+        Self { flash, cache }
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &[u8]> {
+        self.cache.chunks_exact(STORAGE_ENTRY_SIZE)
+    }
+
+    /// Entry of the blob `id` of `domain`
+    fn find(&self, domain: usize, id: &[u8]) -> Option<usize> {
+        self.entries().position(|entry| {
+            word(entry, 0) == STORAGE_MAGIC
+                && word(entry, 2) as usize == domain
+                && ct_eq(&entry[16..16 + SUPD_BLOB_ID_SIZE], id)
+        })
+    }
+
+    /// Blob `id` of `domain`, if it was stored.
+    pub fn load(&self, domain: usize, id: &[u8]) -> Option<&[u8]> {
+        let entry = self.entries().nth(self.find(domain, id)?)?;
+        let size = (word(entry, 1) as usize).min(SUPD_BLOB_MAX_SIZE);
+        Some(&entry[STORAGE_HEADER_SIZE..STORAGE_HEADER_SIZE + size])
+    }
+
+    /// Store `data` as the blob `id` of `domain`, replacing the previous one. Empty `data` removes
+    /// the blob.
+    pub fn store(&mut self, domain: usize, id: &[u8], data: &[u8]) -> Result<(), StorageError> {
+        if data.len() > SUPD_BLOB_MAX_SIZE || id.len() != SUPD_BLOB_ID_SIZE {
+            return Err(StorageError::InvalidBlob(data.len()));
+        }
+
+        let index = match self.find(domain, id) {
+            Some(index) => index,
+            None if data.is_empty() => return Ok(()),
+            None => self
+                .entries()
+                .position(|entry| word(entry, 0) != STORAGE_MAGIC)
+                .ok_or(StorageError::Full)?,
+        };
+
+        let entry = &mut self.cache[index * STORAGE_ENTRY_SIZE..][..STORAGE_ENTRY_SIZE];
+        entry.fill(u8::MAX);
+        if !data.is_empty() {
+            entry[0..4].copy_from_slice(&STORAGE_MAGIC.to_le_bytes());
+            entry[4..8].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            entry[8..12].copy_from_slice(&(domain as u32).to_le_bytes());
+            entry[16..16 + SUPD_BLOB_ID_SIZE].copy_from_slice(id);
+            entry[STORAGE_HEADER_SIZE..][..data.len()].copy_from_slice(data);
+        }
+        self.flash.rewrite(&self.cache)
+    }
+}
+
+/// `index`-th little-endian word of `entry`
+fn word(entry: &[u8], index: usize) -> u32 {
+    u32::from_le_bytes(entry[4 * index..4 * index + 4].try_into().unwrap())
+}
@@ -0,0 +1,189 @@
+/*
+ * Hart suspend (HSM HART_SUSPEND) and system suspend (SUSP) of the supervisor domains. `trap.rs`
+ * sends these calls here before the runtime sees them:
+ *  - a retentive suspend keeps the state of the hart and goes on to the runtime;
+ *  - a non-retentive suspend loses the machine CSRs, the PMP included: resuming with their reset
+ *    values would open the memory of every domain. The firmware saves the machine CSRs, suspends
+ *    the hart and, on resume, reprograms the PMP of the active domain and the IOPMP from the
+ *    state, wipes the DICE input again (the boot ROM may write it back on wake up) and restores
+ *    the CSRs. The supervisor enters its resume address as the SBI specification mandates:
+ *    a0 = hartid, a1 = opaque, satp = 0 and sstatus.SIE = 0.
+ *
+ * The TEE contexts, the TSM and the TVMs live in the retained RAM and need nothing more. A suspend
+ * is denied while a TEECALL is outstanding, the domains of the call chain would resume into a
+ * stale call. The supported platforms have no hart power controller: the hart waits for an
+ * interrupt with `wfi`, then takes the resume path as if its state had been lost.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use common::sbi::SbiRet;
+use riscv::register::mhartid;
+
+use crate::{
+    audit::AuditEvent,
+    cove::program_pmp_from_regions,
+    dispatch::{SBI_ERR_DENIED, SBI_ERR_INVALID_PARAM, SBI_ERR_NOT_SUPPORTED},
+    error::IopmpError,
+    runtime::TrapRegs,
+    state::{self, State},
+};
+
+pub const EXT_HSM: usize = 0x48534D;
+const HSM_HART_SUSPEND: usize = 3;
+pub const EXT_SUSP: usize = 0x53555350;
+const SUSP_SYSTEM_SUSPEND: usize = 0;
+
+// HSM suspend types: bit 31 set for the non-retentive ones
+const SUSPEND_TYPE_NON_RETENTIVE: usize = 0x8000_0000;
+const SUSPEND_TYPE_DEFAULT_NON_RETENTIVE: usize = 0x8000_0000;
+// SUSP sleep types
+const SLEEP_TYPE_SUSPEND_TO_RAM: usize = 0;
+
+const SSTATUS_SIE: usize = 1 << 1;
+
+pub enum Suspend {
+    /// Retentive suspend or another HSM call: the runtime handles it
+    Runtime,
+    /// The hart resumed, the trap frame enters the resume address of the caller
+    Resumed,
+    /// Back to the caller with an error
+    Denied(SbiRet),
+}
+
+/// Handle the HSM or SUSP call `eid`/`fid` of the active domain, whose trap frame is `regs`.
+pub fn handle(
+    state: &mut State,
+    regs: &mut TrapRegs,
+    eid: usize,
+    fid: usize,
+    args: &[usize; 6],
+) -> Suspend {
+    let (suspend_type, resume_addr, opaque) = match (eid, fid) {
+        (EXT_HSM, HSM_HART_SUSPEND) => {
+            if args[0] & SUSPEND_TYPE_NON_RETENTIVE == 0 {
+                return Suspend::Runtime;
+            }
+            if args[0] != SUSPEND_TYPE_DEFAULT_NON_RETENTIVE {
+                return denied(SBI_ERR_NOT_SUPPORTED);
+            }
+            (args[0], args[1], args[2])
+        }
+        (EXT_SUSP, SUSP_SYSTEM_SUSPEND) => {
+            if args[0] != SLEEP_TYPE_SUSPEND_TO_RAM {
+                return denied(SBI_ERR_INVALID_PARAM);
+            }
+            (args[0], args[1], args[2])
+        }
+        (EXT_SUSP, _) => return denied(SBI_ERR_NOT_SUPPORTED),
+        _ => return Suspend::Runtime,
+    };
+
+    if state.calls.top().is_some() {
+        state.record(AuditEvent::PolicyDenied, eid, fid);
+        return denied(SBI_ERR_DENIED);
+    }
+    if !state.domains[state.active_domain].owns(resume_addr, 4) {
+        return denied(SBI_ERR_INVALID_PARAM);
+    }
+
+    debug!(
+        "domain {} suspends (type {:#x})",
+        state.active_domain, suspend_type
+    );
+    state.record(AuditEvent::Suspended, eid, suspend_type);
+    let saved = MachineCsrs::save();
+    riscv::asm::wfi();
+    if let Err(e) = resume(state, &saved) {
+        panic!("cannot restore the isolation after a suspend: {e}");
+    }
+
+    regs.a0 = mhartid::read() as _;
+    regs.a1 = opaque as _;
+    // The call came from S-mode (MPP), which the resume address runs in
+    regs.mepc = resume_addr as _;
+    Suspend::Resumed
+}
+
+/// Give the hart back the state it lost in a non-retentive suspend.
+fn resume(state: &mut State, saved: &MachineCsrs) -> Result<(), IopmpError> {
+    unsafe { saved.restore() };
+    #[cfg(feature = "firmware-wx")]
+    crate::wx::reprogram();
+    program_pmp_from_regions(&state.domains[state.active_domain].memory_regions);
+    if let Some(iopmp) = state.iopmp.as_ref() {
+        iopmp.reprogram()?;
+    }
+    state::wipe_dice_input();
+
+    // The supervisor starts over: no translation and no interrupts
+    unsafe {
+        core::arch::asm!(
+            "csrw satp, zero",
+            "csrc sstatus, {sie}",
+            sie = in(reg) SSTATUS_SIE,
+        )
+    };
+    Ok(())
+}
+
+/// Machine CSRs set up at boot by the firmware and its runtime.
+struct MachineCsrs {
+    mtvec: usize,
+    mscratch: usize,
+    medeleg: usize,
+    mideleg: usize,
+    mie: usize,
+    mcounteren: usize,
+}
+
+impl MachineCsrs {
+    fn save() -> Self {
+        let mut csrs = Self {
+            mtvec: 0,
+            mscratch: 0,
+            medeleg: 0,
+            mideleg: 0,
+            mie: 0,
+            mcounteren: 0,
+        };
+        unsafe {
+            core::arch::asm!(
+                "csrr {0}, mtvec",
+                "csrr {1}, mscratch",
+                "csrr {2}, medeleg",
+                "csrr {3}, mideleg",
+                "csrr {4}, mie",
+                "csrr {5}, mcounteren",
+                out(reg) csrs.mtvec,
+                out(reg) csrs.mscratch,
+                out(reg) csrs.medeleg,
+                out(reg) csrs.mideleg,
+                out(reg) csrs.mie,
+                out(reg) csrs.mcounteren,
+            )
+        };
+        csrs
+    }
+
+    unsafe fn restore(&self) {
+        core::arch::asm!(
+            "csrw mtvec, {0}",
+            "csrw mscratch, {1}",
+            "csrw medeleg, {2}",
+            "csrw mideleg, {3}",
+            "csrw mie, {4}",
+            "csrw mcounteren, {5}",
+            in(reg) self.mtvec,
+            in(reg) self.mscratch,
+            in(reg) self.medeleg,
+            in(reg) self.mideleg,
+            in(reg) self.mie,
+            in(reg) self.mcounteren,
+        );
+    }
+}
+
+fn denied(code: isize) -> Suspend {
+    Suspend::Denied(SbiRet { a0: code, a1: 0 })
+}
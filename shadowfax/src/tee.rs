@@ -0,0 +1,144 @@
+/*
+ * Layout of the TEE RAM (`.tee_ram` in `link.x`), where the CoVE handlers run and where the
+ * contexts of the supervisor domains are saved. Each hart gets a slice, from the top of the TEE
+ * RAM down:
+ *
+ *   stack top  +-------------------------+
+ *              | handler stack           |  TEE_SCRATCH_SIZE, the lowest bytes are a guard
+ *              +-------------------------+
+ *              | scratch context         |  saved by the CoVE entries (slot of domain 0)
+ *              | context of domain 1     |
+ *              | ...                     |
+ *              | context of domain n - 1 |
+ *              +-------------------------+  slice of the next hart
+ *
+ * The number of domains and harts is read from the device tree at init, and the slices must fit
+ * in the `_tee_stack_size` bytes reserved by the linker script. The root domain never calls the
+ * TSM, so its slot holds the scratch context. Only the boot hart handles CoVE calls for now.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use common::config::MAX_DOMAINS;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{_tee_ram_start, _tee_stack_top, context::Context, error::ConfigError, fdt, platform};
+
+// 8K handler stack
+pub const TEE_SCRATCH_SIZE: usize = 0x2000;
+
+// The handlers run right above the saved contexts: the lowest bytes of their stack are a guard
+// filled with a canary, checked when a handler starts and returns.
+const TEE_STACK_GUARD_SIZE: usize = 256;
+const TEE_STACK_CANARY: u8 = 0xa5;
+
+/// Domains created by `state::init` whatever the device tree declares: root, TSM and untrusted
+const TEE_MIN_DOMAINS: usize = 3;
+
+// The TEE RAM of `platform.conf` must hold the slice of a hart with these domains
+const _: () =
+    assert!(TEE_SCRATCH_SIZE + TEE_MIN_DOMAINS * size_of::<Context>() <= platform::TEE_RAM_SIZE);
+
+/// Address of the scratch context of the boot hart, read by the CoVE entries before they have a
+/// stack.
+pub static TEE_SCRATCH_CONTEXT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Debug)]
+pub struct TeeLayout {
+    /// Top of the TEE RAM and of the handler stack of the boot hart
+    pub stack_top: usize,
+    pub num_domains: usize,
+    pub num_harts: usize,
+}
+
+impl TeeLayout {
+    /// Size the TEE RAM for the domains (`opensbi,domain,instance` and the root domain) and the
+    /// harts of the device tree, and publish the scratch context to the CoVE entries.
+    pub fn from_fdt(fdt_addr: usize) -> Result<Self, ConfigError> {
+        let layout = Self {
+            stack_top: tee_stack_top(),
+            num_domains: (fdt::count_domains(fdt_addr) + 1).max(TEE_MIN_DOMAINS),
+            num_harts: fdt::count_harts(fdt_addr).max(1),
+        };
+
+        if layout.num_domains > MAX_DOMAINS {
+            return Err(ConfigError::TooManyDomains {
+                declared: layout.num_domains,
+                max: MAX_DOMAINS,
+            });
+        }
+
+        let reserved = layout.stack_top - &raw const _tee_ram_start as *const u8 as usize;
+        if layout.size() > reserved {
+            return Err(ConfigError::TeeRamTooSmall {
+                domains: layout.num_domains,
+                harts: layout.num_harts,
+                needed: layout.size(),
+                reserved,
+            });
+        }
+
+        TEE_SCRATCH_CONTEXT.store(layout.scratch_context(), Ordering::Relaxed);
+        init_stack_guard();
+        Ok(layout)
+    }
+
+    /// Size of the slice of a hart
+    pub fn hart_size(&self) -> usize {
+        TEE_SCRATCH_SIZE + self.num_domains * size_of::<Context>()
+    }
+
+    pub fn size(&self) -> usize {
+        self.num_harts * self.hart_size()
+    }
+
+    pub fn scratch_context(&self) -> usize {
+        self.stack_top - TEE_SCRATCH_SIZE - size_of::<Context>()
+    }
+
+    /// Address of the saved context of `domain`.
+    pub fn context(&self, domain: usize) -> usize {
+        assert!(domain < self.num_domains, "no context for domain {domain}");
+        self.scratch_context() - domain * size_of::<Context>()
+    }
+}
+
+fn tee_stack_top() -> usize {
+    &raw const _tee_stack_top as *const u8 as usize
+}
+
+fn stack_guard() -> &'static mut [u8] {
+    let guard = (tee_stack_top() - TEE_SCRATCH_SIZE) as *mut u8;
+    unsafe { core::slice::from_raw_parts_mut(guard, TEE_STACK_GUARD_SIZE) }
+}
+
+fn init_stack_guard() {
+    stack_guard().fill(TEE_STACK_CANARY);
+}
+
+/// Checks the TEE stack guard when created and when dropped. A handler which overflowed the stack
+/// has overwritten the saved contexts too: panic instead of restoring a corrupted domain.
+pub struct TeeStackCheck;
+
+impl TeeStackCheck {
+    pub fn enter() -> Self {
+        Self::check();
+        Self
+    }
+
+    fn check() {
+        let guard = stack_guard();
+        if let Some(offset) = guard.iter().rposition(|b| *b != TEE_STACK_CANARY) {
+            panic!(
+                "TEE stack overflow: guard overwritten up to {:#x}, the domain contexts are corrupted",
+                guard.as_ptr() as usize + offset
+            );
+        }
+    }
+}
+
+impl Drop for TeeStackCheck {
+    fn drop(&mut self) {
+        Self::check();
+    }
+}
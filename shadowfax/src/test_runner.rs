@@ -0,0 +1,127 @@
+/*
+ * In-firmware unit tests, for the code which only works on the target: the PMP programming and
+ * what it enforces, the parsing of the device tree of the machine. `cargo test -p shadowfax` (see
+ * `make firmware-tests`) builds a firmware image whose `main` runs the `#[test_case]` functions of
+ * the firmware in M-mode once the heaps are set up, instead of booting the domains. A test case is
+ * a plain `fn()` which panics on failure. The firmware cannot unwind: the panic handler reports
+ * the failing case and ends the run, the cases after it are not run.
+ *
+ * Every line is `[SHADOWFAX-FWTEST] <record> key=value...`, so the functional tests can parse it
+ * (see `test/support`):
+ *
+ *   [SHADOWFAX-FWTEST] begin total=4
+ *   [SHADOWFAX-FWTEST] case name=shadowfax::cove::tests::pmp_reads_back result=pass
+ *   [SHADOWFAX-FWTEST] case name=shadowfax::fdt::tests::domain_instances result=fail location=src/fdt.rs:570
+ *   [SHADOWFAX-FWTEST] summary total=4 passed=1 failed=1
+ *
+ * The machine is then powered off through the SiFive test device, with exit code 0 only if every
+ * case passed. On machines without one (spike) the firmware waits after the summary.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use riscv::asm::wfi;
+use spin::Mutex;
+
+use crate::{fdt, print_raw};
+
+/// Prefix of the test lines
+pub const FW_TEST_MARKER: &str = "[SHADOWFAX-FWTEST]";
+
+// SiFive test device commands, the exit code of a failure goes in the upper 16 bits
+const TEST_FAIL: u32 = 0x3333;
+const TEST_PASS: u32 = 0x5555;
+
+/// Print a test record
+macro_rules! test_line {
+    ($($arg:tt)*) => {
+        print_raw!("{} {}\r\n", FW_TEST_MARKER, format_args!($($arg)*))
+    };
+}
+
+/// Device tree of the machine, for the cases which read it
+static FDT_ADDR: AtomicUsize = AtomicUsize::new(0);
+/// Cases of the run, and those which passed
+static TOTAL: AtomicUsize = AtomicUsize::new(0);
+static PASSED: AtomicUsize = AtomicUsize::new(0);
+/// Name of the case running, reported if it panics
+static CURRENT: Mutex<&str> = Mutex::new("none");
+
+/// A `#[test_case]` function.
+pub trait TestCase {
+    fn name(&self) -> &'static str;
+    fn run(&self);
+}
+
+impl<T: Fn()> TestCase for T {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
+    fn run(&self) {
+        self()
+    }
+}
+
+/// Run the test cases of the firmware with the device tree at `fdt_addr`, then power off.
+pub fn start(fdt_addr: usize) {
+    FDT_ADDR.store(fdt_addr, Ordering::Relaxed);
+    crate::test_main();
+    finish(0)
+}
+
+/// Test runner of the `cargo test` builds (`#![test_runner]`): run `cases` in order.
+pub fn run(cases: &[&dyn TestCase]) {
+    TOTAL.store(cases.len(), Ordering::Relaxed);
+    test_line!("begin total={}", cases.len());
+    for case in cases {
+        *CURRENT.lock() = case.name();
+        case.run();
+        test_line!("case name={} result=pass", case.name());
+        PASSED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Device tree the firmware was booted with.
+pub fn fdt_addr() -> usize {
+    FDT_ADDR.load(Ordering::Relaxed)
+}
+
+/// Report the case which panicked with `info` and end the run. Called by the panic handler.
+pub fn panicked(info: &PanicInfo) {
+    let name = CURRENT.try_lock().map_or("unknown", |name| *name);
+    match info.location() {
+        Some(location) => test_line!(
+            "case name={} result=fail location={}:{}",
+            name,
+            location.file(),
+            location.line()
+        ),
+        None => test_line!("case name={} result=fail location=unknown", name),
+    }
+    finish(1)
+}
+
+fn finish(failed: usize) -> ! {
+    test_line!(
+        "summary total={} passed={} failed={}",
+        TOTAL.load(Ordering::Relaxed),
+        PASSED.load(Ordering::Relaxed),
+        failed
+    );
+    if let Some(base) = fdt::find_test_device(fdt_addr()) {
+        let cmd = match failed {
+            0 => TEST_PASS,
+            _ => TEST_FAIL | 1 << 16,
+        };
+        unsafe { (base as *mut u32).write_volatile(cmd) };
+    }
+    loop {
+        wfi();
+    }
+}
@@ -0,0 +1,118 @@
+/*
+ * G-stage TLB shootdown. The TSM runs every TVM with VMID 0 and only fences the hart entering a
+ * vCPU, so a hart which ran a TVM may still cache its translations while another hart changes its
+ * mappings. The firmware records the harts entering each TVM (RUN_TVM_VCPU); when a COVH call
+ * changing the mappings of a TVM returns successfully, the harts recorded for it, the calling one
+ * excluded, fence their G-stage TLB on an IPI of the SBI runtime (`runtime::remote_hfence_gvma`)
+ * and are forgotten until they enter the TVM again. A hart calling TSM_LOCAL_FENCE is forgotten
+ * by every TVM.
+ *
+ * Harts are tracked as masks of hart ids, which must be below `usize::BITS`.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use alloc::vec::Vec;
+use common::sbi::{
+    CovhQueueEntry, SbiRet, SBI_COVH_ADD_TVM_MEASURED_PAGES, SBI_COVH_ADD_TVM_MEASURED_PAGES_BATCH,
+    SBI_COVH_ADD_TVM_SHARED_PAGES, SBI_COVH_ADD_ZERO_PAGES, SBI_COVH_DESTROY_TVM,
+    SBI_COVH_PROCESS_QUEUE, SBI_COVH_RUN_TVM_VCPU, SBI_COVH_TSM_LOCAL_FENCE,
+};
+
+use crate::runtime;
+
+/// Whether the COVH function `fid` changes the G-stage mappings of the TVM `a0`.
+fn changes_mappings(fid: usize) -> bool {
+    matches!(
+        fid,
+        SBI_COVH_ADD_TVM_MEASURED_PAGES
+            | SBI_COVH_ADD_TVM_MEASURED_PAGES_BATCH
+            | SBI_COVH_ADD_ZERO_PAGES
+            | SBI_COVH_ADD_TVM_SHARED_PAGES
+            | SBI_COVH_DESTROY_TVM
+    )
+}
+
+fn hart_bit(hartid: usize) -> usize {
+    1usize.checked_shl(hartid as u32).unwrap_or(0)
+}
+
+/// Harts which may cache G-stage translations of each TVM.
+pub struct TlbTracker {
+    /// TVM id and mask of its harts
+    tvms: Vec<(usize, usize)>,
+}
+
+impl TlbTracker {
+    pub const fn new() -> Self {
+        Self { tvms: Vec::new() }
+    }
+
+    /// Harts which may cache translations of `tvm_id`.
+    fn harts(&self, tvm_id: usize) -> usize {
+        self.tvms
+            .iter()
+            .find(|(id, _)| *id == tvm_id)
+            .map_or(0, |&(_, harts)| harts)
+    }
+
+    /// `hartid` enters a vCPU of `tvm_id`.
+    fn enter(&mut self, tvm_id: usize, hartid: usize) {
+        match self.tvms.iter_mut().find(|(id, _)| *id == tvm_id) {
+            Some((_, harts)) => *harts |= hart_bit(hartid),
+            None => self.tvms.push((tvm_id, hart_bit(hartid))),
+        }
+    }
+
+    /// `hartid` fenced its G-stage TLB.
+    fn fenced(&mut self, hartid: usize) {
+        for (_, harts) in self.tvms.iter_mut() {
+            *harts &= !hart_bit(hartid);
+        }
+        self.tvms.retain(|&(_, harts)| harts != 0);
+    }
+
+    /// The mappings of `tvm_id` changed on `hartid`: returns the other harts to fence, which are
+    /// forgotten. `hartid` is fenced by the TSM before it enters the TVM again.
+    fn shootdown(&mut self, tvm_id: usize, hartid: usize) -> usize {
+        let targets = self.harts(tvm_id) & !hart_bit(hartid);
+        self.tvms.retain(|&(id, _)| id != tvm_id);
+        targets
+    }
+
+    /// The COVH call `fid` with arguments `args` returned `ret` to the caller on `hartid`.
+    pub fn call_returned(&mut self, fid: usize, args: &[usize; 6], ret: &SbiRet, hartid: usize) {
+        let mut targets = 0;
+        match fid {
+            SBI_COVH_TSM_LOCAL_FENCE if ret.a0 == 0 => self.fenced(hartid),
+            // The queue is in the memory of the caller, checked on TEECALL (see
+            // `cove::queue_allowed`). The entries before the failing one (a1) were executed.
+            SBI_COVH_PROCESS_QUEUE => {
+                let executed = match ret.a0 {
+                    0 => args[1],
+                    _ => (ret.a1 as usize).min(args[1]),
+                };
+                let entries = unsafe {
+                    core::slice::from_raw_parts(args[0] as *const CovhQueueEntry, executed)
+                };
+                for entry in entries.iter().filter(|e| changes_mappings(e.fid)) {
+                    targets |= self.shootdown(entry.args[0], hartid);
+                }
+            }
+            fid if ret.a0 == 0 && changes_mappings(fid) => {
+                targets = self.shootdown(args[0], hartid);
+            }
+            _ => {}
+        }
+        if targets != 0 {
+            runtime::remote_hfence_gvma(targets);
+        }
+    }
+
+    /// The COVH call `fid` with arguments `args` enters the TSM on `hartid`.
+    pub fn call_entered(&mut self, fid: usize, args: &[usize; 6], hartid: usize) {
+        if fid == SBI_COVH_RUN_TVM_VCPU {
+            self.enter(args[0], hartid);
+        }
+    }
+}
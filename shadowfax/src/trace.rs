@@ -0,0 +1,128 @@
+/*
+ * Trace of the domain switches (`trace` feature), for the post-mortem analysis of the activity of
+ * interleaved domains. Every TEECALL, TEERET and SUPD call handled by the firmware is recorded in
+ * the ring of the hart handling it, as a `SupdTraceEntry`: mtime, event, caller and callee, eid,
+ * fid and the SBI error of the call. A TEECALL aborted by the watchdog is recorded as a TEERET of
+ * its TSM with SBI_ERR_TIMEOUT. The oldest entries are overwritten.
+ *
+ * A ring is only written by its hart and never locked: a slot holds the sequence number of its
+ * entry, `WRITING` while it changes, so a reader on another hart (the debug SUPD call READ_TRACE,
+ * the crash dump) drops the entries overwritten under it. The crash dump prints the rings, one
+ * `trace` record per entry, which `test/support` decodes into one timeline.
+ *
+ * Without the feature there are no rings: nothing is recorded and READ_TRACE is not supported.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{fence, AtomicUsize, Ordering},
+};
+
+use common::sbi::{SupdTraceEntry, SUPD_TRACE_SUPD_CALL, SUPD_TRACE_TEECALL, SUPD_TRACE_TEERET};
+use riscv::register::mhartid;
+
+use crate::scheduler::read_mtime;
+
+/// Harts with a ring, the others are not traced
+const TRACE_HARTS: usize = if cfg!(feature = "trace") { 8 } else { 0 };
+/// Entries of each ring
+const TRACE_ENTRIES: usize = 64;
+
+/// Sequence number of a slot being written
+const WRITING: usize = usize::MAX;
+
+struct Slot {
+    /// Sequence number of the entry, `WRITING` while it is written
+    seq: AtomicUsize,
+    entry: UnsafeCell<MaybeUninit<SupdTraceEntry>>,
+}
+
+struct Ring {
+    /// Sequence number of the next entry
+    next: AtomicUsize,
+    slots: [Slot; TRACE_ENTRIES],
+}
+
+// Each ring is written by its hart only, the readers check the sequence number of the slot
+unsafe impl Sync for Ring {}
+
+static RINGS: [Ring; TRACE_HARTS] = [const { Ring::new() }; TRACE_HARTS];
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            next: AtomicUsize::new(0),
+            slots: [const {
+                Slot {
+                    seq: AtomicUsize::new(WRITING),
+                    entry: UnsafeCell::new(MaybeUninit::zeroed()),
+                }
+            }; TRACE_ENTRIES],
+        }
+    }
+
+    /// Entry `seq`, unless it was overwritten.
+    fn read(&self, seq: usize) -> Option<SupdTraceEntry> {
+        let slot = &self.slots[seq % TRACE_ENTRIES];
+        if slot.seq.load(Ordering::Acquire) != seq {
+            return None;
+        }
+        let entry = unsafe { slot.entry.get().read_volatile() };
+        fence(Ordering::Acquire);
+        (slot.seq.load(Ordering::Relaxed) == seq).then(|| unsafe { entry.assume_init() })
+    }
+}
+
+/// Record the call `eid`/`fid` of `src` to `dst` (`SUPD_TRACE_*` event), answered with `error`,
+/// in the ring of the hart.
+pub fn record(event: u32, src: usize, dst: usize, eid: usize, fid: usize, error: isize) {
+    let hartid = mhartid::read();
+    let Some(ring) = RINGS.get(hartid) else {
+        return;
+    };
+
+    let seq = ring.next.load(Ordering::Relaxed);
+    let slot = &ring.slots[seq % TRACE_ENTRIES];
+    slot.seq.store(WRITING, Ordering::Relaxed);
+    fence(Ordering::Release);
+    let entry = SupdTraceEntry {
+        seq: seq as u64,
+        timestamp: read_mtime(),
+        event,
+        hartid: hartid as u32,
+        src_domain: src as u32,
+        dst_domain: dst as u32,
+        eid: eid as u64,
+        fid: fid as u64,
+        error: error as i64,
+    };
+    unsafe { slot.entry.get().write_volatile(MaybeUninit::new(entry)) };
+    slot.seq.store(seq, Ordering::Release);
+    ring.next.store(seq + 1, Ordering::Release);
+}
+
+/// Entries of the ring of `hartid` from `first_seq` on, oldest first.
+pub fn entries(hartid: usize, first_seq: usize) -> impl Iterator<Item = SupdTraceEntry> {
+    let ring = RINGS.get(hartid);
+    let next = ring.map_or(0, |ring| ring.next.load(Ordering::Acquire));
+    let first = first_seq.max(next.saturating_sub(TRACE_ENTRIES));
+    (first..next).filter_map(move |seq| ring?.read(seq))
+}
+
+/// Harts which may have a ring
+pub fn harts() -> core::ops::Range<usize> {
+    0..TRACE_HARTS
+}
+
+/// Name of a `SUPD_TRACE_*` event in the crash dump
+pub fn event_name(event: u32) -> &'static str {
+    match event {
+        SUPD_TRACE_TEECALL => "teecall",
+        SUPD_TRACE_TEERET => "teeret",
+        SUPD_TRACE_SUPD_CALL => "supd",
+        _ => "unknown",
+    }
+}
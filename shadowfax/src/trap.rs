@@ -3,15 +3,99 @@
  * https://github.com/riscv-software-src/opensbi/blob/master/firmware/fw_base.S
  *
  * We need to expose the _trap_handler function which is executed when a trap occurs.
- * We are forwarding everything to `sbi_trap_handler`.
+ * Everything but the CoVE ecalls and the machine timer is forwarded to the trap handler of the SBI
+ * runtime (`sbi_trap_handler` with OpenSBI). Access faults of the supervisor domains are accounted
+ * to the faulting domain, recorded in the audit log and redirected to its trap handler; if the
+ * OpenSBI domain of the faulting domain allows the access, the mismatch is audited too. The console
+ * calls (DBCN) are served by `console.rs`, the reset calls (SRST) are checked by `reset.rs` and the
+ * suspend calls (HSM, SUSP) by `suspend.rs`, whatever the runtime. The ecalls of the VS-mode
+ * guests are not delegated: `cove.rs` serves their CoVE calls and hands the others to the
+ * hypervisor of the guest.
  *
  * Author: Giuseppe Capasso <capassog97@gmail.com>
  */
 
-use riscv::interrupt::supervisor::Exception;
+use riscv::{
+    interrupt::supervisor::Exception,
+    register::{
+        mcause,
+        mstatus::{self, MPP},
+        mtval, stvec,
+    },
+};
 
-use crate::{cove, opensbi, scheduler::scheduler_tick};
-use core::mem::offset_of;
+use crate::{
+    audit::AuditEvent,
+    console, cove, crash,
+    domain::{PMP_R, PMP_W, PMP_X},
+    interrupts,
+    reset::{self, Reset},
+    runtime::{self, TrapContext, TrapInfo, TrapRegs},
+    scheduler::scheduler_tick,
+    state::STATE,
+    suspend::{self, Suspend},
+};
+use common::{reg_load, reg_store};
+use core::{
+    mem::offset_of,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// mstatus.MPP, the privilege mode the trap comes from
+const MSTATUS_MPP_SHIFT: usize = 11;
+
+/*
+ * On RV32 the upper half of mstatus lives in mstatush, which holds MDT too. As in fw_base.S, it is
+ * saved and restored with the trap context. RV64 has no mstatush: the slot is zeroed.
+ */
+#[cfg(target_pointer_width = "32")]
+macro_rules! save_mstatush {
+    () => {
+        concat!(
+            "csrr t0, mstatush\n",
+            reg_store!(t0, "{sbi_trap_regs_offset_mstatush}(sp)")
+        )
+    };
+}
+
+#[cfg(target_pointer_width = "64")]
+macro_rules! save_mstatush {
+    () => {
+        reg_store!(zero, "{sbi_trap_regs_offset_mstatush}(sp)")
+    };
+}
+
+#[cfg(target_pointer_width = "32")]
+macro_rules! restore_mstatush {
+    () => {
+        concat!(
+            reg_load!(t0, "{sbi_trap_regs_offset_mstatush}(a0)"),
+            "csrw mstatush, t0\n"
+        )
+    };
+}
+
+#[cfg(target_pointer_width = "64")]
+macro_rules! restore_mstatush {
+    () => {
+        ""
+    };
+}
+
+// Clear MDT (mstatus bit 42, mstatush bit 10 on RV32)
+#[cfg(target_pointer_width = "32")]
+macro_rules! clear_mdt {
+    () => {
+        "li t0, 0x400\ncsrc mstatush, t0"
+    };
+}
+
+#[cfg(target_pointer_width = "64")]
+macro_rules! clear_mdt {
+    () => {
+        "li t0, 0x40000000000\ncsrc mstatus, t0"
+    };
+}
 
 /// The main trap handler function that orchestrates the saving and restoring of registers.
 /// The handler verifies if the trap is a TEECALL/TEERESUME or a TEERET and handles it with custom
@@ -23,20 +107,21 @@ pub fn handler() -> ! {
         /*
          * Check if the trap is a TEECALL/TEERET and perform the context switch to the tsm
          */
-        "
-            csrrw tp, mscratch, tp
-            sd t0, {sbi_scratch_tmp0_offset}(tp)
-            csrr t0, mcause
-            add t0, t0, -{ecall_code}
-            bnez t0, 1f
-            li t0, {covh_ext_id}
-            sub t0, a7, t0
-            beqz t0, {tee_handler}
-            li t0, {supd_ext_id}
-            sub t0, a7, t0
-            beqz t0, {supd_handler}
-            1:
-        ",
+        "csrrw tp, mscratch, tp",
+        reg_store!(t0, "{sbi_scratch_tmp0_offset}(tp)"),
+        "csrr t0, mcause",
+        "add t0, t0, -{ecall_code}",
+        "bnez t0, 1f",
+        "li t0, {covh_ext_id}",
+        "sub t0, a7, t0",
+        "beqz t0, {tee_handler}",
+        "li t0, {covi_ext_id}",
+        "sub t0, a7, t0",
+        "beqz t0, {covi_handler}",
+        "li t0, {supd_ext_id}",
+        "sub t0, a7, t0",
+        "beqz t0, {supd_handler}",
+        "1:",
         /*
          * Saves the current stack pointer and sets up the stack pointer for the trap context.
          * It also swaps the TP and MSCRATCH registers.
@@ -50,109 +135,98 @@ pub fn handler() -> ! {
          * Came_From_M_Mode = 0    ==>    Exception_Stack = TP
          * Came_From_M_Mode = -1   ==>    Exception_Stack = SP
          */
-        "
-            csrr t0, mstatus
-            srl t0, t0, {mstatus_mpp_shift}
-            and t0, t0, 3
-            slti t0, t0, 3
-            add t0, t0, -1
-            xor sp, sp, tp
-            and t0, t0, sp
-            xor sp, sp, tp
-            xor t0, tp, t0
-
-            // Save original SP on exception st
-            sd sp,  ({sbi_trap_regs_offset_sp}-{sbi_trap_context_size})(t0)
-
-            // Set SP to exception stack and make room for trap context
-            add sp, t0, -{sbi_trap_context_size}
-
-            // Restore T0 from scratch space
-            ld t0, {sbi_scratch_tmp0_offset}(tp)
-
-            // Save T0 on stack
-            sd t0, {sbi_trap_regs_offset_t0}(sp)
-
-            // Swap TP and MSCRATCH
-            csrrw tp, mscratch, tp
-        ",
+        "csrr t0, mstatus",
+        "srl t0, t0, {mstatus_mpp_shift}",
+        "and t0, t0, 3",
+        "slti t0, t0, 3",
+        "add t0, t0, -1",
+        "xor sp, sp, tp",
+        "and t0, t0, sp",
+        "xor sp, sp, tp",
+        "xor t0, tp, t0",
+
+        // Save original SP on exception st
+        reg_store!(sp, "({sbi_trap_regs_offset_sp}-{sbi_trap_context_size})(t0)"),
+
+        // Set SP to exception stack and make room for trap context
+        "add sp, t0, -{sbi_trap_context_size}",
+
+        // Restore T0 from scratch space
+        reg_load!(t0, "{sbi_scratch_tmp0_offset}(tp)"),
+
+        // Save T0 on stack
+        reg_store!(t0, "{sbi_trap_regs_offset_t0}(sp)"),
+
+        // Swap TP and MSCRATCH
+        "csrrw tp, mscratch, tp",
 
         /*
          * Saves the machine exception program counter (MEPC) and machine status (MSTATUS) registers
          * to the trap context stack.
          */
-        "
-            csrr t0, mepc
-            sd t0, {sbi_trap_regs_offset_mepc}(sp)
-            csrr t0, mstatus
-            sd t0, {sbi_trap_regs_offset_mstatus}(sp)
-            sd zero, {sbi_trap_regs_offset_mstatush}(sp)
-        ",
+        "csrr t0, mepc",
+        reg_store!(t0, "{sbi_trap_regs_offset_mepc}(sp)"),
+        "csrr t0, mstatus",
+        reg_store!(t0, "{sbi_trap_regs_offset_mstatus}(sp)"),
+        save_mstatush!(),
 
         /*
          * Saves additional trap information such as cause and trap value to the trap context stack.
          * Clears the machine-dependent trap (MDT) register.
          */
-        "
-            sd zero, {sbi_trap_regs_offset_zero}(sp)
-            sd ra, {sbi_trap_regs_offset_ra}(sp)
-            sd gp, {sbi_trap_regs_offset_gp}(sp)
-            sd tp, {sbi_trap_regs_offset_tp}(sp)
-            sd t1, {sbi_trap_regs_offset_t1}(sp)
-            sd t2, {sbi_trap_regs_offset_t2}(sp)
-            sd s0, {sbi_trap_regs_offset_s0}(sp)
-            sd s1, {sbi_trap_regs_offset_s1}(sp)
-            sd a0, {sbi_trap_regs_offset_a0}(sp)
-            sd a1, {sbi_trap_regs_offset_a1}(sp)
-            sd a2, {sbi_trap_regs_offset_a2}(sp)
-            sd a3, {sbi_trap_regs_offset_a3}(sp)
-            sd a4, {sbi_trap_regs_offset_a4}(sp)
-            sd a5, {sbi_trap_regs_offset_a5}(sp)
-            sd a6, {sbi_trap_regs_offset_a6}(sp)
-            sd a7, {sbi_trap_regs_offset_a7}(sp)
-            sd s2, {sbi_trap_regs_offset_s2}(sp)
-            sd s3, {sbi_trap_regs_offset_s3}(sp)
-            sd s4, {sbi_trap_regs_offset_s4}(sp)
-            sd s5, {sbi_trap_regs_offset_s5}(sp)
-            sd s6, {sbi_trap_regs_offset_s6}(sp)
-            sd s7, {sbi_trap_regs_offset_s7}(sp)
-            sd s8, {sbi_trap_regs_offset_s8}(sp)
-            sd s9, {sbi_trap_regs_offset_s9}(sp)
-            sd s10, {sbi_trap_regs_offset_s10}(sp)
-            sd s11, {sbi_trap_regs_offset_s11}(sp)
-            sd t3, {sbi_trap_regs_offset_t3}(sp)
-            sd t4, {sbi_trap_regs_offset_t4}(sp)
-            sd t5, {sbi_trap_regs_offset_t5}(sp)
-            sd t6, {sbi_trap_regs_offset_t6}(sp)
-        ",
+        reg_store!(zero, "{sbi_trap_regs_offset_zero}(sp)"),
+        reg_store!(ra, "{sbi_trap_regs_offset_ra}(sp)"),
+        reg_store!(gp, "{sbi_trap_regs_offset_gp}(sp)"),
+        reg_store!(tp, "{sbi_trap_regs_offset_tp}(sp)"),
+        reg_store!(t1, "{sbi_trap_regs_offset_t1}(sp)"),
+        reg_store!(t2, "{sbi_trap_regs_offset_t2}(sp)"),
+        reg_store!(s0, "{sbi_trap_regs_offset_s0}(sp)"),
+        reg_store!(s1, "{sbi_trap_regs_offset_s1}(sp)"),
+        reg_store!(a0, "{sbi_trap_regs_offset_a0}(sp)"),
+        reg_store!(a1, "{sbi_trap_regs_offset_a1}(sp)"),
+        reg_store!(a2, "{sbi_trap_regs_offset_a2}(sp)"),
+        reg_store!(a3, "{sbi_trap_regs_offset_a3}(sp)"),
+        reg_store!(a4, "{sbi_trap_regs_offset_a4}(sp)"),
+        reg_store!(a5, "{sbi_trap_regs_offset_a5}(sp)"),
+        reg_store!(a6, "{sbi_trap_regs_offset_a6}(sp)"),
+        reg_store!(a7, "{sbi_trap_regs_offset_a7}(sp)"),
+        reg_store!(s2, "{sbi_trap_regs_offset_s2}(sp)"),
+        reg_store!(s3, "{sbi_trap_regs_offset_s3}(sp)"),
+        reg_store!(s4, "{sbi_trap_regs_offset_s4}(sp)"),
+        reg_store!(s5, "{sbi_trap_regs_offset_s5}(sp)"),
+        reg_store!(s6, "{sbi_trap_regs_offset_s6}(sp)"),
+        reg_store!(s7, "{sbi_trap_regs_offset_s7}(sp)"),
+        reg_store!(s8, "{sbi_trap_regs_offset_s8}(sp)"),
+        reg_store!(s9, "{sbi_trap_regs_offset_s9}(sp)"),
+        reg_store!(s10, "{sbi_trap_regs_offset_s10}(sp)"),
+        reg_store!(s11, "{sbi_trap_regs_offset_s11}(sp)"),
+        reg_store!(t3, "{sbi_trap_regs_offset_t3}(sp)"),
+        reg_store!(t4, "{sbi_trap_regs_offset_t4}(sp)"),
+        reg_store!(t5, "{sbi_trap_regs_offset_t5}(sp)"),
+        reg_store!(t6, "{sbi_trap_regs_offset_t6}(sp)"),
 
-        "
-            csrr t0, mcause
-            sd t0, ({sbi_trap_regs_size} + {sbi_trap_info_offset_cause})(sp)
-            csrr t0, mtval
-            sd t0, ({sbi_trap_regs_size} + {sbi_trap_info_offset_tval})(sp)
-            sd zero, ({sbi_trap_regs_size} + {sbi_trap_info_offset_tval2})(sp)
-            sd zero, ({sbi_trap_regs_size} + {sbi_trap_info_offset_tinst})(sp)
-            li t0, 0
-            sd t0, ({sbi_trap_regs_size} + {sbi_trap_info_offset_gva})(sp)
-        ",
+        "csrr t0, mcause",
+        reg_store!(t0, "({sbi_trap_regs_size} + {sbi_trap_info_offset_cause})(sp)"),
+        "csrr t0, mtval",
+        reg_store!(t0, "({sbi_trap_regs_size} + {sbi_trap_info_offset_tval})(sp)"),
+        reg_store!(zero, "({sbi_trap_regs_size} + {sbi_trap_info_offset_tval2})(sp)"),
+        reg_store!(zero, "({sbi_trap_regs_size} + {sbi_trap_info_offset_tinst})(sp)"),
+        "li t0, 0",
+        reg_store!(t0, "({sbi_trap_regs_size} + {sbi_trap_info_offset_gva})(sp)"),
 
         // We can take another trap
-        "
-            li t0, 0x40000000000
-            csrc mstatus, t0
-        ",
+        clear_mdt!(),
 
         /*
-         * Call the opensbi trap handler which wraps opensbi trap handler
+         * Call the trap handler of the SBI runtime
          */
         "
             csrr t0, mcause
             li t1, 1
-            slli t1, t1, 63     // Set MSB
+            slli t1, t1, {xlen_msb} // Set MSB
             addi t1, t1, 7      // Add 7 (Timer Interrupt)
 
-            // If NOT timer, skip to standard OpenSBI handler
+            // If NOT timer, skip to the SBI runtime handler
             bne t0, t1, 2f
 
             // === TIMER EVENT ===
@@ -176,118 +250,285 @@ pub fn handler() -> ! {
         /*
          * Restores all general-purpose registers except A0 and T0 from the trap context stack.
          */
-        "
-            ld ra, {sbi_trap_regs_offset_ra}(a0)
-            ld sp, {sbi_trap_regs_offset_sp}(a0)
-            ld gp, {sbi_trap_regs_offset_gp}(a0)
-            ld tp, {sbi_trap_regs_offset_tp}(a0)
-            ld t1, {sbi_trap_regs_offset_t1}(a0)
-            ld t2, {sbi_trap_regs_offset_t2}(a0)
-            ld s0, {sbi_trap_regs_offset_s0}(a0)
-            ld s1, {sbi_trap_regs_offset_s1}(a0)
-            ld a1, {sbi_trap_regs_offset_a1}(a0)
-            ld a2, {sbi_trap_regs_offset_a2}(a0)
-            ld a3, {sbi_trap_regs_offset_a3}(a0)
-            ld a4, {sbi_trap_regs_offset_a4}(a0)
-            ld a5, {sbi_trap_regs_offset_a5}(a0)
-            ld a6, {sbi_trap_regs_offset_a6}(a0)
-            ld a7, {sbi_trap_regs_offset_a7}(a0)
-            ld s2, {sbi_trap_regs_offset_s2}(a0)
-            ld s3, {sbi_trap_regs_offset_s3}(a0)
-            ld s4, {sbi_trap_regs_offset_s4}(a0)
-            ld s5, {sbi_trap_regs_offset_s5}(a0)
-            ld s6, {sbi_trap_regs_offset_s6}(a0)
-            ld s7, {sbi_trap_regs_offset_s7}(a0)
-            ld s8, {sbi_trap_regs_offset_s8}(a0)
-            ld s9, {sbi_trap_regs_offset_s9}(a0)
-            ld s10, {sbi_trap_regs_offset_s10}(a0)
-            ld s11, {sbi_trap_regs_offset_s11}(a0)
-            ld t3, {sbi_trap_regs_offset_t3}(a0)
-            ld t4, {sbi_trap_regs_offset_t4}(a0)
-            ld t5, {sbi_trap_regs_offset_t5}(a0)
-            ld t6, {sbi_trap_regs_offset_t6}(a0)
-        ",
+        reg_load!(ra, "{sbi_trap_regs_offset_ra}(a0)"),
+        reg_load!(sp, "{sbi_trap_regs_offset_sp}(a0)"),
+        reg_load!(gp, "{sbi_trap_regs_offset_gp}(a0)"),
+        reg_load!(tp, "{sbi_trap_regs_offset_tp}(a0)"),
+        reg_load!(t1, "{sbi_trap_regs_offset_t1}(a0)"),
+        reg_load!(t2, "{sbi_trap_regs_offset_t2}(a0)"),
+        reg_load!(s0, "{sbi_trap_regs_offset_s0}(a0)"),
+        reg_load!(s1, "{sbi_trap_regs_offset_s1}(a0)"),
+        reg_load!(a1, "{sbi_trap_regs_offset_a1}(a0)"),
+        reg_load!(a2, "{sbi_trap_regs_offset_a2}(a0)"),
+        reg_load!(a3, "{sbi_trap_regs_offset_a3}(a0)"),
+        reg_load!(a4, "{sbi_trap_regs_offset_a4}(a0)"),
+        reg_load!(a5, "{sbi_trap_regs_offset_a5}(a0)"),
+        reg_load!(a6, "{sbi_trap_regs_offset_a6}(a0)"),
+        reg_load!(a7, "{sbi_trap_regs_offset_a7}(a0)"),
+        reg_load!(s2, "{sbi_trap_regs_offset_s2}(a0)"),
+        reg_load!(s3, "{sbi_trap_regs_offset_s3}(a0)"),
+        reg_load!(s4, "{sbi_trap_regs_offset_s4}(a0)"),
+        reg_load!(s5, "{sbi_trap_regs_offset_s5}(a0)"),
+        reg_load!(s6, "{sbi_trap_regs_offset_s6}(a0)"),
+        reg_load!(s7, "{sbi_trap_regs_offset_s7}(a0)"),
+        reg_load!(s8, "{sbi_trap_regs_offset_s8}(a0)"),
+        reg_load!(s9, "{sbi_trap_regs_offset_s9}(a0)"),
+        reg_load!(s10, "{sbi_trap_regs_offset_s10}(a0)"),
+        reg_load!(s11, "{sbi_trap_regs_offset_s11}(a0)"),
+        reg_load!(t3, "{sbi_trap_regs_offset_t3}(a0)"),
+        reg_load!(t4, "{sbi_trap_regs_offset_t4}(a0)"),
+        reg_load!(t5, "{sbi_trap_regs_offset_t5}(a0)"),
+        reg_load!(t6, "{sbi_trap_regs_offset_t6}(a0)"),
 
         /*
          * Restores the machine status (MSTATUS) and machine exception program counter (MEPC)
          * registers from the trap context stack.
          */
-        "
-            ld t0, {sbi_trap_regs_offset_mstatus}(a0)
-            csrw mstatus, t0
-            ld t0, {sbi_trap_regs_offset_mepc}(a0)
-            csrw mepc, t0
-        ",
+        reg_load!(t0, "{sbi_trap_regs_offset_mstatus}(a0)"),
+        "csrw mstatus, t0",
+        restore_mstatush!(),
+        reg_load!(t0, "{sbi_trap_regs_offset_mepc}(a0)"),
+        "csrw mepc, t0",
 
         /*
          * Restores the A0 and T0 registers from the trap context stack.
          */
 
-        "
-            ld t0, {sbi_trap_regs_offset_t0}(a0)
-            ld a0, {sbi_trap_regs_offset_a0}(a0)
-        ",
+        reg_load!(t0, "{sbi_trap_regs_offset_t0}(a0)"),
+        reg_load!(a0, "{sbi_trap_regs_offset_a0}(a0)"),
 
         /*
          * Go back to caller
          */
         "mret",
 
-        sbi_scratch_tmp0_offset = const offset_of!(opensbi::sbi_scratch, tmp0),
+        sbi_scratch_tmp0_offset = const runtime::SCRATCH_TMP0_OFFSET,
         ecall_code = const Exception::SupervisorEnvCall as usize,
         covh_ext_id = const common::sbi::SBI_COVH_EXT_ID,
+        covi_ext_id = const common::sbi::SBI_COVI_EXT_ID,
         supd_ext_id = const common::sbi::SBI_SUPD_EXT_ID,
         tee_handler = sym cove::tee_handler_entry,
+        covi_handler = sym cove::covi_handler_entry,
         supd_handler = sym cove::supd_handler_entry,
 
-        mstatus_mpp_shift = const opensbi::MSTATUS_MPP_SHIFT,
-        sbi_trap_context_size = const (size_of::<opensbi::sbi_trap_regs>() + size_of::<opensbi::sbi_trap_info>() ) ,
-        sbi_trap_regs_offset_sp = const offset_of!(opensbi::sbi_trap_regs, sp),
-        sbi_trap_regs_offset_t0 = const offset_of!(opensbi::sbi_trap_regs, t0),
-
-        sbi_trap_regs_offset_mepc = const offset_of!(opensbi::sbi_trap_regs, mepc),
-        sbi_trap_regs_offset_mstatus= const offset_of!(opensbi::sbi_trap_regs, mstatus),
-        sbi_trap_regs_offset_mstatush = const offset_of!(opensbi::sbi_trap_regs, mstatusH),
-
-        sbi_trap_regs_offset_zero = const offset_of!(opensbi::sbi_trap_regs, zero),
-        sbi_trap_regs_offset_ra = const offset_of!(opensbi::sbi_trap_regs, ra),
-        sbi_trap_regs_offset_gp = const offset_of!(opensbi::sbi_trap_regs, gp),
-        sbi_trap_regs_offset_tp = const offset_of!(opensbi::sbi_trap_regs, tp),
-        sbi_trap_regs_offset_t1 = const offset_of!(opensbi::sbi_trap_regs, t1),
-        sbi_trap_regs_offset_t2 = const offset_of!(opensbi::sbi_trap_regs, t2),
-        sbi_trap_regs_offset_s0 = const offset_of!(opensbi::sbi_trap_regs, s0),
-        sbi_trap_regs_offset_s1 = const offset_of!(opensbi::sbi_trap_regs, s1),
-        sbi_trap_regs_offset_a0 = const offset_of!(opensbi::sbi_trap_regs, a0),
-        sbi_trap_regs_offset_a1 = const offset_of!(opensbi::sbi_trap_regs, a1),
-        sbi_trap_regs_offset_a2 = const offset_of!(opensbi::sbi_trap_regs, a2),
-        sbi_trap_regs_offset_a3 = const offset_of!(opensbi::sbi_trap_regs, a3),
-        sbi_trap_regs_offset_a4 = const offset_of!(opensbi::sbi_trap_regs, a4),
-        sbi_trap_regs_offset_a5 = const offset_of!(opensbi::sbi_trap_regs, a5),
-        sbi_trap_regs_offset_a6 = const offset_of!(opensbi::sbi_trap_regs, a6),
-        sbi_trap_regs_offset_a7 = const offset_of!(opensbi::sbi_trap_regs, a7),
-        sbi_trap_regs_offset_s2 = const offset_of!(opensbi::sbi_trap_regs, s2),
-        sbi_trap_regs_offset_s3 = const offset_of!(opensbi::sbi_trap_regs, s3),
-        sbi_trap_regs_offset_s4 = const offset_of!(opensbi::sbi_trap_regs, s4),
-        sbi_trap_regs_offset_s5 = const offset_of!(opensbi::sbi_trap_regs, s5),
-        sbi_trap_regs_offset_s6 = const offset_of!(opensbi::sbi_trap_regs, s6),
-        sbi_trap_regs_offset_s7 = const offset_of!(opensbi::sbi_trap_regs, s7),
-        sbi_trap_regs_offset_s8 = const offset_of!(opensbi::sbi_trap_regs, s8),
-        sbi_trap_regs_offset_s9 = const offset_of!(opensbi::sbi_trap_regs, s9),
-        sbi_trap_regs_offset_s10 = const offset_of!(opensbi::sbi_trap_regs, s10),
-        sbi_trap_regs_offset_s11 = const offset_of!(opensbi::sbi_trap_regs, s11),
-        sbi_trap_regs_offset_t3 = const offset_of!(opensbi::sbi_trap_regs, t3),
-        sbi_trap_regs_offset_t4 = const offset_of!(opensbi::sbi_trap_regs, t4),
-        sbi_trap_regs_offset_t5 = const offset_of!(opensbi::sbi_trap_regs, t5),
-        sbi_trap_regs_offset_t6 = const offset_of!(opensbi::sbi_trap_regs, t6),
-
-        sbi_trap_regs_size = const size_of::<opensbi::sbi_trap_regs>(),
-        sbi_trap_info_offset_cause = const  offset_of!(opensbi::sbi_trap_info, cause),
-        sbi_trap_info_offset_tval = const offset_of!(opensbi::sbi_trap_info, tval),
-        sbi_trap_info_offset_tval2 = const offset_of!(opensbi::sbi_trap_info, tval2),
-        sbi_trap_info_offset_tinst = const offset_of!(opensbi::sbi_trap_info, tinst),
-        sbi_trap_info_offset_gva = const offset_of!(opensbi::sbi_trap_info, gva),
-
-        trap_handler = sym opensbi::sbi_trap_handler,
+        mstatus_mpp_shift = const MSTATUS_MPP_SHIFT,
+        xlen_msb = const usize::BITS - 1,
+        sbi_trap_context_size = const (size_of::<TrapRegs>() + size_of::<TrapInfo>() ) ,
+        sbi_trap_regs_offset_sp = const offset_of!(TrapRegs, sp),
+        sbi_trap_regs_offset_t0 = const offset_of!(TrapRegs, t0),
+
+        sbi_trap_regs_offset_mepc = const offset_of!(TrapRegs, mepc),
+        sbi_trap_regs_offset_mstatus= const offset_of!(TrapRegs, mstatus),
+        sbi_trap_regs_offset_mstatush = const offset_of!(TrapRegs, mstatusH),
+
+        sbi_trap_regs_offset_zero = const offset_of!(TrapRegs, zero),
+        sbi_trap_regs_offset_ra = const offset_of!(TrapRegs, ra),
+        sbi_trap_regs_offset_gp = const offset_of!(TrapRegs, gp),
+        sbi_trap_regs_offset_tp = const offset_of!(TrapRegs, tp),
+        sbi_trap_regs_offset_t1 = const offset_of!(TrapRegs, t1),
+        sbi_trap_regs_offset_t2 = const offset_of!(TrapRegs, t2),
+        sbi_trap_regs_offset_s0 = const offset_of!(TrapRegs, s0),
+        sbi_trap_regs_offset_s1 = const offset_of!(TrapRegs, s1),
+        sbi_trap_regs_offset_a0 = const offset_of!(TrapRegs, a0),
+        sbi_trap_regs_offset_a1 = const offset_of!(TrapRegs, a1),
+        sbi_trap_regs_offset_a2 = const offset_of!(TrapRegs, a2),
+        sbi_trap_regs_offset_a3 = const offset_of!(TrapRegs, a3),
+        sbi_trap_regs_offset_a4 = const offset_of!(TrapRegs, a4),
+        sbi_trap_regs_offset_a5 = const offset_of!(TrapRegs, a5),
+        sbi_trap_regs_offset_a6 = const offset_of!(TrapRegs, a6),
+        sbi_trap_regs_offset_a7 = const offset_of!(TrapRegs, a7),
+        sbi_trap_regs_offset_s2 = const offset_of!(TrapRegs, s2),
+        sbi_trap_regs_offset_s3 = const offset_of!(TrapRegs, s3),
+        sbi_trap_regs_offset_s4 = const offset_of!(TrapRegs, s4),
+        sbi_trap_regs_offset_s5 = const offset_of!(TrapRegs, s5),
+        sbi_trap_regs_offset_s6 = const offset_of!(TrapRegs, s6),
+        sbi_trap_regs_offset_s7 = const offset_of!(TrapRegs, s7),
+        sbi_trap_regs_offset_s8 = const offset_of!(TrapRegs, s8),
+        sbi_trap_regs_offset_s9 = const offset_of!(TrapRegs, s9),
+        sbi_trap_regs_offset_s10 = const offset_of!(TrapRegs, s10),
+        sbi_trap_regs_offset_s11 = const offset_of!(TrapRegs, s11),
+        sbi_trap_regs_offset_t3 = const offset_of!(TrapRegs, t3),
+        sbi_trap_regs_offset_t4 = const offset_of!(TrapRegs, t4),
+        sbi_trap_regs_offset_t5 = const offset_of!(TrapRegs, t5),
+        sbi_trap_regs_offset_t6 = const offset_of!(TrapRegs, t6),
+
+        sbi_trap_regs_size = const size_of::<TrapRegs>(),
+        sbi_trap_info_offset_cause = const  offset_of!(TrapInfo, cause),
+        sbi_trap_info_offset_tval = const offset_of!(TrapInfo, tval),
+        sbi_trap_info_offset_tval2 = const offset_of!(TrapInfo, tval2),
+        sbi_trap_info_offset_tinst = const offset_of!(TrapInfo, tinst),
+        sbi_trap_info_offset_gva = const offset_of!(TrapInfo, gva),
+
+        trap_handler = sym trap_handler,
         scheduler_tick = sym scheduler_tick
     );
 }
+
+// Instruction, load and store access faults: PMP violations of the supervisor domains
+const ACCESS_FAULTS: [usize; 3] = [1, 5, 7];
+const SUPERVISOR_ECALL: usize = Exception::SupervisorEnvCall as usize;
+const VIRTUAL_SUPERVISOR_ECALL: usize = 10;
+
+/// A domain whose trap handler faults this many times in a row is stuck: it triple faulted
+const TRIPLE_FAULT: usize = 3;
+/// Consecutive access faults at the trap vector of the domain
+static VECTOR_FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Forward the trap to the SBI runtime. The access faults of a supervisor domain (e.g. it touched
+/// the memory of another domain) are accounted and go straight back to the domain, as an
+/// exception for its trap handler.
+// The registers are `c_ulong` with OpenSBI
+#[allow(clippy::unnecessary_cast)]
+extern "C" fn trap_handler(ctx: &mut TrapContext) -> &mut TrapRegs {
+    let _frame = crash::TrapFrameGuard::enter(&ctx.regs);
+    let cause = mcause::read().bits();
+    if cause == VIRTUAL_SUPERVISOR_ECALL {
+        return cove::guest_ecall(ctx);
+    }
+    if cause == SUPERVISOR_ECALL {
+        if let Some(state) = STATE.lock().get() {
+            crash::record_call(
+                state.active_domain,
+                ctx.regs.a7 as usize,
+                ctx.regs.a6 as usize,
+            );
+        }
+        match ctx.regs.a7 as usize {
+            console::EXT_DBCN => return console_ecall(ctx),
+            reset::EXT_SRST => return reset_ecall(ctx),
+            suspend::EXT_HSM | suspend::EXT_SUSP => return suspend_ecall(ctx),
+            _ => {}
+        }
+    }
+    // The IPIs the runtime forwards during a TEECALL wait for the TEERET of the first caller
+    if mcause::read().is_interrupt() {
+        let pending = interrupts::pending();
+        let regs = runtime::trap_handler(ctx);
+        if let Some(state) = STATE.lock().get() {
+            interrupts::defer(state, interrupts::pending() & !pending);
+        }
+        return regs;
+    }
+    if !ACCESS_FAULTS.contains(&cause) || mstatus::read().mpp() == MPP::Machine {
+        return runtime::trap_handler(ctx);
+    }
+
+    let addr = mtval::read();
+    if let Some(state) = STATE.lock().get_mut() {
+        let owner = state.access_fault(cause, addr);
+        debug!(
+            "domain {} access fault {} at {:#x} (owner {:?})",
+            state.active_domain, cause, addr, owner
+        );
+
+        // The PMP denied the access, the OpenSBI domain must deny it too
+        let access = match cause {
+            1 => PMP_X,
+            5 => PMP_R,
+            _ => PMP_W,
+        };
+        if runtime::domain_allows(state.active_domain, addr, access) == Some(true) {
+            debug!(
+                "domain {}: the OpenSBI domain allows {:#x}",
+                state.active_domain, addr
+            );
+            state.record(AuditEvent::DomainMismatch, cause, addr);
+        }
+
+        // The fault is redirected to the trap vector: if the vector faults too, the domain loops
+        if ctx.regs.mepc as usize != stvec::read().address() {
+            VECTOR_FAULTS.store(0, Ordering::Relaxed);
+        } else if VECTOR_FAULTS.fetch_add(1, Ordering::Relaxed) + 1 == TRIPLE_FAULT {
+            panic!(
+                "domain {} triple fault: access fault {} at {:#x} in its trap handler",
+                state.active_domain, cause, addr
+            );
+        }
+    }
+    // Not taken from M-mode (see above), the runtime only reports what it cannot redirect
+    if runtime::redirect_trap(ctx).is_err() {
+        return runtime::trap_handler(ctx);
+    }
+    &mut ctx.regs
+}
+
+/// Serve a DBCN call of the active domain and return after the ecall.
+// The registers are `c_ulong` with OpenSBI
+#[allow(clippy::unnecessary_cast)]
+fn console_ecall(ctx: &mut TrapContext) -> &mut TrapRegs {
+    let regs = &mut ctx.regs;
+    let args = [regs.a0, regs.a1, regs.a2, regs.a3, regs.a4, regs.a5].map(|r| r as usize);
+    let ret = match STATE.lock().get() {
+        Some(state) => console::handle(state, regs.a6 as usize, &args),
+        None => panic!("console call before the state is initialized"),
+    };
+
+    regs.a0 = ret.a0 as _;
+    regs.a1 = ret.a1 as _;
+    regs.mepc += 4;
+    regs
+}
+
+/// Serve an SRST call of the active domain: a platform reset goes on to the runtime.
+// The registers are `c_ulong` with OpenSBI
+#[allow(clippy::unnecessary_cast)]
+fn reset_ecall(ctx: &mut TrapContext) -> &mut TrapRegs {
+    let mut guard = STATE.lock();
+    let Some(state) = guard.get_mut() else {
+        drop(guard);
+        return runtime::trap_handler(ctx);
+    };
+
+    let regs = &mut ctx.regs;
+    let (fid, args) = (
+        regs.a6 as usize,
+        [regs.a0, regs.a1, regs.a2, regs.a3, regs.a4, regs.a5].map(|r| r as usize),
+    );
+    let ret = match reset::handle(state, regs, fid, &args) {
+        Reset::Platform => {
+            drop(guard);
+            return runtime::trap_handler(ctx);
+        }
+        Reset::Stopped => return &mut ctx.regs,
+        Reset::Denied(ret) => ret,
+    };
+
+    let regs = &mut ctx.regs;
+    regs.a0 = ret.a0 as _;
+    regs.a1 = ret.a1 as _;
+    regs.mepc += 4;
+    regs
+}
+
+/// Serve a suspend call of the active domain: a retentive suspend and the other HSM calls go on to
+/// the runtime.
+// The registers are `c_ulong` with OpenSBI
+#[allow(clippy::unnecessary_cast)]
+fn suspend_ecall(ctx: &mut TrapContext) -> &mut TrapRegs {
+    let mut guard = STATE.lock();
+    let Some(state) = guard.get_mut() else {
+        drop(guard);
+        return runtime::trap_handler(ctx);
+    };
+
+    let regs = &mut ctx.regs;
+    let (eid, fid, args) = (
+        regs.a7 as usize,
+        regs.a6 as usize,
+        [regs.a0, regs.a1, regs.a2, regs.a3, regs.a4, regs.a5].map(|r| r as usize),
+    );
+    let ret = match suspend::handle(state, regs, eid, fid, &args) {
+        Suspend::Runtime => {
+            drop(guard);
+            return runtime::trap_handler(ctx);
+        }
+        Suspend::Resumed => return &mut ctx.regs,
+        Suspend::Denied(ret) => ret,
+    };
+
+    let regs = &mut ctx.regs;
+    regs.a0 = ret.a0 as _;
+    regs.a1 = ret.a1 as _;
+    regs.mepc += 4;
+    regs
+}
@@ -0,0 +1,109 @@
+/*
+ * Watchdog of the TEECALLs. The TSM is not preemptible: a TSM stuck in a loop would hang the whole
+ * system. When a TEECALL enters the TSM, the machine timer is armed with the budget of the device
+ * tree (`shadowfax,watchdog-ms` of a domain instance, `WATCHDOG_DEFAULT_MS` if absent, 0 disables
+ * the watchdog). If the TSM has not TEERET'd when the timer fires, `cove::tsm_timeout` switches
+ * back to the caller with SBI_ERR_TIMEOUT and marks the TSM as faulted.
+ *
+ * The machine timer also carries the timer event of the supervisor domains: the earliest of the
+ * two deadlines is programmed, and the event of the supervisor is given back on TEERET. RUN_TVM_VCPU
+ * is not watched, the TVM runs inside the TEECALL for as long as it needs.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use riscv::register::{mhartid, mie};
+
+use crate::{
+    fdt,
+    scheduler::{read_mtime, read_mtimecmp, write_mtimecmp},
+};
+
+/// Budget of a TEECALL if the device tree sets none
+pub const WATCHDOG_DEFAULT_MS: u32 = 1000;
+/// `mtime` frequency if the device tree sets none
+const DEFAULT_TIMEBASE_FREQUENCY: u32 = 10_000_000;
+
+pub struct Watchdog {
+    /// Budget of a TEECALL in `mtime` ticks, 0 if disabled
+    budget: u64,
+    armed: Option<Armed>,
+}
+
+/// TEECALL being watched
+#[derive(Clone, Copy, Debug)]
+pub struct Armed {
+    pub caller: usize,
+    pub tsm: usize,
+    pub eid: usize,
+    pub fid: usize,
+    deadline: u64,
+    /// Timer event of the supervisor, pending when the TEECALL started
+    saved: Option<u64>,
+}
+
+impl Watchdog {
+    pub const fn disabled() -> Self {
+        Self {
+            budget: 0,
+            armed: None,
+        }
+    }
+
+    pub fn from_fdt(fdt_addr: usize) -> Self {
+        let ms = fdt::find_watchdog_ms(fdt_addr).unwrap_or(WATCHDOG_DEFAULT_MS);
+        let frequency =
+            fdt::find_timebase_frequency(fdt_addr).unwrap_or(DEFAULT_TIMEBASE_FREQUENCY);
+        Self {
+            budget: ms as u64 * frequency as u64 / 1000,
+            armed: None,
+        }
+    }
+
+    /// Start watching the TEECALL `eid`/`fid` of `caller` to `tsm`.
+    pub fn arm(&mut self, caller: usize, tsm: usize, eid: usize, fid: usize) {
+        if self.budget == 0 {
+            return;
+        }
+
+        let hartid = mhartid::read();
+        let deadline = read_mtime() + self.budget;
+        let saved = mie::read().mtimer().then(|| read_mtimecmp(hartid));
+        write_mtimecmp(hartid, saved.map_or(deadline, |event| event.min(deadline)));
+        unsafe { mie::set_mtimer() };
+
+        self.armed = Some(Armed {
+            caller,
+            tsm,
+            eid,
+            fid,
+            deadline,
+            saved,
+        });
+    }
+
+    /// Stop watching, on TEERET or when the budget is over, and give the timer back to the
+    /// supervisor.
+    pub fn disarm(&mut self) -> Option<Armed> {
+        let armed = self.armed.take()?;
+        match armed.saved {
+            Some(event) => write_mtimecmp(mhartid::read(), event),
+            None => unsafe { mie::clear_mtimer() },
+        }
+        Some(armed)
+    }
+
+    /// Whether the TEECALL being watched is over budget at `now`.
+    pub fn expired(&self, now: u64) -> bool {
+        self.armed.is_some_and(|armed| now >= armed.deadline)
+    }
+
+    /// The timer event of the supervisor was delivered: program the deadline of the TEECALL again.
+    pub fn rearm(&mut self) {
+        if let Some(armed) = self.armed.as_mut() {
+            armed.saved = None;
+            write_mtimecmp(mhartid::read(), armed.deadline);
+            unsafe { mie::set_mtimer() };
+        }
+    }
+}
@@ -0,0 +1,130 @@
+/*
+ * W^X of the firmware code (`firmware-wx` feature, on the `rust-sbi` runtime which owns the PMP).
+ * M-mode ignores the PMP entries without the L bit, so the firmware can write its own code. At
+ * init, locked entries make read/execute only:
+ *  - the firmware code and read-only data, `[_fw_start, _fw_rw_start)` from the linkerscript;
+ *  - the executable segments of the TSM, once loaded: `_secure_init` runs in M-mode.
+ * They take the first LOCKED_ENTRIES PMP entries, which win over the domain regions programmed
+ * after them (`cove::program_pmp_from_regions`), and stay until the hart resets.
+ *
+ * A locked entry applies to S/U-mode as well: the domains can read and execute the locked code,
+ * which holds no secret (the firmware image and the signed TSM are public). For the same reason
+ * the firmware data is not locked, a read/write entry would hand it to S-mode: making it
+ * non-executable takes the M-mode-only rules of Smepmp (`mseccfg.MML`), which also deny M-mode
+ * the domain memory the firmware reads and writes directly.
+ *
+ * The regions are NAPOT (see `domain::napot_split`): a range which needs more entries than left is
+ * not locked at all. Platforms with fewer than 16 PMP entries leave the feature off.
+ *
+ * Author: Giuseppe Capasso <capassog97@gmail.com>
+ */
+
+use alloc::vec::Vec;
+use common::sbi::PAGE_SIZE;
+use cove_core::{
+    domain::{MEMREGION_M_EXECUTABLE, MEMREGION_SU_EXECUTABLE, PMP_L},
+    pmp::pmp_entry,
+};
+use elf::{
+    abi::{PF_W, PF_X, PT_LOAD},
+    endian::AnyEndian,
+    ElfBytes,
+};
+use spin::Mutex;
+
+use crate::{
+    _fw_rw_start, _fw_start,
+    cove::{read_pmpcfg, write_pmpaddr, write_pmpcfg},
+    domain::{napot_split, MemoryRegion, MAX_MEMORY_REGIONS, MEMREGION_R},
+    error::LockError,
+};
+
+/// PMP entries programmed by the firmware (pmpaddr0-15)
+const PMP_ENTRIES: usize = 16;
+/// Entries reserved to the locked regions, before the domain regions
+pub const LOCKED_ENTRIES: usize = PMP_ENTRIES - MAX_MEMORY_REGIONS;
+
+const MEMREGION_RX: u8 = MEMREGION_R | MEMREGION_M_EXECUTABLE | MEMREGION_SU_EXECUTABLE;
+
+/// Locked regions, the index is the PMP entry
+static LOCKED: Mutex<Vec<MemoryRegion>> = Mutex::new(Vec::new());
+
+/// Lock the firmware code and read-only data.
+pub fn lock_firmware() -> Result<(), LockError> {
+    let start = &raw const _fw_start as usize;
+    let end = &raw const _fw_rw_start as usize;
+    lock(&[(start, end)])
+}
+
+/// Lock the executable segments of the TSM loaded from `bin`, the whole pages of the ones which are
+/// not writable.
+pub fn lock_tsm(bin: &[u8]) -> Result<(), LockError> {
+    let elf = ElfBytes::<AnyEndian>::minimal_parse(bin).map_err(LockError::Elf)?;
+    let segments = elf.segments().ok_or(LockError::NoProgramHeaders)?;
+    let ranges: Vec<_> = segments
+        .iter()
+        .filter(|ph| ph.p_type == PT_LOAD && ph.p_flags & PF_X != 0 && ph.p_flags & PF_W == 0)
+        .map(|ph| {
+            let start = (ph.p_vaddr as usize).next_multiple_of(PAGE_SIZE);
+            let end = (ph.p_vaddr + ph.p_memsz) as usize / PAGE_SIZE * PAGE_SIZE;
+            (start, end)
+        })
+        .filter(|(start, end)| start < end)
+        .collect();
+    lock(&ranges)
+}
+
+/// PMP permissions of `addr` if a locked region covers it: the domain regions do not matter then.
+pub fn locked_permissions(addr: usize) -> Option<usize> {
+    LOCKED
+        .lock()
+        .iter()
+        .find(|r| addr >= r.base_addr && addr - r.base_addr < 1 << r.order)
+        .map(MemoryRegion::pmp_permissions)
+}
+
+/// Program the locked regions again, after a suspend which lost the PMP.
+pub fn reprogram() {
+    for (i, region) in LOCKED.lock().iter().enumerate() {
+        program(i, region);
+    }
+}
+
+/// Lock the ranges `[start, end)` read/execute, all of them or none.
+fn lock(ranges: &[(usize, usize)]) -> Result<(), LockError> {
+    let mut locked = LOCKED.lock();
+    let regions: Vec<_> = ranges
+        .iter()
+        .flat_map(|&(start, end)| napot_split(start, end - start))
+        .map(|(base_addr, order)| MemoryRegion {
+            base_addr,
+            order,
+            mmio: false,
+            permissions: MEMREGION_RX,
+        })
+        .collect();
+    if locked.len() + regions.len() > LOCKED_ENTRIES {
+        return Err(LockError::NoEntries {
+            needed: regions.len(),
+            left: LOCKED_ENTRIES - locked.len(),
+        });
+    }
+    for region in regions {
+        let index = locked.len();
+        let cfg = program(index, &region);
+        // The entry may already be locked by an earlier boot stage
+        if read_pmpcfg(index) != cfg {
+            return Err(LockError::Locked(index));
+        }
+        locked.push(region);
+    }
+    Ok(())
+}
+
+/// Program `region` in the PMP entry `index` and lock it, returns the cfg byte written.
+fn program(index: usize, region: &MemoryRegion) -> usize {
+    let (pmpaddr, pmpcfg) = pmp_entry(region);
+    write_pmpaddr(index, pmpaddr);
+    write_pmpcfg(index, pmpcfg | PMP_L);
+    pmpcfg | PMP_L
+}
@@ -0,0 +1,23 @@
+//! Boots the firmware test image (`make firmware-tests`), which runs the `#[test_case]` functions
+//! of the firmware in M-mode instead of booting the domains. The test passes once the summary of
+//! the run reports every case as passed.
+
+use test_support::{firmware_test_report, Scenario, FIRMWARE_TESTS, FW_TEST_MARKER};
+
+#[test]
+fn firmware_test_cases_pass() {
+    let output = Scenario::new("firmware test cases")
+        .firmware(FIRMWARE_TESTS)
+        .fail_when("a failing firmware test case", |l| {
+            l.contains(FW_TEST_MARKER) && l.contains("result=fail")
+        })
+        .expect_line("the firmware test summary", |l| {
+            l.contains(FW_TEST_MARKER) && l.contains("summary")
+        })
+        .check()
+        .unwrap();
+
+    let report = firmware_test_report(&output.stdout);
+    assert!(report.total.is_some_and(|total| total > 0));
+    assert!(report.success(), "{:?}", report);
+}
@@ -0,0 +1,32 @@
+//! Boots shadowfax with the reference host (`payload/cove-vmm`) in the untrusted domain. The host
+//! runs GET_TSM_INFO → CONVERT_PAGES → CREATE_TVM → ADD_MEMORY_REGION → ADD_MEASURED_PAGES →
+//! CREATE_VCPU → FINALIZE → RUN and reports each step with a `[SHADOWFAX-TEST]` marker. The test
+//! passes once the TVM guest (`guests/hellotvm.c`) prints its greeting.
+
+use std::time::Duration;
+
+use test_support::{Scenario, VMM};
+
+const TVM_GREETING: &str = "Hello from TVM (VS-mode)";
+
+// run_tvm_vcpu does not return on success: the greeting printed by the TVM proves it ran
+const LIFECYCLE_STEPS: [&str; 7] = [
+    "get_tsm_info",
+    "convert_pages",
+    "create_tvm",
+    "add_tvm_memory_region",
+    "add_tvm_measured_pages",
+    "create_tvm_vcpu",
+    "finalize_tvm",
+];
+
+#[test]
+fn tvm_lifecycle_runs_guest() {
+    Scenario::new("TVM lifecycle")
+        .needs_hypervisor()
+        .loader(VMM)
+        .expect_steps(&LIFECYCLE_STEPS)
+        .expect(TVM_GREETING)
+        .timeout(Duration::from_secs(120))
+        .check();
+}
@@ -0,0 +1,110 @@
+//! Report of the firmware test image (`make firmware-tests`), from its `[SHADOWFAX-FWTEST]`
+//! records (see `shadowfax/src/test_runner.rs`): the cases which ran and the summary printed at
+//! the end of the run.
+
+use crate::{records, FW_TEST_MARKER};
+
+/// A test case which ran, `location` is where a failing one panicked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FirmwareTestCase {
+    pub name: String,
+    pub passed: bool,
+    pub location: Option<String>,
+}
+
+/// Cases of the run and its summary, `None` until the run ends.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FirmwareTestReport {
+    pub cases: Vec<FirmwareTestCase>,
+    pub total: Option<usize>,
+    pub passed: Option<usize>,
+    pub failed: Option<usize>,
+}
+
+impl FirmwareTestReport {
+    /// The run ended, every case ran and passed.
+    pub fn success(&self) -> bool {
+        self.failed == Some(0)
+            && self.passed == self.total
+            && self.cases.len() == self.passed.unwrap()
+    }
+
+    /// Cases which did not pass.
+    pub fn failures(&self) -> impl Iterator<Item = &FirmwareTestCase> {
+        self.cases.iter().filter(|case| !case.passed)
+    }
+}
+
+/// Report of the firmware test records in `lines`.
+pub fn firmware_test_report(lines: &[String]) -> FirmwareTestReport {
+    let mut report = FirmwareTestReport::default();
+    for (record, fields) in records(lines, FW_TEST_MARKER) {
+        let field = |key: &str| {
+            fields
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.clone())
+        };
+        let number = |key: &str| field(key).and_then(|v| v.parse().ok());
+        match record.as_str() {
+            "case" => {
+                let Some(name) = field("name") else {
+                    continue;
+                };
+                report.cases.push(FirmwareTestCase {
+                    name,
+                    passed: field("result").as_deref() == Some("pass"),
+                    location: field("location"),
+                });
+            }
+            "summary" => {
+                report.total = number("total");
+                report.passed = number("passed");
+                report.failed = number("failed");
+            }
+            _ => {}
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn passing_run() {
+        let lines = lines(&[
+            "[SHADOWFAX-FWTEST] begin total=2",
+            "[SHADOWFAX-FWTEST] case name=shadowfax::cove::tests::pmp result=pass",
+            "noise from the console",
+            "[SHADOWFAX-FWTEST] case name=shadowfax::fdt::tests::harts result=pass",
+            "[SHADOWFAX-FWTEST] summary total=2 passed=2 failed=0",
+        ]);
+        let report = firmware_test_report(&lines);
+        assert_eq!(report.cases.len(), 2);
+        assert_eq!(report.cases[1].name, "shadowfax::fdt::tests::harts");
+        assert!(report.success());
+    }
+
+    #[test]
+    fn failing_and_unfinished_runs() {
+        let mut lines = lines(&[
+            "[SHADOWFAX-FWTEST] begin total=3",
+            "[SHADOWFAX-FWTEST] case name=shadowfax::cove::tests::pmp result=pass",
+            "[SHADOWFAX-FWTEST] case name=shadowfax::fdt::tests::harts result=fail location=src/fdt.rs:570",
+        ]);
+        assert!(!firmware_test_report(&lines).success());
+
+        lines.push("[SHADOWFAX-FWTEST] summary total=3 passed=1 failed=1".to_string());
+        let report = firmware_test_report(&lines);
+        assert!(!report.success());
+        let failures: Vec<_> = report.failures().collect();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].location.as_deref(), Some("src/fdt.rs:570"));
+    }
+}
@@ -0,0 +1,80 @@
+//! Support of the functional tests: a test describes a `Scenario` (machine, firmware, loaders,
+//! the markers expected in order, the lines that fail it, timeout, exit code) and runs it. The
+//! scenario starts the machine selected with `PLATFORM`, follows its output and stops it, and a
+//! failure comes back as a `Failure` naming what went wrong together with the machine output.
+//!
+//! Paths are relative to the package of the test (the working directory of `cargo test`), e.g.
+//! `test/functional`.
+
+use std::path::PathBuf;
+
+mod firmware_tests;
+mod machine;
+mod scenario;
+mod trace;
+
+pub use firmware_tests::{firmware_test_report, FirmwareTestCase, FirmwareTestReport};
+pub use machine::Machine;
+pub use scenario::{Failure, FailureKind, Output, Scenario};
+pub use trace::{trace_entries, TraceEntry, TraceEvent};
+
+/// Prefix of the structured markers printed by test payloads.
+pub const TEST_MARKER: &str = "[SHADOWFAX-TEST]";
+
+/// Prefix of the crash dump printed by the firmware when it panics (see `shadowfax/src/crash.rs`).
+pub const CRASH_MARKER: &str = "[SHADOWFAX-CRASH]";
+
+/// Prefix of the records of the firmware test image (see `shadowfax/src/test_runner.rs`).
+pub const FW_TEST_MARKER: &str = "[SHADOWFAX-FWTEST]";
+
+pub const FIRMWARE: &str = "../../target/riscv64imac-unknown-none-elf/debug/shadowfax";
+/// Firmware running its test cases, built with `make firmware-tests`
+pub const FIRMWARE_TESTS: &str = "../../bin/shadowfax-tests";
+pub const DTB: &str = "../../bin/device-tree.dtb";
+pub const DICE: &str = "../../bin/shadowfax.dice.bin";
+/// DICE input wrapped in an ELF loaded at 0x88000000, spike only loads ELF payloads
+pub const DICE_ELF: &str = "../../bin/shadowfax.dice.elf";
+/// Reference host, see `payload/cove-vmm`
+pub const VMM: &str = "../../target/riscv64imac-unknown-none-elf/debug/cove-vmm";
+
+/// Returns `path` after checking it has been built.
+pub fn artifact(path: &str) -> PathBuf {
+    let path = PathBuf::from(path);
+    assert!(
+        path.exists(),
+        "{} does not exist. Build it first.",
+        path.display()
+    );
+    path
+}
+
+/// Records of the crash dump in `lines`, as the record name and its `key=value` pairs.
+pub fn crash_records(lines: &[String]) -> Vec<(String, Vec<(String, String)>)> {
+    records(lines, CRASH_MARKER)
+}
+
+/// Records of the lines marked with `marker`, as the record name and its `key=value` pairs.
+fn records(lines: &[String], marker: &str) -> Vec<(String, Vec<(String, String)>)> {
+    lines
+        .iter()
+        .filter_map(|l| l.split_once(marker))
+        .filter_map(|(_, record)| {
+            let mut fields = record.split_whitespace();
+            let name = fields.next()?.to_string();
+            let pairs = fields
+                .filter_map(|f| f.split_once('='))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            Some((name, pairs))
+        })
+        .collect()
+}
+
+/// Location of the firmware panic in the crash dump of `lines`, if any.
+pub fn crash_location(lines: &[String]) -> Option<String> {
+    crash_records(lines)
+        .into_iter()
+        .find(|(record, _)| record == "panic")
+        .and_then(|(_, fields)| fields.into_iter().find(|(key, _)| key == "location"))
+        .map(|(_, location)| location)
+}
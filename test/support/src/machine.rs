@@ -0,0 +1,100 @@
+//! Machines the functional tests run on, selected with `PLATFORM` (QEMU virt by default, QEMU
+//! sifive_u or spike).
+
+use std::path::Path;
+use std::process::Command;
+
+use crate::{DICE, DICE_ELF};
+
+/// Machine the firmware runs on, it must match the `PLATFORM` the firmware was built for.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Machine {
+    /// `qemu-system-riscv64 -M virt` (platform `generic`)
+    QemuVirt,
+    /// `qemu-system-riscv64 -M sifive_u` (platform `sifive-u`)
+    QemuSifiveU,
+    /// spike with the HTIF console (platform `spike`)
+    Spike,
+}
+
+impl Machine {
+    /// Machine of the platform selected with `PLATFORM`, as in the Makefile.
+    pub fn from_env() -> Self {
+        match std::env::var("PLATFORM").as_deref() {
+            Err(_) | Ok("generic") => Machine::QemuVirt,
+            Ok("sifive-u") => Machine::QemuSifiveU,
+            Ok("spike") => Machine::Spike,
+            Ok(other) => panic!("no functional test machine for platform {}", other),
+        }
+    }
+
+    /// DICE input in the format the machine can load.
+    pub fn dice(&self) -> &'static str {
+        match self {
+            Machine::Spike => DICE_ELF,
+            Machine::QemuVirt | Machine::QemuSifiveU => DICE,
+        }
+    }
+
+    /// Whether the harts implement the H extension, needed to run TVMs.
+    pub fn has_hypervisor(&self) -> bool {
+        match self {
+            Machine::QemuVirt | Machine::Spike => true,
+            // the U54 harts of the FU540 have no H extension
+            Machine::QemuSifiveU => false,
+        }
+    }
+
+    pub(crate) fn command(
+        &self,
+        firmware: &Path,
+        dtb: &Path,
+        dice: &Path,
+        payloads: &[&Path],
+    ) -> Command {
+        match self {
+            Machine::QemuVirt | Machine::QemuSifiveU => {
+                let (machine, memory, smp) = match self {
+                    Machine::QemuVirt => ("virt", "512M", "1"),
+                    // hart 0 is the E51 monitor core, hart 1 runs the firmware
+                    _ => ("sifive_u", "1G", "2"),
+                };
+                let mut command = Command::new("qemu-system-riscv64");
+                command.args([
+                    "-M",
+                    machine,
+                    "-m",
+                    memory,
+                    "-nographic",
+                    "-smp",
+                    smp,
+                    "-bios",
+                    firmware.to_str().unwrap(),
+                    "-device",
+                    format!("loader,file={},addr=0x88000000", dice.display()).as_str(),
+                    "-dtb",
+                    dtb.to_str().unwrap(),
+                ]);
+                for payload in payloads {
+                    command.args(["-device", &format!("loader,file={}", payload.display())]);
+                }
+                command
+            }
+            Machine::Spike => {
+                let mut command = Command::new("spike");
+                command.args([
+                    "-p1",
+                    "-m0x80000000:0x20000000",
+                    "--isa=rv64imafdch_zicsr_zifencei",
+                    &format!("--dtb={}", dtb.display()),
+                    &format!("--payload={}", dice.display()),
+                ]);
+                for payload in payloads {
+                    command.arg(format!("--payload={}", payload.display()));
+                }
+                command.arg(firmware);
+                command
+            }
+        }
+    }
+}
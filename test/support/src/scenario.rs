@@ -0,0 +1,462 @@
+//! Declarative functional tests: what to boot and which lines must (or must not) show up.
+
+use std::fmt;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::{Child, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::{artifact, crash_location, Machine, CRASH_MARKER, DTB, FIRMWARE, TEST_MARKER};
+
+/// A line the scenario waits for, or fails on
+struct Matcher {
+    description: String,
+    matches: Box<dyn Fn(&str) -> bool + Send + Sync>,
+}
+
+impl Matcher {
+    fn contains(pattern: &str) -> Self {
+        let owned = pattern.to_string();
+        Self {
+            description: format!("'{}'", pattern),
+            matches: Box::new(move |line| line.contains(&owned)),
+        }
+    }
+}
+
+/// A boot of the firmware on the machine of `PLATFORM`. By default the scenario fails on the
+/// `[SHADOWFAX-TEST] FAIL` markers and on a firmware crash, and stops the machine once the last
+/// expected marker is printed.
+pub struct Scenario {
+    name: String,
+    machine: Machine,
+    firmware: String,
+    dtb: String,
+    dice: Option<String>,
+    loaders: Vec<String>,
+    markers: Vec<Matcher>,
+    fail_on: Vec<Matcher>,
+    timeout: Duration,
+    exit_code: Option<i32>,
+    needs_hypervisor: bool,
+}
+
+impl Scenario {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            machine: Machine::from_env(),
+            firmware: FIRMWARE.to_string(),
+            dtb: DTB.to_string(),
+            dice: None,
+            loaders: Vec::new(),
+            markers: Vec::new(),
+            fail_on: vec![
+                Matcher::contains(&format!("{} FAIL", TEST_MARKER)),
+                Matcher::contains(CRASH_MARKER),
+            ],
+            timeout: Duration::from_secs(60),
+            exit_code: None,
+            needs_hypervisor: false,
+        }
+    }
+
+    pub fn firmware(mut self, path: &str) -> Self {
+        self.firmware = path.to_string();
+        self
+    }
+
+    pub fn dtb(mut self, path: &str) -> Self {
+        self.dtb = path.to_string();
+        self
+    }
+
+    /// DICE input, by default the one in the format of the machine (see `Machine::dice`)
+    pub fn dice(mut self, path: &str) -> Self {
+        self.dice = Some(path.to_string());
+        self
+    }
+
+    /// ELF loaded next to the firmware (a payload of the untrusted domain, a guest)
+    pub fn loader(mut self, path: &str) -> Self {
+        self.loaders.push(path.to_string());
+        self
+    }
+
+    /// Wait for a line containing `marker`, after the lines of the markers expected before it.
+    pub fn expect(mut self, marker: &str) -> Self {
+        self.markers.push(Matcher::contains(marker));
+        self
+    }
+
+    /// Wait for a line satisfying `matches`, `description` names it in the failures.
+    pub fn expect_line(
+        mut self,
+        description: &str,
+        matches: impl Fn(&str) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.markers.push(Matcher {
+            description: description.to_string(),
+            matches: Box::new(matches),
+        });
+        self
+    }
+
+    /// Wait for the `[SHADOWFAX-TEST] step <name> PASS` marker of each step, in order.
+    pub fn expect_steps(mut self, steps: &[&str]) -> Self {
+        for step in steps {
+            let marker = format!("{} step {} PASS", TEST_MARKER, step);
+            self.markers.push(Matcher::contains(&marker));
+        }
+        self
+    }
+
+    /// Fail as soon as a line contains `pattern`.
+    pub fn fail_on(mut self, pattern: &str) -> Self {
+        self.fail_on.push(Matcher::contains(pattern));
+        self
+    }
+
+    /// Fail as soon as a line satisfies `matches`.
+    pub fn fail_when(
+        mut self,
+        description: &str,
+        matches: impl Fn(&str) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.fail_on.push(Matcher {
+            description: description.to_string(),
+            matches: Box::new(matches),
+        });
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Wait for the machine to exit with `code` once the markers are printed, instead of stopping
+    /// it.
+    pub fn exit_code(mut self, code: i32) -> Self {
+        self.exit_code = Some(code);
+        self
+    }
+
+    /// The scenario runs TVMs: it is skipped on machines without the H extension.
+    pub fn needs_hypervisor(mut self) -> Self {
+        self.needs_hypervisor = true;
+        self
+    }
+
+    /// Boot the machine and follow its output. `None` if the machine cannot run the scenario.
+    pub fn run(&self) -> Result<Option<Output>, Failure> {
+        if self.needs_hypervisor && !self.machine.has_hypervisor() {
+            eprintln!(
+                "skipping {}: {:?} has no H extension",
+                self.name, self.machine
+            );
+            return Ok(None);
+        }
+
+        let firmware = artifact(&self.firmware);
+        let dtb = artifact(&self.dtb);
+        let dice = artifact(self.dice.as_deref().unwrap_or(self.machine.dice()));
+        let loaders: Vec<_> = self.loaders.iter().map(|path| artifact(path)).collect();
+        let loaders: Vec<&Path> = loaders.iter().map(|path| path.as_path()).collect();
+
+        let child = self
+            .machine
+            .command(&firmware, &dtb, &dice, &loaders)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| self.failure(FailureKind::Spawn(e.to_string()), Output::default()))?;
+        let mut machine = Running::new(child);
+
+        let kind = self.follow(&mut machine);
+        let output = machine.stop();
+        match kind {
+            None => Ok(Some(output)),
+            Some(FailureKind::Failed(line)) if line.contains(CRASH_MARKER) => {
+                let location = crash_location(&output.stdout);
+                Err(self.failure(FailureKind::Crashed(location), output))
+            }
+            Some(kind) => Err(self.failure(kind, output)),
+        }
+    }
+
+    /// Run the scenario and panic with the failure, if any.
+    pub fn check(&self) -> Option<Output> {
+        self.run().unwrap_or_else(|failure| panic!("{}", failure))
+    }
+
+    fn follow(&self, machine: &mut Running) -> Option<FailureKind> {
+        let deadline = Instant::now() + self.timeout;
+        let mut progress = Progress::new(&self.markers, &self.fail_on);
+
+        loop {
+            // The output is complete once the machine exited and the readers are done
+            let status = machine.try_wait();
+            if status.is_some() {
+                machine.drain();
+            }
+            if let Err(line) = progress.scan(machine) {
+                return Some(FailureKind::Failed(line));
+            }
+
+            let pending = progress.pending().map(|m| m.description.clone());
+            match (status, pending, self.exit_code) {
+                (None, None, None) => return None,
+                (Some(status), Some(pending), _) => {
+                    return Some(FailureKind::Exited {
+                        code: status.code(),
+                        pending,
+                    })
+                }
+                (Some(status), None, Some(expected)) if status.code() != Some(expected) => {
+                    return Some(FailureKind::ExitCode {
+                        expected,
+                        code: status.code(),
+                    })
+                }
+                (Some(_), None, _) => return None,
+                (None, pending, _) if Instant::now() >= deadline => {
+                    return Some(FailureKind::Timeout {
+                        pending: pending.unwrap_or_else(|| "the machine to exit".to_string()),
+                        timeout: self.timeout,
+                    })
+                }
+                (None, _, _) => thread::sleep(Duration::from_millis(100)),
+            }
+        }
+    }
+
+    fn failure(&self, kind: FailureKind, output: Output) -> Failure {
+        Failure {
+            scenario: self.name.clone(),
+            kind,
+            output,
+        }
+    }
+}
+
+/// Markers found so far, from the lines not scanned yet
+struct Progress<'a> {
+    markers: &'a [Matcher],
+    fail_on: &'a [Matcher],
+    next: usize,
+    /// Lines scanned of stdout and stderr
+    scanned: [usize; 2],
+}
+
+impl<'a> Progress<'a> {
+    fn new(markers: &'a [Matcher], fail_on: &'a [Matcher]) -> Self {
+        Self {
+            markers,
+            fail_on,
+            next: 0,
+            scanned: [0; 2],
+        }
+    }
+
+    fn pending(&self) -> Option<&'a Matcher> {
+        self.markers.get(self.next)
+    }
+
+    /// Scan the new lines, returns the first failing one.
+    fn scan(&mut self, machine: &Running) -> Result<(), String> {
+        for (stream, lines) in [&machine.stdout, &machine.stderr].into_iter().enumerate() {
+            let lines = lines.lock().unwrap();
+            for line in &lines[self.scanned[stream]..] {
+                self.feed(line)?;
+            }
+            self.scanned[stream] = lines.len();
+        }
+        Ok(())
+    }
+
+    fn feed(&mut self, line: &str) -> Result<(), String> {
+        if self.fail_on.iter().any(|m| (m.matches)(line)) {
+            return Err(line.to_string());
+        }
+        if self.pending().is_some_and(|m| (m.matches)(line)) {
+            self.next += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Machine started by a scenario, with the threads collecting its output
+struct Running {
+    child: Child,
+    stdout: Arc<Mutex<Vec<String>>>,
+    stderr: Arc<Mutex<Vec<String>>>,
+    readers: Vec<JoinHandle<()>>,
+}
+
+impl Running {
+    fn new(mut child: Child) -> Self {
+        let stdout = Arc::new(Mutex::new(Vec::new()));
+        let stderr = Arc::new(Mutex::new(Vec::new()));
+        let mut readers = Vec::new();
+        if let Some(out) = child.stdout.take() {
+            readers.push(stream(out, Arc::clone(&stdout), "stdout"));
+        }
+        if let Some(err) = child.stderr.take() {
+            readers.push(stream(err, Arc::clone(&stderr), "stderr"));
+        }
+        Self {
+            child,
+            stdout,
+            stderr,
+            readers,
+        }
+    }
+
+    fn try_wait(&mut self) -> Option<ExitStatus> {
+        self.child.try_wait().ok().flatten()
+    }
+
+    /// Wait for the readers to reach the end of the output.
+    fn drain(&mut self) {
+        for reader in self.readers.drain(..) {
+            let _ = reader.join();
+        }
+    }
+
+    fn stop(mut self) -> Output {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        self.drain();
+        Output {
+            stdout: self.stdout.lock().unwrap().clone(),
+            stderr: self.stderr.lock().unwrap().clone(),
+        }
+    }
+}
+
+fn stream(
+    pipe: impl Read + Send + 'static,
+    lines: Arc<Mutex<Vec<String>>>,
+    name: &'static str,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+            println!("[{}] {}", name, line);
+            lines.lock().unwrap().push(line);
+        }
+    })
+}
+
+/// Output of the machine, up to its stop.
+#[derive(Clone, Debug, Default)]
+pub struct Output {
+    pub stdout: Vec<String>,
+    pub stderr: Vec<String>,
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "--- STDOUT ---\n{}", self.stdout.join("\n"))?;
+        writeln!(f, "--- STDERR ---\n{}", self.stderr.join("\n"))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum FailureKind {
+    /// The machine could not be started
+    Spawn(String),
+    /// A line matched a failure pattern
+    Failed(String),
+    /// The firmware crashed, at the location of the crash dump if it got printed
+    Crashed(Option<String>),
+    /// The machine exited before printing the pending marker
+    Exited {
+        code: Option<i32>,
+        pending: String,
+    },
+    /// The machine exited with an unexpected code
+    ExitCode {
+        expected: i32,
+        code: Option<i32>,
+    },
+    Timeout {
+        pending: String,
+        timeout: Duration,
+    },
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailureKind::Spawn(e) => write!(f, "failed to start the machine: {}", e),
+            FailureKind::Failed(line) => write!(f, "failure reported: {}", line),
+            FailureKind::Crashed(location) => write!(
+                f,
+                "firmware crashed at {}",
+                location.as_deref().unwrap_or("unknown")
+            ),
+            FailureKind::Exited { code, pending } => {
+                write!(f, "machine exited ({:?}) before {}", code, pending)
+            }
+            FailureKind::ExitCode { expected, code } => {
+                write!(f, "machine exited with {:?}, expected {}", code, expected)
+            }
+            FailureKind::Timeout { pending, timeout } => {
+                write!(f, "did not see {} within {}s", pending, timeout.as_secs())
+            }
+        }
+    }
+}
+
+/// Why a scenario failed, with the output of the machine.
+#[derive(Debug)]
+pub struct Failure {
+    pub scenario: String,
+    pub kind: FailureKind,
+    pub output: Output,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}\n{}", self.scenario, self.kind, self.output)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matchers(patterns: &[&str]) -> Vec<Matcher> {
+        patterns.iter().map(|p| Matcher::contains(p)).collect()
+    }
+
+    #[test]
+    fn markers_are_matched_in_order() {
+        let markers = matchers(&["first", "second"]);
+        let mut progress = Progress::new(&markers, &[]);
+
+        progress.feed("second too early").unwrap();
+        assert_eq!(progress.pending().unwrap().description, "'first'");
+        progress.feed("first").unwrap();
+        progress.feed("second").unwrap();
+        assert!(progress.pending().is_none());
+    }
+
+    #[test]
+    fn failure_lines_stop_the_scenario() {
+        let markers = matchers(&["done"]);
+        let fail_on = matchers(&["[SHADOWFAX-TEST] FAIL"]);
+        let mut progress = Progress::new(&markers, &fail_on);
+
+        assert_eq!(
+            progress.feed("[SHADOWFAX-TEST] FAIL: create_tvm failed (-3)"),
+            Err("[SHADOWFAX-TEST] FAIL: create_tvm failed (-3)".to_string())
+        );
+    }
+}
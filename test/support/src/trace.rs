@@ -0,0 +1,140 @@
+//! Decoder of the trace of the domain switches, printed by the firmware in its crash dump as
+//! `trace` records (`trace` feature, see `shadowfax/src/trace.rs`). The rings of the harts are
+//! merged into one timeline, printed one call per line as ftrace does.
+
+use std::fmt;
+
+use crate::crash_records;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceEvent {
+    TeeCall,
+    TeeRet,
+    SupdCall,
+}
+
+/// TEECALL, TEERET or SUPD call handled by the firmware.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub hart: usize,
+    /// Position of the entry in the trace of its hart
+    pub seq: u64,
+    /// mtime
+    pub time: u64,
+    pub event: TraceEvent,
+    pub src: usize,
+    /// Callee of a TEECALL, caller a TEERET returns to, the caller itself for a SUPD call
+    pub dst: usize,
+    pub eid: u64,
+    pub fid: u64,
+    /// SBI error of the call, the result of the callee for a TEERET
+    pub error: i64,
+}
+
+/// Trace of the crash dump in `lines`, ordered by time. The entries of a hart keep their order.
+pub fn trace_entries(lines: &[String]) -> Vec<TraceEntry> {
+    let mut entries: Vec<_> = crash_records(lines)
+        .into_iter()
+        .filter(|(record, _)| record == "trace")
+        .filter_map(|(_, fields)| parse(&fields))
+        .collect();
+    entries.sort_by_key(|entry| (entry.time, entry.hart, entry.seq));
+    entries
+}
+
+fn parse(fields: &[(String, String)]) -> Option<TraceEntry> {
+    let field = |key: &str| {
+        fields
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    };
+    let number = |key: &str| {
+        let value = field(key)?;
+        match value.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => value.parse().ok(),
+        }
+    };
+
+    let event = match field("event")? {
+        "teecall" => TraceEvent::TeeCall,
+        "teeret" => TraceEvent::TeeRet,
+        "supd" => TraceEvent::SupdCall,
+        _ => return None,
+    };
+    Some(TraceEntry {
+        hart: number("hart")? as usize,
+        seq: number("seq")?,
+        time: number("time")?,
+        event,
+        src: number("src")? as usize,
+        dst: number("dst")? as usize,
+        eid: number("eid")?,
+        fid: number("fid")?,
+        error: field("error")?.parse().ok()?,
+    })
+}
+
+/// `hart-0 [41] 2772737: teecall 2 -> 1 eid=0x434f5648 fid=0x0 error=0`
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let event = match self.event {
+            TraceEvent::TeeCall => "teecall",
+            TraceEvent::TeeRet => "teeret",
+            TraceEvent::SupdCall => "supd",
+        };
+        write!(
+            f,
+            "hart-{} [{}] {}: {} {} -> {} eid={:#x} fid={:#x} error={}",
+            self.hart,
+            self.seq,
+            self.time,
+            event,
+            self.src,
+            self.dst,
+            self.eid,
+            self.fid,
+            self.error
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn harts_are_merged_by_time() {
+        let lines = lines(&[
+            "[SHADOWFAX-CRASH] begin",
+            "[SHADOWFAX-CRASH] trace hart=0 seq=7 time=0x20 event=teecall src=2 dst=1 eid=0x434f5648 fid=0x0 error=0",
+            "[SHADOWFAX-CRASH] trace hart=0 seq=8 time=0x40 event=teeret src=1 dst=2 eid=0x434f5648 fid=0x0 error=-3",
+            "[SHADOWFAX-CRASH] trace hart=1 seq=0 time=0x30 event=supd src=1 dst=1 eid=0x53555044 fid=0x8 error=0",
+            "[SHADOWFAX-CRASH] pmp index=0 cfg=0x1f addr=0x22bfffff",
+            "[SHADOWFAX-CRASH] end",
+        ]);
+        let entries = trace_entries(&lines);
+        let order: Vec<_> = entries.iter().map(|e| (e.hart, e.seq)).collect();
+        assert_eq!(order, [(0, 7), (1, 0), (0, 8)]);
+        assert_eq!(entries[2].event, TraceEvent::TeeRet);
+        assert_eq!(entries[2].error, -3);
+        assert_eq!(
+            entries[0].to_string(),
+            "hart-0 [7] 32: teecall 2 -> 1 eid=0x434f5648 fid=0x0 error=0"
+        );
+    }
+
+    #[test]
+    fn malformed_records_are_skipped() {
+        let lines = lines(&[
+            "[SHADOWFAX-CRASH] trace hart=0 seq=1 time=0x20 event=bogus src=2 dst=1 eid=0x0 fid=0x0 error=0",
+            "[SHADOWFAX-CRASH] trace hart=0 seq=2 time=0x20 event=teecall src=2",
+        ]);
+        assert!(trace_entries(&lines).is_empty());
+    }
+}
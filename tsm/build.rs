@@ -1,5 +1,5 @@
 // build.rs
-use std::path::PathBuf;
+use std::{path::PathBuf, process::Command};
 
 fn main() {
     let linkerscript_path = PathBuf::from("memory.x").canonicalize().unwrap();
@@ -9,6 +9,28 @@ fn main() {
     println!("cargo:rustc-link-arg=-static");
     println!("cargo:rustc-link-arg=-nostdlib");
 
+    // Commit the TSM is built from, reported by GET_TSM_INFO. Empty outside of a git checkout.
+    let build_id = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=TSM_BUILD_ID={}", build_id);
+
+    // Measured pages copied and measured per TEECALL, the worst-case interrupt blackout of the
+    // untrusted domain while a TVM is built (see `tsm_core::covh::measured_chunk`). Read by
+    // `state::MEASURE_CHUNK_PAGES`, checked here.
+    if let Ok(pages) = std::env::var("TSM_MEASURE_CHUNK_PAGES") {
+        if !pages.parse::<usize>().is_ok_and(|pages| pages > 0) {
+            panic!("TSM_MEASURE_CHUNK_PAGES: invalid page count '{pages}'");
+        }
+    }
+
     println!("cargo:rerun-if-changed=memory.x");
     println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=TSM_MEASURE_CHUNK_PAGES");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
 }
@@ -0,0 +1,132 @@
+//! Feed random COVH calls (fid, a0..a5) and random host memory through the COVH decoding and the
+//! `TvmLifecycle` of the TSM. Any panic (assert, overflow, out of bounds access) is a finding: on
+//! the real TSM it hangs the hart.
+//!
+//! Run with `cargo fuzz run covh_dispatch` from `tsm/core`.
+#![no_main]
+
+use arbitrary::Arbitrary;
+use common::sbi::SBI_COVH_MAX_FID;
+use libfuzzer_sys::fuzz_target;
+use tsm_core::{CoveError, CoveResult, CovhCall, PhysMemory, TvmLifecycle, PAGE_SIZE};
+
+/// Host buffer holding parameter blocks (TVM params, measured page lists)
+const SCRATCH_ADDR: usize = 0x8A20_0000;
+/// Where the host usually converts pages
+const POOL_ADDR: usize = 0x8A80_0000;
+/// Fids worth trying: every COVH call, and one past them
+const FIDS: usize = SBI_COVH_MAX_FID + 2;
+
+/// Arguments are biased towards addresses the state machine cares about, so the fuzzer does not
+/// have to guess them.
+#[derive(Arbitrary, Debug)]
+enum Arg {
+    Raw(usize),
+    Small(u8),
+    Scratch(u16),
+    Pool(u16),
+    PoolPage(u8),
+}
+
+impl Arg {
+    fn value(&self) -> usize {
+        match *self {
+            Arg::Raw(v) => v,
+            Arg::Small(v) => v as usize,
+            Arg::Scratch(offset) => SCRATCH_ADDR + offset as usize,
+            Arg::Pool(offset) => POOL_ADDR + offset as usize,
+            Arg::PoolPage(page) => POOL_ADDR + page as usize * PAGE_SIZE,
+        }
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+struct Call {
+    fid: u16,
+    args: [Arg; 6],
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    scratch: Vec<u8>,
+    calls: Vec<Call>,
+}
+
+/// Host memory: the scratch buffer is readable, everything else faults.
+struct FuzzMemory {
+    scratch: Vec<u8>,
+}
+
+impl PhysMemory for FuzzMemory {
+    fn zero(&mut self, addr: usize, len: usize) {
+        let start = addr.saturating_sub(SCRATCH_ADDR).min(self.scratch.len());
+        let end = addr
+            .saturating_add(len)
+            .saturating_sub(SCRATCH_ADDR)
+            .min(self.scratch.len());
+        self.scratch[start..end.max(start)].fill(0);
+    }
+
+    fn read(&self, addr: usize, buf: &mut [u8]) -> CoveResult<()> {
+        let bytes = addr
+            .checked_sub(SCRATCH_ADDR)
+            .and_then(|start| self.scratch.get(start..start.checked_add(buf.len())?))
+            .ok_or(CoveError::InvalidAddress("read outside of memory"))?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// The lifecycle calls, as the TSM hands them to its `TvmLifecycle`.
+fn dispatch(
+    lifecycle: &mut TvmLifecycle<()>,
+    call: CovhCall,
+    mem: &mut FuzzMemory,
+) -> CoveResult<()> {
+    match call {
+        CovhCall::ConvertPages {
+            base_addr,
+            num_pages,
+        } => lifecycle.convert_pages(base_addr, num_pages),
+        CovhCall::ReclaimPages {
+            base_addr,
+            num_pages,
+        } => lifecycle.reclaim_pages(base_addr, num_pages, mem),
+        CovhCall::CreateTvm(params) => lifecycle.create(&params, (), mem).map(drop),
+        CovhCall::AddTvmMemoryRegion { tvm_id, gpa, len } => {
+            lifecycle.add_memory_region(tvm_id, gpa, len)
+        }
+        CovhCall::AddTvmMeasuredPages { tvm_id, pages } => {
+            lifecycle.claim_measured_pages(tvm_id, &pages).map(drop)
+        }
+        CovhCall::AddTvmZeroPages {
+            tvm_id,
+            base_addr,
+            num_pages,
+            gpa,
+        } => lifecycle
+            .claim_zero_pages(tvm_id, base_addr, num_pages, gpa, mem)
+            .map(drop),
+        CovhCall::FinalizeTvm { tvm_id, .. } => lifecycle.finalize(tvm_id).map(drop),
+        CovhCall::DestroyTvm { .. } => {
+            lifecycle.destroy(mem);
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fuzz_target!(|input: Input| {
+    let mut mem = FuzzMemory {
+        scratch: input.scratch,
+    };
+    let mut lifecycle = TvmLifecycle::new();
+
+    for call in input.calls {
+        let fid = call.fid as usize % FIDS;
+        let args = call.args.each_ref().map(Arg::value);
+        if let Ok(call) = CovhCall::decode(fid, args, &mem) {
+            let _ = dispatch(&mut lifecycle, call, &mut mem);
+        }
+    }
+});
@@ -0,0 +1,113 @@
+//! Harts the vCPUs of a TVM run on. The host pins a vCPU to a physical hart with
+//! `sbi_covh_set_tvm_vcpu_affinity`, e.g. to keep a latency-critical vCPU on a hart of its own:
+//! a RUN_TVM_VCPU of the vCPU from any other hart is denied. The vCPU entered on each hart is
+//! tracked too, so that a vCPU never runs on two harts at once.
+
+use alloc::collections::BTreeMap;
+
+use crate::{CoveError, CoveResult};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VcpuAffinity {
+    /// Hart of each pinned vCPU, by vCPU id
+    pinned: BTreeMap<usize, usize>,
+    /// vCPU running on each hart, by hart id
+    running: BTreeMap<usize, usize>,
+}
+
+impl VcpuAffinity {
+    pub const fn new() -> Self {
+        Self {
+            pinned: BTreeMap::new(),
+            running: BTreeMap::new(),
+        }
+    }
+
+    /// Pin `vcpu_id` to `hart`, or let it run anywhere with `None`. A running vCPU cannot be
+    /// pinned to another hart than its own.
+    pub fn pin(&mut self, vcpu_id: usize, hart: Option<usize>) -> CoveResult<()> {
+        let Some(hart) = hart else {
+            self.pinned.remove(&vcpu_id);
+            return Ok(());
+        };
+        if self.hart_of(vcpu_id).is_some_and(|running| running != hart) {
+            return Err(CoveError::InvalidState("vcpu running on another hart"));
+        }
+        self.pinned.insert(vcpu_id, hart);
+        Ok(())
+    }
+
+    /// Hart `vcpu_id` is pinned to, if any.
+    pub fn pinned_hart(&self, vcpu_id: usize) -> Option<usize> {
+        self.pinned.get(&vcpu_id).copied()
+    }
+
+    /// Hart `vcpu_id` is running on, if any.
+    pub fn hart_of(&self, vcpu_id: usize) -> Option<usize> {
+        self.running
+            .iter()
+            .find_map(|(&hart, &vcpu)| (vcpu == vcpu_id).then_some(hart))
+    }
+
+    /// `vcpu_id` enters the guest on `hart`, which it must be pinned to, if pinned. The hart runs
+    /// a single vCPU: the one it ran before has exited, the TSM is only entered on a hart once its
+    /// vCPU gave it back to the host.
+    pub fn enter(&mut self, vcpu_id: usize, hart: usize) -> CoveResult<()> {
+        if self
+            .pinned_hart(vcpu_id)
+            .is_some_and(|pinned| pinned != hart)
+        {
+            return Err(CoveError::Denied("vcpu pinned to another hart"));
+        }
+        if self.hart_of(vcpu_id).is_some_and(|running| running != hart) {
+            return Err(CoveError::InvalidState("vcpu running on another hart"));
+        }
+        self.running.insert(hart, vcpu_id);
+        Ok(())
+    }
+
+    /// The vCPU of `hart` exited to the host.
+    pub fn exit(&mut self, hart: usize) {
+        self.running.remove(&hart);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pinned_vcpus_run_on_their_hart() {
+        let mut affinity = VcpuAffinity::new();
+        affinity.pin(0, Some(2)).unwrap();
+        assert_eq!(
+            affinity.enter(0, 1),
+            Err(CoveError::Denied("vcpu pinned to another hart"))
+        );
+        affinity.enter(0, 2).unwrap();
+        assert_eq!(affinity.hart_of(0), Some(2));
+
+        // Unpinned vCPUs run anywhere
+        affinity.enter(1, 1).unwrap();
+        affinity.exit(2);
+        affinity.pin(0, None).unwrap();
+        affinity.enter(0, 3).unwrap();
+    }
+
+    #[test]
+    fn vcpus_run_on_one_hart_at_a_time() {
+        let mut affinity = VcpuAffinity::new();
+        affinity.enter(0, 1).unwrap();
+        assert!(affinity.enter(0, 2).is_err());
+        assert!(affinity.pin(0, Some(2)).is_err());
+        // Re-entered on the same hart after an exit to the host
+        affinity.enter(0, 1).unwrap();
+
+        affinity.exit(1);
+        affinity.pin(0, Some(2)).unwrap();
+        affinity.enter(0, 2).unwrap();
+        // A hart runs the last vCPU entered on it
+        affinity.enter(1, 2).unwrap();
+        assert_eq!(affinity.hart_of(0), None);
+    }
+}
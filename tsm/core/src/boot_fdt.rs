@@ -0,0 +1,108 @@
+//! Device tree of a TVM, placed in its measured boot-info page: unlike the one the host passes,
+//! it only describes what the TSM enforces (the vCPUs, the timebase and the confidential memory
+//! regions), so the guest can boot from it without trusting the host.
+
+use alloc::{format, vec::Vec};
+
+use common::fdt::Writer;
+
+use crate::GuestMemoryMap;
+
+/// Device tree describing `num_vcpus` harts and the memory regions of `memory`. The timebase is
+/// left out if the host did not set it (`timebase_frequency` zero).
+pub fn tvm_device_tree(
+    num_vcpus: usize,
+    timebase_frequency: u64,
+    memory: &GuestMemoryMap,
+) -> Vec<u8> {
+    let mut fdt = Writer::new();
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_str("compatible", "shadowfax,tvm");
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    if timebase_frequency != 0 {
+        fdt.property_u32("timebase-frequency", timebase_frequency as u32);
+    }
+    for vcpu in 0..num_vcpus {
+        fdt.begin_node(&format!("cpu@{:x}", vcpu));
+        fdt.property_str("device_type", "cpu");
+        fdt.property_u32("reg", vcpu as u32);
+        fdt.property_str("compatible", "riscv");
+        fdt.property_str("status", "okay");
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    for region in memory.iter() {
+        fdt.begin_node(&format!("memory@{:x}", region.guest_gpa_base));
+        fdt.property_str("device_type", "memory");
+        fdt.property_u64s(
+            "reg",
+            &[
+                region.guest_gpa_base as u64,
+                (region.end() - region.guest_gpa_base) as u64,
+            ],
+        );
+        fdt.end_node();
+    }
+
+    fdt.begin_node("chosen");
+    fdt.end_node();
+    fdt.end_node();
+    fdt.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use common::fdt::{read_cells, Reader, Token};
+
+    use super::*;
+    use crate::PAGE_SIZE;
+
+    #[test]
+    fn describes_vcpus_and_memory() {
+        let mut memory = GuestMemoryMap::new();
+        memory.add_region(0x8000_0000, 4 * PAGE_SIZE).unwrap();
+        memory.add_region(0x9000_0000, PAGE_SIZE).unwrap();
+        let blob = tvm_device_tree(2, 10_000_000, &memory);
+
+        let tokens: Vec<_> = Reader::new(&blob).unwrap().map(|(_, t)| t).collect();
+        let cpus = tokens
+            .iter()
+            .filter(|t| matches!(t, Token::BeginNode(name) if name.starts_with("cpu@")))
+            .count();
+        assert_eq!(cpus, 2);
+
+        let regs: Vec<_> = tokens
+            .windows(3)
+            .filter(|w| matches!(w[0], Token::BeginNode(name) if name.starts_with("memory@")))
+            .filter_map(|w| match w[2] {
+                Token::Prop("reg", value) => Some((
+                    read_cells(value, 2).unwrap(),
+                    read_cells(&value[8..], 2).unwrap(),
+                )),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            regs,
+            [
+                (0x8000_0000, 4 * PAGE_SIZE as u64),
+                (0x9000_0000, PAGE_SIZE as u64)
+            ]
+        );
+        assert_eq!(tokens.last(), Some(&Token::EndNode));
+    }
+
+    #[test]
+    fn timebase_is_optional() {
+        let blob = tvm_device_tree(1, 0, &GuestMemoryMap::new());
+        assert!(!Reader::new(&blob)
+            .unwrap()
+            .any(|(_, t)| matches!(t, Token::Prop("timebase-frequency", _))));
+    }
+}
@@ -0,0 +1,1001 @@
+//! Decoding of the COVH calls. Every argument comes from the untrusted host: sizes, parameter
+//! blocks and page counts are validated here, so a malformed call turns into an error instead of
+//! a panic (which hangs the hart, since the TSM panic handler never returns).
+
+use alloc::vec::Vec;
+use common::{
+    measurement::{HashAlgorithm, MEASUREMENT_ALG_SHA384},
+    migration::MIGRATION_OFFER_SIZE,
+    sbi::{
+        covh_queueable, CovhQueueEntry, MeasuredPageDesc, TsmMemoryStats, TsmStats,
+        TvmGpaTranslation, TvmVcpuTime, COVH_QUEUE_MAX_ENTRIES, SBI_COVH_ADD_TVM_MEASURED_PAGES,
+        SBI_COVH_ADD_TVM_MEASURED_PAGES_BATCH, SBI_COVH_ADD_TVM_MEMORY_REGION,
+        SBI_COVH_ADD_TVM_SEALED_IMAGE, SBI_COVH_ADD_TVM_SHARED_PAGES, SBI_COVH_ADD_ZERO_PAGES,
+        SBI_COVH_CONVERT_PAGES, SBI_COVH_CREATE_TVM, SBI_COVH_CREATE_TVM_VCPU,
+        SBI_COVH_DESTROY_TVM, SBI_COVH_EXPORT_TVM, SBI_COVH_FINALIZE_TVM, SBI_COVH_GET_TSM_INFO,
+        SBI_COVH_GET_TSM_MEMORY_STATS, SBI_COVH_GET_TSM_STATS, SBI_COVH_GET_TVM_VCPU_TIME,
+        SBI_COVH_IMPORT_TVM, SBI_COVH_PREPARE_TVM_IMPORT, SBI_COVH_PROCESS_QUEUE,
+        SBI_COVH_RECLAIM_PAGES, SBI_COVH_RUN_TVM_VCPU, SBI_COVH_SET_TVM_BOOT_INFO,
+        SBI_COVH_SET_TVM_VCPU_AFFINITY, SBI_COVH_TSM_LOCAL_FENCE, SBI_COVH_TVM_TRANSLATE_GPA,
+        SBI_COVH_TVM_VCPU_SET_TIMER_FREQUENCY, TVM_POLICY_DEFAULT, TVM_POLICY_MASK,
+        TVM_POLICY_SW_PAGE_ENCRYPTION, TVM_RUN_FLAGS_MASK, TVM_VCPU_ANY_HART,
+    },
+    sealed_image::{SealedImageHeader, SEALED_IMAGE_HEADER_SIZE},
+};
+
+use crate::{
+    memory::{pages_to_bytes, range_end},
+    CoveError, CoveResult, PhysMemory, PAGE_DIRECTORY_SIZE, PAGE_SIZE,
+};
+
+/// Maximum number of measured pages added by a single call. Each page costs a descriptor and a
+/// digest on the TSM heap.
+pub const MAX_MEASURED_PAGES: usize = 4096;
+
+/// Measured pages copied and measured by a single TEECALL unless the TSM is built with another
+/// `TSM_MEASURE_CHUNK_PAGES`: 256 KiB.
+pub const DEFAULT_MEASURE_CHUNK_PAGES: usize = 64;
+
+/// TSM page type of the 4K pages, the only one supported for now.
+const TSM_PAGE_TYPE_4K: usize = 0;
+
+/// Parameters of `sbi_covh_create_tvm`. The params struct is formatted as:
+/// |  page_table_address  |  state_address  |  measurement_alg (optional)  |
+/// |  policy (optional)  |  working_set_address  |  working_set_pages  |
+/// Hosts passing the 16-byte layout get the default SHA-384 measurement, hosts passing no policy
+/// get `TVM_POLICY_DEFAULT`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CreateTvmParams {
+    pub page_table_addr: usize,
+    pub state_addr: usize,
+    pub measurement_alg: HashAlgorithm,
+    /// `TVM_POLICY_*` flags
+    pub policy: usize,
+    /// Working set address and pages of a software-encrypted TVM
+    pub working_set: Option<(usize, usize)>,
+}
+
+impl CreateTvmParams {
+    fn read(mem: &impl PhysMemory, addr: usize, len: usize) -> CoveResult<Self> {
+        if !matches!(len, 16 | 24 | 32 | 48) {
+            return Err(CoveError::InvalidParam("invalid TVM params size"));
+        }
+        let mut buf = [0u8; 48];
+        range_end(addr, len)?;
+        mem.read(addr, &mut buf[..len])?;
+        let param =
+            |idx: usize| usize::from_le_bytes(buf[idx * 8..(idx + 1) * 8].try_into().unwrap());
+
+        let measurement_alg = if len >= 24 {
+            param(2)
+        } else {
+            MEASUREMENT_ALG_SHA384
+        };
+        let measurement_alg = HashAlgorithm::from_id(measurement_alg)
+            .ok_or(CoveError::InvalidParam("invalid measurement algorithm"))?;
+        let policy = if len >= 32 {
+            param(3)
+        } else {
+            TVM_POLICY_DEFAULT
+        };
+        let working_set = (len == 48).then(|| (param(4), param(5)));
+
+        // Software page encryption needs a working set, and only makes sense with it
+        let working_set = match (policy & TVM_POLICY_SW_PAGE_ENCRYPTION, working_set) {
+            (0, None) => None,
+            (0, Some(_)) | (_, None) => return Err(CoveError::InvalidParam("invalid TVM policy")),
+            (_, working_set) => working_set,
+        };
+
+        let params = Self {
+            page_table_addr: param(0),
+            state_addr: param(1),
+            measurement_alg,
+            policy,
+            working_set,
+        };
+        params.check()?;
+        Ok(params)
+    }
+
+    /// Validate the addresses, also used for TVMs created by the TSM itself (e.g. on import).
+    pub fn check(&self) -> CoveResult<()> {
+        if !self.page_table_addr.is_multiple_of(PAGE_DIRECTORY_SIZE) {
+            return Err(CoveError::InvalidAddress(
+                "page table addr must be 16KB-aligned",
+            ));
+        }
+        if !self.state_addr.is_multiple_of(PAGE_SIZE) {
+            return Err(CoveError::InvalidAddress("state addr must be 4KB-aligned"));
+        }
+        let page_table_end = range_end(self.page_table_addr, PAGE_DIRECTORY_SIZE)?;
+        let state_end = range_end(self.state_addr, PAGE_SIZE)?;
+        if self.state_addr < page_table_end && self.page_table_addr < state_end {
+            return Err(CoveError::BadRange(
+                "state page overlaps the page directory",
+            ));
+        }
+        if self.policy & !TVM_POLICY_MASK != 0 {
+            return Err(CoveError::InvalidParam("unknown TVM policy flags"));
+        }
+        if let Some((addr, num_pages)) = self.working_set {
+            if num_pages == 0 || !addr.is_multiple_of(PAGE_SIZE) {
+                return Err(CoveError::InvalidParam("invalid working set"));
+            }
+            range_end(addr, pages_to_bytes(num_pages)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// A decoded COVH call.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CovhCall {
+    GetTsmInfo {
+        addr: usize,
+        len: usize,
+    },
+    ConvertPages {
+        base_addr: usize,
+        num_pages: usize,
+    },
+    ReclaimPages {
+        base_addr: usize,
+        num_pages: usize,
+    },
+    /// Fence the G-stage TLB of the calling hart
+    TsmLocalFence,
+    CreateTvm(CreateTvmParams),
+    FinalizeTvm {
+        tvm_id: usize,
+        entry_sepc: usize,
+        entry_arg: usize,
+        tvm_identity_addr: usize,
+    },
+    DestroyTvm {
+        tvm_id: usize,
+    },
+    AddTvmMemoryRegion {
+        tvm_id: usize,
+        gpa: usize,
+        len: usize,
+    },
+    /// Both the contiguous and the batch variant
+    AddTvmMeasuredPages {
+        tvm_id: usize,
+        pages: Vec<MeasuredPageDesc>,
+    },
+    /// The pages of the sealed image at `image_addr` are opened into `dest_addr`, see
+    /// `common::sealed_image`
+    AddTvmSealedImage {
+        tvm_id: usize,
+        image_addr: usize,
+        header: SealedImageHeader,
+        dest_addr: usize,
+    },
+    AddTvmZeroPages {
+        tvm_id: usize,
+        base_addr: usize,
+        num_pages: usize,
+        gpa: usize,
+    },
+    /// Host pages mapped in the TVM, neither measured nor confidential
+    AddTvmSharedPages {
+        tvm_id: usize,
+        base_addr: usize,
+        num_pages: usize,
+        gpa: usize,
+    },
+    CreateTvmVcpu {
+        tvm_id: usize,
+        vcpu_id: usize,
+        state_addr: usize,
+    },
+    RunTvmVcpu {
+        tvm_id: usize,
+        vcpu_id: usize,
+        /// `TVM_RUN_*` flags
+        flags: usize,
+    },
+    SetTvmVcpuAffinity {
+        tvm_id: usize,
+        vcpu_id: usize,
+        /// Hart the vCPU is pinned to, `None` to run it anywhere
+        hart: Option<usize>,
+    },
+    ExportTvm {
+        tvm_id: usize,
+        buf_addr: usize,
+        buf_len: usize,
+        /// Import offer of the destination TSM
+        offer_addr: usize,
+    },
+    /// The import offer is written at `offer_addr`
+    PrepareTvmImport {
+        offer_addr: usize,
+    },
+    ImportTvm {
+        blob_addr: usize,
+        blob_len: usize,
+        page_table_addr: usize,
+        state_addr: usize,
+        pool_addr: usize,
+        pool_pages: usize,
+    },
+    TvmVcpuSetTimerFrequency {
+        tvm_id: usize,
+        vcpu_id: usize,
+        frequency: usize,
+    },
+    SetTvmBootInfo {
+        tvm_id: usize,
+        dest_addr: usize,
+        gpa: usize,
+    },
+    /// The `TvmVcpuTime` of the vCPU is written at `addr`
+    GetTvmVcpuTime {
+        tvm_id: usize,
+        vcpu_id: usize,
+        addr: usize,
+    },
+    /// The `TvmGpaTranslation` of `gpa` is written at `addr`
+    TvmTranslateGpa {
+        tvm_id: usize,
+        gpa: usize,
+        addr: usize,
+    },
+    /// The `TsmStats` of the TSM are written at `addr`
+    GetTsmStats {
+        addr: usize,
+    },
+    /// The `TsmMemoryStats` of the TSM are written at `addr`
+    GetTsmMemoryStats {
+        addr: usize,
+    },
+    /// `count` `CovhQueueEntry` at `addr`, executed in order
+    ProcessQueue {
+        addr: usize,
+        count: usize,
+    },
+}
+
+impl CovhCall {
+    /// Decode the call `fid` with arguments `a0..a5`. Parameter blocks passed by address are read
+    /// through `mem`.
+    pub fn decode(fid: usize, args: [usize; 6], mem: &impl PhysMemory) -> CoveResult<Self> {
+        let [a0, a1, a2, a3, a4, a5] = args;
+
+        let call = match fid {
+            SBI_COVH_GET_TSM_INFO => Self::GetTsmInfo { addr: a0, len: a1 },
+            SBI_COVH_CONVERT_PAGES => Self::ConvertPages {
+                base_addr: a0,
+                num_pages: a1,
+            },
+            SBI_COVH_RECLAIM_PAGES => Self::ReclaimPages {
+                base_addr: a0,
+                num_pages: a1,
+            },
+            SBI_COVH_TSM_LOCAL_FENCE => Self::TsmLocalFence,
+            SBI_COVH_CREATE_TVM => Self::CreateTvm(CreateTvmParams::read(mem, a0, a1)?),
+            SBI_COVH_FINALIZE_TVM => Self::FinalizeTvm {
+                tvm_id: a0,
+                entry_sepc: a1,
+                entry_arg: a2,
+                tvm_identity_addr: a3,
+            },
+            SBI_COVH_DESTROY_TVM => Self::DestroyTvm { tvm_id: a0 },
+            SBI_COVH_ADD_TVM_MEMORY_REGION => Self::AddTvmMemoryRegion {
+                tvm_id: a0,
+                gpa: a1,
+                len: a2,
+            },
+            // a0: tvm_id, a1: source, a2: destination, a3: page type, a4: pages, a5: GPA
+            SBI_COVH_ADD_TVM_MEASURED_PAGES => {
+                if a3 != TSM_PAGE_TYPE_4K {
+                    return Err(CoveError::NotSupported("accepting 4k pages for now"));
+                }
+                Self::AddTvmMeasuredPages {
+                    tvm_id: a0,
+                    pages: contiguous_measured_pages(a1, a2, a4, a5)?,
+                }
+            }
+            // a0: tvm_id, a1: address of the MeasuredPageDesc list, a2: number of entries
+            SBI_COVH_ADD_TVM_MEASURED_PAGES_BATCH => Self::AddTvmMeasuredPages {
+                tvm_id: a0,
+                pages: read_measured_pages(mem, a1, a2)?,
+            },
+            // a0: tvm_id, a1: sealed image, a2: its size, a3: destination, a4: pages
+            SBI_COVH_ADD_TVM_SEALED_IMAGE => Self::AddTvmSealedImage {
+                tvm_id: a0,
+                image_addr: a1,
+                header: read_sealed_image(mem, a1, a2, a3, a4)?,
+                dest_addr: a3,
+            },
+            // a0: tvm_id, a1: destination, a2: page type, a3: pages, a4: GPA
+            SBI_COVH_ADD_ZERO_PAGES => {
+                if a2 != TSM_PAGE_TYPE_4K {
+                    return Err(CoveError::NotSupported("accepting 4k pages for now"));
+                }
+                Self::AddTvmZeroPages {
+                    tvm_id: a0,
+                    base_addr: a1,
+                    num_pages: a3,
+                    gpa: a4,
+                }
+            }
+            // a0: tvm_id, a1: host pages, a2: page type, a3: pages, a4: GPA
+            SBI_COVH_ADD_TVM_SHARED_PAGES => {
+                if a2 != TSM_PAGE_TYPE_4K {
+                    return Err(CoveError::NotSupported("accepting 4k pages for now"));
+                }
+                let len = pages_to_bytes(a3)?;
+                range_end(a1, len)?;
+                range_end(a4, len)?;
+                Self::AddTvmSharedPages {
+                    tvm_id: a0,
+                    base_addr: a1,
+                    num_pages: a3,
+                    gpa: a4,
+                }
+            }
+            SBI_COVH_CREATE_TVM_VCPU => Self::CreateTvmVcpu {
+                tvm_id: a0,
+                vcpu_id: a1,
+                state_addr: a2,
+            },
+            // a0: tvm_id, a1: vcpu_id, a2: TVM_RUN_* flags
+            SBI_COVH_RUN_TVM_VCPU => {
+                if a2 & !TVM_RUN_FLAGS_MASK != 0 {
+                    return Err(CoveError::InvalidParam("unknown run flags"));
+                }
+                Self::RunTvmVcpu {
+                    tvm_id: a0,
+                    vcpu_id: a1,
+                    flags: a2,
+                }
+            }
+            // a0: tvm_id, a1: vcpu_id, a2: hart id or TVM_VCPU_ANY_HART
+            SBI_COVH_SET_TVM_VCPU_AFFINITY => Self::SetTvmVcpuAffinity {
+                tvm_id: a0,
+                vcpu_id: a1,
+                hart: (a2 != TVM_VCPU_ANY_HART).then_some(a2),
+            },
+            SBI_COVH_EXPORT_TVM => {
+                check_buffer::<[u8; MIGRATION_OFFER_SIZE]>(a3, a4)?;
+                Self::ExportTvm {
+                    tvm_id: a0,
+                    buf_addr: a1,
+                    buf_len: a2,
+                    offer_addr: a3,
+                }
+            }
+            // a0: address of the offer, a1: its size
+            SBI_COVH_PREPARE_TVM_IMPORT => {
+                check_buffer::<[u8; MIGRATION_OFFER_SIZE]>(a0, a1)?;
+                Self::PrepareTvmImport { offer_addr: a0 }
+            }
+            SBI_COVH_IMPORT_TVM => Self::ImportTvm {
+                blob_addr: a0,
+                blob_len: a1,
+                page_table_addr: a2,
+                state_addr: a3,
+                pool_addr: a4,
+                pool_pages: a5,
+            },
+            SBI_COVH_TVM_VCPU_SET_TIMER_FREQUENCY => Self::TvmVcpuSetTimerFrequency {
+                tvm_id: a0,
+                vcpu_id: a1,
+                frequency: a2,
+            },
+            SBI_COVH_SET_TVM_BOOT_INFO => Self::SetTvmBootInfo {
+                tvm_id: a0,
+                dest_addr: a1,
+                gpa: a2,
+            },
+            // a0: tvm_id, a1: vcpu_id, a2: address of the TvmVcpuTime, a3: its size
+            SBI_COVH_GET_TVM_VCPU_TIME => {
+                check_buffer::<TvmVcpuTime>(a2, a3)?;
+                Self::GetTvmVcpuTime {
+                    tvm_id: a0,
+                    vcpu_id: a1,
+                    addr: a2,
+                }
+            }
+            // a0: tvm_id, a1: GPA, a2: address of the TvmGpaTranslation, a3: its size
+            SBI_COVH_TVM_TRANSLATE_GPA => {
+                check_buffer::<TvmGpaTranslation>(a2, a3)?;
+                Self::TvmTranslateGpa {
+                    tvm_id: a0,
+                    gpa: a1,
+                    addr: a2,
+                }
+            }
+            // a0: address of the TsmStats, a1: its size
+            SBI_COVH_GET_TSM_STATS => {
+                check_buffer::<TsmStats>(a0, a1)?;
+                Self::GetTsmStats { addr: a0 }
+            }
+            // a0: address of the TsmMemoryStats, a1: its size
+            SBI_COVH_GET_TSM_MEMORY_STATS => {
+                check_buffer::<TsmMemoryStats>(a0, a1)?;
+                Self::GetTsmMemoryStats { addr: a0 }
+            }
+            // a0: address of the CovhQueueEntry list, a1: number of entries
+            SBI_COVH_PROCESS_QUEUE => {
+                if a1 == 0 || a1 > COVH_QUEUE_MAX_ENTRIES {
+                    return Err(CoveError::InvalidParam("invalid queue size"));
+                }
+                if !a0.is_multiple_of(core::mem::align_of::<CovhQueueEntry>()) {
+                    return Err(CoveError::InvalidAddress("unaligned queue"));
+                }
+                range_end(a0, a1 * core::mem::size_of::<CovhQueueEntry>())?;
+                Self::ProcessQueue {
+                    addr: a0,
+                    count: a1,
+                }
+            }
+            _ => return Err(CoveError::NotSupported("unsupported CoVH function")),
+        };
+        Ok(call)
+    }
+
+    /// Decode the call of the `index`-th entry of the queue at `addr`. Only the `covh_queueable`
+    /// functions are accepted.
+    pub fn decode_queued(mem: &impl PhysMemory, addr: usize, index: usize) -> CoveResult<Self> {
+        let mut buf = [0u8; 7 * 8];
+        mem.read(queue_entry_addr(addr, index), &mut buf)?;
+        let word =
+            |idx: usize| usize::from_le_bytes(buf[idx * 8..(idx + 1) * 8].try_into().unwrap());
+
+        let fid = word(0);
+        if !covh_queueable(fid) {
+            return Err(CoveError::InvalidParam("CoVH function cannot be queued"));
+        }
+        Self::decode(fid, core::array::from_fn(|i| word(i + 1)), mem)
+    }
+}
+
+/// Address of the `index`-th entry of the queue at `addr`, checked by `decode`.
+pub fn queue_entry_addr(addr: usize, index: usize) -> usize {
+    addr + index * core::mem::size_of::<CovhQueueEntry>()
+}
+
+/// Fail unless the host buffer `[addr, addr + len)` can hold a `T`.
+fn check_buffer<T>(addr: usize, len: usize) -> CoveResult<()> {
+    let size = core::mem::size_of::<T>();
+    if len < size {
+        return Err(CoveError::InvalidParam("buffer too small"));
+    }
+    if !addr.is_multiple_of(core::mem::align_of::<T>()) {
+        return Err(CoveError::InvalidAddress("unaligned buffer"));
+    }
+    range_end(addr, size).map(|_| ())
+}
+
+/// Expand the contiguous variant of `sbi_covh_add_tvm_measured_pages` into page descriptors.
+pub fn contiguous_measured_pages(
+    source_addr: usize,
+    dest_addr: usize,
+    num_pages: usize,
+    tvm_guest_gpa: usize,
+) -> CoveResult<Vec<MeasuredPageDesc>> {
+    if num_pages > MAX_MEASURED_PAGES {
+        return Err(CoveError::InvalidParam("too many measured pages"));
+    }
+    let len = pages_to_bytes(num_pages)?;
+    range_end(source_addr, len)?;
+    range_end(dest_addr, len)?;
+    range_end(tvm_guest_gpa, len)?;
+
+    Ok((0..num_pages)
+        .map(|i| MeasuredPageDesc {
+            source_addr: source_addr + i * PAGE_SIZE,
+            dest_addr: dest_addr + i * PAGE_SIZE,
+            tvm_guest_gpa: tvm_guest_gpa + i * PAGE_SIZE,
+        })
+        .collect())
+}
+
+/// Pages of an `AddTvmMeasuredPages` handled by one TEECALL: the first `max_chunk` (at least one).
+/// The TSM cannot be interrupted, so a large region would keep the untrusted domain away from its
+/// interrupts for milliseconds: the call returns the number of pages added in a1 instead, and the
+/// host issues it again for the pages left. The measurement extends page by page, so it does not
+/// depend on how the pages are split.
+pub fn measured_chunk(pages: &[MeasuredPageDesc], max_chunk: usize) -> &[MeasuredPageDesc] {
+    &pages[..pages.len().min(max_chunk.max(1))]
+}
+
+/// Read the `MeasuredPageDesc` list of `sbi_covh_add_tvm_measured_pages_batch`.
+fn read_measured_pages(
+    mem: &impl PhysMemory,
+    addr: usize,
+    count: usize,
+) -> CoveResult<Vec<MeasuredPageDesc>> {
+    if count > MAX_MEASURED_PAGES {
+        return Err(CoveError::InvalidParam("too many measured pages"));
+    }
+    let desc_size = core::mem::size_of::<MeasuredPageDesc>();
+    if !addr.is_multiple_of(core::mem::align_of::<MeasuredPageDesc>()) {
+        return Err(CoveError::InvalidAddress("unaligned page list"));
+    }
+    range_end(addr, count * desc_size)?;
+
+    let mut pages = Vec::with_capacity(count);
+    let mut buf = [0u8; 24];
+    for i in 0..count {
+        mem.read(addr + i * desc_size, &mut buf[..desc_size])?;
+        let field =
+            |idx: usize| usize::from_le_bytes(buf[idx * 8..(idx + 1) * 8].try_into().unwrap());
+        pages.push(MeasuredPageDesc {
+            source_addr: field(0),
+            dest_addr: field(1),
+            tvm_guest_gpa: field(2),
+        });
+    }
+    Ok(pages)
+}
+
+/// Read and check the header of the sealed image `[addr, addr + len)` of
+/// `sbi_covh_add_tvm_sealed_image`: the image must hold the `num_pages` pages the host converted at
+/// `dest_addr`. The records are authenticated while they are opened.
+fn read_sealed_image(
+    mem: &impl PhysMemory,
+    addr: usize,
+    len: usize,
+    dest_addr: usize,
+    num_pages: usize,
+) -> CoveResult<SealedImageHeader> {
+    range_end(addr, len)?;
+    let mut buf = [0u8; SEALED_IMAGE_HEADER_SIZE];
+    if len < buf.len() {
+        return Err(CoveError::InvalidParam("sealed image too small"));
+    }
+    mem.read(addr, &mut buf)?;
+    let header = SealedImageHeader::parse(&buf)
+        .map_err(|_| CoveError::InvalidParam("invalid sealed image header"))?;
+
+    if header.num_pages as usize != num_pages {
+        return Err(CoveError::InvalidParam(
+            "page count differs from the sealed image",
+        ));
+    }
+    if num_pages > MAX_MEASURED_PAGES {
+        return Err(CoveError::InvalidParam("too many measured pages"));
+    }
+    if len < header.image_size() {
+        return Err(CoveError::InvalidParam("sealed image truncated"));
+    }
+    if !dest_addr.is_multiple_of(PAGE_SIZE) {
+        return Err(CoveError::InvalidAddress(
+            "all addresses must be page-aligned",
+        ));
+    }
+    let size = pages_to_bytes(num_pages)?;
+    range_end(dest_addr, size)?;
+    range_end(header.gpa as usize, size)?;
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::tests::MockMemory;
+    use common::sbi::{TVM_POLICY_MIGRATABLE, TVM_RUN_FAST_PATH};
+
+    const SCRATCH: usize = 0x8A20_0000;
+
+    fn memory_with(words: &[usize]) -> MockMemory {
+        let mut mem = MockMemory::new(SCRATCH, PAGE_SIZE);
+        let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        mem.write(SCRATCH, &bytes);
+        mem
+    }
+
+    fn create_tvm(mem: &MockMemory, len: usize) -> CoveResult<CovhCall> {
+        CovhCall::decode(SBI_COVH_CREATE_TVM, [SCRATCH, len, 0, 0, 0, 0], mem)
+    }
+
+    #[test]
+    fn create_tvm_default_layout() {
+        let mem = memory_with(&[0x8A80_0000, 0x8A80_4000]);
+        let call = create_tvm(&mem, 16).unwrap();
+        assert_eq!(
+            call,
+            CovhCall::CreateTvm(CreateTvmParams {
+                page_table_addr: 0x8A80_0000,
+                state_addr: 0x8A80_4000,
+                measurement_alg: HashAlgorithm::Sha384,
+                policy: TVM_POLICY_DEFAULT,
+                working_set: None,
+            })
+        );
+    }
+
+    #[test]
+    fn create_tvm_policy() {
+        let mem = memory_with(&[0x8A80_0000, 0x8A80_4000, 0, TVM_POLICY_MIGRATABLE]);
+        let CovhCall::CreateTvm(params) = create_tvm(&mem, 32).unwrap() else {
+            panic!("unexpected call");
+        };
+        assert_eq!(params.policy, TVM_POLICY_MIGRATABLE);
+
+        // Unknown flags
+        let mem = memory_with(&[0x8A80_0000, 0x8A80_4000, 0, TVM_POLICY_MASK + 1]);
+        assert!(create_tvm(&mem, 32).is_err());
+    }
+
+    #[test]
+    fn create_tvm_rejects_malformed_params() {
+        // Unsupported size
+        let mem = memory_with(&[0x8A80_0000, 0x8A80_4000]);
+        assert!(create_tvm(&mem, 8).is_err());
+        assert!(create_tvm(&mem, 40).is_err());
+        // State page inside the page directory
+        let mem = memory_with(&[0x8A80_0000, 0x8A80_3000]);
+        assert!(create_tvm(&mem, 16).is_err());
+        // Unaligned page directory
+        let mem = memory_with(&[0x8A80_1000, 0x8A80_4000]);
+        assert!(create_tvm(&mem, 16).is_err());
+        // Page directory at the end of the address space
+        let mem = memory_with(&[usize::MAX & !(PAGE_DIRECTORY_SIZE - 1), 0x8A80_4000]);
+        assert!(create_tvm(&mem, 16).is_err());
+        // Unknown measurement algorithm
+        let mem = memory_with(&[0x8A80_0000, 0x8A80_4000, 7]);
+        assert!(create_tvm(&mem, 24).is_err());
+        // Params outside of memory
+        let call = CovhCall::decode(SBI_COVH_CREATE_TVM, [usize::MAX - 8, 16, 0, 0, 0, 0], &mem);
+        assert!(call.is_err());
+    }
+
+    #[test]
+    fn create_tvm_encryption_policy() {
+        let ws = [0x8A80_0000, 0x8A80_4000, 0, TVM_POLICY_SW_PAGE_ENCRYPTION];
+        // The policy needs a working set
+        assert!(create_tvm(&memory_with(&ws), 32).is_err());
+
+        let mem = memory_with(&[ws[0], ws[1], ws[2], ws[3], 0x8A80_5000, 4]);
+        let CovhCall::CreateTvm(params) = create_tvm(&mem, 48).unwrap() else {
+            panic!("unexpected call");
+        };
+        assert_eq!(params.working_set, Some((0x8A80_5000, 4)));
+
+        // A working set needs the policy
+        let mem = memory_with(&[ws[0], ws[1], 0, 0, 0x8A80_5000, 4]);
+        assert!(create_tvm(&mem, 48).is_err());
+        // Empty working set
+        let mem = memory_with(&[ws[0], ws[1], ws[2], ws[3], 0x8A80_5000, 0]);
+        assert!(create_tvm(&mem, 48).is_err());
+    }
+
+    #[test]
+    fn measured_pages_are_bounded() {
+        let mem = MockMemory::new(SCRATCH, PAGE_SIZE);
+        let args = [1, 0x8A40_0000, 0x8A80_0000, 0, 2, 0x1000];
+        let call = CovhCall::decode(SBI_COVH_ADD_TVM_MEASURED_PAGES, args, &mem).unwrap();
+        let CovhCall::AddTvmMeasuredPages { pages, .. } = call else {
+            panic!("unexpected call");
+        };
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[1].tvm_guest_gpa, 0x2000);
+
+        let huge = [1, 0x8A40_0000, 0x8A80_0000, 0, usize::MAX, 0x1000];
+        assert!(CovhCall::decode(SBI_COVH_ADD_TVM_MEASURED_PAGES, huge, &mem).is_err());
+        let wrap = [1, usize::MAX & !(PAGE_SIZE - 1), 0x8A80_0000, 0, 2, 0x1000];
+        assert!(CovhCall::decode(SBI_COVH_ADD_TVM_MEASURED_PAGES, wrap, &mem).is_err());
+        let huge_page = [1, 0x8A40_0000, 0x8A80_0000, 1, 1, 0x1000];
+        assert!(CovhCall::decode(SBI_COVH_ADD_TVM_MEASURED_PAGES, huge_page, &mem).is_err());
+    }
+
+    #[test]
+    fn measured_pages_are_chunked() {
+        // A 2 MiB region
+        let pages = contiguous_measured_pages(0x8A40_0000, 0x8A80_0000, 512, 0x20_0000).unwrap();
+        let chunk = measured_chunk(&pages, DEFAULT_MEASURE_CHUNK_PAGES);
+        assert_eq!(chunk.len(), DEFAULT_MEASURE_CHUNK_PAGES);
+        assert_eq!(chunk[0].tvm_guest_gpa, 0x20_0000);
+
+        assert_eq!(measured_chunk(&pages[..3], 64).len(), 3);
+        // Every call makes progress
+        assert_eq!(measured_chunk(&pages, 0).len(), 1);
+    }
+
+    #[test]
+    fn measured_pages_batch() {
+        let mem = memory_with(&[
+            0x8A40_0000,
+            0x8A80_0000,
+            0x0,
+            0x8A40_1000,
+            0x8A80_1000,
+            0x5000,
+        ]);
+        let args = [1, SCRATCH, 2, 0, 0, 0];
+        let call = CovhCall::decode(SBI_COVH_ADD_TVM_MEASURED_PAGES_BATCH, args, &mem).unwrap();
+        let CovhCall::AddTvmMeasuredPages { pages, .. } = call else {
+            panic!("unexpected call");
+        };
+        assert_eq!(pages[1].dest_addr, 0x8A80_1000);
+        assert_eq!(pages[1].tvm_guest_gpa, 0x5000);
+
+        // The list must be readable and bounded
+        let args = [1, SCRATCH, PAGE_SIZE, 0, 0, 0];
+        assert!(CovhCall::decode(SBI_COVH_ADD_TVM_MEASURED_PAGES_BATCH, args, &mem).is_err());
+        let args = [1, SCRATCH, usize::MAX, 0, 0, 0];
+        assert!(CovhCall::decode(SBI_COVH_ADD_TVM_MEASURED_PAGES_BATCH, args, &mem).is_err());
+        let args = [1, SCRATCH + 1, 1, 0, 0, 0];
+        assert!(CovhCall::decode(SBI_COVH_ADD_TVM_MEASURED_PAGES_BATCH, args, &mem).is_err());
+    }
+
+    fn queue_entry(fid: usize, args: [usize; 6]) -> Vec<usize> {
+        let mut words = vec![fid];
+        words.extend(args);
+        words.extend([usize::MAX, 0]);
+        words
+    }
+
+    #[test]
+    fn process_queue() {
+        let words: Vec<usize> = [
+            queue_entry(SBI_COVH_ADD_TVM_MEMORY_REGION, [1, 0, 0x2_0000, 0, 0, 0]),
+            queue_entry(SBI_COVH_ADD_ZERO_PAGES, [1, 0x8A80_0000, 0, 1, 0x1000, 0]),
+        ]
+        .concat();
+        let mem = memory_with(&words);
+        let call = CovhCall::decode(SBI_COVH_PROCESS_QUEUE, [SCRATCH, 2, 0, 0, 0, 0], &mem);
+        assert_eq!(
+            call.unwrap(),
+            CovhCall::ProcessQueue {
+                addr: SCRATCH,
+                count: 2
+            }
+        );
+        assert_eq!(
+            CovhCall::decode_queued(&mem, SCRATCH, 1).unwrap(),
+            CovhCall::AddTvmZeroPages {
+                tvm_id: 1,
+                base_addr: 0x8A80_0000,
+                num_pages: 1,
+                gpa: 0x1000,
+            }
+        );
+
+        // Bounded, aligned and readable
+        for args in [
+            [SCRATCH, 0],
+            [SCRATCH, COVH_QUEUE_MAX_ENTRIES + 1],
+            [SCRATCH + 1, 1],
+        ] {
+            let args = [args[0], args[1], 0, 0, 0, 0];
+            assert!(CovhCall::decode(SBI_COVH_PROCESS_QUEUE, args, &mem).is_err());
+        }
+        let args = [usize::MAX & !7, 2, 0, 0, 0, 0];
+        assert!(CovhCall::decode(SBI_COVH_PROCESS_QUEUE, args, &mem).is_err());
+        assert!(CovhCall::decode_queued(&mem, SCRATCH, PAGE_SIZE).is_err());
+    }
+
+    #[test]
+    fn process_queue_rejects_unqueueable_calls() {
+        for fid in [
+            SBI_COVH_GET_TSM_INFO,
+            SBI_COVH_CONVERT_PAGES,
+            SBI_COVH_RECLAIM_PAGES,
+            SBI_COVH_ADD_TVM_SHARED_PAGES,
+            SBI_COVH_RUN_TVM_VCPU,
+            SBI_COVH_PROCESS_QUEUE,
+        ] {
+            let mem = memory_with(&queue_entry(fid, [SCRATCH, 1, 0, 0, 0, 0]));
+            assert!(CovhCall::decode_queued(&mem, SCRATCH, 0).is_err());
+        }
+    }
+
+    #[test]
+    fn run_tvm_vcpu_flags() {
+        let mem = memory_with(&[]);
+        let args = [1, 0, TVM_RUN_FAST_PATH, 0, 0, 0];
+        let call = CovhCall::decode(SBI_COVH_RUN_TVM_VCPU, args, &mem).unwrap();
+        assert_eq!(
+            call,
+            CovhCall::RunTvmVcpu {
+                tvm_id: 1,
+                vcpu_id: 0,
+                flags: TVM_RUN_FAST_PATH,
+            }
+        );
+
+        // Unknown flags
+        let args = [1, 0, TVM_RUN_FLAGS_MASK + 1, 0, 0, 0];
+        assert!(CovhCall::decode(SBI_COVH_RUN_TVM_VCPU, args, &mem).is_err());
+    }
+
+    #[test]
+    fn vcpu_affinity() {
+        let mem = memory_with(&[]);
+        let args = [1, 0, 3, 0, 0, 0];
+        let call = CovhCall::decode(SBI_COVH_SET_TVM_VCPU_AFFINITY, args, &mem).unwrap();
+        assert_eq!(
+            call,
+            CovhCall::SetTvmVcpuAffinity {
+                tvm_id: 1,
+                vcpu_id: 0,
+                hart: Some(3),
+            }
+        );
+
+        let args = [1, 0, TVM_VCPU_ANY_HART, 0, 0, 0];
+        let call = CovhCall::decode(SBI_COVH_SET_TVM_VCPU_AFFINITY, args, &mem).unwrap();
+        assert!(matches!(
+            call,
+            CovhCall::SetTvmVcpuAffinity { hart: None, .. }
+        ));
+    }
+
+    #[test]
+    fn migration_offer_buffer() {
+        let mem = memory_with(&[]);
+        let args = [0x8A20_0000, MIGRATION_OFFER_SIZE, 0, 0, 0, 0];
+        let call = CovhCall::decode(SBI_COVH_PREPARE_TVM_IMPORT, args, &mem).unwrap();
+        assert_eq!(
+            call,
+            CovhCall::PrepareTvmImport {
+                offer_addr: 0x8A20_0000
+            }
+        );
+
+        let short = [0x8A20_0000, MIGRATION_OFFER_SIZE - 1, 0, 0, 0, 0];
+        assert!(CovhCall::decode(SBI_COVH_PREPARE_TVM_IMPORT, short, &mem).is_err());
+        let args = [
+            1,
+            0x8A00_0000,
+            0x1000,
+            0x8A20_0000,
+            MIGRATION_OFFER_SIZE - 1,
+            0,
+        ];
+        assert!(CovhCall::decode(SBI_COVH_EXPORT_TVM, args, &mem).is_err());
+    }
+
+    #[test]
+    fn vcpu_time_buffer() {
+        let mem = memory_with(&[]);
+        let size = core::mem::size_of::<TvmVcpuTime>();
+        let args = [1, 0, 0x8A20_0000, size, 0, 0];
+        let call = CovhCall::decode(SBI_COVH_GET_TVM_VCPU_TIME, args, &mem).unwrap();
+        assert_eq!(
+            call,
+            CovhCall::GetTvmVcpuTime {
+                tvm_id: 1,
+                vcpu_id: 0,
+                addr: 0x8A20_0000,
+            }
+        );
+
+        let short = [1, 0, 0x8A20_0000, size - 1, 0, 0];
+        assert!(CovhCall::decode(SBI_COVH_GET_TVM_VCPU_TIME, short, &mem).is_err());
+        let unaligned = [1, 0, 0x8A20_0004, size, 0, 0];
+        assert!(CovhCall::decode(SBI_COVH_GET_TVM_VCPU_TIME, unaligned, &mem).is_err());
+        let wrapping = [1, 0, usize::MAX - 7, size, 0, 0];
+        assert!(CovhCall::decode(SBI_COVH_GET_TVM_VCPU_TIME, wrapping, &mem).is_err());
+    }
+
+    #[test]
+    fn translate_gpa_buffer() {
+        let mem = memory_with(&[]);
+        let size = core::mem::size_of::<TvmGpaTranslation>();
+        let args = [1, 0x8000_1000, 0x8A20_0000, size, 0, 0];
+        let call = CovhCall::decode(SBI_COVH_TVM_TRANSLATE_GPA, args, &mem).unwrap();
+        assert_eq!(
+            call,
+            CovhCall::TvmTranslateGpa {
+                tvm_id: 1,
+                gpa: 0x8000_1000,
+                addr: 0x8A20_0000,
+            }
+        );
+
+        let short = [1, 0x8000_1000, 0x8A20_0000, size - 1, 0, 0];
+        assert!(CovhCall::decode(SBI_COVH_TVM_TRANSLATE_GPA, short, &mem).is_err());
+    }
+
+    #[test]
+    fn tsm_stats_buffer() {
+        let mem = memory_with(&[]);
+        let size = core::mem::size_of::<TsmStats>();
+        let args = [0x8A20_0000, size, 0, 0, 0, 0];
+        let call = CovhCall::decode(SBI_COVH_GET_TSM_STATS, args, &mem).unwrap();
+        assert_eq!(call, CovhCall::GetTsmStats { addr: 0x8A20_0000 });
+
+        let short = [0x8A20_0000, size - 1, 0, 0, 0, 0];
+        assert!(CovhCall::decode(SBI_COVH_GET_TSM_STATS, short, &mem).is_err());
+        let unaligned = [0x8A20_0004, size, 0, 0, 0, 0];
+        assert!(CovhCall::decode(SBI_COVH_GET_TSM_STATS, unaligned, &mem).is_err());
+    }
+
+    fn sealed_header(num_pages: u32, gpa: u64) -> SealedImageHeader {
+        SealedImageHeader {
+            magic: common::sealed_image::SEALED_IMAGE_MAGIC,
+            version: common::sealed_image::SEALED_IMAGE_VERSION,
+            num_pages,
+            gpa,
+            salt: [7; 16],
+        }
+    }
+
+    #[test]
+    fn sealed_image_header() {
+        let header = sealed_header(2, 0x8000_0000);
+        let mut mem = MockMemory::new(SCRATCH, 4 * PAGE_SIZE);
+        mem.write(SCRATCH, &header.to_bytes());
+        let len = header.image_size();
+        let args = [1, SCRATCH, len, 0x8A80_0000, 2, 0];
+        let call = CovhCall::decode(SBI_COVH_ADD_TVM_SEALED_IMAGE, args, &mem).unwrap();
+        assert_eq!(
+            call,
+            CovhCall::AddTvmSealedImage {
+                tvm_id: 1,
+                image_addr: SCRATCH,
+                header,
+                dest_addr: 0x8A80_0000,
+            }
+        );
+
+        // The host cannot load fewer pages than sealed, nor cut the image
+        let fewer = [1, SCRATCH, len, 0x8A80_0000, 1, 0];
+        assert!(CovhCall::decode(SBI_COVH_ADD_TVM_SEALED_IMAGE, fewer, &mem).is_err());
+        let truncated = [1, SCRATCH, len - 1, 0x8A80_0000, 2, 0];
+        assert!(CovhCall::decode(SBI_COVH_ADD_TVM_SEALED_IMAGE, truncated, &mem).is_err());
+        let unaligned = [1, SCRATCH, len, 0x8A80_0800, 2, 0];
+        assert!(CovhCall::decode(SBI_COVH_ADD_TVM_SEALED_IMAGE, unaligned, &mem).is_err());
+    }
+
+    #[test]
+    fn sealed_image_bad_header() {
+        let mut mem = MockMemory::new(SCRATCH, PAGE_SIZE);
+        let mut header = sealed_header(1, 0x8000_0000);
+        header.magic = *b"SFXMIG02";
+        mem.write(SCRATCH, &header.to_bytes());
+        let args = [1, SCRATCH, PAGE_SIZE, 0x8A80_0000, 1, 0];
+        assert!(CovhCall::decode(SBI_COVH_ADD_TVM_SEALED_IMAGE, args, &mem).is_err());
+
+        mem.write(SCRATCH, &sealed_header(1, 0x8000_0800).to_bytes());
+        assert!(CovhCall::decode(SBI_COVH_ADD_TVM_SEALED_IMAGE, args, &mem).is_err());
+    }
+
+    #[test]
+    fn tsm_memory_stats_buffer() {
+        let mem = memory_with(&[]);
+        let size = core::mem::size_of::<TsmMemoryStats>();
+        let args = [0x8A20_0000, size, 0, 0, 0, 0];
+        let call = CovhCall::decode(SBI_COVH_GET_TSM_MEMORY_STATS, args, &mem).unwrap();
+        assert_eq!(call, CovhCall::GetTsmMemoryStats { addr: 0x8A20_0000 });
+
+        let short = [0x8A20_0000, size - 1, 0, 0, 0, 0];
+        assert!(CovhCall::decode(SBI_COVH_GET_TSM_MEMORY_STATS, short, &mem).is_err());
+    }
+
+    #[test]
+    fn shared_pages_are_bounded() {
+        let mem = memory_with(&[]);
+        let args = [1, 0x8A20_0000, 0, 1, 0x20_0000, 0];
+        let call = CovhCall::decode(SBI_COVH_ADD_TVM_SHARED_PAGES, args, &mem).unwrap();
+        assert_eq!(
+            call,
+            CovhCall::AddTvmSharedPages {
+                tvm_id: 1,
+                base_addr: 0x8A20_0000,
+                num_pages: 1,
+                gpa: 0x20_0000,
+            }
+        );
+
+        let huge_page = [1, 0x8A20_0000, 1, 1, 0x20_0000, 0];
+        assert!(CovhCall::decode(SBI_COVH_ADD_TVM_SHARED_PAGES, huge_page, &mem).is_err());
+        let wrapping = [1, usize::MAX - PAGE_SIZE, 0, 2, 0x20_0000, 0];
+        assert!(CovhCall::decode(SBI_COVH_ADD_TVM_SHARED_PAGES, wrapping, &mem).is_err());
+    }
+
+    #[test]
+    fn unknown_function() {
+        let mem = MockMemory::new(SCRATCH, PAGE_SIZE);
+        assert!(CovhCall::decode(0xFFFF, [0; 6], &mem).is_err());
+    }
+}
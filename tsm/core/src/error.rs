@@ -0,0 +1,79 @@
+//! Errors of the COVH/COVI calls. An error carries the SBI error code returned to the host and a
+//! static message: reporting it neither allocates nor formats, even on the COVH hot path.
+
+use core::fmt;
+
+// SBI error codes, see the SBI specification
+const SBI_ERR_FAILED: isize = -1;
+const SBI_ERR_NOT_SUPPORTED: isize = -2;
+const SBI_ERR_INVALID_PARAM: isize = -3;
+const SBI_ERR_DENIED: isize = -4;
+const SBI_ERR_INVALID_ADDRESS: isize = -5;
+const SBI_ERR_ALREADY_AVAILABLE: isize = -6;
+const SBI_ERR_INVALID_STATE: isize = -10;
+const SBI_ERR_BAD_RANGE: isize = -11;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoveError {
+    /// Malformed argument: unknown id, size or flags
+    InvalidParam(&'static str),
+    /// Misaligned address, or memory the call cannot use
+    InvalidAddress(&'static str),
+    /// The TVM is not in the state the call needs
+    InvalidState(&'static str),
+    /// Already done (a TVM created, a file converted or bound)
+    AlreadyAvailable(&'static str),
+    /// Not implemented, or not available on this platform
+    NotSupported(&'static str),
+    /// Forbidden by the TVM policy or by the memory ownership
+    Denied(&'static str),
+    /// Overlaps memory already in use
+    BadRange(&'static str),
+    /// Anything else: out of memory, a failed seal or authentication
+    Failed(&'static str),
+}
+
+pub type CoveResult<T> = Result<T, CoveError>;
+
+impl CoveError {
+    /// Error code of the `SbiRet` returned to the caller.
+    pub fn sbi_error(&self) -> isize {
+        match self {
+            Self::InvalidParam(_) => SBI_ERR_INVALID_PARAM,
+            Self::InvalidAddress(_) => SBI_ERR_INVALID_ADDRESS,
+            Self::InvalidState(_) => SBI_ERR_INVALID_STATE,
+            Self::AlreadyAvailable(_) => SBI_ERR_ALREADY_AVAILABLE,
+            Self::NotSupported(_) => SBI_ERR_NOT_SUPPORTED,
+            Self::Denied(_) => SBI_ERR_DENIED,
+            Self::BadRange(_) => SBI_ERR_BAD_RANGE,
+            Self::Failed(_) => SBI_ERR_FAILED,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::InvalidParam(msg)
+            | Self::InvalidAddress(msg)
+            | Self::InvalidState(msg)
+            | Self::AlreadyAvailable(msg)
+            | Self::NotSupported(msg)
+            | Self::Denied(msg)
+            | Self::BadRange(msg)
+            | Self::Failed(msg) => msg,
+        }
+    }
+}
+
+impl fmt::Display for CoveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl core::error::Error for CoveError {}
+
+impl From<core::array::TryFromSliceError> for CoveError {
+    fn from(_: core::array::TryFromSliceError) -> Self {
+        Self::InvalidParam("truncated input")
+    }
+}
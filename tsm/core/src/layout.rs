@@ -0,0 +1,271 @@
+//! Guest memory layout and lifecycle state of a TVM.
+
+use alloc::vec::Vec;
+
+use crate::{
+    memory::{pages_to_bytes, range_end},
+    CoveError, CoveResult, PAGE_SIZE,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub guest_gpa_base: usize,
+    pub num_pages: usize,
+}
+
+impl MemoryRegion {
+    pub fn end(&self) -> usize {
+        self.guest_gpa_base + self.num_pages * PAGE_SIZE
+    }
+}
+
+/// Memory regions declared with `sbi_covh_add_tvm_memory_region`. Regions never overlap.
+#[derive(Default)]
+pub struct GuestMemoryMap {
+    regions: Vec<MemoryRegion>,
+}
+
+impl GuestMemoryMap {
+    pub const fn new() -> Self {
+        Self {
+            regions: Vec::new(),
+        }
+    }
+
+    pub fn add_region(&mut self, gpa: usize, len_bytes: usize) -> CoveResult<()> {
+        if !gpa.is_multiple_of(PAGE_SIZE) || !len_bytes.is_multiple_of(PAGE_SIZE) || len_bytes == 0
+        {
+            return Err(CoveError::InvalidAddress(
+                "tvm_gpa_addr and region_len must be 4KB-aligned and non-zero",
+            ));
+        }
+        let end = range_end(gpa, len_bytes)?;
+        if self.overlaps(gpa, end - gpa) {
+            return Err(CoveError::BadRange("region overlap with existing region"));
+        }
+
+        self.regions.push(MemoryRegion {
+            guest_gpa_base: gpa,
+            num_pages: len_bytes / PAGE_SIZE,
+        });
+        Ok(())
+    }
+
+    /// Rebuild a layout from regions received from outside the TSM (e.g. a migration blob).
+    pub fn from_regions(regions: &[MemoryRegion]) -> CoveResult<Self> {
+        let mut map = Self::new();
+        for r in regions {
+            map.add_region(r.guest_gpa_base, pages_to_bytes(r.num_pages)?)?;
+        }
+        Ok(map)
+    }
+
+    /// True if `[gpa, gpa + len)` lies entirely within one region.
+    pub fn contains(&self, gpa: usize, len: usize) -> bool {
+        let Some(end) = gpa.checked_add(len) else {
+            return false;
+        };
+        self.regions
+            .iter()
+            .any(|r| gpa >= r.guest_gpa_base && end <= r.end())
+    }
+
+    /// True if `[gpa, gpa + len)` intersects any region.
+    pub fn overlaps(&self, gpa: usize, len: usize) -> bool {
+        let end = gpa.saturating_add(len);
+        self.regions
+            .iter()
+            .any(|r| gpa < r.end() && r.guest_gpa_base < end)
+    }
+
+    /// Remove `[gpa, gpa + len)`, which must lie within one region. The rest of the region stays,
+    /// in up to two regions.
+    pub fn remove(&mut self, gpa: usize, len: usize) -> CoveResult<()> {
+        if !gpa.is_multiple_of(PAGE_SIZE) || !len.is_multiple_of(PAGE_SIZE) || len == 0 {
+            return Err(CoveError::InvalidAddress(
+                "gpa and len must be 4KB-aligned and non-zero",
+            ));
+        }
+        let end = range_end(gpa, len)?;
+        let index = self
+            .regions
+            .iter()
+            .position(|r| gpa >= r.guest_gpa_base && end <= r.end())
+            .ok_or(CoveError::BadRange("range not within a region"))?;
+
+        let region = self.regions[index];
+        let before = MemoryRegion {
+            guest_gpa_base: region.guest_gpa_base,
+            num_pages: (gpa - region.guest_gpa_base) / PAGE_SIZE,
+        };
+        let after = MemoryRegion {
+            guest_gpa_base: end,
+            num_pages: (region.end() - end) / PAGE_SIZE,
+        };
+        let rest = [before, after].into_iter().filter(|r| r.num_pages != 0);
+        self.regions.splice(index..=index, rest);
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MemoryRegion> {
+        self.regions.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.regions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TvmState {
+    TvmInitializing = 0,
+    TvmRunnable = 1,
+    /// Exported for a migration: the TVM runs on the destination now, it can only be destroyed
+    TvmExported = 2,
+}
+
+impl TvmState {
+    pub fn is_initializing(&self) -> bool {
+        *self == TvmState::TvmInitializing
+    }
+
+    /// TVM_INITIALIZING -> TVM_RUNNABLE. A TVM is finalized only once.
+    pub fn finalize(&mut self) -> CoveResult<()> {
+        if !self.is_initializing() {
+            return Err(CoveError::InvalidState("TVM already finalized"));
+        }
+        *self = TvmState::TvmRunnable;
+        Ok(())
+    }
+
+    /// TVM_RUNNABLE -> TVM_EXPORTED. A TVM is exported only once.
+    pub fn export(&mut self) -> CoveResult<()> {
+        if *self != TvmState::TvmRunnable {
+            return Err(CoveError::InvalidState(
+                "only runnable TVMs can be exported",
+            ));
+        }
+        *self = TvmState::TvmExported;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_region_checks_alignment() {
+        let mut map = GuestMemoryMap::new();
+        assert!(map.add_region(0x1, PAGE_SIZE).is_err());
+        assert!(map.add_region(0x0, PAGE_SIZE + 1).is_err());
+        assert!(map.add_region(0x0, 0).is_err());
+        assert!(map
+            .add_region(usize::MAX & !(PAGE_SIZE - 1), PAGE_SIZE)
+            .is_err());
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn add_region_rejects_overlaps() {
+        let mut map = GuestMemoryMap::new();
+        map.add_region(0x10000, 4 * PAGE_SIZE).unwrap();
+        assert!(map.add_region(0x10000, PAGE_SIZE).is_err());
+        assert!(map.add_region(0xF000, 2 * PAGE_SIZE).is_err());
+        assert!(map.add_region(0x13000, PAGE_SIZE).is_err());
+
+        map.add_region(0xF000, PAGE_SIZE).unwrap();
+        map.add_region(0x14000, PAGE_SIZE).unwrap();
+        assert_eq!(map.len(), 3);
+    }
+
+    #[test]
+    fn contains_and_overlaps() {
+        let mut map = GuestMemoryMap::new();
+        map.add_region(0x0, 2 * PAGE_SIZE).unwrap();
+        map.add_region(0x2000, PAGE_SIZE).unwrap();
+
+        assert!(map.contains(0x1000, PAGE_SIZE));
+        // Adjacent regions are still distinct regions
+        assert!(!map.contains(0x1000, 2 * PAGE_SIZE));
+        assert!(!map.contains(0x3000, PAGE_SIZE));
+        assert!(!map.contains(usize::MAX, 2));
+
+        assert!(map.overlaps(0x2FFF, 1));
+        assert!(!map.overlaps(0x3000, PAGE_SIZE));
+    }
+
+    #[test]
+    fn remove_splits_regions() {
+        let mut map = GuestMemoryMap::new();
+        map.add_region(0x10000, 4 * PAGE_SIZE).unwrap();
+        map.add_region(0x20000, PAGE_SIZE).unwrap();
+
+        map.remove(0x11000, PAGE_SIZE).unwrap();
+        assert_eq!(map.len(), 3);
+        assert!(map.contains(0x10000, PAGE_SIZE));
+        assert!(!map.overlaps(0x11000, PAGE_SIZE));
+        assert!(map.contains(0x12000, 2 * PAGE_SIZE));
+
+        // Whole regions go away
+        map.remove(0x20000, PAGE_SIZE).unwrap();
+        map.remove(0x10000, PAGE_SIZE).unwrap();
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn remove_checks_the_range() {
+        let mut map = GuestMemoryMap::new();
+        map.add_region(0x0, 2 * PAGE_SIZE).unwrap();
+        map.add_region(0x2000, PAGE_SIZE).unwrap();
+
+        // Across two regions, outside, misaligned or empty
+        assert!(map.remove(0x1000, 2 * PAGE_SIZE).is_err());
+        assert!(map.remove(0x3000, PAGE_SIZE).is_err());
+        assert!(map.remove(0x800, PAGE_SIZE).is_err());
+        assert!(map.remove(0x0, 0).is_err());
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn from_regions_validates() {
+        let regions = [
+            MemoryRegion {
+                guest_gpa_base: 0x0,
+                num_pages: 2,
+            },
+            MemoryRegion {
+                guest_gpa_base: 0x1000,
+                num_pages: 1,
+            },
+        ];
+        assert!(GuestMemoryMap::from_regions(&regions).is_err());
+        assert_eq!(
+            GuestMemoryMap::from_regions(&regions[..1]).unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn finalize_only_once() {
+        let mut state = TvmState::TvmInitializing;
+        state.finalize().unwrap();
+        assert_eq!(state, TvmState::TvmRunnable);
+        assert!(state.finalize().is_err());
+    }
+
+    #[test]
+    fn export_only_once() {
+        let mut state = TvmState::TvmInitializing;
+        assert!(state.export().is_err());
+        state.finalize().unwrap();
+        state.export().unwrap();
+        assert_eq!(state, TvmState::TvmExported);
+        assert!(state.export().is_err());
+        assert!(state.finalize().is_err());
+    }
+}
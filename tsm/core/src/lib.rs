@@ -0,0 +1,32 @@
+//! Hardware-independent core of the TSM hypervisor state machine.
+//!
+//! The TSM binary cannot run on the host (RISC-V assembly, H-extension CSRs, raw pointers into
+//! confidential memory), so the logic that decides whether a COVH call is legal lives here:
+//! the decoding of the host arguments, confidential memory ownership, the guest memory layout of a
+//! TVM, its lifecycle state and the harts its vCPUs may run on.
+//! The TSM keeps its TVM in a `TvmLifecycle`, which sequences the convert/create/add/finalize/
+//! destroy calls. Physical memory is only touched through the `PhysMemory` trait, which lets
+//! `cargo test` drive whole sequences against an in-memory mock.
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+pub mod affinity;
+pub mod boot_fdt;
+pub mod covh;
+pub mod error;
+pub mod layout;
+pub mod lifecycle;
+pub mod memory;
+
+pub use affinity::VcpuAffinity;
+pub use covh::{CovhCall, CreateTvmParams};
+pub use error::{CoveError, CoveResult};
+pub use layout::{GuestMemoryMap, MemoryRegion, TvmState};
+pub use lifecycle::{TvmLifecycle, TvmSlot, TVM_ID};
+pub use memory::{ConfidentialBlock, ConfidentialMemory, PhysMemory, RawMemory};
+
+pub use common::sbi::PAGE_SIZE;
+
+/// Page directory of a TVM (SV39x4 root table plus the intermediate tables).
+pub const PAGE_DIRECTORY_SIZE: usize = 16 * 1024;
@@ -0,0 +1,518 @@
+//! Lifecycle of the TVM: the confidential pages converted by the host and the TVM built in them,
+//! from CREATE_TVM through the memory regions and pages added while it is initializing, to
+//! FINALIZE_TVM and DESTROY_TVM. This decides whether a call is legal and which pages the TVM owns;
+//! the TSM keeps the rest of the TVM (vCPUs, measurement, page table entries) in the `data` of
+//! its `TvmSlot`.
+
+use core::ops::{Deref, DerefMut};
+
+use common::sbi::MeasuredPageDesc;
+
+use crate::{
+    memory::pages_to_bytes, ConfidentialMemory, CoveError, CoveResult, CreateTvmParams,
+    GuestMemoryMap, PhysMemory, TvmState, PAGE_DIRECTORY_SIZE, PAGE_SIZE,
+};
+
+/// Id of the TVM: the TSM runs a single TVM.
+pub const TVM_ID: usize = 1;
+
+/// The TVM as seen by the lifecycle, and the TSM state of the TVM in `data`.
+pub struct TvmSlot<T> {
+    pub id: usize,
+    /// Page directory (`PAGE_DIRECTORY_SIZE` bytes), owned by the TVM
+    pub page_table_addr: usize,
+    /// State page, owned by the TVM
+    pub state_addr: usize,
+    /// Pages sealed in host memory: only the working set is confidential
+    pub encrypted: bool,
+    pub memory_regions: GuestMemoryMap,
+    pub state: TvmState,
+    pub data: T,
+}
+
+impl<T> Deref for TvmSlot<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.data
+    }
+}
+
+impl<T> DerefMut for TvmSlot<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.data
+    }
+}
+
+/// Confidential memory and the TVM living in it.
+pub struct TvmLifecycle<T> {
+    confidential_memory: ConfidentialMemory,
+    tvm: Option<TvmSlot<T>>,
+}
+
+impl<T> Default for TvmLifecycle<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> TvmLifecycle<T> {
+    pub const fn new() -> Self {
+        Self {
+            confidential_memory: ConfidentialMemory::new(),
+            tvm: None,
+        }
+    }
+
+    pub fn confidential_memory(&self) -> &ConfidentialMemory {
+        &self.confidential_memory
+    }
+
+    pub fn confidential_memory_mut(&mut self) -> &mut ConfidentialMemory {
+        &mut self.confidential_memory
+    }
+
+    /// The TVM, in any state.
+    pub fn tvm(&self) -> Option<&TvmSlot<T>> {
+        self.tvm.as_ref()
+    }
+
+    pub fn tvm_mut(&mut self) -> Option<&mut TvmSlot<T>> {
+        self.tvm.as_mut()
+    }
+
+    /// The TVM `tvm_id`, in any state.
+    pub fn get(&self, tvm_id: usize) -> CoveResult<&TvmSlot<T>> {
+        match &self.tvm {
+            Some(tvm) if tvm.id == tvm_id => Ok(tvm),
+            Some(_) => Err(CoveError::InvalidParam("tvm id mismatch")),
+            None => Err(CoveError::InvalidParam("no tvm present")),
+        }
+    }
+
+    pub fn get_mut(&mut self, tvm_id: usize) -> CoveResult<&mut TvmSlot<T>> {
+        match &mut self.tvm {
+            Some(tvm) if tvm.id == tvm_id => Ok(tvm),
+            Some(_) => Err(CoveError::InvalidParam("tvm id mismatch")),
+            None => Err(CoveError::InvalidParam("no tvm present")),
+        }
+    }
+
+    /// The TVM `tvm_id`, if it was not finalized yet.
+    pub fn initializing_mut(&mut self, tvm_id: usize) -> CoveResult<&mut TvmSlot<T>> {
+        let tvm = self.get_mut(tvm_id)?;
+        if !tvm.state.is_initializing() {
+            return Err(CoveError::InvalidState("TVM must be in initializing state"));
+        }
+        Ok(tvm)
+    }
+
+    /// Handles `sbi_covh_convert_pages`.
+    pub fn convert_pages(&mut self, base_addr: usize, num_pages: usize) -> CoveResult<()> {
+        self.confidential_memory.convert(base_addr, num_pages)
+    }
+
+    /// Handles `sbi_covh_reclaim_pages`: the pages go back to the host zeroed. Nothing is
+    /// reclaimed while a TVM exists, whatever pages it owns.
+    pub fn reclaim_pages(
+        &mut self,
+        base_addr: usize,
+        num_pages: usize,
+        mem: &mut impl PhysMemory,
+    ) -> CoveResult<()> {
+        if self.tvm.is_some() {
+            return Err(CoveError::InvalidState("TVM is still running"));
+        }
+        self.confidential_memory.reclaim(base_addr, num_pages, mem)
+    }
+
+    /// Handles `sbi_covh_create_tvm`. The page directory, the state page and the working set of
+    /// an encrypted TVM must be confidential and not owned by another TVM: they become pages of
+    /// the TVM, and the page directory is zeroed. Nothing is claimed on failure.
+    pub fn create(
+        &mut self,
+        params: &CreateTvmParams,
+        data: T,
+        mem: &mut impl PhysMemory,
+    ) -> CoveResult<&mut TvmSlot<T>> {
+        if self.tvm.is_some() {
+            return Err(CoveError::AlreadyAvailable("already created tvm"));
+        }
+        params.check()?;
+
+        // No TVM exists, the pages of `TVM_ID` are the ones claimed here
+        if let Err(e) = self.claim_tvm_pages(params) {
+            self.confidential_memory.release(TVM_ID);
+            return Err(e);
+        }
+        mem.zero(params.page_table_addr, PAGE_DIRECTORY_SIZE);
+
+        Ok(self.tvm.insert(TvmSlot {
+            id: TVM_ID,
+            page_table_addr: params.page_table_addr,
+            state_addr: params.state_addr,
+            encrypted: params.working_set.is_some(),
+            memory_regions: GuestMemoryMap::new(),
+            state: TvmState::TvmInitializing,
+            data,
+        }))
+    }
+
+    fn claim_tvm_pages(&mut self, params: &CreateTvmParams) -> CoveResult<()> {
+        // Software-encrypted TVMs only get a small confidential working set
+        let working_set = params
+            .working_set
+            .map(|(addr, num_pages)| pages_to_bytes(num_pages).map(|size| (addr, size)))
+            .transpose()?;
+        let pages = [
+            Some((
+                params.page_table_addr,
+                PAGE_DIRECTORY_SIZE,
+                "page directory addr not in confidential memory",
+            )),
+            Some((
+                params.state_addr,
+                PAGE_SIZE,
+                "state addr not in confidential memory",
+            )),
+            working_set.map(|(addr, size)| (addr, size, "working set not in confidential memory")),
+        ];
+
+        for (addr, size, error) in pages.into_iter().flatten() {
+            if !self.confidential_memory.is_confidential(addr, size) {
+                return Err(CoveError::InvalidAddress(error));
+            }
+            self.confidential_memory.claim(addr, size, TVM_ID)?;
+        }
+        Ok(())
+    }
+
+    /// Handles `sbi_covh_add_tvm_memory_region`.
+    pub fn add_memory_region(&mut self, tvm_id: usize, gpa: usize, len: usize) -> CoveResult<()> {
+        let tvm = self.get_mut(tvm_id)?;
+        if !tvm.state.is_initializing() {
+            return Err(CoveError::InvalidState(
+                "cannot add memory region unless TVM_INITIALIZING",
+            ));
+        }
+        tvm.memory_regions.add_region(gpa, len)
+    }
+
+    /// Claims the destination of the measured pages before the TSM writes them, so the zero-page
+    /// pool leaves them alone. The pages of an encrypted TVM are sealed in host memory: they must
+    /// not be confidential, and nothing is claimed.
+    pub fn claim_measured_pages(
+        &mut self,
+        tvm_id: usize,
+        pages: &[MeasuredPageDesc],
+    ) -> CoveResult<&mut TvmSlot<T>> {
+        let tvm = self.get(tvm_id)?;
+        if !tvm.state.is_initializing() {
+            return Err(CoveError::InvalidState(
+                "cannot add memory region unless TVM_INITIALIZING",
+            ));
+        }
+
+        let encrypted = tvm.encrypted;
+        for page in pages {
+            if !page.dest_addr.is_multiple_of(PAGE_SIZE)
+                || !page.tvm_guest_gpa.is_multiple_of(PAGE_SIZE)
+            {
+                return Err(CoveError::InvalidAddress(
+                    "all addresses must be page-aligned",
+                ));
+            }
+            if !tvm.memory_regions.contains(page.tvm_guest_gpa, PAGE_SIZE) {
+                return Err(CoveError::InvalidAddress(
+                    "GPA range not within any memory region",
+                ));
+            }
+
+            if encrypted {
+                if self
+                    .confidential_memory
+                    .is_confidential(page.dest_addr, PAGE_SIZE)
+                {
+                    return Err(CoveError::InvalidAddress(
+                        "dest_addr must be in host memory for encrypted TVMs",
+                    ));
+                }
+            } else {
+                self.confidential_memory
+                    .check_owner(page.dest_addr, PAGE_SIZE, tvm_id)?;
+            }
+        }
+
+        if !encrypted {
+            for page in pages {
+                self.confidential_memory
+                    .claim(page.dest_addr, PAGE_SIZE, tvm_id)?;
+            }
+        }
+        self.get_mut(tvm_id)
+    }
+
+    /// Handles the checks of `sbi_covh_add_tvm_zero_pages`: the pages are claimed for the TVM, and
+    /// the ones the zero-page pool did not reach are zeroed. The caller maps them. Encrypted TVMs
+    /// keep their zero pages sealed in host memory instead.
+    pub fn claim_zero_pages(
+        &mut self,
+        tvm_id: usize,
+        base_addr: usize,
+        num_pages: usize,
+        gpa: usize,
+        mem: &mut impl PhysMemory,
+    ) -> CoveResult<&mut TvmSlot<T>> {
+        let tvm = self.get(tvm_id)?;
+        if !base_addr.is_multiple_of(PAGE_SIZE) || !gpa.is_multiple_of(PAGE_SIZE) {
+            return Err(CoveError::InvalidAddress(
+                "all addresses must be page-aligned",
+            ));
+        }
+        if tvm.encrypted {
+            return Err(CoveError::NotSupported(
+                "zero pages of encrypted TVMs are not confidential",
+            ));
+        }
+
+        let size = num_pages
+            .checked_mul(PAGE_SIZE)
+            .ok_or(CoveError::InvalidParam("invalid number of pages"))?;
+        if !tvm.memory_regions.contains(gpa, size) {
+            return Err(CoveError::InvalidAddress(
+                "GPA range not within any memory region",
+            ));
+        }
+
+        self.confidential_memory
+            .claim_zeroed(base_addr, size, tvm_id, mem)?;
+        self.get_mut(tvm_id)
+    }
+
+    /// Handles `sbi_covh_finalize_tvm`: the TVM becomes runnable, and no page or memory region can
+    /// be added anymore.
+    pub fn finalize(&mut self, tvm_id: usize) -> CoveResult<&mut TvmSlot<T>> {
+        let tvm = self.get_mut(tvm_id)?;
+        tvm.state.finalize()?;
+        Ok(tvm)
+    }
+
+    /// Handles the end of `sbi_covh_export_tvm`: the TVM never runs again, it keeps its pages
+    /// until it is destroyed.
+    pub fn export(&mut self, tvm_id: usize) -> CoveResult<()> {
+        self.get_mut(tvm_id)?.state.export()
+    }
+
+    /// Handles `sbi_covh_destroy_tvm`: the page directory is zeroed, and the pages of the TVM stay
+    /// confidential for the next TVM. Returns the destroyed TVM, if any.
+    pub fn destroy(&mut self, mem: &mut impl PhysMemory) -> Option<TvmSlot<T>> {
+        let tvm = self.tvm.take()?;
+        mem.zero(tvm.page_table_addr, PAGE_DIRECTORY_SIZE);
+        self.confidential_memory.release(tvm.id);
+        Some(tvm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::tests::MockMemory;
+    use common::measurement::HashAlgorithm;
+
+    const POOL: usize = 0x8A80_0000;
+    const POOL_PAGES: usize = 64;
+    const HOST_PAGE: usize = 0x8A00_0000;
+
+    fn params(page_table_addr: usize, state_addr: usize) -> CreateTvmParams {
+        CreateTvmParams {
+            page_table_addr,
+            state_addr,
+            measurement_alg: HashAlgorithm::Sha384,
+            policy: 0,
+            working_set: None,
+        }
+    }
+
+    fn page(dest_addr: usize, tvm_guest_gpa: usize) -> MeasuredPageDesc {
+        MeasuredPageDesc {
+            source_addr: HOST_PAGE,
+            dest_addr,
+            tvm_guest_gpa,
+        }
+    }
+
+    fn setup() -> (TvmLifecycle<()>, MockMemory) {
+        let mut lifecycle = TvmLifecycle::new();
+        lifecycle.convert_pages(POOL, POOL_PAGES).unwrap();
+        (lifecycle, MockMemory::new(POOL, POOL_PAGES * PAGE_SIZE))
+    }
+
+    #[test]
+    fn full_lifecycle() {
+        let (mut lifecycle, mut mem) = setup();
+        mem.fill(0xAA);
+
+        lifecycle
+            .create(&params(POOL, POOL + 4 * PAGE_SIZE), (), &mut mem)
+            .unwrap();
+        assert_eq!(lifecycle.confidential_memory().owner_of(POOL), Some(TVM_ID));
+        assert!(mem.is_zero(POOL, PAGE_DIRECTORY_SIZE));
+
+        lifecycle.add_memory_region(TVM_ID, 0x0, 0x21000).unwrap();
+        let pages = [
+            page(POOL + 5 * PAGE_SIZE, 0x0),
+            page(POOL + 6 * PAGE_SIZE, 0x20000),
+        ];
+        lifecycle.claim_measured_pages(TVM_ID, &pages).unwrap();
+        lifecycle
+            .claim_zero_pages(TVM_ID, POOL + 7 * PAGE_SIZE, 2, 0x1000, &mut mem)
+            .unwrap();
+        assert!(mem.is_zero(POOL + 7 * PAGE_SIZE, 2 * PAGE_SIZE));
+
+        lifecycle.finalize(TVM_ID).unwrap();
+        assert_eq!(lifecycle.get(TVM_ID).unwrap().state, TvmState::TvmRunnable);
+        assert!(lifecycle.finalize(TVM_ID).is_err());
+        let late = [page(POOL + 9 * PAGE_SIZE, 0x3000)];
+        assert!(lifecycle.claim_measured_pages(TVM_ID, &late).is_err());
+        assert!(lifecycle
+            .add_memory_region(TVM_ID, 0x100000, PAGE_SIZE)
+            .is_err());
+
+        // Pages cannot go back to the host while the TVM owns them
+        assert!(lifecycle.reclaim_pages(POOL, POOL_PAGES, &mut mem).is_err());
+
+        lifecycle.export(TVM_ID).unwrap();
+        assert_eq!(lifecycle.get(TVM_ID).unwrap().state, TvmState::TvmExported);
+        assert!(lifecycle.export(TVM_ID).is_err());
+        assert!(lifecycle.reclaim_pages(POOL, POOL_PAGES, &mut mem).is_err());
+
+        assert!(lifecycle.destroy(&mut mem).is_some());
+        lifecycle.reclaim_pages(POOL, POOL_PAGES, &mut mem).unwrap();
+        assert!(mem.is_zero(POOL, POOL_PAGES * PAGE_SIZE));
+        assert!(!lifecycle
+            .confidential_memory()
+            .is_confidential(POOL, PAGE_SIZE));
+    }
+
+    #[test]
+    fn create_requires_confidential_memory() {
+        let mut lifecycle = TvmLifecycle::new();
+        let mut mem = MockMemory::new(POOL, POOL_PAGES * PAGE_SIZE);
+        let p = params(POOL, POOL + 4 * PAGE_SIZE);
+        assert!(lifecycle.create(&p, (), &mut mem).is_err());
+
+        lifecycle.convert_pages(POOL, 4).unwrap();
+        // The state page is outside the converted block, the page directory is not kept
+        assert!(lifecycle.create(&p, (), &mut mem).is_err());
+        assert_eq!(lifecycle.confidential_memory().owner_of(POOL), None);
+        assert!(lifecycle.tvm().is_none());
+    }
+
+    #[test]
+    fn create_checks_the_params() {
+        let (mut lifecycle, mut mem) = setup();
+        // The page directory must be 16KB-aligned
+        let p = params(POOL + PAGE_SIZE, POOL + 8 * PAGE_SIZE);
+        assert!(lifecycle.create(&p, (), &mut mem).is_err());
+        assert!(lifecycle.tvm().is_none());
+    }
+
+    #[test]
+    fn create_twice_fails() {
+        let (mut lifecycle, mut mem) = setup();
+        let p = params(POOL, POOL + 4 * PAGE_SIZE);
+        lifecycle.create(&p, (), &mut mem).unwrap();
+        assert!(lifecycle.create(&p, (), &mut mem).is_err());
+    }
+
+    #[test]
+    fn calls_for_another_tvm_are_rejected() {
+        let (mut lifecycle, mut mem) = setup();
+        assert!(lifecycle.add_memory_region(TVM_ID, 0x0, PAGE_SIZE).is_err());
+
+        lifecycle
+            .create(&params(POOL, POOL + 4 * PAGE_SIZE), (), &mut mem)
+            .unwrap();
+        assert!(lifecycle
+            .add_memory_region(TVM_ID + 1, 0x0, PAGE_SIZE)
+            .is_err());
+        assert!(lifecycle.finalize(TVM_ID + 1).is_err());
+        assert!(lifecycle.get(TVM_ID).unwrap().state.is_initializing());
+    }
+
+    #[test]
+    fn pages_outside_the_layout_are_rejected() {
+        let (mut lifecycle, mut mem) = setup();
+        lifecycle
+            .create(&params(POOL, POOL + 4 * PAGE_SIZE), (), &mut mem)
+            .unwrap();
+        lifecycle
+            .add_memory_region(TVM_ID, 0x0, 2 * PAGE_SIZE)
+            .unwrap();
+
+        let outside = [page(POOL + 5 * PAGE_SIZE, 2 * PAGE_SIZE)];
+        assert!(lifecycle.claim_measured_pages(TVM_ID, &outside).is_err());
+        assert!(lifecycle
+            .claim_zero_pages(TVM_ID, POOL + 5 * PAGE_SIZE, 3, 0x0, &mut mem)
+            .is_err());
+        // Host memory cannot back a TVM page
+        let host = [page(HOST_PAGE, 0x0)];
+        assert!(lifecycle.claim_measured_pages(TVM_ID, &host).is_err());
+    }
+
+    #[test]
+    fn pages_of_another_tvm_are_rejected() {
+        let (mut lifecycle, mut mem) = setup();
+        lifecycle
+            .convert_pages(POOL + POOL_PAGES * PAGE_SIZE, 1)
+            .unwrap();
+        lifecycle
+            .confidential_memory_mut()
+            .claim(POOL + POOL_PAGES * PAGE_SIZE, PAGE_SIZE, TVM_ID + 1)
+            .unwrap();
+        lifecycle
+            .create(&params(POOL, POOL + 4 * PAGE_SIZE), (), &mut mem)
+            .unwrap();
+        lifecycle.add_memory_region(TVM_ID, 0x0, PAGE_SIZE).unwrap();
+
+        let pages = [page(POOL + POOL_PAGES * PAGE_SIZE, 0x0)];
+        assert!(lifecycle.claim_measured_pages(TVM_ID, &pages).is_err());
+    }
+
+    #[test]
+    fn encrypted_tvms_keep_their_pages_in_host_memory() {
+        let (mut lifecycle, mut mem) = setup();
+        let mut p = params(POOL, POOL + 4 * PAGE_SIZE);
+        p.working_set = Some((POOL + 8 * PAGE_SIZE, 4));
+        assert!(lifecycle.create(&p, (), &mut mem).unwrap().encrypted);
+        lifecycle.add_memory_region(TVM_ID, 0x0, PAGE_SIZE).unwrap();
+
+        let confidential = [page(POOL + 16 * PAGE_SIZE, 0x0)];
+        assert!(lifecycle
+            .claim_measured_pages(TVM_ID, &confidential)
+            .is_err());
+        lifecycle
+            .claim_measured_pages(TVM_ID, &[page(HOST_PAGE, 0x0)])
+            .unwrap();
+        assert!(lifecycle
+            .claim_zero_pages(TVM_ID, POOL + 16 * PAGE_SIZE, 1, 0x0, &mut mem)
+            .is_err());
+    }
+
+    #[test]
+    fn destroy_allows_a_new_tvm() {
+        let (mut lifecycle, mut mem) = setup();
+        let p = params(POOL, POOL + 4 * PAGE_SIZE);
+        lifecycle.create(&p, (), &mut mem).unwrap();
+        lifecycle.finalize(TVM_ID).unwrap();
+        mem.fill(0xAA);
+
+        lifecycle.destroy(&mut mem).unwrap();
+        assert!(mem.is_zero(POOL, PAGE_DIRECTORY_SIZE));
+        assert_eq!(lifecycle.confidential_memory().owner_of(POOL), None);
+        assert!(lifecycle.destroy(&mut mem).is_none());
+
+        let tvm = lifecycle.create(&p, (), &mut mem).unwrap();
+        assert!(tvm.state.is_initializing());
+    }
+}
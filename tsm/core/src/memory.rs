@@ -0,0 +1,378 @@
+//! Confidential memory: the pages converted by the host with `sbi_covh_convert_pages`. Ownership
+//! is tracked per converted block, a block is owned by the TVM whose page directory, state or
+//! pages live in it.
+//!
+//! The blocks nobody owns double as a pool of zero pages: the TSM zeroes them ahead of time, from
+//! the base of each block up to its `zeroed` mark, so that ADD_ZERO_PAGES only zeroes the pages
+//! the pool has not reached yet. A block leaves the pool when a TVM claims it and comes back with
+//! no zeroed page when the TVM is destroyed.
+
+use alloc::vec::Vec;
+use zeroize::Zeroize;
+
+use crate::{CoveError, CoveResult, PAGE_SIZE};
+
+/// Physical memory accesses of the hypervisor state machine.
+pub trait PhysMemory {
+    /// Zero `[addr, addr + len)`. The zeroing must not be optimized away.
+    fn zero(&mut self, addr: usize, len: usize);
+
+    /// Copy `[addr, addr + buf.len())` into `buf`.
+    fn read(&self, addr: usize, buf: &mut [u8]) -> CoveResult<()>;
+}
+
+/// Direct access to physical memory, used by the TSM.
+pub struct RawMemory;
+
+impl PhysMemory for RawMemory {
+    fn zero(&mut self, addr: usize, len: usize) {
+        unsafe {
+            let slice = core::slice::from_raw_parts_mut(addr as *mut u8, len);
+            slice.zeroize();
+        }
+    }
+
+    fn read(&self, addr: usize, buf: &mut [u8]) -> CoveResult<()> {
+        unsafe {
+            core::ptr::copy_nonoverlapping(addr as *const u8, buf.as_mut_ptr(), buf.len());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConfidentialBlock {
+    pub base: usize,
+    pub num_pages: usize,
+    /* TVM id */
+    pub owner: Option<usize>,
+    /* Pages from `base` zeroed since the block was converted or released */
+    pub zeroed: usize,
+}
+
+impl ConfidentialBlock {
+    fn end(&self) -> usize {
+        self.base + self.num_pages * PAGE_SIZE
+    }
+}
+
+#[derive(Default)]
+pub struct ConfidentialMemory {
+    blocks: Vec<ConfidentialBlock>,
+}
+
+impl ConfidentialMemory {
+    pub const fn new() -> Self {
+        Self { blocks: Vec::new() }
+    }
+
+    /// Track `num_pages` pages at `base` as confidential.
+    pub fn convert(&mut self, base: usize, num_pages: usize) -> CoveResult<()> {
+        if !base.is_multiple_of(PAGE_SIZE) || num_pages == 0 {
+            return Err(CoveError::InvalidAddress(
+                "base must be 4KB-aligned and num_pages non-zero",
+            ));
+        }
+        let end = range_end(base, pages_to_bytes(num_pages)?)?;
+        if self.overlaps(base, end - base) {
+            return Err(CoveError::AlreadyAvailable("pages already converted"));
+        }
+        self.blocks.push(ConfidentialBlock {
+            base,
+            num_pages,
+            owner: None,
+            zeroed: 0,
+        });
+        Ok(())
+    }
+
+    /// Index of the block containing `[addr, addr + size)`.
+    pub fn covering(&self, addr: usize, size: usize) -> Option<usize> {
+        let end = addr.checked_add(size)?;
+        self.blocks
+            .iter()
+            .position(|b| addr >= b.base && end <= b.end())
+    }
+
+    pub fn is_confidential(&self, addr: usize, size: usize) -> bool {
+        self.covering(addr, size).is_some()
+    }
+
+    /// True if `[addr, addr + size)` intersects any converted block.
+    pub fn overlaps(&self, addr: usize, size: usize) -> bool {
+        let end = addr.saturating_add(size);
+        self.blocks.iter().any(|b| addr < b.end() && b.base < end)
+    }
+
+    pub fn owner_of(&self, addr: usize) -> Option<usize> {
+        self.covering(addr, 1)
+            .and_then(|idx| self.blocks[idx].owner)
+    }
+
+    /// Fail unless `[addr, addr + size)` is confidential and not owned by another TVM.
+    pub fn check_owner(&self, addr: usize, size: usize, tvm_id: usize) -> CoveResult<()> {
+        self.available_block(addr, size, tvm_id).map(|_| ())
+    }
+
+    /// Assign the block containing `[addr, addr + size)` to `tvm_id`.
+    pub fn claim(&mut self, addr: usize, size: usize, tvm_id: usize) -> CoveResult<()> {
+        let idx = self.available_block(addr, size, tvm_id)?;
+        self.blocks[idx].owner = Some(tvm_id);
+        Ok(())
+    }
+
+    /// Assign the block containing `[addr, addr + size)` to `tvm_id` and zero the pages of the
+    /// range the zero-page pool did not reach.
+    pub fn claim_zeroed(
+        &mut self,
+        addr: usize,
+        size: usize,
+        tvm_id: usize,
+        mem: &mut impl PhysMemory,
+    ) -> CoveResult<()> {
+        let idx = self.available_block(addr, size, tvm_id)?;
+        let block = &mut self.blocks[idx];
+        let start = addr.max(block.base + block.zeroed * PAGE_SIZE);
+        if start < addr + size {
+            mem.zero(start, addr + size - start);
+        }
+        block.owner = Some(tvm_id);
+        Ok(())
+    }
+
+    /// Zero up to `budget` pages of the blocks nobody owns. Returns the number of pages zeroed.
+    pub fn fill_zero_pool(&mut self, budget: usize, mem: &mut impl PhysMemory) -> usize {
+        let mut left = budget;
+        for block in self.blocks.iter_mut().filter(|b| b.owner.is_none()) {
+            let count = (block.num_pages - block.zeroed).min(left);
+            if count > 0 {
+                mem.zero(block.base + block.zeroed * PAGE_SIZE, count * PAGE_SIZE);
+                block.zeroed += count;
+                left -= count;
+            }
+            if left == 0 {
+                break;
+            }
+        }
+        budget - left
+    }
+
+    fn available_block(&self, addr: usize, size: usize, tvm_id: usize) -> CoveResult<usize> {
+        let idx = self.covering(addr, size).ok_or(CoveError::InvalidAddress(
+            "address not in confidential memory",
+        ))?;
+        match self.blocks[idx].owner {
+            Some(owner) if owner != tvm_id => Err(CoveError::Denied(
+                "confidential memory already owned by another TVM",
+            )),
+            _ => Ok(idx),
+        }
+    }
+
+    /// Drop the ownership of every block owned by `tvm_id`, called when the TVM is destroyed. The
+    /// blocks go back to the zero-page pool with their content.
+    pub fn release(&mut self, tvm_id: usize) {
+        for block in self.blocks.iter_mut().filter(|b| b.owner == Some(tvm_id)) {
+            block.owner = None;
+            block.zeroed = 0;
+        }
+    }
+
+    /// Zero a converted block and give it back to the host. The block must match a previous
+    /// `convert` and must not be owned by a TVM.
+    pub fn reclaim(
+        &mut self,
+        base: usize,
+        num_pages: usize,
+        mem: &mut impl PhysMemory,
+    ) -> CoveResult<()> {
+        let idx = self
+            .blocks
+            .iter()
+            .position(|b| b.base == base && b.num_pages == num_pages)
+            .ok_or(CoveError::InvalidParam("No matching memory block"))?;
+        if self.blocks[idx].owner.is_some() {
+            return Err(CoveError::Denied("memory block still owned by a TVM"));
+        }
+
+        mem.zero(base, num_pages * PAGE_SIZE);
+        self.blocks.remove(idx);
+        Ok(())
+    }
+}
+
+pub(crate) fn pages_to_bytes(num_pages: usize) -> CoveResult<usize> {
+    num_pages
+        .checked_mul(PAGE_SIZE)
+        .ok_or(CoveError::InvalidAddress("address range overflow"))
+}
+
+/// End of `[addr, addr + size)`, failing on overflow.
+pub(crate) fn range_end(addr: usize, size: usize) -> CoveResult<usize> {
+    addr.checked_add(size)
+        .ok_or(CoveError::InvalidAddress("address range overflow"))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Physical memory backed by a host buffer starting at `base`.
+    pub struct MockMemory {
+        base: usize,
+        bytes: Vec<u8>,
+    }
+
+    impl MockMemory {
+        pub fn new(base: usize, len: usize) -> Self {
+            Self {
+                base,
+                bytes: vec![0; len],
+            }
+        }
+
+        pub fn fill(&mut self, value: u8) {
+            self.bytes.fill(value);
+        }
+
+        pub fn is_zero(&self, addr: usize, len: usize) -> bool {
+            let start = addr - self.base;
+            self.bytes[start..start + len].iter().all(|b| *b == 0)
+        }
+
+        pub fn write(&mut self, addr: usize, data: &[u8]) {
+            let start = addr - self.base;
+            self.bytes[start..start + data.len()].copy_from_slice(data);
+        }
+    }
+
+    impl PhysMemory for MockMemory {
+        fn zero(&mut self, addr: usize, len: usize) {
+            let start = addr - self.base;
+            self.bytes[start..start + len].fill(0);
+        }
+
+        fn read(&self, addr: usize, buf: &mut [u8]) -> CoveResult<()> {
+            let bytes = addr
+                .checked_sub(self.base)
+                .and_then(|start| self.bytes.get(start..start.checked_add(buf.len())?))
+                .ok_or(CoveError::InvalidAddress("read outside of memory"))?;
+            buf.copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    const BASE: usize = 0x8A80_0000;
+
+    #[test]
+    fn convert_rejects_invalid_ranges() {
+        let mut memory = ConfidentialMemory::new();
+        assert!(memory.convert(BASE + 1, 1).is_err());
+        assert!(memory.convert(BASE, 0).is_err());
+        assert!(memory.convert(usize::MAX & !(PAGE_SIZE - 1), 2).is_err());
+
+        memory.convert(BASE, 4).unwrap();
+        assert!(memory.convert(BASE + 3 * PAGE_SIZE, 4).is_err());
+        memory.convert(BASE + 4 * PAGE_SIZE, 4).unwrap();
+    }
+
+    #[test]
+    fn covering_needs_a_single_block() {
+        let mut memory = ConfidentialMemory::new();
+        memory.convert(BASE, 2).unwrap();
+        memory.convert(BASE + 2 * PAGE_SIZE, 2).unwrap();
+
+        assert_eq!(memory.covering(BASE + PAGE_SIZE, PAGE_SIZE), Some(0));
+        assert_eq!(memory.covering(BASE + 2 * PAGE_SIZE, PAGE_SIZE), Some(1));
+        assert_eq!(memory.covering(BASE + PAGE_SIZE, 2 * PAGE_SIZE), None);
+        assert_eq!(memory.covering(usize::MAX, 2), None);
+
+        assert!(memory.overlaps(BASE - PAGE_SIZE, PAGE_SIZE + 1));
+        assert!(!memory.overlaps(BASE + 4 * PAGE_SIZE, PAGE_SIZE));
+    }
+
+    #[test]
+    fn claim_and_release() {
+        let mut memory = ConfidentialMemory::new();
+        memory.convert(BASE, 4).unwrap();
+
+        memory.claim(BASE, PAGE_SIZE, 1).unwrap();
+        memory.claim(BASE + PAGE_SIZE, PAGE_SIZE, 1).unwrap();
+        assert!(memory.claim(BASE, PAGE_SIZE, 2).is_err());
+        assert!(memory.check_owner(BASE, PAGE_SIZE, 2).is_err());
+
+        memory.release(1);
+        assert_eq!(memory.owner_of(BASE), None);
+        memory.claim(BASE, PAGE_SIZE, 2).unwrap();
+    }
+
+    #[test]
+    fn zero_pool() {
+        let mut memory = ConfidentialMemory::new();
+        let mut mem = MockMemory::new(BASE, 4 * PAGE_SIZE);
+        mem.fill(0x5A);
+        memory.convert(BASE, 2).unwrap();
+        memory.convert(BASE + 2 * PAGE_SIZE, 2).unwrap();
+        memory.claim(BASE + 2 * PAGE_SIZE, PAGE_SIZE, 1).unwrap();
+
+        // Owned blocks are left alone
+        assert_eq!(memory.fill_zero_pool(1, &mut mem), 1);
+        assert_eq!(memory.fill_zero_pool(8, &mut mem), 1);
+        assert!(mem.is_zero(BASE, 2 * PAGE_SIZE));
+        assert!(!mem.is_zero(BASE + 2 * PAGE_SIZE, PAGE_SIZE));
+        assert_eq!(memory.fill_zero_pool(8, &mut mem), 0);
+
+        // Released blocks are dirty until the pool zeroes them again
+        mem.write(BASE, &[0x5A; PAGE_SIZE]);
+        memory.claim(BASE, PAGE_SIZE, 1).unwrap();
+        memory.release(1);
+        assert_eq!(memory.fill_zero_pool(1, &mut mem), 1);
+        assert!(mem.is_zero(BASE, PAGE_SIZE));
+    }
+
+    #[test]
+    fn claim_zeroed_past_the_pool() {
+        let mut memory = ConfidentialMemory::new();
+        let mut mem = MockMemory::new(BASE, 4 * PAGE_SIZE);
+        mem.fill(0x5A);
+        memory.convert(BASE, 4).unwrap();
+        memory.fill_zero_pool(1, &mut mem);
+
+        // Pages of the pool are not zeroed twice, the other ones are zeroed on the spot
+        mem.write(BASE, &[0x11]);
+        memory
+            .claim_zeroed(BASE, 3 * PAGE_SIZE, 1, &mut mem)
+            .unwrap();
+        assert!(!mem.is_zero(BASE, 1));
+        assert!(mem.is_zero(BASE + PAGE_SIZE, 2 * PAGE_SIZE));
+        assert!(!mem.is_zero(BASE + 3 * PAGE_SIZE, PAGE_SIZE));
+        assert_eq!(memory.owner_of(BASE), Some(1));
+        assert!(memory.claim_zeroed(BASE, PAGE_SIZE, 2, &mut mem).is_err());
+    }
+
+    #[test]
+    fn reclaim_zeroes_the_block() {
+        let mut memory = ConfidentialMemory::new();
+        let mut mem = MockMemory::new(BASE, 4 * PAGE_SIZE);
+        mem.fill(0x5A);
+        memory.convert(BASE, 2).unwrap();
+
+        assert!(memory.reclaim(BASE, 1, &mut mem).is_err());
+        memory.reclaim(BASE, 2, &mut mem).unwrap();
+        assert!(mem.is_zero(BASE, 2 * PAGE_SIZE));
+        assert!(!mem.is_zero(BASE + 2 * PAGE_SIZE, PAGE_SIZE));
+        assert!(memory.reclaim(BASE, 2, &mut mem).is_err());
+    }
+
+    #[test]
+    fn reclaim_refuses_owned_blocks() {
+        let mut memory = ConfidentialMemory::new();
+        let mut mem = MockMemory::new(BASE, PAGE_SIZE);
+        memory.convert(BASE, 1).unwrap();
+        memory.claim(BASE, PAGE_SIZE, 1).unwrap();
+
+        assert!(memory.reclaim(BASE, 1, &mut mem).is_err());
+        memory.release(1);
+        memory.reclaim(BASE, 1, &mut mem).unwrap();
+    }
+}
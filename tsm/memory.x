@@ -19,6 +19,7 @@ SECTIONS {
     KEEP(*(._secure_init));
     *(.text .text.*);
     *(.rodata .rodata.*);
+    *(.srodata .srodata.*);
   } > REGION_TEXT
 
   .data : ALIGN(8) {
@@ -5,6 +5,8 @@ pub mod instruction;
 
 /// Exception type in H extension.
 pub enum HvException {
+    /// Illegal instruction, e.g. an FP instruction while `sstatus.FS` is off
+    IllegalInstruction = 2,
     /// Environment call from VS-mode
     EcallFromVsMode = 10,
     /// Instruction guest-page fault
@@ -20,6 +22,7 @@ pub enum HvException {
 impl From<usize> for HvException {
     fn from(exception_num: usize) -> Self {
         match exception_num {
+            2 => HvException::IllegalInstruction,
             10 => HvException::EcallFromVsMode,
             20 => HvException::InstructionGuestPageFault,
             21 => HvException::LoadGuestPageFault,
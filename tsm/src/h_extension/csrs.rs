@@ -93,6 +93,90 @@ pub enum VsInterruptKind {
     Software = 1 << 2,
 }
 
+pub mod vsstatus {
+    //! Virtual supervisor status.
+    #![allow(dead_code)]
+
+    /// vsstatus register number.
+    const VSSTATUS: usize = 0x200;
+    /// Virtual supervisor status.
+    pub struct Vsstatus(usize);
+
+    impl_bits!(Vsstatus);
+    read_csr_as!(Vsstatus, 0x200);
+    write_csr_as!(0x200);
+}
+
+pub mod vsie {
+    //! Virtual supervisor interrupt enable.
+    #![allow(dead_code)]
+
+    /// vsie register number.
+    const VSIE: usize = 0x204;
+    /// Virtual supervisor interrupt enable.
+    pub struct Vsie(usize);
+
+    impl_bits!(Vsie);
+    read_csr_as!(Vsie, 0x204);
+    write_csr_as!(0x204);
+}
+
+pub mod vsscratch {
+    //! Virtual supervisor scratch.
+    #![allow(dead_code)]
+
+    /// vsscratch register number.
+    const VSSCRATCH: usize = 0x240;
+    /// Virtual supervisor scratch.
+    pub struct Vsscratch(usize);
+
+    impl_bits!(Vsscratch);
+    read_csr_as!(Vsscratch, 0x240);
+    write_csr_as!(0x240);
+}
+
+pub mod vsepc {
+    //! Virtual supervisor exception program counter.
+    #![allow(dead_code)]
+
+    /// vsepc register number.
+    const VSEPC: usize = 0x241;
+    /// Virtual supervisor exception program counter.
+    pub struct Vsepc(usize);
+
+    impl_bits!(Vsepc);
+    read_csr_as!(Vsepc, 0x241);
+    write_csr_as!(0x241);
+}
+
+pub mod vscause {
+    //! Virtual supervisor trap cause.
+    #![allow(dead_code)]
+
+    /// vscause register number.
+    const VSCAUSE: usize = 0x242;
+    /// Virtual supervisor trap cause.
+    pub struct Vscause(usize);
+
+    impl_bits!(Vscause);
+    read_csr_as!(Vscause, 0x242);
+    write_csr_as!(0x242);
+}
+
+pub mod vstval {
+    //! Virtual supervisor trap value.
+    #![allow(dead_code)]
+
+    /// vstval register number.
+    const VSTVAL: usize = 0x243;
+    /// Virtual supervisor trap value.
+    pub struct Vstval(usize);
+
+    impl_bits!(Vstval);
+    read_csr_as!(Vstval, 0x243);
+    write_csr_as!(0x243);
+}
+
 pub mod vstvec {
     //! Virtual supervisor trap handler base address.
     #![allow(dead_code)]
@@ -179,6 +263,7 @@ pub mod vsatp {
         Sv64 = 11,
     }
 
+    impl_bits!(Vsatp);
     read_csr_as!(Vsatp, 0x280);
     write_csr_as!(0x280);
 }
@@ -204,6 +289,23 @@ pub mod hstatus {
             bits = in(reg) 0b1000_0000
         );
     }
+
+    /// set VTW bit (Timeout Wait, 21 bit): WFI in VS-mode raises a virtual instruction exception
+    pub unsafe fn set_vtw() {
+        core::arch::asm!(
+            "
+            csrs hstatus, {bits}
+            ",
+            bits = in(reg) 1 << 21
+        );
+    }
+
+    /// set VGEIN field (Virtual Guest External Interrupt Number, 17:12 bits)
+    pub unsafe fn set_vgein(vgein: usize) {
+        let mask = 0x3f << 12;
+        let bits = (read().0 & !mask) | ((vgein << 12) & mask);
+        write(bits);
+    }
 }
 
 pub mod hedeleg {
@@ -248,6 +350,7 @@ pub mod hideleg {
 
     read_csr_as!(Hideleg, 0x603);
     write_csr_as!(0x603);
+    set_csr_as!(0x603);
 }
 
 pub mod hie {
@@ -263,6 +366,19 @@ pub mod hie {
     set_csr_from_enum!(VsInterruptKind, 0x604);
 }
 
+pub mod hip {
+    //! Hypervisor interrupt-pending register.
+    #![allow(dead_code)]
+
+    /// hip register number.
+    const HIP: usize = 0x644;
+    /// Hypervisor interrupt-pending register.
+    pub struct Hip(usize);
+
+    impl_bits!(Hip);
+    read_csr_as!(Hip, 0x644);
+}
+
 pub mod hcounteren {
     //! Hypervisor counter enable.
     #![allow(dead_code)]
@@ -308,26 +424,48 @@ pub mod henvcfg {
     /// Hypervisor environment configuration register.
     pub struct Henvcfg(usize);
 
-    /// set STCE (63 bit)
+    /// set STCE (63 bit), bit 31 of henvcfgh on RV32
     pub fn set_stce() {
+        #[cfg(target_pointer_width = "64")]
         unsafe {
             core::arch::asm!(
                 "
                 csrs henvcfg, {bits}
                 ",
-                bits = in(reg) 1u64 << 63
+                bits = in(reg) 1usize << 63
+            );
+        }
+        #[cfg(target_pointer_width = "32")]
+        unsafe {
+            core::arch::asm!(
+                "
+                csrs {henvcfgh}, {bits}
+                ",
+                henvcfgh = const HENVCFGH,
+                bits = in(reg) 1usize << 31
             );
         }
     }
 
-    /// set CDE (60 bit)
+    /// set CDE (60 bit), bit 28 of henvcfgh on RV32
     pub fn set_cde() {
+        #[cfg(target_pointer_width = "64")]
         unsafe {
             core::arch::asm!(
                 "
                 csrs henvcfg, {bits}
                 ",
-                bits = in(reg) 1u64 << 60
+                bits = in(reg) 1usize << 60
+            );
+        }
+        #[cfg(target_pointer_width = "32")]
+        unsafe {
+            core::arch::asm!(
+                "
+                csrs {henvcfgh}, {bits}
+                ",
+                henvcfgh = const HENVCFGH,
+                bits = in(reg) 1usize << 28
             );
         }
     }
@@ -339,7 +477,7 @@ pub mod henvcfg {
                 "
                 csrs henvcfg, {bits}
                 ",
-                bits = in(reg) 1u64 << 7
+                bits = in(reg) 1usize << 7
             );
         }
     }
@@ -351,7 +489,7 @@ pub mod henvcfg {
                 "
                 csrs henvcfg, {bits}
                 ",
-                bits = in(reg) 1u64 << 6
+                bits = in(reg) 1usize << 6
             );
         }
     }
@@ -363,20 +501,36 @@ pub mod hstateen0 {
 
     /// hstateen0 register number.
     const HSTATEEN0: usize = 0x60c;
+    /// hstateen0h register number, the upper half of hstateen0 on RV32.
+    #[cfg(target_pointer_width = "32")]
+    const HSTATEEN0H: usize = 0x61c;
     /// Hypervisor State Enable 0 Register.
     pub struct HstateEn0(usize);
 
     /// Enable all state except `C` bit
     pub fn all_state_set() {
         unsafe {
-            core::arch::asm!("csrs hstateen0, {all_set}", all_set = in(reg) u64::MAX);
+            core::arch::asm!("csrs hstateen0, {all_set}", all_set = in(reg) usize::MAX);
+            #[cfg(target_pointer_width = "32")]
+            core::arch::asm!(
+                "csrs {hstateen0h}, {all_set}",
+                hstateen0h = const HSTATEEN0H,
+                all_set = in(reg) usize::MAX
+            );
         }
     }
 
-    /// Clear `ENVCFG` (62 bit)
+    /// Clear `ENVCFG` (62 bit), bit 30 of hstateen0h on RV32
     pub fn clear_envcfg() {
         unsafe {
-            core::arch::asm!("csrc hstateen0, {bits}", bits = in(reg) 1u64 << 62);
+            #[cfg(target_pointer_width = "64")]
+            core::arch::asm!("csrc hstateen0, {bits}", bits = in(reg) 1usize << 62);
+            #[cfg(target_pointer_width = "32")]
+            core::arch::asm!(
+                "csrc {hstateen0h}, {bits}",
+                hstateen0h = const HSTATEEN0H,
+                bits = in(reg) 1usize << 30
+            );
         }
     }
 }
@@ -408,6 +562,7 @@ pub mod hvip {
     set_csr_from_enum!(VsInterruptKind, 0x645);
     clear_csr_from_enum!(VsInterruptKind, 0x645);
 
+    impl_bits!(Hvip);
     read_csr_as!(Hvip, 0x645);
     write_csr_as!(0x645);
 }
@@ -435,36 +590,67 @@ pub mod hgatp {
     /// Hypervisor guest address translation and protection.
     pub struct Hgatp(usize);
 
+    // MODE, VMID and PPN fields: 4, 14 and 44 bits on RV64, 1, 7 and 22 bits on RV32
+    #[cfg(target_pointer_width = "64")]
+    const MODE_SHIFT: usize = 60;
+    #[cfg(target_pointer_width = "64")]
+    const VMID_SHIFT: usize = 44;
+    #[cfg(target_pointer_width = "64")]
+    const VMID_MASK: usize = 0x3FFF;
+    #[cfg(target_pointer_width = "64")]
+    const PPN_MASK: usize = 0xFFF_FFFF_FFFF;
+    #[cfg(target_pointer_width = "32")]
+    const MODE_SHIFT: usize = 31;
+    #[cfg(target_pointer_width = "32")]
+    const VMID_SHIFT: usize = 22;
+    #[cfg(target_pointer_width = "32")]
+    const VMID_MASK: usize = 0x7F;
+    #[cfg(target_pointer_width = "32")]
+    const PPN_MASK: usize = 0x3F_FFFF;
+
     impl Hgatp {
         /// Return ppn.
         pub fn ppn(&self) -> usize {
-            self.0 & 0xfff_ffff_ffff // 44 bit
+            self.0 & PPN_MASK
         }
 
         /// Return translation mode.
         pub fn mode(&self) -> Mode {
-            match (self.0 >> 60) & 0b1111 {
+            match self.0 >> MODE_SHIFT {
                 0 => Mode::Bare,
+                #[cfg(target_pointer_width = "32")]
+                1 => Mode::Sv32x4,
+                #[cfg(target_pointer_width = "64")]
                 8 => Mode::Sv39x4,
+                #[cfg(target_pointer_width = "64")]
                 9 => Mode::Sv48x4,
+                #[cfg(target_pointer_width = "64")]
                 10 => Mode::Sv57x4,
                 _ => unreachable!(),
             }
         }
     }
 
-    /// Translation mode in G-stage.
+    /// Translation mode in G-stage, the modes of the XLEN of the hart.
     #[allow(clippy::module_name_repetitions)]
+    #[derive(Clone, Copy, PartialEq, Eq)]
     pub enum Mode {
         Bare = 0,
+        #[cfg(target_pointer_width = "32")]
+        Sv32x4 = 1,
+        #[cfg(target_pointer_width = "64")]
         Sv39x4 = 8,
+        #[cfg(target_pointer_width = "64")]
         Sv48x4 = 9,
+        #[cfg(target_pointer_width = "64")]
         Sv57x4 = 10,
     }
 
     /// Set Hgatp fields.
     pub fn set(mode: Mode, vmid: usize, ppn: usize) {
-        write(((0xF & (mode as usize)) << 60) | ((0x3FFF & vmid) << 44) | 0x0FFF_FFFF_FFFF & ppn);
+        write(
+            ((mode as usize) << MODE_SHIFT) | ((VMID_MASK & vmid) << VMID_SHIFT) | PPN_MASK & ppn,
+        );
     }
 
     impl_bits!(Hgatp);
@@ -1,35 +1,82 @@
-use alloc::vec::Vec;
+use alloc::{boxed::Box, vec, vec::Vec};
 use common::{
     attestation::{DiceLayer, TvmAttestationContext},
-    sbi::{sbi_call, COVG_EXTENSION, PAGE_SIZE},
+    config::{MAX_TVMS, MAX_VCPUS_PER_TVM},
+    measurement::{HashAlgorithm, MeasurementHasher},
+    reg_load, reg_store,
+    sbi::{
+        sbi_call, tvm_exit_unshare, ImsicInfo, MeasuredPageDesc, SbiRet, TvmGpaTranslation,
+        TvmVcpuTime, CONSOLE_RING_SIZE, COVG_CONSOLE_NOTIFY, COVG_EXTENSION, COVG_UNSHARE_MEMORY,
+        PAGE_SIZE, SBI_EXT_BASE, SBI_EXT_BASE_PROBE_EXT, SBI_EXT_SUPD_GET_HART_ID,
+        SBI_PROBE_AVAILABLE, SBI_SUPD_EXT_ID, TVM_EXIT_CONSOLE, TVM_EXIT_TAMPERED, TVM_EXIT_WFI,
+        TVM_GPA_KIND_MASK, TVM_GPA_KIND_MEASURED, TVM_GPA_KIND_SHARED, TVM_GPA_KIND_ZERO,
+        TVM_POLICY_DEBUG, TVM_POLICY_DEFAULT, TVM_POLICY_SHARED_PAGES, TVM_RUN_FAST_PATH,
+    },
+};
+use core::{
+    alloc::Layout,
+    sync::atomic::{AtomicUsize, Ordering},
 };
-use core::alloc::Layout;
 use elf::{abi::PT_LOAD, endian::AnyEndian, ElfBytes};
 use riscv::{
     interrupt::Trap,
     register::{
         sepc,
-        sstatus::{self, FS, SPP},
+        sstatus::{self, SPP},
         stvec::{self, Stvec},
     },
 };
-use sha2::{Digest, Sha384};
 use spin::Mutex;
-use zeroize::Zeroize;
+use tsm_core::{
+    covh::contiguous_measured_pages, CoveError, CoveResult, CreateTvmParams, GuestMemoryMap,
+    PhysMemory, RawMemory, TvmLifecycle, TvmSlot, TvmState, VcpuAffinity, PAGE_DIRECTORY_SIZE,
+};
 
 use crate::{
     h_extension::{
-        csrs::{hgatp, hstatus, htval, vsatp},
+        csrs::{
+            hgatp, hgeie, hideleg, hip, hstatus, hvip, vsatp, vscause, vsepc, vsie, vsscratch,
+            vsstatus, vstval, vstvec, VsInterruptKind,
+        },
         instruction::hfence_gvma_all,
         HvException,
     },
-    perf::{self, read_cycle},
-    println,
-    sbi::{self, handle_covg},
-    TsmState, MEASUREMENT,
+    perf::{read_cycle, read_time},
+    sbi::{handle_covg, TvmBlobs, TvmCounters},
+    stats, teeret, TsmState, ATTESTATION_CONTEXT, MEASUREMENT, STATE, TVM_BLOBS, TVM_CDI,
+    TVM_COUNTERS, TVM_POLICY, TVM_SHARED_MEMORY,
 };
 
-const PAGE_DIRECTORY_SIZE: usize = 16 * 1024;
+mod aia;
+mod boot_info;
+pub mod domain;
+mod encrypted;
+mod fpu;
+pub mod hsm;
+mod irq_routing;
+mod migration;
+mod sealed;
+
+use aia::TvmAia;
+use fpu::FpState;
+use irq_routing::InterruptRoutes;
+pub use migration::TvmImport;
+
+/// Encoding of `wfi`, reported in stval when the guest traps on it
+const WFI_INSTRUCTION: usize = 0x1050_0073;
+
+/// `TVM_RUN_*` flags of the RUN_TVM_VCPU in progress, checked by the trap handler
+static TVM_RUN_FLAGS: AtomicUsize = AtomicUsize::new(0);
+/// Id of the vCPU run by the RUN_TVM_VCPU in progress
+static TVM_RUN_VCPU: AtomicUsize = AtomicUsize::new(0);
+/// Physical hart of the RUN_TVM_VCPU in progress
+static TVM_RUN_HART: AtomicUsize = AtomicUsize::new(0);
+/// Harts the vCPUs are pinned to and run on, see `set_tvm_vcpu_affinity`
+static TVM_VCPU_AFFINITY: Mutex<VcpuAffinity> = Mutex::new(VcpuAffinity::new());
+
+/// Pages of the zero-page pool zeroed by a CONVERT_PAGES and after the other COVH calls
+const ZERO_POOL_CONVERT_PAGES: usize = 1024;
+pub const ZERO_POOL_IDLE_PAGES: usize = 16;
 
 const PTE_SIZE: usize = 8;
 const PTE_V: u64 = 1 << 0;
@@ -39,6 +86,7 @@ const PTE_X: u64 = 1 << 3;
 const PTE_U: u64 = 1 << 4;
 const PTE_A: u64 = 1 << 6;
 const PTE_D: u64 = 1 << 7;
+// The RSW bits of the leaves tell how the page was added, see `TVM_GPA_KIND_*`
 
 // -----------------------------
 // Helper functions for SV39
@@ -131,66 +179,60 @@ fn map_4k_leaf(root_pt: usize, gpa: usize, pa: usize, perms: u64) {
     }
 }
 
-/// Translates a Guest Physical Address (GPA) to a Host Physical Address (PA)
-/// by walking the SV39 page table structure starting at `root_pt`.
-/// Returns `None` if the address is not mapped.
-pub fn translate_gpa_to_pa(root_pt: usize, gpa: usize) -> Option<usize> {
-    let [vpn2, vpn1, vpn0] = make_vpn_sv39(gpa);
-
-    // --- Level 2 (Root) ---
-    // Calculate address of the PTE in the L2 table
-    let pte2_addr = root_pt + (vpn2 * 8);
-    let pte2 = unsafe { core::ptr::read_volatile(pte2_addr as *const u64) };
+/// Returns true if `gpa` fits the fixed page table layout used by `map_4k_leaf`.
+fn is_mappable_gpa(gpa: usize) -> bool {
+    let [vpn2, vpn1, _] = make_vpn_sv39(gpa);
+    vpn2 == 0 && 0x2000 + (vpn1 + 1) * PAGE_SIZE <= PAGE_DIRECTORY_SIZE
+}
 
-    // 1. Check Valid bit
-    if (pte2 & PTE_V) == 0 {
-        return None; // Page fault (not mapped)
-    }
+/// Remove the 4 KiB leaf mapping `gpa`, if any. Intermediate tables are left in place.
+fn unmap_4k_leaf(root_pt: usize, gpa: usize) {
+    let [vpn2, vpn1, vpn0] = make_vpn_sv39(gpa);
 
-    // 2. Check for Leaf (Huge Page 1GB)
-    // If R, W, or X is set, this is a leaf node, not a pointer to the next level.
-    if (pte2 & (PTE_R | PTE_W | PTE_X)) != 0 {
-        // PPN holds the 1GB aligned base address
-        let ppn = (pte2 >> 10) & 0x003F_FFFF_FFFF_FFFF;
-        // PA = (PPN << 12) | Offset within 1GB (30 bits)
-        return Some(ppn_to_pa(ppn) | (gpa & 0x3FFF_FFFF));
+    let pte2 = unsafe { core::ptr::read_volatile((root_pt + vpn2 * PTE_SIZE) as *const u64) };
+    if pte2 & PTE_V == 0 {
+        return;
     }
 
-    // --- Level 1 ---
-    // pte2 was a pointer to the L1 table
     let l1_base = ppn_to_pa(pte2 >> 10);
-    let pte1_addr = l1_base + (vpn1 * 8);
-    let pte1 = unsafe { core::ptr::read_volatile(pte1_addr as *const u64) };
-
-    if (pte1 & PTE_V) == 0 {
-        return None;
-    }
-
-    // Check for Leaf (Huge Page 2MB)
-    if (pte1 & (PTE_R | PTE_W | PTE_X)) != 0 {
-        let ppn = (pte1 >> 10) & 0x003F_FFFF_FFFF_FFFF;
-        // PA = (PPN << 12) | Offset within 2MB (21 bits)
-        return Some(ppn_to_pa(ppn) | (gpa & 0x1F_FFFF));
+    let pte1 = unsafe { core::ptr::read_volatile((l1_base + vpn1 * PTE_SIZE) as *const u64) };
+    if pte1 & PTE_V == 0 {
+        return;
     }
 
-    // --- Level 0 (4KB Page) ---
-    // pte1 was a pointer to the L0 table
-    let l0_base = ppn_to_pa(pte1 >> 10);
-    let pte0_addr = l0_base + (vpn0 * 8);
-    let pte0 = unsafe { core::ptr::read_volatile(pte0_addr as *const u64) };
-
-    if (pte0 & PTE_V) == 0 {
-        return None;
+    let pte0_addr = ppn_to_pa(pte1 >> 10) + vpn0 * PTE_SIZE;
+    unsafe {
+        core::ptr::write_volatile(pte0_addr as *mut u64, 0);
     }
+}
 
-    // This must be a leaf (standard 4KB page)
-    if (pte0 & (PTE_R | PTE_W | PTE_X)) == 0 {
-        return None; // Invalid format: L0 PTE must be a leaf
+/// Leaf PTE mapping `gpa` in the SV39 page tables at `root_pt`, with the mask of the offset in the
+/// page it maps.
+fn leaf_pte(root_pt: usize, gpa: usize) -> Option<(u64, usize)> {
+    let [vpn2, vpn1, vpn0] = make_vpn_sv39(gpa);
+    let mut table = root_pt;
+    for (vpn, offset_mask) in [(vpn2, 0x3FFF_FFFF), (vpn1, 0x1F_FFFF), (vpn0, 0xFFF)] {
+        let pte = unsafe { core::ptr::read_volatile((table + vpn * PTE_SIZE) as *const u64) };
+        if pte & PTE_V == 0 {
+            return None;
+        }
+        // R, W or X set: a leaf, possibly a 1GB or 2MB huge page
+        if pte & (PTE_R | PTE_W | PTE_X) != 0 {
+            return Some((pte, offset_mask));
+        }
+        table = ppn_to_pa(pte >> 10);
     }
+    // L0 PTEs must be leaves
+    None
+}
 
-    let ppn = (pte0 >> 10) & 0x003F_FFFF_FFFF_FFFF;
-    // PA = (PPN << 12) | Offset within 4KB (12 bits)
-    Some(ppn_to_pa(ppn) | (gpa & 0xFFF))
+/// Translates a Guest Physical Address (GPA) to a Host Physical Address (PA)
+/// by walking the SV39 page table structure starting at `root_pt`.
+/// Returns `None` if the address is not mapped.
+pub fn translate_gpa_to_pa(root_pt: usize, gpa: usize) -> Option<usize> {
+    let (pte, offset_mask) = leaf_pte(root_pt, gpa)?;
+    let ppn = (pte >> 10) & 0x003F_FFFF_FFFF_FFFF;
+    Some(ppn_to_pa(ppn) | (gpa & offset_mask))
 }
 
 /// Map a contiguous region of memory (multiple 4KB pages).
@@ -237,117 +279,256 @@ pub fn write_guest_memory(root_pt: usize, gpa: usize, data: &[u8]) -> Result<(),
     Ok(())
 }
 
+/// Split a contiguous measured-pages request into per-page descriptors.
+/// Copy each page into confidential memory and compute its digest. The digest is taken on the
+/// destination page so the host cannot change the content after it has been measured.
+fn copy_and_digest_pages(
+    alg: HashAlgorithm,
+    pages: &[MeasuredPageDesc],
+) -> CoveResult<Vec<Vec<u8>>> {
+    let mut hasher = alg
+        .hasher()
+        .ok_or(CoveError::NotSupported("unsupported measurement algorithm"))?;
+    let mut digests = Vec::with_capacity(pages.len());
+
+    for page in pages {
+        unsafe {
+            let src_ptr = page.source_addr as *const u8;
+            let dst_ptr = page.dest_addr as *mut u8;
+            core::ptr::copy_nonoverlapping(src_ptr, dst_ptr, PAGE_SIZE);
+
+            hasher.extend(core::slice::from_raw_parts(dst_ptr, PAGE_SIZE));
+        }
+        digests.push(hasher.finalize_reset());
+    }
+
+    Ok(digests)
+}
+
+/// Handles `sbi_covh_add_tvm_measured_pages` and its batched variant. Only validation and the
+/// final measurement/mapping step hold the TSM state lock: copying and hashing the pages, which
+/// dominates TVM creation time, is done page by page without it.
+pub fn add_tvm_measured_pages_unlocked(
+    state: &Mutex<Option<TsmState>>,
+    tvm_id: usize,
+    pages: &[MeasuredPageDesc],
+) -> CoveResult<()> {
+    let (alg, encrypted) = {
+        let mut lock = state.lock();
+        let state = lock
+            .as_mut()
+            .ok_or(CoveError::InvalidState("tsm not initialized"))?;
+        let alg = state.hypervisor.check_measured_pages(tvm_id, pages)?;
+        (alg, state.hypervisor.is_encrypted_tvm())
+    };
+
+    // Software-encrypted TVMs digest and seal the pages in TSM memory while committing
+    let digests = if encrypted {
+        Vec::new()
+    } else {
+        copy_and_digest_pages(alg, pages)?
+    };
+
+    let mut lock = state.lock();
+    let state = lock
+        .as_mut()
+        .ok_or(CoveError::InvalidState("tsm not initialized"))?;
+    state
+        .hypervisor
+        .commit_measured_pages(tvm_id, pages, &digests)
+}
+
+/// Measure and seal the pages of a software-encrypted TVM. Each page is copied into TSM memory
+/// first, so the digest and the ciphertext cover the same content.
+fn commit_encrypted_pages(tvm: &mut Tvm, pages: &[MeasuredPageDesc]) -> CoveResult<()> {
+    let mut hasher = tvm
+        .measurement_algorithm()
+        .hasher()
+        .ok_or(CoveError::NotSupported("unsupported measurement algorithm"))?;
+    let mut buf = vec![0u8; PAGE_SIZE];
+
+    for page in pages {
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                page.source_addr as *const u8,
+                buf.as_mut_ptr(),
+                PAGE_SIZE,
+            );
+        }
+        hasher.extend(&buf);
+        let digest = hasher.finalize_reset();
+
+        tvm.extend_measure(&page.tvm_guest_gpa.to_le_bytes());
+        tvm.extend_measure(&digest);
+
+        encrypted::add_page(page.tvm_guest_gpa, page.dest_addr, &mut buf)?;
+    }
+    Ok(())
+}
+
 // -----------------------------
 // Core TSM structures
 // -----------------------------
 
-#[derive(Clone, Copy, Debug)]
-pub struct MemoryRegion {
-    pub guest_gpa_base: usize,
-    pub num_pages: usize,
-}
+// The TSM holds a single TVM (`HypervisorState::lifecycle`)
+const _: () = assert!(MAX_TVMS == 1, "the TSM runs a single TVM");
 
 pub struct HypervisorState {
-    pub tvm: Option<Tvm>,
-    /* Base page address, num pages, vmid */
-    confidential_memory: Vec<(usize, usize, Option<usize>)>,
+    /* Converted pages and the TVM built in them */
+    pub lifecycle: TvmLifecycle<Tvm>,
+    /* Supervisor-level IMSIC, None if the platform has no AIA or no guest interrupt files */
+    imsic: Option<ImsicInfo>,
+    /* Guest interrupt files converted by the host */
+    imsic_files: Vec<usize>,
+    /* Exchange secret of the next IMPORT_TVM, see `migration` */
+    pending_import: Option<migration::PendingImport>,
+    /* Without the hypervisor extension the TSM runs in domain mode, see `domain` */
+    h_extension: bool,
+}
+
+/// G-stage mode of the TVM page tables (see `map_4k_leaf`). Sv39x4 is an RV64 mode: an RV32 TSM
+/// has no G-stage and runs in domain mode.
+#[cfg(target_pointer_width = "64")]
+const G_STAGE_MODE: Option<hgatp::Mode> = Some(hgatp::Mode::Sv39x4);
+#[cfg(target_pointer_width = "32")]
+const G_STAGE_MODE: Option<hgatp::Mode> = None;
+
+/// Whether the hypervisor extension of the hart is usable: `hgatp` is WARL, a write selecting a
+/// G-stage mode the hart does not implement has no effect. Only call it on harts with the
+/// extension, the CSR access traps otherwise.
+pub fn g_stage_supported() -> bool {
+    let Some(mode) = G_STAGE_MODE else {
+        return false;
+    };
+    hgatp::set(mode, 0, 0);
+    let supported = hgatp::read().mode() == mode;
+    hgatp::write(0);
+    supported
 }
 
 impl HypervisorState {
-    pub fn new() -> Self {
+    pub fn new(imsic: Option<ImsicInfo>, h_extension: bool) -> Self {
         Self {
-            tvm: None,
-            confidential_memory: Vec::new(),
+            lifecycle: TvmLifecycle::new(),
+            imsic: imsic.filter(|_| h_extension && hgeie::get_geilen() > 0),
+            imsic_files: Vec::new(),
+            pending_import: None,
+            h_extension,
+        }
+    }
+
+    /// False in domain mode.
+    pub fn h_extension(&self) -> bool {
+        self.h_extension
+    }
+
+    /// Handles `sbi_covh_tsm_local_fence`: drop the G-stage translations cached by the hart. All
+    /// the TVMs run with VMID 0, so the whole G-stage TLB is fenced. Nothing to fence in domain
+    /// mode.
+    pub fn tsm_local_fence(&self) {
+        if self.h_extension {
+            hfence_gvma_all();
         }
     }
+
     // TODO: Zero out the confidential pages
     pub fn add_confidential_pages(
         &mut self,
         base_page_addr: usize,
         num_pages: usize,
-    ) -> anyhow::Result<()> {
-        self.confidential_memory
-            .push((base_page_addr, num_pages, None));
+    ) -> CoveResult<()> {
+        self.lifecycle.convert_pages(base_page_addr, num_pages)?;
+        self.fill_zero_pool(ZERO_POOL_CONVERT_PAGES);
         Ok(())
     }
 
+    /// Zero up to `budget` pages ahead of ADD_ZERO_PAGES.
+    pub fn fill_zero_pool(&mut self, budget: usize) -> usize {
+        self.lifecycle
+            .confidential_memory_mut()
+            .fill_zero_pool(budget, &mut RawMemory)
+    }
+
+    /// True if any byte of `[addr, addr + len)` was converted to confidential memory. The host
+    /// must never get the TSM to write there.
+    pub fn overlaps_confidential_memory(&self, addr: usize, len: usize) -> bool {
+        self.lifecycle.confidential_memory().overlaps(addr, len)
+    }
+
+    /// True if the current TVM keeps its pages encrypted in host memory.
+    pub fn is_encrypted_tvm(&self) -> bool {
+        self.lifecycle.tvm().is_some_and(|tvm| tvm.encrypted)
+    }
+
     pub fn create_tvm(
         &mut self,
         attestation_context: TvmAttestationContext,
         page_table_addr: usize,
         state_addr: usize,
-    ) -> anyhow::Result<usize> {
-        if self.tvm.is_some() {
-            anyhow::bail!("already created tvm");
-        }
-
-        if page_table_addr % PAGE_DIRECTORY_SIZE != 0 {
-            anyhow::bail!("page table addr must be 16KB-aligned");
-        }
-
-        assert!(
-            state_addr < page_table_addr
-                || state_addr > (page_table_addr + PAGE_DIRECTORY_SIZE - 8)
-        );
-
-        let pd_block_idx = self
-            .find_confidential_block_idx_covering(page_table_addr, PAGE_DIRECTORY_SIZE)
-            .ok_or_else(|| anyhow::anyhow!("page directory addr not in confidential memory"))?;
-
-        let state_block_idx = self
-            .find_confidential_block_idx_covering(state_addr, PAGE_SIZE)
-            .ok_or_else(|| anyhow::anyhow!("state addr not in confidential memory"))?;
-
-        {
-            let (_base, _npages, owner) = self
-                .confidential_memory
-                .get_mut(pd_block_idx)
-                .ok_or_else(|| anyhow::anyhow!("invalid pd block idx"))?;
-            *owner = Some(1);
-        }
-        {
-            let (_base, _npages, owner) = self
-                .confidential_memory
-                .get_mut(state_block_idx)
-                .ok_or_else(|| anyhow::anyhow!("invalid state block idx"))?;
-            *owner = Some(1);
-        }
+        measurement_alg: HashAlgorithm,
+        policy: usize,
+        working_set: Option<(usize, usize)>,
+    ) -> CoveResult<usize> {
+        let hasher = measurement_alg
+            .hasher()
+            .ok_or(CoveError::NotSupported("unsupported measurement algorithm"))?;
+        let page_key = working_set
+            .map(|_| encrypted::page_key(attestation_context.cdi()))
+            .transpose()?;
+
+        let params = CreateTvmParams {
+            page_table_addr,
+            state_addr,
+            measurement_alg,
+            policy,
+            working_set,
+        };
+        let tvm = self.lifecycle.create(
+            &params,
+            Tvm::new(attestation_context, hasher),
+            &mut RawMemory,
+        )?;
 
-        unsafe {
-            let ptr = page_table_addr as *mut u8;
-            core::ptr::write_bytes(ptr, 0, PAGE_DIRECTORY_SIZE);
+        if let (Some((working_set_addr, working_set_pages)), Some(key)) = (working_set, page_key) {
+            encrypted::init(&key, page_table_addr, working_set_addr, working_set_pages);
         }
 
-        let tvm = Tvm::new(attestation_context, page_table_addr, state_addr);
-        let tvm_id = tvm.id;
-        self.tvm = Some(tvm);
-        Ok(tvm_id)
+        *TVM_SHARED_MEMORY.lock() = GuestMemoryMap::new();
+        // The policy is part of the measurement, so the TVM owner can check what the host asked
+        tvm.policy = policy;
+        tvm.extend_measure(&policy.to_le_bytes());
+        Ok(tvm.id)
     }
 
     pub fn finalize_tvm(
         &mut self,
-        _tvm_id: usize,
+        tvm_id: usize,
         entry_sepc: usize,
         entry_arg: usize,
         tvm_identity_addr: usize,
-    ) -> anyhow::Result<()> {
-        if let Some(tvm) = &mut self.tvm {
-            tvm.finalize(entry_sepc, entry_arg, tvm_identity_addr);
-        } else {
-            anyhow::bail!("no tvm present");
-        }
-
+    ) -> CoveResult<()> {
+        let tvm = self.lifecycle.initializing_mut(tvm_id)?;
+        tvm.data
+            .write_boot_info(&tvm.memory_regions, tvm.page_table_addr)?;
+        let tvm = self.lifecycle.finalize(tvm_id)?;
+        tvm.finalize(entry_sepc, entry_arg, tvm_identity_addr);
         Ok(())
     }
 
-    pub fn destroy_tvm(&mut self) -> anyhow::Result<()> {
-        if let Some(tvm) = &self.tvm {
-            unsafe {
-                let ptr = tvm.page_table_addr as *mut u8;
-                core::ptr::write_bytes(ptr, 0, PAGE_DIRECTORY_SIZE);
-            }
+    pub fn destroy_tvm(&mut self) -> CoveResult<()> {
+        if self
+            .lifecycle
+            .destroy(&mut RawMemory)
+            .is_some_and(|tvm| tvm.encrypted)
+        {
+            encrypted::clear();
         }
-        self.tvm = None;
+        TVM_COUNTERS.lock().take();
+        TVM_BLOBS.lock().take();
+        TVM_CDI.lock().take();
+        *TVM_SHARED_MEMORY.lock() = GuestMemoryMap::new();
+        hsm::reset(core::iter::empty());
+        *TVM_VCPU_AFFINITY.lock() = VcpuAffinity::new();
         Ok(())
     }
 
@@ -356,45 +537,9 @@ impl HypervisorState {
         tvm_id: usize,
         tvm_gpa_addr: usize,
         region_len_bytes: usize,
-    ) -> anyhow::Result<()> {
-        if self.tvm.is_none() {
-            anyhow::bail!("no tvm present");
-        }
-
-        let t = self.tvm.as_mut().unwrap();
-        if t.id != tvm_id {
-            anyhow::bail!("tvm id mismatch");
-        }
-
-        match t.state_enum {
-            TvmState::TvmInitializing => {}
-            _ => anyhow::bail!("cannot add memory region unless TVM_INITIALIZING"),
-        }
-
-        if (tvm_gpa_addr % PAGE_SIZE) != 0
-            || (region_len_bytes % PAGE_SIZE) != 0
-            || region_len_bytes == 0
-        {
-            anyhow::bail!("tvm_gpa_addr and region_len must be 4KB-aligned and non-zero");
-        }
-
-        let num_pages = region_len_bytes / PAGE_SIZE;
-        let new_a = tvm_gpa_addr;
-        let new_b = tvm_gpa_addr + region_len_bytes;
-
-        for r in t.memory_regions.iter() {
-            let r_a = r.guest_gpa_base;
-            let r_b = r.guest_gpa_base + r.num_pages * PAGE_SIZE;
-            if !(new_b <= r_a || r_b <= new_a) {
-                anyhow::bail!("region overlap with existing region");
-            }
-        }
-
-        t.memory_regions.push(MemoryRegion {
-            guest_gpa_base: tvm_gpa_addr,
-            num_pages,
-        });
-        Ok(())
+    ) -> CoveResult<()> {
+        self.lifecycle
+            .add_memory_region(tvm_id, tvm_gpa_addr, region_len_bytes)
     }
 
     pub fn add_tvm_measured_pages(
@@ -405,163 +550,173 @@ impl HypervisorState {
         tsm_page_type: usize,
         num_pages: usize,
         tvm_guest_gpa: usize,
-    ) -> anyhow::Result<()> {
-        if self.tvm.is_none() {
-            anyhow::bail!("no tvm present");
-        }
-
-        let tvm = self.tvm.as_mut().unwrap();
-        if tvm.id != tvm_id {
-            anyhow::bail!("tvm id mismatch");
+    ) -> CoveResult<()> {
+        if tsm_page_type != 0 {
+            return Err(CoveError::NotSupported("accepting 4k pages for now"));
         }
+        let pages = contiguous_measured_pages(source_addr, dest_addr, num_pages, tvm_guest_gpa)?;
 
-        match tvm.state_enum {
-            TvmState::TvmInitializing => {}
-            _ => anyhow::bail!("cannot add memory region unless TVM_INITIALIZING"),
-        }
+        let alg = self.check_measured_pages(tvm_id, &pages)?;
+        let digests = if self.is_encrypted_tvm() {
+            Vec::new()
+        } else {
+            copy_and_digest_pages(alg, &pages)?
+        };
+        self.commit_measured_pages(tvm_id, &pages, &digests)
+    }
 
-        assert_eq!(tsm_page_type, 0, "accepting 4k pages for now");
+    /// Validates a list of measured pages against the TVM layout and returns the measurement
+    /// algorithm the per-page digests must be computed with. The destination pages are claimed
+    /// before they are written, so the zero-page pool leaves them alone.
+    fn check_measured_pages(
+        &mut self,
+        tvm_id: usize,
+        pages: &[MeasuredPageDesc],
+    ) -> CoveResult<HashAlgorithm> {
+        let tvm = self.lifecycle.claim_measured_pages(tvm_id, pages)?;
+        Ok(tvm.measurement_algorithm())
+    }
 
-        // if (source_addr % PAGE_SIZE) != 0
-        if (dest_addr % PAGE_SIZE) != 0 || (tvm_guest_gpa % PAGE_SIZE) != 0 {
-            anyhow::bail!("all addresses must be page-aligned");
+    /// Extends the TVM measurement with the per-page digests computed by `copy_and_digest_pages`
+    /// and maps the pages in the TVM's page table. The TVM is re-validated since the state lock
+    /// may have been released since `check_measured_pages`.
+    fn commit_measured_pages(
+        &mut self,
+        tvm_id: usize,
+        pages: &[MeasuredPageDesc],
+        digests: &[Vec<u8>],
+    ) -> CoveResult<()> {
+        let tvm = self.lifecycle.initializing_mut(tvm_id)?;
+        if tvm.encrypted {
+            return commit_encrypted_pages(tvm, pages);
         }
 
-        // Verify the GPA range falls within a defined memory region
-        let gpa_end = tvm_guest_gpa + num_pages * PAGE_SIZE;
-        let mut found_region = false;
+        // The measurement covers the page placement as well as the page content
+        for (page, digest) in pages.iter().zip(digests) {
+            tvm.extend_measure(&page.tvm_guest_gpa.to_le_bytes());
+            tvm.extend_measure(digest);
 
-        for r in tvm.memory_regions.iter() {
-            let r_start = r.guest_gpa_base;
-            let r_end = r.guest_gpa_base + r.num_pages * PAGE_SIZE;
-
-            if tvm_guest_gpa >= r_start && gpa_end <= r_end {
-                found_region = true;
-                break;
-            }
-        }
-
-        if !found_region {
-            anyhow::bail!(
-                "GPA range 0x{:x}-0x{:x} not within any memory region",
-                tvm_guest_gpa,
-                gpa_end
+            map_4k_leaf(
+                tvm.page_table_addr,
+                page.tvm_guest_gpa,
+                page.dest_addr,
+                PTE_R | PTE_W | PTE_X | PTE_U | TVM_GPA_KIND_MEASURED,
             );
         }
 
-        // Verify dest_addr is in confidential memory
-        let dest_end = dest_addr + num_pages * PAGE_SIZE;
-        let mut in_confidential = false;
-
-        for (base, npages, owner) in self.confidential_memory.iter() {
-            let conf_start = *base;
-            let conf_end = base + npages * PAGE_SIZE;
-
-            if dest_addr >= conf_start && dest_end <= conf_end {
-                // Check if already owned by this TVM
-                if owner.is_some() && *owner != Some(tvm_id) {
-                    anyhow::bail!("confidential memory already owned by another TVM");
-                }
-                in_confidential = true;
-                break;
-            }
-        }
+        Ok(())
+    }
 
-        if !in_confidential {
-            anyhow::bail!("dest_addr not in confidential memory");
+    pub fn add_tvm_zero_pages(
+        &mut self,
+        tvm_id: usize,
+        base_page_address: usize,
+        tsm_page_type: usize,
+        num_pages: usize,
+        tvm_base_page_address: usize,
+    ) -> CoveResult<()> {
+        if tsm_page_type != 0 {
+            return Err(CoveError::NotSupported("accepting 4k pages for now"));
         }
 
-        // Copy the data in confidential memory and extend the measurement
-        unsafe {
-            let src_ptr = source_addr as *const u8;
-            let dst_ptr = dest_addr as *mut u8;
-            let bytes = num_pages * PAGE_SIZE;
-            core::ptr::copy_nonoverlapping(src_ptr, dst_ptr, bytes);
-
-            let content = core::slice::from_raw_parts(src_ptr, bytes);
-            tvm.extend_measure(content);
+        if self.lifecycle.get(tvm_id)?.encrypted {
+            if !base_page_address.is_multiple_of(PAGE_SIZE)
+                || !tvm_base_page_address.is_multiple_of(PAGE_SIZE)
+            {
+                return Err(CoveError::InvalidAddress(
+                    "all addresses must be page-aligned",
+                ));
+            }
+            let mut zero_page = vec![0u8; PAGE_SIZE];
+            for i in 0..num_pages {
+                zero_page.fill(0);
+                encrypted::add_page(
+                    tvm_base_page_address + i * PAGE_SIZE,
+                    base_page_address + i * PAGE_SIZE,
+                    &mut zero_page,
+                )?;
+            }
+            return Ok(());
         }
 
-        // Map each page in the TVM's page table
+        // dest_addr must be in confidential memory and not owned by another TVM. The pages the
+        // zero-page pool already covers are only mapped
+        let tvm = self.lifecycle.claim_zero_pages(
+            tvm_id,
+            base_page_address,
+            num_pages,
+            tvm_base_page_address,
+            &mut RawMemory,
+        )?;
         map_region(
             tvm.page_table_addr,
-            tvm_guest_gpa,
-            dest_addr,
+            tvm_base_page_address,
+            base_page_address,
             num_pages,
-            PTE_R | PTE_W | PTE_X | PTE_U,
+            PTE_R | PTE_W | PTE_X | PTE_U | TVM_GPA_KIND_ZERO,
         );
-
         Ok(())
     }
 
-    pub fn add_tvm_zero_pages(
+    /// Map host pages in the TVM, e.g. for the console ring. The pages are neither measured nor
+    /// confidential: they must not be converted and their GPA must be outside of the memory
+    /// regions of the TVM.
+    pub fn add_tvm_shared_pages(
         &mut self,
         tvm_id: usize,
         base_page_address: usize,
-        tsm_page_type: usize,
         num_pages: usize,
         tvm_base_page_address: usize,
-    ) -> anyhow::Result<()> {
-        if self.tvm.is_none() {
-            anyhow::bail!("no tvm present");
+    ) -> CoveResult<()> {
+        let tvm = self.lifecycle.get(tvm_id)?;
+        if tvm.encrypted {
+            return Err(CoveError::NotSupported(
+                "no shared pages for software-encrypted TVMs",
+            ));
         }
-        let tvm = self.tvm.as_mut().unwrap();
-        if tvm.id != tvm_id {
-            anyhow::bail!("tvm id mismatch");
+        if tvm.policy & TVM_POLICY_SHARED_PAGES == 0 {
+            return Err(CoveError::Denied("shared pages denied by the TVM policy"));
         }
-
-        assert_eq!(tsm_page_type, 0, "accepting 4k pages for now");
-        if (base_page_address % PAGE_SIZE) != 0 || (tvm_base_page_address % PAGE_SIZE) != 0 {
-            anyhow::bail!("all addresses must be page-aligned");
+        if !base_page_address.is_multiple_of(PAGE_SIZE)
+            || !tvm_base_page_address.is_multiple_of(PAGE_SIZE)
+        {
+            return Err(CoveError::InvalidAddress(
+                "all addresses must be page-aligned",
+            ));
         }
-        let mut in_confidential = false;
 
-        let dest_end = base_page_address + num_pages * PAGE_SIZE;
-        for (base, npages, owner) in self.confidential_memory.iter() {
-            let conf_start = *base;
-            let conf_end = base + npages * PAGE_SIZE;
-
-            if base_page_address >= conf_start && dest_end <= conf_end {
-                // Check if already owned by this TVM
-                if owner.is_some() && *owner != Some(tvm_id) {
-                    anyhow::bail!("confidential memory already owned by another TVM");
-                }
-                in_confidential = true;
-                break;
-            }
+        let size = num_pages
+            .checked_mul(PAGE_SIZE)
+            .ok_or(CoveError::InvalidParam("invalid number of pages"))?;
+        if self
+            .lifecycle
+            .confidential_memory()
+            .overlaps(base_page_address, size)
+        {
+            return Err(CoveError::InvalidAddress(
+                "shared pages must not be confidential",
+            ));
         }
-        if !in_confidential {
-            anyhow::bail!("dest_addr not in confidential memory");
+        if tvm.memory_regions.overlaps(tvm_base_page_address, size) {
+            return Err(CoveError::BadRange(
+                "shared pages overlap a TVM memory region",
+            ));
         }
-
-        // Verify the GPA range falls within a defined memory region
-        let gpa_end = tvm_base_page_address + num_pages * PAGE_SIZE;
-        let mut found_region = false;
-
-        for r in tvm.memory_regions.iter() {
-            let r_start = r.guest_gpa_base;
-            let r_end = r.guest_gpa_base + r.num_pages * PAGE_SIZE;
-
-            if tvm_base_page_address >= r_start && gpa_end <= r_end {
-                found_region = true;
-                break;
-            }
-        }
-
-        if !found_region {
-            anyhow::bail!(
-                "GPA range 0x{:x}-0x{:x} not within any memory region",
-                tvm_base_page_address,
-                gpa_end
-            );
+        if !(0..num_pages).all(|i| is_mappable_gpa(tvm_base_page_address + i * PAGE_SIZE)) {
+            return Err(CoveError::InvalidAddress(
+                "GPA range outside of the page table layout",
+            ));
         }
+        TVM_SHARED_MEMORY
+            .lock()
+            .add_region(tvm_base_page_address, size)?;
 
         map_region(
             tvm.page_table_addr,
             tvm_base_page_address,
             base_page_address,
             num_pages,
-            PTE_R | PTE_W | PTE_X | PTE_U,
+            PTE_R | PTE_W | PTE_U | TVM_GPA_KIND_SHARED,
         );
         Ok(())
     }
@@ -571,159 +726,231 @@ impl HypervisorState {
         tvm_id: usize,
         tvm_vcpu_id: usize,
         _tvm_state_page_addr: usize,
-    ) -> anyhow::Result<()> {
-        if self.tvm.is_none() {
-            anyhow::bail!("no tvm present");
+    ) -> CoveResult<()> {
+        if tvm_vcpu_id >= MAX_VCPUS_PER_TVM {
+            return Err(CoveError::InvalidParam("invalid vcpu id"));
         }
 
-        let tvm = self.tvm.as_mut().unwrap();
-        if tvm.id != tvm_id {
-            anyhow::bail!("tvm id mismatch");
+        // The vCPUs are part of the boot info, measured at finalize
+        let tvm = self.lifecycle.initializing_mut(tvm_id)?;
+        if tvm.vcpu(tvm_vcpu_id).is_some() {
+            return Err(CoveError::AlreadyAvailable("vcpu already created"));
         }
 
-        tvm.vcpu = Some(TvmVcpuState::new(tvm_vcpu_id));
+        tvm.vcpus.push(Box::new(TvmVcpuState::new(tvm_vcpu_id)));
         Ok(())
     }
 
-    pub fn run_tvm_vcpu(&self, tvm_id: usize, _vcpu_id: usize) -> anyhow::Result<!> {
-        if self.tvm.is_none() {
-            anyhow::bail!("no tvm present");
-        }
+    pub fn run_tvm_vcpu(&self, tvm_id: usize, vcpu_id: usize, flags: usize) -> CoveResult<!> {
+        let tvm = self.lifecycle.get(tvm_id)?;
+        let vcpu = tvm
+            .vcpu(vcpu_id)
+            .ok_or(CoveError::InvalidParam("no vcpu present"))?;
 
-        let tvm = self.tvm.as_ref().unwrap();
-        if tvm.id != tvm_id {
-            anyhow::bail!("tvm id mismatch");
+        match tvm.state {
+            TvmState::TvmRunnable => {}
+            _ => return Err(CoveError::InvalidState("TVM must be in runnable state")),
         }
-
-        if tvm.vcpu.is_none() {
-            anyhow::bail!("no vcpu present");
+        if tvm.encrypted && encrypted::tampered() {
+            return Err(CoveError::Denied(
+                "TVM stopped: a sealed page failed authentication",
+            ));
         }
-        let vcpu = tvm.vcpu.as_ref().unwrap();
 
-        match tvm.state_enum {
-            TvmState::TvmRunnable => {}
-            _ => anyhow::bail!("TVM must be in runnable state"),
+        if !self.h_extension {
+            return domain::run(tvm, vcpu_id);
         }
 
-        // Setup H-extension for guest execution
-        self.setup_h_extension(&tvm)?;
+        // A pinned vCPU only runs on its hart, and a vCPU on one hart at a time
+        let hart = current_hart()?;
+        TVM_VCPU_AFFINITY.lock().enter(vcpu_id, hart)?;
 
-        unsafe { vcpu.enter(tvm.entry_sepc, tvm.entry_arg) }
+        // Setup H-extension for guest execution
+        let start = self
+            .setup_h_extension(tvm, vcpu_id)
+            .and_then(|_| hsm::run(vcpu_id))
+            .inspect_err(|_| TVM_VCPU_AFFINITY.lock().exit(hart))?;
+        TVM_RUN_FLAGS.store(flags, Ordering::Relaxed);
+        TVM_RUN_VCPU.store(vcpu_id, Ordering::Relaxed);
+        TVM_RUN_HART.store(hart, Ordering::Relaxed);
+
+        // A vCPU started by the guest enters its start address, a vCPU which exited to the host
+        // continues where it stopped. All see the level interrupts signaled by the host
+        match (start, vcpu.trap_ctx.exit_csrs) {
+            (Some((addr, opaque)), _) => {
+                let hvip = tvm.irq_routes.hvip(vcpu_id, 0);
+                unsafe { vcpu.start(addr, opaque, hvip) }
+            }
+            (None, Some(mut csrs)) => {
+                csrs.hvip = tvm.irq_routes.hvip(vcpu_id, csrs.hvip);
+                unsafe { vcpu.resume(csrs) }
+            }
+            (None, None) => {
+                hvip::write(tvm.irq_routes.hvip(vcpu_id, hvip::read().bits()));
+                unsafe { vcpu.enter(tvm.entry_sepc, tvm.entry_arg) }
+            }
+        }
     }
 
-    pub fn reclaim_pages(
-        &mut self,
-        base_page_address: usize,
-        num_pages: usize,
-    ) -> anyhow::Result<()> {
-        if self.tvm.is_some() {
-            anyhow::bail!("TVM is still running");
+    /// Handles `sbi_covh_set_tvm_vcpu_affinity`: pin the vCPU to `hart`, or let it run on any hart
+    /// with `None`.
+    pub fn set_tvm_vcpu_affinity(
+        &self,
+        tvm_id: usize,
+        vcpu_id: usize,
+        hart: Option<usize>,
+    ) -> CoveResult<()> {
+        let tvm = self.lifecycle.get(tvm_id)?;
+        if tvm.vcpu(vcpu_id).is_none() {
+            return Err(CoveError::InvalidParam("no vcpu present"));
         }
+        TVM_VCPU_AFFINITY.lock().pin(vcpu_id, hart)
+    }
 
-        let idx = self
-            .confidential_memory
-            .iter()
-            .position(|(addr, npages, _)| *addr == base_page_address && *npages == num_pages)
-            .ok_or_else(|| anyhow::anyhow!("No matching memory block"))?;
+    /// Guest time of the vCPU, accumulated since its creation.
+    pub fn get_tvm_vcpu_time(&self, tvm_id: usize, vcpu_id: usize) -> CoveResult<TvmVcpuTime> {
+        let tvm = self.lifecycle.get(tvm_id)?;
+        let vcpu = tvm
+            .vcpu(vcpu_id)
+            .ok_or(CoveError::InvalidParam("no vcpu present"))?;
+        Ok(vcpu.trap_ctx.time.total)
+    }
 
-        let total_bytes = num_pages * PAGE_SIZE;
+    /// Handles `sbi_covh_tvm_translate_gpa`: the G-stage mapping of `gpa`. The backing address is
+    /// only reported for the debug TVMs.
+    pub fn translate_tvm_gpa(&self, tvm_id: usize, gpa: usize) -> CoveResult<TvmGpaTranslation> {
+        let tvm = self.lifecycle.get(tvm_id)?;
 
-        unsafe {
-            let slice = core::slice::from_raw_parts_mut(base_page_address as *mut u8, total_bytes);
-            // This ensures the compiler doesn't optimize away the zeroing operation
-            slice.zeroize();
-        }
-        self.confidential_memory.remove(idx);
+        let Some((pte, offset_mask)) = leaf_pte(tvm.page_table_addr, gpa) else {
+            return Ok(TvmGpaTranslation::default());
+        };
+        // V, R, W and X are the TVM_GPA_* permission bits
+        let flags = pte & (PTE_V | PTE_R | PTE_W | PTE_X | TVM_GPA_KIND_MASK);
+        let pa = match tvm.policy & TVM_POLICY_DEBUG {
+            0 => 0,
+            _ => ppn_to_pa((pte >> 10) & 0x003F_FFFF_FFFF_FFFF) | (gpa & offset_mask),
+        };
+        Ok(TvmGpaTranslation {
+            flags,
+            pa: pa as u64,
+        })
+    }
 
-        Ok(())
+    pub fn reclaim_pages(&mut self, base_page_address: usize, num_pages: usize) -> CoveResult<()> {
+        self.lifecycle
+            .reclaim_pages(base_page_address, num_pages, &mut RawMemory)
     }
 
     /// Setup H-extension CSRs for guest execution
-    fn setup_h_extension(&self, tvm: &Tvm) -> anyhow::Result<()> {
+    fn setup_h_extension(&self, tvm: &TvmSlot<Tvm>, vcpu_id: usize) -> CoveResult<()> {
         // Disable VS-mode address translation (guest manages its own)
         vsatp::write(0);
 
         // Setup guest physical address translation (G-stage)
-        hgatp::set(hgatp::Mode::Sv39x4, 0, tvm.page_table_addr >> 12);
+        let mode = G_STAGE_MODE.ok_or(CoveError::NotSupported("no G-stage translation"))?;
+        hgatp::set(mode, 0, tvm.page_table_addr >> 12);
+
+        // An idle vCPU gives the hart back to the host (see `TVM_EXIT_WFI`)
+        unsafe { hstatus::set_vtw() };
+
+        // Route the bound guest interrupt file to the vCPU as VS-level external interrupts
+        let binding = tvm.aia.as_ref().and_then(|aia| aia.active_binding());
+        match binding.filter(|b| b.vcpu_id == vcpu_id) {
+            Some(binding) => {
+                unsafe { hstatus::set_vgein(binding.vgein) };
+                hideleg::set(VsInterruptKind::External as usize);
+            }
+            None => unsafe { hstatus::set_vgein(0) },
+        }
 
         hfence_gvma_all();
 
         Ok(())
     }
-
-    /// Helper to find which confidential memory block contains an address range
-    fn find_confidential_block_idx_covering(&self, addr: usize, size: usize) -> Option<usize> {
-        let addr_end = addr + size;
-
-        for (idx, (base, npages, _)) in self.confidential_memory.iter().enumerate() {
-            let block_start = *base;
-            let block_end = base + npages * PAGE_SIZE;
-
-            if addr >= block_start && addr_end <= block_end {
-                return Some(idx);
-            }
-        }
-        None
-    }
 }
 
+/// Pages of the state the TSM keeps for a TVM and for each of its vCPUs, reported in `TsmInfo`
+pub const TVM_STATE_PAGES: usize = core::mem::size_of::<Tvm>().div_ceil(PAGE_SIZE);
+pub const TVM_VCPU_STATE_PAGES: usize = core::mem::size_of::<TvmVcpuState>().div_ceil(PAGE_SIZE);
+
 #[repr(C)]
 pub struct Tvm {
-    id: usize,
-    page_table_addr: usize,
-    state_addr: usize,
-    memory_regions: Vec<MemoryRegion>,
-    state_enum: TvmState,
-    vcpu: Option<TvmVcpuState>,
+    vcpus: Vec<Box<TvmVcpuState>>,
     entry_sepc: usize,
     entry_arg: usize,
     tvm_identity_addr: usize,
-    hasher: sha2::Sha384,
+    hasher: Box<dyn MeasurementHasher>,
     measure: Vec<u8>,
     attestation_context: TvmAttestationContext,
+    aia: Option<TvmAia>,
+    /* TVM_POLICY_* flags given at creation */
+    policy: usize,
+    /* Set by the host, reported in the boot-info page */
+    timebase_frequency: u64,
+    /* Boot-info page: physical address, guest physical address */
+    boot_info: Option<(usize, usize)>,
+    /* Host interrupt sources bound to the vCPU */
+    irq_routes: InterruptRoutes,
 }
 
 impl Tvm {
-    fn new(
-        attestation_context: TvmAttestationContext,
-        page_table_addr: usize,
-        state_addr: usize,
-    ) -> Self {
+    fn new(attestation_context: TvmAttestationContext, hasher: Box<dyn MeasurementHasher>) -> Self {
         Self {
-            id: 1,
-            page_table_addr,
-            state_addr,
-            memory_regions: Vec::new(),
-            state_enum: TvmState::TvmInitializing,
-            vcpu: None,
+            vcpus: Vec::new(),
             entry_sepc: 0,
             entry_arg: 0,
             tvm_identity_addr: 0,
-            hasher: Sha384::new(),
+            hasher,
             measure: Vec::new(),
             attestation_context,
+            aia: None,
+            policy: 0,
+            timebase_frequency: 0,
+            boot_info: None,
+            irq_routes: InterruptRoutes::new(),
         }
     }
 
+    /// Called once the lifecycle made the TVM runnable.
     fn finalize(&mut self, entry_sepc: usize, entry_arg: usize, tvm_identity_addr: usize) {
         // Save entry point
         self.entry_sepc = entry_sepc;
         self.entry_arg = entry_arg;
         self.tvm_identity_addr = tvm_identity_addr;
 
-        // Mark the TVM in a runnable state
-        self.state_enum = TvmState::TvmRunnable;
-
         // Finalize the Measurement
-        let old_hasher = core::mem::take(&mut self.hasher);
-        self.measure = old_hasher.finalize().to_vec();
-        self.hasher = Sha384::new();
+        self.measure = self.hasher.finalize_reset();
         let mut lock = MEASUREMENT.lock();
-        lock.replace(self.measure.clone());
+        lock.replace((self.hasher.algorithm(), self.measure.clone()));
+
+        // Guest services reachable while the vCPU runs
+        hsm::reset(self.vcpus.iter().map(|vcpu| vcpu.id));
+        TVM_POLICY.store(self.policy, Ordering::Relaxed);
+        let tvm_context = ATTESTATION_CONTEXT
+            .lock()
+            .as_ref()
+            .map(|tsm_context| tsm_context.compute_next(&self.measure));
+        let cdi = tvm_context.as_ref().map(|context| context.cdi());
+        TVM_COUNTERS.lock().replace(TvmCounters::new(cdi));
+        if let Some(cdi) = cdi {
+            TVM_BLOBS.lock().replace(TvmBlobs::new(cdi));
+            TVM_CDI.lock().replace(cdi.clone());
+        }
+    }
+
+    fn vcpu(&self, id: usize) -> Option<&TvmVcpuState> {
+        self.vcpus
+            .iter()
+            .find(|vcpu| vcpu.id == id)
+            .map(|vcpu| &**vcpu)
     }
 
     fn extend_measure(&mut self, data: &[u8]) {
-        self.hasher.update(data);
+        self.hasher.extend(data);
+    }
+
+    pub fn measurement_algorithm(&self) -> HashAlgorithm {
+        self.hasher.algorithm()
     }
 
     pub fn get_measure(&self) -> Vec<u8> {
@@ -731,24 +958,94 @@ impl Tvm {
     }
 }
 
-#[derive(Clone)]
-enum TvmState {
-    TvmInitializing = 0,
-    TvmRunnable = 1,
-}
-
 #[repr(C)]
 #[derive(Clone, Debug)]
 pub struct VmTrapContext {
-    // Guest registers x0-x31 (Offset 0-248)
+    // Guest registers x0-x31 (slots 0-31)
     // We save x0 as a placeholder to keep indexing simple: regs[i] == x(i)
     pub regs: [usize; 32],
-    // Hypervisor Stack Pointer (Offset 256)
+    // Hypervisor Stack Pointer (slot 32)
     pub hs_sp: usize,
+    // Guest CSRs saved when the vCPU exits to the host, None while it runs
+    pub exit_csrs: Option<VcpuCsrs>,
+    time: VcpuTimeAccounting,
+    // FP and vector registers of the guest, loaded on first use (see `fpu.rs`)
+    fp: FpState,
+}
+
+/// Guest time of a vCPU: from each entry in the vCPU to its exit to the host.
+#[derive(Clone, Debug, Default)]
+struct VcpuTimeAccounting {
+    /// `time` and `cycle` at the last entry, None while the host runs
+    entered: Option<(u64, u64)>,
+    total: TvmVcpuTime,
+}
+
+impl VcpuTimeAccounting {
+    fn enter(&mut self) {
+        self.entered = Some((read_time(), read_cycle()));
+        self.total.runs += 1;
+    }
+
+    fn exit(&mut self) {
+        if let Some((time, cycle)) = self.entered.take() {
+            let run = read_time().wrapping_sub(time);
+            self.total.guest_time += run;
+            self.total.guest_cycles += read_cycle().wrapping_sub(cycle);
+            self.total.longest_run = self.total.longest_run.max(run);
+        }
+    }
+}
+
+/// CSRs of a vCPU which exited to the host. The host runs with the same HS-mode CSRs, so the
+/// TSM saves them instead of trusting what it finds when the vCPU is resumed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VcpuCsrs {
+    sepc: usize,
+    hvip: usize,
+    vsstatus: usize,
+    vsie: usize,
+    vstvec: usize,
+    vsscratch: usize,
+    vsepc: usize,
+    vscause: usize,
+    vstval: usize,
+    vsatp: usize,
+}
+
+impl VcpuCsrs {
+    fn save() -> Self {
+        Self {
+            sepc: sepc::read(),
+            hvip: hvip::read().bits(),
+            vsstatus: vsstatus::read().bits(),
+            vsie: vsie::read().bits(),
+            vstvec: vstvec::read().bits(),
+            vsscratch: vsscratch::read().bits(),
+            vsepc: vsepc::read().bits(),
+            vscause: vscause::read().bits(),
+            vstval: vstval::read().bits(),
+            vsatp: vsatp::read().bits(),
+        }
+    }
+
+    fn restore(&self) {
+        unsafe { sepc::write(self.sepc) };
+        hvip::write(self.hvip);
+        vsstatus::write(self.vsstatus);
+        vsie::write(self.vsie);
+        vstvec::write(self.vstvec);
+        vsscratch::write(self.vsscratch);
+        vsepc::write(self.vsepc);
+        vscause::write(self.vscause);
+        vstval::write(self.vstval);
+        vsatp::write(self.vsatp);
+    }
 }
 
 #[repr(C, align(4))]
 struct TvmVcpuState {
+    id: usize,
     regs: [usize; 32],
     sstatus: usize,
     stvec: usize,
@@ -765,6 +1062,7 @@ struct TvmVcpuState {
 impl TvmVcpuState {
     fn new(id: usize) -> Self {
         let mut vcpu = Self {
+            id,
             regs: [0; 32],
             sstatus: 0,
             stvec: 0,
@@ -776,6 +1074,9 @@ impl TvmVcpuState {
             trap_ctx: VmTrapContext {
                 regs: [0; 32],
                 hs_sp: 0,
+                exit_csrs: None,
+                time: VcpuTimeAccounting::default(),
+                fp: FpState::new(),
             },
             hs_scratch_stack: [0; 1024 * 128],
         };
@@ -785,6 +1086,52 @@ impl TvmVcpuState {
     }
 
     unsafe fn enter(&self, entry_sepc: usize, _entry_arg: usize) -> ! {
+        self.prepare();
+
+        // Set guest PC
+        sepc::write(entry_sepc);
+
+        // TODO: restore vCPU context
+        core::arch::asm!(
+            r#"
+                fence.i
+                sret
+            "#,
+            options(readonly, noreturn, nostack)
+        )
+    }
+
+    /// Enter the vCPU at `entry_sepc` as a hart started by HSM: a0 = hartid, a1 = `opaque`, the
+    /// other registers and the VS CSRs cleared.
+    unsafe fn start(&self, entry_sepc: usize, opaque: usize, hvip: usize) -> ! {
+        let trap_ctx_mut = &self.trap_ctx as *const VmTrapContext as *mut VmTrapContext;
+        (*trap_ctx_mut).regs = [0; 32];
+        (*trap_ctx_mut).regs[10] = self.id;
+        (*trap_ctx_mut).regs[11] = opaque;
+        (*trap_ctx_mut).fp.clear();
+
+        let csrs = VcpuCsrs {
+            sepc: entry_sepc,
+            hvip,
+            ..Default::default()
+        };
+        self.resume(csrs)
+    }
+
+    /// Continue a vCPU which exited to the host, with the registers saved at the exit.
+    unsafe fn resume(&self, csrs: VcpuCsrs) -> ! {
+        let trap_ctx_mut = &self.trap_ctx as *const VmTrapContext as *mut VmTrapContext;
+
+        self.prepare();
+        csrs.restore();
+        (*trap_ctx_mut).exit_csrs = None;
+
+        core::arch::asm!("fence.i");
+        hyper_resume(trap_ctx_mut)
+    }
+
+    /// Setup the HS-mode CSRs to run the vCPU and to trap in `hyper_trap`.
+    unsafe fn prepare(&self) {
         let ctx = &self.trap_ctx as *const VmTrapContext as usize;
 
         // Calculate HS stack top (grows downward, so point to end of array)
@@ -793,6 +1140,7 @@ impl TvmVcpuState {
         // Initialize trap context
         let trap_ctx_mut = ctx as *mut VmTrapContext;
         (*trap_ctx_mut).hs_sp = hs_stack_top;
+        (*trap_ctx_mut).time.enter();
 
         // sscratch = &VmTrapContext
         core::arch::asm!("csrw sscratch, {}", in(reg) ctx);
@@ -800,7 +1148,7 @@ impl TvmVcpuState {
         sstatus::set_sum(); // Allow supervisor to access user pages
         sstatus::set_spp(SPP::Supervisor); // Return to S-mode (VS-mode with SPV=1)
         sstatus::set_sie(); // Enable interrupts
-        sstatus::set_fs(FS::Initial); // Enable FP state
+        fpu::disable(); // The first FP or vector instruction loads the guest registers
 
         // Hypervisor trap handler
         stvec::write(Stvec::from_bits(hyper_trap as *const fn() as usize));
@@ -811,18 +1159,6 @@ impl TvmVcpuState {
         core::arch::asm!("csrw hcounteren, {}", in(reg) hcounteren_val);
         // Enable virtualization (SPV=1 means we enter VS-mode on sret)
         hstatus::set_spv();
-
-        // Set guest PC
-        sepc::write(entry_sepc);
-
-        // TODO: restore vCPU context
-        core::arch::asm!(
-            r#"
-                fence.i
-                sret
-            "#,
-            options(readonly, noreturn, nostack)
-        )
     }
 }
 
@@ -834,83 +1170,93 @@ pub unsafe extern "C" fn hyper_trap() -> ! {
         // Swap Guest t6 (x31) with sscratch (which holds pointer to VmTrapContext)
         "csrrw t6, sscratch, t6",
         // Save Guest GPRs x1-x30 into the context
-        "sd x1,   8(t6)",  // ra
-        "sd x2,  16(t6)",  // sp
-        "sd x3,  24(t6)",  // gp
-        "sd x4,  32(t6)",  // tp
-        "sd x5,  40(t6)",  // t0
-        "sd x6,  48(t6)",  // t1
-        "sd x7,  56(t6)",  // t2
-        "sd x8,  64(t6)",  // s0
-        "sd x9,  72(t6)",  // s1
-        "sd x10, 80(t6)",  // a0
-        "sd x11, 88(t6)",  // a1
-        "sd x12, 96(t6)",  // a2
-        "sd x13, 104(t6)", // a3
-        "sd x14, 112(t6)", // a4
-        "sd x15, 120(t6)", // a5
-        "sd x16, 128(t6)", // a6
-        "sd x17, 136(t6)", // a7
-        "sd x18, 144(t6)", // s2
-        "sd x19, 152(t6)", // s3
-        "sd x20, 160(t6)", // s4
-        "sd x21, 168(t6)", // s5
-        "sd x22, 176(t6)", // s6
-        "sd x23, 184(t6)", // s7
-        "sd x24, 192(t6)", // s8
-        "sd x25, 200(t6)", // s9
-        "sd x26, 208(t6)", // s10
-        "sd x27, 216(t6)", // s11
-        "sd x28, 224(t6)", // t3
-        "sd x29, 232(t6)", // t4
-        "sd x30, 240(t6)", // t5
+        reg_store!(x1, 1(t6)),   // ra
+        reg_store!(x2, 2(t6)),   // sp
+        reg_store!(x3, 3(t6)),   // gp
+        reg_store!(x4, 4(t6)),   // tp
+        reg_store!(x5, 5(t6)),   // t0
+        reg_store!(x6, 6(t6)),   // t1
+        reg_store!(x7, 7(t6)),   // t2
+        reg_store!(x8, 8(t6)),   // s0
+        reg_store!(x9, 9(t6)),   // s1
+        reg_store!(x10, 10(t6)), // a0
+        reg_store!(x11, 11(t6)), // a1
+        reg_store!(x12, 12(t6)), // a2
+        reg_store!(x13, 13(t6)), // a3
+        reg_store!(x14, 14(t6)), // a4
+        reg_store!(x15, 15(t6)), // a5
+        reg_store!(x16, 16(t6)), // a6
+        reg_store!(x17, 17(t6)), // a7
+        reg_store!(x18, 18(t6)), // s2
+        reg_store!(x19, 19(t6)), // s3
+        reg_store!(x20, 20(t6)), // s4
+        reg_store!(x21, 21(t6)), // s5
+        reg_store!(x22, 22(t6)), // s6
+        reg_store!(x23, 23(t6)), // s7
+        reg_store!(x24, 24(t6)), // s8
+        reg_store!(x25, 25(t6)), // s9
+        reg_store!(x26, 26(t6)), // s10
+        reg_store!(x27, 27(t6)), // s11
+        reg_store!(x28, 28(t6)), // t3
+        reg_store!(x29, 29(t6)), // t4
+        reg_store!(x30, 30(t6)), // t5
         // Save the Guest's original t6 (currently in sscratch)
         "csrr t0, sscratch",
-        "sd t0, 248(t6)",
+        reg_store!(t0, 31(t6)),
         // --- 2. TRANSITION: Switch to HS-mode Stack ---
-        "ld sp, 256(t6)", // Load hs_sp
+        reg_load!(sp, 32(t6)), // Load hs_sp
         // Call the Rust handler.
         // a0 must be the pointer to VmTrapContext.
         "mv a0, t6",
         "call hyper_trap_handler_rust",
         // --- 3. EXIT: Restore Guest Context ---
         // Rust returns the pointer to VmTrapContext in a0
+        "j {resume}",
+        resume = sym hyper_resume,
+    )
+}
+
+/// Restore the guest GPRs from `ctx` and return to the guest.
+#[no_mangle]
+#[unsafe(naked)]
+unsafe extern "C" fn hyper_resume(ctx: *mut VmTrapContext) -> ! {
+    core::arch::naked_asm!(
         "mv t6, a0",
         // Restore GPRs x1-x30
-        "ld x1,   8(t6)",
-        "ld x2,  16(t6)",
-        "ld x3,  24(t6)",
-        "ld x4,  32(t6)",
-        "ld x5,  40(t6)",
-        "ld x6,  48(t6)",
-        "ld x7,  56(t6)",
-        "ld x8,  64(t6)",
-        "ld x9,  72(t6)",
-        "ld x10, 80(t6)",
-        "ld x11, 88(t6)",
-        "ld x12, 96(t6)",
-        "ld x13, 104(t6)",
-        "ld x14, 112(t6)",
-        "ld x15, 120(t6)",
-        "ld x16, 128(t6)",
-        "ld x17, 136(t6)",
-        "ld x18, 144(t6)",
-        "ld x19, 152(t6)",
-        "ld x20, 160(t6)",
-        "ld x21, 168(t6)",
-        "ld x22, 176(t6)",
-        "ld x23, 184(t6)",
-        "ld x24, 192(t6)",
-        "ld x25, 200(t6)",
-        "ld x26, 208(t6)",
-        "ld x27, 216(t6)",
-        "ld x28, 224(t6)",
-        "ld x29, 232(t6)",
-        "ld x30, 240(t6)",
+        reg_load!(x1, 1(t6)),
+        reg_load!(x2, 2(t6)),
+        reg_load!(x3, 3(t6)),
+        reg_load!(x4, 4(t6)),
+        reg_load!(x5, 5(t6)),
+        reg_load!(x6, 6(t6)),
+        reg_load!(x7, 7(t6)),
+        reg_load!(x8, 8(t6)),
+        reg_load!(x9, 9(t6)),
+        reg_load!(x10, 10(t6)),
+        reg_load!(x11, 11(t6)),
+        reg_load!(x12, 12(t6)),
+        reg_load!(x13, 13(t6)),
+        reg_load!(x14, 14(t6)),
+        reg_load!(x15, 15(t6)),
+        reg_load!(x16, 16(t6)),
+        reg_load!(x17, 17(t6)),
+        reg_load!(x18, 18(t6)),
+        reg_load!(x19, 19(t6)),
+        reg_load!(x20, 20(t6)),
+        reg_load!(x21, 21(t6)),
+        reg_load!(x22, 22(t6)),
+        reg_load!(x23, 23(t6)),
+        reg_load!(x24, 24(t6)),
+        reg_load!(x25, 25(t6)),
+        reg_load!(x26, 26(t6)),
+        reg_load!(x27, 27(t6)),
+        reg_load!(x28, 28(t6)),
+        reg_load!(x29, 29(t6)),
+        reg_load!(x30, 30(t6)),
         // Restore Guest t6 and set up sscratch for next trap
-        "ld t0, 248(t6)",    // Load saved Guest t6 into t0
-        "csrw sscratch, t6", // Put VmTrapContext pointer back into sscratch
-        "mv t6, t0",         // Finally restore Guest t6
+        reg_load!(t0, 31(t6)), // Load saved Guest t6 into t0
+        "csrw sscratch, t6",   // Put VmTrapContext pointer back into sscratch
+        "mv t6, t0",           // Finally restore Guest t6
         "sret",
     )
 }
@@ -930,20 +1276,30 @@ extern "C" fn hyper_trap_handler_rust(ctx: *mut VmTrapContext) -> *mut VmTrapCon
             _ => match HvException::from(scause.code()) {
                 HvException::EcallFromVsMode => {
                     let regs = unsafe { &mut (*ctx).regs };
+                    stats::count(&stats::GUEST_CALLS, 1);
 
                     // 1.Check if the call was a CoVE-G
-                    let sbi_ret = if regs[17] == COVG_EXTENSION {
-                        handle_covg(
-                            regs[17],
-                            regs[16],
-                            &[regs[10], regs[11], regs[12], regs[13], regs[14], regs[15]],
-                        )
+                    let args = [regs[10], regs[11], regs[12], regs[13], regs[14], regs[15]];
+                    let sbi_ret = if regs[17] == COVG_EXTENSION && regs[16] == COVG_CONSOLE_NOTIFY {
+                        console_notify(ctx, regs[10], sepc + 4)
+                    } else if regs[17] == COVG_EXTENSION && regs[16] == COVG_UNSHARE_MEMORY {
+                        unshare_memory(ctx, regs[10], regs[11], sepc + 4)
+                    } else if regs[17] == hsm::EXT_HSM {
+                        let vcpu_id = TVM_RUN_VCPU.load(Ordering::Relaxed);
+                        hsm::handle(ctx, vcpu_id, regs[16], &args, sepc)
+                    } else if regs[17] == COVG_EXTENSION {
+                        handle_covg(regs[17], regs[16], &args)
+                    } else if regs[17] == SBI_EXT_BASE
+                        && regs[16] == SBI_EXT_BASE_PROBE_EXT
+                        && (regs[10] == COVG_EXTENSION || regs[10] == hsm::EXT_HSM)
+                    {
+                        // CoVE-G and the HSM of the guest are implemented here
+                        SbiRet {
+                            a0: 0,
+                            a1: SBI_PROBE_AVAILABLE as isize,
+                        }
                     } else {
-                        sbi_call(
-                            regs[17],
-                            regs[16],
-                            &[regs[10], regs[11], regs[12], regs[13], regs[14], regs[15]],
-                        )
+                        sbi_call(regs[17], regs[16], &args)
                     };
 
                     // 3. Write return values back to Guest a0, a1
@@ -958,11 +1314,30 @@ extern "C" fn hyper_trap_handler_rust(ctx: *mut VmTrapContext) -> *mut VmTrapCon
                     }
                 }
 
+                // stval holds the trapping instruction. On the fast path, a WFI which would not
+                // stall goes straight back to the guest.
+                HvException::VirtualInstruction if stval == WFI_INSTRUCTION => {
+                    unsafe { riscv::register::sepc::write(sepc + 4) };
+                    let fast_path = TVM_RUN_FLAGS.load(Ordering::Relaxed) & TVM_RUN_FAST_PATH != 0;
+                    if !fast_path || !vcpu_interrupt_pending() {
+                        exit_to_host(ctx, TVM_EXIT_WFI)
+                    }
+                }
+
+                // The guest registers are loaded and the instruction retried
+                HvException::IllegalInstruction if fpu::load_on_trap(unsafe { &(*ctx).fp }) => {}
+
                 HvException::InstructionGuestPageFault
                 | HvException::LoadGuestPageFault
                 | HvException::StoreAmoGuestPageFault => {
                     // 'stval' holds the Guest Physical Address that caused the fault
-                    handle_page_fault(stval);
+                    stats::count(&stats::GUEST_PAGE_FAULTS, 1);
+                    match encrypted::handle_page_fault(stval) {
+                        Ok(true) => {}
+                        Ok(false) => handle_page_fault(stval),
+                        // The vCPU cannot go on without the page
+                        Err(_) => exit_to_host(ctx, TVM_EXIT_TAMPERED),
+                    }
                     // We do NOT increment sepc; we want to retry the instruction
                 }
                 _ => {
@@ -979,6 +1354,91 @@ extern "C" fn hyper_trap_handler_rust(ctx: *mut VmTrapContext) -> *mut VmTrapCon
     ctx
 }
 
+/// The guest has written in the console ring at `ring_gpa`: exit to the host, which drains the ring
+/// and resumes the vCPU at `resume_sepc`. Returns only if the ring is not in shared memory.
+fn console_notify(ctx: *mut VmTrapContext, ring_gpa: usize, resume_sepc: usize) -> SbiRet {
+    if TVM_POLICY.load(Ordering::Relaxed) & TVM_POLICY_DEBUG == 0
+        || !ring_gpa.is_multiple_of(PAGE_SIZE)
+        || !TVM_SHARED_MEMORY
+            .lock()
+            .contains(ring_gpa, CONSOLE_RING_SIZE)
+    {
+        return SbiRet { a0: -1, a1: 0 };
+    }
+
+    let regs = unsafe { &mut (*ctx).regs };
+    regs[10] = 0;
+    regs[11] = 0;
+    unsafe { riscv::register::sepc::write(resume_sepc) };
+    exit_to_host(ctx, TVM_EXIT_CONSOLE | ring_gpa)
+}
+
+/// The guest gives back the shared pages `[gpa, gpa + size)`: zero them, so that nothing the guest
+/// left there outlives the sharing, unmap them and exit to the host, which drops its mappings of
+/// the range and resumes the vCPU at `resume_sepc`. Returns only if the range is not shared.
+fn unshare_memory(ctx: *mut VmTrapContext, gpa: usize, size: usize, resume_sepc: usize) -> SbiRet {
+    if TVM_SHARED_MEMORY.lock().remove(gpa, size).is_err() {
+        return SbiRet { a0: -1, a1: 0 };
+    }
+
+    let root_pt = hgatp::read().ppn() << 12;
+    let num_pages = size / PAGE_SIZE;
+    for page_gpa in (0..num_pages).map(|i| gpa + i * PAGE_SIZE) {
+        if let Some(pa) = translate_gpa_to_pa(root_pt, page_gpa) {
+            RawMemory.zero(pa, PAGE_SIZE);
+        }
+        unmap_4k_leaf(root_pt, page_gpa);
+    }
+    hfence_gvma_all();
+
+    let regs = unsafe { &mut (*ctx).regs };
+    regs[10] = 0;
+    regs[11] = 0;
+    unsafe { riscv::register::sepc::write(resume_sepc) };
+    exit_to_host(ctx, tvm_exit_unshare(gpa, num_pages))
+}
+
+/// Whether an interrupt enabled in `vsie` is pending for the vCPU, IMSIC guest interrupts included.
+fn vcpu_interrupt_pending() -> bool {
+    let vs_interrupts = VsInterruptKind::External as usize
+        | VsInterruptKind::Timer as usize
+        | VsInterruptKind::Software as usize;
+    // The VS bits of hip are one above the S bits of vsie
+    hip::read().bits() & (vsie::read().bits() << 1) & vs_interrupts != 0
+}
+
+/// Save the vCPU, give the host its FP registers back and return `exit` to the host in a1. The
+/// next `run_tvm_vcpu` resumes the vCPU.
+fn exit_to_host(ctx: *mut VmTrapContext, exit: usize) -> ! {
+    stats::count_exit(exit);
+    TVM_VCPU_AFFINITY
+        .lock()
+        .exit(TVM_RUN_HART.load(Ordering::Relaxed));
+    unsafe {
+        (*ctx).time.exit();
+        (*ctx).exit_csrs = Some(VcpuCsrs::save());
+        fpu::unload(&mut (*ctx).fp);
+    }
+
+    // SAFETY: run_tvm_vcpu diverged into the guest while holding the lock, and nothing else runs
+    // in the TSM until the next TEECALL
+    unsafe { STATE.force_unlock() };
+
+    teeret(SbiRet {
+        a0: 0,
+        a1: exit as isize,
+    })
+}
+
+/// Physical hart the TSM runs on: only the TSM-driver knows it.
+fn current_hart() -> CoveResult<usize> {
+    let ret = sbi_call(SBI_SUPD_EXT_ID, SBI_EXT_SUPD_GET_HART_ID, &[0; 6]);
+    match ret.a0 {
+        0 => Ok(ret.a1 as usize),
+        _ => Err(CoveError::Failed("hart id unavailable")),
+    }
+}
+
 // Track ELF segments to know what to copy where
 struct LazySegment {
     vaddr: usize,
@@ -1005,15 +1465,20 @@ pub fn bootstrap_load_elf(
     pt_addr: usize,
     state_addr: usize,
     conf_pool_base: usize,
-) -> anyhow::Result<usize> {
+) -> CoveResult<usize> {
     let elf = ElfBytes::<AnyEndian>::minimal_parse(data)
-        .map_err(|e| anyhow::anyhow!("ELF parse error: {:?}", e))?;
+        .map_err(|_| CoveError::InvalidParam("ELF parse error"))?;
 
     // 1. Create TVM
     let attestation = state.attestation_context.compute_next(&[0; 32]);
-    let tvm_id = state
-        .hypervisor
-        .create_tvm(attestation, pt_addr, state_addr)?;
+    let tvm_id = state.hypervisor.create_tvm(
+        attestation,
+        pt_addr,
+        state_addr,
+        HashAlgorithm::default(),
+        TVM_POLICY_DEFAULT,
+        None,
+    )?;
 
     // 2. Define Guest RAM - MATCH LINKER SCRIPT (ORIGIN = 0x1000)
     let gpa_base = 0x0;
@@ -1024,7 +1489,7 @@ pub fn bootstrap_load_elf(
 
     let segments = elf
         .segments()
-        .ok_or_else(|| anyhow::anyhow!("No program headers"))?;
+        .ok_or(CoveError::InvalidParam("No program headers"))?;
     let mut current_conf_ptr = conf_pool_base;
     let mut highest_gpa_mapped = gpa_base;
 
@@ -1047,7 +1512,7 @@ pub fn bootstrap_load_elf(
             unsafe {
                 let scratchpad = alloc::alloc::alloc_zeroed(layout);
                 if scratchpad.is_null() {
-                    anyhow::bail!("TSM Out of Memory");
+                    return Err(CoveError::Failed("TSM Out of Memory"));
                 }
 
                 // Copy ELF data into scratchpad at the correct sub-page offset
@@ -1185,9 +1650,7 @@ fn handle_page_fault(gpa: usize) {
 
         // 5. Map the page into the Guest Page Table
         // Retrieve the root PPN from HGATP to find the page table location
-        let hgatp_val = hgatp::read().bits();
-        let root_ppn = hgatp_val & 0xFF_FFFF_FFFF_F;
-        let root_pt = (root_ppn << 12) as usize;
+        let root_pt = hgatp::read().ppn() << 12;
 
         // Map with full permissions (R/W/X/U)
         // Note: You must ensure map_4k_leaf is accessible here
@@ -1213,10 +1676,10 @@ pub fn bootstrap_load_elf_lazy(
     pt_addr: usize,
     state_addr: usize,
     conf_pool_base: usize,
-) -> anyhow::Result<usize> {
+) -> CoveResult<usize> {
     // A. Parse ELF to find PT_LOAD segments
     let elf = ElfBytes::<AnyEndian>::minimal_parse(data)
-        .map_err(|e| anyhow::anyhow!("ELF parse error: {:?}", e))?;
+        .map_err(|_| CoveError::InvalidParam("ELF parse error"))?;
 
     let mut segments = Vec::new();
     if let Some(hdrs) = elf.segments() {
@@ -1247,9 +1710,14 @@ pub fn bootstrap_load_elf_lazy(
 
     // C. Standard TVM Creation (Metadata only, NO MAPPING)
     let attestation = state.attestation_context.compute_next(&[0; 32]);
-    let tvm_id = state
-        .hypervisor
-        .create_tvm(attestation, pt_addr, state_addr)?;
+    let tvm_id = state.hypervisor.create_tvm(
+        attestation,
+        pt_addr,
+        state_addr,
+        HashAlgorithm::default(),
+        TVM_POLICY_DEFAULT,
+        None,
+    )?;
 
     // Define the guest physical address range (e.g. 0x1000 size 2MB)
     // This records that the range is valid for the TVM, allowing the
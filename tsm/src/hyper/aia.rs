@@ -0,0 +1,288 @@
+//! CoVE-I support: bind TVM vCPUs to IMSIC guest interrupt files so that MSIs reach the TVM
+//! directly through `hstatus.VGEIN`, without the host injecting them.
+//!
+//! The guest interrupt files are converted by the host (the TSM-driver grants the TSM access to
+//! them) and then mapped in the G-stage page table of the TVM at the IMSIC address of the vCPU.
+//! One vCPU of the TVM is bound at a time, `hstatus.VGEIN` selects its file only when it runs.
+
+use common::sbi::{TvmAiaParams, PAGE_SIZE};
+use tsm_core::{CoveError, CoveResult};
+
+use super::{is_mappable_gpa, map_4k_leaf, unmap_4k_leaf, HypervisorState, TvmState, PTE_R, PTE_W};
+use crate::h_extension::{csrs::hgeie, instruction::hfence_gvma_all};
+
+/// Maximum number of interrupt identities of an IMSIC interrupt file.
+const IMSIC_MAX_IDS: usize = 2047;
+
+/// AIA configuration of a TVM.
+pub struct TvmAia {
+    params: TvmAiaParams,
+    /// Guest physical address of the vCPU IMSIC interrupt file.
+    imsic_gpa: Option<usize>,
+    binding: Option<ImsicBinding>,
+}
+
+/// Guest interrupt file bound to the vCPU.
+#[derive(Clone, Copy)]
+pub struct ImsicBinding {
+    pub vcpu_id: usize,
+    pub file_addr: usize,
+    pub vgein: usize,
+    /// Set between UNBIND_AIA_IMSIC_BEGIN and UNBIND_AIA_IMSIC_END.
+    unbinding: bool,
+}
+
+impl TvmAia {
+    /// The binding to program at vCPU entry, if any.
+    pub fn active_binding(&self) -> Option<ImsicBinding> {
+        self.binding.filter(|b| !b.unbinding)
+    }
+}
+
+impl HypervisorState {
+    pub fn aia_supported(&self) -> bool {
+        self.imsic.is_some()
+    }
+
+    pub fn init_tvm_aia(
+        &mut self,
+        tvm_id: usize,
+        params_addr: usize,
+        params_len: usize,
+    ) -> CoveResult<()> {
+        if !self.aia_supported() {
+            return Err(CoveError::NotSupported("AIA not supported"));
+        }
+        if params_len < core::mem::size_of::<TvmAiaParams>() {
+            return Err(CoveError::InvalidParam("invalid TvmAiaParams size"));
+        }
+
+        let tvm = self.lifecycle.get_mut(tvm_id)?;
+        match tvm.state {
+            TvmState::TvmInitializing => {}
+            _ => {
+                return Err(CoveError::InvalidState(
+                    "cannot init AIA unless TVM_INITIALIZING",
+                ))
+            }
+        }
+        if tvm.aia.is_some() {
+            return Err(CoveError::AlreadyAvailable("AIA already initialized"));
+        }
+
+        let params = unsafe { core::ptr::read(params_addr as *const TvmAiaParams) };
+        if !(params.imsic_base_addr as usize).is_multiple_of(PAGE_SIZE) {
+            return Err(CoveError::InvalidAddress(
+                "imsic base address must be 4KB-aligned",
+            ));
+        }
+
+        tvm.aia = Some(TvmAia {
+            params,
+            imsic_gpa: None,
+            binding: None,
+        });
+        Ok(())
+    }
+
+    pub fn set_tvm_aia_cpu_imsic_addr(
+        &mut self,
+        tvm_id: usize,
+        _vcpu_id: usize,
+        imsic_gpa: usize,
+    ) -> CoveResult<()> {
+        let tvm = self.lifecycle.get_mut(tvm_id)?;
+        match tvm.state {
+            TvmState::TvmInitializing => {}
+            _ => {
+                return Err(CoveError::InvalidState(
+                    "cannot set the IMSIC address unless TVM_INITIALIZING",
+                ))
+            }
+        }
+
+        let overlaps_memory = tvm.memory_regions.overlaps(imsic_gpa, PAGE_SIZE);
+
+        let aia = tvm
+            .aia
+            .as_mut()
+            .ok_or(CoveError::InvalidState("AIA not initialized"))?;
+
+        if !imsic_gpa.is_multiple_of(PAGE_SIZE) || imsic_gpa < aia.params.imsic_base_addr as usize {
+            return Err(CoveError::InvalidAddress("invalid imsic address"));
+        }
+        if overlaps_memory || !is_mappable_gpa(imsic_gpa) {
+            return Err(CoveError::InvalidAddress(
+                "imsic address cannot be mapped in the TVM",
+            ));
+        }
+
+        aia.imsic_gpa = Some(imsic_gpa);
+        Ok(())
+    }
+
+    pub fn convert_aia_imsic(&mut self, imsic_addr: usize) -> CoveResult<()> {
+        let imsic = self
+            .imsic
+            .ok_or(CoveError::NotSupported("AIA not supported"))?;
+
+        if imsic.guest_file_index(imsic_addr).is_none() {
+            return Err(CoveError::InvalidAddress("not a guest interrupt file"));
+        }
+        if self.imsic_files.contains(&imsic_addr) {
+            return Err(CoveError::AlreadyAvailable(
+                "guest interrupt file already converted",
+            ));
+        }
+
+        self.imsic_files.push(imsic_addr);
+        Ok(())
+    }
+
+    pub fn reclaim_aia_imsic(&mut self, imsic_addr: usize) -> CoveResult<()> {
+        let idx = self
+            .imsic_files
+            .iter()
+            .position(|addr| *addr == imsic_addr)
+            .ok_or(CoveError::InvalidState(
+                "guest interrupt file not converted",
+            ))?;
+
+        let bound = self
+            .lifecycle
+            .tvm()
+            .and_then(|tvm| tvm.aia.as_ref())
+            .and_then(|aia| aia.binding)
+            .is_some_and(|b| b.file_addr == imsic_addr);
+        if bound {
+            return Err(CoveError::InvalidState("guest interrupt file still bound"));
+        }
+
+        self.imsic_files.remove(idx);
+        Ok(())
+    }
+
+    /// Bind the vCPU to the guest interrupt file selected by `imsic_mask`. Only a single guest
+    /// interrupt file per vCPU is supported for now.
+    pub fn bind_aia_imsic(
+        &mut self,
+        tvm_id: usize,
+        vcpu_id: usize,
+        imsic_mask: usize,
+    ) -> CoveResult<()> {
+        let imsic = self
+            .imsic
+            .ok_or(CoveError::NotSupported("AIA not supported"))?;
+
+        if imsic_mask.count_ones() != 1 {
+            return Err(CoveError::InvalidParam(
+                "exactly one guest interrupt file must be selected",
+            ));
+        }
+        let vgein = imsic_mask.trailing_zeros() as usize;
+        if vgein == 0 || vgein > hgeie::get_geilen() {
+            return Err(CoveError::InvalidAddress("invalid guest interrupt file"));
+        }
+
+        // This is a single hart TSM: the guest interrupt files are the ones of the first hart
+        let file_addr = imsic.base_addr + vgein * PAGE_SIZE;
+        if !self.imsic_files.contains(&file_addr) {
+            return Err(CoveError::InvalidState(
+                "guest interrupt file not converted",
+            ));
+        }
+
+        let tvm = self.lifecycle.get_mut(tvm_id)?;
+        if tvm.vcpu(vcpu_id).is_none() {
+            return Err(CoveError::InvalidParam("no vcpu present"));
+        }
+        let page_table_addr = tvm.page_table_addr;
+        let aia = tvm
+            .aia
+            .as_mut()
+            .ok_or(CoveError::InvalidState("AIA not initialized"))?;
+        if aia.binding.is_some() {
+            return Err(CoveError::AlreadyAvailable("vcpu already bound"));
+        }
+        let imsic_gpa = aia
+            .imsic_gpa
+            .ok_or(CoveError::InvalidState("vcpu imsic address not set"))?;
+
+        map_4k_leaf(page_table_addr, imsic_gpa, file_addr, PTE_R | PTE_W);
+        hfence_gvma_all();
+
+        aia.binding = Some(ImsicBinding {
+            vcpu_id,
+            file_addr,
+            vgein,
+            unbinding: false,
+        });
+        Ok(())
+    }
+
+    pub fn unbind_aia_imsic_begin(&mut self, tvm_id: usize, _vcpu_id: usize) -> CoveResult<()> {
+        let tvm = self.lifecycle.get_mut(tvm_id)?;
+        let page_table_addr = tvm.page_table_addr;
+        let aia = tvm
+            .aia
+            .as_mut()
+            .ok_or(CoveError::InvalidState("AIA not initialized"))?;
+
+        let binding = aia
+            .binding
+            .as_mut()
+            .filter(|b| !b.unbinding)
+            .ok_or(CoveError::InvalidState("vcpu not bound"))?;
+
+        // Stop MSIs written by the TVM from reaching the interrupt file
+        if let Some(imsic_gpa) = aia.imsic_gpa {
+            unmap_4k_leaf(page_table_addr, imsic_gpa);
+            hfence_gvma_all();
+        }
+        binding.unbinding = true;
+        Ok(())
+    }
+
+    pub fn unbind_aia_imsic_end(&mut self, tvm_id: usize, _vcpu_id: usize) -> CoveResult<()> {
+        let tvm = self.lifecycle.get_mut(tvm_id)?;
+        let aia = tvm
+            .aia
+            .as_mut()
+            .ok_or(CoveError::InvalidState("AIA not initialized"))?;
+
+        if !aia.binding.is_some_and(|b| b.unbinding) {
+            return Err(CoveError::InvalidState("unbind not started"));
+        }
+        aia.binding = None;
+        Ok(())
+    }
+
+    /// Inject `interrupt_id` by writing the `seteipnum_le` register of the bound interrupt file.
+    pub fn inject_tvm_cpu_interrupt(
+        &mut self,
+        tvm_id: usize,
+        _vcpu_id: usize,
+        interrupt_id: usize,
+    ) -> CoveResult<()> {
+        let num_ids = self
+            .imsic
+            .map(|imsic| imsic.num_ids)
+            .filter(|n| *n != 0)
+            .unwrap_or(IMSIC_MAX_IDS);
+        if interrupt_id == 0 || interrupt_id > num_ids {
+            return Err(CoveError::InvalidParam("invalid interrupt id"));
+        }
+
+        let tvm = self.lifecycle.get_mut(tvm_id)?;
+        let binding = tvm
+            .aia
+            .as_ref()
+            .and_then(|aia| aia.active_binding())
+            .ok_or(CoveError::InvalidState("vcpu not bound"))?;
+
+        unsafe {
+            core::ptr::write_volatile(binding.file_addr as *mut u32, interrupt_id as u32);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+//! Measured TVM boot-info page. TVM guests cannot trust the device tree given by the host, so the
+//! TSM generates a `TvmBootInfo` page at finalize (timebase frequency, memory map, vCPU count and
+//! the measurement of the TVM pages) and maps it read-only at a GPA chosen by the host. The same
+//! information follows the structure as a device tree, which the guest can boot from.
+//!
+//! The page is measured as well: the final measurement chains the page measurement with the
+//! boot-info placement and content, so a verifier can recompute both.
+
+use common::{
+    measurement::HashAlgorithm,
+    sbi::{
+        TvmBootInfo, TvmBootMemoryRegion, PAGE_SIZE, TVM_BOOT_INFO_MAGIC,
+        TVM_BOOT_INFO_MAX_REGIONS, TVM_BOOT_INFO_VERSION, TVM_GPA_KIND_MEASURED,
+    },
+};
+
+use tsm_core::{boot_fdt::tvm_device_tree, CoveError, CoveResult, GuestMemoryMap};
+
+use super::{is_mappable_gpa, map_4k_leaf, HypervisorState, Tvm, PTE_A, PTE_R, PTE_U};
+
+impl HypervisorState {
+    /// Handles `sbi_covh_tvm_vcpu_set_timer_frequency`. All the vCPUs of a TVM share the same
+    /// timebase.
+    pub fn tvm_vcpu_set_timer_frequency(
+        &mut self,
+        tvm_id: usize,
+        _vcpu_id: usize,
+        frequency: usize,
+    ) -> CoveResult<()> {
+        let tvm = self.lifecycle.initializing_mut(tvm_id)?;
+        if frequency == 0 {
+            return Err(CoveError::InvalidParam("invalid timer frequency"));
+        }
+        tvm.timebase_frequency = frequency as u64;
+        Ok(())
+    }
+
+    /// Handles `sbi_covh_set_tvm_boot_info`: the boot-info page will be written at `dest_addr`
+    /// (confidential memory) and mapped at `gpa` outside of the TVM memory regions.
+    pub fn set_tvm_boot_info(
+        &mut self,
+        tvm_id: usize,
+        dest_addr: usize,
+        gpa: usize,
+    ) -> CoveResult<()> {
+        if !dest_addr.is_multiple_of(PAGE_SIZE) || !gpa.is_multiple_of(PAGE_SIZE) {
+            return Err(CoveError::InvalidAddress(
+                "all addresses must be page-aligned",
+            ));
+        }
+        self.lifecycle
+            .confidential_memory()
+            .check_owner(dest_addr, PAGE_SIZE, tvm_id)?;
+
+        let tvm = self.lifecycle.initializing_mut(tvm_id)?;
+        if tvm.encrypted {
+            return Err(CoveError::NotSupported(
+                "boot info not supported for encrypted TVMs",
+            ));
+        }
+        if !is_mappable_gpa(gpa) {
+            return Err(CoveError::InvalidAddress(
+                "boot info address cannot be mapped in the TVM",
+            ));
+        }
+
+        tvm.boot_info = Some((dest_addr, gpa));
+        self.lifecycle
+            .confidential_memory_mut()
+            .claim(dest_addr, PAGE_SIZE, tvm_id)
+    }
+}
+
+impl Tvm {
+    /// Write, measure and map the boot-info page of the TVM with `memory_regions` and the page
+    /// directory at `page_table_addr`. Must run right before the measurement is finalized.
+    pub(super) fn write_boot_info(
+        &mut self,
+        memory_regions: &GuestMemoryMap,
+        page_table_addr: usize,
+    ) -> CoveResult<()> {
+        let Some((dest_addr, gpa)) = self.boot_info else {
+            return Ok(());
+        };
+
+        if memory_regions.overlaps(gpa, PAGE_SIZE) {
+            return Err(CoveError::BadRange("boot info page overlaps TVM memory"));
+        }
+        if memory_regions.len() > TVM_BOOT_INFO_MAX_REGIONS {
+            return Err(CoveError::Failed(
+                "too many memory regions for the boot info",
+            ));
+        }
+
+        let fdt = tvm_device_tree(self.vcpus.len(), self.timebase_frequency, memory_regions);
+        let fdt_offset = core::mem::size_of::<TvmBootInfo>().next_multiple_of(8);
+        if fdt_offset + fdt.len() > PAGE_SIZE {
+            return Err(CoveError::Failed(
+                "device tree does not fit in the boot info page",
+            ));
+        }
+
+        let alg = self.measurement_algorithm();
+        let initial_measurement = self.hasher.finalize_reset();
+
+        let mut info = TvmBootInfo {
+            magic: TVM_BOOT_INFO_MAGIC,
+            version: TVM_BOOT_INFO_VERSION,
+            num_vcpus: self.vcpus.len() as u32,
+            timebase_frequency: self.timebase_frequency,
+            measurement_alg: alg as u32,
+            num_memory_regions: memory_regions.len() as u32,
+            initial_measurement: [0; 64],
+            memory_regions: [TvmBootMemoryRegion::default(); TVM_BOOT_INFO_MAX_REGIONS],
+            fdt_offset: fdt_offset as u32,
+            fdt_size: fdt.len() as u32,
+        };
+        info.initial_measurement[..initial_measurement.len()].copy_from_slice(&initial_measurement);
+        for (slot, r) in info.memory_regions.iter_mut().zip(memory_regions.iter()) {
+            *slot = TvmBootMemoryRegion {
+                gpa_base: r.guest_gpa_base as u64,
+                size: (r.num_pages * PAGE_SIZE) as u64,
+            };
+        }
+
+        let page = unsafe {
+            core::ptr::write_bytes(dest_addr as *mut u8, 0, PAGE_SIZE);
+            core::ptr::write(dest_addr as *mut TvmBootInfo, info);
+            core::ptr::copy_nonoverlapping(
+                fdt.as_ptr(),
+                (dest_addr + fdt_offset) as *mut u8,
+                fdt.len(),
+            );
+            core::slice::from_raw_parts(dest_addr as *const u8, PAGE_SIZE)
+        };
+        let digest = page_digest(alg, page)?;
+
+        self.extend_measure(&initial_measurement);
+        self.extend_measure(&gpa.to_le_bytes());
+        self.extend_measure(&digest);
+
+        let perms = PTE_R | PTE_U | PTE_A | TVM_GPA_KIND_MEASURED;
+        map_4k_leaf(page_table_addr, gpa, dest_addr, perms);
+        Ok(())
+    }
+}
+
+fn page_digest(alg: HashAlgorithm, page: &[u8]) -> CoveResult<alloc::vec::Vec<u8>> {
+    let mut hasher = alg
+        .hasher()
+        .ok_or(CoveError::NotSupported("unsupported measurement algorithm"))?;
+    hasher.extend(page);
+    Ok(hasher.finalize_reset())
+}
@@ -0,0 +1,77 @@
+//! Domain mode: CoVE on harts without the hypervisor extension.
+//!
+//! Without G-stage translation there is no TVM: the host builds, measures and finalizes a single
+//! confidential payload with the usual COVH calls, then `RunTvmVcpu` switches to it in S-mode,
+//! inside the supervisor domain of the TSM. The payload runs without address translation, so its
+//! entry point must be mapped at its physical address (the host loads it with gpa == pa).
+//!
+//! Every run enters the payload at its entry point (`a0` = vCPU id, `a1` = entry argument), its
+//! memory is kept across runs. The payload goes back to the host with a COVH ECALL, as the TEERET
+//! of the TSM, keeping the `a6` it got. A trap the payload does not handle itself ends the run
+//! with `SBI_ERR_FAILED` and the trap cause in `a1`.
+//!
+//! The calls needing the hypervisor extension (AIA, shared pages, page encryption and migration)
+//! are not supported.
+
+use core::sync::atomic::Ordering;
+
+use common::sbi::SBI_COVH_EXT_ID;
+use riscv::register::{
+    sepc,
+    sstatus::{self, SPP},
+    stvec::{self, Stvec},
+};
+use tsm_core::{CoveError, CoveResult, CovhCall, TvmSlot};
+
+use super::{translate_gpa_to_pa, Tvm};
+use crate::TEECALL_FID;
+
+/// Whether `call` is served in domain mode.
+pub fn supports(call: &CovhCall) -> bool {
+    match call {
+        CovhCall::CreateTvm(params) => params.working_set.is_none(),
+        CovhCall::AddTvmSharedPages { .. }
+        | CovhCall::ExportTvm { .. }
+        | CovhCall::PrepareTvmImport { .. }
+        | CovhCall::ImportTvm { .. } => false,
+        _ => true,
+    }
+}
+
+/// Switch to the payload of `tvm`, from its entry point.
+pub fn run(tvm: &TvmSlot<Tvm>, vcpu_id: usize) -> CoveResult<!> {
+    if translate_gpa_to_pa(tvm.page_table_addr, tvm.entry_sepc) != Some(tvm.entry_sepc) {
+        return Err(CoveError::InvalidAddress(
+            "payload entry point not mapped at its physical address",
+        ));
+    }
+
+    unsafe {
+        sstatus::set_spp(SPP::Supervisor);
+        stvec::write(Stvec::from_bits(payload_trap as *const fn() as usize));
+        sepc::write(tvm.entry_sepc);
+        // `payload_trap` finds the TEECALL fid in sscratch
+        core::arch::asm!(
+            "csrw sscratch, a6",
+            "fence.i",
+            "sret",
+            in("a0") vcpu_id,
+            in("a1") tvm.entry_arg,
+            in("a6") TEECALL_FID.load(Ordering::Relaxed),
+            options(noreturn, nostack)
+        )
+    }
+}
+
+/// Trap of the payload: back to the host with the trap cause.
+#[unsafe(naked)]
+unsafe extern "C" fn payload_trap() -> ! {
+    core::arch::naked_asm!(
+        "csrr a6, sscratch",
+        "csrr a1, scause",
+        "li a0, -1",
+        "li a7, {covh}",
+        "ecall",
+        covh = const SBI_COVH_EXT_ID,
+    )
+}
@@ -0,0 +1,207 @@
+//! Software page encryption for platforms without enough confidential memory.
+//!
+//! TVMs created with `TVM_POLICY_SW_PAGE_ENCRYPTION` keep their pages in host memory, sealed with
+//! AES-256-GCM under a key derived from the TVM CDI and a per-TVM salt. Only a small working set of confidential
+//! pages is mapped in the G-stage page table: on a guest page fault the page is copied into a free
+//! (or evicted) working set slot and decrypted there. Evicted pages are encrypted in the slot
+//! before being copied back to host memory.
+//!
+//! Each seal uses a fresh version number in the nonce and binds the GPA as associated data. Tags
+//! and versions stay in TSM memory, so the host can neither replay old ciphertext nor swap pages.
+//! A page failing authentication stops the TVM for good.
+//!
+//! The state lives in its own mutex since the guest page fault handler runs while the TSM state
+//! is locked by `run_tvm_vcpu`.
+
+use aes_gcm::{aead::AeadInPlace, Aes256Gcm, KeyInit, Nonce, Tag};
+use alloc::{collections::BTreeMap, vec::Vec};
+use common::{
+    attestation::{Cdi, SecretBytes, KEY_LADDER_OWNER_TSM},
+    sbi::PAGE_SIZE,
+};
+use spin::Mutex;
+use tsm_core::{CoveError, CoveResult};
+
+use super::{map_4k_leaf, unmap_4k_leaf, PTE_A, PTE_D, PTE_R, PTE_U, PTE_W, PTE_X};
+use crate::{h_extension::instruction::hfence_gvma_all, sbi::random_bytes};
+
+const PAGE_KEY_LABEL: &[u8] = b"tvm-page";
+const PAGE_KEY_SALT_SIZE: usize = 16;
+
+static ENCRYPTED_MEMORY: Mutex<Option<EncryptedMemory>> = Mutex::new(None);
+
+struct BackingPage {
+    host_addr: usize,
+    version: u64,
+    tag: Tag,
+}
+
+struct EncryptedMemory {
+    cipher: Aes256Gcm,
+    root_pt: usize,
+    /// Sealed pages by GPA
+    backing: BTreeMap<usize, BackingPage>,
+    /// Confidential working set: physical page and the GPA it currently holds
+    slots: Vec<(usize, Option<usize>)>,
+    next_victim: usize,
+    /// A sealed page failed authentication, the TVM does not run anymore
+    tampered: bool,
+}
+
+/// Derive the page encryption key of a new TVM from its CDI and a salt from the firmware entropy
+/// source. Seal versions start at 0 for every TVM: the salt keeps two TVMs with the same CDI from
+/// sharing a key, and so a nonce.
+pub fn page_key(cdi: &Cdi) -> CoveResult<SecretBytes> {
+    let mut label = [0u8; PAGE_KEY_LABEL.len() + PAGE_KEY_SALT_SIZE];
+    label[..PAGE_KEY_LABEL.len()].copy_from_slice(PAGE_KEY_LABEL);
+    random_bytes(&mut label[PAGE_KEY_LABEL.len()..])
+        .map_err(|_| CoveError::Failed("no entropy from the TSM-driver"))?;
+    Ok(cdi.ladder_key(KEY_LADDER_OWNER_TSM, &label).unwrap())
+}
+
+/// Enable software encryption for the TVM whose G-stage root is `root_pt`.
+pub fn init(key: &[u8], root_pt: usize, working_set_addr: usize, working_set_pages: usize) {
+    let slots = (0..working_set_pages)
+        .map(|i| (working_set_addr + i * PAGE_SIZE, None))
+        .collect();
+
+    ENCRYPTED_MEMORY.lock().replace(EncryptedMemory {
+        cipher: Aes256Gcm::new_from_slice(key).unwrap(),
+        root_pt,
+        backing: BTreeMap::new(),
+        slots,
+        next_victim: 0,
+        tampered: false,
+    });
+}
+
+/// True once a sealed page of the TVM failed authentication.
+pub fn tampered() -> bool {
+    ENCRYPTED_MEMORY
+        .lock()
+        .as_ref()
+        .is_some_and(|mem| mem.tampered)
+}
+
+/// Drop the encryption state (e.g. on TVM destruction). Working set pages are zeroed.
+pub fn clear() {
+    if let Some(mem) = ENCRYPTED_MEMORY.lock().take() {
+        for (pa, _) in mem.slots {
+            unsafe { core::ptr::write_bytes(pa as *mut u8, 0, PAGE_SIZE) };
+        }
+    }
+}
+
+/// Seal the plaintext `page` (in TSM memory) into the host page `host_addr` as content of `gpa`.
+pub fn add_page(gpa: usize, host_addr: usize, page: &mut [u8]) -> CoveResult<()> {
+    let mut lock = ENCRYPTED_MEMORY.lock();
+    let mem = lock
+        .as_mut()
+        .ok_or(CoveError::InvalidState("software encryption not enabled"))?;
+    if mem.backing.contains_key(&gpa) {
+        return Err(CoveError::AlreadyAvailable("gpa already mapped"));
+    }
+
+    let tag = mem.seal(gpa, 0, page)?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(page.as_ptr(), host_addr as *mut u8, PAGE_SIZE);
+    }
+    mem.backing.insert(
+        gpa,
+        BackingPage {
+            host_addr,
+            version: 0,
+            tag,
+        },
+    );
+    Ok(())
+}
+
+/// Handle a guest page fault on a sealed page. Returns false if `gpa` is not a sealed page, and
+/// an error if the host modified the page: the TVM is then stopped.
+pub fn handle_page_fault(gpa: usize) -> CoveResult<bool> {
+    let gpa = gpa & !(PAGE_SIZE - 1);
+    let mut lock = ENCRYPTED_MEMORY.lock();
+    let mem = match lock.as_mut() {
+        Some(mem) if mem.backing.contains_key(&gpa) => mem,
+        _ => return Ok(false),
+    };
+
+    let slot = mem.take_slot();
+    let pa = mem.slots[slot].0;
+    let page = mem.backing.get(&gpa).unwrap();
+    let (host_addr, version, tag) = (page.host_addr, page.version, page.tag);
+
+    // Copy into confidential memory before authenticating so the host cannot race us
+    let buf = unsafe {
+        core::ptr::copy_nonoverlapping(host_addr as *const u8, pa as *mut u8, PAGE_SIZE);
+        core::slice::from_raw_parts_mut(pa as *mut u8, PAGE_SIZE)
+    };
+    let opened = mem.cipher.decrypt_in_place_detached(
+        &page_nonce(gpa, version),
+        &gpa.to_le_bytes(),
+        buf,
+        &tag,
+    );
+    if opened.is_err() {
+        buf.fill(0);
+        mem.tampered = true;
+        return Err(CoveError::Denied("encrypted TVM page tampered by the host"));
+    }
+
+    mem.slots[slot].1 = Some(gpa);
+    map_4k_leaf(
+        mem.root_pt,
+        gpa,
+        pa,
+        PTE_R | PTE_W | PTE_X | PTE_U | PTE_A | PTE_D,
+    );
+    hfence_gvma_all();
+    Ok(true)
+}
+
+impl EncryptedMemory {
+    fn seal(&self, gpa: usize, version: u64, page: &mut [u8]) -> CoveResult<Tag> {
+        self.cipher
+            .encrypt_in_place_detached(&page_nonce(gpa, version), &gpa.to_le_bytes(), page)
+            .map_err(|_| CoveError::Failed("page encryption failed"))
+    }
+
+    /// Return a free working set slot, evicting the next victim if all of them are in use.
+    fn take_slot(&mut self) -> usize {
+        if let Some(free) = self.slots.iter().position(|(_, gpa)| gpa.is_none()) {
+            return free;
+        }
+
+        let victim = self.next_victim;
+        self.next_victim = (self.next_victim + 1) % self.slots.len();
+
+        let (pa, gpa) = self.slots[victim];
+        let gpa = gpa.unwrap();
+        unmap_4k_leaf(self.root_pt, gpa);
+        hfence_gvma_all();
+
+        let page = self.backing.get(&gpa).unwrap();
+        let (host_addr, version) = (page.host_addr, page.version + 1);
+        let buf = unsafe { core::slice::from_raw_parts_mut(pa as *mut u8, PAGE_SIZE) };
+        let tag = self.seal(gpa, version, buf).unwrap();
+        unsafe {
+            core::ptr::copy_nonoverlapping(pa as *const u8, host_addr as *mut u8, PAGE_SIZE);
+            core::ptr::write_bytes(pa as *mut u8, 0, PAGE_SIZE);
+        }
+
+        let page = self.backing.get_mut(&gpa).unwrap();
+        page.version = version;
+        page.tag = tag;
+        self.slots[victim].1 = None;
+        victim
+    }
+}
+
+/// 96-bit nonce: guest page number (low 32 bits) and seal version.
+fn page_nonce(gpa: usize, version: u64) -> Nonce<aes_gcm::aes::cipher::consts::U12> {
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&((gpa / PAGE_SIZE) as u32).to_le_bytes());
+    nonce[4..].copy_from_slice(&version.to_le_bytes());
+    nonce.into()
+}
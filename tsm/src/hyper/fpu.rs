@@ -0,0 +1,322 @@
+//! Floating-point and vector state of the vCPUs. The TSM and the host share the FP and vector
+//! registers of the hart, and the firmware does not switch them between the domains: the TSM
+//! keeps the registers of the host while a vCPU uses them.
+//!
+//! The switch is lazy, so that integer-only guests pay nothing:
+//! - a vCPU is entered with `sstatus.FS` and `sstatus.VS` off, the registers of the host stay in
+//!   the hart;
+//! - the first FP (or vector) instruction of the guest traps as an illegal instruction: the TSM
+//!   saves the registers of the host, loads those of the vCPU and marks the unit clean;
+//! - when the vCPU exits to the host, the registers are saved only if the guest dirtied them, and
+//!   the registers of the host are loaded back.
+//!
+//! The units are probed through the WARL `FS` and `VS` fields of `sstatus`. An FP unit is assumed
+//! to implement D: the registers are saved 64 bits wide.
+
+use alloc::{vec, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+// FS and VS fields of sstatus
+const SSTATUS_FS_SHIFT: usize = 13;
+const SSTATUS_VS_SHIFT: usize = 9;
+const STATUS_OFF: usize = 0;
+const STATUS_INITIAL: usize = 1;
+const STATUS_CLEAN: usize = 2;
+const STATUS_DIRTY: usize = 3;
+
+/// The harts have an FP unit
+const UNIT_F: usize = 1 << 0;
+/// The harts have a vector unit
+const UNIT_V: usize = 1 << 1;
+/// `UNITS` before the probe
+const UNITS_UNKNOWN: usize = usize::MAX;
+
+/// `UNIT_*` of the harts, see `units`
+static UNITS: AtomicUsize = AtomicUsize::new(UNITS_UNKNOWN);
+
+/// Registers of the host while a vCPU owns the units
+static HOST: Mutex<FpState> = Mutex::new(FpState::new());
+
+/// FP and vector registers of a vCPU (or of the host).
+#[derive(Clone, Debug)]
+pub struct FpState {
+    f: [u64; 32],
+    fcsr: usize,
+    /// Allocated on the first save, if the harts have a vector unit
+    vector: Option<VectorState>,
+}
+
+#[derive(Clone, Debug)]
+struct VectorState {
+    vstart: usize,
+    vcsr: usize,
+    vl: usize,
+    vtype: usize,
+    /// v0-v31, `vlenb` bytes each
+    vregs: Vec<u8>,
+}
+
+/// Store (`fsd`) or load (`fld`) f0-f31 at `{0}`.
+#[rustfmt::skip]
+macro_rules! for_each_f {
+    ($op:literal) => {
+        concat!(
+            $op, " f0, 0({0})\n", $op, " f1, 8({0})\n", $op, " f2, 16({0})\n",
+            $op, " f3, 24({0})\n", $op, " f4, 32({0})\n", $op, " f5, 40({0})\n",
+            $op, " f6, 48({0})\n", $op, " f7, 56({0})\n", $op, " f8, 64({0})\n",
+            $op, " f9, 72({0})\n", $op, " f10, 80({0})\n", $op, " f11, 88({0})\n",
+            $op, " f12, 96({0})\n", $op, " f13, 104({0})\n", $op, " f14, 112({0})\n",
+            $op, " f15, 120({0})\n", $op, " f16, 128({0})\n", $op, " f17, 136({0})\n",
+            $op, " f18, 144({0})\n", $op, " f19, 152({0})\n", $op, " f20, 160({0})\n",
+            $op, " f21, 168({0})\n", $op, " f22, 176({0})\n", $op, " f23, 184({0})\n",
+            $op, " f24, 192({0})\n", $op, " f25, 200({0})\n", $op, " f26, 208({0})\n",
+            $op, " f27, 216({0})\n", $op, " f28, 224({0})\n", $op, " f29, 232({0})\n",
+            $op, " f30, 240({0})\n", $op, " f31, 248({0})\n",
+        )
+    };
+}
+
+impl FpState {
+    pub const fn new() -> Self {
+        Self {
+            f: [0; 32],
+            fcsr: 0,
+            vector: None,
+        }
+    }
+
+    /// Zero the registers, for a vCPU started from a clean state.
+    pub fn clear(&mut self) {
+        self.f = [0; 32];
+        self.fcsr = 0;
+        if let Some(vector) = self.vector.as_mut() {
+            vector.vstart = 0;
+            vector.vcsr = 0;
+            vector.vl = 0;
+            vector.vtype = 0;
+            vector.vregs.fill(0);
+        }
+    }
+
+    /// Save f0-f31 and fcsr. FS must not be off.
+    unsafe fn save_f(&mut self) {
+        core::arch::asm!(
+            ".option push",
+            ".option arch, +d",
+            for_each_f!("fsd"),
+            "frcsr {1}",
+            ".option pop",
+            in(reg) self.f.as_mut_ptr(),
+            out(reg) self.fcsr,
+        );
+    }
+
+    /// Load f0-f31 and fcsr. FS must not be off.
+    unsafe fn restore_f(&self) {
+        core::arch::asm!(
+            ".option push",
+            ".option arch, +d",
+            for_each_f!("fld"),
+            "fscsr {1}",
+            ".option pop",
+            in(reg) self.f.as_ptr(),
+            in(reg) self.fcsr,
+        );
+    }
+
+    /// Save v0-v31 and the vector CSRs. VS must not be off.
+    unsafe fn save_v(&mut self) {
+        let vlenb = vlenb();
+        let vector = self.vector.get_or_insert_with(|| VectorState {
+            vstart: 0,
+            vcsr: 0,
+            vl: 0,
+            vtype: 0,
+            vregs: vec![0; 32 * vlenb],
+        });
+        core::arch::asm!(
+            ".option push",
+            ".option arch, +v",
+            "csrr {vstart}, vstart",
+            "csrr {vcsr}, vcsr",
+            "csrr {vl}, vl",
+            "csrr {vtype}, vtype",
+            // The whole register stores start from vstart
+            "csrw vstart, zero",
+            "vs8r.v v0, ({regs})",
+            "add {regs}, {regs}, {group}",
+            "vs8r.v v8, ({regs})",
+            "add {regs}, {regs}, {group}",
+            "vs8r.v v16, ({regs})",
+            "add {regs}, {regs}, {group}",
+            "vs8r.v v24, ({regs})",
+            ".option pop",
+            vstart = out(reg) vector.vstart,
+            vcsr = out(reg) vector.vcsr,
+            vl = out(reg) vector.vl,
+            vtype = out(reg) vector.vtype,
+            regs = inout(reg) vector.vregs.as_mut_ptr() => _,
+            group = in(reg) 8 * vlenb,
+        );
+    }
+
+    /// Load v0-v31 and the vector CSRs, zero if they were never saved. VS must not be off.
+    unsafe fn restore_v(&self) {
+        let Some(vector) = self.vector.as_ref() else {
+            // Not used yet: clear what the previous owner left
+            core::arch::asm!(
+                ".option push",
+                ".option arch, +v",
+                "vsetvli {tmp}, zero, e8, m8, ta, ma",
+                "vmv.v.i v0, 0",
+                "vmv.v.i v8, 0",
+                "vmv.v.i v16, 0",
+                "vmv.v.i v24, 0",
+                "vsetvl {tmp}, zero, {vill}",
+                "csrw vcsr, zero",
+                "csrw vstart, zero",
+                ".option pop",
+                tmp = out(reg) _,
+                vill = in(reg) 1usize << (usize::BITS - 1),
+            );
+            return;
+        };
+        core::arch::asm!(
+            ".option push",
+            ".option arch, +v",
+            "csrw vstart, zero",
+            "vl8r.v v0, ({regs})",
+            "add {regs}, {regs}, {group}",
+            "vl8r.v v8, ({regs})",
+            "add {regs}, {regs}, {group}",
+            "vl8r.v v16, ({regs})",
+            "add {regs}, {regs}, {group}",
+            "vl8r.v v24, ({regs})",
+            "vsetvl zero, {vl}, {vtype}",
+            "csrw vcsr, {vcsr}",
+            "csrw vstart, {vstart}",
+            ".option pop",
+            regs = inout(reg) vector.vregs.as_ptr() => _,
+            group = in(reg) 8 * vlenb(),
+            vl = in(reg) vector.vl,
+            vtype = in(reg) vector.vtype,
+            vcsr = in(reg) vector.vcsr,
+            vstart = in(reg) vector.vstart,
+        );
+    }
+}
+
+/// Bytes of a vector register
+fn vlenb() -> usize {
+    let vlenb: usize;
+    unsafe {
+        core::arch::asm!(
+            ".option push",
+            ".option arch, +v",
+            "csrr {}, vlenb",
+            ".option pop",
+            out(reg) vlenb,
+        )
+    };
+    vlenb
+}
+
+/// `STATUS_*` of the sstatus field at `shift`
+fn status(shift: usize) -> usize {
+    let sstatus: usize;
+    unsafe { core::arch::asm!("csrr {}, sstatus", out(reg) sstatus) };
+    (sstatus >> shift) & 0b11
+}
+
+fn set_status(shift: usize, value: usize) {
+    unsafe {
+        core::arch::asm!(
+            "csrc sstatus, {mask}",
+            "csrs sstatus, {bits}",
+            mask = in(reg) 0b11 << shift,
+            bits = in(reg) value << shift,
+        )
+    };
+}
+
+/// `UNIT_*` of the harts: a unit is there if its sstatus field is writable. Probed once, the units
+/// are left off.
+fn units() -> usize {
+    let units = UNITS.load(Ordering::Relaxed);
+    if units != UNITS_UNKNOWN {
+        return units;
+    }
+
+    let mut units = 0;
+    for (shift, unit) in [(SSTATUS_FS_SHIFT, UNIT_F), (SSTATUS_VS_SHIFT, UNIT_V)] {
+        set_status(shift, STATUS_INITIAL);
+        if status(shift) != STATUS_OFF {
+            units |= unit;
+        }
+        set_status(shift, STATUS_OFF);
+    }
+    UNITS.store(units, Ordering::Relaxed);
+    units
+}
+
+/// Turn the units off before entering a vCPU: its first FP or vector instruction traps to the TSM
+/// (see `load_on_trap`).
+pub fn disable() {
+    units();
+    set_status(SSTATUS_FS_SHIFT, STATUS_OFF);
+    set_status(SSTATUS_VS_SHIFT, STATUS_OFF);
+}
+
+/// The vCPU took an illegal instruction: if a unit is off, save the registers of the host, load
+/// those of `vcpu` and turn the unit on, the instruction is retried. Returns false if the units
+/// of the harts are already on: the instruction is illegal for the guest.
+pub fn load_on_trap(vcpu: &FpState) -> bool {
+    let units = units();
+    let mut host = HOST.lock();
+    if units & UNIT_F != 0 && status(SSTATUS_FS_SHIFT) == STATUS_OFF {
+        set_status(SSTATUS_FS_SHIFT, STATUS_INITIAL);
+        unsafe {
+            host.save_f();
+            vcpu.restore_f();
+        }
+        set_status(SSTATUS_FS_SHIFT, STATUS_CLEAN);
+        return true;
+    }
+    if units & UNIT_V != 0 && status(SSTATUS_VS_SHIFT) == STATUS_OFF {
+        set_status(SSTATUS_VS_SHIFT, STATUS_INITIAL);
+        unsafe {
+            host.save_v();
+            vcpu.restore_v();
+        }
+        set_status(SSTATUS_VS_SHIFT, STATUS_CLEAN);
+        return true;
+    }
+    false
+}
+
+/// The vCPU exits to the host: save in `vcpu` the registers it dirtied and give the host its
+/// registers back. The units are left off.
+pub fn unload(vcpu: &mut FpState) {
+    let host = HOST.lock();
+    match status(SSTATUS_FS_SHIFT) {
+        STATUS_OFF => {}
+        fs => unsafe {
+            if fs == STATUS_DIRTY {
+                vcpu.save_f();
+            }
+            host.restore_f();
+        },
+    }
+    match status(SSTATUS_VS_SHIFT) {
+        STATUS_OFF => {}
+        vs => unsafe {
+            if vs == STATUS_DIRTY {
+                vcpu.save_v();
+            }
+            host.restore_v();
+        },
+    }
+    drop(host);
+    disable();
+}
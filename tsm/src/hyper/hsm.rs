@@ -0,0 +1,166 @@
+//! SBI HSM extension of the TVM guests. The harts of a TVM are vCPUs the host runs with
+//! RUN_TVM_VCPU, so the TSM answers the HSM calls of the guest itself, the firmware would act on
+//! the physical harts:
+//! - the first vCPU created is started at finalize, the other ones are stopped;
+//! - HART_START of a stopped vCPU keeps its start address and opaque in the TSM and exits to the
+//!   host with `TVM_EXIT_HSM_START` and the id of the vCPU. The next RUN_TVM_VCPU of that vCPU
+//!   enters it at the start address with a0 = hartid and a1 = opaque, from a clean state;
+//! - HART_STOP stops the calling vCPU and exits with `TVM_EXIT_HSM_STOP`, RUN_TVM_VCPU fails on a
+//!   stopped vCPU until the guest starts it again;
+//! - HART_SUSPEND exits with `TVM_EXIT_HSM_SUSPEND`: the next RUN_TVM_VCPU resumes the vCPU after
+//!   the call (retentive) or enters it at the resume address (non-retentive);
+//! - HART_GET_STATUS reports the state of a vCPU.
+//!
+//! The hartid of a vCPU is its id. The host only learns which vCPU to schedule.
+
+use common::{
+    config::MAX_VCPUS_PER_TVM,
+    sbi::{
+        SbiRet, TVM_EXIT_HSM_START, TVM_EXIT_HSM_STOP, TVM_EXIT_HSM_SUSPEND, TVM_EXIT_VCPU_SHIFT,
+    },
+};
+use riscv::register::sepc;
+use spin::Mutex;
+use tsm_core::{CoveError, CoveResult};
+
+use super::{exit_to_host, VmTrapContext};
+
+pub const EXT_HSM: usize = 0x48534D;
+const HSM_HART_START: usize = 0;
+const HSM_HART_STOP: usize = 1;
+const HSM_HART_GET_STATUS: usize = 2;
+const HSM_HART_SUSPEND: usize = 3;
+
+const SUSPEND_DEFAULT_RETENTIVE: usize = 0;
+const SUSPEND_DEFAULT_NON_RETENTIVE: usize = 0x8000_0000;
+
+/// HSM state of a vCPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum VcpuState {
+    Started,
+    Stopped,
+    /// Entered at `addr` with a1 = `opaque` on its next run
+    StartPending {
+        addr: usize,
+        opaque: usize,
+    },
+    /// Resumes after the HART_SUSPEND, or at the address and with the opaque of `resume`
+    Suspended {
+        resume: Option<(usize, usize)>,
+    },
+}
+
+impl VcpuState {
+    /// State as reported by HART_GET_STATUS
+    fn status(&self) -> usize {
+        match self {
+            Self::Started => 0,
+            Self::Stopped => 1,
+            Self::StartPending { .. } => 2,
+            Self::Suspended { .. } => 4,
+        }
+    }
+}
+
+/// States of the vCPUs of the TVM, by vCPU id. Reached by the trap handler while a vCPU runs.
+static TVM_VCPUS: Mutex<[Option<VcpuState>; MAX_VCPUS_PER_TVM]> =
+    Mutex::new([None; MAX_VCPUS_PER_TVM]);
+
+/// Start the first vCPU of `ids` and stop the other ones.
+pub fn reset(ids: impl Iterator<Item = usize>) {
+    let mut vcpus = TVM_VCPUS.lock();
+    *vcpus = [None; MAX_VCPUS_PER_TVM];
+    for (i, id) in ids.enumerate() {
+        vcpus[id] = Some(match i {
+            0 => VcpuState::Started,
+            _ => VcpuState::Stopped,
+        });
+    }
+}
+
+/// Number of vCPUs of the TVM, whatever their state.
+pub fn count() -> usize {
+    TVM_VCPUS.lock().iter().flatten().count()
+}
+
+/// Mark the vCPU `id` as started before running it. Returns the address and the opaque to enter it
+/// with, if it is not resumed where it stopped.
+pub fn run(id: usize) -> CoveResult<Option<(usize, usize)>> {
+    let mut vcpus = TVM_VCPUS.lock();
+    let state = vcpus
+        .get_mut(id)
+        .and_then(Option::as_mut)
+        .ok_or(CoveError::InvalidParam("no vcpu present"))?;
+    let entry = match *state {
+        VcpuState::Stopped => return Err(CoveError::InvalidState("vcpu stopped")),
+        VcpuState::Started => None,
+        VcpuState::StartPending { addr, opaque } => Some((addr, opaque)),
+        VcpuState::Suspended { resume } => resume,
+    };
+    *state = VcpuState::Started;
+    Ok(entry)
+}
+
+/// Handle the HSM call `fid` of the vCPU `id`, trapped at `epc`. Start, stop and suspend exit to
+/// the host.
+pub fn handle(
+    ctx: *mut VmTrapContext,
+    id: usize,
+    fid: usize,
+    args: &[usize; 6],
+    epc: usize,
+) -> SbiRet {
+    let mut vcpus = TVM_VCPUS.lock();
+    let exit = match fid {
+        HSM_HART_START => {
+            let Some(target) = vcpus.get_mut(args[0]).and_then(Option::as_mut) else {
+                return error(CoveError::InvalidParam("invalid hartid"));
+            };
+            if *target != VcpuState::Stopped {
+                return error(CoveError::AlreadyAvailable("hart not stopped"));
+            }
+            *target = VcpuState::StartPending {
+                addr: args[1],
+                opaque: args[2],
+            };
+            TVM_EXIT_HSM_START | args[0] << TVM_EXIT_VCPU_SHIFT
+        }
+        HSM_HART_STOP => {
+            vcpus[id] = Some(VcpuState::Stopped);
+            TVM_EXIT_HSM_STOP | id << TVM_EXIT_VCPU_SHIFT
+        }
+        HSM_HART_GET_STATUS => {
+            return match vcpus.get(args[0]).copied().flatten() {
+                Some(state) => SbiRet {
+                    a0: 0,
+                    a1: state.status() as isize,
+                },
+                None => error(CoveError::InvalidParam("invalid hartid")),
+            }
+        }
+        HSM_HART_SUSPEND => {
+            let resume = match args[0] {
+                SUSPEND_DEFAULT_RETENTIVE => None,
+                SUSPEND_DEFAULT_NON_RETENTIVE => Some((args[1], args[2])),
+                _ => return error(CoveError::NotSupported("unsupported suspend type")),
+            };
+            vcpus[id] = Some(VcpuState::Suspended { resume });
+            TVM_EXIT_HSM_SUSPEND | id << TVM_EXIT_VCPU_SHIFT
+        }
+        _ => return error(CoveError::NotSupported("unsupported HSM function")),
+    };
+    drop(vcpus);
+
+    let regs = unsafe { &mut (*ctx).regs };
+    regs[10] = 0;
+    regs[11] = 0;
+    unsafe { sepc::write(epc + 4) };
+    exit_to_host(ctx, exit)
+}
+
+fn error(e: CoveError) -> SbiRet {
+    SbiRet {
+        a0: e.sbi_error(),
+        a1: 0,
+    }
+}
@@ -0,0 +1,142 @@
+//! Shadowfax-specific CoVI extension: route host interrupt sources to a TVM vCPU.
+//!
+//! The host binds an interrupt source (a wired line or an MSI vector, numbered by the host) to a
+//! vCPU and then only signals the source, the TSM decides how the interrupt reaches the guest:
+//! - with a guest interrupt id, the id is written to the IMSIC guest interrupt file bound to the
+//!   vCPU, on each assertion (edge, as MSIs);
+//! - with id 0, the source drives `hvip.VSEIP` of the vCPU (level, as wired lines), which is
+//!   applied on the next RUN_TVM_VCPU. This route does not need an IMSIC.
+//!
+//! A route targets the vCPU given at bind time. The routes belong to the TVM and go away with it.
+
+use alloc::vec::Vec;
+use tsm_core::{CoveError, CoveResult};
+
+use super::{HypervisorState, TvmState};
+use crate::h_extension::csrs::VsInterruptKind;
+
+/// Maximum number of routes of a TVM.
+const MAX_ROUTES: usize = 64;
+
+/// Route of a host interrupt source.
+#[derive(Clone, Copy)]
+struct InterruptRoute {
+    source: usize,
+    vcpu_id: usize,
+    /// Guest interrupt id, 0 for `hvip.VSEIP`.
+    guest_id: usize,
+    /// Level of the source, for `hvip.VSEIP` routes.
+    asserted: bool,
+}
+
+/// Interrupt routes of a TVM.
+pub struct InterruptRoutes(Vec<InterruptRoute>);
+
+impl InterruptRoutes {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// `hvip` of the vCPU `vcpu_id` with VSEIP set by its asserted level routes.
+    pub fn hvip(&self, vcpu_id: usize, hvip: usize) -> usize {
+        let vseip = VsInterruptKind::External as usize;
+        let asserted = self
+            .0
+            .iter()
+            .any(|r| r.vcpu_id == vcpu_id && r.guest_id == 0 && r.asserted);
+        match asserted {
+            true => hvip | vseip,
+            false => hvip & !vseip,
+        }
+    }
+
+    fn find(&self, source: usize) -> Option<usize> {
+        self.0.iter().position(|r| r.source == source)
+    }
+}
+
+impl HypervisorState {
+    pub fn bind_tvm_interrupt(
+        &mut self,
+        tvm_id: usize,
+        source: usize,
+        vcpu_id: usize,
+        guest_id: usize,
+    ) -> CoveResult<()> {
+        let num_ids = self.imsic.map(|imsic| imsic.num_ids);
+        if guest_id != 0 && num_ids.filter(|n| guest_id <= *n || *n == 0).is_none() {
+            return Err(CoveError::InvalidParam("invalid guest interrupt id"));
+        }
+
+        let tvm = self.lifecycle.get_mut(tvm_id)?;
+        if tvm.vcpu(vcpu_id).is_none() {
+            return Err(CoveError::InvalidParam("no vcpu present"));
+        }
+        let routes = &mut tvm.irq_routes.0;
+        if routes.iter().any(|r| r.source == source) {
+            return Err(CoveError::AlreadyAvailable("source already bound"));
+        }
+        if routes.len() == MAX_ROUTES {
+            return Err(CoveError::Failed("too many interrupt routes"));
+        }
+
+        routes.push(InterruptRoute {
+            source,
+            vcpu_id,
+            guest_id,
+            asserted: false,
+        });
+        Ok(())
+    }
+
+    pub fn unbind_tvm_interrupt(&mut self, tvm_id: usize, source: usize) -> CoveResult<()> {
+        let routes = &mut self.lifecycle.get_mut(tvm_id)?.irq_routes;
+        let index = routes
+            .find(source)
+            .ok_or(CoveError::InvalidParam("source not bound"))?;
+        routes.0.swap_remove(index);
+        Ok(())
+    }
+
+    /// Assert (`level` 1) or deassert (`level` 0) `source`.
+    pub fn signal_tvm_interrupt(
+        &mut self,
+        tvm_id: usize,
+        source: usize,
+        level: usize,
+    ) -> CoveResult<()> {
+        if level > 1 {
+            return Err(CoveError::InvalidParam("invalid level"));
+        }
+
+        let tvm = self.lifecycle.get_mut(tvm_id)?;
+        match tvm.state {
+            TvmState::TvmRunnable => {}
+            _ => return Err(CoveError::InvalidState("TVM must be in runnable state")),
+        }
+        let tvm = &mut tvm.data;
+        let index = tvm
+            .irq_routes
+            .find(source)
+            .ok_or(CoveError::InvalidParam("source not bound"))?;
+        let route = &mut tvm.irq_routes.0[index];
+
+        if route.guest_id == 0 {
+            route.asserted = level == 1;
+            return Ok(());
+        }
+        if level == 0 {
+            return Ok(());
+        }
+
+        let binding = tvm
+            .aia
+            .as_ref()
+            .and_then(|aia| aia.active_binding())
+            .ok_or(CoveError::InvalidState("vcpu not bound"))?;
+        unsafe {
+            core::ptr::write_volatile(binding.file_addr as *mut u32, route.guest_id as u32);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,600 @@
+//! TVM migration: export the state of a TVM (memory, vCPU state and measurement) into a
+//! host-provided buffer and import it back into a new TVM, possibly on another host.
+//!
+//! The host never sees plaintext TVM state. The exported blob is a header followed by records:
+//!
+//! |--------|---------|-------------|----------|--------------|
+//! | magic  |  salt   | num_records | reserved | exchange key |
+//! |--------|---------|-------------|----------|--------------|
+//! |   8    |   16    |      4      |    4     |      32      |
+//! |--------|---------|-------------|----------|--------------|
+//!
+//! Each record is `len (u32) | ciphertext (len bytes) | tag (16 bytes)`, sealed with AES-256-GCM
+//! using the record index as nonce and the header as associated data. Record 0 holds the TVM
+//! metadata, the other records hold one 4K guest page each. The key is the transport key
+//! negotiated with the destination TSM (see `common::migration`): the destination makes an offer
+//! with `prepare_tvm_import`, certified by its TSM token. The source only exports to a TSM
+//! certified by its own platform key, and puts its own exchange key and a salt from the firmware
+//! entropy source in the header. The destination drops its exchange secret at the first import,
+//! so a blob is imported at most once.
+//!
+//! The vCPU of the TVM is not executing while the host calls the TSM, which is not reentrant. A
+//! successful export moves the TVM to the exported state: it cannot run or be exported again, so
+//! only the imported copy runs, and the host destroys it to get its pages back.
+
+use aes_gcm::{aead::AeadInPlace, Aes256Gcm, KeyInit, Nonce, Tag};
+use alloc::{boxed::Box, vec::Vec};
+use common::{
+    attestation::{TsmAttestationContext, TvmAttestationContext},
+    measurement::HashAlgorithm,
+    migration::{ExchangeSecret, MigrationOffer, EXCHANGE_KEY_SIZE, MIGRATION_OFFER_SIZE},
+    sbi::{PAGE_SIZE, TVM_GPA_KIND_MASK, TVM_POLICY_MIGRATABLE},
+};
+use core::sync::atomic::Ordering;
+use tsm_core::{CoveError, CoveResult, GuestMemoryMap};
+use zeroize::Zeroize;
+
+use super::{
+    hsm, is_mappable_gpa, map_4k_leaf, ppn_to_pa, HypervisorState, TvmState, TvmVcpuState, PTE_R,
+    PTE_SIZE, PTE_V, PTE_W, PTE_X,
+};
+use crate::{sbi::random_bytes, MEASUREMENT, TVM_POLICY};
+
+const MIGRATION_MAGIC: [u8; 8] = *b"SFXMIG03";
+const HEADER_SIZE: usize = 64;
+const TAG_SIZE: usize = 16;
+const PAGE_RECORD_SIZE: usize = 16 + PAGE_SIZE;
+
+/// Host buffers of an IMPORT_TVM: the blob, the page table and state pages of the new TVM, and
+/// the confidential pool of its guest pages
+pub struct TvmImport {
+    pub blob_addr: usize,
+    pub blob_len: usize,
+    pub page_table_addr: usize,
+    pub state_addr: usize,
+    pub pool_addr: usize,
+    pub pool_pages: usize,
+}
+
+/// Exchange secret of the import prepared by `prepare_tvm_import`, and the offer made with it
+pub(super) struct PendingImport {
+    secret: ExchangeSecret,
+    offer: Vec<u8>,
+}
+
+impl HypervisorState {
+    /// Make the exchange secret of the next `import_tvm` and write its offer, signed with the key
+    /// of the TSM layer `tsm` and certified by its token, at `offer_addr`. The offer of a previous
+    /// call is dropped.
+    pub fn prepare_tvm_import(
+        &mut self,
+        tsm: &TsmAttestationContext,
+        offer_addr: usize,
+    ) -> CoveResult<()> {
+        if self.overlaps_confidential_memory(offer_addr, MIGRATION_OFFER_SIZE) {
+            return Err(CoveError::InvalidAddress(
+                "offer must be in non-confidential memory",
+            ));
+        }
+
+        let secret = new_exchange_secret()?;
+        let offer = MigrationOffer::new(tsm, &secret)
+            .map_err(|_| CoveError::Failed("TSM token too large for the offer"))?
+            .to_bytes();
+        unsafe {
+            core::ptr::copy_nonoverlapping(offer.as_ptr(), offer_addr as *mut u8, offer.len());
+        }
+        self.pending_import = Some(PendingImport { secret, offer });
+        Ok(())
+    }
+
+    /// Export the TVM into `[buf_addr, buf_addr + buf_len)`, for the destination TSM of the
+    /// import offer at `offer_addr`. The destination must be certified by `platform_key`, the key
+    /// of the platform layer of this TSM. The TVM is exported and stops running for good once the
+    /// blob is written. Returns the number of bytes written.
+    pub fn export_tvm(
+        &mut self,
+        tvm_id: usize,
+        buf_addr: usize,
+        buf_len: usize,
+        offer_addr: usize,
+        platform_key: &[u8],
+    ) -> CoveResult<usize> {
+        let tvm = self.lifecycle.get(tvm_id)?;
+        match tvm.state {
+            TvmState::TvmRunnable => {}
+            _ => {
+                return Err(CoveError::InvalidState(
+                    "only runnable TVMs can be exported",
+                ))
+            }
+        }
+        if tvm.encrypted {
+            return Err(CoveError::NotSupported(
+                "software-encrypted TVMs cannot be exported",
+            ));
+        }
+        if tvm.policy & TVM_POLICY_MIGRATABLE == 0 {
+            return Err(CoveError::Denied("migration denied by the TVM policy"));
+        }
+        if self.overlaps_confidential_memory(buf_addr, buf_len)
+            || self.overlaps_confidential_memory(offer_addr, MIGRATION_OFFER_SIZE)
+        {
+            return Err(CoveError::InvalidAddress(
+                "export buffer and offer must be in non-confidential memory",
+            ));
+        }
+        // Check a copy, the host can change the offer at any time
+        let mut offer = alloc::vec![0u8; MIGRATION_OFFER_SIZE];
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                offer_addr as *const u8,
+                offer.as_mut_ptr(),
+                MIGRATION_OFFER_SIZE,
+            );
+        }
+        let destination = MigrationOffer::parse(&offer, platform_key)
+            .map_err(|_| CoveError::Denied("import offer failed authentication"))?;
+        let offer = destination.to_bytes();
+
+        // Only confidential pages belong to the TVM (e.g. IMSIC files are MMIO)
+        let pages: Vec<(usize, usize, u64)> = mapped_leaves(tvm.page_table_addr)
+            .into_iter()
+            .filter(|(_, pa, _)| {
+                self.lifecycle
+                    .confidential_memory()
+                    .is_confidential(*pa, PAGE_SIZE)
+            })
+            .collect();
+
+        let mut metadata = Vec::new();
+        put_u64(&mut metadata, tvm.measurement_algorithm() as u64);
+        put_u64(&mut metadata, tvm.policy as u64);
+        put_u64(&mut metadata, tvm.measure.len() as u64);
+        metadata.extend_from_slice(&tvm.measure);
+        put_u64(&mut metadata, tvm.entry_sepc as u64);
+        put_u64(&mut metadata, tvm.entry_arg as u64);
+        put_u64(&mut metadata, tvm.tvm_identity_addr as u64);
+        put_u64(&mut metadata, tvm.memory_regions.len() as u64);
+        for r in tvm.memory_regions.iter() {
+            put_u64(&mut metadata, r.guest_gpa_base as u64);
+            put_u64(&mut metadata, r.num_pages as u64);
+        }
+        match tvm.vcpus.as_slice() {
+            [vcpu] => {
+                put_u64(&mut metadata, 1);
+                for reg in vcpu_csrs(vcpu).iter().chain(vcpu.regs.iter()) {
+                    put_u64(&mut metadata, *reg as u64);
+                }
+            }
+            [] => put_u64(&mut metadata, 0),
+            _ => {
+                return Err(CoveError::NotSupported(
+                    "TVMs with several vCPUs cannot be exported",
+                ))
+            }
+        }
+
+        let num_records = 1 + pages.len();
+        let total_len = HEADER_SIZE
+            + 4
+            + metadata.len()
+            + TAG_SIZE
+            + pages.len() * (4 + PAGE_RECORD_SIZE + TAG_SIZE);
+        if buf_len < total_len {
+            return Err(CoveError::InvalidParam("export buffer too small"));
+        }
+
+        let secret = new_exchange_secret()?;
+        let mut header = [0u8; HEADER_SIZE];
+        header[0..8].copy_from_slice(&MIGRATION_MAGIC);
+        random_bytes(&mut header[8..24])
+            .map_err(|_| CoveError::Failed("no entropy from the TSM-driver"))?;
+        header[24..28].copy_from_slice(&(num_records as u32).to_le_bytes());
+        header[32..64].copy_from_slice(&secret.public_key());
+
+        let transport = Transport::new(&secret, &destination.exchange_key, header, &offer)?;
+        let mut out = BlobWriter {
+            addr: buf_addr,
+            offset: 0,
+        };
+        out.write(&header);
+
+        transport.seal(0, &mut metadata, &mut out)?;
+
+        let mut record = Vec::with_capacity(PAGE_RECORD_SIZE);
+        for (i, (gpa, pa, perms)) in pages.iter().enumerate() {
+            record.clear();
+            put_u64(&mut record, *gpa as u64);
+            put_u64(&mut record, *perms);
+            record.extend_from_slice(unsafe {
+                core::slice::from_raw_parts(*pa as *const u8, PAGE_SIZE)
+            });
+            transport.seal(i + 1, &mut record, &mut out)?;
+        }
+
+        self.lifecycle.export(tvm_id)?;
+        Ok(out.offset)
+    }
+
+    /// Import a TVM exported by `export_tvm` for the offer of `prepare_tvm_import`. The page
+    /// table and the state page are used as in `create_tvm`, guest pages are taken from the
+    /// confidential pool `[pool_addr, pool_pages)` and owned by the new TVM. Every record is
+    /// authenticated before being used: on failure the partial TVM is destroyed.
+    pub fn import_tvm(
+        &mut self,
+        attestation_context: TvmAttestationContext,
+        import: TvmImport,
+    ) -> CoveResult<usize> {
+        let TvmImport {
+            blob_addr,
+            blob_len,
+            page_table_addr,
+            state_addr,
+            pool_addr,
+            pool_pages,
+        } = import;
+        if blob_len < HEADER_SIZE {
+            return Err(CoveError::InvalidParam("migration blob too small"));
+        }
+        if !pool_addr.is_multiple_of(PAGE_SIZE)
+            || !pool_pages.checked_mul(PAGE_SIZE).is_some_and(|size| {
+                self.lifecycle
+                    .confidential_memory()
+                    .is_confidential(pool_addr, size)
+            })
+        {
+            return Err(CoveError::InvalidAddress(
+                "page pool not in confidential memory",
+            ));
+        }
+
+        let mut input = BlobReader {
+            addr: blob_addr,
+            len: blob_len,
+            offset: 0,
+        };
+        let mut header = [0u8; HEADER_SIZE];
+        header.copy_from_slice(input.read(HEADER_SIZE)?);
+        if header[0..8] != MIGRATION_MAGIC {
+            return Err(CoveError::InvalidParam("invalid migration blob"));
+        }
+        let num_records = u32::from_le_bytes(header[24..28].try_into().unwrap()) as usize;
+        if num_records == 0 || num_records - 1 > pool_pages {
+            return Err(CoveError::InvalidParam(
+                "not enough pages to import the TVM",
+            ));
+        }
+
+        // The exchange secret goes with the first import: the same blob cannot be imported twice
+        let pending = self
+            .pending_import
+            .take()
+            .ok_or(CoveError::InvalidState("no import prepared"))?;
+        let source_key = header[32..64].try_into().unwrap();
+        let transport = Transport::new(&pending.secret, source_key, header, &pending.offer)?;
+        let metadata = transport.open(0, &mut input)?;
+        let metadata = TvmMetadata::parse(&metadata)?;
+
+        let tvm_id = self.create_tvm(
+            attestation_context,
+            page_table_addr,
+            state_addr,
+            metadata.measurement_alg,
+            metadata.policy,
+            None,
+        )?;
+
+        let ret = self.import_pages(
+            tvm_id,
+            &transport,
+            num_records,
+            &mut input,
+            pool_addr,
+            &metadata.memory_regions,
+        );
+        if let Err(e) = ret {
+            self.destroy_tvm()?;
+            return Err(e);
+        }
+
+        // The TVM runs as exported, with no call of the initializing state in between
+        let tvm = self.lifecycle.finalize(tvm_id)?;
+        tvm.memory_regions = metadata.memory_regions;
+        if let Some((csrs, regs)) = metadata.vcpu {
+            let mut vcpu = TvmVcpuState::new(0);
+            vcpu.regs = regs;
+            [
+                vcpu.sstatus,
+                vcpu.stvec,
+                vcpu.sip,
+                vcpu.satp,
+                vcpu.sepc,
+                vcpu.scause,
+                vcpu.stval,
+            ] = csrs;
+            tvm.vcpus.push(Box::new(vcpu));
+        }
+        hsm::reset(tvm.vcpus.iter().map(|vcpu| vcpu.id));
+        tvm.entry_sepc = metadata.entry_sepc;
+        tvm.entry_arg = metadata.entry_arg;
+        tvm.tvm_identity_addr = metadata.tvm_identity_addr;
+        tvm.measure = metadata.measure;
+        TVM_POLICY.store(tvm.policy, Ordering::Relaxed);
+        MEASUREMENT
+            .lock()
+            .replace((metadata.measurement_alg, tvm.measure.clone()));
+
+        Ok(tvm_id)
+    }
+
+    fn import_pages(
+        &mut self,
+        tvm_id: usize,
+        transport: &Transport,
+        num_records: usize,
+        input: &mut BlobReader,
+        pool_addr: usize,
+        memory_regions: &GuestMemoryMap,
+    ) -> CoveResult<()> {
+        let root_pt = self.lifecycle.get(tvm_id)?.page_table_addr;
+
+        for i in 1..num_records {
+            let record = transport.open(i, input)?;
+            if record.len() != PAGE_RECORD_SIZE {
+                return Err(CoveError::InvalidParam("invalid page record"));
+            }
+            let gpa = u64::from_le_bytes(record[0..8].try_into().unwrap()) as usize;
+            let perms = u64::from_le_bytes(record[8..16].try_into().unwrap());
+            if !gpa.is_multiple_of(PAGE_SIZE)
+                || !memory_regions.contains(gpa, PAGE_SIZE)
+                || !is_mappable_gpa(gpa)
+            {
+                return Err(CoveError::InvalidAddress(
+                    "imported page outside of the TVM memory regions",
+                ));
+            }
+
+            // Fails before the copy if another TVM owns the page
+            let pa = pool_addr + (i - 1) * PAGE_SIZE;
+            self.lifecycle
+                .confidential_memory_mut()
+                .claim(pa, PAGE_SIZE, tvm_id)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(record[16..].as_ptr(), pa as *mut u8, PAGE_SIZE);
+            }
+            let perms = perms & (PTE_R | PTE_W | PTE_X | TVM_GPA_KIND_MASK);
+            map_4k_leaf(root_pt, gpa, pa, perms);
+        }
+        Ok(())
+    }
+}
+
+struct TvmMetadata {
+    measurement_alg: HashAlgorithm,
+    policy: usize,
+    measure: Vec<u8>,
+    entry_sepc: usize,
+    entry_arg: usize,
+    tvm_identity_addr: usize,
+    memory_regions: GuestMemoryMap,
+    vcpu: Option<([usize; 7], [usize; 32])>,
+}
+
+impl TvmMetadata {
+    fn parse(buf: &[u8]) -> CoveResult<Self> {
+        let mut r = SliceReader { buf, offset: 0 };
+
+        let measurement_alg = HashAlgorithm::from_id(r.usize()?)
+            .ok_or(CoveError::InvalidParam("invalid measurement algorithm"))?;
+        let policy = r.usize()?;
+        let measure_len = r.usize()?;
+        let measure = r.bytes(measure_len)?.to_vec();
+        let entry_sepc = r.usize()?;
+        let entry_arg = r.usize()?;
+        let tvm_identity_addr = r.usize()?;
+
+        let num_regions = r.usize()?;
+        let mut memory_regions = GuestMemoryMap::new();
+        for _ in 0..num_regions {
+            let gpa = r.usize()?;
+            let num_pages = r.usize()?;
+            let len = num_pages
+                .checked_mul(PAGE_SIZE)
+                .ok_or(CoveError::InvalidParam("invalid memory region"))?;
+            memory_regions.add_region(gpa, len)?;
+        }
+
+        let vcpu = if r.usize()? != 0 {
+            let mut csrs = [0; 7];
+            for csr in csrs.iter_mut() {
+                *csr = r.usize()?;
+            }
+            let mut regs = [0; 32];
+            for reg in regs.iter_mut() {
+                *reg = r.usize()?;
+            }
+            Some((csrs, regs))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            measurement_alg,
+            policy,
+            measure,
+            entry_sepc,
+            entry_arg,
+            tvm_identity_addr,
+            memory_regions,
+            vcpu,
+        })
+    }
+}
+
+fn vcpu_csrs(vcpu: &TvmVcpuState) -> [usize; 7] {
+    [
+        vcpu.sstatus,
+        vcpu.stvec,
+        vcpu.sip,
+        vcpu.satp,
+        vcpu.sepc,
+        vcpu.scause,
+        vcpu.stval,
+    ]
+}
+
+/// Walk the G-stage page table and return (gpa, pa, pte permissions) for every 4K leaf.
+fn mapped_leaves(root_pt: usize) -> Vec<(usize, usize, u64)> {
+    let read_pte = |addr: usize| unsafe { core::ptr::read_volatile(addr as *const u64) };
+    let is_leaf = |pte: u64| pte & (PTE_R | PTE_W | PTE_X) != 0;
+    let mut leaves = Vec::new();
+
+    for vpn2 in 0..512 {
+        let pte2 = read_pte(root_pt + vpn2 * PTE_SIZE);
+        if pte2 & PTE_V == 0 || is_leaf(pte2) {
+            continue;
+        }
+        let l1_base = ppn_to_pa(pte2 >> 10);
+        for vpn1 in 0..512 {
+            let pte1 = read_pte(l1_base + vpn1 * PTE_SIZE);
+            if pte1 & PTE_V == 0 || is_leaf(pte1) {
+                continue;
+            }
+            let l0_base = ppn_to_pa(pte1 >> 10);
+            for vpn0 in 0..512 {
+                let pte0 = read_pte(l0_base + vpn0 * PTE_SIZE);
+                if pte0 & PTE_V == 0 || !is_leaf(pte0) {
+                    continue;
+                }
+                let gpa = (vpn2 << 30) | (vpn1 << 21) | (vpn0 << 12);
+                let perms = pte0 & (PTE_R | PTE_W | PTE_X | TVM_GPA_KIND_MASK);
+                leaves.push((gpa, ppn_to_pa(pte0 >> 10), perms));
+            }
+        }
+    }
+    leaves
+}
+
+/// Exchange secret from the firmware entropy source
+fn new_exchange_secret() -> CoveResult<ExchangeSecret> {
+    let mut random = [0u8; 32];
+    random_bytes(&mut random).map_err(|_| CoveError::Failed("no entropy from the TSM-driver"))?;
+    let secret = ExchangeSecret::new(random);
+    random.zeroize();
+    Ok(secret)
+}
+
+fn record_nonce(index: usize) -> Nonce<aes_gcm::aes::cipher::consts::U12> {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&(index as u64).to_le_bytes());
+    nonce.into()
+}
+
+/// Cipher of the records of a blob, authenticated with its header
+struct Transport {
+    cipher: Aes256Gcm,
+    header: [u8; HEADER_SIZE],
+}
+
+impl Transport {
+    /// Cipher of the transport key shared with the owner of `peer_key`. The key is bound to the
+    /// header, with its random salt, and to the offer of the destination.
+    fn new(
+        secret: &ExchangeSecret,
+        peer_key: &[u8; EXCHANGE_KEY_SIZE],
+        header: [u8; HEADER_SIZE],
+        offer: &[u8],
+    ) -> CoveResult<Self> {
+        let key = secret
+            .transport_key(peer_key, &[&header, offer])
+            .map_err(|_| CoveError::Denied("invalid exchange key"))?;
+        Ok(Self {
+            cipher: Aes256Gcm::new_from_slice(&key).unwrap(),
+            header,
+        })
+    }
+
+    /// Encrypt `plaintext` in place (in TSM memory) and copy the record out.
+    fn seal(&self, index: usize, plaintext: &mut [u8], out: &mut BlobWriter) -> CoveResult<()> {
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(&record_nonce(index), &self.header, plaintext)
+            .map_err(|_| CoveError::Failed("record encryption failed"))?;
+        out.write(&(plaintext.len() as u32).to_le_bytes());
+        out.write(plaintext);
+        out.write(&tag);
+        Ok(())
+    }
+
+    /// Copy the next record into TSM memory and authenticate it.
+    fn open(&self, index: usize, input: &mut BlobReader) -> CoveResult<Vec<u8>> {
+        let len = u32::from_le_bytes(input.read(4)?.try_into().unwrap()) as usize;
+        let mut record = input.read(len)?.to_vec();
+        let tag = Tag::clone_from_slice(input.read(TAG_SIZE)?);
+        self.cipher
+            .decrypt_in_place_detached(&record_nonce(index), &self.header, &mut record, &tag)
+            .map_err(|_| CoveError::Denied("migration record failed authentication"))?;
+        Ok(record)
+    }
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+struct BlobWriter {
+    addr: usize,
+    offset: usize,
+}
+
+impl BlobWriter {
+    fn write(&mut self, data: &[u8]) {
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                (self.addr + self.offset) as *mut u8,
+                data.len(),
+            );
+        }
+        self.offset += data.len();
+    }
+}
+
+struct BlobReader {
+    addr: usize,
+    len: usize,
+    offset: usize,
+}
+
+impl BlobReader {
+    fn read(&mut self, len: usize) -> CoveResult<&[u8]> {
+        if self.offset + len > self.len {
+            return Err(CoveError::InvalidParam("truncated migration blob"));
+        }
+        let data =
+            unsafe { core::slice::from_raw_parts((self.addr + self.offset) as *const u8, len) };
+        self.offset += len;
+        Ok(data)
+    }
+}
+
+struct SliceReader<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> SliceReader<'a> {
+    fn bytes(&mut self, len: usize) -> CoveResult<&'a [u8]> {
+        let data = self
+            .buf
+            .get(self.offset..self.offset + len)
+            .ok_or(CoveError::InvalidParam("truncated TVM metadata"))?;
+        self.offset += len;
+        Ok(data)
+    }
+
+    fn usize(&mut self) -> CoveResult<usize> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()) as usize)
+    }
+}
@@ -0,0 +1,109 @@
+//! Sealed boot: the pages of a TVM loaded from a sealed image (see `common::sealed_image`). Each
+//! record is copied into its confidential destination page and opened in place, so the plaintext
+//! only ever exists in confidential memory. The plaintext is measured as if it was added with
+//! `sbi_covh_add_tvm_measured_pages`: the owner of the workload computes the expected measurement
+//! from the plaintext image, without the key.
+//!
+//! A record failing authentication fails the whole call: the destination pages are zeroed and the
+//! TVM measurement is left untouched.
+
+use aes_gcm::{aead::AeadInPlace, Aes256Gcm, KeyInit, Nonce, Tag};
+use alloc::vec::Vec;
+use common::{
+    attestation::{Cdi, KEY_LADDER_OWNER_TSM},
+    sbi::{MeasuredPageDesc, PAGE_SIZE},
+    sealed_image::{record_nonce, SealedImageHeader, SEALED_IMAGE_TAG_SIZE},
+};
+use tsm_core::{CoveError, CoveResult};
+
+use super::HypervisorState;
+
+impl HypervisorState {
+    /// Open the sealed image at `image_addr`, described by `header`, into the confidential pages
+    /// at `dest_addr` and map them at the GPA of the image.
+    pub fn add_tvm_sealed_image(
+        &mut self,
+        cdi: &Cdi,
+        tvm_id: usize,
+        image_addr: usize,
+        header: &SealedImageHeader,
+        dest_addr: usize,
+    ) -> CoveResult<()> {
+        if self.is_encrypted_tvm() {
+            return Err(CoveError::NotSupported(
+                "software-encrypted TVMs cannot load sealed images",
+            ));
+        }
+        if self.overlaps_confidential_memory(image_addr, header.image_size()) {
+            return Err(CoveError::InvalidAddress(
+                "sealed image must be in non-confidential memory",
+            ));
+        }
+
+        let pages: Vec<MeasuredPageDesc> = (0..header.num_pages as usize)
+            .map(|i| MeasuredPageDesc {
+                source_addr: image_addr + SealedImageHeader::record_offset(i),
+                dest_addr: dest_addr + i * PAGE_SIZE,
+                tvm_guest_gpa: header.gpa as usize + i * PAGE_SIZE,
+            })
+            .collect();
+        let alg = self.check_measured_pages(tvm_id, &pages)?;
+        let mut hasher = alg
+            .hasher()
+            .ok_or(CoveError::NotSupported("unsupported measurement algorithm"))?;
+
+        let key = cdi
+            .ladder_key(KEY_LADDER_OWNER_TSM, &header.key_label())
+            .unwrap();
+        let cipher = Aes256Gcm::new_from_slice(&key).unwrap();
+        let aad = header.to_bytes();
+
+        let mut digests = Vec::with_capacity(pages.len());
+        for (i, page) in pages.iter().enumerate() {
+            if let Err(e) = open_page(&cipher, &aad, i, page) {
+                for page in &pages {
+                    unsafe { core::ptr::write_bytes(page.dest_addr as *mut u8, 0, PAGE_SIZE) };
+                }
+                return Err(e);
+            }
+            hasher.extend(unsafe {
+                core::slice::from_raw_parts(page.dest_addr as *const u8, PAGE_SIZE)
+            });
+            digests.push(hasher.finalize_reset());
+        }
+
+        self.commit_measured_pages(tvm_id, &pages, &digests)
+    }
+}
+
+/// Copy the record of page `index` into its destination and open it there.
+fn open_page(
+    cipher: &Aes256Gcm,
+    aad: &[u8],
+    index: usize,
+    page: &MeasuredPageDesc,
+) -> CoveResult<()> {
+    // The host may change the image during the call: the tag is read once, into TSM memory
+    let mut tag = [0u8; SEALED_IMAGE_TAG_SIZE];
+    let dest = unsafe {
+        core::ptr::copy_nonoverlapping(
+            page.source_addr as *const u8,
+            page.dest_addr as *mut u8,
+            PAGE_SIZE,
+        );
+        core::ptr::copy_nonoverlapping(
+            (page.source_addr + PAGE_SIZE) as *const u8,
+            tag.as_mut_ptr(),
+            SEALED_IMAGE_TAG_SIZE,
+        );
+        core::slice::from_raw_parts_mut(page.dest_addr as *mut u8, PAGE_SIZE)
+    };
+    cipher
+        .decrypt_in_place_detached(
+            Nonce::from_slice(&record_nonce(index)),
+            aad,
+            dest,
+            Tag::from_slice(&tag),
+        )
+        .map_err(|_| CoveError::Denied("sealed image failed authentication"))
+}
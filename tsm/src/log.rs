@@ -7,22 +7,24 @@ use common::sbi::sbi_call;
 const EDBCN: usize = 0x4442434E;
 const CONSOLE_WRITE_FID: usize = 0x0;
 
-/// Writer for print macro.
+/// Writer for print macro, on the SBI debug console of the firmware.
 struct Writer;
 impl core::fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        sbi_call(
-            EDBCN,
-            CONSOLE_WRITE_FID,
-            &[
-                s.len(),
-                s.as_ptr() as usize & 0xffff_ffff,
-                (s.as_ptr() as usize >> 32) & 0xffff_ffff,
-                0,
-                0,
-                0,
-            ],
-        );
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            // On RV64 the whole address goes in base_addr_lo, base_addr_hi is 0
+            let ret = sbi_call(
+                EDBCN,
+                CONSOLE_WRITE_FID,
+                &[bytes.len(), bytes.as_ptr() as usize, 0, 0, 0, 0],
+            );
+            // The console may write less than asked
+            match ret.a0 {
+                0 if ret.a1 > 0 => bytes = &bytes[(ret.a1 as usize).min(bytes.len())..],
+                _ => return Err(fmt::Error),
+            }
+        }
         Ok(())
     }
 }
@@ -30,7 +32,8 @@ impl core::fmt::Write for Writer {
 /// Print function calling from print macro
 pub fn print_for_macro(args: fmt::Arguments) {
     let mut writer = Writer;
-    writer.write_fmt(args).unwrap();
+    // nowhere to report a console error
+    let _ = writer.write_fmt(args);
 }
 
 /// Print to standard output.
@@ -3,25 +3,43 @@
 #![feature(never_type)]
 #![feature(fn_align)]
 
-use core::panic::PanicInfo;
+use core::{
+    alloc::Layout,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 
 use alloc::vec::Vec;
 use common::{
-    attestation::{DiceLayer, TsmAttestationContext},
+    arch_attribute,
+    attestation::{Cdi, DiceLayer, TsmAttestationContext},
+    boot_manifest::{self, ManifestError, BOOT_MANIFEST_ADDR},
+    config::MAX_VCPUS_PER_TVM,
+    heap::{HeapStats, TrackedHeap},
+    measurement::HashAlgorithm,
     sbi::{
-        SbiRet, SBI_COVH_ADD_TVM_MEASURED_PAGES, SBI_COVH_ADD_TVM_MEMORY_REGION,
-        SBI_COVH_ADD_ZERO_PAGES, SBI_COVH_CONVERT_PAGES, SBI_COVH_CREATE_TVM,
-        SBI_COVH_CREATE_TVM_VCPU, SBI_COVH_DESTROY_TVM, SBI_COVH_EXT_ID, SBI_COVH_FINALIZE_TVM,
-        SBI_COVH_GET_TSM_INFO, SBI_COVH_RECLAIM_PAGES, SBI_COVH_RUN_TVM_VCPU,
+        cove_unpack_fid, sbi_call, CovhQueueEntry, ImsicInfo, SbiRet, TsmIdentity, TsmMemoryStats,
+        TsmStats, TvmGpaTranslation, TvmVcpuTime, COVE_TSM_CAP_AIA, SBI_COVH_EXT_ID,
+        SBI_COVI_BIND_AIA_IMSIC, SBI_COVI_BIND_TVM_INTERRUPT, SBI_COVI_CONVERT_AIA_IMSIC,
+        SBI_COVI_EXT_ID, SBI_COVI_INIT_TVM_AIA, SBI_COVI_INJECT_TVM_CPU_INTERRUPT,
+        SBI_COVI_RECLAIM_AIA_IMSIC, SBI_COVI_SET_TVM_AIA_CPU_IMSIC_ADDR,
+        SBI_COVI_SIGNAL_TVM_INTERRUPT, SBI_COVI_UNBIND_AIA_IMSIC_BEGIN,
+        SBI_COVI_UNBIND_AIA_IMSIC_END, SBI_COVI_UNBIND_TVM_INTERRUPT,
+        SBI_EXT_SUPD_TSM_INFO_CHANGED, SBI_SUPD_EXT_ID, SHADOWFAX_TSM_CAP_DOMAIN_MODE,
+        SHADOWFAX_TSM_CAP_SW_PAGE_ENCRYPTION, SHADOWFAX_TSM_CAP_TRANSLATE_GPA, TSM_STATUS_LOADED,
+        TSM_STATUS_READY,
     },
 };
-use linked_list_allocator::LockedHeap;
 use spin::Mutex;
+use tsm_core::{
+    covh::{measured_chunk, queue_entry_addr},
+    CoveError, CovhCall, GuestMemoryMap, RawMemory,
+};
 
 use crate::{
     hyper::HypervisorState,
     perf::{read_cycle, read_instret, read_time},
-    state::{TsmInfo, TSM_IMPL_ID, TSM_VERSION},
+    state::{TsmBuildInfo, TsmInfo, MEASURE_CHUNK_PAGES, TSM_BUILD_ID, TSM_IMPL_ID, TSM_VERSION},
 };
 
 mod h_extension;
@@ -30,14 +48,66 @@ mod log;
 mod perf;
 mod sbi;
 mod state;
+mod stats;
+mod watermark;
 
+#[cfg(feature = "embed-elf")]
 #[link_section = ".rodata"]
 pub static GUEST_ELF: &[u8] = include_bytes!("../../guests/attestation.out");
 
+/// Guest of the bootstrap tests: the `attestation` entry of the boot manifest, else `GUEST_ELF`.
+pub fn guest_elf() -> &'static [u8] {
+    match unsafe { boot_manifest::find(BOOT_MANIFEST_ADDR, "attestation") } {
+        Ok(image) => return image.data,
+        Err(ManifestError::NoManifest) => {}
+        Err(e) => println!("[TSM] no guest in the boot manifest: {:?}", e),
+    }
+
+    #[cfg(feature = "embed-elf")]
+    return GUEST_ELF;
+    #[cfg(not(feature = "embed-elf"))]
+    panic!("no guest image, build with the embed-elf feature")
+}
+
 extern crate alloc;
 #[global_allocator]
 /// Global allocator.
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+pub static ALLOCATOR: TrackedHeap = TrackedHeap::new(&HEAP_TAGS, heap_oom);
+
+/// Heap tags of the TSM subsystems
+pub const HEAP_TAG_TVM: usize = 1;
+pub const HEAP_TAG_ATTESTATION: usize = 2;
+const HEAP_TAGS: [&str; 3] = ["tsm", "tvm", "attestation"];
+
+/// Set when an allocation failed: the TSM is no longer reported as ready, as after a stack
+/// overflow (see `watermark`).
+static HEAP_EXHAUSTED: AtomicBool = AtomicBool::new(false);
+
+/// Set once the TSM-driver knows the TSM is no longer ready: it answers GET_TSM_INFO from the
+/// last answer of the TSM until then.
+static DEGRADED_SIGNALED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the TSM lost its heap or its stack, and reports itself as loaded only.
+fn degraded() -> bool {
+    HEAP_EXHAUSTED.load(Ordering::Relaxed) || watermark::stack_overflowed()
+}
+
+/// Tell the TSM-driver, once, that GET_TSM_INFO no longer reports the TSM as ready.
+fn signal_degraded() {
+    if degraded() && !DEGRADED_SIGNALED.swap(true, Ordering::Relaxed) {
+        sbi_call(SBI_SUPD_EXT_ID, SBI_EXT_SUPD_TSM_INFO_CHANGED, &[0; 6]);
+    }
+}
+
+fn heap_oom(layout: Layout, stats: &HeapStats) {
+    HEAP_EXHAUSTED.store(true, Ordering::Relaxed);
+    println!(
+        "[TSM] out of memory allocating {} bytes (align {}): {}",
+        layout.size(),
+        layout.align(),
+        stats
+    );
+}
 
 unsafe extern "C" {
     /// boot stack top (defined in `memory.x`)
@@ -71,9 +141,8 @@ extern "C" fn _start() -> ! {
      *
      */
     core::arch::naked_asm!(
+        arch_attribute!(),
         r#"
-        .attribute arch, "rv64imac"
-
         // setup up the stack
         li t1, {stack_size_per_hart}
         la sp, {stack_top}
@@ -90,46 +159,107 @@ extern "C" fn _start() -> ! {
 
 pub struct TsmState {
     info: TsmInfo,
+    build_info: TsmBuildInfo,
     pub hypervisor: HypervisorState,
     pub attestation_context: TsmAttestationContext,
 }
 
 impl TsmState {
-    fn new(attestation_context: TsmAttestationContext) -> Self {
+    fn new(
+        attestation_context: TsmAttestationContext,
+        identity: TsmIdentity,
+        imsic: Option<ImsicInfo>,
+        h_extension: bool,
+    ) -> Self {
+        // A hart with the extension but without Sv39x4 cannot run TVMs either
+        let h_extension = h_extension && hyper::g_stage_supported();
+        if !h_extension {
+            println!("[TSM] no hypervisor extension, running in domain mode");
+        }
+        let hypervisor = HypervisorState::new(imsic, h_extension);
+        let mut tsm_capabilities = if h_extension {
+            1 << SHADOWFAX_TSM_CAP_SW_PAGE_ENCRYPTION
+        } else {
+            1 << SHADOWFAX_TSM_CAP_DOMAIN_MODE
+        };
+        if hypervisor.aia_supported() {
+            tsm_capabilities |= 1 << COVE_TSM_CAP_AIA;
+        }
+        if cfg!(debug_assertions) {
+            tsm_capabilities |= 1 << SHADOWFAX_TSM_CAP_TRANSLATE_GPA;
+        }
+
         Self {
             info: TsmInfo {
-                tsm_status: state::TsmStatus::TsmReady,
+                // Ready at the end of `_secure_init`
+                tsm_status: TSM_STATUS_LOADED,
                 tsm_impl_id: TSM_IMPL_ID,
                 tsm_version: TSM_VERSION,
                 _padding: 0,
-                tsm_capabilities: 0,
-                tvm_state_pages: 1,
-                tvm_max_vcpus: 1,
-                tvm_vcpu_state_pages: 1,
+                tsm_capabilities,
+                tvm_state_pages: hyper::TVM_STATE_PAGES,
+                tvm_max_vcpus: MAX_VCPUS_PER_TVM,
+                tvm_vcpu_state_pages: hyper::TVM_VCPU_STATE_PAGES,
+            },
+            build_info: TsmBuildInfo {
+                identity,
+                build_id: TSM_BUILD_ID,
             },
-            hypervisor: HypervisorState::new(),
+            hypervisor,
             attestation_context,
         }
     }
 }
 
 pub static STATE: Mutex<Option<TsmState>> = Mutex::new(None);
-pub static MEASUREMENT: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+pub static MEASUREMENT: Mutex<Option<(HashAlgorithm, Vec<u8>)>> = Mutex::new(None);
 pub static ATTESTATION_CONTEXT: Mutex<Option<TsmAttestationContext>> = Mutex::new(None);
+pub static TVM_COUNTERS: Mutex<Option<sbi::TvmCounters>> = Mutex::new(None);
+pub static TVM_BLOBS: Mutex<Option<sbi::TvmBlobs>> = Mutex::new(None);
+/// CDI of the finalized TVM, the root of its key ladder
+pub static TVM_CDI: Mutex<Option<Cdi>> = Mutex::new(None);
+/// `TVM_POLICY_*` flags of the finalized TVM, checked by the guest services
+pub static TVM_POLICY: AtomicUsize = AtomicUsize::new(0);
+/// Pages the host shares with the TVM, where the guest services accept buffers
+pub static TVM_SHARED_MEMORY: Mutex<GuestMemoryMap> = Mutex::new(GuestMemoryMap::new());
+
+/// Returned for the calls needing the hypervisor extension, in domain mode
+const SBI_ERR_NOT_SUPPORTED: isize = -2;
+/// SBI_ERR_INVALID_STATE: returned before `_secure_init`
+const SBI_ERR_NOT_READY: isize = -10;
+
+/// a6 of the TEECALL being served, the TEERET gives it back to the TSM-driver
+pub static TEECALL_FID: AtomicUsize = AtomicUsize::new(0);
 
 #[no_mangle]
 #[allow(dead_code)]
 #[inline(never)]
 #[link_section = "._secure_init"]
 /// This function will be called by the TSM-driver to initialize securely the TSM after the
-/// signature has bee authenticated.
-fn _secure_init(addr: usize) {
+/// signature has bee authenticated. `imsic_addr` points to the `ImsicInfo` of the platform or is
+/// 0 if AIA is not available. `h_extension` is 0 if the harts lack the hypervisor extension: the
+/// TSM runs in domain mode (see `hyper::domain`). `identity_addr` points to the `TsmIdentity`
+/// computed by the TSM-driver, 0 when testing. `crypto_features` selects the crypto backend of the
+/// hashes (see `common::crypto`). Returns `TSM_STATUS_READY` once the TSM takes
+/// TEECALLs, the TSM-driver does not forward them before.
+fn _secure_init(
+    addr: usize,
+    imsic_addr: usize,
+    h_extension: usize,
+    identity_addr: usize,
+    crypto_features: usize,
+) -> usize {
+    common::crypto::select(crypto_features);
+    watermark::paint_stack();
+
     // Initialize heap
     unsafe {
         let heap_start = (&raw const _heap_start as *const u8) as usize;
         let heap_size = ((&raw const _heap_end as *const u8) as usize) - heap_start;
 
-        ALLOCATOR.lock().init(heap_start as *mut u8, heap_size);
+        ALLOCATOR
+            .init(heap_start as *mut u8, heap_size)
+            .expect("cannot initialize the heap, check _heap_size");
     }
     // 2. Prepare the Initial Context
     // If addr is 0 (Testing), create a fresh default context ON THE HEAP.
@@ -140,19 +270,37 @@ fn _secure_init(addr: usize) {
     } else {
         unsafe { (*(addr as *const TsmAttestationContext)).clone() }
     };
+    let imsic = if imsic_addr == 0 {
+        None
+    } else {
+        unsafe { Some(core::ptr::read(imsic_addr as *const ImsicInfo)) }
+    };
+    let identity = if identity_addr == 0 {
+        TsmIdentity::UNKNOWN
+    } else {
+        unsafe { core::ptr::read(identity_addr as *const TsmIdentity) }
+    };
 
     // 3. Update Global State
     // We clone into State and Attestation Context.
     // Since heap is Init, these clones allocate safely.
     let mut state = STATE.lock();
-    state.replace(TsmState::new(initial_context.clone()));
+    state.replace(TsmState::new(
+        initial_context.clone(),
+        identity,
+        imsic,
+        h_extension != 0,
+    ));
 
     let mut att = ATTESTATION_CONTEXT.lock();
     att.replace(initial_context);
-    // *state = Some(TsmState::new(payload.clone()));
+
+    // 4. Heap and attestation context in place, the TSM is ready
+    state.as_mut().unwrap().info.tsm_status = TSM_STATUS_READY;
 
     drop(state);
     drop(att);
+    TSM_STATUS_READY as usize
 }
 
 // Since this is a TSM with non reentrant model, an ECALL should be a TEERET
@@ -166,20 +314,41 @@ fn main(
     a6: usize,
     a7: usize,
 ) -> ! {
-    // The TSM should be called only for CoVH.
-    assert_eq!(a7, SBI_COVH_EXT_ID);
+    TEECALL_FID.store(a6, Ordering::Relaxed);
 
-    let ret = handle_covh(a0, a1, a2, a3, a4, a5, a6);
+    if STATE.lock().is_none() {
+        teeret(SbiRet {
+            a0: SBI_ERR_NOT_READY,
+            a1: 0,
+        });
+    }
 
-    // Issue the TEERET
+    // The TSM should be called only for CoVH and CoVI.
+    let ret = match a7 {
+        SBI_COVH_EXT_ID => handle_covh(a0, a1, a2, a3, a4, a5, a6),
+        SBI_COVI_EXT_ID => handle_covi(a0, a1, a2, a3, a6),
+        _ => panic!("unexpected extension {:#x}", a7),
+    };
+
+    signal_degraded();
+    teeret(ret)
+}
+
+/// Return `ret` to the caller of the TEECALL. The next TEECALL enters the TSM from `_start`.
+pub fn teeret(ret: SbiRet) -> ! {
+    // A TEERET the TSM-driver rejects comes back with an error in a0: try again
     unsafe {
         core::arch::asm!(
             "
+            1:
+            mv a0, t0
+            mv a1, t1
             ecall
+            j 1b
             ",
-            in("a0") ret.a0,
-            in("a1") ret.a1,
-            in("a6") a6,
+            in("t0") ret.a0,
+            in("t1") ret.a1,
+            in("a6") TEECALL_FID.load(Ordering::Relaxed),
             in("a7") SBI_COVH_EXT_ID,
             options(noreturn)
         );
@@ -195,100 +364,562 @@ fn handle_covh(
     a5: usize,
     a6: usize,
 ) -> SbiRet {
-    let mut lock = STATE.lock();
-    let state = lock.as_mut().unwrap();
-
     // fid is formated as:
     // bits[31:26]: SDID target
     // bits[15:0]: function ID
-    let fid = a6 & 0xFFFF;
+    let (_, fid) = cove_unpack_fid(a6);
+    stats::count(&stats::TEECALLS, 1);
+
+    let call = match CovhCall::decode(fid, [a0, a1, a2, a3, a4, a5], &RawMemory) {
+        Ok(call) => call,
+        Err(e) => {
+            return SbiRet {
+                a0: e.sbi_error(),
+                a1: 0,
+            }
+        }
+    };
+
+    let zero_pages = matches!(call, CovhCall::AddTvmZeroPages { .. });
+    let ret = match call {
+        CovhCall::ProcessQueue { addr, count } => process_queue(addr, count),
+        call => execute_covh(call),
+    };
+
+    // Between two calls the TSM has nothing else to do: zero a few pages ahead of ADD_ZERO_PAGES,
+    // but not on that hot path
+    if !zero_pages {
+        if let Some(state) = STATE.lock().as_mut() {
+            state.hypervisor.fill_zero_pool(hyper::ZERO_POOL_IDLE_PAGES);
+        }
+    }
+    ret
+}
+
+/// Execute the `count` queued calls at `addr` in order and write the result of each one in its
+/// entry. The queue stops at the first failing call, or at a measured-pages call with pages left
+/// (its entry holds the pages added), returning its index in a1.
+fn process_queue(addr: usize, count: usize) -> SbiRet {
+    let size = count * core::mem::size_of::<CovhQueueEntry>();
+    if STATE
+        .lock()
+        .as_ref()
+        .unwrap()
+        .hypervisor
+        .overlaps_confidential_memory(addr, size)
+    {
+        return SbiRet { a0: -1, a1: 0 };
+    }
+
+    for index in 0..count {
+        // Pages of a queued measured-pages call, which may be added only in part
+        let mut measured = None;
+        let ret = match CovhCall::decode_queued(&RawMemory, addr, index) {
+            Ok(call) => {
+                if let CovhCall::AddTvmMeasuredPages { pages, .. } = &call {
+                    measured = Some(pages.len());
+                }
+                execute_covh(call)
+            }
+            Err(e) => SbiRet {
+                a0: e.sbi_error(),
+                a1: 0,
+            },
+        };
+        let entry = queue_entry_addr(addr, index) as *mut CovhQueueEntry;
+        unsafe {
+            (&raw mut (*entry).error).write(ret.a0);
+            (&raw mut (*entry).value).write(ret.a1);
+        }
+        // The queue stops at an entry with pages left, the host resubmits it from there
+        let partial = measured.is_some_and(|pages| (ret.a1 as usize) < pages);
+        if ret.a0 != 0 || partial {
+            return SbiRet {
+                a0: ret.a0,
+                a1: index as isize,
+            };
+        }
+    }
+    SbiRet {
+        a0: 0,
+        a1: count as isize,
+    }
+}
+
+/// Execute a decoded COVH call, issued alone or queued, and count the pages it handled.
+fn execute_covh(call: CovhCall) -> SbiRet {
+    let pages = stats::pages_of(&call);
+    let ret = execute_covh_call(call);
+    if let (0, Some((counter, num_pages))) = (ret.a0, pages) {
+        stats::count(counter, num_pages);
+    }
+    ret
+}
 
-    match fid {
-        SBI_COVH_GET_TSM_INFO => {
-            assert!(a1 >= core::mem::size_of::<TsmInfo>());
+fn execute_covh_call(call: CovhCall) -> SbiRet {
+    // Measured pages take the state lock only while validating and committing, so they are
+    // handled before locking for the remaining calls. A chunk of them at a time: a1 tells the
+    // host how many were added, it issues the call again for the rest.
+    if let CovhCall::AddTvmMeasuredPages { tvm_id, pages } = &call {
+        let chunk = measured_chunk(pages, MEASURE_CHUNK_PAGES);
+        return match hyper::add_tvm_measured_pages_unlocked(&STATE, *tvm_id, chunk) {
+            Ok(_) => SbiRet {
+                a0: 0,
+                a1: chunk.len() as isize,
+            },
+            Err(e) => SbiRet {
+                a0: e.sbi_error(),
+                a1: 0,
+            },
+        };
+    }
+
+    let mut lock = STATE.lock();
+    let state = lock.as_mut().unwrap();
+
+    if !state.hypervisor.h_extension() && !hyper::domain::supports(&call) {
+        return SbiRet {
+            a0: SBI_ERR_NOT_SUPPORTED,
+            a1: 0,
+        };
+    }
+
+    match call {
+        CovhCall::GetTsmInfo { addr, len } => {
+            let size = core::mem::size_of::<TsmInfo>();
+            if len < size
+                || addr % core::mem::align_of::<TsmInfo>() != 0
+                || addr.checked_add(size).is_none()
+                || state.hypervisor.overlaps_confidential_memory(addr, size)
+            {
+                return SbiRet { a0: -1, a1: 0 };
+            }
+            let mut info = state.info.clone();
+            if degraded() {
+                info.tsm_status = TSM_STATUS_LOADED;
+            }
+            unsafe {
+                core::ptr::write(addr as *mut TsmInfo, info);
+            }
+            // The build info follows when the buffer is large enough, a1 tells what was written
+            let ext_size = core::mem::size_of::<TsmBuildInfo>();
+            if len < size + ext_size
+                || addr.checked_add(size + ext_size).is_none()
+                || state
+                    .hypervisor
+                    .overlaps_confidential_memory(addr + size, ext_size)
+            {
+                return SbiRet {
+                    a0: 0,
+                    a1: size as isize,
+                };
+            }
             unsafe {
-                core::ptr::write(a0 as *mut TsmInfo, state.info.clone());
+                core::ptr::write((addr + size) as *mut TsmBuildInfo, state.build_info.clone());
             }
             SbiRet {
                 a0: 0,
-                a1: core::mem::size_of::<TsmInfo>() as isize,
+                a1: (size + ext_size) as isize,
             }
         }
 
-        SBI_COVH_CONVERT_PAGES => match state.hypervisor.add_confidential_pages(a0, a1) {
+        CovhCall::ConvertPages {
+            base_addr,
+            num_pages,
+        } => match state
+            .hypervisor
+            .add_confidential_pages(base_addr, num_pages)
+        {
             Ok(_) => SbiRet { a0: 0, a1: 0 },
-            Err(_) => SbiRet { a0: -1, a1: 0 },
+            Err(e) => SbiRet {
+                a0: e.sbi_error(),
+                a1: 0,
+            },
         },
 
-        SBI_COVH_RECLAIM_PAGES => match state.hypervisor.reclaim_pages(a0, a1) {
+        CovhCall::ReclaimPages {
+            base_addr,
+            num_pages,
+        } => match state.hypervisor.reclaim_pages(base_addr, num_pages) {
             Ok(_) => SbiRet { a0: 0, a1: 0 },
-            Err(_) => SbiRet { a0: -1, a1: 0 },
+            Err(e) => SbiRet {
+                a0: e.sbi_error(),
+                a1: 0,
+            },
         },
 
-        SBI_COVH_CREATE_TVM => {
-            assert!(a1 == 16);
-            let tvm_params = unsafe {
-                let page_table_address = core::ptr::read(a0 as *const usize);
-                let state_address = core::ptr::read((a0 + 8) as *const usize);
-                (page_table_address, state_address)
-            };
+        CovhCall::TsmLocalFence => {
+            state.hypervisor.tsm_local_fence();
+            SbiRet { a0: 0, a1: 0 }
+        }
 
+        CovhCall::CreateTvm(params) => {
+            let _heap_tag = ALLOCATOR.tag(HEAP_TAG_TVM);
             let attestation_context = state.attestation_context.compute_next(&[0; 32]);
 
-            match state
-                .hypervisor
-                .create_tvm(attestation_context, tvm_params.0, tvm_params.1)
-            {
+            match state.hypervisor.create_tvm(
+                attestation_context,
+                params.page_table_addr,
+                params.state_addr,
+                params.measurement_alg,
+                params.policy,
+                params.working_set,
+            ) {
                 Ok(id) => SbiRet {
                     a0: 0,
                     a1: id as isize,
                 },
-                Err(_) => SbiRet { a0: -1, a1: 0 },
+                Err(e) => SbiRet {
+                    a0: e.sbi_error(),
+                    a1: 0,
+                },
             }
         }
 
-        SBI_COVH_FINALIZE_TVM => match state.hypervisor.finalize_tvm(a0, a1, a2, a3) {
+        CovhCall::FinalizeTvm {
+            tvm_id,
+            entry_sepc,
+            entry_arg,
+            tvm_identity_addr,
+        } => match state
+            .hypervisor
+            .finalize_tvm(tvm_id, entry_sepc, entry_arg, tvm_identity_addr)
+        {
             Ok(_) => SbiRet { a0: 0, a1: 0 },
-            Err(_) => SbiRet { a0: -1, a1: 0 },
+            Err(e) => SbiRet {
+                a0: e.sbi_error(),
+                a1: 0,
+            },
         },
 
-        SBI_COVH_ADD_TVM_MEMORY_REGION => {
-            match state.hypervisor.add_tvm_memory_region(a0, a1, a2) {
+        CovhCall::AddTvmMemoryRegion { tvm_id, gpa, len } => {
+            match state.hypervisor.add_tvm_memory_region(tvm_id, gpa, len) {
                 Ok(_) => SbiRet { a0: 0, a1: 0 },
-                Err(_) => SbiRet { a0: -1, a1: 0 },
+                Err(e) => SbiRet {
+                    a0: e.sbi_error(),
+                    a1: 0,
+                },
             }
         }
 
-        SBI_COVH_ADD_TVM_MEASURED_PAGES => {
+        CovhCall::AddTvmSealedImage {
+            tvm_id,
+            image_addr,
+            header,
+            dest_addr,
+        } => {
+            let cdi = state.attestation_context.cdi();
             match state
                 .hypervisor
-                .add_tvm_measured_pages(a0, a1, a2, a3, a4, a5)
+                .add_tvm_sealed_image(cdi, tvm_id, image_addr, &header, dest_addr)
             {
                 Ok(_) => SbiRet { a0: 0, a1: 0 },
-                Err(_) => SbiRet { a0: -1, a1: 0 },
+                Err(e) => SbiRet {
+                    a0: e.sbi_error(),
+                    a1: 0,
+                },
             }
         }
 
-        SBI_COVH_ADD_ZERO_PAGES => match state.hypervisor.add_tvm_zero_pages(a0, a1, a2, a3, a4) {
+        CovhCall::AddTvmZeroPages {
+            tvm_id,
+            base_addr,
+            num_pages,
+            gpa,
+        } => match state
+            .hypervisor
+            .add_tvm_zero_pages(tvm_id, base_addr, 0, num_pages, gpa)
+        {
+            Ok(_) => SbiRet { a0: 0, a1: 0 },
+            Err(e) => SbiRet {
+                a0: e.sbi_error(),
+                a1: 0,
+            },
+        },
+
+        CovhCall::AddTvmSharedPages {
+            tvm_id,
+            base_addr,
+            num_pages,
+            gpa,
+        } => match state
+            .hypervisor
+            .add_tvm_shared_pages(tvm_id, base_addr, num_pages, gpa)
+        {
             Ok(_) => SbiRet { a0: 0, a1: 0 },
-            Err(_) => SbiRet { a0: -1, a1: 0 },
+            Err(e) => SbiRet {
+                a0: e.sbi_error(),
+                a1: 0,
+            },
         },
 
-        SBI_COVH_CREATE_TVM_VCPU => match state.hypervisor.create_tvm_vcpu(a0, a1, a2) {
+        CovhCall::CreateTvmVcpu {
+            tvm_id,
+            vcpu_id,
+            state_addr,
+        } => match state
+            .hypervisor
+            .create_tvm_vcpu(tvm_id, vcpu_id, state_addr)
+        {
             Ok(_) => SbiRet { a0: 0, a1: 0 },
-            Err(_) => SbiRet { a0: -1, a1: 0 },
+            Err(e) => SbiRet {
+                a0: e.sbi_error(),
+                a1: 0,
+            },
         },
 
-        SBI_COVH_RUN_TVM_VCPU => match state.hypervisor.run_tvm_vcpu(a0, a1) {
+        CovhCall::RunTvmVcpu {
+            tvm_id,
+            vcpu_id,
+            flags,
+        } => match state.hypervisor.run_tvm_vcpu(tvm_id, vcpu_id, flags) {
             Ok(_) => unreachable!(),
-            Err(_) => SbiRet { a0: -1, a1: 0 },
+            Err(e) => SbiRet {
+                a0: e.sbi_error(),
+                a1: 0,
+            },
+        },
+
+        CovhCall::SetTvmVcpuAffinity {
+            tvm_id,
+            vcpu_id,
+            hart,
+        } => match state
+            .hypervisor
+            .set_tvm_vcpu_affinity(tvm_id, vcpu_id, hart)
+        {
+            Ok(_) => SbiRet { a0: 0, a1: 0 },
+            Err(e) => SbiRet {
+                a0: e.sbi_error(),
+                a1: 0,
+            },
+        },
+
+        CovhCall::DestroyTvm { .. } => match state.hypervisor.destroy_tvm() {
+            Ok(_) => SbiRet { a0: 0, a1: 0 },
+            Err(e) => SbiRet {
+                a0: e.sbi_error(),
+                a1: 0,
+            },
+        },
+
+        // Returns the blob length in a1.
+        CovhCall::ExportTvm {
+            tvm_id,
+            buf_addr,
+            buf_len,
+            offer_addr,
+        } => {
+            let platform_key = state.attestation_context.platform_public_key();
+            match state
+                .hypervisor
+                .export_tvm(tvm_id, buf_addr, buf_len, offer_addr, platform_key)
+            {
+                Ok(len) => SbiRet {
+                    a0: 0,
+                    a1: len as isize,
+                },
+                Err(e) => SbiRet {
+                    a0: e.sbi_error(),
+                    a1: 0,
+                },
+            }
+        }
+
+        CovhCall::PrepareTvmImport { offer_addr } => {
+            match state
+                .hypervisor
+                .prepare_tvm_import(&state.attestation_context, offer_addr)
+            {
+                Ok(_) => SbiRet { a0: 0, a1: 0 },
+                Err(e) => SbiRet {
+                    a0: e.sbi_error(),
+                    a1: 0,
+                },
+            }
+        }
+
+        // Returns the new tvm id in a1.
+        CovhCall::ImportTvm {
+            blob_addr,
+            blob_len,
+            page_table_addr,
+            state_addr,
+            pool_addr,
+            pool_pages,
+        } => {
+            let _heap_tag = ALLOCATOR.tag(HEAP_TAG_TVM);
+            let attestation_context = state.attestation_context.compute_next(&[0; 32]);
+            let import = hyper::TvmImport {
+                blob_addr,
+                blob_len,
+                page_table_addr,
+                state_addr,
+                pool_addr,
+                pool_pages,
+            };
+            match state.hypervisor.import_tvm(attestation_context, import) {
+                Ok(id) => SbiRet {
+                    a0: 0,
+                    a1: id as isize,
+                },
+                Err(e) => SbiRet {
+                    a0: e.sbi_error(),
+                    a1: 0,
+                },
+            }
+        }
+
+        CovhCall::TvmVcpuSetTimerFrequency {
+            tvm_id,
+            vcpu_id,
+            frequency,
+        } => match state
+            .hypervisor
+            .tvm_vcpu_set_timer_frequency(tvm_id, vcpu_id, frequency)
+        {
+            Ok(_) => SbiRet { a0: 0, a1: 0 },
+            Err(e) => SbiRet {
+                a0: e.sbi_error(),
+                a1: 0,
+            },
         },
 
-        SBI_COVH_DESTROY_TVM => match state.hypervisor.destroy_tvm() {
+        CovhCall::SetTvmBootInfo {
+            tvm_id,
+            dest_addr,
+            gpa,
+        } => match state.hypervisor.set_tvm_boot_info(tvm_id, dest_addr, gpa) {
             Ok(_) => SbiRet { a0: 0, a1: 0 },
-            Err(_) => SbiRet { a0: -1, a1: 0 },
+            Err(e) => SbiRet {
+                a0: e.sbi_error(),
+                a1: 0,
+            },
+        },
+
+        CovhCall::GetTvmVcpuTime {
+            tvm_id,
+            vcpu_id,
+            addr,
+        } => {
+            let size = core::mem::size_of::<TvmVcpuTime>();
+            if state.hypervisor.overlaps_confidential_memory(addr, size) {
+                let e = CoveError::InvalidAddress("buffer in confidential memory");
+                return SbiRet {
+                    a0: e.sbi_error(),
+                    a1: 0,
+                };
+            }
+            match state.hypervisor.get_tvm_vcpu_time(tvm_id, vcpu_id) {
+                Ok(time) => {
+                    unsafe { core::ptr::write(addr as *mut TvmVcpuTime, time) };
+                    SbiRet { a0: 0, a1: 0 }
+                }
+                Err(e) => SbiRet {
+                    a0: e.sbi_error(),
+                    a1: 0,
+                },
+            }
+        }
+
+        CovhCall::TvmTranslateGpa { tvm_id, gpa, addr } => {
+            if state.info.tsm_capabilities & (1 << SHADOWFAX_TSM_CAP_TRANSLATE_GPA) == 0 {
+                return SbiRet {
+                    a0: SBI_ERR_NOT_SUPPORTED,
+                    a1: 0,
+                };
+            }
+            let size = core::mem::size_of::<TvmGpaTranslation>();
+            if state.hypervisor.overlaps_confidential_memory(addr, size) {
+                let e = CoveError::InvalidAddress("buffer in confidential memory");
+                return SbiRet {
+                    a0: e.sbi_error(),
+                    a1: 0,
+                };
+            }
+            match state.hypervisor.translate_tvm_gpa(tvm_id, gpa) {
+                Ok(translation) => {
+                    unsafe { core::ptr::write(addr as *mut TvmGpaTranslation, translation) };
+                    SbiRet { a0: 0, a1: 0 }
+                }
+                Err(e) => SbiRet {
+                    a0: e.sbi_error(),
+                    a1: 0,
+                },
+            }
+        }
+
+        CovhCall::GetTsmStats { addr } => {
+            let size = core::mem::size_of::<TsmStats>();
+            if state.hypervisor.overlaps_confidential_memory(addr, size) {
+                let e = CoveError::InvalidAddress("buffer in confidential memory");
+                return SbiRet {
+                    a0: e.sbi_error(),
+                    a1: 0,
+                };
+            }
+            unsafe { core::ptr::write(addr as *mut TsmStats, stats::snapshot()) };
+            SbiRet { a0: 0, a1: 0 }
+        }
+
+        CovhCall::GetTsmMemoryStats { addr } => {
+            if state.info.tsm_capabilities & (1 << SHADOWFAX_TSM_CAP_TRANSLATE_GPA) == 0 {
+                return SbiRet {
+                    a0: SBI_ERR_NOT_SUPPORTED,
+                    a1: 0,
+                };
+            }
+            let size = core::mem::size_of::<TsmMemoryStats>();
+            if state.hypervisor.overlaps_confidential_memory(addr, size) {
+                let e = CoveError::InvalidAddress("buffer in confidential memory");
+                return SbiRet {
+                    a0: e.sbi_error(),
+                    a1: 0,
+                };
+            }
+            unsafe { core::ptr::write(addr as *mut TsmMemoryStats, watermark::snapshot()) };
+            SbiRet { a0: 0, a1: 0 }
+        }
+
+        CovhCall::AddTvmMeasuredPages { .. } | CovhCall::ProcessQueue { .. } => unreachable!(),
+    }
+}
+
+fn handle_covi(a0: usize, a1: usize, a2: usize, a3: usize, a6: usize) -> SbiRet {
+    let (_, fid) = cove_unpack_fid(a6);
+
+    let mut lock = STATE.lock();
+    let state = lock.as_mut().unwrap();
+    if !state.hypervisor.h_extension() {
+        return SbiRet {
+            a0: SBI_ERR_NOT_SUPPORTED,
+            a1: 0,
+        };
+    }
+
+    let ret = match fid {
+        SBI_COVI_INIT_TVM_AIA => state.hypervisor.init_tvm_aia(a0, a1, a2),
+        SBI_COVI_SET_TVM_AIA_CPU_IMSIC_ADDR => {
+            state.hypervisor.set_tvm_aia_cpu_imsic_addr(a0, a1, a2)
+        }
+        SBI_COVI_CONVERT_AIA_IMSIC => state.hypervisor.convert_aia_imsic(a0),
+        SBI_COVI_RECLAIM_AIA_IMSIC => state.hypervisor.reclaim_aia_imsic(a0),
+        SBI_COVI_BIND_AIA_IMSIC => state.hypervisor.bind_aia_imsic(a0, a1, a2),
+        SBI_COVI_UNBIND_AIA_IMSIC_BEGIN => state.hypervisor.unbind_aia_imsic_begin(a0, a1),
+        SBI_COVI_UNBIND_AIA_IMSIC_END => state.hypervisor.unbind_aia_imsic_end(a0, a1),
+        SBI_COVI_INJECT_TVM_CPU_INTERRUPT => state.hypervisor.inject_tvm_cpu_interrupt(a0, a1, a2),
+        SBI_COVI_BIND_TVM_INTERRUPT => state.hypervisor.bind_tvm_interrupt(a0, a1, a2, a3),
+        SBI_COVI_UNBIND_TVM_INTERRUPT => state.hypervisor.unbind_tvm_interrupt(a0, a1),
+        SBI_COVI_SIGNAL_TVM_INTERRUPT => state.hypervisor.signal_tvm_interrupt(a0, a1, a2),
+        _ => Err(CoveError::NotSupported("unsupported CoVI function")),
+    };
+
+    match ret {
+        Ok(_) => SbiRet { a0: 0, a1: 0 },
+        Err(e) => SbiRet {
+            a0: e.sbi_error(),
+            a1: 0,
         },
-        _ => SbiRet { a0: -1, a1: 0 },
     }
 }
 
@@ -297,7 +928,7 @@ fn test_tvm_bootstrap() -> ! {
     println!("[OLORIN] Starting Mapping TVM from ELF");
     // 1. Initialize the TSM state manually (if _secure_init wasn't called by a driver)
     // We'll simulate a dummy attestation context for testing.
-    _secure_init(0);
+    _secure_init(0, 0, 1, 0, 0);
 
     let mut lock = STATE.lock();
     let state = lock.as_mut().expect("State not initialized");
@@ -324,10 +955,10 @@ fn test_tvm_bootstrap() -> ! {
         .unwrap();
 
     // 4. Use the ELF loading procedure
-    // This helper parses GUEST_ELF and maps it into the TVM
+    // This helper parses the guest ELF and maps it into the TVM
     let tvm_id = hyper::bootstrap_load_elf_lazy(
         state,
-        GUEST_ELF,
+        guest_elf(),
         tvm_page_table_addr,
         tvm_state_addr,
         tvm_confidential_pool,
@@ -345,7 +976,7 @@ fn test_tvm_bootstrap() -> ! {
     // 6. Run it!
     state
         .hypervisor
-        .run_tvm_vcpu(tvm_id, 0)
+        .run_tvm_vcpu(tvm_id, 0, 0)
         .expect("Failed to run VCPU");
 }
 
@@ -359,7 +990,7 @@ fn test_tvm_bootstrap_perf() -> ! {
     let time_start = read_time();
 
     let dummy_context = TsmAttestationContext::default();
-    _secure_init(&dummy_context as *const _ as usize);
+    _secure_init(&dummy_context as *const _ as usize, 0, 1, 0, 0);
 
     let mut lock = STATE.lock();
     let state = lock.as_mut().expect("State not initialized");
@@ -386,10 +1017,10 @@ fn test_tvm_bootstrap_perf() -> ! {
         .unwrap();
 
     // 4. Use the ELF loading procedure
-    // This helper parses GUEST_ELF and maps it into the TVM
+    // This helper parses the guest ELF and maps it into the TVM
     let tvm_id = hyper::bootstrap_load_elf_lazy(
         state,
-        GUEST_ELF,
+        guest_elf(),
         tvm_page_table_addr,
         tvm_state_addr,
         tvm_confidential_pool,
@@ -1,23 +1,378 @@
+use aes_gcm::{aead::AeadInPlace, Aes256Gcm, KeyInit, Nonce, Tag};
 use common::{
-    attestation::DiceLayer,
-    sbi::{SbiRet, COVG_GET_EVIDENCE, PAGE_SIZE},
+    attestation::{
+        Cdi, DiceLayer, KEY_LADDER_MAX_LABEL, KEY_LADDER_OWNER_GUEST, KEY_LADDER_OWNER_TSM,
+    },
+    sbi::{
+        sbi_call, SbiRet, COVG_BLOB_MAX_SIZE, COVG_DERIVE_KEY, COVG_FREE_COUNTER,
+        COVG_GET_CERT_CHAIN, COVG_GET_EVIDENCE, COVG_GET_RANDOM, COVG_GET_TIME, COVG_GET_TVM_INFO,
+        COVG_INCREMENT_COUNTER, COVG_LOAD_BLOB, COVG_NUM_BLOB_SLOTS, COVG_NUM_COUNTERS,
+        COVG_READ_COUNTER, COVG_STORE_BLOB, COVG_TVM_INFO_MAX_SHARED, PAGE_SIZE,
+        SBI_EXT_SUPD_FREE_COUNTER, SBI_EXT_SUPD_GET_RANDOM, SBI_EXT_SUPD_GET_TIME,
+        SBI_EXT_SUPD_INCREMENT_COUNTER, SBI_EXT_SUPD_LOAD_BLOB, SBI_EXT_SUPD_READ_COUNTER,
+        SBI_EXT_SUPD_STORE_BLOB, SBI_SUPD_EXT_ID, SUPD_BLOB_ID_SIZE, SUPD_COUNTER_ID_SIZE,
+        TVM_POLICY_CERT_CHAIN,
+    },
+    sbi::{TvmBootMemoryRegion, TvmInfo},
 };
+use core::sync::atomic::Ordering;
 
 use crate::{
-    hyper::{read_guest_memory, write_guest_memory},
-    println, ATTESTATION_CONTEXT, MEASUREMENT, STATE,
+    hyper::{hsm, read_guest_memory, write_guest_memory},
+    println, ATTESTATION_CONTEXT, MEASUREMENT, TVM_BLOBS, TVM_CDI, TVM_COUNTERS, TVM_POLICY,
+    TVM_SHARED_MEMORY,
 };
 
+const BLOB_KEY_LABEL: &[u8] = b"tvm-blob";
+const BLOB_ID_LABEL: &[u8] = b"tvm-blob-id";
+const COUNTER_ID_LABEL: &[u8] = b"tvm-counter-id";
+const BLOB_NONCE_SIZE: usize = 12;
+const BLOB_TAG_SIZE: usize = 16;
+
+/// Monotonic counters and trusted time of the running TVM. The counters live in the TSM-driver,
+/// which only serves them to the TSM: the ids of the `COVG_NUM_COUNTERS` counters of a TVM are
+/// derived from the CDI of its measurement, so a TVM gets its own counters back, and no other
+/// TVM reaches them. A counter is only freed at the request of its TVM (COVG_FREE_COUNTER).
+/// Without a CDI the TVM has no counters, only the time.
+pub struct TvmCounters {
+    ids: Option<[[u8; SUPD_COUNTER_ID_SIZE]; COVG_NUM_COUNTERS]>,
+    /// Last time returned to the TVM, so that time is monotonic as well
+    last_time: u64,
+}
+
+impl TvmCounters {
+    pub fn new(cdi: Option<&Cdi>) -> Self {
+        let ids = cdi.map(|cdi| {
+            core::array::from_fn(|index| {
+                let label = [COUNTER_ID_LABEL, &[index as u8]].concat();
+                let id = cdi.ladder_key(KEY_LADDER_OWNER_TSM, &label).unwrap();
+                id[..].try_into().unwrap()
+            })
+        });
+        Self { ids, last_time: 0 }
+    }
+
+    fn counter_call(&self, fid: usize, index: usize) -> SbiRet {
+        let Some(id) = self.ids.as_ref().and_then(|ids| ids.get(index)) else {
+            return SbiRet { a0: -1, a1: 0 };
+        };
+        sbi_call(SBI_SUPD_EXT_ID, fid, &[id.as_ptr() as usize, 0, 0, 0, 0, 0])
+    }
+
+    fn time(&mut self) -> SbiRet {
+        let ret = sbi_call(SBI_SUPD_EXT_ID, SBI_EXT_SUPD_GET_TIME, &[0; 6]);
+        if ret.a0 != 0 {
+            return ret;
+        }
+        self.last_time = self.last_time.max(ret.a1 as u64);
+        SbiRet {
+            a0: 0,
+            a1: self.last_time as isize,
+        }
+    }
+}
+
+/// Sealed blobs of the running TVM, persisted by the TSM-driver (SUPD STORE_BLOB and LOAD_BLOB).
+/// The key and the blob ids are derived from the CDI of the TVM measurement: only a TVM with the
+/// same measurement, on the same TSM and platform, finds and unseals them, across reboots. A blob
+/// is stored as `nonce | ciphertext | tag` (AES-256-GCM, random nonce) with its id as associated
+/// data, so the TSM-driver can neither read nor swap blobs.
+pub struct TvmBlobs {
+    cipher: Aes256Gcm,
+    ids: [[u8; SUPD_BLOB_ID_SIZE]; COVG_NUM_BLOB_SLOTS],
+}
+
+impl TvmBlobs {
+    pub fn new(cdi: &Cdi) -> Self {
+        let key = cdi
+            .ladder_key(KEY_LADDER_OWNER_TSM, BLOB_KEY_LABEL)
+            .unwrap();
+        Self {
+            cipher: Aes256Gcm::new_from_slice(&key).unwrap(),
+            ids: core::array::from_fn(|slot| {
+                let label = [BLOB_ID_LABEL, &[slot as u8]].concat();
+                let id = cdi.ladder_key(KEY_LADDER_OWNER_TSM, &label).unwrap();
+                id[..].try_into().unwrap()
+            }),
+        }
+    }
+
+    /// Seal the `size` bytes at `gpa` into `slot`. Size 0 removes the blob.
+    fn store(&self, slot: usize, gpa: usize, size: usize) -> SbiRet {
+        let Some(id) = self.ids.get(slot) else {
+            return SbiRet { a0: -1, a1: 0 };
+        };
+        if size == 0 {
+            return sbi_call(
+                SBI_SUPD_EXT_ID,
+                SBI_EXT_SUPD_STORE_BLOB,
+                &[id.as_ptr() as usize, 0, 0, 0, 0, 0],
+            );
+        }
+        if size > COVG_BLOB_MAX_SIZE {
+            return SbiRet { a0: -1, a1: 0 };
+        }
+
+        let mut blob = alloc::vec![0u8; BLOB_NONCE_SIZE + size + BLOB_TAG_SIZE];
+        let (nonce, rest) = blob.split_at_mut(BLOB_NONCE_SIZE);
+        let (data, tag) = rest.split_at_mut(size);
+        if random_bytes(nonce).is_err() || read_guest_memory(guest_root_pt(), gpa, data).is_err() {
+            return SbiRet { a0: -1, a1: 0 };
+        }
+        let Ok(sealed_tag) =
+            self.cipher
+                .encrypt_in_place_detached(Nonce::from_slice(nonce), id, data)
+        else {
+            return SbiRet { a0: -1, a1: 0 };
+        };
+        tag.copy_from_slice(&sealed_tag);
+
+        sbi_call(
+            SBI_SUPD_EXT_ID,
+            SBI_EXT_SUPD_STORE_BLOB,
+            &[
+                id.as_ptr() as usize,
+                blob.as_ptr() as usize,
+                blob.len(),
+                0,
+                0,
+                0,
+            ],
+        )
+    }
+
+    /// Unseal the blob of `slot` into the `size` bytes at `gpa` and return its size.
+    fn load(&self, slot: usize, gpa: usize, size: usize) -> SbiRet {
+        let Some(id) = self.ids.get(slot) else {
+            return SbiRet { a0: -1, a1: 0 };
+        };
+
+        let mut blob = alloc::vec![0u8; BLOB_NONCE_SIZE + COVG_BLOB_MAX_SIZE + BLOB_TAG_SIZE];
+        let ret = sbi_call(
+            SBI_SUPD_EXT_ID,
+            SBI_EXT_SUPD_LOAD_BLOB,
+            &[
+                id.as_ptr() as usize,
+                blob.as_ptr() as usize,
+                blob.len(),
+                0,
+                0,
+                0,
+            ],
+        );
+        if ret.a0 != 0 {
+            return ret;
+        }
+
+        let Some(len) = (ret.a1 as usize).checked_sub(BLOB_NONCE_SIZE + BLOB_TAG_SIZE) else {
+            return SbiRet { a0: -1, a1: 0 };
+        };
+        if len > size {
+            return SbiRet { a0: -1, a1: 0 };
+        }
+        let (nonce, rest) = blob.split_at_mut(BLOB_NONCE_SIZE);
+        let (data, rest) = rest.split_at_mut(len);
+        let tag = Tag::clone_from_slice(&rest[..BLOB_TAG_SIZE]);
+        if self
+            .cipher
+            .decrypt_in_place_detached(Nonce::from_slice(nonce), id, data, &tag)
+            .is_err()
+            || write_guest_memory(guest_root_pt(), gpa, data).is_err()
+        {
+            return SbiRet { a0: -1, a1: 0 };
+        }
+
+        SbiRet {
+            a0: 0,
+            a1: len as isize,
+        }
+    }
+}
+
 pub fn handle_covg(_eid: usize, fid: usize, args: &[usize; 6]) -> SbiRet {
     match fid {
         COVG_GET_EVIDENCE => {
             println!("[OLORIN] Requested attestation certificate");
             handle_covg_get_evidence(args[0], args[1], args[2], args[3], args[4], args[5])
         }
+        COVG_GET_RANDOM => handle_covg_get_random(args[0], args[1]),
+        COVG_READ_COUNTER | COVG_INCREMENT_COUNTER | COVG_FREE_COUNTER | COVG_GET_TIME => {
+            let mut lock = TVM_COUNTERS.lock();
+            let counters = match lock.as_mut() {
+                Some(counters) => counters,
+                None => return SbiRet { a0: -1, a1: 0 },
+            };
+            match fid {
+                COVG_READ_COUNTER => counters.counter_call(SBI_EXT_SUPD_READ_COUNTER, args[0]),
+                COVG_INCREMENT_COUNTER => {
+                    counters.counter_call(SBI_EXT_SUPD_INCREMENT_COUNTER, args[0])
+                }
+                COVG_FREE_COUNTER => counters.counter_call(SBI_EXT_SUPD_FREE_COUNTER, args[0]),
+                _ => counters.time(),
+            }
+        }
+        COVG_STORE_BLOB | COVG_LOAD_BLOB => {
+            let lock = TVM_BLOBS.lock();
+            let Some(blobs) = lock.as_ref() else {
+                return SbiRet { a0: -1, a1: 0 };
+            };
+            match fid {
+                COVG_STORE_BLOB => blobs.store(args[0], args[1], args[2]),
+                _ => blobs.load(args[0], args[1], args[2]),
+            }
+        }
+        COVG_DERIVE_KEY => handle_covg_derive_key(args[0], args[1], args[2]),
+        COVG_GET_CERT_CHAIN if TVM_POLICY.load(Ordering::Relaxed) & TVM_POLICY_CERT_CHAIN != 0 => {
+            handle_covg_get_cert_chain(args[0], args[1])
+        }
+        COVG_GET_TVM_INFO => handle_covg_get_tvm_info(args[0], args[1]),
         _ => SbiRet { a0: -1, a1: 0 },
     }
 }
 
+/// Write the `TvmInfo` of the TVM at guest address `buf_addr`, if it fits in `size` bytes.
+fn handle_covg_get_tvm_info(buf_addr: usize, size: usize) -> SbiRet {
+    let len = core::mem::size_of::<TvmInfo>();
+    if size < len {
+        return SbiRet { a0: -1, a1: 0 };
+    }
+
+    let mut info = TvmInfo {
+        measurement_alg: 0,
+        num_vcpus: hsm::count() as u32,
+        policy: TVM_POLICY.load(Ordering::Relaxed) as u64,
+        measurement: [0; 64],
+        num_shared_regions: 0,
+        reserved: 0,
+        shared_regions: [TvmBootMemoryRegion::default(); COVG_TVM_INFO_MAX_SHARED],
+    };
+    {
+        let measure_lock = MEASUREMENT.lock();
+        let Some((hash_alg, measurement)) = measure_lock.as_ref() else {
+            return SbiRet { a0: -1, a1: 0 };
+        };
+        info.measurement_alg = *hash_alg as u32;
+        info.measurement[..measurement.len()].copy_from_slice(measurement);
+    }
+    {
+        let shared = TVM_SHARED_MEMORY.lock();
+        info.num_shared_regions = shared.len() as u32;
+        for (slot, r) in info.shared_regions.iter_mut().zip(shared.iter()) {
+            *slot = TvmBootMemoryRegion {
+                gpa_base: r.guest_gpa_base as u64,
+                size: r.num_pages as u64 * PAGE_SIZE as u64,
+            };
+        }
+    }
+
+    let bytes = unsafe { core::slice::from_raw_parts(&info as *const TvmInfo as *const u8, len) };
+    if write_guest_memory(guest_root_pt(), buf_addr, bytes).is_err() {
+        return SbiRet { a0: -1, a1: 0 };
+    }
+    SbiRet {
+        a0: 0,
+        a1: len as isize,
+    }
+}
+
+/// Write the certificate chain of the TVM (platform, TSM and TVM tokens) at guest address
+/// `buf_addr`, if it fits in `size` bytes, and return its size.
+fn handle_covg_get_cert_chain(buf_addr: usize, size: usize) -> SbiRet {
+    let _heap_tag = crate::ALLOCATOR.tag(crate::HEAP_TAG_ATTESTATION);
+    let chain = {
+        let measure_lock = MEASUREMENT.lock();
+        let Some((hash_alg, measurement)) = measure_lock.as_ref() else {
+            return SbiRet { a0: -1, a1: 0 };
+        };
+        let attestation_lock = ATTESTATION_CONTEXT.lock();
+        let Some(tsm_context) = attestation_lock.as_ref() else {
+            return SbiRet { a0: -1, a1: 0 };
+        };
+        let chain = tsm_context
+            .compute_next(measurement)
+            .get_cert_chain(measurement, *hash_alg);
+        match chain.to_bytes() {
+            Ok(chain) => chain,
+            Err(e) => {
+                println!("[OLORIN] Error during certificate chain encoding {}", e);
+                return SbiRet { a0: -1, a1: 0 };
+            }
+        }
+    };
+
+    if chain.len() > size || write_guest_memory(guest_root_pt(), buf_addr, &chain).is_err() {
+        return SbiRet { a0: -1, a1: 0 };
+    }
+    SbiRet {
+        a0: 0,
+        a1: chain.len() as isize,
+    }
+}
+
+/// Derive the key of the label (`label_size` bytes at guest address `label_addr`) from the CDI of
+/// the TVM and write it at guest address `key_addr`.
+fn handle_covg_derive_key(label_addr: usize, label_size: usize, key_addr: usize) -> SbiRet {
+    if label_size > KEY_LADDER_MAX_LABEL {
+        return SbiRet { a0: -1, a1: 0 };
+    }
+
+    let mut label = [0u8; KEY_LADDER_MAX_LABEL];
+    let label = &mut label[..label_size];
+    if read_guest_memory(guest_root_pt(), label_addr, label).is_err() {
+        return SbiRet { a0: -1, a1: 0 };
+    }
+    let Some(key) = TVM_CDI
+        .lock()
+        .as_ref()
+        .and_then(|cdi| cdi.ladder_key(KEY_LADDER_OWNER_GUEST, label))
+    else {
+        return SbiRet { a0: -1, a1: 0 };
+    };
+    if write_guest_memory(guest_root_pt(), key_addr, &key).is_err() {
+        return SbiRet { a0: -1, a1: 0 };
+    }
+
+    SbiRet { a0: 0, a1: 0 }
+}
+
+/// Fill `len` bytes (at most a page) at guest address `buf_addr` with random bytes from the
+/// TSM-driver DRBG.
+fn handle_covg_get_random(buf_addr: usize, len: usize) -> SbiRet {
+    if len > PAGE_SIZE {
+        return SbiRet { a0: -1, a1: 0 };
+    }
+
+    let mut buf = alloc::vec![0u8; len];
+    if random_bytes(&mut buf).is_err()
+        || write_guest_memory(guest_root_pt(), buf_addr, &buf).is_err()
+    {
+        return SbiRet { a0: -1, a1: 0 };
+    }
+
+    SbiRet {
+        a0: 0,
+        a1: len as isize,
+    }
+}
+
+/// Fill `buf` from the TSM-driver DRBG.
+pub(crate) fn random_bytes(buf: &mut [u8]) -> Result<(), ()> {
+    // A call returns a register worth of random bytes
+    for chunk in buf.chunks_mut(core::mem::size_of::<usize>()) {
+        let ret = sbi_call(SBI_SUPD_EXT_ID, SBI_EXT_SUPD_GET_RANDOM, &[0; 6]);
+        if ret.a0 != 0 {
+            return Err(());
+        }
+        chunk.copy_from_slice(&(ret.a1 as usize).to_le_bytes()[..chunk.len()]);
+    }
+    Ok(())
+}
+
+/// G-stage root page table of the running TVM
+fn guest_root_pt() -> usize {
+    crate::h_extension::csrs::hgatp::read().ppn() << 12
+}
+
 // fn handle_covg_get_evidence(
 //     pub_key_addr: usize,
 //     _pub_key_size: usize,
@@ -92,8 +447,7 @@ fn handle_covg_get_evidence(
     cert_size: usize,
 ) -> SbiRet {
     // A. SETUP: Get Page Table
-    let hgatp_val = crate::h_extension::csrs::hgatp::read().bits();
-    let root_pt = ((hgatp_val & 0xFF_FFFF_FFFF_F) << 12) as usize;
+    let root_pt = guest_root_pt();
 
     // B. INPUT: Read Challenge from Guest
     let mut challenge = [0u8; 64];
@@ -102,12 +456,13 @@ fn handle_covg_get_evidence(
     }
 
     // C. LOGIC: Generate Evidence (Holds Locks)
+    let _heap_tag = crate::ALLOCATOR.tag(crate::HEAP_TAG_ATTESTATION);
     let encoded_evidence = {
         // We assume Measurement is also available here or passed in
         // For this example, let's say it's in TSM or separate lock
         let measure_lock = MEASUREMENT.lock();
-        let measurement = match measure_lock.as_ref() {
-            Some(m) => m,
+        let (hash_alg, measurement) = match measure_lock.as_ref() {
+            Some((alg, m)) => (*alg, m),
             None => return SbiRet { a0: -1, a1: 0 },
         };
 
@@ -117,7 +472,7 @@ fn handle_covg_get_evidence(
             None => return SbiRet { a0: -1, a1: 0 },
         }
         .compute_next(measurement);
-        let evidence = tvm_attestation_ctx.get_evidence(&measurement, &challenge);
+        let evidence = tvm_attestation_ctx.get_evidence(measurement, hash_alg, &challenge);
         match evidence.to_bytes() {
             Ok(e) => e,
             Err(e) => {
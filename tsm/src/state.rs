@@ -1,17 +1,49 @@
-pub const TSM_IMPL_ID: u32 = 0x45;
+pub use common::sbi::TsmInfo;
+use common::sbi::{TsmIdentity, SHADOWFAX_TSM_IMPL_ID, TSM_BUILD_ID_SIZE};
+use tsm_core::covh::DEFAULT_MEASURE_CHUNK_PAGES;
+
+pub const TSM_IMPL_ID: u32 = SHADOWFAX_TSM_IMPL_ID;
 pub const TSM_VERSION: u32 = 0x45;
+/// Git commit the TSM was built from (see build.rs)
+pub const TSM_BUILD_ID: [u8; TSM_BUILD_ID_SIZE] = build_id(env!("TSM_BUILD_ID"));
+/// Measured pages copied and measured per TEECALL (`TSM_MEASURE_CHUNK_PAGES` at build time)
+pub const MEASURE_CHUNK_PAGES: usize = match option_env!("TSM_MEASURE_CHUNK_PAGES") {
+    Some(pages) => parse_pages(pages),
+    None => DEFAULT_MEASURE_CHUNK_PAGES,
+};
 
+/// Shadowfax extension of `TsmInfo`: GET_TSM_INFO writes it right after `TsmInfo` when the buffer
+/// has room for both.
 #[repr(C)]
 #[derive(Clone, Debug)]
-pub struct TsmInfo {
-    pub tsm_status: TsmStatus,
-    pub tsm_impl_id: u32,
-    pub tsm_version: u32,
-    pub _padding: u32,
-    pub tsm_capabilities: usize,
-    pub tvm_state_pages: usize,
-    pub tvm_max_vcpus: usize,
-    pub tvm_vcpu_state_pages: usize,
+pub struct TsmBuildInfo {
+    /// Measurement and signer of the TSM binary, as verified by the TSM-driver
+    pub identity: TsmIdentity,
+    /// Git commit hash in hex, zero-filled if unknown
+    pub build_id: [u8; TSM_BUILD_ID_SIZE],
+}
+
+const fn build_id(hash: &str) -> [u8; TSM_BUILD_ID_SIZE] {
+    let hash = hash.as_bytes();
+    let mut id = [0; TSM_BUILD_ID_SIZE];
+    let mut i = 0;
+    while i < hash.len() && i < TSM_BUILD_ID_SIZE {
+        id[i] = hash[i];
+        i += 1;
+    }
+    id
+}
+
+/// Decimal page count, validated by build.rs
+const fn parse_pages(pages: &str) -> usize {
+    let pages = pages.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+    while i < pages.len() {
+        value = value * 10 + (pages[i] - b'0') as usize;
+        i += 1;
+    }
+    value
 }
 
 pub enum TsmPageType {
@@ -20,10 +52,3 @@ pub enum TsmPageType {
     Page1gb = 2,
     Page512gb = 3,
 }
-
-#[derive(Clone, Debug)]
-pub enum TsmStatus {
-    TsmNotLoaded = 0,
-    TsmLoaded = 1,
-    TsmReady = 2,
-}
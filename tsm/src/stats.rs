@@ -0,0 +1,63 @@
+//! Event counters of the TSM, reported to the host by `SBI_COVH_GET_TSM_STATS`. Together with the
+//! counters of the domains kept by the TSM-driver (SUPD GET_DOMAIN_STATS) they tell where the time
+//! goes across the firmware/TSM boundary. The counters are never reset.
+
+use common::sbi::{TsmStats, TSM_STATS_EXITS, TVM_EXIT_REASON_MASK};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use tsm_core::{covh::measured_chunk, CovhCall};
+
+use crate::state::MEASURE_CHUNK_PAGES;
+
+pub static TEECALLS: AtomicUsize = AtomicUsize::new(0);
+pub static PAGES_CONVERTED: AtomicUsize = AtomicUsize::new(0);
+pub static PAGES_RECLAIMED: AtomicUsize = AtomicUsize::new(0);
+pub static MEASURED_PAGES: AtomicUsize = AtomicUsize::new(0);
+pub static ZERO_PAGES: AtomicUsize = AtomicUsize::new(0);
+pub static SHARED_PAGES: AtomicUsize = AtomicUsize::new(0);
+pub static GUEST_PAGE_FAULTS: AtomicUsize = AtomicUsize::new(0);
+pub static GUEST_CALLS: AtomicUsize = AtomicUsize::new(0);
+static EXITS: [AtomicUsize; TSM_STATS_EXITS] = [const { AtomicUsize::new(0) }; TSM_STATS_EXITS];
+
+pub fn count(counter: &AtomicUsize, n: usize) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+/// Count an exit to the host, `exit` as returned in a1 by RUN_TVM_VCPU.
+pub fn count_exit(exit: usize) {
+    if let Some(counter) = EXITS.get(exit & TVM_EXIT_REASON_MASK) {
+        count(counter, 1);
+    }
+}
+
+/// Pages counter bumped by `call` if it succeeds, with its number of pages.
+pub fn pages_of(call: &CovhCall) -> Option<(&'static AtomicUsize, usize)> {
+    match call {
+        CovhCall::ConvertPages { num_pages, .. } => Some((&PAGES_CONVERTED, *num_pages)),
+        CovhCall::ReclaimPages { num_pages, .. } => Some((&PAGES_RECLAIMED, *num_pages)),
+        CovhCall::AddTvmMeasuredPages { pages, .. } => Some((
+            &MEASURED_PAGES,
+            measured_chunk(pages, MEASURE_CHUNK_PAGES).len(),
+        )),
+        CovhCall::AddTvmSealedImage { header, .. } => {
+            Some((&MEASURED_PAGES, header.num_pages as usize))
+        }
+        CovhCall::AddTvmZeroPages { num_pages, .. } => Some((&ZERO_PAGES, *num_pages)),
+        CovhCall::AddTvmSharedPages { num_pages, .. } => Some((&SHARED_PAGES, *num_pages)),
+        _ => None,
+    }
+}
+
+pub fn snapshot() -> TsmStats {
+    let read = |counter: &AtomicUsize| counter.load(Ordering::Relaxed) as u64;
+    TsmStats {
+        teecalls: read(&TEECALLS),
+        pages_converted: read(&PAGES_CONVERTED),
+        pages_reclaimed: read(&PAGES_RECLAIMED),
+        measured_pages: read(&MEASURED_PAGES),
+        zero_pages: read(&ZERO_PAGES),
+        shared_pages: read(&SHARED_PAGES),
+        guest_page_faults: read(&GUEST_PAGE_FAULTS),
+        guest_calls: read(&GUEST_CALLS),
+        exits: core::array::from_fn(|i| read(&EXITS[i])),
+    }
+}
@@ -0,0 +1,66 @@
+//! Stack and heap usage of the TSM, reported to the host by `SBI_COVH_GET_TSM_MEMORY_STATS`.
+//! `_secure_init` runs on the stack of the TSM-driver, so it fills the whole TSM stack with a
+//! pattern and writes a canary in its lowest word. The deepest use of the stack is the lowest word
+//! which lost the pattern; an overwritten canary means the stack overflowed into the memory below
+//! it, and the TSM is no longer reported as ready. The heap counters come from the allocator.
+
+use core::mem::size_of;
+
+use common::sbi::TsmMemoryStats;
+
+use crate::{_stack_top, ALLOCATOR, STACK_SIZE_PER_HART};
+
+// Truncated to the low word on RV32
+const STACK_PATTERN: usize = 0xa5a5_a5a5_a5a5_a5a5_u64 as usize;
+const STACK_CANARY: usize = 0x5346_5853_5441_434b_u64 as usize;
+
+/// Bottom and top of the stack, `_start` sets `sp` to the top.
+fn stack() -> (usize, usize) {
+    let top = &raw const _stack_top as usize - STACK_SIZE_PER_HART;
+    (top - STACK_SIZE_PER_HART, top)
+}
+
+fn read(addr: usize) -> usize {
+    unsafe { (addr as *const usize).read_volatile() }
+}
+
+/// Fill the stack with the pattern and the canary, before its first use.
+pub fn paint_stack() {
+    let (bottom, top) = stack();
+    for addr in (bottom..top).step_by(size_of::<usize>()) {
+        unsafe { (addr as *mut usize).write_volatile(STACK_PATTERN) };
+    }
+    unsafe { (bottom as *mut usize).write_volatile(STACK_CANARY) };
+}
+
+/// Whether the canary at the bottom of the stack was overwritten.
+pub fn stack_overflowed() -> bool {
+    read(stack().0) != STACK_CANARY
+}
+
+/// Deepest use of the stack in bytes, the whole stack once it overflowed.
+fn stack_peak() -> usize {
+    let (bottom, top) = stack();
+    if stack_overflowed() {
+        return top - bottom;
+    }
+    let untouched = (bottom + size_of::<usize>()..top)
+        .step_by(size_of::<usize>())
+        .take_while(|&addr| read(addr) == STACK_PATTERN)
+        .count();
+    top - bottom - (untouched + 1) * size_of::<usize>()
+}
+
+pub fn snapshot() -> TsmMemoryStats {
+    let (bottom, top) = stack();
+    let heap = ALLOCATOR.stats();
+    TsmMemoryStats {
+        stack_size: (top - bottom) as u64,
+        stack_peak: stack_peak() as u64,
+        stack_overflow: stack_overflowed() as u64,
+        heap_size: heap.size as u64,
+        heap_used: heap.used as u64,
+        heap_peak: heap.peak as u64,
+        heap_failures: heap.failures as u64,
+    }
+}